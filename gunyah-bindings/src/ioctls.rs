@@ -6,60 +6,99 @@ use crate::ack_bindings::*;
 #[cfg(not(feature = "ack-bindings"))]
 use crate::bindings::*;
 
-ioctl_write_int_bad!(gunyah_create_vm, request_code_none!(GUNYAH_IOCTL_TYPE, 0));
-ioctl_write_ptr!(
-    gunyah_vm_set_dtb_config,
+use std::mem::size_of;
+
+use nix::sys::ioctl::ioctl_num_type;
+
+// Request codes are public so that seccomp filters can allow individual ioctls
+pub const GUNYAH_CREATE_VM: ioctl_num_type = request_code_none!(GUNYAH_IOCTL_TYPE, 0);
+pub const GUNYAH_VM_SET_DTB_CONFIG: ioctl_num_type =
+    request_code_write!(GUNYAH_IOCTL_TYPE, 2, size_of::<gunyah_vm_dtb_config>());
+pub const GUNYAH_VM_START: ioctl_num_type = request_code_none!(GUNYAH_IOCTL_TYPE, 3);
+pub const GUNYAH_VM_ADD_FUNCTION: ioctl_num_type =
+    request_code_write!(GUNYAH_IOCTL_TYPE, 4, size_of::<gunyah_fn_desc>());
+pub const GUNYAH_VCPU_RUN: ioctl_num_type = request_code_none!(GUNYAH_IOCTL_TYPE, 5);
+pub const GUNYAH_VCPU_MMAP_SIZE: ioctl_num_type = request_code_none!(GUNYAH_IOCTL_TYPE, 6);
+pub const GUNYAH_VM_REMOVE_FUNCTION: ioctl_num_type =
+    request_code_write!(GUNYAH_IOCTL_TYPE, 7, size_of::<gunyah_fn_desc>());
+#[cfg(not(feature = "ack-bindings"))]
+pub const GUNYAH_CREATE_GUEST_MEM: ioctl_num_type =
+    request_code_write!(GUNYAH_IOCTL_TYPE, 8, size_of::<gunyah_create_mem_args>());
+#[cfg(not(feature = "ack-bindings"))]
+pub const GUNYAH_VM_MAP_MEM: ioctl_num_type =
+    request_code_write!(GUNYAH_IOCTL_TYPE, 9, size_of::<gunyah_map_mem_args>());
+#[cfg(feature = "ack-bindings")]
+pub const GUNYAH_VM_SET_USER_MEM_REGION: ioctl_num_type = request_code_write!(
     GUNYAH_IOCTL_TYPE,
-    2,
+    0x1,
+    size_of::<gunyah_userspace_memory_region>()
+);
+#[cfg(feature = "ack-bindings")]
+pub const GH_VM_ANDROID_LEND_USER_MEM: ioctl_num_type = request_code_write!(
+    GH_ANDROID_IOCTL_TYPE,
+    0x11,
+    size_of::<gunyah_userspace_memory_region>()
+);
+#[cfg(feature = "ack-bindings")]
+pub const GH_VM_ANDROID_SET_FW_CONFIG: ioctl_num_type = request_code_write!(
+    GH_ANDROID_IOCTL_TYPE,
+    0x12,
+    size_of::<gunyah_vm_firmware_config>()
+);
+pub const GUNYAH_VM_SET_BOOT_CONTEXT: ioctl_num_type =
+    request_code_write!(GUNYAH_IOCTL_TYPE, 0xa, size_of::<gunyah_vm_boot_context>());
+
+ioctl_write_int_bad!(gunyah_create_vm, GUNYAH_CREATE_VM);
+ioctl_write_ptr_bad!(
+    gunyah_vm_set_dtb_config,
+    GUNYAH_VM_SET_DTB_CONFIG,
     gunyah_vm_dtb_config
 );
-ioctl_none!(gunyah_vm_start, GUNYAH_IOCTL_TYPE, 3);
-ioctl_write_ptr!(gunyah_vm_add_function, GUNYAH_IOCTL_TYPE, 4, gunyah_fn_desc);
-ioctl_none!(gunyah_vcpu_run, GUNYAH_IOCTL_TYPE, 5);
-ioctl_none!(gunyah_vcpu_mmap_size, GUNYAH_IOCTL_TYPE, 6);
-ioctl_write_ptr!(
+ioctl_none_bad!(gunyah_vm_start, GUNYAH_VM_START);
+ioctl_write_ptr_bad!(
+    gunyah_vm_add_function,
+    GUNYAH_VM_ADD_FUNCTION,
+    gunyah_fn_desc
+);
+ioctl_none_bad!(gunyah_vcpu_run, GUNYAH_VCPU_RUN);
+ioctl_none_bad!(gunyah_vcpu_mmap_size, GUNYAH_VCPU_MMAP_SIZE);
+ioctl_write_ptr_bad!(
     gunyah_vm_remove_function,
-    GUNYAH_IOCTL_TYPE,
-    7,
+    GUNYAH_VM_REMOVE_FUNCTION,
     gunyah_fn_desc
 );
 
 #[cfg(not(feature = "ack-bindings"))]
-ioctl_write_ptr!(
+ioctl_write_ptr_bad!(
     gunyah_create_guest_mem,
-    GUNYAH_IOCTL_TYPE,
-    8,
+    GUNYAH_CREATE_GUEST_MEM,
     gunyah_create_mem_args
 );
 #[cfg(not(feature = "ack-bindings"))]
-ioctl_write_ptr!(gunyah_vm_map_mem, GUNYAH_IOCTL_TYPE, 9, gunyah_map_mem_args);
+ioctl_write_ptr_bad!(gunyah_vm_map_mem, GUNYAH_VM_MAP_MEM, gunyah_map_mem_args);
 
 #[cfg(feature = "ack-bindings")]
-ioctl_write_ptr!(
+ioctl_write_ptr_bad!(
     gunyah_vm_set_user_mem_region,
-    GUNYAH_IOCTL_TYPE,
-    0x1,
+    GUNYAH_VM_SET_USER_MEM_REGION,
     gunyah_userspace_memory_region
 );
 #[cfg(feature = "ack-bindings")]
-ioctl_write_ptr!(
+ioctl_write_ptr_bad!(
     gh_vm_android_lend_user_mem,
-    GH_ANDROID_IOCTL_TYPE,
-    0x11,
+    GH_VM_ANDROID_LEND_USER_MEM,
     gunyah_userspace_memory_region
 );
 #[cfg(feature = "ack-bindings")]
-ioctl_write_ptr!(
+ioctl_write_ptr_bad!(
     gh_vm_android_set_fw_config,
-    GH_ANDROID_IOCTL_TYPE,
-    0x12,
+    GH_VM_ANDROID_SET_FW_CONFIG,
     gunyah_vm_firmware_config
 );
 
-ioctl_write_ptr!(
+ioctl_write_ptr_bad!(
     gunyah_vm_set_boot_context,
-    GUNYAH_IOCTL_TYPE,
-    0xa,
+    GUNYAH_VM_SET_BOOT_CONTEXT,
     gunyah_vm_boot_context
 );
 
@@ -10,11 +10,32 @@ use std::{
 
 use anyhow::anyhow;
 use libc::{c_int, off_t};
-pub use memmap::Mmap;
-use memmap::{MmapMut, MmapOptions};
-use nix::unistd::dup;
+use memmap::MmapOptions;
+pub use memmap::{Mmap, MmapMut};
+pub use nix::fcntl::SealFlag;
+use nix::{
+    fcntl::{fcntl, FcntlArg},
+    unistd::dup,
+};
 use same_file::Handle;
 
+/// Size of the hugetlbfs pages backing guest memory, see
+/// [`crate::Gunyah::create_hugetlb_guest_memory`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HugePageSize {
+    Size2M,
+    Size1G,
+}
+
+impl HugePageSize {
+    pub fn bytes(self) -> usize {
+        match self {
+            Self::Size2M => 2 << 20,
+            Self::Size1G => 1 << 30,
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Hash)]
 pub struct GuestMem(Handle, #[cfg(feature = "ack-bindings")] bool);
 
@@ -31,6 +52,19 @@ impl GuestMem {
         nix::errno::Errno::result(res).map(drop)
     }
 
+    /// Adds `seals` to the memory, e.g. F_SEAL_SHRINK | F_SEAL_GROW so it can't be resized under
+    /// a running VM. Seals can't be removed again. F_SEAL_WRITE fails while the memory has writable
+    /// shared mappings. Fails with EINVAL if the memory doesn't support sealing, like guest memfds
+    /// of kernels without sealing support.
+    pub fn seal(&self, seals: SealFlag) -> nix::Result<()> {
+        fcntl(self.as_raw_fd(), FcntlArg::F_ADD_SEALS(seals)).map(drop)
+    }
+
+    /// Seals added with [`Self::seal`].
+    pub fn seals(&self) -> nix::Result<SealFlag> {
+        fcntl(self.as_raw_fd(), FcntlArg::F_GET_SEALS).map(SealFlag::from_bits_truncate)
+    }
+
     pub fn dup(&self) -> nix::Result<Self> {
         // SAFETY: Safe because fd our fd is a GuestMem and the resulting dup'd
         // fd is also a GuestMem
@@ -165,7 +199,7 @@ mod tests {
 
     use crate::gunyah::Gunyah;
 
-    use super::GuestMemRegion;
+    use super::{GuestMemRegion, SealFlag};
 
     macro_rules! mib {
         ($x:expr) => {
@@ -200,6 +234,33 @@ mod tests {
         assert_ok!(gmem.as_file().set_len(mib!(10)));
     }
 
+    #[test]
+    fn sealed() {
+        let gunyah = Gunyah::new().unwrap();
+        let gmem =
+            match gunyah.create_sealed_guest_memory(NonZeroUsize::new(mib!(4)).unwrap(), false) {
+                // Not all guest memfds can be sealed
+                Err(nix::Error::EINVAL) => return,
+                result => assert_ok!(result),
+            };
+        assert_ok_eq!(
+            gmem.seals(),
+            SealFlag::F_SEAL_SHRINK | SealFlag::F_SEAL_GROW
+        );
+        assert_err!(gmem.as_file().set_len(mib!(100)));
+        assert_err!(gmem.as_file().set_len(mib!(1)));
+        assert_ok!(gmem.as_file().set_len(mib!(4)));
+        assert_ok!(gmem.allocate(0, mib!(4)));
+
+        // Seals stay once added, and can't be added once sealed
+        assert_ok!(gmem.seal(SealFlag::F_SEAL_SEAL));
+        assert_err!(gmem.seal(SealFlag::F_SEAL_WRITE));
+        assert_ok_eq!(
+            gmem.seals(),
+            SealFlag::F_SEAL_SHRINK | SealFlag::F_SEAL_GROW | SealFlag::F_SEAL_SEAL
+        );
+    }
+
     #[test]
     fn punch_hole() {
         let gunyah = Gunyah::new().unwrap();
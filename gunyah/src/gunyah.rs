@@ -15,8 +15,8 @@ use nix::sys::stat::Mode;
 use nix::unistd::dup;
 use nix::NixPath;
 
-use crate::guest_mem::GuestMem;
-use crate::vm::Vm;
+use crate::guest_mem::{GuestMem, HugePageSize, SealFlag};
+use crate::vm::{Vm, VmType};
 use crate::Result;
 
 #[derive(Debug)]
@@ -122,19 +122,18 @@ impl Gunyah {
     ///
     /// See the documentation for `GUNYAH_CREATE_VM`.
     ///
-    /// * `vm_type` - Platform and architecture specific platform VM type. A value of 0 is the equivalent
-    ///               to using the default VM type.
+    /// * `vm_type` - Picks how the Resource Manager authenticates the VM, see [`VmType`].
     /// # Example
     ///
     /// ```
-    /// # use gunyah::Gunyah;
+    /// # use gunyah::{Gunyah, VmType};
     /// let gunyah = Gunyah::new().unwrap();
-    /// let vm = gunyah.create_vm_with_type(0).unwrap();
+    /// let vm = gunyah.create_vm_with_type(VmType::Unauthenticated).unwrap();
     /// ```
-    fn create_vm_with_type(&self, vm_type: i32) -> Result<Vm> {
+    pub fn create_vm_with_type(&self, vm_type: VmType) -> Result<Vm> {
         // SAFETY: Safe because we know `self.gunyah` is a real Gunyah fd as this module is the only one
         // that create Gunyah objects.
-        let ret = unsafe { gunyah_create_vm(self.gunyah.as_raw_fd(), vm_type) }?;
+        let ret = unsafe { gunyah_create_vm(self.gunyah.as_raw_fd(), vm_type.raw()) }?;
 
         // SAFETY: Safe because we know gunyah_create_vm returns a file descriptor and we know it
         // returned successfully
@@ -143,12 +142,10 @@ impl Gunyah {
         Ok(Vm::from(file))
     }
 
-    /// Creates an unauthenticated VM using the Gunyah fd of a specific type.
+    /// Creates an unauthenticated VM using the Gunyah fd.
     ///
     /// See the documentation for `GUNYAH_CREATE_VM`.
     ///
-    /// * `vm_type` - Platform and architecture specific platform VM type. A value of 0 is the equivalent
-    ///               to using the default VM type.
     /// # Example
     ///
     /// ```
@@ -157,7 +154,61 @@ impl Gunyah {
     /// let vm = gunyah.create_vm().unwrap();
     /// ```
     pub fn create_vm(&self) -> Result<Vm> {
-        self.create_vm_with_type(0)
+        self.create_vm_with_type(VmType::Unauthenticated)
+    }
+
+    /// Whether the kernel creates VMs of `vm_type`, found out by creating one and dropping it
+    /// again. Kernels reject types they don't know with EINVAL or EOPNOTSUPP, other errors are
+    /// returned.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use gunyah::{Gunyah, VmType};
+    /// let gunyah = Gunyah::new().unwrap();
+    /// assert!(gunyah.supports_vm_type(VmType::Unauthenticated).unwrap());
+    /// ```
+    pub fn supports_vm_type(&self, vm_type: VmType) -> Result<bool> {
+        match self.create_vm_with_type(vm_type) {
+            Ok(_) => Ok(true),
+            Err(nix::Error::EINVAL | nix::Error::EOPNOTSUPP) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The types of [`VmType::ALL`] the kernel creates VMs of, see [`Self::supports_vm_type`].
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use gunyah::{Gunyah, VmType};
+    /// let gunyah = Gunyah::new().unwrap();
+    /// let vm_types = gunyah.supported_vm_types().unwrap();
+    /// assert!(vm_types.contains(&VmType::Unauthenticated));
+    /// for vm_type in vm_types {
+    ///     gunyah.create_vm_with_type(vm_type).unwrap();
+    /// }
+    /// ```
+    pub fn supported_vm_types(&self) -> Result<Vec<VmType>> {
+        let mut vm_types = Vec::new();
+        for vm_type in VmType::ALL {
+            if self.supports_vm_type(vm_type)? {
+                vm_types.push(vm_type);
+            }
+        }
+        Ok(vm_types)
+    }
+
+    /// Like [`Self::create_guest_memory`], but the memory is sealed against shrinking and
+    /// growing, so its size can't change under a running VM. See [`GuestMem::seal`].
+    pub fn create_sealed_guest_memory(
+        &self,
+        size: NonZeroUsize,
+        huge_pages: bool,
+    ) -> Result<GuestMem> {
+        let mem = self.create_guest_memory(size, huge_pages)?;
+        mem.seal(SealFlag::F_SEAL_SHRINK | SealFlag::F_SEAL_GROW)?;
+        Ok(mem)
     }
 
     cfg_if! {
@@ -205,6 +256,16 @@ impl Gunyah {
             pub fn create_guest_memory_with_cloexec(&self, size: NonZeroUsize) -> Result<GuestMem> {
                 self.create_guest_memory_with_flags(size, gunyah_mem_flags::GHMF_CLOEXEC as u64)
             }
+
+            /// Guest memfds can't be backed by hugetlbfs, only transparent huge pages are
+            /// available with [`Self::create_guest_memory`].
+            pub fn create_hugetlb_guest_memory(
+                &self,
+                _size: NonZeroUsize,
+                _page_size: HugePageSize,
+            ) -> Result<GuestMem> {
+                Err(nix::Error::EOPNOTSUPP)
+            }
         } else {
             pub fn create_guest_memory(&self, size: NonZeroUsize, huge_pages: bool) -> Result<GuestMem> {
                 let size = u64::try_from(size.get()).map_err(|_| nix::Error::EINVAL)?;
@@ -222,6 +283,36 @@ impl Gunyah {
 
                 Ok(GuestMem::from(mfd.into_file()))
             }
+
+            /// Creates memory for Gunyah VMs from hugetlbfs pages of `page_size`, which must be
+            /// reserved on the host, e.g. through /sys/kernel/mm/hugepages. Unlike the transparent
+            /// huge pages of [`Self::create_guest_memory`], every page is a huge page, so `size`
+            /// must be a multiple of `page_size`.
+            pub fn create_hugetlb_guest_memory(
+                &self,
+                size: NonZeroUsize,
+                page_size: HugePageSize,
+            ) -> Result<GuestMem> {
+                if !size.get().is_multiple_of(page_size.bytes()) {
+                    return Err(nix::Error::EINVAL);
+                }
+                let opts = memfd::MemfdOptions::default()
+                    .allow_sealing(true)
+                    .hugetlb(Some(match page_size {
+                        HugePageSize::Size2M => memfd::HugetlbSize::Huge2MB,
+                        HugePageSize::Size1G => memfd::HugetlbSize::Huge1GB,
+                    }));
+                let mfd = opts.create("guest-mem-hugetlb").map_err(|e| match e {
+                    memfd::Error::Create(e) => e
+                        .raw_os_error()
+                        .map_or(nix::Error::UnknownErrno, nix::Error::from_i32),
+                    _ => nix::Error::UnknownErrno,
+                })?;
+                mfd.as_file()
+                    .set_len(size.get() as u64)
+                    .map_err(|e| e.raw_os_error().map_or(nix::Error::UnknownErrno, nix::Error::from_i32))?;
+                Ok(GuestMem::from_file(mfd.into_file(), true))
+            }
         }
     }
 
@@ -334,7 +425,7 @@ mod tests {
     #[test]
     fn create_vm_with_type() {
         let gunyah = Gunyah::new().unwrap();
-        gunyah.create_vm_with_type(0).unwrap();
+        gunyah.create_vm_with_type(VmType::Unauthenticated).unwrap();
     }
 
     #[test]
@@ -354,6 +445,19 @@ mod tests {
             .unwrap();
     }
 
+    #[test]
+    fn supported_vm_types() {
+        let gunyah = Gunyah::new().unwrap();
+        let vm_types = gunyah.supported_vm_types().unwrap();
+        assert_eq!(vm_types.first(), Some(&VmType::Unauthenticated));
+        for vm_type in VmType::ALL {
+            assert_eq!(
+                gunyah.supports_vm_type(vm_type).unwrap(),
+                vm_types.contains(&vm_type)
+            );
+        }
+    }
+
     #[test]
     #[cfg(not(feature = "ack-bindings"))]
     fn create_mem_with_cloexec() {
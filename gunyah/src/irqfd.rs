@@ -4,7 +4,7 @@
 use std::{
     fs::File,
     mem,
-    os::fd::{AsRawFd, FromRawFd},
+    os::fd::{AsRawFd, FromRawFd, RawFd},
 };
 
 use anyhow::{Context, Result};
@@ -80,6 +80,12 @@ impl Irqfd {
     }
 }
 
+impl AsRawFd for Irqfd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.eventfd.as_raw_fd()
+    }
+}
+
 impl Drop for Irqfd {
     fn drop(&mut self) {
         let mut flags = 0;
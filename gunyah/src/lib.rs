@@ -18,8 +18,12 @@ pub mod guest_mem;
 pub use guest_mem::*;
 pub mod vcpu;
 pub use vcpu::*;
+pub mod vcpu_exit;
+pub use vcpu_exit::*;
 pub mod vm;
 pub use vm::*;
+pub mod vm_builder;
+pub use vm_builder::*;
 pub mod ioeventfd;
 pub use ioeventfd::*;
 pub mod irqfd;
@@ -3,18 +3,22 @@
 
 use std::{
     fs::File,
-    mem::size_of,
+    mem::{offset_of, size_of},
     os::fd::{AsRawFd, FromRawFd, RawFd},
+    sync::atomic::{AtomicU8, Ordering},
 };
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use claim::assert_ge;
 use same_file::Handle;
 
 use gunyah_bindings::{gunyah_fn_vcpu_arg, gunyah_vcpu_mmap_size, gunyah_vcpu_run};
 use memmap::{MmapMut, MmapOptions};
 
-use crate::vm::{VcpuFunction, Vm};
+use crate::{
+    vm::{VcpuFunction, Vm},
+    ResumeAction, VcpuExit,
+};
 
 #[derive(Debug)]
 pub struct Vcpu {
@@ -63,6 +67,44 @@ impl Vcpu {
         unsafe { (self.mmap.as_mut_ptr() as *mut gunyah_vcpu_run).as_mut() }.unwrap()
     }
 
+    /// Why the last [`Self::run`] returned.
+    pub fn exit(&self) -> VcpuExit {
+        VcpuExit::from(self.mmap())
+    }
+
+    /// Completes the MMIO exit the last [`Self::run`] returned with, the next run resumes the guest
+    /// according to `action`. A read returns `data`, which must be as long as the access.
+    pub fn complete_mmio(&mut self, data: Option<&[u8]>, action: ResumeAction) -> Result<()> {
+        let VcpuExit::Mmio { is_write, len, .. } = self.exit() else {
+            bail!("vCPU didn't exit for mmio");
+        };
+        // SAFETY: Safe because we just checked exit_reason is GUNYAH_VCPU_EXIT_MMIO
+        let mmio = unsafe { &mut self.mmap_mut().__bindgen_anon_1.mmio };
+        if let Some(data) = data {
+            if is_write {
+                bail!("vCPU didn't exit for mmio read");
+            }
+            if data.len() != len {
+                bail!("vCPU length didn't match");
+            }
+            mmio.data[..len].copy_from_slice(data);
+        }
+        mmio.resume_action = action.into();
+        Ok(())
+    }
+
+    /// Completes the page fault exit the last [`Self::run`] returned with, the next run resumes
+    /// the guest according to `action`.
+    pub fn complete_page_fault(&mut self, action: ResumeAction) -> Result<()> {
+        if !matches!(self.exit(), VcpuExit::PageFault { .. }) {
+            bail!("vCPU didn't exit for a page fault");
+        }
+        // SAFETY: Safe because we just checked exit_reason is GUNYAH_VCPU_EXIT_PAGE_FAULT
+        let page_fault = unsafe { &mut self.mmap_mut().__bindgen_anon_1.page_fault };
+        page_fault.resume_action = action.into();
+        Ok(())
+    }
+
     pub fn run(&mut self) -> nix::Result<()> {
         // SAFETY: Safe because we know we are a vcpu fd
         unsafe { gunyah_vcpu_run(self.as_raw_fd()) }.map(|_| ())
@@ -71,6 +113,54 @@ impl Vcpu {
     pub fn id(&self) -> u32 {
         self.id
     }
+
+    /// Returns a handle which other threads can use to stop this vCPU from entering the guest,
+    /// even while [`Self::run`] is in progress.
+    pub fn kicker(&self) -> Result<VcpuKicker> {
+        // SAFETY: Safe because this is the same mapping as in Self::new
+        let mmap = unsafe {
+            MmapOptions::new()
+                .len(self.mmap.len())
+                .map_mut(self.vcpu.as_file())
+        }
+        .context("failed to mmap vcpu")?;
+        Ok(VcpuKicker { mmap })
+    }
+}
+
+/// Controls the `immediate_exit` flag of a [`Vcpu`] through a second mapping of its run
+/// structure.
+#[derive(Debug)]
+pub struct VcpuKicker {
+    mmap: MmapMut,
+}
+
+impl VcpuKicker {
+    fn immediate_exit(&self) -> &AtomicU8 {
+        // SAFETY: Safe because the mapping holds a gunyah_vcpu_run and the flag is only accessed
+        // atomically by us. The kernel only reads it.
+        unsafe {
+            &*(self
+                .mmap
+                .as_ptr()
+                .add(offset_of!(gunyah_vcpu_run, immediate_exit)) as *const AtomicU8)
+        }
+    }
+
+    /// Makes the current or next [`Vcpu::run`] return EINTR instead of entering the guest, until
+    /// [`Self::clear`] is called. A run which is already in the guest only notices once it is
+    /// interrupted, e.g. by a signal.
+    pub fn set(&self) {
+        self.immediate_exit().store(1, Ordering::SeqCst);
+    }
+
+    pub fn clear(&self) {
+        self.immediate_exit().store(0, Ordering::SeqCst);
+    }
+
+    pub fn is_set(&self) -> bool {
+        self.immediate_exit().load(Ordering::SeqCst) != 0
+    }
 }
 
 impl Drop for Vcpu {
@@ -124,6 +214,34 @@ mod tests {
             vcpu.mmap().exit_reason,
             gunyah_vcpu_exit::GUNYAH_VCPU_EXIT_UNKNOWN
         );
+        assert_eq!(
+            vcpu.exit(),
+            VcpuExit::Unknown(gunyah_vcpu_exit::GUNYAH_VCPU_EXIT_UNKNOWN)
+        );
+    }
+
+    #[test]
+    pub fn complete_without_exit() {
+        let gunyah = Gunyah::new().unwrap();
+        let vm = gunyah.create_vm().unwrap();
+
+        let mut vcpu = Vcpu::new(vm, 0).unwrap();
+        assert_err!(vcpu.complete_mmio(Some(&[0; 4]), ResumeAction::Handled));
+        assert_err!(vcpu.complete_page_fault(ResumeAction::Retry));
+    }
+
+    #[test]
+    pub fn kicker() {
+        let gunyah = Gunyah::new().unwrap();
+        let vm = gunyah.create_vm().unwrap();
+
+        let vcpu = Vcpu::new(vm, 0).unwrap();
+        let kicker = vcpu.kicker().unwrap();
+        kicker.set();
+        assert!(kicker.is_set());
+        assert_eq!(vcpu.mmap().immediate_exit, 1);
+        kicker.clear();
+        assert_eq!(vcpu.mmap().immediate_exit, 0);
     }
 
     #[test]
@@ -0,0 +1,162 @@
+// Copyright (c) 2024, Qualcomm Innovation Center, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause-Clear
+
+use gunyah_bindings::{
+    gunyah_vcpu_exit::{
+        GUNYAH_VCPU_EXIT_MMIO, GUNYAH_VCPU_EXIT_PAGE_FAULT, GUNYAH_VCPU_EXIT_STATUS,
+    },
+    gunyah_vcpu_resume_action::{
+        GUNYAH_VCPU_RESUME_FAULT, GUNYAH_VCPU_RESUME_HANDLED, GUNYAH_VCPU_RESUME_RETRY,
+    },
+    gunyah_vcpu_run, gunyah_vm_status,
+};
+
+/// Why [`crate::Vcpu::run`] returned, decoded from the vCPU's run structure.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VcpuExit {
+    /// The guest accessed an address without memory behind it. `data[..len]` holds the value of a
+    /// write, a read is answered with [`crate::Vcpu::complete_mmio`].
+    Mmio {
+        addr: u64,
+        data: [u8; 8],
+        is_write: bool,
+        len: usize,
+    },
+    /// The guest accessed memory it can't access right now, see
+    /// [`crate::Vcpu::complete_page_fault`].
+    PageFault { addr: u64, attempt: i32 },
+    /// The VM's status changed, e.g. it exited with a Resource Manager exit type.
+    Status {
+        status: gunyah_vm_status::Type,
+        exit_type: u16,
+    },
+    /// No exit yet, or an exit reason this crate doesn't know, which is kept.
+    Unknown(u32),
+}
+
+impl VcpuExit {
+    /// The raw `GUNYAH_VCPU_EXIT_*` reason.
+    pub fn reason(&self) -> u32 {
+        match self {
+            Self::Mmio { .. } => GUNYAH_VCPU_EXIT_MMIO,
+            Self::PageFault { .. } => GUNYAH_VCPU_EXIT_PAGE_FAULT,
+            Self::Status { .. } => GUNYAH_VCPU_EXIT_STATUS,
+            Self::Unknown(reason) => *reason,
+        }
+    }
+}
+
+impl From<&gunyah_vcpu_run> for VcpuExit {
+    fn from(run: &gunyah_vcpu_run) -> Self {
+        // SAFETY: Safe because the kernel fills in the union member matching exit_reason, which is
+        // the only one read, and all members are plain data valid for any bit pattern.
+        unsafe {
+            match run.exit_reason {
+                GUNYAH_VCPU_EXIT_MMIO => {
+                    let mmio = run.__bindgen_anon_1.mmio;
+                    Self::Mmio {
+                        addr: mmio.phys_addr,
+                        data: mmio.data,
+                        is_write: mmio.is_write != 0,
+                        len: (mmio.len as usize).min(mmio.data.len()),
+                    }
+                }
+                GUNYAH_VCPU_EXIT_PAGE_FAULT => {
+                    let page_fault = run.__bindgen_anon_1.page_fault;
+                    Self::PageFault {
+                        addr: page_fault.phys_addr,
+                        attempt: page_fault.attempt,
+                    }
+                }
+                GUNYAH_VCPU_EXIT_STATUS => {
+                    let status = run.__bindgen_anon_1.status;
+                    Self::Status {
+                        status: status.status,
+                        exit_type: status.exit_info.type_,
+                    }
+                }
+                reason => Self::Unknown(reason),
+            }
+        }
+    }
+}
+
+/// How the guest continues after an MMIO or page fault exit.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ResumeAction {
+    /// The access completed, a read with the data the VMM provided
+    #[default]
+    Handled,
+    /// The access faults in the guest
+    Fault,
+    /// The access is tried again
+    Retry,
+}
+
+impl From<ResumeAction> for u8 {
+    fn from(action: ResumeAction) -> Self {
+        let action = match action {
+            ResumeAction::Handled => GUNYAH_VCPU_RESUME_HANDLED,
+            ResumeAction::Fault => GUNYAH_VCPU_RESUME_FAULT,
+            ResumeAction::Retry => GUNYAH_VCPU_RESUME_RETRY,
+        };
+        action.try_into().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use gunyah_bindings::{
+        gunyah_vcpu_exit::{GUNYAH_VCPU_EXIT_MMIO, GUNYAH_VCPU_EXIT_STATUS},
+        gunyah_vcpu_run,
+        gunyah_vm_status::GUNYAH_VM_STATUS_EXITED,
+    };
+
+    use super::VcpuExit;
+
+    #[test]
+    fn decodes_run() {
+        // SAFETY: Safe because gunyah_vcpu_run is plain data
+        let mut run: gunyah_vcpu_run = unsafe { std::mem::zeroed() };
+        assert_eq!(VcpuExit::from(&run), VcpuExit::Unknown(0));
+
+        run.exit_reason = GUNYAH_VCPU_EXIT_MMIO;
+        run.__bindgen_anon_1.mmio.phys_addr = 0x1000;
+        run.__bindgen_anon_1.mmio.data = [1, 2, 3, 4, 5, 6, 7, 8];
+        run.__bindgen_anon_1.mmio.len = 4;
+        run.__bindgen_anon_1.mmio.is_write = 1;
+        let exit = VcpuExit::from(&run);
+        assert_eq!(
+            exit,
+            VcpuExit::Mmio {
+                addr: 0x1000,
+                data: [1, 2, 3, 4, 5, 6, 7, 8],
+                is_write: true,
+                len: 4
+            }
+        );
+        assert_eq!(exit.reason(), GUNYAH_VCPU_EXIT_MMIO);
+
+        // A bogus length can't make callers slice past the data
+        run.__bindgen_anon_1.mmio.len = 16;
+        assert!(matches!(
+            VcpuExit::from(&run),
+            VcpuExit::Mmio { len: 8, .. }
+        ));
+
+        run.exit_reason = GUNYAH_VCPU_EXIT_STATUS;
+        run.__bindgen_anon_1.status.status = GUNYAH_VM_STATUS_EXITED;
+        run.__bindgen_anon_1.status.exit_info.type_ = 2;
+        assert_eq!(
+            VcpuExit::from(&run),
+            VcpuExit::Status {
+                status: GUNYAH_VM_STATUS_EXITED,
+                exit_type: 2
+            }
+        );
+
+        run.exit_reason = 42;
+        assert_eq!(VcpuExit::from(&run), VcpuExit::Unknown(42));
+        assert_eq!(VcpuExit::from(&run).reason(), 42);
+    }
+}
@@ -11,12 +11,13 @@ use std::{
     os::fd::{AsRawFd, FromRawFd, RawFd},
 };
 
+use cfg_if::cfg_if;
 use gunyah_bindings::{
     gunyah_fn_desc, gunyah_fn_ioeventfd_arg, gunyah_fn_irqfd_arg, gunyah_fn_type,
     gunyah_fn_vcpu_arg, gunyah_map_flags, gunyah_vm_add_function, gunyah_vm_boot_context,
     gunyah_vm_boot_context_reg, gunyah_vm_boot_context_reg_id, gunyah_vm_dtb_config,
     gunyah_vm_remove_function, gunyah_vm_set_boot_context, gunyah_vm_set_dtb_config,
-    gunyah_vm_start,
+    gunyah_vm_start, GUNYAH_VM_BOOT_CONTEXT_REG_SHIFT,
 };
 #[cfg(feature = "ack-bindings")]
 use memmap::MmapMut;
@@ -25,7 +26,8 @@ use same_file::Handle;
 
 #[cfg(feature = "ack-bindings")]
 use gunyah_bindings::{
-    gh_vm_android_lend_user_mem, gunyah_userspace_memory_region, gunyah_vm_set_user_mem_region,
+    gh_vm_android_lend_user_mem, gh_vm_android_set_fw_config, gunyah_userspace_memory_region,
+    gunyah_vm_firmware_config, gunyah_vm_set_user_mem_region,
 };
 #[cfg(not(feature = "ack-bindings"))]
 use gunyah_bindings::{gunyah_map_mem_args, gunyah_vm_map_mem};
@@ -38,6 +40,90 @@ pub enum ShareType {
     Lend,
 }
 
+/// Register of the boot context the VM's vCPUs start with, see [`Vm::set_boot_context`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum BootReg {
+    /// General purpose register x0 to x31
+    X(u8),
+    Pc,
+    SpEl0,
+    SpEl1,
+}
+
+impl BootReg {
+    /// ID of the register in `GUNYAH_VM_SET_BOOT_CONTEXT`.
+    pub fn id(&self) -> u32 {
+        use gunyah_vm_boot_context_reg::{REG_SET_PC, REG_SET_SP, REG_SET_X};
+
+        match *self {
+            Self::X(idx) => gunyah_vm_boot_context_reg_id(REG_SET_X, idx),
+            Self::Pc => gunyah_vm_boot_context_reg_id(REG_SET_PC, 0),
+            Self::SpEl0 => gunyah_vm_boot_context_reg_id(REG_SET_SP, 0),
+            Self::SpEl1 => gunyah_vm_boot_context_reg_id(REG_SET_SP, 1),
+        }
+    }
+
+    /// The register with ID `id`, see [`Self::id`].
+    pub fn from_id(id: u32) -> Option<Self> {
+        use gunyah_vm_boot_context_reg::{REG_SET_PC, REG_SET_SP, REG_SET_X};
+
+        let idx = u8::try_from(id & 0xff).unwrap();
+        match (id >> GUNYAH_VM_BOOT_CONTEXT_REG_SHIFT, idx) {
+            (REG_SET_X, 0..=31) => Some(Self::X(idx)),
+            (REG_SET_PC, 0) => Some(Self::Pc),
+            (REG_SET_SP, 0) => Some(Self::SpEl0),
+            (REG_SET_SP, 1) => Some(Self::SpEl1),
+            _ => None,
+        }
+    }
+}
+
+/// Type of a VM passed to `GUNYAH_CREATE_VM`, which picks how the Resource Manager
+/// authenticates the VM's images before it runs.
+///
+/// The Android kernel passes the type on as the Resource Manager's authentication mechanism;
+/// mainline Gunyah only accepts [`VmType::Unauthenticated`]. Which types the kernel accepts can be
+/// probed with [`crate::Gunyah::supported_vm_types`].
+///
+/// # Example
+///
+/// ```
+/// # use gunyah::{Gunyah, VmType};
+/// let gunyah = Gunyah::new().unwrap();
+/// let vm_type = if gunyah.supports_vm_type(VmType::Pas).unwrap() {
+///     VmType::Pas
+/// } else {
+///     VmType::Unauthenticated
+/// };
+/// let vm = gunyah.create_vm_with_type(vm_type).unwrap();
+/// ```
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum VmType {
+    /// The images aren't authenticated
+    #[default]
+    Unauthenticated,
+    /// The images are authenticated by the Peripheral Authentication Service (PAS), like the
+    /// firmware of other subsystems
+    Pas,
+    /// Android protected VM, whose firmware authenticates the rest of the VM, see
+    /// [`Vm::set_firmware_config`]
+    Android,
+}
+
+impl VmType {
+    /// Every VM type, whether or not the kernel accepts it.
+    pub const ALL: [VmType; 3] = [Self::Unauthenticated, Self::Pas, Self::Android];
+
+    /// The type as passed to `GUNYAH_CREATE_VM`.
+    pub fn raw(self) -> i32 {
+        match self {
+            Self::Unauthenticated => 0,
+            Self::Pas => 1,
+            Self::Android => 2,
+        }
+    }
+}
+
 #[derive(Clone, Copy)]
 pub enum GuestMemoryAccess {
     R,
@@ -313,17 +399,39 @@ impl Vm {
         .and(Ok(()))
     }
 
-    fn set_boot_context(
-        &self,
-        reg_type: gunyah_vm_boot_context_reg::Type,
-        reg_idx: u8,
-        value: u64,
-    ) -> nix::Result<()> {
+    /// Tells Gunyah where the firmware of a protected VM is, which the Resource Manager
+    /// authenticates and boots first.
+    ///
+    /// Mainline Gunyah has no call for this, the Resource Manager finds the firmware through the
+    /// `firmware-address` of the VM's configuration in the DTB instead.
+    pub fn set_firmware_config(&self, guest_phys_addr: u64, size: u64) -> nix::Result<()> {
+        cfg_if! {
+            if #[cfg(feature = "ack-bindings")] {
+                // SAFETY: Safe because we know fd is a gunyah-vm and
+                // gh_vm_android_set_fw_config is a valid ioctl on gunyah-vm fds
+                unsafe {
+                    gh_vm_android_set_fw_config(
+                        self.as_raw_fd(),
+                        &gunyah_vm_firmware_config {
+                            guest_phys_addr,
+                            size,
+                        },
+                    )
+                }
+                .and(Ok(()))
+            } else {
+                let _ = (guest_phys_addr, size);
+                Ok(())
+            }
+        }
+    }
+
+    fn set_boot_context_id(&self, reg: u32, value: u64) -> nix::Result<()> {
         unsafe {
             gunyah_vm_set_boot_context(
                 self.as_raw_fd(),
                 &gunyah_vm_boot_context {
-                    reg: gunyah_vm_boot_context_reg_id(reg_type, reg_idx),
+                    reg,
                     value,
                     ..Default::default()
                 },
@@ -332,12 +440,18 @@ impl Vm {
         .and(Ok(()))
     }
 
+    /// Sets `reg` to `value` when the VM starts. Registers which aren't set start as Gunyah
+    /// resets them.
+    pub fn set_boot_context(&self, reg: BootReg, value: u64) -> nix::Result<()> {
+        self.set_boot_context_id(reg.id(), value)
+    }
+
     pub fn set_boot_pc(&self, value: u64) -> nix::Result<()> {
-        self.set_boot_context(gunyah_vm_boot_context_reg::REG_SET_PC, 0, value)
+        self.set_boot_context(BootReg::Pc, value)
     }
 
     pub fn set_boot_sp(&self, value: u64) -> nix::Result<()> {
-        self.set_boot_context(gunyah_vm_boot_context_reg::REG_SET_SP, 1, value)
+        self.set_boot_context(BootReg::SpEl1, value)
     }
 }
 
@@ -856,11 +970,37 @@ mod tests {
         let vm = gunyah.create_vm().unwrap();
 
         for i in 0..32 {
-            assert_ok!(vm.set_boot_context(REG_SET_X, i, 0xd00d));
+            assert_ok!(vm.set_boot_context(BootReg::X(i), 0xd00d));
+        }
+        assert_ok!(vm.set_boot_context(BootReg::X(0), u64::MAX));
+        assert_err!(vm.set_boot_context(BootReg::X(32), 0xd00d));
+        assert_ok!(vm.set_boot_context(BootReg::Pc, 0x8000_0000));
+        assert_err!(
+            vm.set_boot_context_id(gunyah_vm_boot_context_reg_id(REG_SET_PC, 1), 0x8000_0000)
+        );
+        assert_ok!(vm.set_boot_context(BootReg::SpEl0, 0x8000_1000));
+        assert_ok!(vm.set_boot_context(BootReg::SpEl1, 0x8000_2000));
+    }
+
+    #[test]
+    fn boot_reg_ids() {
+        assert_eq!(
+            BootReg::X(3).id(),
+            gunyah_vm_boot_context_reg_id(REG_SET_X, 3)
+        );
+        for reg in [
+            BootReg::X(0),
+            BootReg::X(31),
+            BootReg::Pc,
+            BootReg::SpEl0,
+            BootReg::SpEl1,
+        ] {
+            assert_eq!(BootReg::from_id(reg.id()), Some(reg));
         }
-        assert_ok!(vm.set_boot_context(REG_SET_X, 0, u64::MAX));
-        assert_err!(vm.set_boot_context(REG_SET_X, 32, 0xd00d));
-        assert_ok!(vm.set_boot_context(REG_SET_PC, 0, 0x8000_0000));
-        assert_err!(vm.set_boot_context(REG_SET_PC, 1, 0x8000_0000));
+        assert_eq!(BootReg::from_id(BootReg::X(32).id()), None);
+        assert_eq!(
+            BootReg::from_id(gunyah_vm_boot_context_reg_id(REG_SET_PC, 1)),
+            None
+        );
     }
 }
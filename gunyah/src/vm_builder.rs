@@ -0,0 +1,269 @@
+// Copyright (c) 2024, Qualcomm Innovation Center, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause-Clear
+
+use anyhow::{Context, Result};
+
+use crate::{
+    BootReg, GuestMemRegion, GuestMemoryAccess, Gunyah, Ioeventfd, Irqfd, ShareType, Vcpu, Vm,
+    VmType,
+};
+
+struct MemoryMapping {
+    guest_addr: u64,
+    share_type: ShareType,
+    access: GuestMemoryAccess,
+    region: GuestMemRegion,
+}
+
+struct IoeventfdConfig {
+    addr: u64,
+    len: u32,
+    datamatch: Option<u64>,
+}
+
+/// Configures a [`Vm`] and sets it up in the order Gunyah expects: memory is mapped before the
+/// DTB and firmware configuration which point into it, and everything is set up before the VM can
+/// be started through the returned [`ReadyVm`].
+///
+/// # Example
+///
+/// ```no_run
+/// # use std::num::NonZeroUsize;
+/// # use gunyah::{BootReg, GuestMemRegion, GuestMemoryAccess, Gunyah, ShareType, VmBuilder};
+/// let gunyah = Gunyah::new().unwrap();
+/// let size = NonZeroUsize::new(0x10_0000).unwrap();
+/// let mem = gunyah.create_guest_memory(size, false).unwrap();
+/// let region = GuestMemRegion::new(mem, 0, size).unwrap();
+/// // The DTB has to be written to the region before the VM starts
+/// let vm = VmBuilder::new(&gunyah, 0x800f_0000, 0x1000)
+///     .memory(0x8000_0000, ShareType::Lend, GuestMemoryAccess::Rwx, region)
+///     .vcpu(0)
+///     .boot_reg(BootReg::Pc, 0x8000_0000)
+///     .boot_reg(BootReg::X(0), 0x800f_0000)
+///     .build()
+///     .unwrap();
+/// vm.start().unwrap();
+/// ```
+pub struct VmBuilder {
+    gunyah: Gunyah,
+    vm_type: VmType,
+    memory: Vec<MemoryMapping>,
+    dtb: (u64, u64),
+    firmware: Option<(u64, u64)>,
+    boot_regs: Vec<(BootReg, u64)>,
+    vcpus: Vec<u32>,
+    ioeventfds: Vec<IoeventfdConfig>,
+    irqfds: Vec<(u32, bool)>,
+}
+
+impl VmBuilder {
+    /// A VM whose DTB is the `dtb_size` bytes at `dtb_addr`, which must be in its memory.
+    pub fn new(gunyah: &Gunyah, dtb_addr: u64, dtb_size: u64) -> Self {
+        Self {
+            gunyah: gunyah.clone(),
+            vm_type: VmType::default(),
+            memory: Vec::new(),
+            dtb: (dtb_addr, dtb_size),
+            firmware: None,
+            boot_regs: Vec::new(),
+            vcpus: Vec::new(),
+            ioeventfds: Vec::new(),
+            irqfds: Vec::new(),
+        }
+    }
+
+    /// How the Resource Manager authenticates the VM (default: [`VmType::Unauthenticated`]).
+    pub fn vm_type(mut self, vm_type: VmType) -> Self {
+        self.vm_type = vm_type;
+        self
+    }
+
+    /// Maps `region` into the VM at `guest_addr`.
+    pub fn memory(
+        mut self,
+        guest_addr: u64,
+        share_type: ShareType,
+        access: GuestMemoryAccess,
+        region: GuestMemRegion,
+    ) -> Self {
+        self.memory.push(MemoryMapping {
+            guest_addr,
+            share_type,
+            access,
+            region,
+        });
+        self
+    }
+
+    /// Firmware of the VM, the `size` bytes at `addr`, see [`Vm::set_firmware_config`].
+    pub fn firmware(mut self, addr: u64, size: u64) -> Self {
+        self.firmware = Some((addr, size));
+        self
+    }
+
+    /// Starts the VM with `reg` set to `value`, see [`Vm::set_boot_context`].
+    pub fn boot_reg(mut self, reg: BootReg, value: u64) -> Self {
+        self.boot_regs.push((reg, value));
+        self
+    }
+
+    /// Adds vCPU `id`.
+    pub fn vcpu(mut self, id: u32) -> Self {
+        self.vcpus.push(id);
+        self
+    }
+
+    /// Adds an ioeventfd for `len` byte writes to `addr`, see [`Ioeventfd::new`].
+    pub fn ioeventfd(mut self, addr: u64, len: u32, datamatch: Option<u64>) -> Self {
+        self.ioeventfds.push(IoeventfdConfig {
+            addr,
+            len,
+            datamatch,
+        });
+        self
+    }
+
+    /// Adds an irqfd for the interrupt with `label`, see [`Irqfd::new`].
+    pub fn irqfd(mut self, label: u32, level: bool) -> Self {
+        self.irqfds.push((label, level));
+        self
+    }
+
+    /// Creates the VM and sets it up. The error says which step failed.
+    pub fn build(self) -> Result<ReadyVm> {
+        let mut vm = self
+            .gunyah
+            .create_vm_with_type(self.vm_type)
+            .context("Failed to create VM")?;
+        for mapping in &self.memory {
+            vm.map_memory(
+                mapping.guest_addr,
+                mapping.share_type,
+                mapping.access,
+                &mapping.region,
+            )
+            .context(format!("Failed to map memory at {:#x}", mapping.guest_addr))?;
+        }
+        let (dtb_addr, dtb_size) = self.dtb;
+        vm.set_dtb_config(dtb_addr, dtb_size)
+            .context("Failed to set DTB configuration")?;
+        if let Some((addr, size)) = self.firmware {
+            vm.set_firmware_config(addr, size)
+                .context("Failed to set firmware configuration")?;
+        }
+        for (reg, value) in self.boot_regs {
+            vm.set_boot_context(reg, value)
+                .context(format!("Failed to set boot register {:?}", reg))?;
+        }
+        let vcpus = self
+            .vcpus
+            .iter()
+            .map(|&id| Vcpu::new(vm.clone(), id).context(format!("Failed to create vCPU {}", id)))
+            .collect::<Result<_>>()?;
+        let ioeventfds = self
+            .ioeventfds
+            .iter()
+            .map(|config| {
+                Ioeventfd::new(vm.clone(), config.addr, config.len, config.datamatch)
+                    .context(format!("Failed to add ioeventfd at {:#x}", config.addr))
+            })
+            .collect::<Result<_>>()?;
+        let irqfds = self
+            .irqfds
+            .iter()
+            .map(|&(label, level)| {
+                Irqfd::new(vm.clone(), label, level)
+                    .context(format!("Failed to add irqfd {}", label))
+            })
+            .collect::<Result<_>>()?;
+        Ok(ReadyVm {
+            vm,
+            memory: self.memory.into_iter().map(|m| m.region).collect(),
+            vcpus,
+            ioeventfds,
+            irqfds,
+        })
+    }
+}
+
+/// A VM set up by [`VmBuilder`], along with the functions added to it, in the order they were
+/// added. Dropping it removes the functions.
+#[derive(Debug)]
+pub struct ReadyVm {
+    vm: Vm,
+    memory: Vec<GuestMemRegion>,
+    pub vcpus: Vec<Vcpu>,
+    pub ioeventfds: Vec<Ioeventfd>,
+    pub irqfds: Vec<Irqfd>,
+}
+
+impl ReadyVm {
+    pub fn start(&self) -> nix::Result<()> {
+        self.vm.start()
+    }
+
+    pub fn vm(&self) -> &Vm {
+        &self.vm
+    }
+
+    /// The memory regions mapped into the VM, in the order they were added.
+    pub fn memory(&self) -> &[GuestMemRegion] {
+        &self.memory
+    }
+}
+
+#[cfg(all(test, not(feature = "ack-bindings")))]
+mod tests {
+    use std::num::NonZeroUsize;
+
+    use claim::*;
+
+    use crate::{BootReg, GuestMemRegion, GuestMemoryAccess, Gunyah, ShareType, VmBuilder};
+
+    fn region(gunyah: &Gunyah) -> GuestMemRegion {
+        let size = NonZeroUsize::new(0x10000).unwrap();
+        let mem = gunyah.create_guest_memory(size, false).unwrap();
+        GuestMemRegion::new(mem, 0, size).unwrap()
+    }
+
+    #[test]
+    fn builds() {
+        let gunyah = Gunyah::new().unwrap();
+        let vm = assert_ok!(VmBuilder::new(&gunyah, 0x8000_f000, 0x1000)
+            .memory(
+                0x8000_0000,
+                ShareType::Lend,
+                GuestMemoryAccess::Rwx,
+                region(&gunyah)
+            )
+            .boot_reg(BootReg::Pc, 0x8000_0000)
+            .boot_reg(BootReg::X(0), 0x8000_f000)
+            .vcpu(0)
+            .vcpu(1)
+            .ioeventfd(0x9000, 4, None)
+            .irqfd(0, false)
+            .build());
+        assert_eq!(vm.vcpus.iter().map(|v| v.id()).collect::<Vec<_>>(), [0, 1]);
+        assert_eq!(vm.ioeventfds.len(), 1);
+        assert_eq!(vm.irqfds[0].label(), 0);
+        assert_eq!(vm.memory().len(), 1);
+    }
+
+    #[test]
+    fn reports_failed_step() {
+        let gunyah = Gunyah::new().unwrap();
+        let result = VmBuilder::new(&gunyah, 0x8000_f000, 0x1000)
+            .memory(
+                0x8000_0000,
+                ShareType::Lend,
+                GuestMemoryAccess::Rwx,
+                region(&gunyah),
+            )
+            .boot_reg(BootReg::X(32), 0)
+            .build();
+        assert_eq!(
+            assert_err!(result).to_string(),
+            "Failed to set boot register X(32)"
+        );
+    }
+}
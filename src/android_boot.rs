@@ -0,0 +1,177 @@
+// Copyright (c) 2024, Qualcomm Innovation Center, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause-Clear
+
+//! Android boot images as built by mkbootimg, header versions 0 to 4.
+
+use anyhow::{anyhow, Result};
+
+const BOOT_MAGIC: &[u8; 8] = b"ANDROID!";
+/// Offset of `header_version`, which is the same in all versions
+const HEADER_VERSION_OFFSET: usize = 40;
+/// Versions 3 and later always use 4 KiB pages
+const V3_PAGE_SIZE: usize = 4096;
+
+/// Command line fields of version 0 to 2 headers
+const V0_CMDLINE_OFFSET: usize = 64;
+const V0_CMDLINE_SIZE: usize = 512;
+const V0_EXTRA_CMDLINE_OFFSET: usize = 608;
+const V0_EXTRA_CMDLINE_SIZE: usize = 1024;
+const V0_HEADER_SIZE: usize = V0_EXTRA_CMDLINE_OFFSET + V0_EXTRA_CMDLINE_SIZE;
+/// Command line field of version 3 and 4 headers
+const V3_CMDLINE_OFFSET: usize = 44;
+const V3_CMDLINE_SIZE: usize = 1536;
+
+/// Contents of an Android boot image.
+#[derive(Debug, PartialEq, Eq)]
+pub struct AndroidBootImage<'a> {
+    pub header_version: u32,
+    pub kernel: &'a [u8],
+    pub ramdisk: &'a [u8],
+    pub cmdline: String,
+    /// Load addresses requested by version 0 to 2 headers
+    pub kernel_addr: Option<u64>,
+    pub ramdisk_addr: Option<u64>,
+}
+
+impl<'a> AndroidBootImage<'a> {
+    /// Splits `data` into its parts, or returns None if it isn't an Android boot image.
+    pub fn parse(data: &'a [u8]) -> Result<Option<Self>> {
+        if !data.starts_with(BOOT_MAGIC) {
+            return Ok(None);
+        }
+        let header_version = read_u32(data, HEADER_VERSION_OFFSET)?;
+        let image = match header_version {
+            0..=2 => {
+                let page_size = usize::try_from(read_u32(data, 36)?)?;
+                if page_size == 0 {
+                    return Err(anyhow!("Boot image has a page size of 0"));
+                }
+                let kernel_size = read_u32(data, 8)?;
+                let ramdisk_size = read_u32(data, 16)?;
+                let mut cmdline = read_str(data, V0_CMDLINE_OFFSET, V0_CMDLINE_SIZE)?;
+                cmdline += &read_str(data, V0_EXTRA_CMDLINE_OFFSET, V0_EXTRA_CMDLINE_SIZE)?;
+                let kernel = section(data, page_size, V0_HEADER_SIZE, 0, kernel_size)?;
+                Self {
+                    header_version,
+                    kernel,
+                    ramdisk: section(data, page_size, V0_HEADER_SIZE, kernel.len(), ramdisk_size)?,
+                    cmdline,
+                    kernel_addr: Some(read_u32(data, 12)?.into()),
+                    ramdisk_addr: Some(read_u32(data, 20)?.into()),
+                }
+            }
+            3 | 4 => {
+                let kernel_size = read_u32(data, 8)?;
+                let ramdisk_size = read_u32(data, 12)?;
+                let header_size = usize::try_from(read_u32(data, 20)?)?;
+                let kernel = section(data, V3_PAGE_SIZE, header_size, 0, kernel_size)?;
+                Self {
+                    header_version,
+                    kernel,
+                    ramdisk: section(data, V3_PAGE_SIZE, header_size, kernel.len(), ramdisk_size)?,
+                    cmdline: read_str(data, V3_CMDLINE_OFFSET, V3_CMDLINE_SIZE)?,
+                    kernel_addr: None,
+                    ramdisk_addr: None,
+                }
+            }
+            v => return Err(anyhow!("Unsupported boot image header version {}", v)),
+        };
+        Ok(Some(image))
+    }
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32> {
+    let bytes = data
+        .get(offset..offset + 4)
+        .ok_or(anyhow!("Boot image header is truncated"))?;
+    Ok(u32::from_le_bytes(bytes.try_into()?))
+}
+
+/// Reads a NUL terminated string field.
+fn read_str(data: &[u8], offset: usize, size: usize) -> Result<String> {
+    let field = data
+        .get(offset..offset + size)
+        .ok_or(anyhow!("Boot image header is truncated"))?;
+    let len = field.iter().position(|b| *b == 0).unwrap_or(size);
+    Ok(String::from_utf8_lossy(&field[..len]).into_owned())
+}
+
+/// Returns the section of `size` bytes following the header and the section of `previous` bytes
+/// before it. Every part of the image starts on a new page.
+fn section(
+    data: &[u8],
+    page_size: usize,
+    header_size: usize,
+    previous: usize,
+    size: u32,
+) -> Result<&[u8]> {
+    let start = header_size.next_multiple_of(page_size) + previous.next_multiple_of(page_size);
+    data.get(start..start + usize::try_from(size)?)
+        .ok_or(anyhow!("Boot image is truncated"))
+}
+
+#[cfg(test)]
+mod tests {
+    use claim::{assert_err, assert_none, assert_ok, assert_some};
+
+    use super::AndroidBootImage;
+
+    fn put_u32(data: &mut [u8], offset: usize, value: u32) {
+        data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    #[test]
+    fn version_2() {
+        let page_size = 2048;
+        let mut data = vec![0u8; 5 * page_size];
+        data[..8].copy_from_slice(b"ANDROID!");
+        put_u32(&mut data, 8, 3000);
+        put_u32(&mut data, 12, 0x1000_8000);
+        put_u32(&mut data, 16, 10);
+        put_u32(&mut data, 20, 0x1100_0000);
+        put_u32(&mut data, 36, page_size as u32);
+        put_u32(&mut data, 40, 2);
+        data[64..73].copy_from_slice(b"console=a");
+        data[608..614].copy_from_slice(b" quiet");
+        // Kernel in pages 1 and 2, ramdisk in page 3
+        data[page_size] = 0xaa;
+        data[3 * page_size..3 * page_size + 10].fill(0xbb);
+
+        let image = assert_some!(assert_ok!(AndroidBootImage::parse(&data)));
+        assert_eq!(image.header_version, 2);
+        assert_eq!(image.kernel.len(), 3000);
+        assert_eq!(image.kernel[0], 0xaa);
+        assert_eq!(image.ramdisk, [0xbb; 10]);
+        assert_eq!(image.cmdline, "console=a quiet");
+        assert_eq!(image.kernel_addr, Some(0x1000_8000));
+        assert_eq!(image.ramdisk_addr, Some(0x1100_0000));
+
+        assert_err!(AndroidBootImage::parse(&data[..3 * page_size]));
+        put_u32(&mut data, 36, 0);
+        assert_err!(AndroidBootImage::parse(&data));
+    }
+
+    #[test]
+    fn version_4() {
+        let mut data = vec![0u8; 3 * 4096];
+        data[..8].copy_from_slice(b"ANDROID!");
+        put_u32(&mut data, 8, 4096);
+        put_u32(&mut data, 12, 5);
+        put_u32(&mut data, 20, 1584);
+        put_u32(&mut data, 40, 4);
+        data[44..51].copy_from_slice(b"init=/x");
+        data[4096] = 0xaa;
+        data[2 * 4096..2 * 4096 + 5].fill(0xbb);
+
+        let image = assert_some!(assert_ok!(AndroidBootImage::parse(&data)));
+        assert_eq!(image.kernel.len(), 4096);
+        assert_eq!(image.kernel[0], 0xaa);
+        assert_eq!(image.ramdisk, [0xbb; 5]);
+        assert_eq!(image.cmdline, "init=/x");
+        assert_eq!(image.kernel_addr, None);
+
+        put_u32(&mut data, 40, 5);
+        assert_err!(AndroidBootImage::parse(&data));
+        assert_none!(assert_ok!(AndroidBootImage::parse(&[0u8; 4096])));
+    }
+}
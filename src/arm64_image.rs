@@ -0,0 +1,118 @@
+// Copyright (c) 2024, Qualcomm Innovation Center, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause-Clear
+
+//! Header of arm64 Linux `Image` files, see Documentation/arch/arm64/booting.rst.
+
+use anyhow::{anyhow, Result};
+
+const HEADER_SIZE: usize = 64;
+const MAGIC_OFFSET: usize = 56;
+const MAGIC: &[u8; 4] = b"ARM\x64";
+
+/// The kernel is loaded `text_offset` bytes after an address with this alignment.
+pub const ARM64_IMAGE_ALIGN: u64 = 0x20_0000;
+/// `text_offset` of kernels older than v3.17, whose `image_size` is 0
+const LEGACY_TEXT_OFFSET: u64 = 0x8_0000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Arm64ImageHeader {
+    pub text_offset: u64,
+    /// Memory the kernel uses from its load address, including its BSS. 0 if unknown.
+    pub image_size: u64,
+    pub flags: u64,
+}
+
+impl Arm64ImageHeader {
+    /// Returns the header of `image`, or None if it isn't an arm64 Linux `Image`.
+    pub fn parse(image: &[u8]) -> Option<Self> {
+        if image.len() < HEADER_SIZE || &image[MAGIC_OFFSET..MAGIC_OFFSET + 4] != MAGIC {
+            return None;
+        }
+        let field =
+            |offset: usize| u64::from_le_bytes(image[offset..offset + 8].try_into().unwrap());
+        let header = Self {
+            text_offset: field(8),
+            image_size: field(16),
+            flags: field(24),
+        };
+        Some(if header.image_size == 0 {
+            Self {
+                text_offset: LEGACY_TEXT_OFFSET,
+                ..header
+            }
+        } else {
+            header
+        })
+    }
+
+    /// Lowest address the kernel can be loaded at in memory starting at `mem_base`.
+    pub fn load_address(&self, mem_base: u64) -> u64 {
+        mem_base.next_multiple_of(ARM64_IMAGE_ALIGN) + self.text_offset
+    }
+
+    /// Checks that the kernel can run when loaded at `addr`.
+    pub fn check_load_address(&self, addr: u64) -> Result<()> {
+        match addr.checked_sub(self.text_offset) {
+            Some(base) if base.is_multiple_of(ARM64_IMAGE_ALIGN) => Ok(()),
+            _ => Err(anyhow!(
+                "The kernel must be loaded {:#x} bytes after a {:#x} aligned address, not at {:#x}",
+                self.text_offset,
+                ARM64_IMAGE_ALIGN,
+                addr
+            )),
+        }
+    }
+
+    /// Memory the kernel uses from its load address when the file is `file_len` bytes long.
+    pub fn effective_size(&self, file_len: u64) -> u64 {
+        self.image_size.max(file_len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use claim::{assert_err, assert_none, assert_ok, assert_some};
+
+    use super::Arm64ImageHeader;
+
+    fn image(text_offset: u64, image_size: u64) -> Vec<u8> {
+        let mut image = vec![0u8; 0x1000];
+        image[8..16].copy_from_slice(&text_offset.to_le_bytes());
+        image[16..24].copy_from_slice(&image_size.to_le_bytes());
+        image[24..32].copy_from_slice(&0xau64.to_le_bytes());
+        image[56..60].copy_from_slice(b"ARM\x64");
+        image
+    }
+
+    #[test]
+    fn parse() {
+        let header = assert_some!(Arm64ImageHeader::parse(&image(0, 0x200_0000)));
+        assert_eq!(
+            header,
+            Arm64ImageHeader {
+                text_offset: 0,
+                image_size: 0x200_0000,
+                flags: 0xa,
+            }
+        );
+        assert_eq!(header.effective_size(0x1000), 0x200_0000);
+
+        // Old kernels don't fill in text_offset
+        let header = assert_some!(Arm64ImageHeader::parse(&image(0x1234, 0)));
+        assert_eq!(header.text_offset, 0x8_0000);
+        assert_eq!(header.effective_size(0x1000), 0x1000);
+
+        assert_none!(Arm64ImageHeader::parse(&[0u8; 0x1000]));
+        assert_none!(Arm64ImageHeader::parse(&image(0, 0x1000)[..60]));
+    }
+
+    #[test]
+    fn placement() {
+        let header = assert_some!(Arm64ImageHeader::parse(&image(0x8_0000, 0x1000)));
+        assert_eq!(header.load_address(0x8000_0000), 0x8008_0000);
+        assert_eq!(header.load_address(0x8010_0000), 0x8028_0000);
+        assert_ok!(header.check_load_address(0x8028_0000));
+        assert_err!(header.check_load_address(0x8000_0000));
+        assert_err!(header.check_load_address(0x4_0000));
+    }
+}
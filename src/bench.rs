@@ -0,0 +1,308 @@
+// Copyright (c) 2024, Qualcomm Innovation Center, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause-Clear
+
+//! `bench`: measurements of the hypervisor interface against holding cell guests, see
+//! [`vmm::HoldingCell`], printed as one JSON object per benchmark so runs can be compared.
+//!
+//! - `vcpu-round-trip`: entering the guest until its next exit, from the `ack` command which
+//!   reads the command word and writes the result
+//! - `mmio-exit`: an MMIO read exit whose data the VMM provides, from the `read_io` command
+//! - `lend`: the guest touching every page of lent memory, which the host pages in on demand
+//! - `irqfd`: triggering an irqfd until the guest, waiting in WFI, acknowledged the interrupt
+//!   and exited to report it
+
+use std::{
+    num::NonZeroUsize,
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Context, Result};
+use gunyah::{GuestMemoryAccess, ShareType};
+use serde_json::{json, Value};
+use vmm::{HoldingCell, COMMAND_ADDR};
+
+/// Where the `lend` benchmark adds its memory
+const LEND_ADDR: u64 = 0xa000_0000;
+/// MMIO address the `mmio-exit` benchmark reads, outside of the holding cell's memory
+const MMIO_EXIT_ADDR: u64 = COMMAND_ADDR + 0x100;
+/// SPI the `irqfd` benchmark triggers
+const IRQFD_LINE: u32 = 5;
+/// How long the `irqfd` benchmark leaves the guest to start waiting before triggering
+const IRQFD_SETTLE: Duration = Duration::from_millis(1);
+
+/// A benchmark of `bench`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Benchmark {
+    VcpuRoundTrip,
+    MmioExit,
+    Lend,
+    Irqfd,
+}
+
+impl Benchmark {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::VcpuRoundTrip => "vcpu-round-trip",
+            Self::MmioExit => "mmio-exit",
+            Self::Lend => "lend",
+            Self::Irqfd => "irqfd",
+        }
+    }
+
+    /// Runs the benchmark, each in a new holding cell VM.
+    pub fn run(&self, config: &BenchConfig) -> Result<Vec<BenchResult>> {
+        match self {
+            Self::VcpuRoundTrip => {
+                let hc = started_holding_cell(|_| Ok(()))?;
+                // ack exits twice: for the command word and the result
+                let stats = sample_latency(config.iterations, 2, || hc.ack_ok(0))?;
+                Ok(vec![BenchResult::Latency {
+                    name: self.name(),
+                    stats,
+                }])
+            }
+            Self::MmioExit => {
+                let hc = started_holding_cell(|_| Ok(()))?;
+                // read_io exits four times: for the command word, the address, the read and the
+                // result
+                let stats = sample_latency(config.iterations, 4, || {
+                    hc.read_io(0, MMIO_EXIT_ADDR, 0).map(|_| ())
+                })?;
+                Ok(vec![BenchResult::Latency {
+                    name: self.name(),
+                    stats,
+                }])
+            }
+            Self::Lend => config
+                .lend_sizes
+                .iter()
+                .map(|&size| self.lend(size, config.huge_pages))
+                .collect(),
+            Self::Irqfd => Ok(vec![BenchResult::Latency {
+                name: self.name(),
+                stats: irqfd_latency(config.iterations)?,
+            }]),
+        }
+    }
+
+    fn lend(&self, size: NonZeroUsize, huge_pages: bool) -> Result<BenchResult> {
+        // Memory can only be added before the VM starts
+        let hc = started_holding_cell(|hc| {
+            hc.vm
+                .add_memory(
+                    LEND_ADDR,
+                    size,
+                    ShareType::Lend,
+                    GuestMemoryAccess::Rw,
+                    huge_pages,
+                )
+                .context(format!("Failed to lend {:#x} bytes", size))?;
+            Ok(())
+        })?;
+        let start = Instant::now();
+        hc.run_immediately(0, 7, &[LEND_ADDR, size.get() as u64])?;
+        Ok(BenchResult::Throughput {
+            name: self.name(),
+            bytes: size.get() as u64,
+            time: start.elapsed(),
+        })
+    }
+}
+
+/// Distribution of the latency of an operation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LatencyStats {
+    pub samples: usize,
+    pub min: Duration,
+    pub median: Duration,
+    pub mean: Duration,
+    /// 99th percentile
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl LatencyStats {
+    /// Summarizes `samples`, None if there are none.
+    pub fn new(mut samples: Vec<Duration>) -> Option<Self> {
+        if samples.is_empty() {
+            return None;
+        }
+        samples.sort();
+        let percentile = |p: usize| samples[(samples.len() * p).div_ceil(100).max(1) - 1];
+        Some(Self {
+            samples: samples.len(),
+            min: samples[0],
+            median: percentile(50),
+            mean: samples.iter().sum::<Duration>() / samples.len() as u32,
+            p99: percentile(99),
+            max: samples[samples.len() - 1],
+        })
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "samples": self.samples,
+            "min_ns": self.min.as_nanos(),
+            "median_ns": self.median.as_nanos(),
+            "mean_ns": self.mean.as_nanos(),
+            "p99_ns": self.p99.as_nanos(),
+            "max_ns": self.max.as_nanos(),
+        })
+    }
+}
+
+/// Outcome of one benchmark.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BenchResult {
+    Latency {
+        name: &'static str,
+        stats: LatencyStats,
+    },
+    Throughput {
+        name: &'static str,
+        bytes: u64,
+        time: Duration,
+    },
+}
+
+impl BenchResult {
+    /// The result as printed by `bench`.
+    pub fn to_json(&self) -> Value {
+        match self {
+            Self::Latency { name, stats } => {
+                let mut result = stats.to_json();
+                result["benchmark"] = json!(name);
+                result
+            }
+            Self::Throughput { name, bytes, time } => json!({
+                "benchmark": name,
+                "bytes": bytes,
+                "time_ns": time.as_nanos(),
+                "mib_per_s": *bytes as f64 / 1048576.0 / time.as_secs_f64(),
+            }),
+        }
+    }
+}
+
+/// Options of `bench`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BenchConfig {
+    /// Samples taken by latency benchmarks
+    pub iterations: u32,
+    /// Memory sizes the `lend` benchmark is run with
+    pub lend_sizes: Vec<NonZeroUsize>,
+    /// Back lent memory with transparent huge pages
+    pub huge_pages: bool,
+}
+
+/// A holding cell which already ran a command, so starting the VM isn't measured. `setup`
+/// prepares the VM before it starts.
+fn started_holding_cell(setup: impl FnOnce(&mut HoldingCell) -> Result<()>) -> Result<HoldingCell> {
+    let mut hc = HoldingCell::builder()
+        .build()
+        .context("Failed to create holding cell")?;
+    setup(&mut hc)?;
+    hc.ack_ok(0).context("Holding cell didn't start")?;
+    Ok(hc)
+}
+
+/// Times `iterations` runs of `op`, which exits from the guest `exits` times.
+fn sample_latency(
+    iterations: u32,
+    exits: u32,
+    mut op: impl FnMut() -> Result<()>,
+) -> Result<LatencyStats> {
+    let mut samples = Vec::new();
+    for _ in 0..iterations {
+        let start = Instant::now();
+        op()?;
+        samples.push(start.elapsed() / exits);
+    }
+    LatencyStats::new(samples).ok_or(anyhow!("No iterations to measure"))
+}
+
+fn irqfd_latency(iterations: u32) -> Result<LatencyStats> {
+    // irqfds can only be added before the VM starts
+    let mut irq = None;
+    let hc = started_holding_cell(|hc| {
+        irq = Some(hc.vm.add_edge_interrupt(IRQFD_LINE)?);
+        Ok(())
+    })?;
+    let irq = irq.unwrap();
+
+    let mut samples = Vec::new();
+    for _ in 0..iterations {
+        let (triggered, received) = thread::scope(|s| -> Result<_> {
+            let waiter = s.spawn(|| -> Result<Instant> {
+                hc.wait_irq(0, IRQFD_LINE)?;
+                Ok(Instant::now())
+            });
+            thread::sleep(IRQFD_SETTLE);
+            let triggered = Instant::now();
+            irq.trigger()?;
+            let received = waiter
+                .join()
+                .map_err(|_| anyhow!("Waiting for the interrupt panicked"))??;
+            Ok((triggered, received))
+        })?;
+        samples.push(received.saturating_duration_since(triggered));
+    }
+    LatencyStats::new(samples).ok_or(anyhow!("No iterations to measure"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use claim::{assert_none, assert_some};
+    use serde_json::json;
+
+    use super::{BenchResult, LatencyStats};
+
+    #[test]
+    fn latency_stats() {
+        assert_none!(LatencyStats::new(Vec::new()));
+
+        let samples = (1..=200).rev().map(Duration::from_micros).collect();
+        let stats = assert_some!(LatencyStats::new(samples));
+        assert_eq!(stats.samples, 200);
+        assert_eq!(stats.min, Duration::from_micros(1));
+        assert_eq!(stats.median, Duration::from_micros(100));
+        assert_eq!(stats.mean, Duration::from_nanos(100_500));
+        assert_eq!(stats.p99, Duration::from_micros(198));
+        assert_eq!(stats.max, Duration::from_micros(200));
+
+        let single = assert_some!(LatencyStats::new(vec![Duration::from_nanos(7)]));
+        assert_eq!(single.median, Duration::from_nanos(7));
+        assert_eq!(single.p99, Duration::from_nanos(7));
+    }
+
+    #[test]
+    fn json() {
+        let latency = BenchResult::Latency {
+            name: "vcpu-round-trip",
+            stats: LatencyStats::new(vec![Duration::from_nanos(5)]).unwrap(),
+        };
+        assert_eq!(
+            latency.to_json(),
+            json!({
+                "benchmark": "vcpu-round-trip",
+                "samples": 1,
+                "min_ns": 5,
+                "median_ns": 5,
+                "mean_ns": 5,
+                "p99_ns": 5,
+                "max_ns": 5,
+            })
+        );
+
+        let throughput = BenchResult::Throughput {
+            name: "lend",
+            bytes: 2 * 1048576,
+            time: Duration::from_millis(500),
+        };
+        assert_eq!(throughput.to_json()["mib_per_s"], json!(4.0));
+        assert_eq!(throughput.to_json()["time_ns"], json!(500_000_000));
+    }
+}
@@ -0,0 +1,147 @@
+// Copyright (c) 2024, Qualcomm Innovation Center, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause-Clear
+
+//! A cgroup v2 of its own for the VMM, limiting the CPU time and memory of its VM, see
+//! `--cgroup-parent`.
+//!
+//! The whole process moves into the cgroup before the VM is set up, so guest memory is charged to
+//! it, and every thread, vCPU or device, is limited alike. The cgroup is left behind when the
+//! VMM exits, so its statistics like `memory.peak` and `cpu.stat` can still be read.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process,
+};
+
+use anyhow::{Context, Result};
+
+/// Period of the CPU quota, the cgroup default
+const CPU_PERIOD_US: u64 = 100_000;
+
+/// Limits of a [`VmCgroup`], none by default.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CgroupLimits {
+    /// CPU time per period, in CPUs: 1.5 is one and a half CPUs busy all the time
+    pub cpus: Option<f64>,
+    /// Bytes of memory, including guest memory
+    pub memory: Option<u64>,
+}
+
+impl CgroupLimits {
+    /// Contents of `cpu.max`.
+    fn cpu_max(&self) -> Option<String> {
+        self.cpus.map(|cpus| {
+            let quota = (cpus * CPU_PERIOD_US as f64).round().max(1.0) as u64;
+            format!("{} {}", quota, CPU_PERIOD_US)
+        })
+    }
+
+    /// Controllers the limits need, as written to `cgroup.subtree_control`.
+    fn controllers(&self) -> Vec<&'static str> {
+        let mut controllers = Vec::new();
+        if self.cpus.is_some() {
+            controllers.push("cpu");
+        }
+        if self.memory.is_some() {
+            controllers.push("memory");
+        }
+        controllers
+    }
+}
+
+/// The cgroup the VMM runs in.
+#[derive(Debug)]
+pub struct VmCgroup {
+    path: PathBuf,
+}
+
+impl VmCgroup {
+    /// Creates a cgroup named after the VMM's pid below `parent`, a cgroup v2 directory, with
+    /// `limits`, and moves the VMM into it. The controllers the limits need are enabled for the
+    /// children of `parent` if they aren't yet.
+    pub fn create(parent: &Path, limits: &CgroupLimits) -> Result<Self> {
+        let subtree_control = parent.join("cgroup.subtree_control");
+        let enabled = fs::read_to_string(&subtree_control).unwrap_or_default();
+        for controller in limits.controllers() {
+            if !enabled.split_whitespace().any(|c| c == controller) {
+                fs::write(&subtree_control, format!("+{}", controller)).context(format!(
+                    "Failed to enable the {} controller in {}",
+                    controller,
+                    parent.display()
+                ))?;
+            }
+        }
+
+        let path = parent.join(format!("gunyah-test-vmm-{}", process::id()));
+        fs::create_dir(&path).context(format!("Failed to create cgroup {}", path.display()))?;
+        let cgroup = Self { path };
+        if let Some(cpu_max) = limits.cpu_max() {
+            cgroup.write("cpu.max", &cpu_max)?;
+        }
+        if let Some(memory) = limits.memory {
+            cgroup.write("memory.max", &memory.to_string())?;
+            // Guest memory can't be swapped out, reclaim would only stall the VM
+            let _ = cgroup.write("memory.swap.max", "0");
+        }
+        cgroup.write("cgroup.procs", &process::id().to_string())?;
+        Ok(cgroup)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn write(&self, file: &str, value: &str) -> Result<()> {
+        let path = self.path.join(file);
+        fs::write(&path, value).context(format!("Failed to write {} to {}", value, path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use claim::assert_ok;
+
+    use super::{CgroupLimits, VmCgroup};
+
+    #[test]
+    fn cpu_max() {
+        assert_eq!(CgroupLimits::default().cpu_max(), None);
+        let limits = CgroupLimits {
+            cpus: Some(1.5),
+            memory: None,
+        };
+        assert_eq!(limits.cpu_max().as_deref(), Some("150000 100000"));
+        let tiny = CgroupLimits {
+            cpus: Some(0.0),
+            memory: None,
+        };
+        assert_eq!(tiny.cpu_max().as_deref(), Some("1 100000"));
+    }
+
+    #[test]
+    fn create() {
+        // A plain directory stands in for the cgroup filesystem
+        let parent = std::env::temp_dir().join(format!("cgroup-{}", std::process::id()));
+        assert_ok!(fs::create_dir_all(&parent));
+        assert_ok!(fs::write(parent.join("cgroup.subtree_control"), "cpu"));
+        let limits = CgroupLimits {
+            cpus: Some(0.5),
+            memory: Some(0x4000_0000),
+        };
+        let cgroup = assert_ok!(VmCgroup::create(&parent, &limits));
+
+        let read = |file: &str| fs::read_to_string(cgroup.path().join(file)).unwrap();
+        assert_eq!(read("cpu.max"), "50000 100000");
+        assert_eq!(read("memory.max"), "1073741824");
+        assert_eq!(read("cgroup.procs"), std::process::id().to_string());
+        // Only the missing controller is enabled
+        assert_eq!(
+            assert_ok!(fs::read_to_string(parent.join("cgroup.subtree_control"))),
+            "+memory"
+        );
+        assert_ok!(fs::remove_dir_all(&parent));
+    }
+}
@@ -0,0 +1,192 @@
+// Copyright (c) 2024, Qualcomm Innovation Center, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause-Clear
+
+//! `--config FILE`: command line options read from a TOML or JSON file.
+//!
+//! The file is a table whose keys are the long option names (or argument names for positional
+//! arguments) and whose values are strings, numbers, booleans for flags, or arrays for options
+//! that can be repeated:
+//!
+//! ```toml
+//! image = "Image"
+//! rdisk = "initrd.img"
+//! size = "512MB"
+//! serial-base = ["0x3f800", "0x3f900"]
+//! serial-interrupt = [1, 2]
+//! unprotected = true
+//! ```
+
+use std::{ffi::OsString, fs, path::Path};
+
+use anyhow::{anyhow, Context, Result};
+use clap::{parser::ValueSource, Arg, Command};
+use serde_json::{Map, Value};
+
+/// Appends the options from the file named by `--config` in `args` to `args`. Options given on
+/// the command line take precedence: they replace the file's values instead of adding to them.
+/// Errors in `args` themselves are left for the caller's parser to report.
+pub fn with_config_file(command: &Command, mut args: Vec<OsString>) -> Result<Vec<OsString>> {
+    let Ok(matches) = command
+        .clone()
+        .ignore_errors(true)
+        .mut_args(|arg| arg.required(false))
+        .try_get_matches_from(&args)
+    else {
+        return Ok(args);
+    };
+    let Some(path) = matches
+        .get_raw("config")
+        .and_then(|mut values| values.next())
+    else {
+        return Ok(args);
+    };
+    let path = Path::new(path);
+    let config = read_config(path)?;
+
+    if let Some(key) = config.keys().find(|key| find_arg(command, key).is_none()) {
+        return Err(anyhow!("Unknown option {:?} in {}", key, path.display()));
+    }
+    // Positional arguments have to be added in the order they are declared
+    for arg in command.get_arguments() {
+        let Some(value) = config
+            .get(arg.get_id().as_str())
+            .or_else(|| arg.get_long().and_then(|long| config.get(long)))
+        else {
+            continue;
+        };
+        if matches.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine) {
+            continue;
+        }
+        let values = match value {
+            Value::Array(values) => values.as_slice(),
+            value => std::slice::from_ref(value),
+        };
+        for value in values {
+            let value = match (arg.get_action().takes_values(), value) {
+                (false, Value::Bool(true)) => None,
+                (false, Value::Bool(false)) => continue,
+                (true, Value::String(value)) => Some(value.clone()),
+                (true, Value::Number(value)) => Some(value.to_string()),
+                _ => {
+                    return Err(anyhow!(
+                        "Invalid value {} for {:?} in {}",
+                        value,
+                        arg.get_id(),
+                        path.display()
+                    ))
+                }
+            };
+            args.push(match (arg.get_long(), value) {
+                (Some(long), Some(value)) => format!("--{}={}", long, value).into(),
+                (Some(long), None) => format!("--{}", long).into(),
+                (None, Some(value)) => value.into(),
+                (None, None) => unreachable!("positional arguments take values"),
+            });
+        }
+    }
+    Ok(args)
+}
+
+fn read_config(path: &Path) -> Result<Map<String, Value>> {
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("Unable to read config file {}", path.display()))?;
+    if path
+        .extension()
+        .is_some_and(|extension| extension == "toml")
+    {
+        toml::from_str(&text).with_context(|| format!("Invalid TOML in {}", path.display()))
+    } else {
+        serde_json::from_str(&text).with_context(|| format!("Invalid JSON in {}", path.display()))
+    }
+}
+
+fn find_arg<'a>(command: &'a Command, key: &str) -> Option<&'a Arg> {
+    command
+        .get_arguments()
+        .find(|arg| arg.get_id() == key || arg.get_long() == Some(key))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::OsString, fs, path::PathBuf};
+
+    use claim::{assert_err, assert_ok};
+    use clap::{Arg, ArgAction, Command};
+
+    use super::with_config_file;
+
+    fn command() -> Command {
+        Command::new("vmm")
+            .arg(Arg::new("image").required(true))
+            .arg(Arg::new("rdisk"))
+            .arg(Arg::new("config").long("config"))
+            .arg(Arg::new("mem_size").long("mem-size"))
+            .arg(Arg::new("serial").long("serial").action(ArgAction::Append))
+            .arg(Arg::new("paused").long("paused").action(ArgAction::SetTrue))
+    }
+
+    fn config_file(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("{}-{}", std::process::id(), name));
+        assert_ok!(fs::write(&path, contents));
+        path
+    }
+
+    fn args(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn toml() {
+        let path = config_file(
+            "config.toml",
+            "image = \"Image\"\nrdisk = \"initrd\"\nmem_size = 512\n\
+             serial = [\"pl011\", \"16550\"]\npaused = true\n",
+        );
+        let config = format!("--config={}", path.display());
+        assert_eq!(
+            assert_ok!(with_config_file(&command(), args(&["vmm", &config]))),
+            args(&[
+                "vmm",
+                &config,
+                "Image",
+                "initrd",
+                "--mem-size=512",
+                "--serial=pl011",
+                "--serial=16550",
+                "--paused"
+            ])
+        );
+
+        // The command line replaces values from the file
+        let cli = args(&["vmm", "Other", &config, "--serial", "none"]);
+        let merged = assert_ok!(with_config_file(&command(), cli.clone()));
+        assert_eq!(merged[..cli.len()], cli);
+        assert_eq!(
+            merged[cli.len()..],
+            args(&["initrd", "--mem-size=512", "--paused"])
+        );
+        assert_ok!(fs::remove_file(&path));
+    }
+
+    #[test]
+    fn json() {
+        let path = config_file("config.json", r#"{"image": "Image", "paused": false}"#);
+        let config = format!("--config={}", path.display());
+        assert_eq!(
+            assert_ok!(with_config_file(&command(), args(&["vmm", &config]))),
+            args(&["vmm", &config, "Image"])
+        );
+
+        assert_ok!(fs::write(&path, r#"{"unknown": 1}"#));
+        assert_err!(with_config_file(&command(), args(&["vmm", &config])));
+        assert_ok!(fs::write(&path, r#"{"paused": "yes"}"#));
+        assert_err!(with_config_file(&command(), args(&["vmm", &config])));
+        assert_ok!(fs::write(&path, "image = "));
+        assert_err!(with_config_file(&command(), args(&["vmm", &config])));
+        assert_ok!(fs::remove_file(&path));
+
+        // Without --config the arguments are left alone
+        let cli = args(&["vmm", "Image"]);
+        assert_eq!(assert_ok!(with_config_file(&command(), cli.clone())), cli);
+    }
+}
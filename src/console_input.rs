@@ -0,0 +1,240 @@
+// Copyright (c) 2024, Qualcomm Innovation Center, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause-Clear
+
+use std::{
+    io::{self, Write},
+    os::fd::RawFd,
+    sync::Arc,
+    time::Instant,
+};
+
+use anyhow::Result;
+use vmm::{DeviceExecutor, DeviceTask, Monitor, TaskPoll, VmExit, VmExitRequest};
+
+use crate::{InputBuffer, ReceiveInput};
+
+/// Ctrl-A starts an escape sequence on the console, as in QEMU
+const ESCAPE: u8 = 0x01;
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7f;
+
+const ESCAPE_HELP: &str = "\
+Ctrl-A x    terminate the VM
+Ctrl-A c    switch between the guest console and the monitor
+Ctrl-A h    show this help
+Ctrl-A Ctrl-A  send Ctrl-A to the guest
+";
+
+/// Puts the terminal on stdin into raw mode until dropped, so the guest sees every key as it's
+/// typed, including Ctrl-C. Output processing stays on, so host messages still start on a new
+/// line.
+pub struct RawTerminal {
+    original: libc::termios,
+}
+
+impl RawTerminal {
+    /// Returns None if stdin isn't a terminal.
+    pub fn new() -> Result<Option<Self>> {
+        // SAFETY: Safe because termios is plain data and tcgetattr only writes to it.
+        let mut original = unsafe { std::mem::zeroed::<libc::termios>() };
+        // SAFETY: Safe because we pass a valid termios.
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut original) } != 0 {
+            return Ok(None);
+        }
+        let mut raw = original;
+        // SAFETY: Safe because we pass a valid termios.
+        unsafe { libc::cfmakeraw(&mut raw) };
+        raw.c_oflag |= libc::OPOST | libc::ONLCR;
+        // SAFETY: Safe because we pass a valid termios.
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) } != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(Some(Self { original }))
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        // SAFETY: Safe because we pass a valid termios.
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original) };
+    }
+}
+
+/// Splits console input between the guest, the [`Monitor`] and escape sequences.
+pub struct ConsoleInput {
+    exit: VmExitRequest,
+    monitor: Option<Arc<Monitor>>,
+    /// Echo monitor input, which is needed when the terminal is in raw mode
+    echo: bool,
+    /// The last byte was [`ESCAPE`]
+    escaped: bool,
+    /// Monitor command typed so far
+    line: Vec<u8>,
+}
+
+impl ConsoleInput {
+    pub fn new(exit: VmExitRequest, monitor: Option<Arc<Monitor>>, echo: bool) -> Self {
+        Self {
+            exit,
+            monitor,
+            echo,
+            escaped: false,
+            line: Vec::new(),
+        }
+    }
+
+    /// Reads stdin in a task on `executor` and passes input meant for the guest to `receive`
+    /// until that returns None.
+    pub fn forward_stdin(self, executor: &DeviceExecutor, receive: ReceiveInput) {
+        executor.spawn(Box::new(StdinTask {
+            console: self,
+            input: InputBuffer::new(receive),
+        }));
+    }
+
+    /// Handles escape sequences and monitor input in `data` and returns the rest, which is meant
+    /// for the guest.
+    pub fn process(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut guest = Vec::new();
+        for &byte in data {
+            if self.escaped {
+                self.escaped = false;
+                match byte {
+                    b'x' => {
+                        print_flush("Terminating the VM\n");
+                        self.exit.request(VmExit::Poweroff);
+                    }
+                    b'c' => match &self.monitor {
+                        Some(monitor) => {
+                            self.line.clear();
+                            monitor.toggle();
+                        }
+                        None => print_flush("No monitor available\n"),
+                    },
+                    b'h' => print_flush(ESCAPE_HELP),
+                    ESCAPE if !self.monitor_active() => guest.push(ESCAPE),
+                    _ => {}
+                }
+            } else if byte == ESCAPE {
+                self.escaped = true;
+            } else if self.monitor_active() {
+                self.monitor_input(byte);
+            } else {
+                guest.push(byte);
+            }
+        }
+        guest
+    }
+
+    fn monitor_active(&self) -> bool {
+        self.monitor
+            .as_ref()
+            .is_some_and(|monitor| monitor.is_active())
+    }
+
+    fn monitor_input(&mut self, byte: u8) {
+        match byte {
+            b'\r' | b'\n' => {
+                if self.echo {
+                    print_flush("\n");
+                }
+                let line = String::from_utf8_lossy(&self.line).into_owned();
+                self.line.clear();
+                if let Some(monitor) = &self.monitor {
+                    monitor.run_line(&line);
+                }
+            }
+            BACKSPACE | DELETE => {
+                if self.line.pop().is_some() && self.echo {
+                    print_flush("\x08 \x08");
+                }
+            }
+            _ => {
+                self.line.push(byte);
+                if self.echo {
+                    let _ = io::stdout().write_all(&[byte]);
+                    let _ = io::stdout().flush();
+                }
+            }
+        }
+    }
+}
+
+/// Forwards stdin through a [`ConsoleInput`].
+struct StdinTask {
+    console: ConsoleInput,
+    input: InputBuffer,
+}
+
+impl DeviceTask for StdinTask {
+    fn debug_label(&self) -> String {
+        "console input".to_string()
+    }
+
+    fn fds(&self) -> Vec<RawFd> {
+        if self.input.is_blocked() {
+            Vec::new()
+        } else {
+            vec![libc::STDIN_FILENO]
+        }
+    }
+
+    fn poll(&mut self, now: Instant) -> Result<TaskPoll> {
+        if !self.input.push(&[]) {
+            return Ok(TaskPoll::Done);
+        }
+        if !self.input.is_blocked() && stdin_readable() {
+            // Bypasses the buffer of io::stdin(), which would hide input from poll
+            let mut buf = [0u8; 64];
+            // SAFETY: Safe because buf is valid for writes of its length.
+            let len = unsafe { libc::read(libc::STDIN_FILENO, buf.as_mut_ptr().cast(), buf.len()) };
+            let Ok(len @ 1..) = usize::try_from(len) else {
+                return Ok(TaskPoll::Done);
+            };
+            let data = self.console.process(&buf[..len]);
+            if !self.input.push(&data) {
+                return Ok(TaskPoll::Done);
+            }
+        }
+        Ok(self.input.next_poll(now))
+    }
+}
+
+/// Whether reading stdin wouldn't block. Stdin stays in blocking mode, since its file
+/// description is shared with the shell.
+fn stdin_readable() -> bool {
+    let mut pollfd = libc::pollfd {
+        fd: libc::STDIN_FILENO,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    // SAFETY: Safe because we pass one valid pollfd.
+    unsafe { libc::poll(&mut pollfd, 1, 0) == 1 }
+}
+
+fn print_flush(output: &str) {
+    print!("{}", output);
+    let _ = io::stdout().flush();
+}
+
+#[cfg(test)]
+mod tests {
+    use vmm::{VmExit, VmExitRequest};
+
+    use super::ConsoleInput;
+
+    #[test]
+    fn escapes() {
+        let exit = VmExitRequest::default();
+        let mut input = ConsoleInput::new(exit.clone(), None, false);
+        assert_eq!(input.process(b"ls\r"), b"ls\r");
+        // Split across reads
+        assert_eq!(input.process(b"a\x01"), b"a");
+        assert_eq!(input.process(b"\x01b"), b"\x01b");
+        // Unknown escapes and the monitor without a monitor are swallowed
+        assert_eq!(input.process(b"\x01z\x01c"), b"");
+        assert_eq!(exit.reason(), None);
+        assert_eq!(input.process(b"\x03\x01x"), b"\x03");
+        assert_eq!(exit.reason(), Some(VmExit::Poweroff));
+    }
+}
@@ -0,0 +1,187 @@
+// Copyright (c) 2024, Qualcomm Innovation Center, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause-Clear
+
+use std::{
+    fmt::Debug,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use vmm::IoEngine;
+
+/// File the output of all serial ports is appended to, whatever their backends, see
+/// `--console-log`. Each line is prefixed with the time since the log was opened, from a
+/// monotonic clock, and the serial port's alias: `[    1.234567] serial0: ...`.
+#[derive(Clone, Debug)]
+pub struct ConsoleLog {
+    file: Arc<File>,
+    engine: IoEngine,
+    start: Instant,
+}
+
+impl ConsoleLog {
+    /// Opens `path` for appending, output is written with `engine`.
+    pub fn open(path: &Path, engine: &IoEngine) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .context(format!("Failed to open {}", path.display()))?;
+        Ok(Self {
+            file: Arc::new(file),
+            engine: engine.clone(),
+            start: Instant::now(),
+        })
+    }
+
+    /// Output of the serial port aliased `name`.
+    pub fn port(&self, name: &str) -> ConsoleLogPort {
+        ConsoleLogPort {
+            log: self.clone(),
+            name: name.to_string(),
+            line: Vec::new(),
+            line_start: Duration::ZERO,
+        }
+    }
+
+    fn write_line(&self, time: Duration, name: &str, line: &[u8]) -> io::Result<()> {
+        let mut entry = format!(
+            "[{:5}.{:06}] {}: ",
+            time.as_secs(),
+            time.subsec_micros(),
+            name
+        )
+        .into_bytes();
+        entry.extend_from_slice(line);
+        entry.push(b'\n');
+        let mut entry = &entry[..];
+        while !entry.is_empty() {
+            let written = self.engine.write(&self.file, entry)?;
+            if written == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            entry = &entry[written..];
+        }
+        Ok(())
+    }
+}
+
+/// One serial port's output into a [`ConsoleLog`]. Lines are written once complete, stamped with
+/// the time their first character arrived, and a partial last line when dropped.
+#[derive(Debug)]
+pub struct ConsoleLogPort {
+    log: ConsoleLog,
+    name: String,
+    line: Vec<u8>,
+    line_start: Duration,
+}
+
+impl ConsoleLogPort {
+    fn log(&mut self, data: &[u8]) -> io::Result<()> {
+        for &byte in data {
+            if self.line.is_empty() {
+                self.line_start = self.log.start.elapsed();
+            }
+            match byte {
+                b'\n' => {
+                    let line = self.line.strip_suffix(b"\r").unwrap_or(&self.line);
+                    self.log.write_line(self.line_start, &self.name, line)?;
+                    self.line.clear();
+                }
+                _ => self.line.push(byte),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ConsoleLogPort {
+    fn drop(&mut self) {
+        if !self.line.is_empty() {
+            let _ = self.log.write_line(self.line_start, &self.name, &self.line);
+        }
+    }
+}
+
+/// Serial port output which also goes to a [`ConsoleLogPort`], if there is one.
+#[derive(Debug)]
+pub struct LoggedOutput<W: Write + Debug + Send> {
+    out: W,
+    log: Option<ConsoleLogPort>,
+}
+
+impl<W: Write + Debug + Send> LoggedOutput<W> {
+    pub fn new(out: W, log: Option<ConsoleLogPort>) -> Self {
+        Self { out, log }
+    }
+}
+
+impl<W: Write + Debug + Send> Write for LoggedOutput<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.out.write(buf)?;
+        if let Some(log) = &mut self.log {
+            // A log which can't be written mustn't take the guest's console down with it
+            let _ = log.log(&buf[..written]);
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Write};
+
+    use claim::assert_ok;
+    use vmm::{IoEngine, IoEngineKind};
+
+    use super::{ConsoleLog, LoggedOutput};
+
+    #[test]
+    fn lines_are_stamped() {
+        let path = std::env::temp_dir().join(format!("console-log-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let engine = assert_ok!(IoEngine::new(IoEngineKind::default()));
+        let log = assert_ok!(ConsoleLog::open(&path, &engine));
+
+        let mut serial0 = LoggedOutput::new(Vec::new(), Some(log.port("serial0")));
+        let mut serial1 = LoggedOutput::new(Vec::new(), Some(log.port("serial1")));
+        assert_ok!(serial0.write_all(b"Booting Linux\r\nlog"));
+        assert_ok!(serial1.write_all(b"login: \n\n"));
+        assert_ok!(serial0.write_all(b"in\n"));
+        assert_ok!(serial1.write_all(b"partial"));
+        assert_eq!(serial0.out, b"Booting Linux\r\nlogin\n");
+        drop(serial0);
+        drop(serial1);
+
+        let contents = assert_ok!(fs::read_to_string(&path));
+        let lines: Vec<_> = contents
+            .lines()
+            .map(|line| {
+                // [    0.000123] serial0: ...
+                assert_eq!(&line[..1], "[");
+                assert_eq!(&line[6..7], ".");
+                assert_eq!(&line[13..15], "] ");
+                &line[15..]
+            })
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                "serial0: Booting Linux",
+                "serial1: login: ",
+                "serial1: ",
+                "serial0: login",
+                "serial1: partial"
+            ]
+        );
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,441 @@
+// Copyright (c) 2024, Qualcomm Innovation Center, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause-Clear
+
+//! `daemon`: hosts VMs started and stopped through a control socket, and `ctl`, its client.
+//!
+//! Like the control socket of a single VM, clients send one JSON object per line with the
+//! command name in `"command"` and get one line back: `{"return": ...}` on success or
+//! `{"error": "..."}` on failure. Supported commands:
+//!
+//! - `start` with `name` and `config`: starts a VM with the options in the config file `config`,
+//!   see [`crate::with_config_file`]
+//! - `stop` with `name`: powers the VM off
+//! - `status`, optionally with `name`: `{"vms": [{"name": "vm0", "status": "running"}, ...]}`, with
+//!   `"status": "exited"` and the `"exit"` reason or `"status": "failed"` and the `"error"` once a
+//!   VM ended
+//! - `console` with `name`: `{"socket": "<path>"}`, the socket of the VM's console serial port
+//! - `quit`: stops all VMs and the daemon
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, BufRead, BufReader, Read, Write},
+    os::{
+        fd::AsRawFd,
+        unix::net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+};
+
+use anyhow::{anyhow, Context, Result};
+use serde_json::{json, Map, Value};
+use vmm::{handle_request, remove_stale_socket, VmExit, VmExitRequest};
+
+use crate::RawTerminal;
+
+/// Detaches `ctl console` from the console, like telnet's escape character
+const DETACH: u8 = 0x1d;
+
+/// Lets the daemon stop a VM it started, whichever boot of the VM is running.
+#[derive(Clone, Default)]
+pub struct VmControl {
+    state: Arc<Mutex<VmControlState>>,
+}
+
+#[derive(Default)]
+struct VmControlState {
+    /// Exit request of the VM's current boot
+    exit: Option<VmExitRequest>,
+    stopped: bool,
+}
+
+impl VmControl {
+    /// Makes [`Self::stop`] stop the boot of the VM `exit` belongs to. Stops it right away if
+    /// the VM was stopped already.
+    pub fn attach(&self, exit: VmExitRequest) {
+        let mut state = self.state.lock().unwrap();
+        if state.stopped {
+            exit.request(VmExit::Poweroff);
+        }
+        state.exit = Some(exit);
+    }
+
+    /// Powers the VM off. It isn't restarted afterwards.
+    pub fn stop(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.stopped = true;
+        if let Some(exit) = &state.exit {
+            exit.request(VmExit::Poweroff);
+        }
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.state.lock().unwrap().stopped
+    }
+}
+
+/// A VM the daemon is about to start, see [`Daemon::new`].
+pub struct PreparedVm {
+    /// Socket of the VM's console, for `ctl console`
+    pub console: Option<PathBuf>,
+    /// Runs the VM until it ends
+    pub run: Box<dyn FnOnce(VmControl) -> Result<VmExit> + Send>,
+}
+
+type Launcher = Box<dyn Fn(&Path) -> Result<PreparedVm> + Send + Sync>;
+
+struct DaemonVm {
+    console: Option<PathBuf>,
+    control: VmControl,
+    /// How the VM ended, once it did
+    result: Arc<Mutex<Option<Result<VmExit, String>>>>,
+    thread: JoinHandle<()>,
+}
+
+/// Serves the control socket of the daemon. One client is served at a time.
+pub struct Daemon {
+    launcher: Launcher,
+    vms: Mutex<BTreeMap<String, DaemonVm>>,
+    quit: AtomicBool,
+}
+
+impl Daemon {
+    /// Creates a daemon which prepares the VM for a config file with `launcher`.
+    pub fn new(launcher: impl Fn(&Path) -> Result<PreparedVm> + Send + Sync + 'static) -> Self {
+        Self {
+            launcher: Box::new(launcher),
+            vms: Mutex::new(BTreeMap::new()),
+            quit: AtomicBool::new(false),
+        }
+    }
+
+    /// Serves clients on a socket at `path` until one sends `quit`, then waits for the VMs to
+    /// stop. See [`remove_stale_socket`] for what may be at `path` already.
+    pub fn listen(self, path: &Path) -> Result<()> {
+        remove_stale_socket(path)?;
+        let listener =
+            UnixListener::bind(path).context(format!("Failed to listen on {}", path.display()))?;
+        println!("Daemon listening on {}", path.display());
+        while !self.quit.load(Ordering::SeqCst) {
+            let (stream, _) = listener
+                .accept()
+                .context("Failed to accept control connection")?;
+            if let Err(e) = self.serve(stream) {
+                println!("Control connection failed: {:?}", e);
+            }
+        }
+        let _ = fs::remove_file(path);
+
+        let vms = std::mem::take(&mut *self.vms.lock().unwrap());
+        for (name, vm) in vms {
+            vm.control.stop();
+            if vm.thread.join().is_err() {
+                println!("VM {} panicked", name);
+            }
+        }
+        Ok(())
+    }
+
+    fn serve(&self, stream: UnixStream) -> Result<()> {
+        let reader = BufReader::new(stream.try_clone()?);
+        let mut writer = stream;
+        for line in reader.lines() {
+            let reply = self.handle(&line?);
+            writer.write_all(format!("{}\n", reply).as_bytes())?;
+            if self.quit.load(Ordering::SeqCst) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn handle(&self, request: &str) -> Value {
+        handle_request(request, |command, request| self.execute(command, request))
+    }
+
+    fn execute(&self, command: &str, request: &Map<String, Value>) -> Result<Value> {
+        match command {
+            "start" => {
+                let name = get_str(request, "name")?;
+                let config = Path::new(get_str(request, "config")?);
+                self.start(name, config)?;
+                Ok(json!({}))
+            }
+            "stop" => {
+                let name = get_str(request, "name")?;
+                self.with_vm(name, |vm| vm.control.stop())?;
+                Ok(json!({}))
+            }
+            "status" => {
+                let name = request.get("name").and_then(Value::as_str);
+                let vms = self.vms.lock().unwrap();
+                if let Some(name) = name.filter(|name| !vms.contains_key(*name)) {
+                    return Err(anyhow!("No VM named {}", name));
+                }
+                let statuses: Vec<Value> = vms
+                    .iter()
+                    .filter(|(vm_name, _)| name.is_none_or(|name| name == vm_name.as_str()))
+                    .map(|(name, vm)| {
+                        let mut status = json!({ "name": name });
+                        let fields = match &*vm.result.lock().unwrap() {
+                            None if vm.control.is_stopped() => json!({ "status": "stopping" }),
+                            None => json!({ "status": "running" }),
+                            Some(Ok(exit)) => {
+                                json!({ "status": "exited", "exit": format!("{:?}", exit) })
+                            }
+                            Some(Err(e)) => json!({ "status": "failed", "error": e }),
+                        };
+                        status
+                            .as_object_mut()
+                            .unwrap()
+                            .extend(fields.as_object().unwrap().clone());
+                        status
+                    })
+                    .collect();
+                Ok(json!({ "vms": statuses }))
+            }
+            "console" => {
+                let name = get_str(request, "name")?;
+                let socket = self
+                    .with_vm(name, |vm| vm.console.clone())?
+                    .ok_or(anyhow!("The console of VM {} isn't a socket", name))?;
+                Ok(json!({ "socket": socket }))
+            }
+            "quit" => {
+                self.quit.store(true, Ordering::SeqCst);
+                Ok(json!({}))
+            }
+            _ => Err(anyhow!("Unknown command {}", command)),
+        }
+    }
+
+    fn with_vm<T>(&self, name: &str, f: impl FnOnce(&DaemonVm) -> T) -> Result<T> {
+        let vms = self.vms.lock().unwrap();
+        Ok(f(vms.get(name).ok_or(anyhow!("No VM named {}", name))?))
+    }
+
+    /// Starts the VM configured in `config` as `name`, replacing a VM of that name which ended.
+    fn start(&self, name: &str, config: &Path) -> Result<()> {
+        let is_running = |vms: &BTreeMap<String, DaemonVm>| {
+            vms.get(name)
+                .is_some_and(|vm| vm.result.lock().unwrap().is_none())
+        };
+        if is_running(&self.vms.lock().unwrap()) {
+            return Err(anyhow!("VM {} is still running", name));
+        }
+        // Preparing the VM may take a while, other VMs can be queried meanwhile
+        let prepared =
+            (self.launcher)(config).context(format!("Invalid VM config {}", config.display()))?;
+        let mut vms = self.vms.lock().unwrap();
+        if is_running(&vms) {
+            return Err(anyhow!("VM {} is still running", name));
+        }
+        let control = VmControl::default();
+        let result = Arc::new(Mutex::new(None));
+        let thread = {
+            let control = control.clone();
+            let result = result.clone();
+            let name = name.to_string();
+            thread::spawn(move || {
+                let ended = (prepared.run)(control).map_err(|e| format!("{:#}", e));
+                match &ended {
+                    Ok(exit) => println!("VM {} exited: {:?}", name, exit),
+                    Err(e) => println!("VM {} failed: {}", name, e),
+                }
+                *result.lock().unwrap() = Some(ended);
+            })
+        };
+        vms.insert(
+            name.to_string(),
+            DaemonVm {
+                console: prepared.console,
+                control,
+                result,
+                thread,
+            },
+        );
+        Ok(())
+    }
+}
+
+fn get_str<'a>(request: &'a Map<String, Value>, name: &str) -> Result<&'a str> {
+    request
+        .get(name)
+        .and_then(Value::as_str)
+        .ok_or(anyhow!("Missing or invalid {}", name))
+}
+
+/// Sends `request` to the daemon listening on `socket` and returns what it returned.
+pub fn daemon_command(socket: &Path, request: Value) -> Result<Value> {
+    let stream = UnixStream::connect(socket).context(format!(
+        "Failed to connect to daemon at {}",
+        socket.display()
+    ))?;
+    (&stream).write_all(format!("{}\n", request).as_bytes())?;
+    let mut reply = String::new();
+    BufReader::new(&stream).read_line(&mut reply)?;
+    let mut reply: Map<String, Value> =
+        serde_json::from_str(&reply).context("Invalid reply from daemon")?;
+    match (reply.remove("return"), reply.remove("error")) {
+        (Some(value), _) => Ok(value),
+        (None, Some(Value::String(e))) => Err(anyhow!(e)),
+        _ => Err(anyhow!("Invalid reply from daemon")),
+    }
+}
+
+/// Connects the terminal to the console socket at `path` until the VM closes it or the user
+/// types Ctrl-].
+pub fn attach_console(path: &Path) -> Result<()> {
+    let mut socket =
+        UnixStream::connect(path).context(format!("Failed to connect to {}", path.display()))?;
+    let _terminal = RawTerminal::new().context("Failed to put the terminal into raw mode")?;
+    println!("Connected to {}, Ctrl-] detaches", path.display());
+    let mut stdout = io::stdout();
+    let mut buf = [0u8; 256];
+    loop {
+        let mut fds = [
+            libc::pollfd {
+                fd: libc::STDIN_FILENO,
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: socket.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+        ];
+        // SAFETY: Safe because fds is an array of valid pollfds of the length passed.
+        if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) } < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e.into());
+        }
+        if fds[1].revents != 0 {
+            let len = socket.read(&mut buf)?;
+            if len == 0 {
+                println!("\r\nConsole closed");
+                return Ok(());
+            }
+            stdout.write_all(&buf[..len])?;
+            stdout.flush()?;
+        }
+        if fds[0].revents != 0 {
+            let len = io::stdin().lock().read(&mut buf)?;
+            let input = &buf[..len];
+            let detach = input.iter().position(|&b| b == DETACH);
+            socket.write_all(&input[..detach.unwrap_or(len)])?;
+            if len == 0 || detach.is_some() {
+                println!("\r\nDetached");
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::{Path, PathBuf},
+        thread,
+        time::Duration,
+    };
+
+    use anyhow::anyhow;
+    use serde_json::{json, Value};
+    use vmm::{VmExit, VmExitRequest};
+
+    use super::{Daemon, PreparedVm, VmControl};
+
+    /// Prepares VMs which run until stopped, failing for configs named `bad` and without a
+    /// console for configs named `headless`
+    fn daemon() -> Daemon {
+        Daemon::new(|config: &Path| {
+            if config == Path::new("bad") {
+                return Err(anyhow!("bad config"));
+            }
+            Ok(PreparedVm {
+                console: (config != Path::new("headless")).then(|| PathBuf::from("/tmp/console")),
+                run: Box::new(|control: VmControl| {
+                    let exit = VmExitRequest::default();
+                    control.attach(exit.clone());
+                    while exit.reason().is_none() {
+                        thread::sleep(Duration::from_millis(1));
+                    }
+                    Ok(exit.reason().unwrap())
+                }),
+            })
+        })
+    }
+
+    fn status(daemon: &Daemon, name: &str) -> Value {
+        daemon.handle(&json!({ "command": "status", "name": name }).to_string())["return"]["vms"][0]
+            .clone()
+    }
+
+    #[test]
+    fn start_and_stop() {
+        let daemon = daemon();
+        let start = json!({ "command": "start", "name": "vm0", "config": "vm0.toml" }).to_string();
+        assert_eq!(daemon.handle(&start), json!({ "return": {} }));
+        assert_eq!(status(&daemon, "vm0")["status"], "running");
+        // Still running
+        assert!(daemon.handle(&start).get("error").is_some());
+        assert_eq!(
+            daemon.handle(r#"{"command": "console", "name": "vm0"}"#),
+            json!({ "return": { "socket": "/tmp/console" } })
+        );
+
+        assert_eq!(
+            daemon.handle(r#"{"command": "stop", "name": "vm0"}"#),
+            json!({ "return": {} })
+        );
+        while status(&daemon, "vm0")["status"] != "exited" {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(status(&daemon, "vm0")["exit"], "Poweroff");
+        // Ended VMs can be started again
+        assert_eq!(daemon.handle(&start), json!({ "return": {} }));
+    }
+
+    #[test]
+    fn errors() {
+        let daemon = daemon();
+        assert!(daemon
+            .handle(r#"{"command": "start", "name": "vm0", "config": "bad"}"#)
+            .get("error")
+            .is_some());
+        assert!(daemon
+            .handle(r#"{"command": "stop", "name": "vm0"}"#)
+            .get("error")
+            .is_some());
+        assert!(daemon
+            .handle(r#"{"command": "start"}"#)
+            .get("error")
+            .is_some());
+        assert!(daemon.handle("not json").get("error").is_some());
+
+        daemon.handle(r#"{"command": "start", "name": "vm1", "config": "headless"}"#);
+        assert!(daemon
+            .handle(r#"{"command": "console", "name": "vm1"}"#)
+            .get("error")
+            .is_some());
+        daemon.handle(r#"{"command": "stop", "name": "vm1"}"#);
+    }
+
+    #[test]
+    fn stopped_before_attach() {
+        let control = VmControl::default();
+        control.stop();
+        let exit = VmExitRequest::default();
+        control.attach(exit.clone());
+        assert_eq!(exit.reason(), Some(VmExit::Poweroff));
+    }
+}
@@ -0,0 +1,289 @@
+// Copyright (c) 2024, Qualcomm Innovation Center, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause-Clear
+
+//! Host side of running commands in a Linux guest, so tests which boot one can check what
+//! happens inside it.
+//!
+//! The guest runs a shell on a channel the host can connect to:
+//! - A dedicated serial port with a Unix socket backend (`--serial-backend socket:PATH`), with
+//!   e.g. `setsid sh </dev/ttyAMA1 >/dev/ttyAMA1 2>&1` in the guest
+//! - A vsock port served by a vhost-user vsock backend such as vhost-device-vsock, which hands
+//!   host connections on its Unix socket to the guest after `CONNECT <port>`, with e.g.
+//!   `socat VSOCK-LISTEN:1234,fork EXEC:sh,stderr` in the guest
+//!
+//! [`GuestAgent::run`] wraps each command in markers the shell prints before and after it, along
+//! with its exit status, so the output can be picked out of echoed input and prompts.
+
+use std::{
+    io::{self, Read, Write},
+    os::unix::net::UnixStream,
+    path::PathBuf,
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+
+/// How often connecting is retried while the socket doesn't exist yet
+const CONNECT_RETRY: Duration = Duration::from_millis(100);
+
+/// Channel a [`GuestAgent`] reaches the guest's shell over.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AgentChannel {
+    /// Unix socket of a serial port's socket backend
+    Serial(PathBuf),
+    /// Unix socket of a vhost-user vsock backend, forwarding to this guest port
+    Vsock { socket: PathBuf, port: u32 },
+}
+
+impl AgentChannel {
+    /// Connects to the channel, retrying until `deadline` while the VMM or backend hasn't
+    /// created the socket yet or the guest doesn't listen yet.
+    fn connect(&self, deadline: Instant) -> Result<UnixStream> {
+        loop {
+            match self.try_connect(deadline) {
+                Ok(stream) => return Ok(stream),
+                Err(e) if Instant::now() >= deadline => return Err(e),
+                Err(_) => thread::sleep(CONNECT_RETRY),
+            }
+        }
+    }
+
+    fn try_connect(&self, deadline: Instant) -> Result<UnixStream> {
+        match self {
+            Self::Serial(path) => UnixStream::connect(path)
+                .context(format!("Failed to connect to {}", path.display())),
+            Self::Vsock { socket, port } => {
+                let mut stream = UnixStream::connect(socket)
+                    .context(format!("Failed to connect to {}", socket.display()))?;
+                stream.write_all(format!("CONNECT {}\n", port).as_bytes())?;
+                let reply = read_line(&mut stream, deadline)?;
+                if !reply.starts_with("OK ") {
+                    bail!("Guest refused vsock port {}: {}", port, reply.trim_end());
+                }
+                Ok(stream)
+            }
+        }
+    }
+}
+
+/// Reads byte by byte up to a newline, so nothing after it is consumed.
+fn read_line(stream: &mut UnixStream, deadline: Instant) -> Result<String> {
+    let mut line = Vec::new();
+    let mut byte = [0u8; 1];
+    while byte[0] != b'\n' {
+        stream.set_read_timeout(Some(remaining(deadline)?))?;
+        if stream.read(&mut byte)? == 0 {
+            bail!("Connection closed");
+        }
+        line.push(byte[0]);
+    }
+    Ok(String::from_utf8_lossy(&line).into_owned())
+}
+
+fn remaining(deadline: Instant) -> Result<Duration> {
+    deadline
+        .checked_duration_since(Instant::now())
+        .filter(|left| !left.is_zero())
+        .ok_or(anyhow!("Timed out waiting for the guest"))
+}
+
+/// Output and exit status of a command run in the guest.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CommandOutput {
+    /// stdout, and stderr if the guest's shell redirects it
+    pub output: String,
+    pub status: i32,
+}
+
+impl CommandOutput {
+    pub fn success(&self) -> bool {
+        self.status == 0
+    }
+}
+
+/// Runs commands in the guest's shell over an [`AgentChannel`].
+#[derive(Debug)]
+pub struct GuestAgent {
+    stream: UnixStream,
+    /// Read but not consumed yet
+    pending: Vec<u8>,
+    timeout: Duration,
+    commands: u32,
+}
+
+impl GuestAgent {
+    /// Connects to the guest. `timeout` bounds connecting and every later call.
+    pub fn connect(channel: &AgentChannel, timeout: Duration) -> Result<Self> {
+        let stream = channel.connect(Instant::now() + timeout)?;
+        Ok(Self {
+            stream,
+            pending: Vec::new(),
+            timeout,
+            commands: 0,
+        })
+    }
+
+    /// Reads the guest's output until `pattern`, e.g. a shell prompt, and returns it up to and
+    /// including `pattern`, with `\r\n` turned into `\n`.
+    pub fn wait_for(&mut self, pattern: &str) -> Result<String> {
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            self.pending.retain(|&byte| byte != b'\r');
+            if let Some(start) = find(&self.pending, pattern.as_bytes()) {
+                let rest = self.pending.split_off(start + pattern.len());
+                let output = std::mem::replace(&mut self.pending, rest);
+                return Ok(String::from_utf8_lossy(&output).into_owned());
+            }
+            self.fill(deadline)
+                .context(format!("Didn't see {:?} in the guest's output", pattern))?;
+        }
+    }
+
+    /// Runs `command` in the guest's shell and waits for it to finish.
+    pub fn run(&mut self, command: &str) -> Result<CommandOutput> {
+        self.commands += 1;
+        let id = self.commands;
+        // The quotes keep the echoed command line from matching the markers
+        let line = format!(
+            "echo __agent{}\"\"_start; {}\necho __agent{}\"\"_end $?\n",
+            id, command, id
+        );
+        self.stream
+            .write_all(line.as_bytes())
+            .context("Failed to send the command to the guest")?;
+
+        let end = format!("__agent{}_end ", id);
+        self.wait_for(&format!("__agent{}_start\n", id))?;
+        let output = self.wait_for(&end)?;
+        let status = self.wait_for("\n")?;
+        Ok(CommandOutput {
+            output: output.strip_suffix(&end).unwrap_or(&output).to_string(),
+            status: status
+                .trim()
+                .parse()
+                .context(format!("Bad exit status {:?}", status.trim()))?,
+        })
+    }
+
+    fn fill(&mut self, deadline: Instant) -> Result<()> {
+        let mut buf = [0u8; 4096];
+        self.stream.set_read_timeout(Some(remaining(deadline)?))?;
+        match self.stream.read(&mut buf) {
+            Ok(0) => Err(anyhow!("The guest closed the connection")),
+            Ok(len) => {
+                self.pending.extend_from_slice(&buf[..len]);
+                Ok(())
+            }
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                Err(anyhow!("Timed out waiting for the guest"))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        io::Write,
+        os::{fd::OwnedFd, unix::net::UnixListener},
+        path::PathBuf,
+        process::{Command, Stdio},
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use claim::{assert_err, assert_ok};
+
+    use super::{read_line, AgentChannel, GuestAgent};
+
+    fn socket_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("guest-agent-{}-{}", name, std::process::id()))
+    }
+
+    /// Stands in for the guest: a shell on the first connection, after the vsock handshake if
+    /// `vsock`.
+    fn serve_shell(path: &PathBuf, vsock: bool) -> thread::JoinHandle<()> {
+        let _ = fs::remove_file(path);
+        let listener = UnixListener::bind(path).unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            if vsock {
+                let deadline = Instant::now() + Duration::from_secs(5);
+                assert_eq!(read_line(&mut stream, deadline).unwrap(), "CONNECT 1234\n");
+                stream.write_all(b"OK 1073741824\n").unwrap();
+            }
+            stream.write_all(b"# ").unwrap();
+            Command::new("sh")
+                .stdin(Stdio::from(OwnedFd::from(stream.try_clone().unwrap())))
+                .stdout(Stdio::from(OwnedFd::from(stream)))
+                .status()
+                .unwrap();
+        })
+    }
+
+    #[test]
+    fn run_over_serial() {
+        let path = socket_path("serial");
+        let shell = serve_shell(&path, false);
+        let mut agent = assert_ok!(GuestAgent::connect(
+            &AgentChannel::Serial(path.clone()),
+            Duration::from_secs(5)
+        ));
+        assert_eq!(assert_ok!(agent.wait_for("# ")), "# ");
+
+        let output = assert_ok!(agent.run("echo hello; echo world"));
+        assert_eq!(output.output, "hello\nworld\n");
+        assert!(output.success());
+
+        let output = assert_ok!(agent.run("false"));
+        assert_eq!(output.output, "");
+        assert_eq!(output.status, 1);
+
+        // The shell never gets to print the end marker
+        assert_err!(agent.run("exit"));
+        shell.join().unwrap();
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn run_over_vsock() {
+        let path = socket_path("vsock");
+        let shell = serve_shell(&path, true);
+        let mut agent = assert_ok!(GuestAgent::connect(
+            &AgentChannel::Vsock {
+                socket: path.clone(),
+                port: 1234
+            },
+            Duration::from_secs(5)
+        ));
+        let output = assert_ok!(agent.run("printf 'a\\r\\nb'; (exit 3)"));
+        assert_eq!(output.output, "a\nb");
+        assert_eq!(output.status, 3);
+        assert_err!(agent.run("exit"));
+        shell.join().unwrap();
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn connect_times_out() {
+        let path = socket_path("missing");
+        assert_err!(GuestAgent::connect(
+            &AgentChannel::Serial(path),
+            Duration::from_millis(200)
+        ));
+    }
+}
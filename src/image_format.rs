@@ -0,0 +1,276 @@
+// Copyright (c) 2024, Qualcomm Innovation Center, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause-Clear
+
+//! Formats of VM images, told apart by their magic numbers, and the ones which need unpacking
+//! before they can be placed in memory: gzip and ELF.
+
+use anyhow::{anyhow, Result};
+
+use crate::Arm64ImageHeader;
+
+const ANDROID_BOOT_MAGIC: &[u8; 8] = b"ANDROID!";
+const GZIP_MAGIC: &[u8; 2] = b"\x1f\x8b";
+const ELF_MAGIC: &[u8; 4] = b"\x7fELF";
+
+/// The only compression method of gzip
+const GZIP_DEFLATE: u8 = 8;
+const GZIP_FHCRC: u8 = 1 << 1;
+const GZIP_FEXTRA: u8 = 1 << 2;
+const GZIP_FNAME: u8 = 1 << 3;
+const GZIP_FCOMMENT: u8 = 1 << 4;
+const GZIP_HEADER_SIZE: usize = 10;
+/// CRC32 and size of the uncompressed data
+const GZIP_TRAILER_SIZE: usize = 8;
+
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EM_AARCH64: u16 = 183;
+const ELF_HEADER_SIZE: usize = 64;
+const ELF_PHDR_SIZE: usize = 56;
+const PT_LOAD: u32 = 1;
+
+/// How the VM image is placed in memory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum ImageFormat {
+    /// Android boot image, bundling the kernel with a ramdisk and command line
+    Android,
+    /// gzip compressed image of another format, e.g. `Image.gz`
+    Gzip,
+    /// ELF executable, whose segments are loaded at their physical addresses
+    Elf,
+    /// arm64 Linux `Image`, placed where its header says
+    Arm64,
+    /// Anything else, loaded as is
+    Flat,
+}
+
+impl ImageFormat {
+    /// Tells the format of `image` from its magic number, falling back to a flat binary.
+    pub fn detect(image: &[u8]) -> Self {
+        if image.starts_with(ANDROID_BOOT_MAGIC) {
+            Self::Android
+        } else if image.starts_with(GZIP_MAGIC) {
+            Self::Gzip
+        } else if image.starts_with(ELF_MAGIC) {
+            Self::Elf
+        } else if Arm64ImageHeader::parse(image).is_some() {
+            Self::Arm64
+        } else {
+            Self::Flat
+        }
+    }
+}
+
+/// Decompresses the gzip file `data`, see RFC 1952.
+pub fn gunzip(data: &[u8]) -> Result<Vec<u8>> {
+    if data.len() < GZIP_HEADER_SIZE + GZIP_TRAILER_SIZE || !data.starts_with(GZIP_MAGIC) {
+        return Err(anyhow!("Not a gzip file"));
+    }
+    if data[2] != GZIP_DEFLATE {
+        return Err(anyhow!("Unknown gzip compression method {}", data[2]));
+    }
+    let flags = data[3];
+    let truncated = || anyhow!("Truncated gzip header");
+    let mut offset = GZIP_HEADER_SIZE;
+    if flags & GZIP_FEXTRA != 0 {
+        let len = data.get(offset..offset + 2).ok_or_else(truncated)?;
+        offset += 2 + usize::from(u16::from_le_bytes(len.try_into().unwrap()));
+    }
+    for flag in [GZIP_FNAME, GZIP_FCOMMENT] {
+        if flags & flag != 0 {
+            let len = data
+                .get(offset..)
+                .and_then(|rest| rest.iter().position(|&b| b == 0))
+                .ok_or_else(truncated)?;
+            offset += len + 1;
+        }
+    }
+    if flags & GZIP_FHCRC != 0 {
+        offset += 2;
+    }
+    let compressed = data
+        .get(offset..data.len() - GZIP_TRAILER_SIZE)
+        .ok_or_else(truncated)?;
+    let decompressed = miniz_oxide::inflate::decompress_to_vec(compressed)
+        .map_err(|e| anyhow!("Failed to decompress gzip file: {}", e))?;
+
+    let size = u32::from_le_bytes(data[data.len() - 4..].try_into().unwrap());
+    if size != decompressed.len() as u32 {
+        return Err(anyhow!(
+            "gzip file decompressed to {} bytes, expected {}",
+            decompressed.len(),
+            size
+        ));
+    }
+    Ok(decompressed)
+}
+
+/// A loadable segment of an [`ElfImage`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ElfSegment<'a> {
+    /// Physical address the segment is loaded at
+    pub addr: u64,
+    pub data: &'a [u8],
+    /// Memory the segment takes, past `data` it is zeroed
+    pub mem_size: u64,
+}
+
+/// A 64-bit little endian arm64 ELF executable.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ElfImage<'a> {
+    pub entry: u64,
+    pub segments: Vec<ElfSegment<'a>>,
+}
+
+impl<'a> ElfImage<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self> {
+        if data.len() < ELF_HEADER_SIZE || !data.starts_with(ELF_MAGIC) {
+            return Err(anyhow!("Not an ELF file"));
+        }
+        if data[4] != ELFCLASS64 || data[5] != ELFDATA2LSB {
+            return Err(anyhow!("Only 64-bit little endian ELF files can be loaded"));
+        }
+        let u16_at =
+            |offset: usize| u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap());
+        let u64_at =
+            |offset: usize| u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap());
+        let machine = u16_at(0x12);
+        if machine != EM_AARCH64 {
+            return Err(anyhow!("ELF file is for machine {}, not arm64", machine));
+        }
+
+        let phoff = usize::try_from(u64_at(0x20))?;
+        let phentsize = usize::from(u16_at(0x36));
+        let phnum = usize::from(u16_at(0x38));
+        if phentsize < ELF_PHDR_SIZE
+            || phoff
+                .checked_add(phentsize * phnum)
+                .is_none_or(|end| end > data.len())
+        {
+            return Err(anyhow!("Truncated ELF program headers"));
+        }
+        let mut segments = Vec::new();
+        for phdr in (0..phnum).map(|i| &data[phoff + i * phentsize..][..ELF_PHDR_SIZE]) {
+            let field =
+                |offset: usize| u64::from_le_bytes(phdr[offset..offset + 8].try_into().unwrap());
+            if u32::from_le_bytes(phdr[0..4].try_into().unwrap()) != PT_LOAD {
+                continue;
+            }
+            let (offset, addr, file_size, mem_size) = (field(8), field(24), field(32), field(40));
+            let segment_data = usize::try_from(offset)
+                .ok()
+                .zip(usize::try_from(file_size).ok())
+                .and_then(|(offset, len)| data.get(offset..offset.checked_add(len)?))
+                .ok_or(anyhow!("ELF segment at {:#x} lies outside the file", addr))?;
+            if mem_size < file_size {
+                return Err(anyhow!(
+                    "ELF segment at {:#x} is smaller than its data",
+                    addr
+                ));
+            }
+            segments.push(ElfSegment {
+                addr,
+                data: segment_data,
+                mem_size,
+            });
+        }
+        if segments.is_empty() {
+            return Err(anyhow!("ELF file has nothing to load"));
+        }
+        Ok(Self {
+            entry: u64_at(0x18),
+            segments,
+        })
+    }
+
+    /// Lowest and end address of the memory the segments take.
+    pub fn extent(&self) -> (u64, u64) {
+        let start = self.segments.iter().map(|s| s.addr).min().unwrap_or(0);
+        let end = self
+            .segments
+            .iter()
+            .map(|s| s.addr.saturating_add(s.mem_size))
+            .max()
+            .unwrap_or(0);
+        (start, end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use claim::{assert_err, assert_ok};
+
+    use super::{gunzip, ElfImage, ImageFormat};
+
+    /// `gunyah ` 8 times, compressed by Python's gzip module
+    const GZIP: [u8; 30] = [
+        0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0x4b, 0x2f, 0xcd, 0xab, 0x4c,
+        0xcc, 0x50, 0x48, 0x27, 0x95, 0x02, 0x00, 0x65, 0x92, 0xdc, 0x66, 0x38, 0x00, 0x00, 0x00,
+    ];
+
+    /// An arm64 ELF file with a 16 byte segment at 0x8000_1000 taking 0x1000 bytes of memory,
+    /// followed by a note which isn't loaded
+    fn elf() -> Vec<u8> {
+        let mut elf = vec![0u8; 0x100];
+        elf[..4].copy_from_slice(b"\x7fELF");
+        elf[4] = 2;
+        elf[5] = 1;
+        elf[0x12..0x14].copy_from_slice(&183u16.to_le_bytes());
+        elf[0x18..0x20].copy_from_slice(&0x8000_1008u64.to_le_bytes());
+        elf[0x20..0x28].copy_from_slice(&0x40u64.to_le_bytes());
+        elf[0x36..0x38].copy_from_slice(&56u16.to_le_bytes());
+        elf[0x38..0x3a].copy_from_slice(&2u16.to_le_bytes());
+        let phdr = &mut elf[0x40..0x78];
+        phdr[..4].copy_from_slice(&1u32.to_le_bytes());
+        phdr[8..16].copy_from_slice(&0xf0u64.to_le_bytes());
+        phdr[24..32].copy_from_slice(&0x8000_1000u64.to_le_bytes());
+        phdr[32..40].copy_from_slice(&0x10u64.to_le_bytes());
+        phdr[40..48].copy_from_slice(&0x1000u64.to_le_bytes());
+        elf[0x78..0x7c].copy_from_slice(&4u32.to_le_bytes());
+        elf[0xf0..].fill(0xaa);
+        elf
+    }
+
+    #[test]
+    fn detect() {
+        let mut android = vec![0u8; 0x100];
+        android[..8].copy_from_slice(b"ANDROID!");
+        assert_eq!(ImageFormat::detect(&android), ImageFormat::Android);
+        assert_eq!(ImageFormat::detect(&GZIP), ImageFormat::Gzip);
+        assert_eq!(ImageFormat::detect(&elf()), ImageFormat::Elf);
+        let mut image = vec![0u8; 0x100];
+        image[56..60].copy_from_slice(b"ARM\x64");
+        assert_eq!(ImageFormat::detect(&image), ImageFormat::Arm64);
+        assert_eq!(ImageFormat::detect(&[0u8; 0x100]), ImageFormat::Flat);
+        assert_eq!(ImageFormat::detect(&[]), ImageFormat::Flat);
+    }
+
+    #[test]
+    fn gzip() {
+        assert_eq!(assert_ok!(gunzip(&GZIP)), b"gunyah ".repeat(8));
+
+        let mut corrupt = GZIP;
+        corrupt[26] += 1;
+        assert_err!(gunzip(&corrupt));
+        assert_err!(gunzip(&GZIP[..16]));
+        assert_err!(gunzip(&elf()));
+    }
+
+    #[test]
+    fn elf_segments() {
+        let data = elf();
+        let elf = assert_ok!(ElfImage::parse(&data));
+        assert_eq!(elf.entry, 0x8000_1008);
+        assert_eq!(elf.segments.len(), 1);
+        assert_eq!(elf.segments[0].addr, 0x8000_1000);
+        assert_eq!(elf.segments[0].data, [0xaa; 0x10]);
+        assert_eq!(elf.extent(), (0x8000_1000, 0x8000_2000));
+
+        let mut truncated = data.clone();
+        truncated.truncate(0xf8);
+        assert_err!(ElfImage::parse(&truncated));
+        let mut other_machine = data.clone();
+        other_machine[0x12] = 62;
+        assert_err!(ElfImage::parse(&other_machine));
+    }
+}
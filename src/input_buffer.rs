@@ -0,0 +1,127 @@
+// Copyright (c) 2024, Qualcomm Innovation Center, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause-Clear
+
+use std::{
+    io::{self, Read},
+    time::{Duration, Instant},
+};
+
+use vmm::TaskPoll;
+
+/// How soon input a device had no room for is offered again
+const RETRY_INTERVAL: Duration = Duration::from_millis(1);
+/// How often input tasks check whether their device is still there while there's no input
+const CHECK_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Takes input for a device and returns how many bytes of it the device had room for, or None
+/// once the device is gone.
+pub type ReceiveInput = Box<dyn FnMut(&[u8]) -> Option<usize> + Send>;
+
+/// What became of a stream passed to [`InputBuffer::forward`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StreamStatus {
+    Open,
+    Ended,
+    ReceiverGone,
+}
+
+/// Input on its way to a device, held back while the device has no room for it, so input tasks
+/// never block their [`vmm::DeviceExecutor`].
+pub struct InputBuffer {
+    receive: ReceiveInput,
+    pending: Vec<u8>,
+}
+
+impl InputBuffer {
+    pub fn new(receive: ReceiveInput) -> Self {
+        Self {
+            receive,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Passes held back input and then `data` to the device. Returns false once the device is
+    /// gone.
+    pub fn push(&mut self, data: &[u8]) -> bool {
+        self.pending.extend_from_slice(data);
+        let Some(len) = (self.receive)(&self.pending) else {
+            return false;
+        };
+        self.pending.drain(..len);
+        true
+    }
+
+    /// Whether input is held back, in which case no more should be read.
+    pub fn is_blocked(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Reads what non-blocking `stream` has and passes it on, as far as the device has room.
+    pub fn forward<R: Read>(&mut self, stream: &mut R) -> io::Result<StreamStatus> {
+        if !self.push(&[]) {
+            return Ok(StreamStatus::ReceiverGone);
+        }
+        let mut buf = [0u8; 64];
+        while !self.is_blocked() {
+            match stream.read(&mut buf) {
+                Ok(0) => return Ok(StreamStatus::Ended),
+                Ok(len) => {
+                    if !self.push(&buf[..len]) {
+                        return Ok(StreamStatus::ReceiverGone);
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(StreamStatus::Open)
+    }
+
+    /// When the task forwarding this input wants to be polled again at the latest.
+    pub fn next_poll(&self, now: Instant) -> TaskPoll {
+        TaskPoll::Pending(Some(
+            now + if self.is_blocked() {
+                RETRY_INTERVAL
+            } else {
+                CHECK_INTERVAL
+            },
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use claim::assert_ok;
+
+    use super::{InputBuffer, StreamStatus};
+
+    #[test]
+    fn holds_back_input() {
+        let fifo = Arc::new(Mutex::new(Vec::<u8>::new()));
+        let room = Arc::new(Mutex::new(Some(2)));
+        let mut input = {
+            let fifo = fifo.clone();
+            let room = room.clone();
+            InputBuffer::new(Box::new(move |data| {
+                let len = (*room.lock().unwrap())?.min(data.len());
+                fifo.lock().unwrap().extend(&data[..len]);
+                Some(len)
+            }))
+        };
+
+        let mut stream: &[u8] = b"hello";
+        assert_eq!(assert_ok!(input.forward(&mut stream)), StreamStatus::Open);
+        assert_eq!(*fifo.lock().unwrap(), b"he");
+        assert!(input.is_blocked());
+
+        *room.lock().unwrap() = Some(64);
+        assert_eq!(assert_ok!(input.forward(&mut stream)), StreamStatus::Ended);
+        assert_eq!(*fifo.lock().unwrap(), b"hello");
+        assert!(!input.is_blocked());
+
+        *room.lock().unwrap() = None;
+        assert!(!input.push(b"x"));
+    }
+}
@@ -3,5 +3,41 @@
 
 mod types;
 pub use types::*;
+mod android_boot;
+pub use android_boot::*;
+mod arm64_image;
+pub use arm64_image::*;
+mod bench;
+pub use bench::*;
+mod cgroup;
+pub use cgroup::*;
+mod config_file;
+pub use config_file::*;
+mod image_format;
+pub use image_format::*;
+mod daemon;
+pub use daemon::*;
+mod guest_agent;
+pub use guest_agent::*;
+mod console_input;
+pub use console_input::*;
+mod console_log;
+pub use console_log::*;
+mod input_buffer;
+pub use input_buffer::*;
+mod pl011;
+pub use pl011::*;
+mod pl061;
+pub use pl061::*;
+mod privileges;
+pub use privileges::*;
 mod serial;
 pub use serial::*;
+mod serial_backend;
+pub use serial_backend::*;
+mod sp805;
+pub use sp805::*;
+mod verify;
+pub use verify::*;
+mod virtio_console;
+pub use virtio_console::*;
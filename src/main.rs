@@ -2,20 +2,46 @@
 // SPDX-License-Identifier: BSD-3-Clause-Clear
 
 use std::cell::OnceCell;
-use std::ffi::OsStr;
+use std::ffi::{OsStr, OsString};
 use std::fmt::Debug;
-use std::io::Stdout;
+use std::io::{IsTerminal, Stdout, Write};
+use std::net::SocketAddr;
+use std::num::NonZeroUsize;
 use std::ops::Add;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use std::{fs, io, thread};
-use std::{path::PathBuf, str::FromStr};
+use std::fs::OpenOptions;
+use std::{env, fs, io, process, thread};
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 
 use anyhow::{anyhow, Context, Result};
-use clap::{ArgAction, Parser};
-use gunyah::GuestMemoryAccess;
-use gunyah_test_vmm::{GuestAddress, GuestSize, SerialDevice};
-use vmm::{FdtWriter, GunyahVirtualMachine};
+use clap::{ArgAction, CommandFactory, Parser};
+use core_affinity::CoreId;
+use gunyah::{GuestMemoryAccess, HugePageSize, VmType};
+use gunyah_test_vmm::{
+    attach_console, create_fdt_pl011_clock, create_fdt_serial_aliases, daemon_command, gunzip,
+    verify_image, with_config_file, AndroidBootImage, Arm64ImageHeader, BenchConfig, Benchmark,
+    CgroupLimits, ConsoleInput, ConsoleLog, Daemon, ElfImage, GuestAddress, GuestSize, ImageFormat,
+    LoggedOutput, Namespace, PayloadDigest, Pl061, PreparedVm, RawTerminal, RunAs, SerialBackend,
+    SerialDevice, SerialInput, SerialOutput, SerialType, Sp805, VirtioConsole, VmCgroup, VmControl,
+    WatchdogAction, VIRTIO_CONSOLE_ARGS,
+};
+use serde_json::{json, Value};
+use vmm::{
+    add_vhost_user_fs, create_fdt_reserved_memory, merge_fdt, parse_fdt, AccessId, ApiServer,
+    CacheInfo, ChosenConfig, CpuTopology, GdbServer, GicConfig, GunyahGuestMemoryRegion,
+    GunyahVirtualMachine, IoEngine, IoEngineKind, IrqGen, Ivshmem, MessageQueueConfig,
+    MetricsServer, MmioTrace, Monitor, NumaNode, PmuConfig, Ramoops, RestrictedDmaPool,
+    SeccompPolicy, TimerConfig, VcpuAffinity, VcpuScheduling, VhostUserConfig, VhostUserDevice,
+    Virtio9p, VirtioBalloon, VirtioDevice, VirtioGpu, VirtioInput, VirtioIommu, VirtioMmio,
+    VirtioPmem, VmExit, BALLOON_PAGE_SIZE,
+};
+
+const DEFAULT_COMMAND_LINE: &str = "nokaslr rw root=/dev/ram rdinit=/sbin/init";
 
 #[derive(Clone, Debug)]
 struct LoadFileArg {
@@ -26,28 +52,316 @@ struct LoadFileArg {
 impl FromStr for LoadFileArg {
     type Err = anyhow::Error;
 
+    /// Parses `FILE,ADDR`. Paths containing a comma are not supported.
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut parts = s.split(',');
-        let file = PathBuf::from(parts.next().ok_or(anyhow!("No path specified"))?);
-        let addr = GuestAddress::from_str(parts.next().ok_or(anyhow!("No address specified"))?)?;
-        Ok(Self { file, addr })
+        let (file, addr) = match s.split(',').collect::<Vec<_>>()[..] {
+            [file, addr] => (file, addr),
+            [_] => return Err(anyhow!("No address specified in {:?}, expected FILE,ADDR", s)),
+            _ => {
+                return Err(anyhow!(
+                    "Too many fields in {:?}, expected FILE,ADDR (paths containing ',' are not supported)",
+                    s
+                ))
+            }
+        };
+        if file.is_empty() {
+            return Err(anyhow!("No path specified in {:?}", s));
+        }
+        if addr.trim().is_empty() {
+            return Err(anyhow!("No address specified in {:?}", s));
+        }
+        let addr = GuestAddress::from_str(addr)
+            .with_context(|| format!("Invalid address {:?} for {}", addr, file))?;
+        Ok(Self {
+            file: PathBuf::from(file),
+            addr,
+        })
     }
 }
 
-#[derive(Parser, Debug)]
+/// Gunyah VM types of --vm-type, see [`VmType`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+enum VmTypeArg {
+    Unauthenticated,
+    /// Images authenticated by the Peripheral Authentication Service
+    Pas,
+    /// Android protected VM, whose --firmware authenticates the rest
+    Android,
+}
+
+/// Hypervisor vCPU scheduling of --vcpu-sched, see [`VcpuAffinity`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+enum VcpuSchedArg {
+    /// On the host CPUs running the vCPU threads
+    Proxy,
+    /// Each vCPU only on its CPU of --vcpu-sched-cpus
+    Static,
+    /// Each vCPU first on its CPU of --vcpu-sched-cpus, the hypervisor may migrate it
+    Sticky,
+}
+
+/// I/O engines of --io-engine, see [`IoEngineKind`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+enum IoEngineArg {
+    /// read and write syscalls
+    Sync,
+    /// An io_uring, falling back to sync where the kernel lacks it. Needs the io-uring feature.
+    IoUring,
+}
+
+impl Default for IoEngineArg {
+    fn default() -> Self {
+        if cfg!(feature = "io-uring") {
+            Self::IoUring
+        } else {
+            Self::Sync
+        }
+    }
+}
+
+impl From<IoEngineArg> for IoEngineKind {
+    fn from(arg: IoEngineArg) -> Self {
+        match arg {
+            IoEngineArg::Sync => IoEngineKind::Sync,
+            IoEngineArg::IoUring => IoEngineKind::IoUring,
+        }
+    }
+}
+
+/// Policies of --seccomp, see [`SeccompPolicy`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+enum SeccompArg {
+    /// Kill the VMM on disallowed syscalls
+    Kill,
+    /// Only log disallowed syscalls to the audit log
+    Log,
+}
+
+impl From<SeccompArg> for SeccompPolicy {
+    fn from(arg: SeccompArg) -> Self {
+        match arg {
+            SeccompArg::Kill => SeccompPolicy::Kill,
+            SeccompArg::Log => SeccompPolicy::Log,
+        }
+    }
+}
+
+/// What --reboot-policy does when the guest resets.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+enum RebootPolicy {
+    /// Exit the VMM
+    Exit,
+    /// Recreate the VM with the same configuration and boot it again
+    Restart,
+}
+
+impl From<VmTypeArg> for VmType {
+    fn from(arg: VmTypeArg) -> Self {
+        match arg {
+            VmTypeArg::Unauthenticated => VmType::Unauthenticated,
+            VmTypeArg::Pas => VmType::Pas,
+            VmTypeArg::Android => VmType::Android,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct MemoryArg {
+    base: GuestAddress,
+    size: GuestSize,
+    /// Defaults to lending if --protected, sharing otherwise
+    share_type: Option<gunyah::ShareType>,
+}
+
+impl FromStr for MemoryArg {
+    type Err = anyhow::Error;
+
+    /// Parses `base=ADDR,size=SZ[,share|lend]`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut base = None;
+        let mut size = None;
+        let mut share_type = None;
+        for field in s.split(',') {
+            match field.split_once('=') {
+                Some(("base", addr)) => {
+                    base = Some(
+                        GuestAddress::from_str(addr)
+                            .with_context(|| format!("Invalid base {:?} in {:?}", addr, s))?,
+                    )
+                }
+                Some(("size", sz)) => {
+                    size = Some(
+                        GuestSize::from_str(sz)
+                            .with_context(|| format!("Invalid size {:?} in {:?}", sz, s))?,
+                    )
+                }
+                None if field == "share" => share_type = Some(gunyah::ShareType::Share),
+                None if field == "lend" => share_type = Some(gunyah::ShareType::Lend),
+                _ => {
+                    return Err(anyhow!(
+                        "Unknown field {:?} in {:?}, expected base=ADDR,size=SZ[,share|lend]",
+                        field,
+                        s
+                    ))
+                }
+            }
+        }
+        Ok(Self {
+            base: base.ok_or(anyhow!("No base specified in {:?}", s))?,
+            size: size.ok_or(anyhow!("No size specified in {:?}", s))?,
+            share_type,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+struct ShareDirArg {
+    path: PathBuf,
+    tag: String,
+}
+
+impl FromStr for ShareDirArg {
+    type Err = anyhow::Error;
+
+    /// Parses `PATH,TAG`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let [path, tag] = s.split(',').collect::<Vec<_>>()[..] else {
+            return Err(anyhow!("Invalid {:?}, expected PATH,TAG", s));
+        };
+        if path.is_empty() {
+            return Err(anyhow!("No path specified in {:?}", s));
+        }
+        if tag.is_empty() {
+            return Err(anyhow!("No tag specified in {:?}", s));
+        }
+        Ok(Self {
+            path: PathBuf::from(path),
+            tag: tag.to_string(),
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+struct VcpuAffinityArg {
+    vcpu: u8,
+    cpu: usize,
+}
+
+impl FromStr for VcpuAffinityArg {
+    type Err = anyhow::Error;
+
+    /// Parses `VCPU:CPU`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (vcpu, cpu) = s
+            .split_once(':')
+            .ok_or(anyhow!("Invalid {:?}, expected VCPU:CPU", s))?;
+        Ok(Self {
+            vcpu: vcpu
+                .parse()
+                .with_context(|| format!("Invalid vCPU {:?} in {:?}", vcpu, s))?,
+            cpu: cpu
+                .parse()
+                .with_context(|| format!("Invalid host CPU {:?} in {:?}", cpu, s))?,
+        })
+    }
+}
+
+/// Devices which can be backed by a vhost-user backend with `--vhost-user`: name, virtio device
+/// ID and number of queues.
+const VHOST_USER_DEVICES: [(&str, u32, usize); 6] = [
+    ("net", 1, 2),
+    ("blk", 2, 1),
+    ("gpu", 16, 2),
+    ("input", 18, 2),
+    ("vsock", 19, 3),
+    ("sound", 25, 4),
+];
+const VHOST_USER_QUEUE_SIZE: u16 = 256;
+
+#[derive(Clone, Debug)]
+struct VhostUserArg {
+    socket: PathBuf,
+    device_type: u32,
+    num_queues: usize,
+    addr: GuestAddress,
+    interrupt: u32,
+}
+
+impl FromStr for VhostUserArg {
+    type Err = anyhow::Error;
+
+    /// Parses `SOCKET,TYPE,ADDR,SPI`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let [socket, name, addr, interrupt] = s.split(',').collect::<Vec<_>>()[..] else {
+            return Err(anyhow!("Invalid {:?}, expected SOCKET,TYPE,ADDR,SPI", s));
+        };
+        if socket.is_empty() {
+            return Err(anyhow!("No socket specified in {:?}", s));
+        }
+        let (_, device_type, num_queues) = VHOST_USER_DEVICES
+            .into_iter()
+            .find(|(known, _, _)| *known == name)
+            .ok_or(anyhow!(
+                "Unknown vhost-user device type {:?}, expected one of {:?}",
+                name,
+                VHOST_USER_DEVICES.map(|(name, _, _)| name)
+            ))?;
+        let addr = GuestAddress::from_str(addr)
+            .with_context(|| format!("Invalid address {:?} for {}", addr, socket))?;
+        let interrupt = interrupt
+            .parse()
+            .with_context(|| format!("Invalid SPI {:?} for {}", interrupt, socket))?;
+        Ok(Self {
+            socket: PathBuf::from(socket),
+            device_type,
+            num_queues,
+            addr,
+            interrupt,
+        })
+    }
+}
+
+#[derive(Parser, Clone, Debug)]
 /// Run a Gunyah Virtual Machine
 struct RunCommand {
-    /// Binary image to execute
+    /// Read options from a TOML (*.toml) or JSON file whose keys are long option names, or
+    /// image and rdisk. Options given on the command line replace the file's.
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Binary image to execute: a raw binary, an arm64 Linux Image, an ELF executable or an
+    /// Android boot image, any of them but the last may be gzip compressed
     image: PathBuf,
+    /// Format of the image, detected from its contents if not specified
+    #[arg(long, value_enum)]
+    image_format: Option<ImageFormat>,
+
+    /// PEM-encoded ed25519 public key used to verify the binary image before loading it
+    #[arg(long, requires = "signature")]
+    verify_key: Option<PathBuf>,
+
+    /// Detached ed25519 signature of the binary image
+    #[arg(long, requires = "verify_key")]
+    signature: Option<PathBuf>,
 
-    /// Base address of the binary image. If not specified, then use MEM_BASE.
+    /// Expected digest of the binary image as sha256=HEX, checked before loading it
+    #[arg(long)]
+    verify: Option<PayloadDigest>,
+    /// Expected digest of RDISK as sha256=HEX, checked before loading it
+    #[arg(long, requires = "rdisk")]
+    verify_rdisk: Option<PayloadDigest>,
+
+    /// Base address of the binary image. If not specified, then use MEM_BASE, or for arm64 Linux
+    /// Images the lowest address the image header allows. ELF executables are always loaded at
+    /// the addresses of their segments.
     #[arg(long, short)]
     image_base: Option<GuestAddress>,
 
-    // Ramdisk to be loaded
-    rdisk: PathBuf,
+    /// Ramdisk to be loaded. May be left out if IMAGE is an Android boot image with a ramdisk;
+    /// if both are given, RDISK is appended to the boot image's ramdisk.
+    rdisk: Option<PathBuf>,
 
-    /// List of files to load into the VM memory
+    /// List of files to load into the VM memory. Paths must not contain ','
     #[arg(id = "FILE,ADDR")]
     files: Vec<LoadFileArg>,
 
@@ -59,25 +373,109 @@ struct RunCommand {
     #[arg(long, short, default_value_t = GuestSize::from_str("100MB").unwrap())]
     size: GuestSize,
 
+    /// Add a bank of RAM besides the one at --mem-base, as base=ADDR,size=SZ[,share|lend].
+    /// May be repeated. Images, the DTB and the ramdisk are only placed in the first bank.
+    #[arg(long)]
+    memory: Vec<MemoryArg>,
+
     /// Number of vCPUs to spawn
     #[arg(long, default_value_t = 8)]
     vcpus: u8,
+    /// Number of vCPUs the VM can have, including those added after it started with the control
+    /// socket's add-vcpu command. Defaults to --vcpus.
+    #[arg(long)]
+    max_vcpus: Option<u8>,
+
+    /// Pin vCPU threads to host CPUs, as a comma separated list of VCPU:CPU pairs. vCPUs which
+    /// aren't listed run wherever the host schedules them.
+    #[arg(long, value_delimiter = ',')]
+    vcpu_affinity: Vec<VcpuAffinityArg>,
+    /// How the hypervisor schedules vCPUs
+    #[arg(long, value_enum, default_value_t = VcpuSchedArg::Proxy)]
+    vcpu_sched: VcpuSchedArg,
+    /// Physical CPU of each vCPU with static or sticky --vcpu-sched, as a comma separated list
+    /// in order of vCPU IDs. Covers --max-vcpus vCPUs.
+    #[arg(long, value_delimiter = ',')]
+    vcpu_sched_cpus: Vec<u32>,
+    /// Hypervisor priority of all vCPUs, relative to its default
+    #[arg(long, allow_negative_numbers = true)]
+    vcpu_sched_priority: Option<i32>,
+    /// Hypervisor timeslice of all vCPUs in microseconds
+    #[arg(long)]
+    vcpu_sched_timeslice: Option<u32>,
 
     /// Address to place DTB configuration. If none, places at the end of guest memory
     #[arg(long)]
     dtb_base: Option<GuestAddress>,
+    /// Merge the nodes of this device tree blob or compiled overlay into the generated device
+    /// tree, e.g. to describe devices for out-of-tree drivers. Overlay fragments must use
+    /// target-path.
+    #[arg(long)]
+    dtb_overlay: Option<PathBuf>,
+    /// Write the device tree blob the VM boots with to this file, e.g. to decompile it with dtc
+    #[arg(long)]
+    dump_dtb: Option<PathBuf>,
+
+    /// Reserve guest physical addresses starting here for memory added after the VM started with
+    /// the control socket's add-memory command
+    #[arg(long)]
+    hotplug_memory: Option<GuestAddress>,
+    /// Size of the addresses reserved for hotplugged memory
+    #[arg(long, default_value_t = GuestSize::from_str("1GB").unwrap(), requires = "hotplug_memory")]
+    hotplug_memory_size: GuestSize,
 
     /// Use huge pages
     #[arg(long)]
     huge_pages: bool,
+    /// Back the VM's memory with hugetlbfs pages of this size (2MB or 1GB) instead of
+    /// transparent huge pages. The host must have enough pages of the size reserved, and --size
+    /// must be a multiple of it.
+    #[arg(long, conflicts_with = "huge_pages")]
+    hugetlb_page_size: Option<GuestSize>,
+
+    /// Describe the VM to the guest as this many NUMA nodes, each with an equal share of the
+    /// memory and vCPUs
+    #[arg(long)]
+    numa_nodes: Option<u32>,
+    /// Allocate the VM's memory from these host NUMA nodes, as a comma separated list with
+    /// either one node for all memory or one for each node of --numa-nodes
+    #[arg(long, value_delimiter = ',')]
+    host_numa_nodes: Vec<u32>,
+
+    /// Don't add the empty cpu-idle-states property which makes older Resource Manager versions
+    /// set up PSCI for the VM
+    #[arg(long)]
+    no_force_psci: bool,
+
+    /// Groups the vCPUs into clusters of this many cores in the device tree's cpu-map. Without
+    /// it, the guest sees no topology.
+    #[arg(long)]
+    cores_per_cluster: Option<u32>,
+    /// Size of each core's L1 instruction and data caches, described with 64 byte lines
+    #[arg(long, requires = "cores_per_cluster")]
+    l1_cache_size: Option<GuestSize>,
+    /// Size of the L2 cache shared by the cores of a cluster, described with 64 byte lines
+    #[arg(long, requires = "cores_per_cluster")]
+    l2_cache_size: Option<GuestSize>,
 
     /// Launches an unprotected VM where primary guest memory is shared instead of lent
     #[arg(long = "unprotected", default_value_t = true, action=ArgAction::SetFalse)]
     protected: bool,
+    /// Type of the VM, which picks how the Resource Manager authenticates it
+    #[arg(long, value_enum, default_value_t = VmTypeArg::Unauthenticated)]
+    vm_type: VmTypeArg,
+    /// Firmware of a protected VM, which the Resource Manager authenticates and boots before the
+    /// image
+    #[arg(long, requires = "firmware_base")]
+    firmware: Option<PathBuf>,
+    /// Address to load the firmware at
+    #[arg(long, requires = "firmware")]
+    firmware_base: Option<GuestAddress>,
 
-    /// Additional kernel command line options
-    #[arg(long = "cmdline", short, default_value_t=String::from("nokaslr earlycon console=ttyACM0 rw root=/dev/ram rdinit=/sbin/init console=ttyS0"))]
-    command_line: String,
+    /// Kernel command line. If not specified, earlycon and console options are generated for the
+    /// console serial device.
+    #[arg(long = "cmdline", short)]
+    command_line: Option<String>,
 
     /// GIC Distributor base address
     #[arg(long, default_value_t = 0x3FFF0000u64.into())]
@@ -87,74 +485,993 @@ struct RunCommand {
     gic_dist_size: GuestSize,
     /// GIC Redistributor base address. If none, redistributor is placed before the distributor.
     #[arg(long)]
-    gic_redist_base: Option<GuestAddress>,
-    /// GIC Redistributor size per CPU
-    #[arg(long, default_value_t = 0x20000u64.into())]
-    gic_redist_size: GuestSize,
+    gic_redist_base: Option<GuestAddress>,
+    /// GIC Redistributor size per CPU
+    #[arg(long, default_value_t = 0x20000u64.into())]
+    gic_redist_size: GuestSize,
+
+    /// Architected timer PPIs, as a comma separated list of the secure, non-secure, virtual and
+    /// hypervisor timer interrupts
+    #[arg(long, value_delimiter = ',', default_values_t = [13, 14, 11, 10])]
+    timer_irqs: Vec<u32>,
+    /// Architected timer frequency in Hz
+    #[arg(long, default_value_t = 19_200_000)]
+    timer_freq: u32,
+    /// Describe the PMU to the guest, with this PPI as its overflow interrupt. Linux on most
+    /// boards uses PPI 7.
+    #[arg(long)]
+    pmu_irq: Option<u32>,
+
+    /// UART emulated by the serial ports
+    #[arg(long, value_enum, default_value_t = SerialType::Ns16550a)]
+    serial_type: SerialType,
+    /// Serial port address. Repeat to add more serial ports, which are aliased serial0, serial1,
+    /// ... in order.
+    #[arg(long, default_values_t = [GuestAddress::from(0x3f800u64)])]
+    serial_base: Vec<GuestAddress>,
+    /// Serial port SPI, one per serial port address
+    #[arg(long, default_values_t = [1])]
+    serial_interrupt: Vec<u32>,
+    /// Where a serial port's output goes and its input comes from: stdio, file:PATH (output
+    /// only), pty, socket:PATH or null. One per serial port address; all ports use stdio if not
+    /// specified. Of the stdio ports, only the console reads stdin.
+    #[arg(long)]
+    serial_backend: Vec<SerialBackend>,
+    /// Also append the output of all serial ports to this file, whatever their backends. Each
+    /// line is prefixed with the seconds since the VM was set up, from a monotonic clock, and the
+    /// port's alias.
+    #[arg(long)]
+    console_log: Option<PathBuf>,
+    /// Give the VM a Resource Manager console and relay its output from this host file, e.g.
+    /// the host's tty of the VM's RM console
+    #[arg(long)]
+    rm_console: Option<PathBuf>,
+    /// Where the Resource Manager console's output goes: stdio, file:PATH, pty, socket:PATH or
+    /// null. It takes no input.
+    #[arg(long, default_value_t = SerialBackend::Stdio, requires = "rm_console")]
+    rm_console_backend: SerialBackend,
+    /// Add a message queue between the VM and host kernel drivers:
+    /// LABEL,to-host|to-guest[,SIZE,DEPTH], e.g. 1,to-host. Messages hold up to 240 bytes and
+    /// 8 are queued by default.
+    #[arg(long)]
+    message_queue: Vec<MessageQueueConfig>,
+    /// Alias number of the serial port used as earlycon and console
+    #[arg(long, default_value_t = 0)]
+    console: usize,
+
+    /// Add a virtio console at this address and use it as the console instead of the serial
+    /// port. The guest's memory must be accessible to the host, so this requires --unprotected or
+    /// --restricted-dma.
+    #[arg(long)]
+    virtio_console: Option<GuestAddress>,
+    /// virtio console SPI
+    #[arg(long, default_value_t = 2)]
+    virtio_console_interrupt: u32,
+
+    /// Add a virtio balloon at this address which frees the memory of pages the guest gives up.
+    /// Requires --unprotected.
+    #[arg(long)]
+    balloon: Option<GuestAddress>,
+    /// virtio balloon SPI
+    #[arg(long, default_value_t = 3)]
+    balloon_interrupt: u32,
+    /// Amount of memory the guest is asked to put into the balloon
+    #[arg(long, default_value_t = 0u64.into(), requires = "balloon")]
+    balloon_size: GuestSize,
+
+    /// Add a virtio-fs device at this address which exports the directory served by a
+    /// vhost-user-fs backend such as virtiofsd. Requires --unprotected.
+    #[arg(long, requires = "virtiofs_socket")]
+    virtiofs: Option<GuestAddress>,
+    /// Socket the vhost-user-fs backend listens on
+    #[arg(long, requires = "virtiofs")]
+    virtiofs_socket: Option<PathBuf>,
+    /// Tag the guest mounts the shared directory by
+    #[arg(long, default_value = "virtiofs", requires = "virtiofs")]
+    virtiofs_tag: String,
+    /// virtio-fs SPI
+    #[arg(long, default_value_t = 4)]
+    virtiofs_interrupt: u32,
+
+    /// Add a 2D virtio-gpu at this address whose display is written to --gpu-output whenever the
+    /// guest updates it. Requires --unprotected or --restricted-dma.
+    #[arg(long, requires = "gpu_output")]
+    gpu: Option<GuestAddress>,
+    /// virtio-gpu SPI
+    #[arg(long, default_value_t = 5)]
+    gpu_interrupt: u32,
+    /// PPM image the virtio-gpu display is written to
+    #[arg(long, requires = "gpu")]
+    gpu_output: Option<PathBuf>,
+    /// Width of the virtio-gpu display in pixels
+    #[arg(long, default_value_t = 1024)]
+    gpu_width: u32,
+    /// Height of the virtio-gpu display in pixels
+    #[arg(long, default_value_t = 768)]
+    gpu_height: u32,
+
+    /// Add a virtio-pmem device at this address which exposes --pmem-file as persistent memory.
+    /// Requires --unprotected or --restricted-dma.
+    #[arg(long, requires_all = ["pmem_file", "pmem_base"])]
+    pmem: Option<GuestAddress>,
+    /// virtio-pmem SPI
+    #[arg(long, default_value_t = 8)]
+    pmem_interrupt: u32,
+    /// File backing the virtio-pmem device
+    #[arg(long, requires = "pmem")]
+    pmem_file: Option<PathBuf>,
+    /// Guest address of the virtio-pmem memory, which must lie outside the VM's memory
+    #[arg(long, requires = "pmem")]
+    pmem_base: Option<GuestAddress>,
+    /// Map the virtio-pmem memory read-only and never write back to --pmem-file
+    #[arg(long, requires = "pmem")]
+    pmem_read_only: bool,
+
+    /// Add an ivshmem-style shared memory device with its doorbell register page at this address.
+    /// The guest can bind it with uio_pdrv_genirq.of_id=gunyah-vmm,ivshmem.
+    #[arg(long, requires_all = ["shmem_file", "shmem_base"])]
+    shmem: Option<GuestAddress>,
+    /// Shared memory device SPI
+    #[arg(long, default_value_t = 12)]
+    shmem_interrupt: u32,
+    /// File, e.g. a memfd shared by another process, whose whole contents are shared with the
+    /// guest
+    #[arg(long, requires = "shmem")]
+    shmem_file: Option<PathBuf>,
+    /// Guest address of the shared memory, which must lie outside the VM's memory
+    #[arg(long, requires = "shmem")]
+    shmem_base: Option<GuestAddress>,
+
+    /// Share a host directory with the guest over virtio-9p, as PATH,TAG. The guest mounts it
+    /// with `mount -t 9p -o trans=virtio,version=9p2000.L TAG DIR`. Requires --unprotected or
+    /// --restricted-dma.
+    #[arg(long)]
+    share_dir: Option<ShareDirArg>,
+    /// virtio-9p device address
+    #[arg(long, default_value_t = 0x3f000u64.into(), requires = "share_dir")]
+    share_dir_base: GuestAddress,
+    /// virtio-9p SPI
+    #[arg(long, default_value_t = 9)]
+    share_dir_interrupt: u32,
+
+    /// Add a virtio-iommu at this address and put the other in-process virtio devices behind it.
+    /// vhost-user devices bypass the iommu. Requires --unprotected.
+    #[arg(long)]
+    iommu: Option<GuestAddress>,
+    /// virtio-iommu SPI
+    #[arg(long, default_value_t = 7)]
+    iommu_interrupt: u32,
+
+    /// Add a virtio-input keyboard and mouse at this address. Requires --unprotected or
+    /// --restricted-dma.
+    #[arg(long)]
+    input: Option<GuestAddress>,
+    /// virtio-input SPI
+    #[arg(long, default_value_t = 6)]
+    input_interrupt: u32,
+
+    /// Add a device at this address which stops the VM when the guest writes a value N to it,
+    /// and exit with code N.
+    #[arg(long)]
+    debug_exit: Option<GuestAddress>,
+
+    /// Serve the GDB remote protocol on this localhost port. GDB can read and write memory, and
+    /// pause and continue the VM, but Gunyah doesn't expose vCPU registers or single-stepping.
+    #[arg(long)]
+    gdb: Option<u16>,
+
+    /// Start the VM with its vCPUs paused. Sending the VMM SIGUSR2 resumes the vCPUs and SIGUSR1
+    /// pauses them again; this works without the flag as well.
+    #[arg(long)]
+    paused: bool,
+    /// What to do when the guest resets, e.g. with PSCI SYSTEM_RESET
+    #[arg(long, value_enum, default_value_t = RebootPolicy::Restart)]
+    reboot_policy: RebootPolicy,
+    /// How serial file backends and virtio-9p read and write host files
+    #[arg(long, value_enum, default_value_t = IoEngineArg::default())]
+    io_engine: IoEngineArg,
+    /// Confine vCPU and device threads with seccomp filters once the VM is set up, so they can
+    /// only make the syscalls and Gunyah ioctls devices need. Disallowed syscalls kill the VMM, or
+    /// are logged to the audit log to find what a setup needs.
+    #[arg(long, value_enum)]
+    seccomp: Option<SeccompArg>,
+    /// Once the VM is set up, switch to this user and group before starting it, so a guest
+    /// exploiting a device bug doesn't get root: names or IDs, the group defaults to the user's.
+    /// A VM restarted on reset is set up as that user, who then needs access to /dev/gunyah.
+    #[arg(long, value_name = "USER[:GROUP]", conflicts_with = "secondary_vm")]
+    run_as: Option<RunAs>,
+    /// With --run-as, enter new namespaces of these kinds before switching users. Threads started
+    /// earlier, like the GDB server's, stay in the old ones.
+    #[arg(long, value_enum, value_delimiter = ',', requires = "run_as")]
+    unshare: Vec<Namespace>,
+    /// Run the VMM in a cgroup of its own below this cgroup v2 directory, e.g.
+    /// /sys/fs/cgroup/vms, to limit its resources with --cpu-quota and --memory-limit. The cgroup
+    /// is named after the VMM's pid and left behind for its statistics.
+    #[arg(long, value_name = "DIR", conflicts_with = "secondary_vm")]
+    cgroup_parent: Option<PathBuf>,
+    /// CPU time the VMM's threads may use, in CPUs, e.g. 1.5
+    #[arg(long, value_name = "CPUS", requires = "cgroup_parent")]
+    cpu_quota: Option<f64>,
+    /// Memory the VMM may use, including guest memory
+    #[arg(long, value_name = "SIZE", requires = "cgroup_parent")]
+    memory_limit: Option<GuestSize>,
+    /// Set the VM up and print its memory map and device tree, but don't load its images or
+    /// start it. Device backends are still opened.
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Accept JSON control commands (pause, resume, interrupt injection, memory dumps, ...) on a
+    /// Unix socket at this path, one command per line.
+    #[arg(long)]
+    api_socket: Option<PathBuf>,
+
+    /// When the VM stops, print how often each vCPU exited to the VMM and how long handling the
+    /// exits took, by exit reason, and how much memory the balloon freed. The control socket's
+    /// query-exit-stats command reports the vCPU exits while the VM runs.
+    #[arg(long)]
+    exit_stats: bool,
+
+    /// Serve metrics of the running VM in Prometheus' text format over HTTP at this address, e.g.
+    /// 127.0.0.1:9100: vCPU exits by reason, interrupts injected, memory mapped and punched, and
+    /// MMIO accesses per device. The control socket's query-metrics command returns the same.
+    #[arg(long, value_name = "ADDR:PORT")]
+    metrics_addr: Option<SocketAddr>,
+
+    /// Write every MMIO access the vCPUs make to devices to this file: time, vCPU, direction,
+    /// address/width and value
+    #[arg(long)]
+    mmio_trace: Option<PathBuf>,
+    /// Keep the last N MMIO accesses the vCPUs make to devices and print them when the VM stops
+    #[arg(long, value_name = "N", conflicts_with = "mmio_trace")]
+    mmio_trace_last: Option<usize>,
+
+    /// Reserve guest memory for the guest kernel's ramoops pstore backend and write its contents
+    /// to this file whenever the VM stops, so crash logs survive even when the console is lost.
+    /// The contents are kept across VM resets.
+    #[arg(long)]
+    ramoops: Option<PathBuf>,
+    /// Guest address of the ramoops memory, which must lie outside the VM's memory. If not
+    /// specified, it's placed right after the VM's memory.
+    #[arg(long, requires = "ramoops")]
+    ramoops_base: Option<GuestAddress>,
+    /// Size of the ramoops memory, a power of two of at least 16KiB
+    #[arg(long, default_value_t = GuestSize::from_str("1MB").unwrap(), requires = "ramoops")]
+    ramoops_size: GuestSize,
+
+    /// Reserve this much guest memory, shared with the host, as a restricted DMA pool which the
+    /// guest bounces the buffers of the in-process virtio devices through (swiotlb). Lets a
+    /// protected VM use the virtio console, gpu, input, pmem and 9p devices.
+    #[arg(long, conflicts_with = "iommu")]
+    restricted_dma: Option<GuestSize>,
+    /// Guest address of the restricted DMA pool, which must lie outside the VM's memory. If not
+    /// specified, it's placed right after the VM's memory and the ramoops memory.
+    #[arg(long, requires = "restricted_dma")]
+    restricted_dma_base: Option<GuestAddress>,
+
+    /// Add a PL061 GPIO controller at this address. Changes to output lines are printed.
+    #[arg(long)]
+    gpio: Option<GuestAddress>,
+    /// PL061 GPIO controller SPI
+    #[arg(long, default_value_t = 11)]
+    gpio_interrupt: u32,
+
+    /// Add a test device at this address which raises its interrupt every --irq-gen-period-us
+    /// or when the guest asks it to, and counts how many interrupts the guest acknowledged
+    #[arg(long)]
+    irq_gen: Option<GuestAddress>,
+    /// Interrupt generator SPI
+    #[arg(long, default_value_t = 13)]
+    irq_gen_interrupt: u32,
+    /// Microseconds between generated interrupts. 0 only raises interrupts on request.
+    #[arg(long, default_value_t = 0, requires = "irq_gen")]
+    irq_gen_period_us: u64,
+
+    /// Add a frame at this address which raises MSIs, described to the guest as GICv3
+    /// message based interrupts
+    #[arg(long)]
+    msi_frame: Option<GuestAddress>,
+    /// First SPI reserved for MSIs
+    #[arg(long, default_value_t = 64, requires = "msi_frame")]
+    msi_first_interrupt: u32,
+    /// Number of SPIs reserved for MSIs
+    #[arg(long, default_value_t = 32, requires = "msi_frame")]
+    msi_interrupts: u32,
+
+    /// Add an SP805 watchdog at this address
+    #[arg(long)]
+    watchdog: Option<GuestAddress>,
+    /// SP805 watchdog SPI
+    #[arg(long, default_value_t = 10)]
+    watchdog_interrupt: u32,
+    /// What to do when the watchdog expires. `exit` stops the VMM with exit code 124.
+    #[arg(long, value_enum, default_value_t = WatchdogAction::Reset, requires = "watchdog")]
+    watchdog_action: WatchdogAction,
+
+    /// Add a virtio device whose queues are processed by the vhost-user backend listening on
+    /// SOCKET, as SOCKET,TYPE,ADDR,SPI. TYPE is one of net, blk, gpu, input, vsock or sound.
+    /// Requires --unprotected.
+    #[arg(long)]
+    vhost_user: Vec<VhostUserArg>,
+
+    /// Run another VM alongside this one, configured by a file as for --config. Repeat for more
+    /// VMs. Secondary VMs don't read stdin or react to SIGUSR1/SIGUSR2, so give them their own
+    /// --serial-backend. They restart on reset and stop when this VM exits.
+    #[arg(long)]
+    secondary_vm: Vec<PathBuf>,
+}
+
+impl RunCommand {
+    /// Whether the guest console takes its input from stdin.
+    fn console_reads_stdin(&self) -> bool {
+        self.virtio_console.is_some()
+            || self
+                .serial_backend
+                .get(self.console)
+                .is_none_or(|backend| *backend == SerialBackend::Stdio)
+    }
+
+    fn possible_vcpus(&self) -> u8 {
+        self.max_vcpus.unwrap_or(self.vcpus)
+    }
+
+    fn vcpu_scheduling(&self) -> VcpuScheduling {
+        let cpus = self.vcpu_sched_cpus.clone();
+        VcpuScheduling {
+            affinity: match self.vcpu_sched {
+                VcpuSchedArg::Proxy => VcpuAffinity::Proxy,
+                VcpuSchedArg::Static => VcpuAffinity::Static(cpus),
+                VcpuSchedArg::Sticky => VcpuAffinity::Sticky(cpus),
+            },
+            priority: self.vcpu_sched_priority,
+            timeslice_us: self.vcpu_sched_timeslice,
+        }
+    }
+
+    /// Granularity of the VM's memory, which depends on the kind of huge pages it uses.
+    fn page_size(&self) -> usize {
+        if let Ok(Some(page_size)) = self.hugetlb_page_size() {
+            page_size.bytes()
+        } else if self.huge_pages {
+            usize::from_str(
+                fs::read_to_string("/sys/kernel/mm/transparent_hugepage/hpage_pmd_size")
+                    .unwrap()
+                    .trim(),
+            )
+            .context("Failed to parse hpage_pmd_size")
+            .unwrap()
+        } else {
+            page_size::get()
+        }
+    }
+
+    fn hugetlb_page_size(&self) -> Result<Option<HugePageSize>> {
+        self.hugetlb_page_size
+            .map(|size| match *size {
+                0x20_0000 => Ok(HugePageSize::Size2M),
+                0x4000_0000 => Ok(HugePageSize::Size1G),
+                _ => Err(anyhow!("Unsupported hugetlbfs page size {}", size)),
+            })
+            .transpose()
+    }
+
+    /// The guest NUMA nodes of --numa-nodes: vCPUs are assigned to nodes in order of their IDs.
+    fn guest_numa_nodes(&self) -> Vec<NumaNode> {
+        let Some(count) = self.numa_nodes else {
+            return Vec::new();
+        };
+        let vcpus = u32::from(self.possible_vcpus());
+        let size = *self.size / u64::from(count);
+        (0..count)
+            .map(|id| NumaNode {
+                memory: vec![(*self.mem_base + u64::from(id) * size, size)],
+                vcpus: (0..vcpus)
+                    .filter(|vcpu| vcpu * count / vcpus == id)
+                    .collect(),
+            })
+            .collect()
+    }
+
+    fn timer_config(&self) -> TimerConfig {
+        TimerConfig {
+            secure: self.timer_irqs[0],
+            non_secure: self.timer_irqs[1],
+            virt: self.timer_irqs[2],
+            hyp: self.timer_irqs[3],
+            clock_frequency: self.timer_freq,
+        }
+    }
+
+    fn cpu_topology(&self) -> Option<CpuTopology> {
+        const CACHE_LINE_SIZE: u32 = 64;
+        let cache = |size: Option<GuestSize>| {
+            size.map(|size| CacheInfo {
+                size: *size as u32,
+                line_size: CACHE_LINE_SIZE,
+            })
+        };
+        Some(CpuTopology {
+            cores_per_cluster: self.cores_per_cluster?,
+            l1: cache(self.l1_cache_size),
+            l2: cache(self.l2_cache_size),
+        })
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if !self.image.is_file() {
+            return Err(anyhow!(format!("{} is not a file", self.image.display())));
+        }
+
+        if self.vcpus == 0 {
+            return Err(anyhow!(
+                "Need more than zero vCPUs to run a virtual machine"
+            ));
+        }
+
+        for path in [&self.verify_key, &self.signature].into_iter().flatten() {
+            if !path.is_file() {
+                return Err(anyhow!(format!("{} is not a file", path.display())));
+            }
+        }
+
+        if self.possible_vcpus() < self.vcpus {
+            return Err(anyhow!(
+                "--max-vcpus can't be less than the {} vCPUs the VM starts with",
+                self.vcpus
+            ));
+        }
+
+        for (i, affinity) in self.vcpu_affinity.iter().enumerate() {
+            if affinity.vcpu >= self.vcpus {
+                return Err(anyhow!(
+                    "Can't pin vCPU {}, the VM only has {} vCPUs",
+                    affinity.vcpu,
+                    self.vcpus
+                ));
+            }
+            if self.vcpu_affinity[..i]
+                .iter()
+                .any(|other| other.vcpu == affinity.vcpu)
+            {
+                return Err(anyhow!("vCPU {} is pinned more than once", affinity.vcpu));
+            }
+        }
+
+        match self.vcpu_sched {
+            VcpuSchedArg::Proxy if !self.vcpu_sched_cpus.is_empty() => {
+                return Err(anyhow!(
+                    "--vcpu-sched-cpus requires static or sticky --vcpu-sched"
+                ));
+            }
+            VcpuSchedArg::Static | VcpuSchedArg::Sticky
+                if self.vcpu_sched_cpus.len() != usize::from(self.possible_vcpus()) =>
+            {
+                return Err(anyhow!(
+                    "--vcpu-sched-cpus lists {} CPUs, expected one for each of the {} vCPUs",
+                    self.vcpu_sched_cpus.len(),
+                    self.possible_vcpus()
+                ));
+            }
+            _ => {}
+        }
+
+        if self.cores_per_cluster == Some(0) {
+            return Err(anyhow!("Clusters need at least one core"));
+        }
+        for size in [self.l1_cache_size, self.l2_cache_size]
+            .into_iter()
+            .flatten()
+        {
+            if u32::try_from(*size).is_err() {
+                return Err(anyhow!("Cache size {} is too large", size));
+            }
+        }
+
+        if self.timer_irqs.len() != 4 {
+            return Err(anyhow!(
+                "--timer-irqs needs the secure, non-secure, virtual and hypervisor timer PPIs, got {}",
+                self.timer_irqs.len()
+            ));
+        }
+        if let Some(ppi) = self.timer_irqs.iter().find(|&&ppi| ppi >= 16) {
+            return Err(anyhow!("Timer interrupt {} is not a PPI", ppi));
+        }
+        if let Some(ppi) = self.pmu_irq {
+            if ppi >= 16 {
+                return Err(anyhow!("PMU interrupt {} is not a PPI", ppi));
+            }
+            if self.timer_irqs.contains(&ppi) {
+                return Err(anyhow!("PMU interrupt {} is used by the timer", ppi));
+            }
+        }
+        if self.timer_freq == 0 {
+            return Err(anyhow!("Timer frequency can't be zero"));
+        }
+
+        if let Some(f) = self.files.iter().find(|f| !f.file.is_file()) {
+            return Err(anyhow!(format!("{} is not a file", f.file.display())));
+        }
+
+        if self.serial_base.len() != self.serial_interrupt.len() {
+            return Err(anyhow!(
+                "Got {} serial port addresses but {} serial port SPIs",
+                self.serial_base.len(),
+                self.serial_interrupt.len()
+            ));
+        }
+
+        if !self.serial_backend.is_empty() && self.serial_backend.len() != self.serial_base.len() {
+            return Err(anyhow!(
+                "Got {} serial port addresses but {} serial port backends",
+                self.serial_base.len(),
+                self.serial_backend.len()
+            ));
+        }
+
+        if self.console >= self.serial_base.len() {
+            return Err(anyhow!(
+                "Console serial{} doesn't exist, only {} serial ports are configured",
+                self.console,
+                self.serial_base.len()
+            ));
+        }
+
+        // Devices which only access the buffers the guest hands them, so they work through a
+        // restricted DMA pool
+        const BOUNCED_DEVICES: [&str; 5] = [
+            "virtio console",
+            "virtio-gpu",
+            "virtio-input device",
+            "virtio-pmem device",
+            "virtio-9p device",
+        ];
+        let mut spis = self.serial_interrupt.clone();
+        for (name, base, spi) in [
+            (
+                "virtio console",
+                self.virtio_console,
+                self.virtio_console_interrupt,
+            ),
+            ("virtio balloon", self.balloon, self.balloon_interrupt),
+            ("virtio-fs device", self.virtiofs, self.virtiofs_interrupt),
+            ("virtio-gpu", self.gpu, self.gpu_interrupt),
+            ("virtio-input device", self.input, self.input_interrupt),
+            ("virtio-iommu", self.iommu, self.iommu_interrupt),
+            ("virtio-pmem device", self.pmem, self.pmem_interrupt),
+            (
+                "virtio-9p device",
+                self.share_dir.as_ref().map(|_| self.share_dir_base),
+                self.share_dir_interrupt,
+            ),
+        ]
+        .into_iter()
+        .chain(
+            self.vhost_user
+                .iter()
+                .map(|dev| ("vhost-user device", Some(dev.addr), dev.interrupt)),
+        ) {
+            if base.is_none() {
+                continue;
+            }
+            if self.protected && BOUNCED_DEVICES.contains(&name) {
+                if self.restricted_dma.is_none() {
+                    return Err(anyhow!(
+                        "The {} needs guest memory shared with the host, use --unprotected or \
+                         --restricted-dma",
+                        name
+                    ));
+                }
+            } else if self.protected {
+                return Err(anyhow!(
+                    "The {} needs guest memory shared with the host, use --unprotected",
+                    name
+                ));
+            }
+            if spis.contains(&spi) {
+                return Err(anyhow!("The {} SPI {} is already in use", name, spi));
+            }
+            spis.push(spi);
+        }
+        for (name, base, spi) in [
+            ("watchdog", self.watchdog, self.watchdog_interrupt),
+            ("GPIO controller", self.gpio, self.gpio_interrupt),
+            ("shared memory device", self.shmem, self.shmem_interrupt),
+            ("interrupt generator", self.irq_gen, self.irq_gen_interrupt),
+        ] {
+            if base.is_none() {
+                continue;
+            }
+            if spis.contains(&spi) {
+                return Err(anyhow!("The {} SPI {} is already in use", name, spi));
+            }
+            spis.push(spi);
+        }
+
+        if self.msi_frame.is_some() {
+            if self.msi_interrupts == 0 {
+                return Err(anyhow!("At least one MSI interrupt is needed"));
+            }
+            let msis = self.msi_first_interrupt
+                ..self
+                    .msi_first_interrupt
+                    .checked_add(self.msi_interrupts)
+                    .context("MSI interrupts out of range")?;
+            if let Some(spi) = spis.iter().find(|spi| msis.contains(spi)) {
+                return Err(anyhow!("SPI {} is already in use, it can't be an MSI", spi));
+            }
+        }
+
+        if let Some(share) = &self.share_dir {
+            if !share.path.is_dir() {
+                return Err(anyhow!("{} is not a directory", share.path.display()));
+            }
+        }
+
+        if let (Some(pmem_base), Some(file)) = (self.pmem_base, &self.pmem_file) {
+            let len = fs::metadata(file)
+                .context(format!("Failed to read {}", file.display()))?
+                .len()
+                .next_multiple_of(self.page_size() as u64);
+            let pmem_end = pmem_base
+                .checked_add(len)
+                .ok_or(anyhow!("virtio-pmem memory at {} is too big", pmem_base))?;
+            if *pmem_base < *(self.mem_base + self.size) && pmem_end > *self.mem_base {
+                return Err(anyhow!(
+                    "virtio-pmem memory at {}+{:#x} overlaps the VM's memory",
+                    pmem_base,
+                    len
+                ));
+            }
+        }
+
+        if let Some(shmem_base) = self.shmem_base {
+            if (*self.mem_base..*(self.mem_base + self.size)).contains(&*shmem_base) {
+                return Err(anyhow!(
+                    "Shared memory at {} overlaps the VM's memory",
+                    shmem_base
+                ));
+            }
+        }
+
+        if self.ramoops.is_some() {
+            if !self.ramoops_size.is_power_of_two() || *self.ramoops_size < 0x4000 {
+                return Err(anyhow!(
+                    "ramoops size {} is not a power of two of at least 16KiB",
+                    self.ramoops_size
+                ));
+            }
+            if let Some(ramoops_base) = self.ramoops_base {
+                if *ramoops_base < *(self.mem_base + self.size)
+                    && *self.mem_base < *(ramoops_base + self.ramoops_size)
+                {
+                    return Err(anyhow!(
+                        "ramoops memory at {} overlaps the VM's memory",
+                        ramoops_base
+                    ));
+                }
+            }
+        }
+
+        if let Some(size) = self.restricted_dma {
+            if *size == 0 || !(*size).is_multiple_of(0x1000) {
+                return Err(anyhow!(
+                    "Restricted DMA pool size {} is not a positive multiple of 4KiB",
+                    size
+                ));
+            }
+            if let Some(base) = self.restricted_dma_base {
+                if *base < *(self.mem_base + self.size) && *self.mem_base < *(base + size) {
+                    return Err(anyhow!(
+                        "Restricted DMA pool at {} overlaps the VM's memory",
+                        base
+                    ));
+                }
+            }
+        }
+
+        if let Some(page_size) = self.hugetlb_page_size()? {
+            if !(*self.size).is_multiple_of(page_size.bytes() as u64) {
+                return Err(anyhow!(
+                    "Memory size {} is not a multiple of the hugetlbfs page size {}",
+                    self.size,
+                    GuestSize::from(page_size.bytes())
+                ));
+            }
+        }
+
+        if let Some(count) = self.numa_nodes {
+            if count == 0 {
+                return Err(anyhow!("Need more than zero NUMA nodes"));
+            }
+            // Keeps the nodes' memory aligned to huge pages
+            if !(*self.size).is_multiple_of(u64::from(count) * 0x20_0000) {
+                return Err(anyhow!(
+                    "Memory size {} can't be split into {} NUMA nodes of a multiple of 2MB",
+                    self.size,
+                    count
+                ));
+            }
+        }
+        let guest_nodes = self.numa_nodes.unwrap_or(1) as usize;
+        if self.host_numa_nodes.len() > 1 && self.host_numa_nodes.len() != guest_nodes {
+            return Err(anyhow!(
+                "Got {} host NUMA nodes for {} guest NUMA nodes",
+                self.host_numa_nodes.len(),
+                guest_nodes
+            ));
+        }
+
+        let mut banks = vec![(self.mem_base, self.size)];
+        for bank in &self.memory {
+            if *bank.size == 0 || !(*bank.base | *bank.size).is_multiple_of(0x1000) {
+                return Err(anyhow!(
+                    "Memory at {} of size {} is not page aligned",
+                    bank.base,
+                    bank.size
+                ));
+            }
+            if let Some(page_size) = self.hugetlb_page_size()? {
+                if !(*bank.size).is_multiple_of(page_size.bytes() as u64) {
+                    return Err(anyhow!(
+                        "Memory size {} is not a multiple of the hugetlbfs page size {}",
+                        bank.size,
+                        GuestSize::from(page_size.bytes())
+                    ));
+                }
+            }
+            if banks.iter().any(|&(base, size)| {
+                *bank.base < *(base + size) && *base < *(bank.base + bank.size)
+            }) {
+                return Err(anyhow!(
+                    "Memory at {} overlaps other memory of the VM",
+                    bank.base
+                ));
+            }
+            banks.push((bank.base, bank.size));
+        }
+
+        if let Some(base) = self.hotplug_memory {
+            if banks.iter().any(|&(mem_base, size)| {
+                *base < *(mem_base + size) && *mem_base < *(base + self.hotplug_memory_size)
+            }) {
+                return Err(anyhow!(
+                    "Hotplug memory at {} overlaps the VM's memory",
+                    base
+                ));
+            }
+        }
+
+        if *self.balloon_size > *self.size {
+            return Err(anyhow!(
+                "Balloon size {} is larger than the VM's memory ({})",
+                self.balloon_size,
+                self.size
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Parser, Debug)]
+#[command(name = "gunyah-test-vmm daemon", bin_name = "gunyah-test-vmm daemon")]
+/// Host VMs started and stopped with `gunyah-test-vmm ctl`
+struct DaemonCommand {
+    /// Control socket to listen on
+    #[arg(long)]
+    socket: PathBuf,
+}
+
+#[derive(Parser, Debug)]
+#[command(name = "gunyah-test-vmm ctl", bin_name = "gunyah-test-vmm ctl")]
+/// Control the VMs of `gunyah-test-vmm daemon`
+struct CtlCommand {
+    /// Control socket of the daemon
+    #[arg(long)]
+    socket: PathBuf,
+    #[command(subcommand)]
+    action: CtlAction,
+}
 
-    /// Serial port address
-    #[arg(long, default_value_t = 0x3f800u64.into())]
-    serial_base: GuestAddress,
-    /// Serial port SPI
-    #[arg(long, default_value_t = 1)]
-    serial_interrupt: u32,
+#[derive(clap::Subcommand, Debug)]
+enum CtlAction {
+    /// Start a VM configured by a file as for --config
+    Start { name: String, config: PathBuf },
+    /// Power a VM off
+    Stop { name: String },
+    /// Show whether VMs run and how they ended
+    Status { name: Option<String> },
+    /// Attach the terminal to a VM's console, which needs a socket:PATH --serial-backend.
+    /// Ctrl-] detaches.
+    Console { name: String },
+    /// Stop all VMs and the daemon
+    Quit,
 }
 
-impl RunCommand {
-    pub fn validate(&self) -> Result<()> {
-        if !self.image.is_file() {
-            return Err(anyhow!(format!("{} is not a file", self.image.display())));
+impl CtlCommand {
+    fn execute(&self) -> Result<()> {
+        let request = match &self.action {
+            CtlAction::Start { name, config } => {
+                // The daemon may run elsewhere in the file system
+                let config = fs::canonicalize(config)
+                    .context(format!("Failed to find {}", config.display()))?;
+                json!({ "command": "start", "name": name, "config": config })
+            }
+            CtlAction::Stop { name } => json!({ "command": "stop", "name": name }),
+            CtlAction::Status { name } => json!({ "command": "status", "name": name }),
+            CtlAction::Console { name } => json!({ "command": "console", "name": name }),
+            CtlAction::Quit => json!({ "command": "quit" }),
+        };
+        let reply = daemon_command(&self.socket, request)?;
+        match &self.action {
+            CtlAction::Status { .. } => {
+                for vm in reply["vms"].as_array().into_iter().flatten() {
+                    let detail = vm
+                        .get("exit")
+                        .or(vm.get("error"))
+                        .and_then(Value::as_str)
+                        .map(|detail| format!(" ({})", detail))
+                        .unwrap_or_default();
+                    println!(
+                        "{}: {}{}",
+                        vm["name"].as_str().unwrap_or_default(),
+                        vm["status"].as_str().unwrap_or_default(),
+                        detail
+                    );
+                }
+            }
+            CtlAction::Console { .. } => {
+                let socket = reply["socket"]
+                    .as_str()
+                    .ok_or(anyhow!("Invalid reply from daemon"))?;
+                attach_console(Path::new(socket))?;
+            }
+            _ => {}
         }
+        Ok(())
+    }
+}
 
-        if self.vcpus == 0 {
-            return Err(anyhow!(
-                "Need more than zero vCPUs to run a virtual machine"
-            ));
-        }
+#[derive(Parser, Debug)]
+#[command(name = "gunyah-test-vmm bench", bin_name = "gunyah-test-vmm bench")]
+/// Measure the hypervisor interface against holding cell VMs, printing one JSON object per result
+struct BenchCommand {
+    /// Benchmarks to run, all of them by default
+    #[arg(value_enum)]
+    benchmarks: Vec<Benchmark>,
+    /// Samples taken by the latency benchmarks
+    #[arg(long, default_value_t = 1000)]
+    iterations: u32,
+    /// Size of the memory lent by the lend benchmark, which runs once for each
+    #[arg(long = "lend-size", default_values = ["1M", "10M", "100M"])]
+    lend_sizes: Vec<GuestSize>,
+    /// Back the memory lent by the lend benchmark with transparent huge pages
+    #[arg(long)]
+    huge_pages: bool,
+}
 
-        if let Some(f) = self.files.iter().find(|f| !f.file.is_file()) {
-            return Err(anyhow!(format!("{} is not a file", f.file.display())));
+impl BenchCommand {
+    fn execute(&self) -> Result<()> {
+        let config = BenchConfig {
+            iterations: self.iterations,
+            lend_sizes: self
+                .lend_sizes
+                .iter()
+                .map(|size| {
+                    NonZeroUsize::new(usize::try_from(**size)?)
+                        .ok_or(anyhow!("Memory size can't be zero"))
+                })
+                .collect::<Result<_>>()?,
+            huge_pages: self.huge_pages,
+        };
+        let benchmarks = if self.benchmarks.is_empty() {
+            <Benchmark as clap::ValueEnum>::value_variants()
+        } else {
+            &self.benchmarks
+        };
+        // Keep going, so one broken benchmark doesn't hide the results of the others
+        let mut failed = false;
+        for benchmark in benchmarks {
+            match benchmark.run(&config) {
+                Ok(results) => {
+                    for result in results {
+                        println!("{}", result.to_json());
+                    }
+                }
+                Err(e) => {
+                    failed = true;
+                    println!(
+                        "{}",
+                        json!({ "benchmark": benchmark.name(), "error": format!("{:#}", e) })
+                    );
+                }
+            }
+        }
+        if failed {
+            Err(anyhow!("Some benchmarks failed"))
+        } else {
+            Ok(())
         }
-        Ok(())
     }
 }
 
 struct Run {
     args: RunCommand,
+    /// The primary VM owns stdin and the pause signals
+    primary: bool,
 
-    serial: Option<Arc<Mutex<SerialDevice<Stdout>>>>,
+    serials: Vec<Arc<Mutex<SerialDevice<LoggedOutput<SerialOutput>>>>>,
+    /// Input of each serial port, connected once the VM is set up
+    serial_inputs: Vec<SerialInput>,
+    virtio_console: Option<Arc<Mutex<VirtioMmio<VirtioConsole<Stdout>>>>>,
+    iommu: Option<Arc<Mutex<VirtioMmio<VirtioIommu>>>>,
+    balloon: Option<Arc<Mutex<VirtioMmio<VirtioBalloon>>>>,
+    ramoops: Option<Ramoops>,
+    restricted_dma: Option<RestrictedDmaPool>,
     vm: GunyahVirtualMachine,
     page_size_once: OnceCell<usize>,
 }
 
 impl Run {
-    pub fn new(args: RunCommand) -> Result<Self> {
+    /// Puts `device` behind the virtio-iommu, if there is one, or has it use the restricted DMA
+    /// pool, if there is one.
+    fn attach_iommu<D: VirtioDevice>(&self, device: &Arc<Mutex<VirtioMmio<D>>>) {
+        if let Some(iommu) = &self.iommu {
+            let endpoint = iommu.lock().unwrap().device_mut().add_endpoint();
+            device.lock().unwrap().set_iommu(endpoint);
+        }
+        if let Some(pool) = &self.restricted_dma {
+            device.lock().unwrap().set_restricted_dma(pool);
+        }
+    }
+
+    pub fn new(args: RunCommand, primary: bool) -> Result<Self> {
+        let vm = GunyahVirtualMachine::with_type(args.vm_type.into())
+            .context("Failed to create Gunyah Virtual Machine")?;
         Ok(Self {
             args,
-            serial: None,
+            primary,
+            serials: Vec::new(),
+            serial_inputs: Vec::new(),
+            virtio_console: None,
+            iommu: None,
+            balloon: None,
+            ramoops: None,
+            restricted_dma: None,
             page_size_once: OnceCell::new(),
-            vm: GunyahVirtualMachine::new().context("Failed to create Gunyah Virtual Machine")?,
+            vm,
         })
     }
 
+    /// Adds RAM backed by pages as chosen with --huge-pages or --hugetlb-page-size.
+    fn add_ram(
+        &mut self,
+        base: GuestAddress,
+        size: GuestSize,
+        share_type: gunyah::ShareType,
+    ) -> Result<Arc<Mutex<GunyahGuestMemoryRegion>>> {
+        match self.args.hugetlb_page_size()? {
+            Some(page_size) => self.vm.add_hugetlb_memory(
+                *base,
+                size.try_into()?,
+                share_type,
+                GuestMemoryAccess::Rwx,
+                page_size,
+            ),
+            None => self.vm.add_memory(
+                *base,
+                size.try_into()?,
+                share_type,
+                GuestMemoryAccess::Rwx,
+                self.args.huge_pages,
+            ),
+        }
+    }
+
     fn mem_end(&self) -> GuestAddress {
         self.args.mem_base + self.args.size
     }
 
     fn page_size(&self) -> usize {
-        *self.page_size_once.get_or_init(|| {
-            if self.args.huge_pages {
-                usize::from_str(
-                    fs::read_to_string("/sys/kernel/mm/transparent_hugepage/hpage_pmd_size")
-                        .unwrap()
-                        .trim(),
-                )
-                .context("Failed to parse hpage_pmd_size")
-                .unwrap()
-            } else {
-                page_size::get()
-            }
-        })
+        *self.page_size_once.get_or_init(|| self.args.page_size())
     }
 
     fn align_address_offset(&self, addr: GuestAddress, offset: u64) -> Result<GuestAddress> {
@@ -171,20 +1488,156 @@ impl Run {
         Ok(((*size + page_mask) & !page_mask).into())
     }
 
+    fn command_line(&self) -> String {
+        if self.args.command_line.is_none() && self.virtio_console.is_some() {
+            return format!("{} {}", DEFAULT_COMMAND_LINE, VIRTIO_CONSOLE_ARGS);
+        }
+        match (&self.args.command_line, self.serials.get(self.args.console)) {
+            (Some(command_line), _) => command_line.clone(),
+            (None, Some(ser)) => format!(
+                "{} {}",
+                DEFAULT_COMMAND_LINE,
+                ser.lock().unwrap().console_args(self.args.console)
+            ),
+            (None, None) => DEFAULT_COMMAND_LINE.to_string(),
+        }
+    }
+
     fn load_binaries(&self) -> Result<()> {
-        let image_base = self.args.image_base.unwrap_or(self.args.mem_base);
-        let image = fs::read(&self.args.image).context("Unable to read VM image")?;
-
-        let rdisk = fs::read(&self.args.rdisk).context("Unable to read Ramdisk image")?;
-        let image_end = image_base.add(self.align_size((image.len() + self.page_size()).into())?);
-        let rdisk_base = self.align_address_offset(image_end, 0x100_0000u64 - 1)?;
-
-        let command_line = self.args.command_line.clone();
-        let dtb = self.generate_fdt(
-            &command_line,
-            rdisk_base,
-            rdisk_base.add(rdisk.len().into()),
-        )?;
+        let file = fs::read(&self.args.image).context("Unable to read VM image")?;
+        if let (Some(key), Some(signature)) = (&self.args.verify_key, &self.args.signature) {
+            let key = fs::read_to_string(key).context("Unable to read verification key")?;
+            let signature = fs::read(signature).context("Unable to read image signature")?;
+            verify_image(&file, &signature, &key).context("Failed to verify VM image")?;
+        }
+        if let Some(digest) = &self.args.verify {
+            digest.verify(&file).context("Failed to verify VM image")?;
+        }
+        if let (Some(digest), Some(path)) = (&self.args.verify_rdisk, &self.args.rdisk) {
+            digest
+                .verify_file(path)
+                .context("Failed to verify ramdisk")?;
+        }
+
+        // Android boot images bundle the kernel with a ramdisk and command line. Load addresses
+        // from the boot image header are only used if they lie in the VM's memory.
+        let mut format = self
+            .args
+            .image_format
+            .unwrap_or_else(|| ImageFormat::detect(&file));
+        let boot = match format {
+            ImageFormat::Android => Some(
+                AndroidBootImage::parse(&file)
+                    .context("Failed to parse Android boot image")?
+                    .ok_or(anyhow!("VM image isn't an Android boot image"))?,
+            ),
+            _ => None,
+        };
+        let mut image = boot.as_ref().map_or(file.as_slice(), |boot| boot.kernel);
+        if boot.is_some() {
+            format = ImageFormat::detect(image);
+        }
+        // Kernels are often compressed, also inside boot images
+        let decompressed;
+        if format == ImageFormat::Gzip {
+            decompressed = gunzip(image).context("Failed to decompress VM image")?;
+            image = &decompressed;
+            format = ImageFormat::detect(image);
+        }
+        let (header, elf) = match format {
+            ImageFormat::Arm64 => (
+                Some(
+                    Arm64ImageHeader::parse(image)
+                        .ok_or(anyhow!("VM image isn't an arm64 Image"))?,
+                ),
+                None,
+            ),
+            ImageFormat::Elf => (
+                None,
+                Some(ElfImage::parse(image).context("Failed to parse ELF image")?),
+            ),
+            ImageFormat::Flat => (None, None),
+            ImageFormat::Android | ImageFormat::Gzip => {
+                return Err(anyhow!("VM image holds another {:?} image", format))
+            }
+        };
+        let in_memory = |addr: &u64| (*self.args.mem_base..*self.mem_end()).contains(addr);
+
+        // Linux kernels need to be placed where their header says and use memory past the end of
+        // the file for their BSS
+        let boot_kernel_addr = boot
+            .as_ref()
+            .and_then(|boot| boot.kernel_addr)
+            .filter(in_memory)
+            .filter(|addr| header.is_none_or(|header| header.check_load_address(*addr).is_ok()));
+        let (image_base, image_size) = match &elf {
+            Some(_) if self.args.image_base.is_some() => {
+                return Err(anyhow!(
+                    "ELF images are loaded at the addresses of their segments, not --image-base"
+                ))
+            }
+            Some(elf) => {
+                let (start, end) = elf.extent();
+                (GuestAddress::from(start), GuestSize::from(end - start))
+            }
+            None => {
+                let image_base = match (self.args.image_base, boot_kernel_addr, header) {
+                    (Some(base), _, Some(header)) => {
+                        header.check_load_address(*base)?;
+                        base
+                    }
+                    (Some(base), _, None) => base,
+                    (None, Some(addr), _) => addr.into(),
+                    (None, None, Some(header)) => header.load_address(*self.args.mem_base).into(),
+                    (None, None, None) => self.args.mem_base,
+                };
+                let file_len = u64::try_from(image.len())?;
+                let image_size = header.map_or(file_len, |header| header.effective_size(file_len));
+                (image_base, GuestSize::from(image_size))
+            }
+        };
+
+        // RDISK is loaded straight from the file after the boot image's ramdisk
+        let boot_rdisk: &[u8] = boot.as_ref().map_or(&[], |boot| boot.ramdisk);
+        let rdisk_file_len = match &self.args.rdisk {
+            Some(path) => path
+                .metadata()
+                .context("Unable to read Ramdisk image")?
+                .len(),
+            None if boot.is_none() => return Err(anyhow!("No ramdisk given")),
+            None => 0,
+        };
+        let rdisk_len = u64::try_from(boot_rdisk.len())? + rdisk_file_len;
+        let page_size = u64::try_from(self.page_size())?;
+        let image_end = image_base.add(self.align_size((*image_size + page_size).into())?);
+        let rdisk_base = match boot
+            .as_ref()
+            .and_then(|boot| boot.ramdisk_addr)
+            .filter(in_memory)
+        {
+            Some(addr) => addr.into(),
+            None => self.align_address_offset(image_end, 0x100_0000u64 - 1)?,
+        };
+
+        let mut command_line = self.command_line();
+        if let Some(boot) = boot.as_ref().filter(|boot| !boot.cmdline.is_empty()) {
+            if self.args.command_line.is_none() {
+                command_line = format!("{} {}", command_line, boot.cmdline);
+            }
+        }
+        // The DTB tells the Resource Manager where the firmware is
+        let firmware = match (&self.args.firmware, self.args.firmware_base) {
+            (Some(path), Some(base)) => {
+                let len = path
+                    .metadata()
+                    .context("Unable to read firmware image")?
+                    .len();
+                self.vm.set_firmware(*base, len)?;
+                Some((path, base, len))
+            }
+            _ => None,
+        };
+        let dtb = self.generate_fdt(&command_line, rdisk_base, rdisk_base.add(rdisk_len.into()))?;
         let dtb_addr = match self.args.dtb_base {
             Some(b) => b,
             None => {
@@ -199,19 +1652,30 @@ impl Run {
         };
         let dtb_len = self.align_size((dtb.len() + self.page_size()).into())?;
 
-        if !self.args.files.is_empty() {
-            todo!();
-        }
-
         let mut regions: Vec<(&OsStr, GuestAddress, GuestSize)> = Vec::new();
         regions.push((OsStr::new("dtb"), dtb_addr, dtb_len));
-        regions.push((self.args.image.as_os_str(), image_base, image.len().into()));
-        regions.push((self.args.rdisk.as_os_str(), rdisk_base, rdisk.len().into()));
+        regions.push((self.args.image.as_os_str(), image_base, image_size));
+        let rdisk_name = self
+            .args
+            .rdisk
+            .as_ref()
+            .map_or(OsStr::new("ramdisk"), |path| path.as_os_str());
+        regions.push((rdisk_name, rdisk_base, rdisk_len.into()));
+        if let Some((path, base, len)) = firmware {
+            regions.push((path.as_os_str(), base, len.into()));
+        }
+        // Extra files take whole pages, so nothing else shares a page with them
+        let page_mask = page_size - 1;
         for arg in &self.args.files {
+            let len = arg
+                .file
+                .metadata()
+                .context(format!("Unable to read {}", arg.file.display()))?
+                .len();
             regions.push((
                 arg.file.as_os_str(),
-                arg.addr,
-                arg.file.metadata()?.len().into(),
+                (*arg.addr & !page_mask).into(),
+                self.align_size((len + (*arg.addr & page_mask)).into())?,
             ))
         }
 
@@ -245,17 +1709,102 @@ impl Run {
                 )));
             }
         }
+        if let Some(path) = &self.args.dump_dtb {
+            fs::write(path, &dtb).context(format!("Failed to write {}", path.display()))?;
+        }
+        if self.args.dry_run {
+            return self.print_plan(&regions, &dtb);
+        }
         self.vm.set_dtb_config(*dtb_addr, *dtb_len, &dtb)?;
-        self.vm.set_boot_pc(*image_base)?;
-
-        self.vm
-            .write_slice(*image_base, image.as_slice())
-            .context("Unable to copy binary image to VM's memory")?;
+        match &elf {
+            Some(elf) => {
+                self.vm.set_boot_pc(elf.entry)?;
+                for segment in &elf.segments {
+                    self.vm
+                        .write_slice(segment.addr, segment.data)
+                        .context("Unable to copy binary image to VM's memory")?;
+                }
+            }
+            None => {
+                self.vm.set_boot_pc(*image_base)?;
+                self.vm
+                    .write_slice(*image_base, image)
+                    .context("Unable to copy binary image to VM's memory")?;
+            }
+        }
 
         self.vm
-            .write_slice(*rdisk_base, rdisk.as_slice())
+            .write_slice(*rdisk_base, boot_rdisk)
             .context("Unable to copy ramdisk to VM's memory")?;
+        if let Some(path) = &self.args.rdisk {
+            self.vm
+                .load_file(path, *rdisk_base + u64::try_from(boot_rdisk.len())?)
+                .context("Unable to copy ramdisk to VM's memory")?;
+        }
+
+        if let Some((path, base, _)) = firmware {
+            self.vm
+                .load_file(path, *base)
+                .context("Unable to copy firmware to VM's memory")?;
+        }
+
+        for arg in &self.args.files {
+            self.vm.load_file(&arg.file, *arg.addr).context(format!(
+                "Unable to copy {} to VM's memory",
+                arg.file.display()
+            ))?;
+        }
+
+        Ok(())
+    }
 
+    fn gic_config(&self) -> GicConfig {
+        let redist_size = *self.args.gic_redist_size * u64::from(self.args.possible_vcpus());
+        GicConfig {
+            dist_base: *self.args.gic_dist_base,
+            dist_size: *self.args.gic_dist_size,
+            redist_base: match self.args.gic_redist_base {
+                Some(b) => *b,
+                None => *self.args.gic_dist_base - redist_size,
+            },
+            redist_size,
+        }
+    }
+
+    /// Prints where the VM's memory, the files loaded into it, its devices and GIC lie, followed
+    /// by the device tree, for --dry-run.
+    fn print_plan(&self, files: &[(&OsStr, GuestAddress, GuestSize)], dtb: &[u8]) -> Result<()> {
+        let range = |base: u64, len: u64| format!("{:#012x}-{:#012x}", base, base + len - 1);
+        println!("Memory:");
+        for region in self.vm.memory_regions() {
+            let region = region.lock().unwrap();
+            println!(
+                "  {} {:?}",
+                range(
+                    region.guest_address(),
+                    region.as_region().size().try_into()?
+                ),
+                region.share_type()
+            );
+        }
+        println!("Files:");
+        for (name, base, len) in files {
+            println!("  {} {}", range(**base, **len), name.to_string_lossy());
+        }
+        println!("Devices:");
+        for device in self.vm.get_bus(AccessId::VmmUserspace).stats() {
+            println!("  {} {}", range(device.base, device.len), device.label);
+        }
+        let gic = self.gic_config();
+        println!("GIC:");
+        println!("  {} distributor", range(gic.dist_base, gic.dist_size));
+        println!(
+            "  {} redistributors",
+            range(gic.redist_base, gic.redist_size)
+        );
+        println!("Device tree:");
+        // fdt prints dtc-like source as Debug
+        println!("{:?}", parse_fdt(dtb)?.fdt());
         Ok(())
     }
 
@@ -265,48 +1814,76 @@ impl Run {
         rdisk_start: GuestAddress,
         rdisk_end: GuestAddress,
     ) -> Result<Vec<u8>> {
-        let mut fdt = FdtWriter::new()?;
-        let root_node = fdt.begin_node("")?;
-
-        self.vm.create_fdt_basic_config(
-            &mut fdt,
-            &[
-                *self.args.gic_dist_base,
-                *self.args.gic_dist_size,
-                match self.args.gic_redist_base {
-                    Some(b) => *b,
-                    None => {
-                        let offset = *self.args.gic_redist_size * u64::from(self.args.vcpus);
-                        *self.args.gic_dist_base - offset
+        let mut builder = self
+            .vm
+            .fdt_builder(self.gic_config())
+            .timer(self.args.timer_config());
+        if let Some(ppi) = self.args.pmu_irq {
+            builder = builder.pmu(PmuConfig { ppi });
+        }
+        let dtb = builder
+            .node(|fdt| {
+                if !self.serials.is_empty() {
+                    if self.args.serial_type == SerialType::Pl011 {
+                        create_fdt_pl011_clock(fdt)?;
                     }
-                },
-                *self.args.gic_redist_size * u64::from(self.args.vcpus),
-            ],
-            &[13, 14, 11, 10], // TODO: move this to command line option
-        )?;
-
-        let chosen = fdt.begin_node("chosen")?;
-        if let Some(ser) = &self.serial {
-            fdt.property_string(
-                "stdout-path",
-                &format!("/{}", ser.lock().unwrap().device_name()),
-            )?;
+                    create_fdt_serial_aliases(fdt, &self.serials)?;
+                }
+                if self.ramoops.is_some() || self.restricted_dma.is_some() {
+                    create_fdt_reserved_memory(fdt, |fdt| {
+                        if let Some(ramoops) = &self.ramoops {
+                            ramoops.device_config(fdt)?;
+                        }
+                        if let Some(pool) = &self.restricted_dma {
+                            pool.device_config(fdt)?;
+                        }
+                        Ok(())
+                    })?;
+                }
+                Ok(())
+            })
+            .chosen(ChosenConfig {
+                bootargs: command_line.to_string(),
+                stdout_path: (self.args.console < self.serials.len())
+                    .then(|| format!("serial{}", self.args.console)),
+                initrd: Some((*rdisk_start, *rdisk_end)),
+            })
+            .build()?;
+        match &self.args.dtb_overlay {
+            Some(path) => {
+                let overlay =
+                    fs::read(path).context(format!("Failed to read {}", path.display()))?;
+                merge_fdt(&dtb, &overlay)
+                    .context(format!("Failed to merge {} into the dtb", path.display()))
+            }
+            None => Ok(dtb),
         }
-        fdt.property_string("bootargs", command_line)?;
-
-        fdt.property_u32("linux,initrd-start", *rdisk_start as u32)?;
-        fdt.property_u32("linux,initrd-end", *rdisk_end as u32)?;
-
-        fdt.end_node(chosen)?;
-
-        fdt.end_node(root_node)?;
-
-        fdt.finish().context("Failed to finalize dtb")
     }
 
-    pub fn execute(mut self) -> Result<()> {
+    /// Runs the VM until it stops and returns why it stopped, along with the ramoops contents to
+    /// carry over into the next boot. `ramoops` holds the contents left by the previous boot.
+    pub fn execute(mut self, ramoops: Option<Vec<u8>>) -> Result<(VmExit, Option<Vec<u8>>)> {
         self.args.validate()?;
 
+        self.vm.set_force_psci(!self.args.no_force_psci);
+        self.vm.set_cpu_topology(self.args.cpu_topology());
+        self.vm.set_vcpu_scheduling(self.args.vcpu_scheduling());
+        self.vm.set_possible_vcpus(self.args.possible_vcpus());
+        self.vm.set_numa_nodes(self.args.guest_numa_nodes());
+        self.vm
+            .set_io_engine(IoEngine::new(self.args.io_engine.into())?);
+        self.vm.set_seccomp(self.args.seccomp.map(Into::into));
+        if let Some(base) = self.args.hotplug_memory {
+            self.vm
+                .set_hotplug_memory(*base, *self.args.hotplug_memory_size);
+        }
+        let mmio_trace = match (&self.args.mmio_trace, self.args.mmio_trace_last) {
+            (Some(path), _) => Some(MmioTrace::file(path)?),
+            (None, Some(last)) => Some(MmioTrace::ring(last)),
+            (None, None) => None,
+        };
+        self.vm.set_mmio_trace(mmio_trace.clone());
+
         let vcpus = Arc::new(Mutex::new(Vec::new()));
         let mut vcpu_handles = Vec::new();
 
@@ -317,35 +1894,349 @@ impl Run {
                 .push(self.vm.create_vcpu(id).context("Failed to create vcpu"));
         }
 
-        self.serial = Some(SerialDevice::new(
-            &mut self.vm,
-            *self.args.serial_base,
-            self.args.serial_interrupt,
-            io::stdout(),
-        )?);
+        let console_log = match &self.args.console_log {
+            Some(path) => Some(ConsoleLog::open(path, &self.vm.io_engine())?),
+            None => None,
+        };
+        for (i, (base, interrupt)) in self
+            .args
+            .serial_base
+            .iter()
+            .zip(&self.args.serial_interrupt)
+            .enumerate()
+        {
+            let backend = self.args.serial_backend.get(i).cloned().unwrap_or_default();
+            let (out, input) = backend
+                .open(&self.vm.io_engine())
+                .context(format!("Failed to open serial backend {}", backend))?;
+            let log = console_log
+                .as_ref()
+                .map(|log| log.port(&format!("serial{}", i)));
+            self.serials.push(SerialDevice::new(
+                &mut self.vm,
+                self.args.serial_type,
+                **base,
+                *interrupt,
+                LoggedOutput::new(out, log),
+            )?);
+            self.serial_inputs.push(input);
+        }
+        if let Some(base) = self.args.debug_exit {
+            self.vm.add_debug_exit(*base)?;
+        }
 
-        self.vm
-            .add_memory(
-                *self.args.mem_base,
-                self.args.size.try_into()?,
-                if self.args.protected {
-                    gunyah::ShareType::Lend
-                } else {
-                    gunyah::ShareType::Share
-                },
-                GuestMemoryAccess::Rwx,
-                self.args.huge_pages,
-            )
+        if let Some(base) = self.args.gpio {
+            let gpio = Pl061::new(&mut self.vm, *base, self.args.gpio_interrupt)?;
+            gpio.lock()
+                .unwrap()
+                .set_output_callback(Box::new(|line, level| {
+                    println!("GPIO line {} set to {}", line, u8::from(level))
+                }));
+        }
+
+        if let Some(base) = self.args.irq_gen {
+            let irq_gen = IrqGen::new(&mut self.vm, *base, self.args.irq_gen_interrupt)?;
+            irq_gen
+                .lock()
+                .unwrap()
+                .set_period(Some(Duration::from_micros(self.args.irq_gen_period_us)));
+        }
+
+        if let Some(base) = self.args.msi_frame {
+            self.vm.add_msi_frame(
+                *base,
+                self.args.msi_first_interrupt,
+                self.args.msi_interrupts,
+            )?;
+        }
+
+        if let Some(base) = self.args.watchdog {
+            Sp805::new(
+                &mut self.vm,
+                *base,
+                self.args.watchdog_interrupt,
+                self.args.watchdog_action,
+            )?;
+        }
+
+        if let Some(base) = self.args.iommu {
+            self.iommu = Some(VirtioIommu::new(
+                &mut self.vm,
+                *base,
+                self.args.iommu_interrupt,
+            )?);
+        }
+
+        if let Some(base) = self.args.virtio_console {
+            let console = VirtioConsole::new(
+                &mut self.vm,
+                *base,
+                self.args.virtio_console_interrupt,
+                io::stdout(),
+            )?;
+            self.attach_iommu(&console);
+            self.virtio_console = Some(console);
+        }
+
+        if let Some(path) = &self.args.rm_console {
+            let source =
+                fs::File::open(path).context(format!("Failed to open {}", path.display()))?;
+            let backend = &self.args.rm_console_backend;
+            let (output, input) = backend
+                .open(&self.vm.io_engine())
+                .context(format!("Failed to open RM console backend {}", backend))?;
+            // The RM console takes no input, but sockets still need their clients accepted
+            input.forward(&self.vm.executor(), Box::new(|data| Some(data.len())));
+            self.vm.add_rm_console(source, Box::new(output))?;
+        }
+        for queue in &self.args.message_queue {
+            self.vm.add_message_queue(*queue)?;
+        }
+
+        let share_type = if self.args.protected {
+            gunyah::ShareType::Lend
+        } else {
+            gunyah::ShareType::Share
+        };
+        let memory = self
+            .add_ram(self.args.mem_base, self.args.size, share_type)
             .expect("Failed to add memory to the vm");
+        for bank in self.args.memory.clone() {
+            self.add_ram(bank.base, bank.size, bank.share_type.unwrap_or(share_type))
+                .with_context(|| format!("Failed to add memory at {}", bank.base))?;
+        }
+        match self.args.host_numa_nodes[..] {
+            [] => {}
+            [node] => memory.lock().unwrap().bind_host_numa_node(
+                0,
+                (*self.args.size).try_into()?,
+                node,
+            )?,
+            ref nodes => {
+                for (guest_node, &node) in self.args.guest_numa_nodes().iter().zip(nodes) {
+                    let (base, size) = guest_node.memory[0];
+                    memory.lock().unwrap().bind_host_numa_node(
+                        base - *self.args.mem_base,
+                        size.try_into()?,
+                        node,
+                    )?;
+                }
+            }
+        }
+
+        if self.args.ramoops.is_some() {
+            let base = self.args.ramoops_base.unwrap_or(self.mem_end());
+            let dev = Ramoops::new(&mut self.vm, *base, self.args.ramoops_size.try_into()?)?;
+            if let Some(contents) = ramoops {
+                dev.restore(&contents)?;
+            }
+            self.ramoops = Some(dev);
+        }
+
+        if let Some(size) = self.args.restricted_dma {
+            let base = self.args.restricted_dma_base.unwrap_or_else(|| {
+                match (&self.args.ramoops, self.args.ramoops_base) {
+                    (Some(_), None) => self.mem_end() + self.args.ramoops_size,
+                    _ => self.mem_end(),
+                }
+            });
+            self.restricted_dma = Some(RestrictedDmaPool::new(
+                &mut self.vm,
+                *base,
+                size.try_into()?,
+            )?);
+        }
+
+        if let Some(base) = self.args.balloon {
+            let balloon = VirtioBalloon::new(
+                &mut self.vm,
+                *base,
+                self.args.balloon_interrupt,
+                vec![memory.clone()],
+            )?;
+            self.attach_iommu(&balloon);
+            let num_pages = *self.args.balloon_size / BALLOON_PAGE_SIZE;
+            VirtioBalloon::set_target(&mut balloon.lock().unwrap(), num_pages.try_into()?)?;
+            self.balloon = Some(balloon);
+        }
+
+        if let (Some(base), Some(socket)) = (self.args.virtiofs, &self.args.virtiofs_socket) {
+            add_vhost_user_fs(
+                &mut self.vm,
+                *base,
+                self.args.virtiofs_interrupt,
+                socket,
+                &self.args.virtiofs_tag,
+                vec![memory.clone()],
+            )
+            .context(format!(
+                "Failed to connect to vhost-user-fs backend at {}",
+                socket.display()
+            ))?;
+        }
+
+        if let (Some(base), Some(output)) = (self.args.gpu, &self.args.gpu_output) {
+            let gpu = VirtioGpu::new(
+                &mut self.vm,
+                *base,
+                self.args.gpu_interrupt,
+                self.args.gpu_width,
+                self.args.gpu_height,
+                output.clone(),
+            )?;
+            self.attach_iommu(&gpu);
+        }
+
+        if let (Some(base), Some(file), Some(pmem_base)) =
+            (self.args.pmem, &self.args.pmem_file, self.args.pmem_base)
+        {
+            let page_size = self.page_size();
+            let pmem = VirtioPmem::new(
+                &mut self.vm,
+                *base,
+                self.args.pmem_interrupt,
+                file,
+                *pmem_base,
+                page_size,
+                self.args.pmem_read_only,
+            )?;
+            self.attach_iommu(&pmem);
+        }
+
+        if let (Some(base), Some(file), Some(shmem_base)) =
+            (self.args.shmem, &self.args.shmem_file, self.args.shmem_base)
+        {
+            let mem = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(file)
+                .context(format!("Failed to open {}", file.display()))?;
+            Ivshmem::new(
+                &mut self.vm,
+                *base,
+                self.args.shmem_interrupt,
+                mem.into(),
+                *shmem_base,
+            )?;
+        }
+
+        if let Some(share) = &self.args.share_dir {
+            let p9 = Virtio9p::new(
+                &mut self.vm,
+                *self.args.share_dir_base,
+                self.args.share_dir_interrupt,
+                &share.path,
+                &share.tag,
+            )?;
+            self.attach_iommu(&p9);
+        }
+
+        if let Some(base) = self.args.input {
+            let input = VirtioInput::new(&mut self.vm, *base, self.args.input_interrupt)?;
+            self.attach_iommu(&input);
+        }
+
+        for dev in &self.args.vhost_user {
+            VhostUserDevice::new(
+                &mut self.vm,
+                *dev.addr,
+                dev.interrupt,
+                &dev.socket,
+                dev.device_type,
+                vec![VHOST_USER_QUEUE_SIZE; dev.num_queues],
+                VhostUserConfig::Backend,
+                vec![memory.clone()],
+            )
+            .context(format!(
+                "Failed to connect to vhost-user backend at {}",
+                dev.socket.display()
+            ))?;
+        }
 
         self.load_binaries()?;
+        if self.args.dry_run {
+            return Ok((VmExit::Poweroff, None));
+        }
+
+        // Ctrl-A c on the console switches to the monitor, Ctrl-A x terminates the VM
+        let console_input = || {
+            ConsoleInput::new(
+                self.vm.exit_request(),
+                Some(Arc::new(Monitor::new(&self.vm))),
+                io::stdin().is_terminal(),
+            )
+        };
+        let executor = self.vm.executor();
+        let inputs = std::mem::take(&mut self.serial_inputs);
+        for (i, (serial, input)) in self.serials.iter().zip(inputs).enumerate() {
+            match input {
+                SerialInput::Stdin
+                    if self.primary && i == self.args.console && self.virtio_console.is_none() =>
+                {
+                    SerialDevice::forward_stdin(serial, console_input(), &executor)
+                }
+                input => SerialDevice::forward_input(serial, input, &executor),
+            }
+        }
+        if let Some(console) = self.virtio_console.as_ref().filter(|_| self.primary) {
+            VirtioConsole::forward_stdin(console, console_input(), &executor);
+        }
+
+        if let Some(port) = self.args.gdb {
+            GdbServer::new(&self.vm).listen(port)?;
+        }
+
+        if let Some(path) = &self.args.api_socket {
+            ApiServer::new(&self.vm).listen(path)?;
+        }
+
+        if let Some(addr) = self.args.metrics_addr {
+            let addr = MetricsServer::new(&self.vm).listen(addr)?;
+            if self.primary {
+                println!("Serving metrics at http://{}/metrics", addr);
+            }
+        }
+
+        if let Some(run_as) = &self.args.run_as {
+            run_as
+                .drop_privileges(&self.args.unshare)
+                .context("Failed to drop privileges")?;
+        }
 
         self.vm.start().context("Failed to start the VM")?;
 
+        if self.primary {
+            self.vm
+                .exit_request()
+                .pause_on_signals(&executor)
+                .context("Failed to set up SIGUSR1/SIGUSR2 handling")?;
+            self.vm
+                .exit_request()
+                .exit_on_signals(&executor)
+                .context("Failed to set up SIGINT/SIGTERM handling")?;
+        }
+        if self.args.paused {
+            if self.primary {
+                println!("VM paused, send SIGUSR2 to resume");
+            }
+            self.vm.pause().context("Failed to pause the VM")?;
+        }
+
         for _id in 0..self.args.vcpus {
             let vcpu = vcpus.lock().unwrap().pop().unwrap()?;
+            let core = self
+                .args
+                .vcpu_affinity
+                .iter()
+                .find(|affinity| u32::from(affinity.vcpu) == vcpu.id())
+                .map(|affinity| CoreId { id: affinity.cpu });
             vcpu_handles.push(thread::spawn(move || {
-                vcpu.run().unwrap();
+                if let Some(core) = core {
+                    if !core_affinity::set_for_current(core) {
+                        println!("Failed to pin vCPU {} to CPU {}", vcpu.id(), core.id);
+                    }
+                }
+                vcpu.run().unwrap()
             }));
         }
 
@@ -354,10 +2245,261 @@ impl Run {
             handle.join().unwrap();
         }
 
-        Ok(())
+        // Every vCPU stops for the first exit requested
+        let exit = self
+            .vm
+            .exit_request()
+            .reason()
+            .expect("vCPUs stopped without an exit reason");
+        if self.args.exit_stats {
+            for vcpu in self.vm.vcpus() {
+                for line in vcpu.stats().to_string().lines() {
+                    println!("vCPU {}: {}", vcpu.id(), line);
+                }
+            }
+            if let Some(balloon) = &self.balloon {
+                let balloon = balloon.lock().unwrap();
+                println!(
+                    "Balloon: reclaimed {} ({} reported free by the guest)",
+                    GuestSize::from(balloon.device().reclaimed()),
+                    GuestSize::from(balloon.device().reported())
+                );
+            }
+        }
+        if let Some(trace) = mmio_trace {
+            trace.flush()?;
+            for record in trace.records() {
+                println!("MMIO {}", record);
+            }
+        }
+        // Guest output still buffered on its way to stdout shouldn't be lost on exit
+        io::stdout().flush()?;
+
+        let ramoops = match (&self.ramoops, &self.args.ramoops) {
+            (Some(ramoops), Some(path)) => {
+                ramoops.dump(path)?;
+                Some(ramoops.contents()?)
+            }
+            _ => None,
+        };
+        Ok((exit, ramoops))
+    }
+}
+
+/// Runs the VM described by `args`, restarting it on reset as --reboot-policy asks, until it exits
+/// for another reason or `control` stops it.
+fn run_until_exit(args: RunCommand, primary: bool, control: Option<VmControl>) -> Result<VmExit> {
+    let mut ramoops = None;
+    loop {
+        let run = Run::new(args.clone(), primary)?;
+        if let Some(control) = &control {
+            control.attach(run.vm.exit_request());
+        }
+        let exit;
+        (exit, ramoops) = run.execute(ramoops)?;
+        if exit != VmExit::Reset
+            || args.reboot_policy == RebootPolicy::Exit
+            || control.as_ref().is_some_and(VmControl::is_stopped)
+        {
+            return Ok(exit);
+        }
+        println!("Restarting the VM");
+    }
+}
+
+/// Hosts the VMs started with `ctl`. They run like secondary VMs.
+fn run_daemon(args: DaemonCommand) -> Result<()> {
+    Daemon::new(|config: &Path| {
+        let args = vm_config_args(config)?;
+        args.validate()?;
+        let console = match args.serial_backend.get(args.console) {
+            Some(SerialBackend::Socket(path)) => Some(path.clone()),
+            _ => None,
+        };
+        Ok(PreparedVm {
+            console,
+            run: Box::new(move |control| run_until_exit(args, false, Some(control))),
+        })
+    })
+    .listen(&args.socket)
+}
+
+/// Reads the options of a VM from a config file, e.g. one given with --secondary-vm.
+fn vm_config_args(path: &Path) -> Result<RunCommand> {
+    let program = env::args_os().next().unwrap_or_default();
+    let mut config = OsString::from("--config=");
+    config.push(path);
+    let args = RunCommand::try_parse_from(with_config_file(
+        &RunCommand::command(),
+        vec![program, config],
+    )?)
+    .with_context(|| format!("Invalid VM config {}", path.display()))?;
+    if !args.secondary_vm.is_empty() {
+        return Err(anyhow!("VM config {} can't add more VMs", path.display()));
+    }
+    if args.run_as.is_some() {
+        // The VMM's other VMs would lose their privileges too
+        return Err(anyhow!(
+            "VM config {} can't drop privileges, only a single VM can",
+            path.display()
+        ));
     }
+    if args.cgroup_parent.is_some() {
+        // The VMM's other VMs would share the cgroup
+        return Err(anyhow!(
+            "VM config {} can't have a cgroup, only a single VM can",
+            path.display()
+        ));
+    }
+    Ok(args)
 }
 
 fn main() -> Result<()> {
-    Run::new(RunCommand::parse())?.execute()
+    // `run` is the default, so VMs can still be run without naming a subcommand
+    let mut argv: Vec<OsString> = env::args_os().collect();
+    match argv.get(1).and_then(|arg| arg.to_str()) {
+        Some("daemon") => {
+            argv.remove(1);
+            return run_daemon(DaemonCommand::parse_from(argv));
+        }
+        Some("ctl") => {
+            argv.remove(1);
+            return CtlCommand::parse_from(argv).execute();
+        }
+        Some("bench") => {
+            argv.remove(1);
+            return BenchCommand::parse_from(argv).execute();
+        }
+        Some("run") => {
+            argv.remove(1);
+        }
+        _ => {}
+    }
+    let args = RunCommand::parse_from(with_config_file(&RunCommand::command(), argv)?);
+    let secondaries = args
+        .secondary_vm
+        .iter()
+        .map(|path| Ok((path.display().to_string(), vm_config_args(path)?)))
+        .collect::<Result<Vec<_>>>()?;
+    // Lets the guest see every key, Ctrl-A x terminates the VM instead of Ctrl-C
+    let terminal = if args.console_reads_stdin() {
+        RawTerminal::new().context("Failed to put the terminal into raw mode")?
+    } else {
+        None
+    };
+
+    if let Some(parent) = &args.cgroup_parent {
+        // Before any guest memory is allocated, so the cgroup is charged for it
+        let limits = CgroupLimits {
+            cpus: args.cpu_quota,
+            memory: args.memory_limit.map(|size| *size),
+        };
+        let cgroup = VmCgroup::create(parent, &limits)?;
+        println!("Running in cgroup {}", cgroup.path().display());
+    }
+
+    for (name, args) in secondaries {
+        thread::spawn(move || match run_until_exit(args, false, None) {
+            Ok(exit) => println!("Secondary VM {} exited: {:?}", name, exit),
+            Err(e) => eprintln!("Secondary VM {} failed: {:#}", name, e),
+        });
+    }
+
+    match run_until_exit(args, true, None)? {
+        VmExit::Crash => Err(anyhow!("The VM crashed")),
+        VmExit::Exit(code) => {
+            drop(terminal);
+            process::exit(code)
+        }
+        VmExit::Poweroff | VmExit::Reset => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, str::FromStr};
+
+    use claim::{assert_err, assert_ok};
+
+    use super::{LoadFileArg, MemoryArg, ShareDirArg, VcpuAffinityArg, VhostUserArg};
+
+    #[test]
+    fn load_file_arg() {
+        let arg = assert_ok!(LoadFileArg::from_str("initrd.img,0x8800_0000"));
+        assert_eq!(arg.file, PathBuf::from("initrd.img"));
+        assert_eq!(*arg.addr, 0x8800_0000);
+    }
+
+    #[test]
+    fn load_file_arg_missing_address() {
+        let err = assert_err!(LoadFileArg::from_str("initrd.img"));
+        assert!(err.to_string().contains("No address specified"));
+        let err = assert_err!(LoadFileArg::from_str("initrd.img,"));
+        assert!(err.to_string().contains("No address specified"));
+    }
+
+    #[test]
+    fn load_file_arg_extra_field() {
+        let err = assert_err!(LoadFileArg::from_str("initrd.img,0x8800_0000,rw"));
+        assert!(err.to_string().contains("Too many fields"));
+    }
+
+    #[test]
+    fn load_file_arg_bad_address() {
+        assert_err!(LoadFileArg::from_str("initrd.img,0xnope"));
+        assert_err!(LoadFileArg::from_str(",0x8800_0000"));
+    }
+
+    #[test]
+    fn memory_arg() {
+        let arg = assert_ok!(MemoryArg::from_str("base=0x1_0000_0000,size=256MB"));
+        assert_eq!(*arg.base, 0x1_0000_0000);
+        assert_eq!(*arg.size, 256 * 1024 * 1024);
+        assert_eq!(arg.share_type, None);
+        let arg = assert_ok!(MemoryArg::from_str("size=1MB,base=0x9000_0000,lend"));
+        assert_eq!(arg.share_type, Some(gunyah::ShareType::Lend));
+        let err = assert_err!(MemoryArg::from_str("base=0x9000_0000"));
+        assert!(err.to_string().contains("No size specified"));
+        assert_err!(MemoryArg::from_str("base=0x9000_0000,size=1MB,borrow"));
+        assert_err!(MemoryArg::from_str("base=nope,size=1MB"));
+    }
+
+    #[test]
+    fn share_dir_arg() {
+        let arg = assert_ok!(ShareDirArg::from_str("/tmp/artifacts,results"));
+        assert_eq!(arg.path, PathBuf::from("/tmp/artifacts"));
+        assert_eq!(arg.tag, "results");
+        assert_err!(ShareDirArg::from_str("/tmp/artifacts"));
+        assert_err!(ShareDirArg::from_str("/tmp/artifacts,"));
+        assert_err!(ShareDirArg::from_str(",results"));
+    }
+
+    #[test]
+    fn vcpu_affinity_arg() {
+        let arg = assert_ok!(VcpuAffinityArg::from_str("1:3"));
+        assert_eq!(arg.vcpu, 1);
+        assert_eq!(arg.cpu, 3);
+        assert_err!(VcpuAffinityArg::from_str("1"));
+        assert_err!(VcpuAffinityArg::from_str("x:3"));
+        assert_err!(VcpuAffinityArg::from_str("1:-3"));
+    }
+
+    #[test]
+    fn vhost_user_arg() {
+        let arg = assert_ok!(VhostUserArg::from_str("/tmp/net.sock,net,0x3e000,5"));
+        assert_eq!(arg.socket, PathBuf::from("/tmp/net.sock"));
+        assert_eq!(arg.device_type, 1);
+        assert_eq!(arg.num_queues, 2);
+        assert_eq!(*arg.addr, 0x3e000);
+        assert_eq!(arg.interrupt, 5);
+    }
+
+    #[test]
+    fn vhost_user_arg_invalid() {
+        let err = assert_err!(VhostUserArg::from_str("/tmp/net.sock,net,0x3e000"));
+        assert!(err.to_string().contains("expected SOCKET,TYPE,ADDR,SPI"));
+        let err = assert_err!(VhostUserArg::from_str("/tmp/x.sock,floppy,0x3e000,5"));
+        assert!(err.to_string().contains("Unknown vhost-user device type"));
+        assert_err!(VhostUserArg::from_str("/tmp/blk.sock,blk,0x3e000,spi"));
+    }
 }
@@ -0,0 +1,263 @@
+// Copyright (c) 2024, Qualcomm Innovation Center, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause-Clear
+
+use std::{collections::VecDeque, io::Write};
+
+use anyhow::{anyhow, Result};
+use vm_superio::Trigger;
+
+pub const PL011_MMIO_SIZE: u64 = 0x1000;
+const FIFO_SIZE: usize = 32;
+
+const UARTDR: u64 = 0x000;
+const UARTRSR: u64 = 0x004;
+const UARTFR: u64 = 0x018;
+const UARTILPR: u64 = 0x020;
+const UARTIBRD: u64 = 0x024;
+const UARTFBRD: u64 = 0x028;
+const UARTLCR_H: u64 = 0x02c;
+const UARTCR: u64 = 0x030;
+const UARTIFLS: u64 = 0x034;
+const UARTIMSC: u64 = 0x038;
+const UARTRIS: u64 = 0x03c;
+const UARTMIS: u64 = 0x040;
+const UARTICR: u64 = 0x044;
+const UARTDMACR: u64 = 0x048;
+const UARTPERIPHID0: u64 = 0xfe0;
+
+const FR_RXFE: u32 = 1 << 4;
+const FR_RXFF: u32 = 1 << 6;
+const FR_TXFE: u32 = 1 << 7;
+
+const CR_LBE: u32 = 1 << 7;
+const CR_TXE: u32 = 1 << 8;
+const CR_RXE: u32 = 1 << 9;
+
+const INT_RX: u32 = 1 << 4;
+const INT_TX: u32 = 1 << 5;
+const INT_RT: u32 = 1 << 6;
+const INT_ALL: u32 = 0x7ff;
+
+/// PeriphID0-3 followed by PCellID0-3, which identify the device as a PL011 r1p5 to the AMBA bus.
+const ID: [u8; 8] = [0x11, 0x10, 0x34, 0x00, 0x0d, 0xf0, 0x05, 0xb1];
+
+/// ARM PrimeCell UART (PL011) emulation.
+///
+/// Transmitted bytes are written to `out` immediately, so the transmit FIFO is always empty and
+/// the transmit interrupt is always raised. `interrupt` is triggered whenever an unmasked
+/// interrupt becomes pending.
+#[derive(Debug)]
+pub struct Pl011<T: Trigger, W: Write> {
+    interrupt: T,
+    out: W,
+    rx: VecDeque<u8>,
+    ibrd: u32,
+    fbrd: u32,
+    lcr_h: u32,
+    cr: u32,
+    ifls: u32,
+    imsc: u32,
+    ris: u32,
+    dmacr: u32,
+    ilpr: u32,
+}
+
+impl<T: Trigger, W: Write> Pl011<T, W> {
+    pub fn new(interrupt: T, out: W) -> Self {
+        Self {
+            interrupt,
+            out,
+            rx: VecDeque::with_capacity(FIFO_SIZE),
+            ibrd: 0,
+            fbrd: 0,
+            lcr_h: 0,
+            // Reset value: transmit and receive enabled, UART disabled
+            cr: CR_TXE | CR_RXE,
+            ifls: 0x12,
+            imsc: 0,
+            ris: INT_TX,
+            dmacr: 0,
+            ilpr: 0,
+        }
+    }
+
+    pub fn interrupt_evt(&self) -> &T {
+        &self.interrupt
+    }
+
+    pub fn fifo_capacity(&self) -> usize {
+        FIFO_SIZE - self.rx.len()
+    }
+
+    /// Queues `data` as received by the UART. Fails if the receive FIFO can't hold all of it.
+    pub fn enqueue_raw_bytes(&mut self, data: &[u8]) -> Result<()> {
+        if data.len() > self.fifo_capacity() {
+            return Err(anyhow!("PL011 receive FIFO is full"));
+        }
+        self.rx.extend(data);
+        self.raise(INT_RX | INT_RT)
+    }
+
+    pub fn read(&mut self, offset: u64) -> u32 {
+        match offset {
+            UARTDR => {
+                let value = self.rx.pop_front().unwrap_or(0);
+                if self.rx.is_empty() {
+                    self.ris &= !(INT_RX | INT_RT);
+                }
+                value.into()
+            }
+            UARTFR => {
+                let mut flags = FR_TXFE;
+                if self.rx.is_empty() {
+                    flags |= FR_RXFE;
+                }
+                if self.rx.len() == FIFO_SIZE {
+                    flags |= FR_RXFF;
+                }
+                flags
+            }
+            UARTILPR => self.ilpr,
+            UARTIBRD => self.ibrd,
+            UARTFBRD => self.fbrd,
+            UARTLCR_H => self.lcr_h,
+            UARTCR => self.cr,
+            UARTIFLS => self.ifls,
+            UARTIMSC => self.imsc,
+            UARTRIS => self.ris,
+            UARTMIS => self.ris & self.imsc,
+            UARTDMACR => self.dmacr,
+            UARTPERIPHID0..PL011_MMIO_SIZE if offset.is_multiple_of(4) => {
+                ID[((offset - UARTPERIPHID0) / 4) as usize].into()
+            }
+            _ => 0,
+        }
+    }
+
+    pub fn write(&mut self, offset: u64, value: u32) -> Result<()> {
+        match offset {
+            UARTDR => {
+                let byte = value as u8;
+                if self.cr & CR_LBE != 0 {
+                    if self.fifo_capacity() > 0 {
+                        self.enqueue_raw_bytes(&[byte])?;
+                    }
+                } else {
+                    self.out.write_all(&[byte])?;
+                    self.out.flush()?;
+                }
+                self.raise(INT_TX)?;
+            }
+            // Writing the error clear register clears the receive errors, which never happen
+            UARTRSR => {}
+            UARTILPR => self.ilpr = value & 0xff,
+            UARTIBRD => self.ibrd = value & 0xffff,
+            UARTFBRD => self.fbrd = value & 0x3f,
+            UARTLCR_H => self.lcr_h = value & 0xff,
+            UARTCR => self.cr = value & 0xffff,
+            UARTIFLS => self.ifls = value & 0x3f,
+            UARTIMSC => {
+                self.imsc = value & INT_ALL;
+                self.raise(0)?;
+            }
+            UARTICR => self.ris &= !value,
+            UARTDMACR => self.dmacr = value & 0x7,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Sets `bits` in the raw interrupt status and triggers the interrupt if any unmasked
+    /// interrupt is pending.
+    fn raise(&mut self, bits: u32) -> Result<()> {
+        self.ris |= bits;
+        if self.ris & self.imsc != 0 {
+            self.interrupt
+                .trigger()
+                .map_err(|e| anyhow!("Failed to trigger PL011 interrupt: {:?}", e))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, io::Write};
+
+    use claim::assert_ok;
+    use vm_superio::Trigger;
+
+    use super::{
+        Pl011, CR_LBE, FR_RXFE, INT_RX, INT_TX, UARTCR, UARTDR, UARTFR, UARTICR, UARTIMSC, UARTMIS,
+        UARTPERIPHID0,
+    };
+
+    #[derive(Default)]
+    struct Counter(Cell<usize>);
+
+    impl Trigger for Counter {
+        type E = ();
+
+        fn trigger(&self) -> Result<(), Self::E> {
+            self.0.set(self.0.get() + 1);
+            Ok(())
+        }
+    }
+
+    fn pl011<W: Write>(out: W) -> Pl011<Counter, W> {
+        Pl011::new(Counter::default(), out)
+    }
+
+    #[test]
+    fn transmit() {
+        let mut uart = pl011(Vec::new());
+        for byte in b"ok\n" {
+            assert_ok!(uart.write(UARTDR, (*byte).into()));
+        }
+        assert_eq!(uart.out, b"ok\n");
+        // Nothing is unmasked yet
+        assert_eq!(uart.interrupt.0.get(), 0);
+
+        assert_ok!(uart.write(UARTIMSC, INT_TX));
+        assert_eq!(uart.interrupt.0.get(), 1);
+        assert_eq!(uart.read(UARTMIS), INT_TX);
+    }
+
+    #[test]
+    fn receive() {
+        let mut uart = pl011(Vec::new());
+        assert_ok!(uart.write(UARTIMSC, INT_RX));
+        assert_ne!(uart.read(UARTFR) & FR_RXFE, 0);
+
+        assert_ok!(uart.enqueue_raw_bytes(b"hi"));
+        assert_eq!(uart.interrupt.0.get(), 1);
+        assert_eq!(uart.read(UARTFR) & FR_RXFE, 0);
+        assert_eq!(uart.read(UARTDR), u32::from(b'h'));
+        assert_eq!(uart.read(UARTMIS), INT_RX);
+        assert_eq!(uart.read(UARTDR), u32::from(b'i'));
+        assert_ne!(uart.read(UARTFR) & FR_RXFE, 0);
+        assert_eq!(uart.read(UARTMIS), 0);
+
+        assert!(uart.enqueue_raw_bytes(&[0; 33]).is_err());
+    }
+
+    #[test]
+    fn loopback() {
+        let mut uart = pl011(Vec::new());
+        assert_ok!(uart.write(UARTCR, CR_LBE));
+        assert_ok!(uart.write(UARTDR, 0x55));
+        assert!(uart.out.is_empty());
+        assert_eq!(uart.read(UARTDR), 0x55);
+        assert_ok!(uart.write(UARTICR, 0x7ff));
+        assert_eq!(uart.ris, 0);
+    }
+
+    #[test]
+    fn identification() {
+        let mut uart = pl011(Vec::new());
+        let id = (0..8)
+            .map(|i| uart.read(UARTPERIPHID0 + i * 4) as u8)
+            .collect::<Vec<_>>();
+        assert_eq!(id, [0x11, 0x10, 0x34, 0x00, 0x0d, 0xf0, 0x05, 0xb1]);
+    }
+}
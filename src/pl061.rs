@@ -0,0 +1,432 @@
+// Copyright (c) 2024, Qualcomm Innovation Center, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause-Clear
+
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Result};
+use vm_superio::Trigger;
+use vmm::{BusAccessInfo, BusDevice, FdtWriter, GunyahVirtualMachine};
+
+use crate::GunyahEventTrigger;
+
+pub const PL061_MMIO_SIZE: u64 = 0x1000;
+pub const PL061_NUM_LINES: u8 = 8;
+
+const PL061_CLOCK_PHANDLE: u32 = 0x202;
+const PL061_CLOCK_FREQUENCY: u32 = 24_000_000;
+
+/// GPIODATA is mirrored over 0x000-0x3fc, address bits [9:2] select the lines accessed.
+const GPIODATA_END: u64 = 0x400;
+const GPIODIR: u64 = 0x400;
+const GPIOIS: u64 = 0x404;
+const GPIOIBE: u64 = 0x408;
+const GPIOIEV: u64 = 0x40c;
+const GPIOIE: u64 = 0x410;
+const GPIORIS: u64 = 0x414;
+const GPIOMIS: u64 = 0x418;
+const GPIOIC: u64 = 0x41c;
+const GPIOAFSEL: u64 = 0x420;
+const GPIOPERIPHID0: u64 = 0xfe0;
+
+/// PeriphID0-3 followed by PCellID0-3 of a PL061.
+const ID: [u8; 8] = [0x61, 0x10, 0x04, 0x00, 0x0d, 0xf0, 0x05, 0xb1];
+
+/// Called with the line number and new level whenever the guest changes an output line.
+pub type GpioOutputCallback = Box<dyn FnMut(u8, bool) + Send>;
+
+/// ARM PrimeCell GPIO controller (PL061) with 8 lines.
+///
+/// Lines the guest configures as outputs are reported through the output callback, lines
+/// configured as inputs are driven by the host with [`Pl061::set_input`].
+pub struct Pl061<T: Trigger> {
+    interrupt: T,
+    start: u64,
+    on_output: Option<GpioOutputCallback>,
+    /// Level of each line: driven by the guest for outputs, by the host for inputs
+    data: u8,
+    /// Level the host drives each line to, used when the guest switches a line to input
+    inputs: u8,
+    dir: u8,
+    is: u8,
+    ibe: u8,
+    iev: u8,
+    ie: u8,
+    ris: u8,
+    afsel: u8,
+}
+
+impl Pl061<GunyahEventTrigger> {
+    pub fn new(
+        vm: &mut GunyahVirtualMachine,
+        start: u64,
+        interrupt_line: u32,
+    ) -> Result<Arc<Mutex<Self>>> {
+        let device = Arc::new(Mutex::new(Self::with_trigger(
+            GunyahEventTrigger::new(vm.add_edge_interrupt(interrupt_line)?),
+            start,
+        )));
+        vm.add_device(device.clone(), start, PL061_MMIO_SIZE)?;
+        Ok(device)
+    }
+}
+
+impl<T: Trigger> Pl061<T> {
+    fn with_trigger(interrupt: T, start: u64) -> Self {
+        Self {
+            interrupt,
+            start,
+            on_output: None,
+            data: 0,
+            inputs: 0,
+            dir: 0,
+            is: 0,
+            ibe: 0,
+            iev: 0,
+            ie: 0,
+            ris: 0,
+            afsel: 0,
+        }
+    }
+
+    pub fn device_name(&self) -> String {
+        format!("gpio@{:x}", self.start)
+    }
+
+    /// Calls `callback` whenever the guest changes the level of an output line.
+    pub fn set_output_callback(&mut self, callback: GpioOutputCallback) {
+        self.on_output = Some(callback);
+    }
+
+    /// Current level of `line`, whether it's an input or an output.
+    pub fn level(&self, line: u8) -> bool {
+        self.data & (1 << line) != 0
+    }
+
+    /// Whether the guest configured `line` as an output.
+    pub fn is_output(&self, line: u8) -> bool {
+        self.dir & (1 << line) != 0
+    }
+
+    /// Drives input `line` to `level`, raising the line's interrupt if the guest enabled one for
+    /// the change. The level is latched until the guest makes the line an input if it's
+    /// currently an output.
+    pub fn set_input(&mut self, line: u8, level: bool) -> Result<()> {
+        if line >= PL061_NUM_LINES {
+            return Err(anyhow!("GPIO line {} doesn't exist", line));
+        }
+        let bit = 1 << line;
+        if level {
+            self.inputs |= bit;
+        } else {
+            self.inputs &= !bit;
+        }
+        self.update_inputs()
+    }
+
+    /// Register state for a VM snapshot.
+    fn save_state(&self) -> Vec<u8> {
+        vec![
+            self.data,
+            self.inputs,
+            self.dir,
+            self.is,
+            self.ibe,
+            self.iev,
+            self.ie,
+            self.ris,
+            self.afsel,
+        ]
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<()> {
+        let &[data, inputs, dir, is, ibe, iev, ie, ris, afsel] = state else {
+            return Err(anyhow!(
+                "Expected 9 bytes of GPIO state, got {}",
+                state.len()
+            ));
+        };
+        self.data = data;
+        self.inputs = inputs;
+        self.dir = dir;
+        self.is = is;
+        self.ibe = ibe;
+        self.iev = iev;
+        self.ie = ie;
+        self.ris = ris;
+        self.afsel = afsel;
+        Ok(())
+    }
+
+    /// Takes the host driven level for all input lines and latches any interrupts this causes.
+    fn update_inputs(&mut self) -> Result<()> {
+        let old = self.data;
+        self.data = (self.data & self.dir) | (self.inputs & !self.dir);
+        let changed = (old ^ self.data) & !self.dir;
+        let rising = changed & self.data;
+        let falling = changed & !self.data;
+
+        let edge = !self.is
+            & ((self.ibe & changed)
+                | (!self.ibe & self.iev & rising)
+                | (!self.ibe & !self.iev & falling));
+        let level = self.is & !self.dir & !(self.data ^ self.iev);
+        self.raise(edge | level)
+    }
+
+    fn raise(&mut self, bits: u8) -> Result<()> {
+        let newly_pending = bits & !self.ris & self.ie;
+        self.ris |= bits;
+        if newly_pending != 0 {
+            self.interrupt
+                .trigger()
+                .map_err(|e| anyhow!("Failed to trigger GPIO interrupt: {:?}", e))?;
+        }
+        Ok(())
+    }
+
+    fn set_outputs(&mut self, mask: u8, value: u8) {
+        let mask = mask & self.dir;
+        let old = self.data;
+        self.data = (self.data & !mask) | (value & mask);
+        let changed = old ^ self.data;
+        if let Some(on_output) = &mut self.on_output {
+            for line in (0..PL061_NUM_LINES).filter(|line| changed & (1 << line) != 0) {
+                on_output(line, self.data & (1 << line) != 0);
+            }
+        }
+    }
+
+    fn read_register(&self, offset: u64) -> u8 {
+        match offset {
+            0..GPIODATA_END => self.data & (offset >> 2) as u8,
+            GPIODIR => self.dir,
+            GPIOIS => self.is,
+            GPIOIBE => self.ibe,
+            GPIOIEV => self.iev,
+            GPIOIE => self.ie,
+            GPIORIS => self.ris,
+            GPIOMIS => self.ris & self.ie,
+            GPIOAFSEL => self.afsel,
+            GPIOPERIPHID0..PL061_MMIO_SIZE if offset.is_multiple_of(4) => {
+                ID[((offset - GPIOPERIPHID0) / 4) as usize]
+            }
+            _ => 0,
+        }
+    }
+
+    fn write_register(&mut self, offset: u64, value: u8) -> Result<()> {
+        match offset {
+            0..GPIODATA_END => self.set_outputs((offset >> 2) as u8, value),
+            GPIODIR => {
+                self.dir = value;
+                // Lines which became inputs take the level the host drives them to
+                self.update_inputs()?;
+            }
+            GPIOIS => self.is = value,
+            GPIOIBE => self.ibe = value,
+            GPIOIEV => self.iev = value,
+            GPIOIE => {
+                self.update_inputs()?;
+                // Interrupts which are already pending fire as soon as they're enabled
+                let newly_enabled = value & !self.ie;
+                self.ie = value;
+                if self.ris & newly_enabled != 0 {
+                    self.interrupt
+                        .trigger()
+                        .map_err(|e| anyhow!("Failed to trigger GPIO interrupt: {:?}", e))?;
+                }
+            }
+            GPIOIC => {
+                self.ris &= !value;
+                // A level interrupt stays pending while the line is at its level
+                self.update_inputs()?;
+            }
+            GPIOAFSEL => self.afsel = value,
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+impl BusDevice for Pl061<GunyahEventTrigger> {
+    fn debug_label(&self) -> String {
+        "pl061 gpio".to_string()
+    }
+
+    fn read(&mut self, offset: BusAccessInfo, data: &mut [u8]) -> Result<()> {
+        if data.len() > 4 {
+            return Err(anyhow!("Only reads of up to 4 bytes allowed"));
+        }
+        data.fill(0);
+        data[0] = self.read_register(offset.offset);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: BusAccessInfo, data: &[u8]) -> Result<()> {
+        if data.is_empty() || data.len() > 4 {
+            return Err(anyhow!("Only writes of up to 4 bytes allowed"));
+        }
+        self.write_register(offset.offset, data[0])
+    }
+
+    fn snapshot(&self) -> Result<Option<Vec<u8>>> {
+        Ok(Some(self.save_state()))
+    }
+
+    fn restore(&mut self, state: &[u8]) -> Result<()> {
+        self.load_state(state)
+    }
+
+    fn device_config(&self, fdt: &mut FdtWriter) -> Result<()> {
+        let clock = fdt.begin_node("gpio-clk")?;
+        fdt.property_string("compatible", "fixed-clock")?;
+        fdt.property_u32("#clock-cells", 0)?;
+        fdt.property_u32("clock-frequency", PL061_CLOCK_FREQUENCY)?;
+        fdt.property_u32("phandle", PL061_CLOCK_PHANDLE)?;
+        fdt.end_node(clock)?;
+
+        let node = fdt.begin_node(&self.device_name())?;
+        fdt.property_string_list(
+            "compatible",
+            vec!["arm,pl061".to_string(), "arm,primecell".to_string()],
+        )?;
+        fdt.property_array_u64("reg", &[self.start, PL061_MMIO_SIZE])?;
+        fdt.property_array_u32("interrupts", &self.interrupt.fdt_config())?;
+        fdt.property_null("gpio-controller")?;
+        fdt.property_u32("#gpio-cells", 2)?;
+        fdt.property_null("interrupt-controller")?;
+        fdt.property_u32("#interrupt-cells", 2)?;
+        fdt.property_u32("clocks", PL061_CLOCK_PHANDLE)?;
+        fdt.property_string("clock-names", "apb_pclk")?;
+        fdt.end_node(node)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::Cell,
+        sync::{Arc, Mutex},
+    };
+
+    use claim::{assert_err, assert_ok};
+    use vm_superio::Trigger;
+
+    use super::{Pl061, GPIODIR, GPIOIBE, GPIOIC, GPIOIE, GPIOIEV, GPIOIS, GPIOMIS, GPIOPERIPHID0};
+
+    #[derive(Default)]
+    struct Counter(Cell<usize>);
+
+    impl Trigger for Counter {
+        type E = ();
+
+        fn trigger(&self) -> Result<(), Self::E> {
+            self.0.set(self.0.get() + 1);
+            Ok(())
+        }
+    }
+
+    fn gpio() -> Pl061<Counter> {
+        Pl061::with_trigger(Counter::default(), 0x9030000)
+    }
+
+    /// Offset of GPIODATA which accesses the lines in `mask`.
+    fn data(mask: u8) -> u64 {
+        u64::from(mask) << 2
+    }
+
+    #[test]
+    fn outputs_are_observed() {
+        let mut gpio = gpio();
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let observed = changes.clone();
+        gpio.set_output_callback(Box::new(move |line, level| {
+            observed.lock().unwrap().push((line, level))
+        }));
+
+        assert_ok!(gpio.write_register(GPIODIR, 0b0000_0110));
+        // Line 0 is an input and line 2 isn't selected by the address mask
+        assert_ok!(gpio.write_register(data(0b0000_0011), 0xff));
+        assert_eq!(*changes.lock().unwrap(), [(1, true)]);
+        assert!(gpio.level(1));
+        assert!(!gpio.level(2));
+        assert_eq!(gpio.read_register(data(0xff)), 0b0000_0010);
+        assert_eq!(gpio.read_register(data(0b0000_0001)), 0);
+
+        assert_ok!(gpio.write_register(data(0b0000_0110), 0b0000_0100));
+        assert_eq!(*changes.lock().unwrap(), [(1, true), (1, false), (2, true)]);
+    }
+
+    #[test]
+    fn edge_interrupts() {
+        let mut gpio = gpio();
+        // Line 3 on rising edges, line 4 on both
+        assert_ok!(gpio.write_register(GPIOIEV, 0b0000_1000));
+        assert_ok!(gpio.write_register(GPIOIBE, 0b0001_0000));
+        assert_ok!(gpio.write_register(GPIOIE, 0b0001_1000));
+
+        assert_ok!(gpio.set_input(3, true));
+        assert_eq!(gpio.interrupt.0.get(), 1);
+        assert_eq!(gpio.read_register(GPIOMIS), 0b0000_1000);
+        assert_eq!(gpio.read_register(data(0xff)), 0b0000_1000);
+        assert_ok!(gpio.write_register(GPIOIC, 0xff));
+        assert_ok!(gpio.set_input(3, false));
+        assert_eq!(gpio.read_register(GPIOMIS), 0);
+
+        assert_ok!(gpio.set_input(4, true));
+        assert_ok!(gpio.write_register(GPIOIC, 0xff));
+        assert_ok!(gpio.set_input(4, false));
+        assert_eq!(gpio.read_register(GPIOMIS), 0b0001_0000);
+        assert_eq!(gpio.interrupt.0.get(), 3);
+
+        assert_err!(gpio.set_input(8, true));
+    }
+
+    #[test]
+    fn level_interrupts() {
+        let mut gpio = gpio();
+        // Line 5 while high
+        assert_ok!(gpio.write_register(GPIOIS, 0b0010_0000));
+        assert_ok!(gpio.write_register(GPIOIEV, 0b0010_0000));
+        assert_ok!(gpio.set_input(5, true));
+        assert_eq!(gpio.interrupt.0.get(), 0);
+
+        assert_ok!(gpio.write_register(GPIOIE, 0b0010_0000));
+        assert_eq!(gpio.interrupt.0.get(), 1);
+        // Still high, so clearing doesn't help
+        assert_ok!(gpio.write_register(GPIOIC, 0xff));
+        assert_eq!(gpio.read_register(GPIOMIS), 0b0010_0000);
+
+        assert_ok!(gpio.set_input(5, false));
+        assert_ok!(gpio.write_register(GPIOIC, 0xff));
+        assert_eq!(gpio.read_register(GPIOMIS), 0);
+    }
+
+    #[test]
+    fn identification() {
+        let gpio = gpio();
+        let id = (0..8)
+            .map(|i| gpio.read_register(GPIOPERIPHID0 + i * 4))
+            .collect::<Vec<_>>();
+        assert_eq!(id, [0x61, 0x10, 0x04, 0x00, 0x0d, 0xf0, 0x05, 0xb1]);
+    }
+
+    #[test]
+    fn snapshot() {
+        let mut original = gpio();
+        assert_ok!(original.write_register(GPIODIR, 0x0f));
+        assert_ok!(original.write_register(data(0x0f), 0x05));
+        assert_ok!(original.write_register(GPIOIEV, 0x10));
+        assert_ok!(original.write_register(GPIOIE, 0x10));
+        assert_ok!(original.set_input(4, true));
+
+        let mut restored = gpio();
+        assert_ok!(restored.load_state(&original.save_state()));
+        assert_eq!(restored.read_register(data(0xff)), 0x15);
+        assert!(restored.is_output(0));
+        assert!(!restored.is_output(4));
+        assert_eq!(restored.read_register(GPIOMIS), 0x10);
+        assert_err!(restored.load_state(&[0; 8]));
+    }
+}
@@ -0,0 +1,227 @@
+// Copyright (c) 2024, Qualcomm Innovation Center, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause-Clear
+
+//! Dropping the VMM's privileges once the VM is set up, see `--run-as`.
+//!
+//! Opening /dev/gunyah, creating guest memory and opening device backends may need root, running
+//! the VM doesn't: the file descriptors stay usable. Switching to an unprivileged user before
+//! the VM starts leaves a guest which exploits a device bug without root. The VMM can also enter
+//! new namespaces first, which threads started afterwards, like the vCPUs', share.
+
+use std::{
+    ffi::CString,
+    str::FromStr,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use anyhow::{anyhow, Context, Result};
+
+/// Whether the process dropped its privileges already, see [`RunAs::drop_privileges`]
+static DROPPED: AtomicBool = AtomicBool::new(false);
+
+/// Size of the buffer for the strings of password and group entries
+const ENTRY_BUFFER_SIZE: usize = 0x4000;
+
+/// Namespaces the VMM can enter before dropping its privileges.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Namespace {
+    /// Mounts made afterwards stay private to the VMM
+    Mount,
+    /// No network interfaces but a loopback one which is down
+    Net,
+}
+
+impl Namespace {
+    fn clone_flag(&self) -> libc::c_int {
+        match self {
+            Self::Mount => libc::CLONE_NEWNS,
+            Self::Net => libc::CLONE_NEWNET,
+        }
+    }
+}
+
+/// User and group the VMM runs as once the VM is set up, parsed from `USER[:GROUP]` with names
+/// or numeric IDs. The group defaults to the user's primary group.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RunAs {
+    pub uid: libc::uid_t,
+    pub gid: libc::gid_t,
+}
+
+impl FromStr for RunAs {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (user, group) = match s.split_once(':') {
+            Some((user, group)) => (user, Some(group)),
+            None => (s, None),
+        };
+        let (uid, primary_gid) = lookup_user(user)?;
+        let gid = match group {
+            Some(group) => lookup_group(group)?,
+            None => primary_gid.ok_or(anyhow!(
+                "User {} has no password entry, its group must be given as {}:GROUP",
+                user,
+                user
+            ))?,
+        };
+        Ok(Self { uid, gid })
+    }
+}
+
+impl RunAs {
+    /// Enters new `namespaces`, then switches to the user and group for good, without
+    /// supplementary groups. Only done once per process, later calls do nothing, so restarted
+    /// VMs are set up unprivileged.
+    pub fn drop_privileges(&self, namespaces: &[Namespace]) -> Result<()> {
+        if DROPPED.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        let flags = namespaces
+            .iter()
+            .fold(0, |flags, namespace| flags | namespace.clone_flag());
+        if flags != 0 {
+            // SAFETY: Safe because unshare only changes the namespaces of the calling thread.
+            check(unsafe { libc::unshare(flags) })
+                .context(format!("Failed to enter new {:?} namespaces", namespaces))?;
+        }
+        if namespaces.contains(&Namespace::Mount) {
+            let root = CString::new("/").unwrap();
+            // SAFETY: Safe because the path is a valid C string and the other pointers may be
+            // null when only changing propagation.
+            check(unsafe {
+                libc::mount(
+                    std::ptr::null(),
+                    root.as_ptr(),
+                    std::ptr::null(),
+                    libc::MS_REC | libc::MS_PRIVATE,
+                    std::ptr::null(),
+                )
+            })
+            .context("Failed to make mounts private")?;
+        }
+
+        // The group goes first, the user couldn't change it anymore. The C library changes the
+        // IDs of every thread.
+        // SAFETY: Safe because the group list is one valid gid_t.
+        check(unsafe { libc::setgroups(1, &self.gid) }).context("Failed to drop groups")?;
+        // SAFETY: Safe because setresgid has no memory safety preconditions.
+        check(unsafe { libc::setresgid(self.gid, self.gid, self.gid) })
+            .context(format!("Failed to switch to group {}", self.gid))?;
+        // SAFETY: Safe because setresuid has no memory safety preconditions.
+        check(unsafe { libc::setresuid(self.uid, self.uid, self.uid) })
+            .context(format!("Failed to switch to user {}", self.uid))?;
+        // SAFETY: Safe because setuid has no memory safety preconditions.
+        if self.uid != 0 && unsafe { libc::setuid(0) } == 0 {
+            return Err(anyhow!(
+                "Regained root after switching to user {}",
+                self.uid
+            ));
+        }
+        DROPPED.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+fn check(result: libc::c_int) -> std::io::Result<()> {
+    if result < 0 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// The uid of `user` and its primary group, if it has a password entry.
+fn lookup_user(user: &str) -> Result<(libc::uid_t, Option<libc::gid_t>)> {
+    // SAFETY: Safe because passwd is plain data, which the lookup fills in.
+    let mut entry: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; ENTRY_BUFFER_SIZE];
+    let mut found = std::ptr::null_mut();
+    let uid = user.parse::<libc::uid_t>().ok();
+    let result = match uid {
+        // SAFETY: Safe because entry, buf and found are valid for the lengths given and outlive
+        // the call.
+        Some(uid) => unsafe {
+            libc::getpwuid_r(uid, &mut entry, buf.as_mut_ptr(), buf.len(), &mut found)
+        },
+        None => {
+            let name = CString::new(user).context(format!("Invalid user {:?}", user))?;
+            // SAFETY: Safe because entry, buf and found are valid for the lengths given and
+            // outlive the call, and name is a valid C string.
+            unsafe {
+                libc::getpwnam_r(
+                    name.as_ptr(),
+                    &mut entry,
+                    buf.as_mut_ptr(),
+                    buf.len(),
+                    &mut found,
+                )
+            }
+        }
+    };
+    match (found.is_null(), uid) {
+        (false, _) => Ok((entry.pw_uid, Some(entry.pw_gid))),
+        // Numeric IDs needn't have an entry
+        (true, Some(uid)) => Ok((uid, None)),
+        (true, None) if result != 0 => Err(std::io::Error::from_raw_os_error(result))
+            .context(format!("Failed to look user {} up", user)),
+        (true, None) => Err(anyhow!("No user {}", user)),
+    }
+}
+
+fn lookup_group(group: &str) -> Result<libc::gid_t> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+    let name = CString::new(group).context(format!("Invalid group {:?}", group))?;
+    // SAFETY: Safe because group is plain data, which the lookup fills in.
+    let mut entry: libc::group = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; ENTRY_BUFFER_SIZE];
+    let mut found = std::ptr::null_mut();
+    // SAFETY: Safe because entry, buf and found are valid for the lengths given and outlive
+    // the call, and name is a valid C string.
+    let result = unsafe {
+        libc::getgrnam_r(
+            name.as_ptr(),
+            &mut entry,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut found,
+        )
+    };
+    if !found.is_null() {
+        Ok(entry.gr_gid)
+    } else if result != 0 {
+        Err(std::io::Error::from_raw_os_error(result))
+            .context(format!("Failed to look group {} up", group))
+    } else {
+        Err(anyhow!("No group {}", group))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use claim::{assert_err, assert_ok};
+
+    use super::RunAs;
+
+    #[test]
+    fn parse() {
+        let root = RunAs { uid: 0, gid: 0 };
+        assert_eq!(assert_ok!("root".parse::<RunAs>()), root);
+        assert_eq!(assert_ok!("root:root".parse::<RunAs>()), root);
+        assert_eq!(assert_ok!("0".parse::<RunAs>()), root);
+        assert_eq!(
+            assert_ok!("54321:54322".parse::<RunAs>()),
+            RunAs {
+                uid: 54321,
+                gid: 54322
+            }
+        );
+
+        // Without a password entry, the group is unknown
+        assert_err!("54321".parse::<RunAs>());
+        assert_err!("no-such-user".parse::<RunAs>());
+        assert_err!("root:no-such-group".parse::<RunAs>());
+    }
+}
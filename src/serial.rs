@@ -2,19 +2,85 @@
 // SPDX-License-Identifier: BSD-3-Clause-Clear
 
 use std::fmt::Debug;
-use std::sync::Mutex;
-use std::thread;
-use std::{io::Write, ops::Deref, sync::Arc};
+use std::sync::{Mutex, Weak};
+use std::time::{Duration, Instant};
+use std::{
+    io::{self, Write},
+    ops::Deref,
+    sync::Arc,
+};
 
 use anyhow::{anyhow, Context, Result};
 use derive_more::Constructor;
 use vm_superio::{serial::NoEvents, Serial, Trigger};
-use vmm::{BusDevice, FdtWriter, GunyahInterrupt, GunyahVirtualMachine};
+use vmm::{
+    BusDevice, DeviceExecutor, DeviceTask, FdtWriter, GunyahInterrupt, GunyahVirtualMachine,
+    TaskPoll,
+};
+
+use crate::{ConsoleInput, Pl011, SerialInput, PL011_MMIO_SIZE};
 
 const SERIAL_MMIO_SIZE: u64 = 8;
 
+/// A partial line, e.g. a prompt, waits at most this long to be written, see [`BufferedOutput`]
+const OUTPUT_FLUSH_INTERVAL: Duration = Duration::from_millis(10);
+/// Output is written once this much is buffered, even without a newline
+const OUTPUT_BUFFER_SIZE: usize = 4096;
+
+/// Phandle of the clock referenced by PL011 nodes, see [`create_fdt_pl011_clock`].
+const PL011_CLOCK_PHANDLE: u32 = 0x200;
+const PL011_CLOCK_FREQUENCY: u32 = 24_000_000;
+
+/// UART model emulated by a [`SerialDevice`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum SerialType {
+    #[default]
+    Ns16550a,
+    Pl011,
+}
+
+/// Kernel command line arguments that select the UART at `base`, aliased as `serial<alias>`, as
+/// the early and regular console.
+fn console_args(serial_type: SerialType, base: u64, alias: usize) -> String {
+    match serial_type {
+        SerialType::Ns16550a => format!("earlycon=uart8250,mmio,{:#x} console=ttyS{}", base, alias),
+        SerialType::Pl011 => format!("earlycon=pl011,mmio32,{:#x} console=ttyAMA{}", base, alias),
+    }
+}
+
+/// Adds an `/aliases` node naming `serials` `serial0`, `serial1`, ... in order. Linux numbers the
+/// ttyS ports after these aliases.
+pub fn create_fdt_serial_aliases<W: Write + Debug + 'static + Send>(
+    fdt: &mut FdtWriter,
+    serials: &[Arc<Mutex<SerialDevice<W>>>],
+) -> Result<()> {
+    let aliases = fdt.begin_node("aliases")?;
+    for (alias, serial) in serials.iter().enumerate() {
+        fdt.property_string(
+            &format!("serial{}", alias),
+            &format!("/{}", serial.lock().unwrap().device_name()),
+        )?;
+    }
+    fdt.end_node(aliases)?;
+    Ok(())
+}
+
+/// Adds the fixed clock which PL011 serial ports take their UART and bus clocks from. Needed
+/// once if any serial port is a [`SerialType::Pl011`].
+pub fn create_fdt_pl011_clock(fdt: &mut FdtWriter) -> Result<()> {
+    let clock = fdt.begin_node("apb-pclk")?;
+    fdt.property_string("compatible", "fixed-clock")?;
+    fdt.property_u32("#clock-cells", 0)?;
+    fdt.property_u32("clock-frequency", PL011_CLOCK_FREQUENCY)?;
+    fdt.property_string("clock-output-names", "clk24mhz")?;
+    fdt.property_u32("phandle", PL011_CLOCK_PHANDLE)?;
+    fdt.end_node(clock)?;
+    Ok(())
+}
+
+/// Raises a Gunyah interrupt for vm-superio style devices.
 #[derive(Constructor, Debug)]
-struct GunyahEventTrigger(Arc<GunyahInterrupt>);
+pub struct GunyahEventTrigger(Arc<GunyahInterrupt>);
 impl Trigger for GunyahEventTrigger {
     type E = anyhow::Error;
 
@@ -31,84 +97,450 @@ impl Deref for GunyahEventTrigger {
     }
 }
 
+/// Output of a UART, written in batches rather than with a syscall per character.
+///
+/// The UARTs write and flush every character the guest transmits. This writes whole lines, and
+/// anything buffered once the buffer is full, and leaves partial lines to [`OutputFlushTask`].
+///
+/// Each character still takes an MMIO exit: an ioeventfd only signals that the transmit register
+/// was written, not with what, and the 8250 and PL011 drivers don't keep their output anywhere
+/// the VMM could fetch it from. virtio-console avoids the exits.
+#[derive(Debug)]
+struct BufferedOutput<W: Write + Debug + Send>(Arc<Mutex<OutputBuffer<W>>>);
+
+#[derive(Debug)]
+struct OutputBuffer<W: Write> {
+    out: W,
+    pending: Vec<u8>,
+}
+
+impl<W: Write> OutputBuffer<W> {
+    fn flush_pending(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let result = self
+            .out
+            .write_all(&self.pending)
+            .and_then(|_| self.out.flush());
+        // Output which can't be written is lost, as when the UART writes it directly
+        self.pending.clear();
+        result
+    }
+}
+
+impl<W: Write> Drop for OutputBuffer<W> {
+    fn drop(&mut self) {
+        let _ = self.flush_pending();
+    }
+}
+
+impl<W: Write + Debug + Send> BufferedOutput<W> {
+    fn new(out: W) -> Self {
+        Self(Arc::new(Mutex::new(OutputBuffer {
+            out,
+            pending: Vec::with_capacity(OUTPUT_BUFFER_SIZE),
+        })))
+    }
+}
+
+impl<W: Write + Debug + Send> Write for BufferedOutput<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut output = self.0.lock().unwrap();
+        output.pending.extend_from_slice(buf);
+        if buf.contains(&b'\n') || output.pending.len() >= OUTPUT_BUFFER_SIZE {
+            output.flush_pending()?;
+        }
+        Ok(buf.len())
+    }
+
+    /// The UARTs flush after every character, the output is flushed in batches instead.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Writes the partial lines left in a [`BufferedOutput`], until the serial port is gone.
+struct OutputFlushTask<W: Write>(Weak<Mutex<OutputBuffer<W>>>);
+
+impl<W: Write + Send> DeviceTask for OutputFlushTask<W> {
+    fn debug_label(&self) -> String {
+        "serial output".to_string()
+    }
+
+    fn poll(&mut self, now: Instant) -> Result<TaskPoll> {
+        let Some(output) = self.0.upgrade() else {
+            return Ok(TaskPoll::Done);
+        };
+        // Lost, as when a write of a whole line fails
+        let _ = output.lock().unwrap().flush_pending();
+        Ok(TaskPoll::Pending(Some(now + OUTPUT_FLUSH_INTERVAL)))
+    }
+}
+
+#[derive(Debug)]
+enum Uart<W: Write + Debug + Send> {
+    Ns16550a(Serial<GunyahEventTrigger, NoEvents, W>),
+    Pl011(Pl011<GunyahEventTrigger, W>),
+}
+
+impl<W: Write + Debug + Send> Uart<W> {
+    fn fifo_capacity(&self) -> usize {
+        match self {
+            Self::Ns16550a(serial) => serial.fifo_capacity(),
+            Self::Pl011(pl011) => pl011.fifo_capacity(),
+        }
+    }
+
+    fn enqueue_raw_bytes(&mut self, data: &[u8]) -> Result<()> {
+        match self {
+            Self::Ns16550a(serial) => serial
+                .enqueue_raw_bytes(data)
+                .map(|_| ())
+                .map_err(|e| anyhow!("Failed to enqueue bytes: {:?}", e)),
+            Self::Pl011(pl011) => pl011.enqueue_raw_bytes(data),
+        }
+    }
+
+    fn interrupt_evt(&self) -> &GunyahEventTrigger {
+        match self {
+            Self::Ns16550a(serial) => serial.interrupt_evt(),
+            Self::Pl011(pl011) => pl011.interrupt_evt(),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct SerialDevice<W: Write + Debug + Send> {
-    serial: Serial<GunyahEventTrigger, NoEvents, W>,
+    serial: Uart<BufferedOutput<W>>,
     start: u64,
 }
 
 impl<W: Write + Debug + 'static + Send> SerialDevice<W> {
     pub fn new(
         vm: &mut GunyahVirtualMachine,
+        serial_type: SerialType,
         start: u64,
         interrupt_line: u32,
         out: W,
     ) -> Result<Arc<Mutex<Self>>> {
-        let device = Arc::new(Mutex::new(Self {
-            serial: Serial::new(
-                GunyahEventTrigger::new(vm.add_edge_interrupt(interrupt_line)?),
-                out,
+        let interrupt = GunyahEventTrigger::new(vm.add_edge_interrupt(interrupt_line)?);
+        let out = BufferedOutput::new(out);
+        vm.executor()
+            .spawn(Box::new(OutputFlushTask(Arc::downgrade(&out.0))));
+        let (serial, size) = match serial_type {
+            SerialType::Ns16550a => (
+                Uart::Ns16550a(Serial::new(interrupt, out)),
+                SERIAL_MMIO_SIZE,
             ),
-            start,
-        }));
-
-        vm.add_device(device.clone(), start, start + SERIAL_MMIO_SIZE)?;
-
-        let stdin_serial = device.clone();
-        thread::spawn(move || loop {
-            let mut buf = String::new();
-            let ret = std::io::stdin().read_line(&mut buf).unwrap();
-            if ret > 0 {
-                let mut stdin = stdin_serial.lock().unwrap();
-                if stdin.serial.fifo_capacity() >= ret {
-                    stdin.serial.enqueue_raw_bytes(buf.as_bytes()).unwrap();
-                }
-            }
-        });
+            SerialType::Pl011 => (Uart::Pl011(Pl011::new(interrupt, out)), PL011_MMIO_SIZE),
+        };
+        let device = Arc::new(Mutex::new(Self { serial, start }));
+
+        vm.add_device(device.clone(), start, size)?;
         Ok(device)
     }
 
+    pub fn serial_type(&self) -> SerialType {
+        match self.serial {
+            Uart::Ns16550a(_) => SerialType::Ns16550a,
+            Uart::Pl011(_) => SerialType::Pl011,
+        }
+    }
+
+    /// Feeds stdin to `device`'s receive FIFO, except for escape sequences and monitor input
+    /// handled by `input`.
+    pub fn forward_stdin(
+        device: &Arc<Mutex<Self>>,
+        input: ConsoleInput,
+        executor: &DeviceExecutor,
+    ) {
+        // Stops once the device is gone, e.g. after the VM was reset
+        let device = Arc::downgrade(device);
+        input.forward_stdin(executor, Box::new(move |data| Self::receive(&device, data)));
+    }
+
+    /// Feeds input from a [`crate::SerialBackend`] other than stdio to `device`'s receive FIFO.
+    pub fn forward_input(device: &Arc<Mutex<Self>>, input: SerialInput, executor: &DeviceExecutor) {
+        // Stops once the device is gone, e.g. after the VM was reset
+        let device = Arc::downgrade(device);
+        input.forward(executor, Box::new(move |data| Self::receive(&device, data)));
+    }
+
+    /// Queues as much of `data` in the receive FIFO as there is room for, see
+    /// [`crate::ReceiveInput`].
+    fn receive(device: &Weak<Mutex<Self>>, data: &[u8]) -> Option<usize> {
+        let device = device.upgrade()?;
+        let mut device = device.lock().unwrap();
+        let len = device.serial.fifo_capacity().min(data.len());
+        if len > 0 {
+            device.serial.enqueue_raw_bytes(&data[..len]).unwrap();
+        }
+        Some(len)
+    }
+
     pub fn device_name(&self) -> String {
         format!("serial@{:x}", self.start)
     }
+
+    /// See [`create_fdt_serial_aliases`] for the `alias` numbering.
+    pub fn console_args(&self, alias: usize) -> String {
+        console_args(self.serial_type(), self.start, alias)
+    }
 }
 
 impl<W: Write + Debug + 'static + Send> BusDevice for SerialDevice<W> {
     fn debug_label(&self) -> String {
-        "ns16550a serial".to_string()
+        match self.serial_type() {
+            SerialType::Ns16550a => "ns16550a serial".to_string(),
+            SerialType::Pl011 => "pl011 serial".to_string(),
+        }
     }
 
     fn read(&mut self, offset: vmm::BusAccessInfo, data: &mut [u8]) -> Result<()> {
-        if data.len() != 1 {
-            return Err(anyhow!("Only reads of size 1 allowed"));
+        match &mut self.serial {
+            Uart::Ns16550a(serial) => {
+                if data.len() != 1 {
+                    return Err(anyhow!("Only reads of size 1 allowed"));
+                }
+                data[0] = serial.read(offset.offset.try_into().unwrap());
+            }
+            Uart::Pl011(pl011) => {
+                if data.len() > 4 {
+                    return Err(anyhow!("Only reads of up to 4 bytes allowed"));
+                }
+                let value = pl011.read(offset.offset).to_le_bytes();
+                data.copy_from_slice(&value[..data.len()]);
+            }
         }
-
-        data[0] = self.serial.read(offset.offset.try_into().unwrap());
         Ok(())
     }
 
     fn write(&mut self, offset: vmm::BusAccessInfo, data: &[u8]) -> Result<()> {
-        if data.len() != 1 {
-            return Err(anyhow!("Only writes of size 1 allowed"));
+        match &mut self.serial {
+            Uart::Ns16550a(serial) => {
+                if data.len() != 1 {
+                    return Err(anyhow!("Only writes of size 1 allowed"));
+                }
+                serial
+                    .write(offset.offset.try_into().unwrap(), data[0])
+                    .map_err(|e| {
+                        anyhow!(format!(
+                            "Failed to write to offset: {:x}: {:?}",
+                            offset.offset, e
+                        ))
+                    })
+            }
+            Uart::Pl011(pl011) => {
+                if data.len() > 4 {
+                    return Err(anyhow!("Only writes of up to 4 bytes allowed"));
+                }
+                let mut value = [0u8; 4];
+                value[..data.len()].copy_from_slice(data);
+                pl011.write(offset.offset, u32::from_le_bytes(value))
+            }
         }
-        self.serial
-            .write(offset.offset.try_into().unwrap(), data[0])
-            .map_err(|e| {
-                anyhow!(format!(
-                    "Failed to write to offset: {:x}: {:?}",
-                    offset.offset, e
-                ))
-            })
     }
 
     fn device_config(&self, fdt: &mut FdtWriter) -> anyhow::Result<()> {
         let node = fdt.begin_node(&self.device_name())?;
-        fdt.property_string_list("compatible", vec!["ns16550a".to_string()])?;
-        fdt.property_array_u64("reg", vec![self.start, SERIAL_MMIO_SIZE].as_slice())?;
+        match self.serial_type() {
+            SerialType::Ns16550a => {
+                fdt.property_string_list("compatible", vec!["ns16550a".to_string()])?;
+                fdt.property_array_u64("reg", vec![self.start, SERIAL_MMIO_SIZE].as_slice())?;
+                fdt.property_u32("clock-frequency", 0x1C2000)?;
+            }
+            SerialType::Pl011 => {
+                fdt.property_string_list(
+                    "compatible",
+                    vec!["arm,pl011".to_string(), "arm,primecell".to_string()],
+                )?;
+                fdt.property_array_u64("reg", vec![self.start, PL011_MMIO_SIZE].as_slice())?;
+                fdt.property_array_u32("clocks", &[PL011_CLOCK_PHANDLE, PL011_CLOCK_PHANDLE])?;
+                fdt.property_string_list(
+                    "clock-names",
+                    vec!["uartclk".to_string(), "apb_pclk".to_string()],
+                )?;
+            }
+        }
         let irq_config = self.serial.interrupt_evt().fdt_config();
         fdt.property_array_u32("interrupts", &irq_config)?;
-        fdt.property_u32("clock-frequency", 0x1C2000)?;
         fdt.end_node(node)?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{self, Write},
+        sync::{Arc, Mutex},
+        time::Instant,
+    };
+
+    use claim::assert_ok;
+    use vmm::{parse_fdt, BusDevice, DeviceTask, FdtWriter, GunyahVirtualMachine, TaskPoll};
+
+    use super::{
+        console_args, create_fdt_pl011_clock, create_fdt_serial_aliases, BufferedOutput,
+        OutputFlushTask, SerialDevice, SerialType,
+    };
+
+    /// Records each write it gets
+    #[derive(Clone, Debug, Default)]
+    struct Writes(Arc<Mutex<Vec<Vec<u8>>>>);
+
+    impl Write for Writes {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().push(buf.to_vec());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn output_is_batched() {
+        let writes = Writes::default();
+        let mut out = BufferedOutput::new(writes.clone());
+        let mut task = OutputFlushTask(Arc::downgrade(&out.0));
+
+        // The UARTs write and flush one character at a time
+        for &byte in b"login: root\n# ls" {
+            assert_ok!(out.write_all(&[byte]));
+            assert_ok!(out.flush());
+        }
+        assert_eq!(*writes.0.lock().unwrap(), [b"login: root\n".to_vec()]);
+
+        assert!(matches!(
+            assert_ok!(task.poll(Instant::now())),
+            TaskPoll::Pending(Some(_))
+        ));
+        assert_ok!(out.write_all(b"x"));
+        drop(out);
+        assert_eq!(
+            *writes.0.lock().unwrap(),
+            [b"login: root\n".to_vec(), b"# ls".to_vec(), b"x".to_vec()]
+        );
+        assert_eq!(assert_ok!(task.poll(Instant::now())), TaskPoll::Done);
+    }
+
+    #[test]
+    fn earlycon_follows_serial_base() {
+        assert_eq!(
+            console_args(SerialType::Ns16550a, 0x3f800, 0),
+            "earlycon=uart8250,mmio,0x3f800 console=ttyS0"
+        );
+        assert_eq!(
+            console_args(SerialType::Ns16550a, 0x9000_0000, 0),
+            "earlycon=uart8250,mmio,0x90000000 console=ttyS0"
+        );
+        assert_eq!(
+            console_args(SerialType::Ns16550a, 0x3f900, 1),
+            "earlycon=uart8250,mmio,0x3f900 console=ttyS1"
+        );
+        assert_eq!(
+            console_args(SerialType::Pl011, 0x9000_0000, 1),
+            "earlycon=pl011,mmio32,0x90000000 console=ttyAMA1"
+        );
+    }
+
+    #[test]
+    fn fdt_describes_serial() {
+        let mut vm = GunyahVirtualMachine::new().unwrap();
+        let serial =
+            SerialDevice::new(&mut vm, SerialType::Ns16550a, 0x3f800, 1, io::sink()).unwrap();
+        let serial = serial.lock().unwrap();
+
+        let mut fdt = FdtWriter::new().unwrap();
+        let root_node = fdt.begin_node("").unwrap();
+        fdt.property_u32("#address-cells", 2).unwrap();
+        fdt.property_u32("#size-cells", 2).unwrap();
+        serial.device_config(&mut fdt).unwrap();
+        let chosen = fdt.begin_node("chosen").unwrap();
+        fdt.property_string("bootargs", &serial.console_args(0))
+            .unwrap();
+        fdt.end_node(chosen).unwrap();
+        fdt.end_node(root_node).unwrap();
+        let dtb = fdt.finish().unwrap();
+
+        let parsed = parse_fdt(&dtb).unwrap();
+        assert_eq!(
+            parsed.prop_u64_array("/serial@3f800", "reg"),
+            Some(vec![0x3f800, 8])
+        );
+        assert_eq!(
+            parsed.prop_str("/chosen", "bootargs"),
+            Some("earlycon=uart8250,mmio,0x3f800 console=ttyS0")
+        );
+    }
+
+    #[test]
+    fn fdt_aliases_serials() {
+        let mut vm = GunyahVirtualMachine::new().unwrap();
+        let serials = vec![
+            SerialDevice::new(&mut vm, SerialType::Ns16550a, 0x3f800, 1, io::sink()).unwrap(),
+            SerialDevice::new(&mut vm, SerialType::Ns16550a, 0x3f900, 2, io::sink()).unwrap(),
+        ];
+
+        let mut fdt = FdtWriter::new().unwrap();
+        let root_node = fdt.begin_node("").unwrap();
+        fdt.property_u32("#address-cells", 2).unwrap();
+        fdt.property_u32("#size-cells", 2).unwrap();
+        for serial in &serials {
+            serial.lock().unwrap().device_config(&mut fdt).unwrap();
+        }
+        create_fdt_serial_aliases(&mut fdt, &serials).unwrap();
+        let chosen = fdt.begin_node("chosen").unwrap();
+        fdt.property_string("stdout-path", "serial1").unwrap();
+        fdt.property_string("bootargs", &serials[1].lock().unwrap().console_args(1))
+            .unwrap();
+        fdt.end_node(chosen).unwrap();
+        fdt.end_node(root_node).unwrap();
+        let dtb = fdt.finish().unwrap();
+
+        let parsed = parse_fdt(&dtb).unwrap();
+        assert_eq!(
+            parsed.prop_str("/aliases", "serial0"),
+            Some("/serial@3f800")
+        );
+        assert_eq!(
+            parsed.prop_str("/aliases", "serial1"),
+            Some("/serial@3f900")
+        );
+        assert_eq!(parsed.prop_str("/chosen", "stdout-path"), Some("serial1"));
+        assert_eq!(
+            parsed.prop_str("/chosen", "bootargs"),
+            Some("earlycon=uart8250,mmio,0x3f900 console=ttyS1")
+        );
+    }
+
+    #[test]
+    fn fdt_describes_pl011() {
+        let mut vm = GunyahVirtualMachine::new().unwrap();
+        let serial =
+            SerialDevice::new(&mut vm, SerialType::Pl011, 0x9000000, 1, io::sink()).unwrap();
+        let serial = serial.lock().unwrap();
+
+        let mut fdt = FdtWriter::new().unwrap();
+        let root_node = fdt.begin_node("").unwrap();
+        fdt.property_u32("#address-cells", 2).unwrap();
+        fdt.property_u32("#size-cells", 2).unwrap();
+        create_fdt_pl011_clock(&mut fdt).unwrap();
+        serial.device_config(&mut fdt).unwrap();
+        fdt.end_node(root_node).unwrap();
+        let dtb = fdt.finish().unwrap();
+
+        let parsed = parse_fdt(&dtb).unwrap();
+        assert_eq!(
+            parsed.prop_u64_array("/serial@9000000", "reg"),
+            Some(vec![0x9000000, 0x1000])
+        );
+        assert_eq!(
+            parsed.prop_str("/apb-pclk", "compatible"),
+            Some("fixed-clock")
+        );
+    }
+}
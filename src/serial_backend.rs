@@ -0,0 +1,447 @@
+// Copyright (c) 2024, Qualcomm Innovation Center, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause-Clear
+
+use std::{
+    ffi::CStr,
+    fmt::Display,
+    fs::{self, File, OpenOptions},
+    io::{self, Stdout, Write},
+    os::{
+        fd::{AsRawFd, FromRawFd, RawFd},
+        unix::net::{UnixListener, UnixStream},
+    },
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use anyhow::{anyhow, Context, Result};
+use vmm::{DeviceExecutor, DeviceTask, IoEngine, TaskPoll};
+
+use crate::{InputBuffer, ReceiveInput, StreamStatus};
+
+/// Where a serial port's output goes and its input comes from.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum SerialBackend {
+    /// Output to stdout. Only the console reads stdin.
+    #[default]
+    Stdio,
+    /// Output appended to a file, no input
+    File(PathBuf),
+    /// A newly allocated pseudo terminal, whose path is printed
+    Pty,
+    /// A Unix socket which one client at a time can connect to. Output is dropped while no
+    /// client is connected.
+    Socket(PathBuf),
+    /// Output is dropped, no input
+    Null,
+}
+
+impl FromStr for SerialBackend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stdio" => Ok(Self::Stdio),
+            "pty" => Ok(Self::Pty),
+            "null" => Ok(Self::Null),
+            _ => match s.split_once(':') {
+                Some(("file", path)) if !path.is_empty() => Ok(Self::File(path.into())),
+                Some(("socket", path)) if !path.is_empty() => Ok(Self::Socket(path.into())),
+                _ => Err(anyhow!(
+                    "Unknown serial backend {}, expected stdio, file:PATH, pty, socket:PATH or null",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+impl Display for SerialBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Stdio => write!(f, "stdio"),
+            Self::File(path) => write!(f, "file:{}", path.display()),
+            Self::Pty => write!(f, "pty"),
+            Self::Socket(path) => write!(f, "socket:{}", path.display()),
+            Self::Null => write!(f, "null"),
+        }
+    }
+}
+
+impl SerialBackend {
+    /// Opens the backend, returning the output to hand to the serial port and the input to
+    /// connect once the port exists. Output to files is written with `engine`.
+    pub fn open(&self, engine: &IoEngine) -> Result<(SerialOutput, SerialInput)> {
+        match self {
+            Self::Stdio => Ok((SerialOutput::Stdout(io::stdout()), SerialInput::Stdin)),
+            Self::File(path) => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .context(format!("Failed to open {}", path.display()))?;
+                Ok((SerialOutput::File(file, engine.clone()), SerialInput::None))
+            }
+            Self::Pty => {
+                let (master, path) = open_pty()?;
+                println!("Serial port connected to {}", path.display());
+                Ok((
+                    SerialOutput::Pty(master.try_clone()?),
+                    SerialInput::File(master),
+                ))
+            }
+            Self::Socket(path) => {
+                // Replace the socket of a previous run
+                if fs::symlink_metadata(path).is_ok() {
+                    fs::remove_file(path)
+                        .context(format!("Failed to remove {}", path.display()))?;
+                }
+                let listener = UnixListener::bind(path)
+                    .context(format!("Failed to listen on {}", path.display()))?;
+                listener.set_nonblocking(true)?;
+                let client = SocketClient::default();
+                Ok((
+                    SerialOutput::Socket(client.clone()),
+                    SerialInput::Socket(listener, client),
+                ))
+            }
+            Self::Null => Ok((SerialOutput::Null, SerialInput::None)),
+        }
+    }
+}
+
+/// The client currently connected to a [`SerialBackend::Socket`].
+#[derive(Clone, Debug, Default)]
+pub struct SocketClient(Arc<Mutex<Option<UnixStream>>>);
+
+/// Output side of a [`SerialBackend`].
+#[derive(Debug)]
+pub enum SerialOutput {
+    Stdout(Stdout),
+    File(File, IoEngine),
+    /// Master side of a pseudo terminal
+    Pty(File),
+    Socket(SocketClient),
+    Null,
+}
+
+impl Write for SerialOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Stdout(stdout) => stdout.write(buf),
+            Self::File(file, engine) => engine.write(file, buf),
+            // Output is dropped rather than blocking the guest once nobody reads it and the
+            // terminal buffer is full
+            Self::Pty(master) => match master.write(buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(buf.len()),
+                // The terminal was hung up
+                Err(e) if e.raw_os_error() == Some(libc::EIO) => Ok(buf.len()),
+                result => result,
+            },
+            Self::Socket(client) => {
+                let mut client = client.0.lock().unwrap();
+                // The stream is non-blocking, output a slow client can't take is dropped
+                if let Some(stream) = client.as_mut() {
+                    match stream.write_all(buf) {
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                        Err(_) => *client = None,
+                        Ok(_) => {}
+                    }
+                }
+                Ok(buf.len())
+            }
+            Self::Null => Ok(buf.len()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Stdout(stdout) => stdout.flush(),
+            Self::File(file, _) | Self::Pty(file) => file.flush(),
+            Self::Socket(_) | Self::Null => Ok(()),
+        }
+    }
+}
+
+/// Input side of a [`SerialBackend`].
+#[derive(Debug)]
+pub enum SerialInput {
+    /// Read stdin, see [`crate::SerialDevice::forward_stdin`]
+    Stdin,
+    /// Read a file in non-blocking mode
+    File(File),
+    Socket(UnixListener, SocketClient),
+    None,
+}
+
+impl SerialInput {
+    /// Passes input to `receive` in a task on `executor` until `receive` returns None.
+    ///
+    /// Stdin is left alone since it's shared with the monitor.
+    pub fn forward(self, executor: &DeviceExecutor, receive: ReceiveInput) {
+        match self {
+            Self::Stdin | Self::None => {}
+            Self::File(file) => executor.spawn(Box::new(FileInputTask {
+                file,
+                input: InputBuffer::new(receive),
+                idle: false,
+            })),
+            Self::Socket(listener, client) => executor.spawn(Box::new(SocketInputTask {
+                listener,
+                client,
+                stream: None,
+                input: InputBuffer::new(receive),
+            })),
+        }
+    }
+}
+
+/// Forwards input from a non-blocking file, e.g. a pty master.
+struct FileInputTask {
+    file: File,
+    input: InputBuffer,
+    /// Nothing can be read for now, only check back later
+    idle: bool,
+}
+
+impl DeviceTask for FileInputTask {
+    fn debug_label(&self) -> String {
+        "serial file input".to_string()
+    }
+
+    fn fds(&self) -> Vec<RawFd> {
+        if self.idle || self.input.is_blocked() {
+            Vec::new()
+        } else {
+            vec![self.file.as_raw_fd()]
+        }
+    }
+
+    fn poll(&mut self, now: Instant) -> Result<TaskPoll> {
+        self.idle = false;
+        match self.input.forward(&mut self.file) {
+            Ok(StreamStatus::Open) => {}
+            Ok(StreamStatus::Ended | StreamStatus::ReceiverGone) => return Ok(TaskPoll::Done),
+            // A pty master reads EIO, and polls as hung up, while no one has the terminal open
+            Err(e) if e.raw_os_error() == Some(libc::EIO) => self.idle = true,
+            Err(e) => return Err(e.into()),
+        }
+        Ok(self.input.next_poll(now))
+    }
+}
+
+/// Accepts one client at a time on a [`SerialBackend::Socket`] and forwards its input.
+struct SocketInputTask {
+    listener: UnixListener,
+    client: SocketClient,
+    /// The connected client
+    stream: Option<UnixStream>,
+    input: InputBuffer,
+}
+
+impl SocketInputTask {
+    fn disconnect(&mut self) {
+        self.stream = None;
+        *self.client.0.lock().unwrap() = None;
+    }
+}
+
+impl DeviceTask for SocketInputTask {
+    fn debug_label(&self) -> String {
+        "serial socket input".to_string()
+    }
+
+    fn fds(&self) -> Vec<RawFd> {
+        match &self.stream {
+            _ if self.input.is_blocked() => Vec::new(),
+            Some(stream) => vec![stream.as_raw_fd()],
+            None => vec![self.listener.as_raw_fd()],
+        }
+    }
+
+    fn poll(&mut self, now: Instant) -> Result<TaskPoll> {
+        if self.stream.is_none() {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    stream.set_nonblocking(true)?;
+                    *self.client.0.lock().unwrap() = Some(stream.try_clone()?);
+                    self.stream = Some(stream);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e).context("Failed to accept serial connection"),
+            }
+        }
+        let status = match &mut self.stream {
+            Some(stream) => self.input.forward(stream),
+            None if self.input.push(&[]) => Ok(StreamStatus::Open),
+            None => Ok(StreamStatus::ReceiverGone),
+        };
+        match status {
+            Ok(StreamStatus::Open) => {}
+            Ok(StreamStatus::ReceiverGone) => {
+                self.disconnect();
+                return Ok(TaskPoll::Done);
+            }
+            Ok(StreamStatus::Ended) | Err(_) => self.disconnect(),
+        }
+        Ok(self.input.next_poll(now))
+    }
+}
+
+/// Allocates a pseudo terminal in raw mode and returns its non-blocking master side and the path
+/// of its slave side.
+fn open_pty() -> Result<(File, PathBuf)> {
+    // SAFETY: Safe because posix_openpt has no preconditions and we check the result.
+    let fd = unsafe { libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY | libc::O_NONBLOCK) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error()).context("Failed to allocate a pty");
+    }
+    // SAFETY: Safe because fd is a newly opened file descriptor we own.
+    let master = unsafe { File::from_raw_fd(fd) };
+
+    let mut name = [0u8; 64];
+    // SAFETY: Safe because master is a pty master and name is large enough for the path.
+    unsafe {
+        if libc::grantpt(master.as_raw_fd()) != 0
+            || libc::unlockpt(master.as_raw_fd()) != 0
+            || libc::ptsname_r(master.as_raw_fd(), name.as_mut_ptr().cast(), name.len()) != 0
+        {
+            return Err(io::Error::last_os_error()).context("Failed to set up the pty");
+        }
+    }
+
+    // SAFETY: Safe because termios is plain data and master is a terminal.
+    unsafe {
+        let mut termios = std::mem::zeroed::<libc::termios>();
+        if libc::tcgetattr(master.as_raw_fd(), &mut termios) == 0 {
+            libc::cfmakeraw(&mut termios);
+            libc::tcsetattr(master.as_raw_fd(), libc::TCSANOW, &termios);
+        }
+    }
+
+    let path = CStr::from_bytes_until_nul(&name)?.to_str()?;
+    Ok((master, PathBuf::from(path)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        os::unix::net::UnixStream,
+        str::FromStr,
+        sync::mpsc,
+        time::Duration,
+    };
+
+    use claim::{assert_err, assert_matches, assert_ok};
+    use vmm::{DeviceExecutor, IoEngine, VmExit, VmExitRequest};
+
+    use super::{SerialBackend, SerialInput, SerialOutput};
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("serial-backend-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn parse() {
+        for backend in ["stdio", "file:/tmp/log", "pty", "socket:/tmp/sock", "null"] {
+            let parsed = assert_ok!(SerialBackend::from_str(backend));
+            assert_eq!(parsed.to_string(), backend);
+        }
+        assert_eq!(
+            assert_ok!(SerialBackend::from_str("file:out.log")),
+            SerialBackend::File("out.log".into())
+        );
+        assert_err!(SerialBackend::from_str("file:"));
+        assert_err!(SerialBackend::from_str("tcp:1234"));
+    }
+
+    #[test]
+    fn file_appends() {
+        let path = temp_path("file");
+        assert_ok!(std::fs::write(&path, "old\n"));
+        let backend = SerialBackend::File(path.clone());
+        let (mut out, input) = assert_ok!(backend.open(&IoEngine::default()));
+        assert_matches!(input, SerialInput::None);
+        assert_ok!(out.write_all(b"new\n"));
+        assert_eq!(assert_ok!(std::fs::read_to_string(&path)), "old\nnew\n");
+        assert_ok!(std::fs::remove_file(&path));
+
+        let (mut out, _) = assert_ok!(SerialBackend::Null.open(&IoEngine::default()));
+        assert_matches!(out, SerialOutput::Null);
+        assert_ok!(out.write_all(b"dropped"));
+    }
+
+    #[test]
+    fn socket() {
+        let path = temp_path("socket");
+        let (mut out, input) =
+            assert_ok!(SerialBackend::Socket(path.clone()).open(&IoEngine::default()));
+        // Nobody is connected yet
+        assert_ok!(out.write_all(b"dropped"));
+
+        let (sender, receiver) = mpsc::channel();
+        let executor = DeviceExecutor::default();
+        input.forward(
+            &executor,
+            Box::new(move |data| {
+                if !data.is_empty() {
+                    sender.send(data.to_vec()).ok()?;
+                }
+                Some(data.len())
+            }),
+        );
+        let exit = VmExitRequest::default();
+        let executor = executor.run(exit.clone());
+
+        let mut client = assert_ok!(UnixStream::connect(&path));
+        assert_ok!(client.write_all(b"hi"));
+        assert_eq!(
+            assert_ok!(receiver.recv_timeout(Duration::from_secs(5))),
+            b"hi"
+        );
+
+        // The input task registered the client before reading from it
+        assert_ok!(out.write_all(b"hello"));
+        let mut buf = [0u8; 5];
+        assert_ok!(client.read_exact(&mut buf));
+        assert_eq!(&buf, b"hello");
+
+        drop(receiver);
+        exit.request(VmExit::Poweroff);
+        assert_ok!(executor.join());
+        assert_ok!(std::fs::remove_file(&path));
+    }
+
+    #[test]
+    fn pty() {
+        let (mut out, input) = assert_ok!(SerialBackend::Pty.open(&IoEngine::default()));
+
+        let SerialInput::File(master) = input else {
+            panic!("Unexpected pty input {:?}", input);
+        };
+        let mut name = [0u8; 64];
+        // SAFETY: Safe because master is a pty master and name is large enough for the path.
+        assert_eq!(
+            unsafe {
+                libc::ptsname_r(
+                    std::os::fd::AsRawFd::as_raw_fd(&master),
+                    name.as_mut_ptr().cast(),
+                    name.len(),
+                )
+            },
+            0
+        );
+        let path = assert_ok!(assert_ok!(std::ffi::CStr::from_bytes_until_nul(&name)).to_str());
+        let mut slave = assert_ok!(std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path));
+        assert_ok!(out.write_all(b"raw\n"));
+        let mut buf = [0u8; 4];
+        assert_ok!(slave.read_exact(&mut buf));
+        assert_eq!(&buf, b"raw\n");
+    }
+}
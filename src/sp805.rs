@@ -0,0 +1,383 @@
+// Copyright (c) 2024, Qualcomm Innovation Center, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause-Clear
+
+use std::{
+    sync::{Arc, Mutex, Weak},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use vm_superio::Trigger;
+use vmm::{
+    BusAccessInfo, BusDevice, DeviceTask, FdtWriter, GunyahVirtualMachine, TaskPoll, VmExit,
+    VmExitRequest,
+};
+
+use crate::GunyahEventTrigger;
+
+pub const SP805_MMIO_SIZE: u64 = 0x1000;
+/// Exit code of the VMM when the watchdog expires with [`WatchdogAction::Exit`].
+pub const WATCHDOG_EXIT_CODE: i32 = 124;
+
+const SP805_CLOCK_PHANDLE: u32 = 0x201;
+const SP805_CLOCK_FREQUENCY: u64 = 1_000_000;
+/// How often the countdown is checked for expiry.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+const WDOGLOAD: u64 = 0x000;
+const WDOGVALUE: u64 = 0x004;
+const WDOGCONTROL: u64 = 0x008;
+const WDOGINTCLR: u64 = 0x00c;
+const WDOGRIS: u64 = 0x010;
+const WDOGMIS: u64 = 0x014;
+const WDOGLOCK: u64 = 0xc00;
+const WDOGPERIPHID0: u64 = 0xfe0;
+
+const CONTROL_INTEN: u32 = 1 << 0;
+const CONTROL_RESEN: u32 = 1 << 1;
+
+const UNLOCK_KEY: u32 = 0x1acce551;
+
+/// PeriphID0-3 followed by PCellID0-3 of an SP805.
+const ID: [u8; 8] = [0x05, 0x18, 0x14, 0x00, 0x0d, 0xf0, 0x05, 0xb1];
+
+/// What happens when the watchdog isn't serviced in time.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum WatchdogAction {
+    /// Restart the VM
+    #[default]
+    Reset,
+    /// Stop the VM and exit the VMM with [`WATCHDOG_EXIT_CODE`]
+    Exit,
+}
+
+impl From<WatchdogAction> for VmExit {
+    fn from(action: WatchdogAction) -> Self {
+        match action {
+            WatchdogAction::Reset => VmExit::Reset,
+            WatchdogAction::Exit => VmExit::Exit(WATCHDOG_EXIT_CODE),
+        }
+    }
+}
+
+/// ARM SP805 watchdog.
+///
+/// The counter runs while the interrupt is enabled. The first time it reaches zero the interrupt
+/// is raised and the counter reloads; if it reaches zero again before the guest clears the
+/// interrupt and reset is enabled, `action` is taken.
+#[derive(Debug)]
+pub struct Sp805<T: Trigger> {
+    interrupt: T,
+    exit: VmExitRequest,
+    action: WatchdogAction,
+    start: u64,
+    load: u32,
+    control: u32,
+    ris: bool,
+    locked: bool,
+    /// When the counter reaches zero, if it's running
+    deadline: Option<Instant>,
+}
+
+impl Sp805<GunyahEventTrigger> {
+    pub fn new(
+        vm: &mut GunyahVirtualMachine,
+        start: u64,
+        interrupt_line: u32,
+        action: WatchdogAction,
+    ) -> Result<Arc<Mutex<Self>>> {
+        let device = Arc::new(Mutex::new(Self::with_trigger(
+            GunyahEventTrigger::new(vm.add_edge_interrupt(interrupt_line)?),
+            vm.exit_request(),
+            action,
+            start,
+        )));
+        vm.add_device(device.clone(), start, SP805_MMIO_SIZE)?;
+
+        vm.executor()
+            .spawn(Box::new(Sp805Task(Arc::downgrade(&device))));
+        Ok(device)
+    }
+}
+
+/// Checks the countdown of an [`Sp805`] for expiry until the device is dropped.
+struct Sp805Task<T: Trigger>(Weak<Mutex<Sp805<T>>>);
+
+impl<T: Trigger + Send> DeviceTask for Sp805Task<T> {
+    fn debug_label(&self) -> String {
+        "watchdog".to_string()
+    }
+
+    fn poll(&mut self, now: Instant) -> Result<TaskPoll> {
+        let Some(device) = self.0.upgrade() else {
+            return Ok(TaskPoll::Done);
+        };
+        if let Err(e) = device.lock().unwrap().tick(now) {
+            println!("Failed to update the watchdog: {:?}", e);
+        }
+        Ok(TaskPoll::Pending(Some(now + POLL_INTERVAL)))
+    }
+}
+
+impl<T: Trigger> Sp805<T> {
+    fn with_trigger(interrupt: T, exit: VmExitRequest, action: WatchdogAction, start: u64) -> Self {
+        Self {
+            interrupt,
+            exit,
+            action,
+            start,
+            load: u32::MAX,
+            control: 0,
+            ris: false,
+            locked: false,
+            deadline: None,
+        }
+    }
+
+    pub fn device_name(&self) -> String {
+        format!("watchdog@{:x}", self.start)
+    }
+
+    fn period(&self) -> Duration {
+        Duration::from_micros(u64::from(self.load) * 1_000_000 / SP805_CLOCK_FREQUENCY)
+    }
+
+    /// Restarts the countdown from the load value, if the counter is enabled.
+    fn reload(&mut self, now: Instant) {
+        self.deadline = (self.control & CONTROL_INTEN != 0).then(|| now + self.period());
+    }
+
+    /// Current counter value.
+    fn value(&self, now: Instant) -> u32 {
+        match self.deadline {
+            Some(deadline) => {
+                let remaining = deadline.saturating_duration_since(now);
+                (remaining.as_micros() * u128::from(SP805_CLOCK_FREQUENCY) / 1_000_000) as u32
+            }
+            None => self.load,
+        }
+    }
+
+    /// Advances the counter to `now`, raising the interrupt or taking the watchdog's action if
+    /// it expired.
+    fn tick(&mut self, now: Instant) -> Result<()> {
+        let Some(deadline) = self.deadline else {
+            return Ok(());
+        };
+        if now < deadline {
+            return Ok(());
+        }
+        if self.ris {
+            if self.control & CONTROL_RESEN != 0 {
+                println!("Watchdog expired, {:?}", self.action);
+                self.exit.request(self.action.into());
+                self.deadline = None;
+                return Ok(());
+            }
+        } else {
+            self.ris = true;
+            self.interrupt
+                .trigger()
+                .map_err(|e| anyhow!("Failed to trigger watchdog interrupt: {:?}", e))?;
+        }
+        self.deadline = Some(deadline + self.period());
+        Ok(())
+    }
+
+    fn read_register(&self, offset: u64, now: Instant) -> u32 {
+        match offset {
+            WDOGLOAD => self.load,
+            WDOGVALUE => self.value(now),
+            WDOGCONTROL => self.control,
+            WDOGRIS => self.ris.into(),
+            WDOGMIS => (self.ris && self.control & CONTROL_INTEN != 0).into(),
+            WDOGLOCK => self.locked.into(),
+            WDOGPERIPHID0..SP805_MMIO_SIZE if offset.is_multiple_of(4) => {
+                ID[((offset - WDOGPERIPHID0) / 4) as usize].into()
+            }
+            _ => 0,
+        }
+    }
+
+    fn write_register(&mut self, offset: u64, value: u32, now: Instant) {
+        if offset == WDOGLOCK {
+            self.locked = value != UNLOCK_KEY;
+            return;
+        }
+        if self.locked {
+            return;
+        }
+        match offset {
+            WDOGLOAD => {
+                // A load value of zero raises the interrupt right away
+                self.load = value.max(1);
+                self.reload(now);
+            }
+            WDOGCONTROL => {
+                let was_enabled = self.control & CONTROL_INTEN != 0;
+                self.control = value & (CONTROL_INTEN | CONTROL_RESEN);
+                if !was_enabled || self.control & CONTROL_INTEN == 0 {
+                    self.reload(now);
+                }
+            }
+            WDOGINTCLR => {
+                self.ris = false;
+                self.reload(now);
+            }
+            _ => {}
+        }
+    }
+}
+
+impl BusDevice for Sp805<GunyahEventTrigger> {
+    fn debug_label(&self) -> String {
+        "sp805 watchdog".to_string()
+    }
+
+    fn read(&mut self, offset: BusAccessInfo, data: &mut [u8]) -> Result<()> {
+        if data.len() != 4 {
+            return Err(anyhow!("Only 32-bit reads allowed"));
+        }
+        data.copy_from_slice(
+            &self
+                .read_register(offset.offset, Instant::now())
+                .to_le_bytes(),
+        );
+        Ok(())
+    }
+
+    fn write(&mut self, offset: BusAccessInfo, data: &[u8]) -> Result<()> {
+        let data: [u8; 4] = data
+            .try_into()
+            .map_err(|_| anyhow!("Only 32-bit writes allowed"))?;
+        self.write_register(offset.offset, u32::from_le_bytes(data), Instant::now());
+        Ok(())
+    }
+
+    fn device_config(&self, fdt: &mut FdtWriter) -> Result<()> {
+        let clock = fdt.begin_node("wdog-clk")?;
+        fdt.property_string("compatible", "fixed-clock")?;
+        fdt.property_u32("#clock-cells", 0)?;
+        fdt.property_u32("clock-frequency", SP805_CLOCK_FREQUENCY as u32)?;
+        fdt.property_u32("phandle", SP805_CLOCK_PHANDLE)?;
+        fdt.end_node(clock)?;
+
+        let node = fdt.begin_node(&self.device_name())?;
+        fdt.property_string_list(
+            "compatible",
+            vec!["arm,sp805".to_string(), "arm,primecell".to_string()],
+        )?;
+        fdt.property_array_u64("reg", &[self.start, SP805_MMIO_SIZE])?;
+        fdt.property_array_u32("interrupts", &self.interrupt.fdt_config())?;
+        fdt.property_array_u32("clocks", &[SP805_CLOCK_PHANDLE, SP805_CLOCK_PHANDLE])?;
+        fdt.property_string_list(
+            "clock-names",
+            vec!["wdog_clk".to_string(), "apb_pclk".to_string()],
+        )?;
+        fdt.end_node(node)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::Cell,
+        time::{Duration, Instant},
+    };
+
+    use claim::assert_ok;
+    use vm_superio::Trigger;
+    use vmm::{VmExit, VmExitRequest};
+
+    use super::{
+        Sp805, WatchdogAction, CONTROL_INTEN, CONTROL_RESEN, UNLOCK_KEY, WATCHDOG_EXIT_CODE,
+        WDOGCONTROL, WDOGINTCLR, WDOGLOAD, WDOGLOCK, WDOGMIS, WDOGPERIPHID0, WDOGVALUE,
+    };
+
+    #[derive(Debug, Default)]
+    struct Counter(Cell<usize>);
+
+    impl Trigger for Counter {
+        type E = ();
+
+        fn trigger(&self) -> Result<(), Self::E> {
+            self.0.set(self.0.get() + 1);
+            Ok(())
+        }
+    }
+
+    fn watchdog(action: WatchdogAction, now: Instant) -> Sp805<Counter> {
+        let mut wdog = Sp805::with_trigger(
+            Counter::default(),
+            VmExitRequest::default(),
+            action,
+            0x3e000,
+        );
+        // 1 second
+        wdog.write_register(WDOGLOAD, 1_000_000, now);
+        wdog.write_register(WDOGCONTROL, CONTROL_INTEN | CONTROL_RESEN, now);
+        wdog
+    }
+
+    #[test]
+    fn interrupt_then_action() {
+        let now = Instant::now();
+        let mut wdog = watchdog(WatchdogAction::Exit, now);
+        assert_eq!(
+            wdog.read_register(WDOGVALUE, now + Duration::from_millis(250)),
+            750_000
+        );
+
+        assert_ok!(wdog.tick(now + Duration::from_millis(999)));
+        assert_eq!(wdog.interrupt.0.get(), 0);
+        assert_ok!(wdog.tick(now + Duration::from_secs(1)));
+        assert_eq!(wdog.interrupt.0.get(), 1);
+        assert_eq!(wdog.read_register(WDOGMIS, now), 1);
+        assert_eq!(wdog.exit.reason(), None);
+
+        assert_ok!(wdog.tick(now + Duration::from_secs(2)));
+        assert_eq!(wdog.exit.reason(), Some(VmExit::Exit(WATCHDOG_EXIT_CODE)));
+    }
+
+    #[test]
+    fn serviced_watchdog_never_fires() {
+        let now = Instant::now();
+        let mut wdog = watchdog(WatchdogAction::Reset, now);
+        for second in 1..10 {
+            let now = now + Duration::from_secs(second);
+            assert_ok!(wdog.tick(now));
+            wdog.write_register(WDOGINTCLR, 0, now);
+        }
+        assert_eq!(wdog.exit.reason(), None);
+
+        // Without reset enabled, the interrupt is all that happens
+        wdog.write_register(WDOGCONTROL, CONTROL_INTEN, now);
+        assert_ok!(wdog.tick(now + Duration::from_secs(20)));
+        assert_ok!(wdog.tick(now + Duration::from_secs(30)));
+        assert_eq!(wdog.exit.reason(), None);
+    }
+
+    #[test]
+    fn lock() {
+        let now = Instant::now();
+        let mut wdog = watchdog(WatchdogAction::Reset, now);
+        wdog.write_register(WDOGLOCK, 0, now);
+        assert_eq!(wdog.read_register(WDOGLOCK, now), 1);
+        wdog.write_register(WDOGLOAD, 5, now);
+        assert_eq!(wdog.read_register(WDOGLOAD, now), 1_000_000);
+
+        wdog.write_register(WDOGLOCK, UNLOCK_KEY, now);
+        wdog.write_register(WDOGLOAD, 5, now);
+        assert_eq!(wdog.read_register(WDOGLOAD, now), 5);
+    }
+
+    #[test]
+    fn identification() {
+        let wdog = watchdog(WatchdogAction::Reset, Instant::now());
+        let id = (0..8)
+            .map(|i| wdog.read_register(WDOGPERIPHID0 + i * 4, Instant::now()) as u8)
+            .collect::<Vec<_>>();
+        assert_eq!(id, [0x05, 0x18, 0x14, 0x00, 0x0d, 0xf0, 0x05, 0xb1]);
+    }
+}
@@ -0,0 +1,160 @@
+// Copyright (c) 2024, Qualcomm Innovation Center, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause-Clear
+
+use std::{fmt, fs::File, io, path::Path, str::FromStr};
+
+use anyhow::{anyhow, Context, Result};
+use ed25519_dalek::{pkcs8::DecodePublicKey, Signature, Verifier, VerifyingKey};
+use sha2::{Digest, Sha256};
+
+/// A payload doesn't match its signature or expected digest, so it must not be loaded.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum VerificationError {
+    SignatureMismatch,
+    DigestMismatch { expected: String, actual: String },
+}
+
+impl fmt::Display for VerificationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::SignatureMismatch => write!(f, "Signature does not match"),
+            Self::DigestMismatch { expected, actual } => write!(
+                f,
+                "Digest does not match: expected sha256={}, got sha256={}",
+                expected, actual
+            ),
+        }
+    }
+}
+
+impl std::error::Error for VerificationError {}
+
+/// Verifies a detached ed25519 `signature` of `image` against the PEM-encoded public key `key`.
+pub fn verify_image(image: &[u8], signature: &[u8], key: &str) -> Result<()> {
+    let key = VerifyingKey::from_public_key_pem(key)
+        .map_err(|e| anyhow!("Failed to parse public key: {}", e))?;
+    let signature = Signature::from_slice(signature).context("Malformed signature")?;
+    key.verify(image, &signature)
+        .or(Err(VerificationError::SignatureMismatch))
+        .context("Image signature does not match")
+}
+
+/// Expected digest of a payload, given as `sha256=HEX`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PayloadDigest {
+    sha256: [u8; 32],
+}
+
+impl FromStr for PayloadDigest {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hex = s
+            .strip_prefix("sha256=")
+            .ok_or(anyhow!("Invalid digest {:?}, expected sha256=HEX", s))?;
+        if hex.len() != 64 || !hex.is_ascii() {
+            return Err(anyhow!("SHA-256 digest {:?} is not 64 hex digits", hex));
+        }
+        let mut sha256 = [0u8; 32];
+        for (i, byte) in sha256.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+                .with_context(|| format!("Invalid hex digits in {:?}", hex))?;
+        }
+        Ok(Self { sha256 })
+    }
+}
+
+impl PayloadDigest {
+    /// Verifies that `data` has the digest.
+    pub fn verify(&self, data: &[u8]) -> Result<()> {
+        self.check(Sha256::digest(data).into())
+    }
+
+    /// Verifies that the contents of the file at `path` have the digest, without reading the
+    /// whole file into memory.
+    pub fn verify_file(&self, path: &Path) -> Result<()> {
+        let mut file = File::open(path).context(format!("Failed to open {}", path.display()))?;
+        let mut hasher = Sha256::new();
+        io::copy(&mut file, &mut hasher).context(format!("Failed to read {}", path.display()))?;
+        self.check(hasher.finalize().into())
+            .context(format!("Failed to verify {}", path.display()))
+    }
+
+    fn check(&self, actual: [u8; 32]) -> Result<()> {
+        if actual == self.sha256 {
+            return Ok(());
+        }
+        let hex = |digest: &[u8]| digest.iter().map(|b| format!("{:02x}", b)).collect();
+        Err(VerificationError::DigestMismatch {
+            expected: hex(&self.sha256),
+            actual: hex(&actual),
+        }
+        .into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use claim::{assert_err, assert_ok};
+    use ed25519_dalek::{pkcs8::EncodePublicKey, Signer, SigningKey};
+
+    use super::{verify_image, PayloadDigest, VerificationError};
+
+    const IMAGE: &[u8] = b"gunyah test image";
+    /// SHA-256 of IMAGE
+    const IMAGE_SHA256: &str =
+        "sha256=7af71a63047683e3435dd30b1b53935782f6fdb93e755cfafb3e092e42c29567";
+
+    fn test_key() -> (SigningKey, String) {
+        let signing_key = SigningKey::from_bytes(&[0x5a; 32]);
+        let pem = signing_key
+            .verifying_key()
+            .to_public_key_pem(Default::default())
+            .unwrap();
+        (signing_key, pem)
+    }
+
+    #[test]
+    fn valid_image() {
+        let (signing_key, pem) = test_key();
+        let signature = signing_key.sign(IMAGE).to_bytes();
+        assert_ok!(verify_image(IMAGE, &signature, &pem));
+    }
+
+    #[test]
+    fn tampered_image() {
+        let (signing_key, pem) = test_key();
+        let signature = signing_key.sign(IMAGE).to_bytes();
+
+        let mut tampered = IMAGE.to_vec();
+        tampered[0] ^= 1;
+        let err = assert_err!(verify_image(&tampered, &signature, &pem));
+        assert_eq!(
+            err.downcast_ref::<VerificationError>(),
+            Some(&VerificationError::SignatureMismatch)
+        );
+        assert_err!(verify_image(IMAGE, &signature[1..], &pem));
+    }
+
+    #[test]
+    fn digest() {
+        let digest = assert_ok!(PayloadDigest::from_str(IMAGE_SHA256));
+        assert_ok!(digest.verify(IMAGE));
+        let err = assert_err!(digest.verify(b"tampered image"));
+        assert!(matches!(
+            err.downcast_ref::<VerificationError>(),
+            Some(VerificationError::DigestMismatch { .. })
+        ));
+
+        let path = std::env::temp_dir().join(format!("verify-digest-{}", std::process::id()));
+        assert_ok!(std::fs::write(&path, IMAGE));
+        assert_ok!(digest.verify_file(&path));
+        assert_ok!(std::fs::remove_file(&path));
+
+        assert_err!(PayloadDigest::from_str("md5=00"));
+        assert_err!(PayloadDigest::from_str("sha256=00"));
+        assert_err!(PayloadDigest::from_str(&IMAGE_SHA256.replace('a', "x")));
+    }
+}
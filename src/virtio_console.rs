@@ -0,0 +1,162 @@
+// Copyright (c) 2024, Qualcomm Innovation Center, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause-Clear
+
+use std::{
+    collections::VecDeque,
+    io::Write,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{Context, Result};
+use vmm::{DeviceExecutor, GuestMemory, GunyahVirtualMachine, VirtioDevice, VirtioMmio, Virtqueue};
+
+use crate::ConsoleInput;
+
+const RECEIVE_QUEUE: usize = 0;
+const TRANSMIT_QUEUE: usize = 1;
+const QUEUE_SIZE: u16 = 64;
+/// Most output accepted in one buffer chain
+const MAX_TRANSMIT_SIZE: usize = 0x10000;
+
+/// Kernel command line argument that selects the first virtio console as the console.
+pub const VIRTIO_CONSOLE_ARGS: &str = "console=hvc0";
+
+/// Single port virtio console. Unlike the ns16550a, the guest hands over whole buffers at once,
+/// so console output costs one exit per write instead of one per byte.
+pub struct VirtioConsole<W: Write + Send> {
+    out: W,
+    input: VecDeque<u8>,
+}
+
+impl<W: Write + Send + 'static> VirtioConsole<W> {
+    pub fn new(
+        vm: &mut GunyahVirtualMachine,
+        base: u64,
+        interrupt_line: u32,
+        out: W,
+    ) -> Result<Arc<Mutex<VirtioMmio<Self>>>> {
+        VirtioMmio::new(
+            vm,
+            base,
+            interrupt_line,
+            Self {
+                out,
+                input: VecDeque::new(),
+            },
+        )
+    }
+
+    /// Queues `data` for the guest and hands over as much as the guest has buffers for.
+    pub fn queue_input(console: &mut VirtioMmio<Self>, data: &[u8]) -> Result<()> {
+        console.device_mut().input.extend(data);
+        console.notify(RECEIVE_QUEUE)
+    }
+
+    /// Feeds stdin to `console`, except for escape sequences and monitor input handled by
+    /// `input`.
+    pub fn forward_stdin(
+        console: &Arc<Mutex<VirtioMmio<Self>>>,
+        input: ConsoleInput,
+        executor: &DeviceExecutor,
+    ) {
+        // Stops once the console is gone, e.g. after the VM was reset
+        let console = Arc::downgrade(console);
+        input.forward_stdin(
+            executor,
+            Box::new(move |data| {
+                let console = console.upgrade()?;
+                Self::queue_input(&mut console.lock().unwrap(), data).unwrap();
+                Some(data.len())
+            }),
+        );
+    }
+
+    fn receive(&mut self, queue: &mut Virtqueue, mem: &GuestMemory) -> Result<bool> {
+        let mut used = false;
+        while !self.input.is_empty() {
+            let Some(chain) = queue.pop(mem)? else {
+                break;
+            };
+            let written = chain.write_all(mem, self.input.make_contiguous())?;
+            self.input.drain(..written);
+            queue.add_used(mem, chain.head, written.try_into()?)?;
+            used = true;
+        }
+        Ok(used)
+    }
+
+    fn transmit(&mut self, queue: &mut Virtqueue, mem: &GuestMemory) -> Result<bool> {
+        let mut used = false;
+        while let Some(chain) = queue.pop(mem)? {
+            let data = chain.read_all(mem, MAX_TRANSMIT_SIZE)?;
+            self.out
+                .write_all(&data)
+                .context("Failed to write console output")?;
+            queue.add_used(mem, chain.head, 0)?;
+            used = true;
+        }
+        self.out.flush()?;
+        Ok(used)
+    }
+}
+
+impl<W: Write + Send + 'static> VirtioDevice for VirtioConsole<W> {
+    fn debug_label(&self) -> String {
+        "console".to_string()
+    }
+
+    fn device_type(&self) -> u32 {
+        vmm::VIRTIO_ID_CONSOLE
+    }
+
+    fn queue_max_sizes(&self) -> Vec<u16> {
+        vec![QUEUE_SIZE, QUEUE_SIZE]
+    }
+
+    fn process_queue(
+        &mut self,
+        index: usize,
+        queue: &mut Virtqueue,
+        mem: &GuestMemory,
+    ) -> Result<bool> {
+        match index {
+            RECEIVE_QUEUE => self.receive(queue, mem),
+            TRANSMIT_QUEUE => self.transmit(queue, mem),
+            _ => unreachable!("virtio console has only 2 queues"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use vmm::{parse_fdt, BusDevice, FdtWriter, GunyahVirtualMachine};
+
+    use super::VirtioConsole;
+
+    #[test]
+    fn fdt_describes_virtio_console() {
+        let mut vm = GunyahVirtualMachine::new().unwrap();
+        let console = VirtioConsole::new(&mut vm, 0x3f000, 2, io::sink()).unwrap();
+
+        let mut fdt = FdtWriter::new().unwrap();
+        let root_node = fdt.begin_node("").unwrap();
+        fdt.property_u32("#address-cells", 2).unwrap();
+        fdt.property_u32("#size-cells", 2).unwrap();
+        console.lock().unwrap().device_config(&mut fdt).unwrap();
+        fdt.end_node(root_node).unwrap();
+        let dtb = fdt.finish().unwrap();
+
+        let parsed = parse_fdt(&dtb).unwrap();
+        assert_eq!(
+            parsed.prop_str("/virtio_mmio@3f000", "compatible"),
+            Some("virtio,mmio")
+        );
+        assert_eq!(
+            parsed.prop_u64_array("/virtio_mmio@3f000", "reg"),
+            Some(vec![0x3f000, 0x200])
+        );
+        assert!(parsed.has_prop("/virtio_mmio@3f000", "dma-coherent"));
+    }
+}
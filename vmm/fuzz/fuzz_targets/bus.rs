@@ -0,0 +1,162 @@
+// Copyright (c) 2024, Qualcomm Innovation Center, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause-Clear
+
+//! Drives a [`Bus`] with devices which don't need /dev/gunyah through arbitrary sequences of
+//! accesses, as a guest would through MMIO exits, and changes to the bus, as hotplug would.
+//! Errors are fine, panics aren't.
+
+#![no_main]
+
+use std::{
+    io,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, Result};
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use vmm::{
+    AccessId, Bus, BusAccessInfo, BusDevice, DebugExit, PrefixedLog, VmExitRequest,
+    DEBUG_EXIT_MMIO_SIZE, DEBUG_LOG_MMIO_SIZE,
+};
+
+/// Base of the addresses most accesses go to, so they hit devices
+const WINDOW_BASE: u64 = 0x1000_0000;
+/// Largest access, bigger ones are truncated
+const MAX_ACCESS: usize = 0x100;
+
+/// Memory-like device, which checks its accesses instead of trusting the bus.
+struct Ram(Vec<u8>);
+
+impl BusDevice for Ram {
+    fn debug_label(&self) -> String {
+        "ram".to_string()
+    }
+
+    fn read(&mut self, access: BusAccessInfo, data: &mut [u8]) -> Result<()> {
+        let start = usize::try_from(access.offset)?;
+        let bytes = start
+            .checked_add(data.len())
+            .and_then(|end| self.0.get(start..end))
+            .ok_or(anyhow!("Read beyond the end"))?;
+        data.copy_from_slice(bytes);
+        Ok(())
+    }
+
+    fn write(&mut self, access: BusAccessInfo, data: &[u8]) -> Result<()> {
+        let start = usize::try_from(access.offset)?;
+        let bytes = start
+            .checked_add(data.len())
+            .and_then(|end| self.0.get_mut(start..end))
+            .ok_or(anyhow!("Write beyond the end"))?;
+        bytes.copy_from_slice(data);
+        Ok(())
+    }
+}
+
+#[derive(Arbitrary, Debug)]
+enum Address {
+    /// Offset into the window devices are usually put in
+    Window(u16),
+    /// Anywhere, including the ends of the address space
+    Raw(u64),
+}
+
+impl Address {
+    fn get(&self) -> u64 {
+        match self {
+            Self::Window(offset) => WINDOW_BASE + u64::from(*offset),
+            Self::Raw(addr) => *addr,
+        }
+    }
+}
+
+#[derive(Arbitrary, Debug)]
+enum Device {
+    Ram { len: u16 },
+    Log,
+    Exit,
+}
+
+#[derive(Arbitrary, Debug)]
+enum Op {
+    Insert {
+        device: Device,
+        base: Address,
+    },
+    Remove {
+        base: Address,
+        len: u16,
+    },
+    Read {
+        addr: Address,
+        len: u16,
+    },
+    Write {
+        addr: Address,
+        data: Vec<u8>,
+    },
+    Split {
+        addr: Address,
+        len: u16,
+    },
+    /// Accesses are made by this vCPU, or the VMM
+    Vcpu(Option<u8>),
+}
+
+fuzz_target!(|ops: Vec<Op>| {
+    let mut bus = Bus::new();
+    let exit = VmExitRequest::default();
+
+    for op in ops {
+        match op {
+            Op::Insert { device, base } => {
+                let base = base.get();
+                let (device, len): (Arc<Mutex<dyn BusDevice>>, u64) = match device {
+                    Device::Ram { len } => (
+                        Arc::new(Mutex::new(Ram(vec![0; usize::from(len)]))),
+                        u64::from(len),
+                    ),
+                    Device::Log => (
+                        Arc::new(Mutex::new(PrefixedLog::new(base, "fuzz: ", io::sink()))),
+                        DEBUG_LOG_MMIO_SIZE,
+                    ),
+                    Device::Exit => (
+                        Arc::new(Mutex::new(DebugExit::new(base, exit.clone()))),
+                        DEBUG_EXIT_MMIO_SIZE,
+                    ),
+                };
+                let _ = bus.insert(device, base, len);
+            }
+            Op::Remove { base, len } => {
+                let _ = bus.remove_and_stop(base.get(), u64::from(len));
+            }
+            Op::Read { addr, len } => {
+                let mut data = vec![0u8; usize::from(len).min(MAX_ACCESS)];
+                let _ = bus.read(addr.get(), &mut data);
+            }
+            Op::Write { addr, mut data } => {
+                data.truncate(MAX_ACCESS);
+                let _ = bus.write(addr.get(), &data);
+            }
+            Op::Split { addr, len } => {
+                let (addr, len) = (addr.get(), usize::from(len));
+                if let Ok(pieces) = bus.split(addr, len) {
+                    // The pieces cover the range, in order and without gaps
+                    let mut next = addr;
+                    for (piece_addr, piece_len) in pieces {
+                        assert_eq!(piece_addr, next);
+                        assert!(piece_len > 0);
+                        next += piece_len as u64;
+                    }
+                    assert_eq!(next, addr + len as u64);
+                }
+            }
+            Op::Vcpu(vcpu) => {
+                bus = bus.set_access_id(vcpu.map_or(AccessId::VmmUserspace, AccessId::Vcpu));
+            }
+        }
+    }
+    // Accesses of vCPUs are counted
+    let _ = bus.stats();
+});
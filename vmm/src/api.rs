@@ -0,0 +1,551 @@
+// Copyright (c) 2024, Qualcomm Innovation Center, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause-Clear
+
+//! Control socket for orchestrating a running VM.
+//!
+//! Clients send one JSON object per line with the command name in `"command"` and its arguments
+//! as further members, and get one line back: `{"return": ...}` on success or
+//! `{"error": "..."}` on failure. Supported commands:
+//!
+//! - `query-status`: `{"status": "running" | "paused" | "exited"}`
+//! - `pause`, `resume`: see [`GunyahVirtualMachine::pause`]
+//! - `inject-irq` with `line`: triggers the SPI `line`, which a device must have registered
+//! - `dump-memory` with `address`, `size` and optionally `path`: writes guest memory to `path`,
+//!   or returns it as `{"data": "<hex>"}`
+//! - `system-reset`, `quit`: stop the VM with [`VmExit::Reset`] or [`VmExit::Poweroff`]
+//! - `query-exit-stats`: `{"vcpus": [{"id": 0, "mmio": {"count": 12, "time_ns": 3456}, ...}]}`,
+//!   see [`crate::VcpuStats`]
+//! - `query-metrics`: vCPU exits, interrupts injected, memory mapped and punched, and MMIO
+//!   accesses per device, see [`crate::MetricsSnapshot`]: `{"vcpus": [...], "interrupts":
+//!   [{"line": 5, "triggers": 3}], "memory": {"mapped": 1048576, "punched": 0}, "devices": [...]}`
+//! - `add-vcpu` with `id`: adds a possible vCPU to the running VM, see [`VcpuHotplug::add`]
+//! - `add-memory` with `address`, `size` and optionally `lend`: adds memory in the hotplug window
+//!   to the running VM, shared with the guest unless `lend` is true, see [`MemoryHotplug::add`]
+//!
+//! Devices can't be added once the VM runs: the guest learns about devices from its device tree.
+//!
+//! The socket and its clients are served by [`DeviceTask`]s on the VM's executor, so several
+//! clients can be connected at once.
+
+use std::{
+    fs,
+    io::{self, Read, Write},
+    os::{
+        fd::{AsRawFd, RawFd},
+        unix::{
+            fs::FileTypeExt,
+            net::{UnixListener, UnixStream},
+        },
+    },
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Context, Result};
+use gunyah::ShareType;
+use serde_json::{json, Map, Value};
+
+use crate::{
+    AccessId, Bus, DeviceExecutor, DeviceTask, GunyahInterrupt, GunyahVcpu, GunyahVirtualMachine,
+    MemoryHotplug, TaskPoll, VcpuHotplug, VmExit, VmExitRequest, VmMetrics,
+};
+
+/// How long a client which doesn't read its replies is waited for before writing again.
+const WRITE_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+/// Largest memory dump returned inline instead of written to a file
+const INLINE_DUMP_SIZE: u64 = 0x1000;
+/// Memory is dumped in chunks of this size
+const DUMP_CHUNK_SIZE: u64 = 0x10_0000;
+
+/// Serves the control socket of a VM.
+pub struct ApiServer {
+    executor: DeviceExecutor,
+    bus: Bus,
+    exit: VmExitRequest,
+    interrupts: Vec<Arc<GunyahInterrupt>>,
+    /// None if the server has no VM to add vCPUs to
+    hotplug: Option<VcpuHotplug>,
+    /// None if the server has no VM to add memory to
+    memory_hotplug: Option<MemoryHotplug>,
+    metrics: VmMetrics,
+}
+
+impl ApiServer {
+    /// Creates a server for `vm`. Only interrupts registered so far are known.
+    pub fn new(vm: &GunyahVirtualMachine) -> Self {
+        Self {
+            executor: vm.executor(),
+            bus: vm.get_bus(AccessId::VmmUserspace),
+            exit: vm.exit_request(),
+            interrupts: vm.interrupts(),
+            hotplug: Some(vm.vcpu_hotplug()),
+            memory_hotplug: Some(vm.memory_hotplug()),
+            metrics: vm.metrics(),
+        }
+    }
+
+    /// Serves clients on a socket at `path` until the VM exits, when the socket is removed. A
+    /// stale socket left at `path` by a previous run is replaced, anything else there is an
+    /// error.
+    pub fn listen(self, path: &Path) -> Result<()> {
+        remove_stale_socket(path)?;
+        let listener =
+            UnixListener::bind(path).context(format!("Failed to listen on {}", path.display()))?;
+        listener.set_nonblocking(true)?;
+        let executor = self.executor.clone();
+        executor.spawn(Box::new(ListenTask {
+            server: Arc::new(self),
+            listener,
+            path: PathBuf::from(path),
+        }));
+        Ok(())
+    }
+
+    fn handle(&self, request: &str) -> Value {
+        handle_request(request, |command, request| self.execute(command, request))
+    }
+
+    fn execute(&self, command: &str, request: &Map<String, Value>) -> Result<Value> {
+        match command {
+            "query-status" => {
+                let status = if self.exit.reason().is_some() {
+                    "exited"
+                } else if self.exit.is_paused() {
+                    "paused"
+                } else {
+                    "running"
+                };
+                Ok(json!({ "status": status }))
+            }
+            "pause" => {
+                self.exit.pause();
+                Ok(json!({}))
+            }
+            "resume" => {
+                self.exit.resume();
+                Ok(json!({}))
+            }
+            "inject-irq" => {
+                let line = u32::try_from(get_u64(request, "line")?)?;
+                self.interrupts
+                    .iter()
+                    .find(|interrupt| interrupt.line() == line)
+                    .ok_or(anyhow!("No device uses interrupt {}", line))?
+                    .trigger()?;
+                Ok(json!({}))
+            }
+            "dump-memory" => {
+                let address = get_u64(request, "address")?;
+                let size = get_u64(request, "size")?;
+                match request.get("path").and_then(Value::as_str) {
+                    Some(path) => {
+                        self.dump_memory(address, size, Path::new(path))?;
+                        Ok(json!({}))
+                    }
+                    None if size <= INLINE_DUMP_SIZE => {
+                        let mut data = vec![0u8; size.try_into()?];
+                        self.bus.read(address, &mut data)?;
+                        let hex: String = data.iter().map(|b| format!("{:02x}", b)).collect();
+                        Ok(json!({ "data": hex }))
+                    }
+                    None => Err(anyhow!(
+                        "Dumps of more than {} bytes need a path",
+                        INLINE_DUMP_SIZE
+                    )),
+                }
+            }
+            "system-reset" => {
+                self.exit.request(VmExit::Reset);
+                Ok(json!({}))
+            }
+            "quit" => {
+                self.exit.request(VmExit::Poweroff);
+                Ok(json!({}))
+            }
+            "query-exit-stats" => {
+                let vcpus: Vec<Value> = self
+                    .vcpus()
+                    .iter()
+                    .map(|vcpu| {
+                        let mut stats = Map::new();
+                        stats.insert("id".to_string(), json!(vcpu.id()));
+                        for (reason, exits) in vcpu.stats().by_reason() {
+                            stats.insert(
+                                reason.to_string(),
+                                json!({ "count": exits.count, "time_ns": exits.time.as_nanos() }),
+                            );
+                        }
+                        Value::Object(stats)
+                    })
+                    .collect();
+                Ok(json!({ "vcpus": vcpus }))
+            }
+            "query-metrics" => Ok(self.metrics.snapshot().to_json()),
+            "add-vcpu" => {
+                let id = u8::try_from(get_u64(request, "id")?)?;
+                self.hotplug
+                    .as_ref()
+                    .ok_or(anyhow!("vCPUs can't be added"))?
+                    .add(id)?;
+                Ok(json!({}))
+            }
+            "add-memory" => {
+                let address = get_u64(request, "address")?;
+                let size = usize::try_from(get_u64(request, "size")?)?
+                    .try_into()
+                    .context("Memory size can't be zero")?;
+                let share_type = match request.get("lend").and_then(Value::as_bool) {
+                    Some(true) => ShareType::Lend,
+                    _ => ShareType::Share,
+                };
+                self.memory_hotplug
+                    .as_ref()
+                    .ok_or(anyhow!("Memory can't be added"))?
+                    .add(address, size, share_type, false)?;
+                Ok(json!({}))
+            }
+            _ => Err(anyhow!("Unknown command {}", command)),
+        }
+    }
+
+    fn vcpus(&self) -> Vec<Arc<GunyahVcpu>> {
+        self.hotplug
+            .as_ref()
+            .map(VcpuHotplug::vcpus)
+            .unwrap_or_default()
+    }
+
+    fn dump_memory(&self, address: u64, size: u64, path: &Path) -> Result<()> {
+        let mut file =
+            fs::File::create(path).context(format!("Failed to create {}", path.display()))?;
+        let mut offset = 0;
+        while offset < size {
+            let mut data = vec![0u8; (size - offset).min(DUMP_CHUNK_SIZE).try_into()?];
+            self.bus.read(address + offset, &mut data)?;
+            file.write_all(&data)?;
+            offset += DUMP_CHUNK_SIZE;
+        }
+        Ok(())
+    }
+}
+
+/// Accepts clients of the control socket.
+struct ListenTask {
+    server: Arc<ApiServer>,
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl DeviceTask for ListenTask {
+    fn debug_label(&self) -> String {
+        format!("control socket {}", self.path.display())
+    }
+
+    fn fds(&self) -> Vec<RawFd> {
+        vec![self.listener.as_raw_fd()]
+    }
+
+    fn poll(&mut self, _now: Instant) -> Result<TaskPoll> {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    stream.set_nonblocking(true)?;
+                    self.server.executor.spawn(Box::new(ClientTask {
+                        server: self.server.clone(),
+                        stream,
+                        line: Vec::new(),
+                        replies: Vec::new(),
+                    }));
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return Ok(TaskPoll::Pending(None))
+                }
+                Err(e) => return Err(e).context("Failed to accept control connection"),
+            }
+        }
+    }
+}
+
+impl Drop for ListenTask {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Runs the commands of one control socket client.
+struct ClientTask {
+    server: Arc<ApiServer>,
+    stream: UnixStream,
+    /// Start of a command whose end didn't arrive yet
+    line: Vec<u8>,
+    /// Replies the client didn't read yet
+    replies: Vec<u8>,
+}
+
+impl DeviceTask for ClientTask {
+    fn debug_label(&self) -> String {
+        "control connection".to_string()
+    }
+
+    fn fds(&self) -> Vec<RawFd> {
+        vec![self.stream.as_raw_fd()]
+    }
+
+    fn poll(&mut self, _now: Instant) -> Result<TaskPoll> {
+        let mut buf = [0u8; 4096];
+        loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => return Ok(TaskPoll::Done),
+                Ok(len) => {
+                    for &byte in &buf[..len] {
+                        self.line.push(byte);
+                        if byte == b'\n' {
+                            let reply = self.server.handle(&String::from_utf8_lossy(&self.line));
+                            self.replies
+                                .extend_from_slice(format!("{}\n", reply).as_bytes());
+                            self.line.clear();
+                        }
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e).context("Control connection failed"),
+            }
+        }
+        while !self.replies.is_empty() {
+            match self.stream.write(&self.replies) {
+                Ok(len) => {
+                    self.replies.drain(..len);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return Ok(TaskPoll::Pending(Some(
+                        Instant::now() + WRITE_RETRY_INTERVAL,
+                    )))
+                }
+                Err(e) => return Err(e).context("Control connection failed"),
+            }
+        }
+        Ok(TaskPoll::Pending(None))
+    }
+}
+
+/// Runs the command in the JSON object `request` with `execute`, which gets the command's name
+/// and the request, and returns the reply line of the control socket protocol.
+pub fn handle_request(
+    request: &str,
+    execute: impl FnOnce(&str, &Map<String, Value>) -> Result<Value>,
+) -> Value {
+    let result = serde_json::from_str::<Map<String, Value>>(request)
+        .context("Invalid request")
+        .and_then(|request| {
+            let command = request
+                .get("command")
+                .and_then(Value::as_str)
+                .ok_or(anyhow!("Missing command"))?;
+            execute(command, &request)
+        });
+    match result {
+        Ok(value) => json!({ "return": value }),
+        Err(e) => json!({ "error": format!("{:#}", e) }),
+    }
+}
+
+/// Removes the socket at `path`, if any. Other files are left alone and are an error.
+pub fn remove_stale_socket(path: &Path) -> Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            fs::remove_file(path).context(format!("Failed to remove {}", path.display()))
+        }
+        Ok(_) => Err(anyhow!("{} exists and isn't a socket", path.display())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e).context(format!("Failed to check {}", path.display())),
+    }
+}
+
+fn get_u64(request: &Map<String, Value>, name: &str) -> Result<u64> {
+    request
+        .get(name)
+        .and_then(Value::as_u64)
+        .ok_or(anyhow!("Missing or invalid {}", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        io::{BufRead, BufReader, Write},
+        os::unix::net::{UnixListener, UnixStream},
+        sync::{Arc, Mutex},
+    };
+
+    use anyhow::Result;
+    use claim::assert_ok;
+    use serde_json::{json, Value};
+
+    use super::ApiServer;
+    use crate::{Bus, BusAccessInfo, BusDevice, DeviceExecutor, VmExit, VmExitRequest, VmMetrics};
+
+    struct Rom;
+
+    impl BusDevice for Rom {
+        fn debug_label(&self) -> String {
+            "rom".to_string()
+        }
+
+        fn read(&mut self, access: BusAccessInfo, data: &mut [u8]) -> Result<()> {
+            for (i, byte) in data.iter_mut().enumerate() {
+                *byte = access.offset as u8 + i as u8;
+            }
+            Ok(())
+        }
+    }
+
+    fn server() -> ApiServer {
+        let bus = Bus::new();
+        assert_ok!(bus.insert(Arc::new(Mutex::new(Rom)), 0x8000_0000, 0x2000));
+        ApiServer {
+            executor: DeviceExecutor::default(),
+            bus,
+            exit: VmExitRequest::default(),
+            interrupts: Vec::new(),
+            hotplug: None,
+            memory_hotplug: None,
+            metrics: VmMetrics::default(),
+        }
+    }
+
+    fn is_error(reply: &Value) -> bool {
+        reply.get("error").is_some_and(Value::is_string)
+    }
+
+    #[test]
+    fn lifecycle() {
+        let server = server();
+        let status = r#"{"command": "query-status"}"#;
+        assert_eq!(
+            server.handle(status),
+            json!({ "return": { "status": "running" } })
+        );
+        assert_eq!(
+            server.handle(r#"{"command": "pause"}"#),
+            json!({ "return": {} })
+        );
+        assert_eq!(
+            server.handle(status),
+            json!({ "return": { "status": "paused" } })
+        );
+        server.handle(r#"{"command": "resume"}"#);
+        assert_eq!(
+            server.handle(status),
+            json!({ "return": { "status": "running" } })
+        );
+        assert_eq!(
+            server.handle(r#"{"command": "query-exit-stats"}"#),
+            json!({ "return": { "vcpus": [] } })
+        );
+        assert_eq!(
+            server.handle(r#"{"command": "query-metrics"}"#)["return"]["memory"],
+            json!({ "mapped": 0, "punched": 0 })
+        );
+        assert!(is_error(
+            &server.handle(r#"{"command": "add-vcpu", "id": 1}"#)
+        ));
+        assert!(is_error(&server.handle(
+            r#"{"command": "add-memory", "address": 3221225472, "size": 4096}"#
+        )));
+        server.handle(r#"{"command": "quit"}"#);
+        assert_eq!(server.exit.reason(), Some(VmExit::Poweroff));
+        assert_eq!(
+            server.handle(status),
+            json!({ "return": { "status": "exited" } })
+        );
+    }
+
+    #[test]
+    fn dump_memory() {
+        let server = server();
+        assert_eq!(
+            server.handle(r#"{"command": "dump-memory", "address": 2147483648, "size": 4}"#),
+            json!({ "return": { "data": "00010203" } })
+        );
+        assert!(is_error(&server.handle(
+            r#"{"command": "dump-memory", "address": 2147483648, "size": 8192}"#
+        )));
+
+        let path = std::env::temp_dir().join(format!("api-dump-{}", std::process::id()));
+        let request = json!({
+            "command": "dump-memory",
+            "address": 0x8000_0000u64,
+            "size": 0x2000,
+            "path": path,
+        });
+        assert_eq!(server.handle(&request.to_string()), json!({ "return": {} }));
+        let dump = assert_ok!(std::fs::read(&path));
+        assert_eq!(dump.len(), 0x2000);
+        assert_eq!(dump[0x1ff], 0xff);
+        assert_ok!(std::fs::remove_file(&path));
+
+        // Outside of any device
+        assert!(is_error(&server.handle(
+            r#"{"command": "dump-memory", "address": 0, "size": 4}"#
+        )));
+    }
+
+    #[test]
+    fn socket_clients() {
+        let server = server();
+        let exit = server.exit.clone();
+        let runner = server.executor.run(exit.clone());
+        let path = std::env::temp_dir().join(format!("api-socket-{}", std::process::id()));
+        assert_ok!(server.listen(&path));
+
+        // Both clients are served while connected
+        let mut clients: Vec<_> = (0..2)
+            .map(|_| BufReader::new(assert_ok!(UnixStream::connect(&path))))
+            .collect();
+        for client in clients.iter_mut().rev() {
+            assert_ok!(client
+                .get_mut()
+                .write_all(b"{\"command\": \"query-status\"}\n"));
+            let mut reply = String::new();
+            assert_ok!(client.read_line(&mut reply));
+            assert_eq!(
+                assert_ok!(serde_json::from_str::<Value>(&reply)),
+                json!({ "return": { "status": "running" } })
+            );
+        }
+
+        exit.request(VmExit::Poweroff);
+        runner.join().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn stale_socket() {
+        let path = std::env::temp_dir().join(format!("api-stale-{}", std::process::id()));
+        drop(assert_ok!(UnixListener::bind(&path)));
+        assert_ok!(server().listen(&path));
+        assert_ok!(fs::remove_file(&path));
+
+        // Files other than sockets are kept
+        assert_ok!(fs::write(&path, b"keep"));
+        assert!(server().listen(&path).is_err());
+        assert_eq!(assert_ok!(fs::read(&path)), b"keep");
+        assert_ok!(fs::remove_file(&path));
+    }
+
+    #[test]
+    fn bad_requests() {
+        let server = server();
+        for request in [
+            "not json",
+            "[1, 2]",
+            r#"{"line": 3}"#,
+            r#"{"command": "device-add"}"#,
+            r#"{"command": "inject-irq"}"#,
+            r#"{"command": "inject-irq", "line": 3}"#,
+        ] {
+            assert!(is_error(&server.handle(request)), "{}", request);
+        }
+    }
+}
@@ -7,13 +7,18 @@ use std::{
     collections::BTreeMap,
     fmt::Display,
     result,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicU64, Ordering as AtomicOrdering},
+        Arc, Mutex, RwLock,
+    },
 };
 
 use anyhow::{anyhow, Context};
 use thiserror::Error as ThisError;
 pub use vm_fdt::FdtWriter;
 
+use crate::{DeviceSnapshot, MmioDirection, MmioTrace};
+
 #[derive(ThisError, Debug)]
 pub enum Error {
     #[error("Bus Range not found")]
@@ -30,6 +35,9 @@ pub enum Error {
 
 pub type Result<T> = result::Result<T, Error>;
 
+/// A device together with the base address and length it occupies on the bus.
+pub type BusPlacement = (Arc<Mutex<dyn BusDevice>>, u64, u64);
+
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
 pub enum AccessId {
     VmmUserspace,
@@ -47,6 +55,18 @@ pub struct BusAccessInfo {
     pub id: AccessId,
 }
 
+/// Part of a device which the guest only writes to notify it, like a doorbell. Writes there reach
+/// the device through an ioeventfd instead of a vCPU exit, see [`BusDevice::fast_write_regions`].
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct FastWriteRegion {
+    /// Offset from the device's base address
+    pub offset: u64,
+    /// Size of the writes, 1, 2, 4 or 8 bytes
+    pub len: u32,
+    /// Only writes of this value go to the region, others are handled by [`BusDevice::write`]
+    pub datamatch: Option<u64>,
+}
+
 pub trait BusDevice: Send {
     fn debug_label(&self) -> String;
     /// Reads at `offset` from this device
@@ -58,6 +78,17 @@ pub trait BusDevice: Send {
         Err(anyhow!("Unhandled write"))
     }
 
+    /// Regions whose writes are delivered to [`BusDevice::fast_write`] asynchronously when the
+    /// device is added with [`crate::GunyahVirtualMachine::add_device`]. The written data is
+    /// lost, so regions without a datamatch only suit writes whose value doesn't matter.
+    fn fast_write_regions(&self) -> Vec<FastWriteRegion> {
+        Vec::new()
+    }
+    /// Called after the guest wrote to `region` one or more times.
+    fn fast_write(&mut self, _region: FastWriteRegion) -> anyhow::Result<()> {
+        Err(anyhow!("Unhandled fast write"))
+    }
+
     fn memory_regions(&self) -> Option<Box<[u64]>> {
         None
     }
@@ -67,11 +98,29 @@ pub trait BusDevice: Send {
     fn device_config(&self, _fdt: &mut FdtWriter) -> anyhow::Result<()> {
         Ok(())
     }
+    /// Returns the device's state for a VM snapshot, or None if it has nothing worth keeping.
+    fn snapshot(&self) -> anyhow::Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+    /// Restores state returned by [`BusDevice::snapshot`].
+    fn restore(&mut self, _state: &[u8]) -> anyhow::Result<()> {
+        Err(anyhow!("{} can't restore state", self.debug_label()))
+    }
+    /// Called once the device was removed from the bus and no access is in flight, see
+    /// [`crate::GunyahVirtualMachine::remove_device`]. Devices with threads or pending work
+    /// of their own finish or stop it here.
+    fn stop(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
 }
 
 pub trait BusDeviceSync: BusDevice + Sync {
     fn read(&self, offset: BusAccessInfo, data: &mut [u8]) -> anyhow::Result<()>;
     fn write(&self, offset: BusAccessInfo, data: &[u8]) -> anyhow::Result<()>;
+    /// See [`BusDevice::stop`].
+    fn stop(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
 }
 
 /// Holds a base and length representing the address space occupied by a `BusDevice`.
@@ -122,9 +171,46 @@ impl std::fmt::Debug for BusRange {
     }
 }
 
+/// Accesses vCPUs made to the device at `base`, see [`Bus::stats`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BusDeviceStats {
+    pub label: String,
+    pub base: u64,
+    pub len: u64,
+    pub reads: u64,
+    pub read_bytes: u64,
+    pub writes: u64,
+    pub written_bytes: u64,
+}
+
+#[derive(Debug, Default)]
+struct AccessCounters {
+    reads: AtomicU64,
+    read_bytes: AtomicU64,
+    writes: AtomicU64,
+    written_bytes: AtomicU64,
+}
+
+impl AccessCounters {
+    fn record(&self, count: &AtomicU64, bytes: &AtomicU64, len: usize) {
+        count.fetch_add(1, AtomicOrdering::Relaxed);
+        bytes.fetch_add(len as u64, AtomicOrdering::Relaxed);
+    }
+}
+
 #[derive(Clone, Debug)]
 struct BusEntry {
     device: BusDeviceEntry,
+    counters: Arc<AccessCounters>,
+}
+
+impl BusEntry {
+    fn new(device: BusDeviceEntry) -> Self {
+        Self {
+            device,
+            counters: Arc::new(AccessCounters::default()),
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -165,6 +251,8 @@ impl Display for BusDeviceEntry {
 pub struct Bus {
     devices: Arc<Mutex<BTreeMap<BusRange, BusEntry>>>,
     access_id: AccessId,
+    /// Shared by all clones, so tracing can be turned on after vCPUs got their bus
+    trace: Arc<RwLock<Option<MmioTrace>>>,
 }
 
 impl Display for Bus {
@@ -188,6 +276,21 @@ impl Bus {
         Bus {
             devices: Arc::new(Mutex::new(BTreeMap::new())),
             access_id: AccessId::VmmUserspace,
+            trace: Arc::new(RwLock::new(None)),
+        }
+    }
+
+    /// Records the accesses vCPUs make through this bus and its clones in `trace`, or stops
+    /// tracing if None. Accesses by the VMM itself aren't traced.
+    pub fn set_trace(&self, trace: Option<MmioTrace>) {
+        *self.trace.write().unwrap() = trace;
+    }
+
+    fn trace(&self, direction: MmioDirection, addr: u64, data: &[u8], result: &anyhow::Result<()>) {
+        if let AccessId::Vcpu(vcpu) = self.access_id {
+            if let Some(trace) = &*self.trace.read().unwrap() {
+                trace.record(vcpu, direction, addr, data, result.is_err());
+            }
         }
     }
 
@@ -216,6 +319,29 @@ impl Bus {
         None
     }
 
+    /// Splits `len` bytes at `addr` at the boundaries between devices, returning the address and
+    /// length of each piece. Fails if no device owns part of the range.
+    pub fn split(&self, addr: u64, len: usize) -> anyhow::Result<Vec<(u64, usize)>> {
+        let end = addr.checked_add(len as u64).ok_or(anyhow!(
+            "{:#x} bytes at {:#x} wrap around",
+            len,
+            addr
+        ))?;
+        let mut pieces = Vec::new();
+        let mut next = addr;
+        while next < end {
+            let range = self
+                .first_before(next)
+                .map(|(range, _)| range)
+                .filter(|range| next - range.base < range.len)
+                .ok_or(anyhow!("No device at {:#x}", next))?;
+            let piece = (end - next).min(range.len - (next - range.base));
+            pieces.push((next, piece.try_into()?));
+            next += piece;
+        }
+        Ok(pieces)
+    }
+
     /// Puts the given device at the given address space.
     pub fn insert(&self, device: Arc<Mutex<dyn BusDevice>>, base: u64, len: u64) -> Result<()> {
         if len == 0 {
@@ -244,9 +370,7 @@ impl Bus {
         if devices
             .insert(
                 BusRange { base, len },
-                BusEntry {
-                    device: BusDeviceEntry::OuterSync(device),
-                },
+                BusEntry::new(BusDeviceEntry::OuterSync(device)),
             )
             .is_some()
         {
@@ -292,9 +416,7 @@ impl Bus {
         if devices
             .insert(
                 BusRange { base, len },
-                BusEntry {
-                    device: BusDeviceEntry::InnerSync(device),
-                },
+                BusEntry::new(BusDeviceEntry::InnerSync(device)),
             )
             .is_some()
         {
@@ -311,6 +433,27 @@ impl Bus {
 
     /// Remove the given device at the given address space.
     pub fn remove(&self, base: u64, len: u64) -> Result<()> {
+        self.take(base, len).map(|_| ())
+    }
+
+    /// Removes the device at the given address space and stops it. Accesses already routed to
+    /// the device may still reach it until they finish, so callers have to make sure there are
+    /// none, see [`crate::GunyahVirtualMachine::remove_device`].
+    pub fn remove_and_stop(&self, base: u64, len: u64) -> anyhow::Result<()> {
+        match self.take(base, len)?.device {
+            BusDeviceEntry::OuterSync(dev) => {
+                let mut device = dev.lock().unwrap();
+                device
+                    .stop()
+                    .context(format!("Failed to stop {}", device.debug_label()))
+            }
+            BusDeviceEntry::InnerSync(dev) => {
+                BusDeviceSync::stop(&*dev).context(format!("Failed to stop {}", dev.debug_label()))
+            }
+        }
+    }
+
+    fn take(&self, base: u64, len: u64) -> Result<BusEntry> {
         if len == 0 {
             return Err(Error::Overlap {
                 base,
@@ -325,22 +468,59 @@ impl Bus {
             .iter()
             .any(|(range, _dev)| range.base == base && range.len == len)
         {
-            let ret = devices.remove(&BusRange { base, len });
-            if ret.is_some() {
-                Ok(())
-            } else {
-                Err(Error::Empty)
-            }
+            devices.remove(&BusRange { base, len }).ok_or(Error::Empty)
         } else {
             Err(Error::Empty)
         }
     }
 
+    /// Atomically replaces the device at the given address space with `devices`. The new devices
+    /// must lie within the replaced range, so concurrent accesses see either the old device or
+    /// the new ones.
+    pub fn replace(&self, base: u64, len: u64, devices: Vec<BusPlacement>) -> Result<()> {
+        let range = BusRange { base, len };
+        let mut map = self.devices.lock().unwrap();
+        match map.get_key_value(&range) {
+            Some((existing, _)) if existing.len == len => (),
+            _ => return Err(Error::Empty),
+        }
+
+        if let Some((_, new_base, new_len)) = devices.iter().find(|(_, new_base, new_len)| {
+            *new_len == 0 || *new_base < base || new_base + new_len > base + len
+        }) {
+            return Err(Error::Overlap {
+                base: *new_base,
+                len: *new_len,
+                other_base: base,
+                other_len: len,
+            });
+        }
+
+        map.remove(&range);
+        for (device, base, len) in devices {
+            map.insert(
+                BusRange { base, len },
+                BusEntry::new(BusDeviceEntry::OuterSync(device)),
+            );
+        }
+        Ok(())
+    }
+
     /// Reads data from the device that owns the range containing `addr` and puts it into `data`.
     ///
     /// Returns true on success, otherwise `data` is untouched.
     pub fn read(&self, addr: u64, data: &mut [u8]) -> anyhow::Result<()> {
+        let result = self.read_device(addr, data);
+        self.trace(MmioDirection::Read, addr, data, &result);
+        result
+    }
+
+    fn read_device(&self, addr: u64, data: &mut [u8]) -> anyhow::Result<()> {
         if let Some((offset, address, entry)) = self.get_device(addr) {
+            if let AccessId::Vcpu(_) = self.access_id {
+                let counters = &entry.counters;
+                counters.record(&counters.reads, &counters.read_bytes, data.len());
+            }
             let io = BusAccessInfo {
                 address,
                 offset,
@@ -367,7 +547,17 @@ impl Bus {
     ///
     /// Returns true on success, otherwise `data` is untouched.
     pub fn write(&self, addr: u64, data: &[u8]) -> anyhow::Result<()> {
+        let result = self.write_device(addr, data);
+        self.trace(MmioDirection::Write, addr, data, &result);
+        result
+    }
+
+    fn write_device(&self, addr: u64, data: &[u8]) -> anyhow::Result<()> {
         if let Some((offset, address, entry)) = self.get_device(addr) {
+            if let AccessId::Vcpu(_) = self.access_id {
+                let counters = &entry.counters;
+                counters.record(&counters.writes, &counters.written_bytes, data.len());
+            }
             let io = BusAccessInfo {
                 address,
                 offset,
@@ -421,6 +611,84 @@ impl Bus {
         vec
     }
 
+    /// Collects the state of every device which has any.
+    /// How often vCPUs accessed each device, in address order. Accesses by the VMM itself
+    /// aren't counted.
+    pub fn stats(&self) -> Vec<BusDeviceStats> {
+        let devices: Vec<(BusRange, BusEntry)> = self
+            .devices
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(range, entry)| (*range, entry.clone()))
+            .collect();
+        devices
+            .into_iter()
+            .map(|(range, entry)| {
+                let counters = &entry.counters;
+                BusDeviceStats {
+                    label: match &entry.device {
+                        BusDeviceEntry::OuterSync(dev) => dev.lock().unwrap().debug_label(),
+                        BusDeviceEntry::InnerSync(dev) => dev.debug_label(),
+                    },
+                    base: range.base,
+                    len: range.len,
+                    reads: counters.reads.load(AtomicOrdering::Relaxed),
+                    read_bytes: counters.read_bytes.load(AtomicOrdering::Relaxed),
+                    writes: counters.writes.load(AtomicOrdering::Relaxed),
+                    written_bytes: counters.written_bytes.load(AtomicOrdering::Relaxed),
+                }
+            })
+            .collect()
+    }
+
+    pub fn snapshot_devices(&self) -> anyhow::Result<Vec<DeviceSnapshot>> {
+        let devices = self.devices.lock().unwrap();
+        let mut snapshots = Vec::new();
+        for (range, device) in devices.iter() {
+            let (label, state) = match &device.device {
+                BusDeviceEntry::OuterSync(dev) => {
+                    let dev = dev.lock().unwrap();
+                    (dev.debug_label(), dev.snapshot()?)
+                }
+                BusDeviceEntry::InnerSync(dev) => (dev.debug_label(), dev.snapshot()?),
+            };
+            if let Some(state) = state {
+                snapshots.push(DeviceSnapshot {
+                    base: range.base,
+                    label,
+                    state,
+                });
+            }
+        }
+        Ok(snapshots)
+    }
+
+    /// Restores the state of the device at `snapshot.base`, which must have the same label as the
+    /// device the snapshot was taken of.
+    pub fn restore_device(&self, snapshot: &DeviceSnapshot) -> anyhow::Result<()> {
+        let (offset, _, entry) = self
+            .get_device(snapshot.base)
+            .ok_or(anyhow!("No device at {:#x}", snapshot.base))?;
+        let BusDeviceEntry::OuterSync(dev) = &entry.device else {
+            return Err(anyhow!(
+                "Device at {:#x} can't restore state",
+                snapshot.base
+            ));
+        };
+        let mut dev = dev.lock().unwrap();
+        if offset != 0 || dev.debug_label() != snapshot.label {
+            return Err(anyhow!(
+                "Expected {} at {:#x}, found {}",
+                snapshot.label,
+                snapshot.base,
+                dev.debug_label()
+            ));
+        }
+        dev.restore(&snapshot.state)
+            .context(format!("Failed to restore {}", snapshot.label))
+    }
+
     pub fn generate_device_config(&self, fdt: &mut FdtWriter) -> anyhow::Result<()> {
         let devices = self.devices.lock().unwrap();
         devices
@@ -431,3 +699,104 @@ impl Bus {
             })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use claim::{assert_err, assert_ok};
+
+    use super::{AccessId, Bus, BusAccessInfo, BusDevice, BusDeviceStats};
+
+    #[derive(Default)]
+    struct Device {
+        stopped: bool,
+    }
+
+    impl BusDevice for Device {
+        fn debug_label(&self) -> String {
+            "device".to_string()
+        }
+
+        fn read(&mut self, _access: BusAccessInfo, data: &mut [u8]) -> anyhow::Result<()> {
+            data.fill(0);
+            Ok(())
+        }
+
+        fn stop(&mut self) -> anyhow::Result<()> {
+            self.stopped = true;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn remove_and_stop() {
+        let bus = Bus::new();
+        let device = Arc::new(Mutex::new(Device::default()));
+        assert_ok!(bus.insert(device.clone(), 0x1000, 0x100));
+        assert_ok!(bus.read(0x1000, &mut [0u8; 4]));
+
+        assert_err!(bus.remove_and_stop(0x1000, 0x80));
+        assert!(!device.lock().unwrap().stopped);
+        assert_ok!(bus.remove_and_stop(0x1000, 0x100));
+        assert!(device.lock().unwrap().stopped);
+        assert_err!(bus.read(0x1000, &mut [0u8; 4]));
+        assert_err!(bus.remove_and_stop(0x1000, 0x100));
+    }
+
+    #[test]
+    fn split() {
+        let bus = Bus::new();
+        assert_ok!(bus.insert(Arc::new(Mutex::new(Device::default())), 0x1000, 0x100));
+        assert_ok!(bus.insert(Arc::new(Mutex::new(Device::default())), 0x1100, 0x100));
+        assert_eq!(assert_ok!(bus.split(0x1010, 0x10)), [(0x1010, 0x10)]);
+        assert_eq!(
+            assert_ok!(bus.split(0x10f0, 0x20)),
+            [(0x10f0, 0x10), (0x1100, 0x10)]
+        );
+        assert_eq!(assert_ok!(bus.split(0x1000, 0)), []);
+        assert_err!(bus.split(0x11f0, 0x20));
+
+        // Up to the end of the address space
+        assert_ok!(bus.insert(
+            Arc::new(Mutex::new(Device::default())),
+            u64::MAX - 0xff,
+            0x100
+        ));
+        assert_eq!(
+            assert_ok!(bus.split(u64::MAX - 0xf, 0xf)),
+            [(u64::MAX - 0xf, 0xf)]
+        );
+        assert_err!(bus.split(u64::MAX - 0xf, 0x20));
+    }
+
+    #[test]
+    fn stats() {
+        let bus = Bus::new();
+        assert_ok!(bus.insert(Arc::new(Mutex::new(Device::default())), 0x1000, 0x100));
+        assert_ok!(bus.insert(Arc::new(Mutex::new(Device::default())), 0x2000, 0x100));
+        let vcpu_bus = bus.clone().set_access_id(AccessId::Vcpu(0));
+        assert_ok!(vcpu_bus.read(0x1000, &mut [0u8; 4]));
+        assert_ok!(vcpu_bus.read(0x1008, &mut [0u8; 8]));
+        assert_err!(vcpu_bus.write(0x2000, &[0u8; 2]));
+        // The VMM's own accesses aren't counted
+        assert_ok!(bus.read(0x2000, &mut [0u8; 4]));
+
+        let stats = bus.stats();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(
+            stats[0],
+            BusDeviceStats {
+                label: "device".to_string(),
+                base: 0x1000,
+                len: 0x100,
+                reads: 2,
+                read_bytes: 12,
+                writes: 0,
+                written_bytes: 0,
+            }
+        );
+        assert_eq!((stats[1].reads, stats[1].writes), (0, 1));
+        assert_eq!(stats[1].written_bytes, 2);
+    }
+}
@@ -0,0 +1,143 @@
+// Copyright (c) 2024, Qualcomm Innovation Center, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause-Clear
+
+//! Breakpoints on guest physical addresses.
+//!
+//! Gunyah gives the host neither the guest's debug registers nor a way to single-step a vCPU,
+//! so breakpoints work by removing the page holding the address from the VM. The first guest
+//! access to that page, whether an instruction fetch or a data access, exits to the VMM, which
+//! maps the page back and pauses the VM before the access is retried. Breakpoints therefore have
+//! page granularity and fire once.
+
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Result;
+
+/// Maps a page removed for a breakpoint back into the VM.
+pub(crate) type RemapPage = Box<dyn FnOnce() -> Result<()> + Send>;
+
+/// Where a vCPU stopped for a breakpoint.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DebugStop {
+    pub vcpu: u32,
+    /// Address the breakpoint was set at
+    pub breakpoint: u64,
+    /// Address the vCPU accessed
+    pub addr: u64,
+}
+
+struct Breakpoint {
+    addr: u64,
+    remap: RemapPage,
+}
+
+#[derive(Default)]
+struct DebugState {
+    /// Armed breakpoints by page address
+    breakpoints: BTreeMap<u64, Breakpoint>,
+    stop: Option<DebugStop>,
+}
+
+/// Breakpoints of a VM, shared by the VM and its vCPUs.
+#[derive(Clone, Default)]
+pub(crate) struct VmDebug {
+    state: Arc<Mutex<DebugState>>,
+}
+
+impl VmDebug {
+    /// Arms a breakpoint at `addr`, whose page has been removed from the VM and is mapped back
+    /// by `remap`.
+    pub(crate) fn insert(&self, page: u64, addr: u64, remap: RemapPage) {
+        self.state
+            .lock()
+            .unwrap()
+            .breakpoints
+            .insert(page, Breakpoint { addr, remap });
+    }
+
+    /// Whether a breakpoint is armed in `page`.
+    pub(crate) fn contains(&self, page: u64) -> bool {
+        self.state.lock().unwrap().breakpoints.contains_key(&page)
+    }
+
+    /// Disarms the breakpoint in `page`, if there is one, and maps the page back.
+    pub(crate) fn remove(&self, page: u64) -> Result<bool> {
+        let breakpoint = self.state.lock().unwrap().breakpoints.remove(&page);
+        match breakpoint {
+            Some(breakpoint) => (breakpoint.remap)().map(|_| true),
+            None => Ok(false),
+        }
+    }
+
+    /// Handles a page fault of `vcpu` at `addr` in `page`. Returns false if it wasn't caused by
+    /// a breakpoint.
+    pub(crate) fn hit(&self, vcpu: u32, page: u64, addr: u64) -> Result<bool> {
+        let mut state = self.state.lock().unwrap();
+        let Some(breakpoint) = state.breakpoints.remove(&page) else {
+            return Ok(false);
+        };
+        state.stop = Some(DebugStop {
+            vcpu,
+            breakpoint: breakpoint.addr,
+            addr,
+        });
+        drop(state);
+        (breakpoint.remap)()?;
+        Ok(true)
+    }
+
+    /// Returns the last breakpoint stop, if any, and forgets it.
+    pub(crate) fn take_stop(&self) -> Option<DebugStop> {
+        self.state.lock().unwrap().stop.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use claim::{assert_none, assert_ok};
+
+    use super::{DebugStop, VmDebug};
+
+    #[test]
+    fn breakpoints_fire_once() {
+        let debug = VmDebug::default();
+        let remapped = Arc::new(AtomicUsize::new(0));
+        let remap = || {
+            let remapped = remapped.clone();
+            Box::new(move || {
+                remapped.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            })
+        };
+        debug.insert(0x8000_1000, 0x8000_1234, remap());
+        debug.insert(0x8000_3000, 0x8000_3000, remap());
+        assert!(debug.contains(0x8000_1000));
+
+        assert!(!assert_ok!(debug.hit(0, 0x8000_2000, 0x8000_2000)));
+        assert_none!(debug.take_stop());
+        assert!(assert_ok!(debug.hit(1, 0x8000_1000, 0x8000_1008)));
+        assert_eq!(remapped.load(Ordering::SeqCst), 1);
+        assert_eq!(
+            debug.take_stop(),
+            Some(DebugStop {
+                vcpu: 1,
+                breakpoint: 0x8000_1234,
+                addr: 0x8000_1008,
+            })
+        );
+        assert_none!(debug.take_stop());
+        assert!(!assert_ok!(debug.hit(1, 0x8000_1000, 0x8000_1008)));
+
+        assert!(assert_ok!(debug.remove(0x8000_3000)));
+        assert!(!assert_ok!(debug.remove(0x8000_3000)));
+        assert_eq!(remapped.load(Ordering::SeqCst), 2);
+    }
+}
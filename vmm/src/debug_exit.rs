@@ -0,0 +1,86 @@
+// Copyright (c) 2024, Qualcomm Innovation Center, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause-Clear
+
+use anyhow::{anyhow, Result};
+use vm_fdt::FdtWriter;
+
+use crate::{BusAccessInfo, BusDevice, VmExit, VmExitRequest};
+
+pub const DEBUG_EXIT_MMIO_SIZE: u64 = 4;
+
+/// Write-only device which stops the VM when the guest writes to it. The VMM is expected to exit
+/// with the value written as its exit code, like QEMU's isa-debug-exit but without mangling the
+/// value.
+pub struct DebugExit {
+    base: u64,
+    exit: VmExitRequest,
+}
+
+impl DebugExit {
+    pub fn new(base: u64, exit: VmExitRequest) -> Self {
+        Self { base, exit }
+    }
+}
+
+impl BusDevice for DebugExit {
+    fn debug_label(&self) -> String {
+        "debug exit".to_string()
+    }
+
+    fn write(&mut self, access: BusAccessInfo, data: &[u8]) -> Result<()> {
+        if access.offset != 0 || data.len() > 4 {
+            return Err(anyhow!("Only writes of up to 4 bytes to offset 0 allowed"));
+        }
+        let mut code = [0u8; 4];
+        code[..data.len()].copy_from_slice(data);
+        self.exit.request(VmExit::Exit(i32::from_le_bytes(code)));
+        Ok(())
+    }
+
+    fn device_config(&self, fdt: &mut FdtWriter) -> Result<()> {
+        let node = fdt.begin_node(&format!("debug-exit@{:x}", self.base))?;
+        fdt.property_string("compatible", "gunyah-vmm,debug-exit")?;
+        fdt.property_array_u64("reg", &[self.base, DEBUG_EXIT_MMIO_SIZE])?;
+        fdt.end_node(node)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use claim::{assert_err, assert_ok};
+
+    use super::DebugExit;
+    use crate::{AccessId, BusAccessInfo, BusDevice, VmExit, VmExitRequest};
+
+    fn access(offset: u64) -> BusAccessInfo {
+        BusAccessInfo {
+            offset,
+            address: 0x9100 + offset,
+            id: AccessId::Vcpu(0),
+        }
+    }
+
+    #[test]
+    fn exit_code() {
+        let exit = VmExitRequest::default();
+        let mut dev = DebugExit::new(0x9100, exit.clone());
+        assert_ok!(dev.write(access(0), &[42]));
+        assert_eq!(exit.reason(), Some(VmExit::Exit(42)));
+
+        let exit = VmExitRequest::default();
+        let mut dev = DebugExit::new(0x9100, exit.clone());
+        assert_ok!(dev.write(access(0), &0x102u32.to_le_bytes()));
+        assert_eq!(exit.reason(), Some(VmExit::Exit(0x102)));
+    }
+
+    #[test]
+    fn bad_access() {
+        let exit = VmExitRequest::default();
+        let mut dev = DebugExit::new(0x9100, exit.clone());
+        assert_err!(dev.write(access(2), &[1]));
+        assert_err!(dev.write(access(0), &[0; 8]));
+        assert_err!(dev.read(access(0), &mut [0u8]));
+        assert_eq!(exit.reason(), None);
+    }
+}
@@ -0,0 +1,109 @@
+// Copyright (c) 2024, Qualcomm Innovation Center, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause-Clear
+
+use std::io::Write;
+
+use anyhow::{anyhow, Result};
+use vm_fdt::FdtWriter;
+
+use crate::{BusAccessInfo, BusDevice};
+
+pub const DEBUG_LOG_MMIO_SIZE: u64 = 8;
+
+/// Write-only device which collects bytes written by the guest and prints each complete line to
+/// `out`, prefixed with `prefix`.
+pub struct PrefixedLog<W: Write + Send> {
+    base: u64,
+    prefix: String,
+    line: Vec<u8>,
+    out: W,
+}
+
+impl<W: Write + Send> PrefixedLog<W> {
+    pub fn new(base: u64, prefix: &str, out: W) -> Self {
+        Self {
+            base,
+            prefix: prefix.to_string(),
+            line: Vec::new(),
+            out,
+        }
+    }
+
+    fn flush_line(&mut self) -> Result<()> {
+        self.out.write_all(self.prefix.as_bytes())?;
+        self.out.write_all(&self.line)?;
+        self.out.flush()?;
+        self.line.clear();
+        Ok(())
+    }
+}
+
+impl<W: Write + Send> BusDevice for PrefixedLog<W> {
+    fn debug_label(&self) -> String {
+        format!("debug log {:?}", self.prefix)
+    }
+
+    fn write(&mut self, access: BusAccessInfo, data: &[u8]) -> Result<()> {
+        if access.offset != 0 {
+            return Err(anyhow!("Only writes to offset 0 allowed"));
+        }
+
+        for byte in data {
+            self.line.push(*byte);
+            if *byte == b'\n' {
+                self.flush_line()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn device_config(&self, fdt: &mut FdtWriter) -> Result<()> {
+        let node = fdt.begin_node(&format!("debug-log@{:x}", self.base))?;
+        fdt.property_string("compatible", "gunyah-vmm,debug-log")?;
+        fdt.property_array_u64("reg", &[self.base, DEBUG_LOG_MMIO_SIZE])?;
+        fdt.end_node(node)?;
+        Ok(())
+    }
+}
+
+impl<W: Write + Send> Drop for PrefixedLog<W> {
+    fn drop(&mut self) {
+        if !self.line.is_empty() {
+            self.line.push(b'\n');
+            let _ = self.flush_line();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use claim::{assert_err, assert_ok};
+
+    use super::PrefixedLog;
+    use crate::{AccessId, BusAccessInfo, BusDevice};
+
+    fn access(offset: u64) -> BusAccessInfo {
+        BusAccessInfo {
+            offset,
+            address: 0x9000 + offset,
+            id: AccessId::Vcpu(0),
+        }
+    }
+
+    #[test]
+    fn prefixes_each_line() {
+        let mut log = PrefixedLog::new(0x9000, "[vm0] ", Vec::new());
+        for chunk in [&b"hello"[..], b"\nwor", b"ld\n", b"\n", b"partial"] {
+            assert_ok!(log.write(access(0), chunk));
+        }
+        assert_eq!(log.out, b"[vm0] hello\n[vm0] world\n[vm0] \n");
+        assert_eq!(log.line, b"partial");
+    }
+
+    #[test]
+    fn bad_offset() {
+        let mut log = PrefixedLog::new(0x9000, "[vm0] ", Vec::new());
+        assert_err!(log.write(access(4), b"x"));
+        assert_err!(log.read(access(0), &mut [0u8]));
+    }
+}
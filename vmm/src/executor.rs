@@ -0,0 +1,324 @@
+// Copyright (c) 2024, Qualcomm Innovation Center, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause-Clear
+
+//! Runs the background work of the VMM on one thread instead of a thread per concern: device
+//! timers, ioeventfds, forwarding input and serving the control socket are all [`DeviceTask`]s
+//! multiplexed with `ppoll`. The thread and the tasks left are dropped when the VM exits.
+
+use std::{
+    io,
+    os::fd::{AsRawFd, RawFd},
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
+
+use crate::{SeccompPolicy, SeccompThread, VmExit, VmExitRequest};
+
+/// Longest the executor waits, so it notices new tasks and the VM exiting.
+const MAX_WAIT: Duration = Duration::from_millis(10);
+
+/// When a [`DeviceTask`] wants to be polled again.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TaskPoll {
+    /// Once one of the task's file descriptors is readable, or at the given time at the latest
+    Pending(Option<Instant>),
+    /// The task is finished and dropped
+    Done,
+}
+
+/// Background work of a device, run by a [`DeviceExecutor`]. Tasks must not block: they do
+/// what they can and return when they want to be polled again.
+pub trait DeviceTask: Send {
+    fn debug_label(&self) -> String;
+    /// File descriptors which get the task polled when they become readable. Asked again after
+    /// every poll.
+    fn fds(&self) -> Vec<RawFd> {
+        Vec::new()
+    }
+    fn poll(&mut self, now: Instant) -> Result<TaskPoll>;
+}
+
+struct TaskEntry {
+    task: Box<dyn DeviceTask>,
+    deadline: Option<Instant>,
+}
+
+/// Polls [`DeviceTask`]s on one thread. Clones share the same tasks.
+#[derive(Clone)]
+pub struct DeviceExecutor {
+    /// Tasks spawned since the executor last picked them up
+    spawned: Arc<Mutex<Vec<Box<dyn DeviceTask>>>>,
+    /// Signalled by [`Self::spawn`], so a waiting executor picks new tasks up right away
+    waker: Arc<EventFd>,
+}
+
+impl Default for DeviceExecutor {
+    fn default() -> Self {
+        Self {
+            spawned: Default::default(),
+            waker: Arc::new(EventFd::new(EFD_NONBLOCK).expect("Failed to create eventfd")),
+        }
+    }
+}
+
+impl DeviceExecutor {
+    /// Adds `task`, which is polled right away.
+    pub fn spawn(&self, task: Box<dyn DeviceTask>) {
+        self.spawned.lock().unwrap().push(task);
+        // Only fails if the counter would overflow, in which case the executor wakes anyway
+        let _ = self.waker.write(1);
+    }
+
+    /// Runs the tasks in a new thread until the VM exits. The tasks left are dropped then.
+    pub fn run(&self, exit: VmExitRequest) -> JoinHandle<()> {
+        self.run_with_seccomp(exit, None)
+    }
+
+    /// Like [`Self::run`], but the thread confines itself with `policy` first, see
+    /// [`SeccompThread::Device`]. The VM exits if that fails.
+    pub fn run_confined(&self, exit: VmExitRequest, policy: SeccompPolicy) -> JoinHandle<()> {
+        self.run_with_seccomp(exit, Some(policy))
+    }
+
+    fn run_with_seccomp(
+        &self,
+        exit: VmExitRequest,
+        seccomp: Option<SeccompPolicy>,
+    ) -> JoinHandle<()> {
+        let executor = self.clone();
+        thread::spawn(move || {
+            if let Some(policy) = seccomp {
+                if let Err(e) = SeccompThread::Device.confine(policy) {
+                    println!("{:#}", e);
+                    exit.request(VmExit::Exit(1));
+                    return;
+                }
+            }
+            let mut tasks = Vec::new();
+            while exit.reason().is_none() {
+                executor.turn(&mut tasks, MAX_WAIT);
+            }
+        })
+    }
+
+    /// Waits up to `max_wait` for a task to become ready and polls the ready ones.
+    fn turn(&self, tasks: &mut Vec<TaskEntry>, max_wait: Duration) {
+        let now = Instant::now();
+        tasks.extend(
+            self.spawned
+                .lock()
+                .unwrap()
+                .drain(..)
+                .map(|task| TaskEntry {
+                    task,
+                    deadline: Some(now),
+                }),
+        );
+
+        let wait = tasks
+            .iter()
+            .filter_map(|entry| entry.deadline)
+            .min()
+            .map_or(max_wait, |deadline| {
+                deadline.saturating_duration_since(now).min(max_wait)
+            });
+        let mut pollfds = vec![libc::pollfd {
+            fd: self.waker.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        }];
+        let mut owners = vec![None];
+        for (i, entry) in tasks.iter().enumerate() {
+            for fd in entry.task.fds() {
+                pollfds.push(libc::pollfd {
+                    fd,
+                    events: libc::POLLIN,
+                    revents: 0,
+                });
+                owners.push(Some(i));
+            }
+        }
+        // ppoll, unlike poll, waits less than a millisecond for close deadlines
+        let timeout = libc::timespec {
+            tv_sec: wait.as_secs() as libc::time_t,
+            tv_nsec: wait.subsec_nanos().into(),
+        };
+        // SAFETY: Safe because pollfds is a valid array of pollfd of the given length and
+        // timeout a valid timespec. Errors, like EINTR, leave revents zeroed, so only deadlines
+        // are handled then.
+        unsafe {
+            libc::ppoll(
+                pollfds.as_mut_ptr(),
+                pollfds.len() as libc::nfds_t,
+                &timeout,
+                std::ptr::null(),
+            )
+        };
+
+        let mut ready = vec![false; tasks.len()];
+        for (pollfd, owner) in pollfds.iter().zip(owners) {
+            match owner {
+                Some(owner) if pollfd.revents != 0 => ready[owner] = true,
+                // Tasks spawned meanwhile are picked up next turn
+                None if pollfd.revents != 0 => {
+                    let _ = self.waker.read();
+                }
+                _ => {}
+            }
+        }
+        let now = Instant::now();
+        let mut ready = ready.into_iter();
+        tasks.retain_mut(|entry| {
+            let due = entry.deadline.is_some_and(|deadline| deadline <= now);
+            if !ready.next().unwrap() && !due {
+                return true;
+            }
+            match entry.task.poll(now) {
+                Ok(TaskPoll::Pending(deadline)) => {
+                    entry.deadline = deadline;
+                    true
+                }
+                Ok(TaskPoll::Done) => false,
+                Err(e) => {
+                    println!("{} failed: {:#}", entry.task.debug_label(), e);
+                    false
+                }
+            }
+        });
+    }
+}
+
+/// Makes reads and writes of `fd` return `WouldBlock` instead of blocking the executor.
+pub(crate) fn set_nonblocking(fd: RawFd) -> io::Result<()> {
+    // SAFETY: Safe because only the status flags of fd change, and fcntl fails if it isn't a
+    // file descriptor.
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    // SAFETY: As above.
+    if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::File,
+        io::{Read, Write},
+        os::fd::{AsRawFd, FromRawFd, RawFd},
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
+    };
+
+    use anyhow::{anyhow, Result};
+
+    use super::{DeviceExecutor, DeviceTask, TaskPoll};
+
+    struct PipeReader {
+        pipe: File,
+        received: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl DeviceTask for PipeReader {
+        fn debug_label(&self) -> String {
+            "pipe".to_string()
+        }
+
+        fn fds(&self) -> Vec<RawFd> {
+            vec![self.pipe.as_raw_fd()]
+        }
+
+        fn poll(&mut self, _now: Instant) -> Result<TaskPoll> {
+            let mut buf = [0u8; 16];
+            match self.pipe.read(&mut buf) {
+                Ok(0) => Ok(TaskPoll::Done),
+                Ok(len) => {
+                    self.received.lock().unwrap().extend(&buf[..len]);
+                    Ok(TaskPoll::Pending(None))
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => Ok(TaskPoll::Pending(None)),
+                Err(e) => Err(e.into()),
+            }
+        }
+    }
+
+    struct Countdown(u32);
+
+    impl DeviceTask for Countdown {
+        fn debug_label(&self) -> String {
+            "countdown".to_string()
+        }
+
+        fn poll(&mut self, now: Instant) -> Result<TaskPoll> {
+            match self.0 {
+                0 => Err(anyhow!("Polled after it was done")),
+                1 => Ok(TaskPoll::Done),
+                _ => {
+                    self.0 -= 1;
+                    Ok(TaskPoll::Pending(Some(now)))
+                }
+            }
+        }
+    }
+
+    fn pipe() -> (File, File) {
+        let mut fds = [0; 2];
+        // SAFETY: Safe because fds has room for the two file descriptors.
+        assert_eq!(
+            unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK) },
+            0
+        );
+        // SAFETY: Safe because pipe2 returned two new file descriptors we own.
+        unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) }
+    }
+
+    #[test]
+    fn polls_ready_tasks() {
+        let executor = DeviceExecutor::default();
+        let (reader, mut writer) = pipe();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        executor.spawn(Box::new(PipeReader {
+            pipe: reader,
+            received: received.clone(),
+        }));
+        executor.spawn(Box::new(Countdown(3)));
+
+        let mut tasks = Vec::new();
+        executor.turn(&mut tasks, Duration::ZERO);
+        assert_eq!(tasks.len(), 2);
+        writer.write_all(b"abc").unwrap();
+        executor.turn(&mut tasks, Duration::from_secs(5));
+        assert_eq!(*received.lock().unwrap(), b"abc");
+        // The countdown finished, the reader waits for its pipe
+        executor.turn(&mut tasks, Duration::ZERO);
+        assert_eq!(tasks.len(), 1);
+        assert_eq!(tasks[0].deadline, None);
+
+        drop(writer);
+        executor.turn(&mut tasks, Duration::from_secs(5));
+        assert!(tasks.is_empty());
+    }
+
+    #[test]
+    fn spawn_wakes_executor() {
+        let executor = DeviceExecutor::default();
+        let mut tasks = Vec::new();
+        let spawner = {
+            let executor = executor.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(50));
+                executor.spawn(Box::new(Countdown(2)));
+            })
+        };
+        let start = Instant::now();
+        executor.turn(&mut tasks, Duration::from_secs(5));
+        assert!(start.elapsed() < Duration::from_secs(5));
+        spawner.join().unwrap();
+        executor.turn(&mut tasks, Duration::ZERO);
+        assert_eq!(tasks.len(), 1);
+    }
+}
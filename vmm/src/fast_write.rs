@@ -0,0 +1,157 @@
+// Copyright (c) 2024, Qualcomm Innovation Center, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause-Clear
+
+//! Delivers guest writes to the [`FastWriteRegion`]s of devices, see
+//! [`crate::GunyahVirtualMachine::add_device`].
+//!
+//! Gunyah signals an ioeventfd for each write to a region without exiting to the VMM, so the vCPU
+//! continues right away. A [`FastWriteTask`] waits for the ioeventfd and calls the device later.
+
+use std::{
+    io,
+    os::fd::{AsRawFd, RawFd},
+    sync::{Arc, Mutex, Weak},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+
+use crate::{set_nonblocking, BusDevice, DeviceTask, FastWriteRegion, TaskPoll};
+
+/// How often a task checks whether its device was removed
+const CHECK_INTERVAL: Duration = Duration::from_millis(10);
+
+pub(crate) struct FastWriteTask<E> {
+    device: Arc<Mutex<dyn BusDevice>>,
+    region: FastWriteRegion,
+    /// Owned by the VM, which drops it to end the task when the device is removed
+    eventfd: Weak<E>,
+}
+
+impl<E: AsRawFd + Send + Sync> FastWriteTask<E> {
+    pub(crate) fn new(
+        device: Arc<Mutex<dyn BusDevice>>,
+        region: FastWriteRegion,
+        eventfd: &Arc<E>,
+    ) -> Result<Self> {
+        set_nonblocking(eventfd.as_raw_fd()).context("Failed to make eventfd non-blocking")?;
+        Ok(Self {
+            device,
+            region,
+            eventfd: Arc::downgrade(eventfd),
+        })
+    }
+}
+
+impl<E: AsRawFd + Send + Sync> DeviceTask for FastWriteTask<E> {
+    fn debug_label(&self) -> String {
+        format!(
+            "{} fast write at {:#x}",
+            self.device.lock().unwrap().debug_label(),
+            self.region.offset
+        )
+    }
+
+    fn fds(&self) -> Vec<RawFd> {
+        self.eventfd
+            .upgrade()
+            .map(|eventfd| vec![eventfd.as_raw_fd()])
+            .unwrap_or_default()
+    }
+
+    fn poll(&mut self, now: Instant) -> Result<TaskPoll> {
+        let Some(eventfd) = self.eventfd.upgrade() else {
+            return Ok(TaskPoll::Done);
+        };
+        let mut count = 0u64;
+        // SAFETY: Safe because count has room for the 8 bytes an eventfd read returns.
+        let ret = unsafe {
+            libc::read(
+                eventfd.as_raw_fd(),
+                &mut count as *mut u64 as *mut libc::c_void,
+                std::mem::size_of::<u64>(),
+            )
+        };
+        if ret < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::WouldBlock {
+                return Err(e).context("Failed to read eventfd");
+            }
+        } else {
+            let mut device = self.device.lock().unwrap();
+            // A failed write is the guest's problem, later ones may still succeed
+            if let Err(e) = device.fast_write(self.region) {
+                println!(
+                    "{} fast write at {:#x} failed: {:#}",
+                    device.debug_label(),
+                    self.region.offset,
+                    e
+                );
+            }
+        }
+        Ok(TaskPoll::Pending(Some(now + CHECK_INTERVAL)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::File,
+        io::Write,
+        os::fd::FromRawFd,
+        sync::{Arc, Mutex},
+        time::Instant,
+    };
+
+    use claim::assert_ok;
+
+    use super::FastWriteTask;
+    use crate::{BusDevice, DeviceTask, FastWriteRegion, TaskPoll};
+
+    #[derive(Default)]
+    struct Doorbell {
+        rung: Vec<FastWriteRegion>,
+    }
+
+    impl BusDevice for Doorbell {
+        fn debug_label(&self) -> String {
+            "doorbell".to_string()
+        }
+
+        fn fast_write(&mut self, region: FastWriteRegion) -> anyhow::Result<()> {
+            self.rung.push(region);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn delivers_writes() {
+        // SAFETY: Safe because eventfd returns a new file descriptor we own.
+        let eventfd = Arc::new(unsafe { File::from_raw_fd(libc::eventfd(0, 0)) });
+        let doorbell = Arc::new(Mutex::new(Doorbell::default()));
+        let region = FastWriteRegion {
+            offset: 0x50,
+            len: 4,
+            datamatch: Some(1),
+        };
+        let mut task = assert_ok!(FastWriteTask::new(doorbell.clone(), region, &eventfd));
+        assert_eq!(task.fds().len(), 1);
+
+        // Nothing written yet, the read doesn't block
+        assert!(matches!(
+            assert_ok!(task.poll(Instant::now())),
+            TaskPoll::Pending(Some(_))
+        ));
+        assert!(doorbell.lock().unwrap().rung.is_empty());
+
+        // Writes before the task runs are delivered together
+        assert_ok!((&*eventfd).write_all(&1u64.to_ne_bytes()));
+        assert_ok!((&*eventfd).write_all(&1u64.to_ne_bytes()));
+        assert_ok!(task.poll(Instant::now()));
+        assert_eq!(doorbell.lock().unwrap().rung, [region]);
+
+        drop(eventfd);
+        assert!(task.fds().is_empty());
+        assert_eq!(assert_ok!(task.poll(Instant::now())), TaskPoll::Done);
+    }
+}
@@ -0,0 +1,312 @@
+// Copyright (c) 2024, Qualcomm Innovation Center, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause-Clear
+
+//! Assembly of the device tree handed to the guest and the Resource Manager.
+
+use anyhow::{Context, Result};
+use vm_fdt::FdtWriter;
+
+use crate::{GunyahVirtualMachine, MbiConfig};
+
+/// Phandle of the GIC, the interrupt parent of all devices
+const PHANDLE_GIC: u32 = 1;
+
+const GIC_FDT_IRQ_TYPE_PPI: u32 = 1;
+/// Level triggered, active low, routed to CPU 0
+const TIMER_IRQ_FLAGS: u32 = 0x108;
+/// Level triggered, active high
+const PMU_IRQ_FLAGS: u32 = 0x4;
+
+/// Register regions of the GICv3.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GicConfig {
+    pub dist_base: u64,
+    pub dist_size: u64,
+    pub redist_base: u64,
+    /// Size of the redistributors of all vCPUs
+    pub redist_size: u64,
+}
+
+impl GicConfig {
+    /// Places the redistributors of `vcpus` vCPUs, `redist_size` bytes each, right below the
+    /// distributor.
+    pub fn below_distributor(dist_base: u64, dist_size: u64, redist_size: u64, vcpus: u8) -> Self {
+        let redist_size = redist_size * u64::from(vcpus);
+        Self {
+            dist_base,
+            dist_size,
+            redist_base: dist_base - redist_size,
+            redist_size,
+        }
+    }
+
+    fn generate(&self, fdt: &mut FdtWriter, mbi: Option<&MbiConfig>) -> Result<()> {
+        let intc_node = fdt.begin_node(&format!("interrupt-controller@{:x}", self.dist_base))?;
+        fdt.property_string("compatible", "arm,gic-v3")?;
+        fdt.property_u32("#interrupt-cells", 3)?;
+        fdt.property_u32("#address-cells", 2)?;
+        fdt.property_u32("#size-cells", 2)?;
+        fdt.property_null("interrupt-controller")?;
+        fdt.property_array_u64(
+            "reg",
+            &[
+                self.dist_base,
+                self.dist_size,
+                self.redist_base,
+                self.redist_size,
+            ],
+        )?;
+        fdt.property_u32("phandle", PHANDLE_GIC)?;
+        if let Some(mbi) = mbi {
+            mbi.generate_gic(fdt)?;
+        }
+        fdt.end_node(intc_node)?;
+        Ok(())
+    }
+}
+
+/// PPIs and frequency of the architected timer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimerConfig {
+    pub secure: u32,
+    pub non_secure: u32,
+    pub virt: u32,
+    pub hyp: u32,
+    /// Frequency of the system counter in Hz
+    pub clock_frequency: u32,
+}
+
+impl Default for TimerConfig {
+    fn default() -> Self {
+        Self {
+            secure: 13,
+            non_secure: 14,
+            virt: 11,
+            hyp: 10,
+            clock_frequency: 19_200_000,
+        }
+    }
+}
+
+impl TimerConfig {
+    fn generate(&self, fdt: &mut FdtWriter) -> Result<()> {
+        let timer_node = fdt.begin_node("timer")?;
+        fdt.property_string("compatible", "arm,armv8-timer")?;
+        fdt.property_null("always-on")?;
+        let interrupts: Vec<u32> = [self.secure, self.non_secure, self.virt, self.hyp]
+            .into_iter()
+            .flat_map(|ppi| [GIC_FDT_IRQ_TYPE_PPI, ppi, TIMER_IRQ_FLAGS])
+            .collect();
+        fdt.property_array_u32("interrupts", &interrupts)?;
+        fdt.property_u32("clock-frequency", self.clock_frequency)?;
+        fdt.end_node(timer_node)?;
+        Ok(())
+    }
+}
+
+/// PPI of the PMU, which every vCPU raises when one of its counters overflows.
+///
+/// Gunyah's VM interface has no vCPU option for the PMU: whether the hypervisor lets vCPUs use
+/// the counters is up to the Resource Manager, so the VMM only describes the PMU to the guest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PmuConfig {
+    pub ppi: u32,
+}
+
+impl PmuConfig {
+    fn generate(&self, fdt: &mut FdtWriter) -> Result<()> {
+        let pmu_node = fdt.begin_node("pmu")?;
+        fdt.property_string("compatible", "arm,armv8-pmuv3")?;
+        fdt.property_array_u32(
+            "interrupts",
+            &[GIC_FDT_IRQ_TYPE_PPI, self.ppi, PMU_IRQ_FLAGS],
+        )?;
+        fdt.end_node(pmu_node)?;
+        Ok(())
+    }
+}
+
+/// Contents of the `/chosen` node.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ChosenConfig {
+    pub bootargs: String,
+    /// E.g. `serial0`
+    pub stdout_path: Option<String>,
+    /// Start and end address of the initial ramdisk
+    pub initrd: Option<(u64, u64)>,
+}
+
+impl ChosenConfig {
+    fn generate(&self, fdt: &mut FdtWriter) -> Result<()> {
+        let chosen = fdt.begin_node("chosen")?;
+        if let Some(path) = &self.stdout_path {
+            fdt.property_string("stdout-path", path)?;
+        }
+        fdt.property_string("bootargs", &self.bootargs)?;
+        if let Some((start, end)) = self.initrd {
+            fdt.property_u64("linux,initrd-start", start)?;
+            fdt.property_u64("linux,initrd-end", end)?;
+        }
+        fdt.end_node(chosen)?;
+        Ok(())
+    }
+}
+
+/// Writes the `/reserved-memory` node, with the children written by `nodes`, e.g. with
+/// [`crate::create_fdt_ramoops`].
+pub fn create_fdt_reserved_memory(
+    fdt: &mut FdtWriter,
+    nodes: impl FnOnce(&mut FdtWriter) -> Result<()>,
+) -> Result<()> {
+    let reserved = fdt.begin_node("reserved-memory")?;
+    fdt.property_u32("#address-cells", 2)?;
+    fdt.property_u32("#size-cells", 2)?;
+    fdt.property_null("ranges")?;
+    nodes(fdt)?;
+    fdt.end_node(reserved)?;
+    Ok(())
+}
+
+type FdtNodeFn<'a> = Box<dyn FnOnce(&mut FdtWriter) -> Result<()> + 'a>;
+
+/// Builds the device tree of a VM, see [`GunyahVirtualMachine::fdt_builder`].
+///
+/// The VM describes its memory, vCPUs and devices on the bus; the builder adds the GIC, timer,
+/// PMU and PSCI nodes, any nodes added with [`Self::node`] and the `/chosen` node.
+pub struct FdtBuilder<'a> {
+    vm: &'a GunyahVirtualMachine,
+    gic: GicConfig,
+    timer: TimerConfig,
+    pmu: Option<PmuConfig>,
+    nodes: Vec<FdtNodeFn<'a>>,
+    chosen: Option<ChosenConfig>,
+}
+
+impl<'a> FdtBuilder<'a> {
+    pub(crate) fn new(vm: &'a GunyahVirtualMachine, gic: GicConfig) -> Self {
+        Self {
+            vm,
+            gic,
+            timer: TimerConfig::default(),
+            pmu: None,
+            nodes: Vec::new(),
+            chosen: None,
+        }
+    }
+
+    pub fn timer(mut self, timer: TimerConfig) -> Self {
+        self.timer = timer;
+        self
+    }
+
+    pub fn pmu(mut self, pmu: PmuConfig) -> Self {
+        self.pmu = Some(pmu);
+        self
+    }
+
+    /// Adds nodes or properties at the root with `node`, after the ones describing the VM.
+    pub fn node(mut self, node: impl FnOnce(&mut FdtWriter) -> Result<()> + 'a) -> Self {
+        self.nodes.push(Box::new(node));
+        self
+    }
+
+    pub fn chosen(mut self, chosen: ChosenConfig) -> Self {
+        self.chosen = Some(chosen);
+        self
+    }
+
+    /// Returns the device tree blob.
+    pub fn build(self) -> Result<Vec<u8>> {
+        let mut fdt = FdtWriter::new()?;
+        let root_node = fdt.begin_node("")?;
+        fdt.property_u32("#address-cells", 2)?;
+        fdt.property_u32("#size-cells", 2)?;
+        fdt.property_u32("interrupt-parent", PHANDLE_GIC)?;
+
+        let memory_base = self.vm.generate_fdt_memory(&mut fdt)?;
+        self.vm.generate_fdt_cpus(&mut fdt)?;
+
+        let psci_node = fdt.begin_node("psci")?;
+        fdt.property_string("compatible", "arm,psci-0.2")?;
+        fdt.property_string("method", "hvc")?;
+        fdt.end_node(psci_node)?;
+
+        self.gic.generate(&mut fdt, self.vm.mbi_config())?;
+        self.timer.generate(&mut fdt)?;
+        if let Some(pmu) = &self.pmu {
+            pmu.generate(&mut fdt)?;
+        }
+        self.vm.generate_fdt_devices(&mut fdt)?;
+        self.vm.create_fdt_vm_config(
+            &mut fdt,
+            "linux",
+            memory_base,
+            self.vm.firmware().map(|(addr, _)| addr),
+            PHANDLE_GIC,
+        )?;
+
+        for node in self.nodes {
+            node(&mut fdt)?;
+        }
+        if let Some(chosen) = &self.chosen {
+            chosen.generate(&mut fdt)?;
+        }
+        fdt.end_node(root_node)?;
+        fdt.finish().context("Failed to finalize dtb")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use claim::assert_ok;
+    use vm_fdt::FdtWriter;
+
+    use super::{ChosenConfig, GicConfig, PmuConfig, TimerConfig};
+    use crate::parse_fdt;
+
+    #[test]
+    fn gic_timer_and_chosen() {
+        let gic = GicConfig::below_distributor(0x3fff_0000, 0x1_0000, 0x2_0000, 2);
+        assert_eq!(gic.redist_base, 0x3ffb_0000);
+
+        let mut fdt = assert_ok!(FdtWriter::new());
+        let root = assert_ok!(fdt.begin_node(""));
+        assert_ok!(gic.generate(&mut fdt, None));
+        assert_ok!(TimerConfig {
+            virt: 27,
+            clock_frequency: 24_000_000,
+            ..Default::default()
+        }
+        .generate(&mut fdt));
+        assert_ok!(PmuConfig { ppi: 7 }.generate(&mut fdt));
+        assert_ok!(ChosenConfig {
+            bootargs: "console=ttyS0".to_string(),
+            stdout_path: None,
+            initrd: Some((0x8100_0000, 0x8110_0000)),
+        }
+        .generate(&mut fdt));
+        assert_ok!(fdt.end_node(root));
+        let blob = assert_ok!(fdt.finish());
+        let fdt = assert_ok!(parse_fdt(&blob));
+
+        assert_eq!(
+            fdt.prop_u64_array("/interrupt-controller@3fff0000", "reg"),
+            Some(vec![0x3fff_0000, 0x1_0000, 0x3ffb_0000, 0x4_0000])
+        );
+        assert_eq!(
+            fdt.prop_u32_array("/timer", "interrupts"),
+            Some(vec![1, 13, 0x108, 1, 14, 0x108, 1, 27, 0x108, 1, 10, 0x108])
+        );
+        assert_eq!(fdt.prop_u32("/timer", "clock-frequency"), Some(24_000_000));
+        assert_eq!(fdt.prop_str("/pmu", "compatible"), Some("arm,armv8-pmuv3"));
+        assert_eq!(
+            fdt.prop_u32_array("/pmu", "interrupts"),
+            Some(vec![1, 7, 4])
+        );
+        assert_eq!(
+            fdt.prop_u64("/chosen", "linux,initrd-end"),
+            Some(0x8110_0000)
+        );
+        assert!(!fdt.has_prop("/chosen", "stdout-path"));
+    }
+}
@@ -0,0 +1,223 @@
+// Copyright (c) 2024, Qualcomm Innovation Center, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause-Clear
+
+//! Merging a device tree given by the user into the one generated by the VMM, so nodes for e.g.
+//! out-of-tree drivers can be added without changing the VMM.
+//!
+//! The overlay is either a plain device tree, which is merged into the generated one from the
+//! root on, or a compiled overlay (`/plugin/;`) whose fragments name the node they apply to with
+//! `target-path`. Overlays referring to labels of the generated tree or to their own nodes need
+//! phandle fixups, which aren't supported; use `target-path` and explicit phandles instead.
+
+use anyhow::{anyhow, Context, Result};
+use fdt::{node::FdtNode, Fdt};
+use vm_fdt::{FdtReserveEntry, FdtWriter};
+
+/// Node of a device tree held in memory, so it can be changed before it is written again.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct FdtTree {
+    name: String,
+    properties: Vec<(String, Vec<u8>)>,
+    children: Vec<FdtTree>,
+}
+
+impl FdtTree {
+    fn from_node(node: FdtNode<'_, '_>) -> Self {
+        Self {
+            name: node.name.to_string(),
+            properties: node
+                .properties()
+                .map(|prop| (prop.name.to_string(), prop.value.to_vec()))
+                .collect(),
+            children: node.children().map(Self::from_node).collect(),
+        }
+    }
+
+    fn child(&self, name: &str) -> Option<&Self> {
+        self.children.iter().find(|child| child.name == name)
+    }
+
+    fn property(&self, name: &str) -> Option<&[u8]> {
+        self.properties
+            .iter()
+            .find(|(prop, _)| prop == name)
+            .map(|(_, value)| value.as_slice())
+    }
+
+    /// Returns the node at `path` below this one, e.g. `/soc/serial@1000`.
+    fn find_mut(&mut self, path: &str) -> Option<&mut Self> {
+        path.split('/')
+            .filter(|name| !name.is_empty())
+            .try_fold(self, |node, name| {
+                node.children.iter_mut().find(|child| child.name == name)
+            })
+    }
+
+    /// Adds the properties and children of `other`, replacing properties of the same name and
+    /// merging children of the same name.
+    fn merge(&mut self, other: &FdtTree) {
+        for (name, value) in &other.properties {
+            match self.properties.iter_mut().find(|(prop, _)| prop == name) {
+                Some((_, old)) => old.clone_from(value),
+                None => self.properties.push((name.clone(), value.clone())),
+            }
+        }
+        for child in &other.children {
+            match self.children.iter_mut().find(|old| old.name == child.name) {
+                Some(old) => old.merge(child),
+                None => self.children.push(child.clone()),
+            }
+        }
+    }
+
+    fn write(&self, fdt: &mut FdtWriter, name: &str) -> Result<(), vm_fdt::Error> {
+        let node = fdt.begin_node(name)?;
+        for (name, value) in &self.properties {
+            fdt.property(name, value)?;
+        }
+        for child in &self.children {
+            child.write(fdt, &child.name)?;
+        }
+        fdt.end_node(node)
+    }
+}
+
+/// Returns `base` with the nodes and properties of `overlay` merged into it, see the module
+/// documentation for the kinds of overlays supported.
+pub fn merge_fdt(base: &[u8], overlay: &[u8]) -> Result<Vec<u8>> {
+    let base = Fdt::new(base).map_err(|e| anyhow!("Failed to parse FDT: {}", e))?;
+    let overlay = Fdt::new(overlay).map_err(|e| anyhow!("Failed to parse overlay: {}", e))?;
+    let root = |fdt: &Fdt<'_>| fdt.find_node("/").map(FdtTree::from_node);
+    let mut tree = root(&base).context("FDT has no root node")?;
+    let mut overlay = root(&overlay).context("Overlay has no root node")?;
+    // Labels of the overlay's nodes, which mean nothing in the merged tree
+    overlay.children.retain(|child| child.name != "__symbols__");
+
+    for fixups in ["__fixups__", "__local_fixups__"] {
+        if overlay.child(fixups).is_some() {
+            return Err(anyhow!(
+                "Overlay needs phandle fixups ({}), which aren't supported",
+                fixups
+            ));
+        }
+    }
+    let fragments: Vec<&FdtTree> = overlay
+        .children
+        .iter()
+        .filter(|child| child.child("__overlay__").is_some())
+        .collect();
+    if fragments.is_empty() {
+        tree.merge(&overlay);
+    }
+    for fragment in fragments {
+        let path = fragment
+            .property("target-path")
+            .and_then(|value| std::str::from_utf8(value).ok())
+            .map(|path| path.trim_end_matches('\0'))
+            .context(format!("Overlay {} has no target-path", fragment.name))?;
+        let target = tree
+            .find_mut(path)
+            .context(format!("Overlay target {} doesn't exist", path))?;
+        target.merge(fragment.child("__overlay__").unwrap());
+    }
+
+    let reservations: Vec<FdtReserveEntry> = base
+        .memory_reservations()
+        .map(|entry| FdtReserveEntry::new(entry.address() as u64, entry.size() as u64))
+        .collect::<Result<_, _>>()?;
+    let mut fdt = FdtWriter::new_with_mem_reserv(&reservations)?;
+    tree.write(&mut fdt, "")?;
+    Ok(fdt.finish()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use claim::{assert_err, assert_ok};
+    use vm_fdt::FdtWriter;
+
+    use super::merge_fdt;
+    use crate::parse_fdt;
+
+    fn base() -> Vec<u8> {
+        let mut fdt = assert_ok!(FdtWriter::new());
+        let root = assert_ok!(fdt.begin_node(""));
+        assert_ok!(fdt.property_u32("#address-cells", 2));
+        let serial = assert_ok!(fdt.begin_node("serial@1000"));
+        assert_ok!(fdt.property_string("compatible", "ns16550a"));
+        assert_ok!(fdt.end_node(serial));
+        let chosen = assert_ok!(fdt.begin_node("chosen"));
+        assert_ok!(fdt.property_string("bootargs", "console=ttyS0"));
+        assert_ok!(fdt.end_node(chosen));
+        assert_ok!(fdt.end_node(root));
+        assert_ok!(fdt.finish())
+    }
+
+    #[test]
+    fn plain() {
+        let mut fdt = assert_ok!(FdtWriter::new());
+        let root = assert_ok!(fdt.begin_node(""));
+        let chosen = assert_ok!(fdt.begin_node("chosen"));
+        assert_ok!(fdt.property_string("bootargs", "quiet"));
+        assert_ok!(fdt.end_node(chosen));
+        let dev = assert_ok!(fdt.begin_node("test-device@2000"));
+        assert_ok!(fdt.property_string("compatible", "vendor,test"));
+        assert_ok!(fdt.end_node(dev));
+        assert_ok!(fdt.end_node(root));
+        let overlay = assert_ok!(fdt.finish());
+
+        let merged = assert_ok!(merge_fdt(&base(), &overlay));
+        let fdt = assert_ok!(parse_fdt(&merged));
+        assert_eq!(fdt.prop_str("/chosen", "bootargs"), Some("quiet"));
+        assert_eq!(
+            fdt.prop_str("/test-device@2000", "compatible"),
+            Some("vendor,test")
+        );
+        assert_eq!(fdt.prop_str("/serial@1000", "compatible"), Some("ns16550a"));
+        assert_eq!(fdt.prop_u32("/", "#address-cells"), Some(2));
+    }
+
+    #[test]
+    fn fragments() {
+        // FdtWriter refuses names starting with an underscore, so the nodes are renamed in the
+        // blob
+        let rename = |blob: &mut Vec<u8>, from: &str, to: &str| {
+            let start = blob
+                .windows(from.len())
+                .position(|window| window == from.as_bytes())
+                .unwrap();
+            blob[start..start + to.len()].copy_from_slice(to.as_bytes());
+        };
+        let overlay = |target: Option<&str>, fixups: bool| {
+            let mut fdt = assert_ok!(FdtWriter::new());
+            let root = assert_ok!(fdt.begin_node(""));
+            let fragment = assert_ok!(fdt.begin_node("fragment@0"));
+            if let Some(target) = target {
+                assert_ok!(fdt.property_string("target-path", target));
+            }
+            let node = assert_ok!(fdt.begin_node("xxoverlayxx"));
+            assert_ok!(fdt.property_string("status", "disabled"));
+            assert_ok!(fdt.end_node(node));
+            assert_ok!(fdt.end_node(fragment));
+            if fixups {
+                let node = assert_ok!(fdt.begin_node("xxfixupsxx"));
+                assert_ok!(fdt.end_node(node));
+            }
+            assert_ok!(fdt.end_node(root));
+            let mut blob = assert_ok!(fdt.finish());
+            rename(&mut blob, "xxoverlayxx", "__overlay__");
+            if fixups {
+                rename(&mut blob, "xxfixupsxx", "__fixups__");
+            }
+            blob
+        };
+
+        let merged = assert_ok!(merge_fdt(&base(), &overlay(Some("/serial@1000"), false)));
+        let fdt = assert_ok!(parse_fdt(&merged));
+        assert_eq!(fdt.prop_str("/serial@1000", "status"), Some("disabled"));
+        assert!(fdt.node("/fragment@0").is_none());
+
+        assert_err!(merge_fdt(&base(), &overlay(None, false)));
+        assert_err!(merge_fdt(&base(), &overlay(Some("/missing"), false)));
+        assert_err!(merge_fdt(&base(), &overlay(Some("/serial@1000"), true)));
+    }
+}
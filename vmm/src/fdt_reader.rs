@@ -0,0 +1,76 @@
+// Copyright (c) 2024, Qualcomm Innovation Center, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause-Clear
+
+use anyhow::{anyhow, Result};
+use fdt::{node::FdtNode, Fdt};
+
+/// Read-only view of a flattened device tree, e.g. one produced by `FdtWriter`, with lookups by
+/// node path.
+pub struct ParsedFdt<'a> {
+    fdt: Fdt<'a>,
+}
+
+/// Parses a device tree blob.
+pub fn parse_fdt(blob: &[u8]) -> Result<ParsedFdt<'_>> {
+    Ok(ParsedFdt {
+        fdt: Fdt::new(blob).map_err(|e| anyhow!("Failed to parse FDT: {}", e))?,
+    })
+}
+
+impl<'a> ParsedFdt<'a> {
+    pub fn fdt(&self) -> &Fdt<'a> {
+        &self.fdt
+    }
+
+    /// Returns the node at `path`, e.g. `/chosen` or `/serial@3f800`.
+    pub fn node(&self, path: &str) -> Option<FdtNode<'_, 'a>> {
+        self.fdt.find_node(path)
+    }
+
+    /// Returns the raw value of property `name` of the node at `path`.
+    pub fn prop(&self, path: &str, name: &str) -> Option<&'a [u8]> {
+        self.node(path)?.property(name).map(|p| p.value)
+    }
+
+    pub fn has_prop(&self, path: &str, name: &str) -> bool {
+        self.prop(path, name).is_some()
+    }
+
+    pub fn prop_u32(&self, path: &str, name: &str) -> Option<u32> {
+        Some(u32::from_be_bytes(self.prop(path, name)?.try_into().ok()?))
+    }
+
+    pub fn prop_u64(&self, path: &str, name: &str) -> Option<u64> {
+        Some(u64::from_be_bytes(self.prop(path, name)?.try_into().ok()?))
+    }
+
+    pub fn prop_str(&self, path: &str, name: &str) -> Option<&'a str> {
+        self.node(path)?.property(name)?.as_str()
+    }
+
+    pub fn prop_u32_array(&self, path: &str, name: &str) -> Option<Vec<u32>> {
+        let value = self.prop(path, name)?;
+        if value.len() % 4 != 0 {
+            return None;
+        }
+        Some(
+            value
+                .chunks_exact(4)
+                .map(|c| u32::from_be_bytes(c.try_into().unwrap()))
+                .collect(),
+        )
+    }
+
+    pub fn prop_u64_array(&self, path: &str, name: &str) -> Option<Vec<u64>> {
+        let value = self.prop(path, name)?;
+        if value.len() % 8 != 0 {
+            return None;
+        }
+        Some(
+            value
+                .chunks_exact(8)
+                .map(|c| u64::from_be_bytes(c.try_into().unwrap()))
+                .collect(),
+        )
+    }
+}
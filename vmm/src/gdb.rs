@@ -0,0 +1,391 @@
+// Copyright (c) 2024, Qualcomm Innovation Center, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause-Clear
+
+//! Minimal GDB remote serial protocol server.
+//!
+//! Memory is read and written through the VM's bus, so anything the VMM can reach is visible,
+//! including device registers. Gunyah doesn't let the host read the registers of a running vCPU
+//! or single-step it, so registers are reported as unavailable and step requests fail. The VM is
+//! paused while GDB considers it stopped, i.e. from attaching or interrupting until continuing.
+//! Continuing runs until the VM exits or GDB interrupts. Register access and single-stepping are left until
+//! the Gunyah driver has vCPU register ioctls for the `gunyah` crate to wrap.
+
+use std::{
+    io::{self, BufReader, BufWriter, Read, Write},
+    net::{TcpListener, TcpStream},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::{AccessId, Bus, GunyahVirtualMachine, VmExit, VmExitRequest};
+
+/// How often the server checks whether the VM exited while waiting for a connection or for the
+/// guest to stop.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// Largest packet accepted from GDB
+const PACKET_SIZE: usize = 0x1000;
+/// Size of the aarch64 general purpose registers in a `g` packet: x0-x30, sp and pc followed by
+/// the 32 bit cpsr.
+const G_PACKET_SIZE: usize = 33 * 8 + 4;
+const CPSR_REGNUM: usize = 33;
+
+/// Signal numbers in stop replies
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+/// Error replies
+const EFAULT: &str = "E0e";
+const EINVAL: &str = "E16";
+
+/// What a connection should do after a packet was handled.
+#[derive(Debug, PartialEq, Eq)]
+enum Action {
+    Reply(String),
+    /// Run until the guest stops, then reply with the stop reason
+    Continue,
+    /// Reply and close the connection
+    Close(String),
+}
+
+/// Debugs a VM over the GDB remote serial protocol. One GDB connection is served at a time.
+pub struct GdbServer {
+    bus: Bus,
+    exit: VmExitRequest,
+}
+
+impl GdbServer {
+    pub fn new(vm: &GunyahVirtualMachine) -> Self {
+        Self {
+            bus: vm.get_bus(AccessId::VmmUserspace),
+            exit: vm.exit_request(),
+        }
+    }
+
+    /// Serves GDB connections on `port` until the VM exits.
+    pub fn listen(self, port: u16) -> Result<JoinHandle<()>> {
+        let listener = TcpListener::bind(("localhost", port))
+            .context(format!("Failed to listen for GDB on port {}", port))?;
+        listener.set_nonblocking(true)?;
+        Ok(thread::spawn(move || {
+            while self.exit.reason().is_none() {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        if let Err(e) = self.serve(stream) {
+                            println!("GDB connection failed: {:?}", e);
+                        }
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(POLL_INTERVAL),
+                    Err(e) => {
+                        println!("Failed to accept GDB connection: {:?}", e);
+                        break;
+                    }
+                }
+            }
+        }))
+    }
+
+    fn serve(&self, stream: TcpStream) -> Result<()> {
+        stream.set_nonblocking(false)?;
+        stream.set_nodelay(true)?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = BufWriter::new(stream.try_clone()?);
+        let result = self.serve_packets(&stream, &mut reader, &mut writer);
+        // Don't leave the VM stopped for a GDB which is gone
+        self.exit.resume();
+        result
+    }
+
+    fn serve_packets(
+        &self,
+        stream: &TcpStream,
+        reader: &mut BufReader<TcpStream>,
+        writer: &mut BufWriter<TcpStream>,
+    ) -> Result<()> {
+        while let Some(packet) = read_packet(reader, writer)? {
+            match self.handle(&packet) {
+                Action::Reply(reply) => write_packet(writer, &reply)?,
+                Action::Continue => {
+                    let reply = self.wait_for_stop(stream)?;
+                    write_packet(writer, &reply)?;
+                }
+                Action::Close(reply) => {
+                    write_packet(writer, &reply)?;
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Waits until the VM exits or GDB sends an interrupt, which pauses the VM, and returns the
+    /// stop reply.
+    fn wait_for_stop(&self, mut stream: &TcpStream) -> Result<String> {
+        stream.set_read_timeout(Some(POLL_INTERVAL))?;
+        let result = loop {
+            if let Some(reason) = self.exit.reason() {
+                break Ok(exit_reply(reason));
+            }
+            let mut byte = [0u8];
+            match stream.read(&mut byte) {
+                Ok(0) => break Err(anyhow!("GDB disconnected")),
+                Ok(_) if byte[0] == 0x03 => {
+                    self.exit.pause();
+                    break Ok(format!("S{:02x}", SIGINT));
+                }
+                Ok(_) => {}
+                Err(e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut => {}
+                Err(e) => break Err(e.into()),
+            }
+        };
+        stream.set_read_timeout(None)?;
+        result
+    }
+
+    fn handle(&self, packet: &str) -> Action {
+        let Some(command) = packet.chars().next() else {
+            return Action::Reply(String::new());
+        };
+        let args = &packet[command.len_utf8()..];
+        let reply = match command {
+            // GDB asks on attaching and then considers the VM stopped
+            '?' => match self.exit.reason() {
+                Some(reason) => exit_reply(reason),
+                None => {
+                    self.exit.pause();
+                    format!("S{:02x}", SIGTRAP)
+                }
+            },
+            'g' => "xx".repeat(G_PACKET_SIZE),
+            'p' => match usize::from_str_radix(args, 16) {
+                Ok(CPSR_REGNUM) => "xx".repeat(4),
+                Ok(n) if n < CPSR_REGNUM => "xx".repeat(8),
+                _ => EINVAL.to_string(),
+            },
+            'G' | 'P' | 's' | 'S' => EINVAL.to_string(),
+            'm' => self
+                .read_memory(args)
+                .unwrap_or_else(|_| EFAULT.to_string()),
+            'M' => self
+                .write_memory(args)
+                .map_or_else(|_| EFAULT.to_string(), |_| "OK".to_string()),
+            'c' | 'C' => {
+                self.exit.resume();
+                return Action::Continue;
+            }
+            'H' => "OK".to_string(),
+            'D' => {
+                self.exit.resume();
+                return Action::Close("OK".to_string());
+            }
+            'k' => {
+                self.exit.request(VmExit::Poweroff);
+                return Action::Close("OK".to_string());
+            }
+            'q' if args.starts_with("Supported") => format!("PacketSize={:x}", PACKET_SIZE),
+            'q' if args == "Attached" => "1".to_string(),
+            _ => String::new(),
+        };
+        Action::Reply(reply)
+    }
+
+    /// Handles `ADDR,LENGTH`
+    fn read_memory(&self, args: &str) -> Result<String> {
+        let (addr, len) = parse_addr_len(args)?;
+        if len > PACKET_SIZE / 2 {
+            return Err(anyhow!("Read of {} bytes is too large", len));
+        }
+        let mut data = vec![0u8; len];
+        self.bus.read(addr, &mut data)?;
+        Ok(encode_hex(&data))
+    }
+
+    /// Handles `ADDR,LENGTH:XX...`
+    fn write_memory(&self, args: &str) -> Result<()> {
+        let (range, data) = args.split_once(':').ok_or(anyhow!("Missing data"))?;
+        let (addr, len) = parse_addr_len(range)?;
+        let data = decode_hex(data)?;
+        if data.len() != len {
+            return Err(anyhow!("Expected {} bytes, got {}", len, data.len()));
+        }
+        if !data.is_empty() {
+            self.bus.write(addr, &data)?;
+        }
+        Ok(())
+    }
+}
+
+fn exit_reply(reason: VmExit) -> String {
+    match reason {
+        VmExit::Poweroff | VmExit::Reset => "W00".to_string(),
+        VmExit::Exit(code) => format!("W{:02x}", code as u8),
+        VmExit::Crash => format!("X{:02x}", SIGSEGV),
+    }
+}
+
+fn parse_addr_len(args: &str) -> Result<(u64, usize)> {
+    let (addr, len) = args
+        .split_once(',')
+        .ok_or(anyhow!("Expected ADDR,LENGTH"))?;
+    Ok((
+        u64::from_str_radix(addr, 16)?,
+        usize::from_str_radix(len, 16)?,
+    ))
+}
+
+fn encode_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_hex(hex: &str) -> Result<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return Err(anyhow!("Odd number of hex digits"));
+    }
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| Ok(u8::from_str_radix(std::str::from_utf8(pair)?, 16)?))
+        .collect()
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+}
+
+/// Reads the next packet, acknowledging it. Returns None when GDB disconnected.
+fn read_packet<R: Read, W: Write>(reader: &mut R, writer: &mut W) -> Result<Option<String>> {
+    let mut byte = [0u8];
+    loop {
+        // Skip acks and interrupts outside of a continue until the start of a packet
+        loop {
+            if reader.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            if byte[0] == b'$' {
+                break;
+            }
+        }
+        let mut data = Vec::new();
+        loop {
+            if reader.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            if byte[0] == b'#' {
+                break;
+            }
+            if data.len() == PACKET_SIZE {
+                return Err(anyhow!("GDB packet too large"));
+            }
+            data.push(byte[0]);
+        }
+        let mut sum = [0u8; 2];
+        reader.read_exact(&mut sum)?;
+        let expected = u8::from_str_radix(std::str::from_utf8(&sum)?, 16)?;
+        if checksum(&data) != expected {
+            writer.write_all(b"-")?;
+            writer.flush()?;
+            continue;
+        }
+        writer.write_all(b"+")?;
+        writer.flush()?;
+        return Ok(Some(String::from_utf8(data)?));
+    }
+}
+
+fn write_packet<W: Write>(writer: &mut W, data: &str) -> Result<()> {
+    write!(writer, "${}#{:02x}", data, checksum(data.as_bytes()))?;
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use claim::{assert_none, assert_ok, assert_some};
+
+    use super::{read_packet, write_packet, Action, GdbServer};
+    use crate::{test_util::Ram, Bus, VmExit, VmExitRequest};
+
+    fn server() -> GdbServer {
+        let bus = Bus::new();
+        assert_ok!(bus.insert(
+            Arc::new(Mutex::new(Ram((0..16).collect()))),
+            0x8000_0000,
+            16
+        ));
+        GdbServer {
+            bus,
+            exit: VmExitRequest::default(),
+        }
+    }
+
+    fn reply(server: &GdbServer, packet: &str) -> String {
+        match server.handle(packet) {
+            Action::Reply(reply) => reply,
+            action => panic!("Unexpected {:?} for {}", action, packet),
+        }
+    }
+
+    #[test]
+    fn framing() {
+        let mut input: &[u8] = b"+$m80000000,4#55$?#00$qAttached#8f";
+        let mut acks = Vec::new();
+        assert_eq!(
+            assert_some!(assert_ok!(read_packet(&mut input, &mut acks))),
+            "m80000000,4"
+        );
+        // The bad checksum is rejected
+        assert_eq!(
+            assert_some!(assert_ok!(read_packet(&mut input, &mut acks))),
+            "qAttached"
+        );
+        assert_eq!(acks, b"+-+");
+        assert_none!(assert_ok!(read_packet(&mut input, &mut acks)));
+
+        let mut out = Vec::new();
+        assert_ok!(write_packet(&mut out, "OK"));
+        assert_eq!(out, b"$OK#9a");
+    }
+
+    #[test]
+    fn memory() {
+        let server = server();
+        assert_eq!(reply(&server, "m80000002,3"), "020304");
+        assert_eq!(reply(&server, "M80000002,2:aabb"), "OK");
+        assert_eq!(reply(&server, "m80000001,4"), "01aabb04");
+        assert_eq!(reply(&server, "m1000,4"), "E0e");
+        assert_eq!(reply(&server, "M80000002,2:aa"), "E0e");
+        assert_eq!(reply(&server, "M80000002,2:a\u{e9}a"), "E0e");
+    }
+
+    #[test]
+    fn registers_unavailable() {
+        let server = server();
+        assert_eq!(reply(&server, "g"), "x".repeat(536));
+        assert_eq!(reply(&server, "p20"), "x".repeat(16));
+        assert_eq!(reply(&server, "p21"), "x".repeat(8));
+        assert_eq!(reply(&server, "p22"), "E16");
+        assert_eq!(reply(&server, "s"), "E16");
+        assert_eq!(reply(&server, "vMustReplyEmpty"), "");
+    }
+
+    #[test]
+    fn control() {
+        let server = server();
+        // Attaching stops the VM until GDB continues it
+        assert_eq!(reply(&server, "?"), "S05");
+        assert!(server.exit.is_paused());
+        assert_eq!(server.handle("c"), Action::Continue);
+        assert!(!server.exit.is_paused());
+        assert_eq!(reply(&server, "?"), "S05");
+        assert_eq!(server.handle("D"), Action::Close("OK".to_string()));
+        assert!(!server.exit.is_paused());
+        assert_eq!(server.exit.reason(), None);
+        assert_eq!(server.handle("k"), Action::Close("OK".to_string()));
+        assert_eq!(server.exit.reason(), Some(VmExit::Poweroff));
+    }
+}
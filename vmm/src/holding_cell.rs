@@ -0,0 +1,623 @@
+// Copyright (c) 2024, Qualcomm Innovation Center, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause-Clear
+
+//! Support for driving bare-metal payloads which speak the holding cell protocol.
+//!
+//! The payload reads a command word from [`COMMAND_ADDR`], followed by one word per argument,
+//! and writes the result back to [`COMMAND_ADDR`]. Synchronous exceptions are reported by writing
+//! ESR and then FAR to [`EXCEPTION_ADDR`].
+
+use std::fs;
+use std::str::FromStr;
+use std::{
+    num::NonZeroUsize,
+    sync::{Arc, OnceLock},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use gunyah::{GuestMemoryAccess, ShareType, VcpuExit};
+use pow2::Pow2;
+
+use crate::{GicConfig, GunyahVcpu, GunyahVirtualMachine, TimerConfig, VmState};
+
+macro_rules! kib {
+    ($x:expr) => {
+        $x * 1024
+    };
+}
+
+macro_rules! gunyah_hvc {
+    ($x:expr) => {
+        ((1 << 31) | (1 << 30) | ((6 & 0x3f) << 24) | ($x & 0xffff))
+    };
+}
+
+/// The holding cell payload shipped with this crate. It is linked to run at 0x8000_0000.
+pub const HOLDING_CELL_BIN: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/holding-cell.bin"));
+
+/// MMIO address the payload reads commands and arguments from and writes results to.
+pub const COMMAND_ADDR: u64 = 0x6000;
+/// MMIO address the payload writes ESR and FAR to when it takes a synchronous exception.
+pub const EXCEPTION_ADDR: u64 = 0x7000;
+
+/// Address of the GIC distributor of holding cell VMs.
+pub const HOLDING_CELL_GICD_ADDR: u64 = 0x3FFF0000;
+
+/// Offset of the boot flags in [`HOLDING_CELL_BIN`], right after the branch to its entry point.
+const BOOT_FLAGS_OFFSET: u64 = 8;
+/// Boot flag of [`HOLDING_CELL_BIN`] to set up stage 1 page tables and enable the caches.
+const BOOT_FLAG_MMU: u64 = 1 << 0;
+
+/// Number of SGIs, GIC interrupt IDs 0 to 15.
+const GIC_NR_SGIS: u8 = 16;
+/// GIC interrupt ID of PPI 0.
+const GIC_PPI_BASE: u32 = 16;
+/// GIC interrupt ID of SPI 0.
+const GIC_SPI_BASE: u32 = 32;
+
+/// Value [`HoldingCell::wait_timer`]'s command returns when the timer fired before its deadline.
+const TIMER_EARLY: u64 = u64::MAX;
+
+fn holding_cell_gic(num_cells: u8) -> GicConfig {
+    GicConfig::below_distributor(HOLDING_CELL_GICD_ADDR, 0x10000, 0x20000, num_cells)
+}
+
+pub fn generate_holding_cell_fdt(vm: &GunyahVirtualMachine, num_cells: u8) -> Result<Vec<u8>> {
+    vm.fdt_builder(holding_cell_gic(num_cells))
+        .timer(TimerConfig::default())
+        .build()
+}
+
+/// Encodes a command word: command in bits [7:0], number of arguments in bits [11:8] and whether
+/// to hold the result in bit 16.
+fn command_word(command: u8, nargs: usize, hold: bool) -> Result<[u8; 8]> {
+    if nargs > 0xf {
+        bail!("Too many arguments for a holding cell command: {}", nargs);
+    }
+    Ok((u64::from(command) | (nargs as u64) << 8 | u64::from(hold) << 16).to_le_bytes())
+}
+
+#[derive(PartialEq, Eq)]
+pub enum FlushType {
+    FlushEvery,
+    FlushAfter,
+    FlushOnLast,
+    NoFlush,
+}
+
+/// Data cache maintenance by VA to the point of coherency, see [`HoldingCell::dcache_maintain`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheMaintenance {
+    /// Write dirty lines back to memory (`dc cvac`)
+    Clean,
+    /// Drop lines, so later reads come from memory (`dc ivac`). The hypervisor may clean them
+    /// first.
+    Invalidate,
+    /// Write dirty lines back to memory, then drop them (`dc civac`)
+    CleanInvalidate,
+}
+
+impl CacheMaintenance {
+    fn command(&self) -> u8 {
+        match self {
+            Self::Clean => 11,
+            Self::Invalidate => 12,
+            Self::CleanInvalidate => 13,
+        }
+    }
+}
+
+/// Architected timers of the holding cell, see [`HoldingCell::wait_timer`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArchTimer {
+    /// The EL1 virtual timer
+    Virtual,
+    /// The EL1 physical timer
+    Physical,
+}
+
+impl ArchTimer {
+    /// The PPI `config` describes for the timer.
+    pub fn ppi(&self, config: &TimerConfig) -> u32 {
+        match self {
+            Self::Virtual => config.virt,
+            Self::Physical => config.non_secure,
+        }
+    }
+}
+
+fn page_size(huge: bool) -> Pow2 {
+    static PAGE_SIZE_ONCE: OnceLock<usize> = OnceLock::new();
+    Pow2::try_from(if huge {
+        *PAGE_SIZE_ONCE.get_or_init(|| {
+            usize::from_str(
+                fs::read_to_string("/sys/kernel/mm/transparent_hugepage/hpage_pmd_size")
+                    .unwrap()
+                    .trim(),
+            )
+            .context("Failed to parse hpage_pmd_size")
+            .unwrap()
+        })
+    } else {
+        page_size::get()
+    })
+    .expect("Page size not a power of 2?")
+}
+
+/// Creates a VM preconfigured for a holding cell payload.
+///
+/// Memory map, starting at the payload's base address with all entries page-aligned:
+/// [payload][dtb][cpu0 stack][cpuN stack...]
+/// Each stack is 1 page (4kb).
+pub struct HoldingCellBuilder<'a> {
+    payload: &'a [u8],
+    base: u64,
+    num_cells: u8,
+    huge_pages: bool,
+    mmu: bool,
+}
+
+impl Default for HoldingCellBuilder<'_> {
+    fn default() -> Self {
+        Self {
+            payload: HOLDING_CELL_BIN,
+            base: 0x8000_0000,
+            num_cells: 1,
+            huge_pages: false,
+            mmu: true,
+        }
+    }
+}
+
+impl<'a> HoldingCellBuilder<'a> {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Payload binary, linked to run at the base address (default: [`HOLDING_CELL_BIN`]).
+    pub fn payload(mut self, payload: &'a [u8]) -> Self {
+        self.payload = payload;
+        self
+    }
+
+    /// Address the payload is loaded at and boots from (default: 0x8000_0000).
+    pub fn base(mut self, base: u64) -> Self {
+        self.base = base;
+        self
+    }
+
+    /// Number of vCPUs (default: 1).
+    pub fn num_cells(mut self, num_cells: u8) -> Self {
+        self.num_cells = num_cells;
+        self
+    }
+
+    /// Back the payload's memory with huge pages (default: false).
+    pub fn huge_pages(mut self, huge_pages: bool) -> Self {
+        self.huge_pages = huge_pages;
+        self
+    }
+
+    /// Run the payload with identity-mapped stage 1 page tables, so its memory is normal
+    /// cacheable memory, and the caches enabled (default: true). Without, every access goes to
+    /// memory. Only understood by [`HOLDING_CELL_BIN`], other payloads ignore it.
+    pub fn mmu(mut self, mmu: bool) -> Self {
+        self.mmu = mmu;
+        self
+    }
+
+    pub fn build(self) -> Result<HoldingCell> {
+        let rounded_size = page_size(false)
+            .align_up(self.payload.len())
+            .context("payload too big")?;
+        let dtb_start = self.base + rounded_size as u64;
+
+        // Memory for the payload + 1 page for DTB + 1 page for each cpu's stack
+        let mem_size = rounded_size + (usize::from(1 + self.num_cells) * page_size(false));
+        let mem_size = page_size(self.huge_pages)
+            .align_up(mem_size)
+            .context("memory size too big")?;
+        let mem_size = NonZeroUsize::new(mem_size).unwrap();
+
+        let mut vm =
+            GunyahVirtualMachine::new().context("Failed to create Gunyah Virtual machine")?;
+        vm.add_memory(
+            self.base,
+            mem_size,
+            ShareType::Lend,
+            GuestMemoryAccess::Rwx,
+            self.huge_pages,
+        )
+        .context("Failed to add memory to the vm")?;
+        let mut vcpus = Vec::new();
+        for id in 0..self.num_cells {
+            vcpus.push(vm.create_vcpu(id).context("Failed to create vcpu")?);
+        }
+
+        let dtb = generate_holding_cell_fdt(&vm, self.num_cells)
+            .context("Failed to generate holding cell DT")?;
+        vm.set_dtb_config(
+            dtb_start,
+            page_size(false)
+                .align_up(dtb.len())
+                .context("dtb too big")? as u64,
+            &dtb,
+        )
+        .context("Failed to set dtb configuration")?;
+
+        vm.write_slice(self.base, self.payload)
+            .context("Failed to copy payload to VM's memory")?;
+        if !self.mmu && self.payload == HOLDING_CELL_BIN {
+            let flags = u64::from_le_bytes(
+                HOLDING_CELL_BIN[BOOT_FLAGS_OFFSET as usize..][..8]
+                    .try_into()
+                    .unwrap(),
+            );
+            vm.write_slice(
+                self.base + BOOT_FLAGS_OFFSET,
+                &(flags & !BOOT_FLAG_MMU).to_le_bytes(),
+            )
+            .context("Failed to set the payload's boot flags")?;
+        }
+        vm.set_boot_pc(self.base).context("Failed to set boot pc")?;
+        vm.set_boot_sp(dtb_start + kib!(8))
+            .context("Failed to set boot sp")?;
+
+        Ok(HoldingCell {
+            vm,
+            vcpus,
+            base: self.base,
+        })
+    }
+}
+
+pub struct HoldingCell {
+    pub vm: GunyahVirtualMachine,
+    pub vcpus: Vec<Arc<GunyahVcpu>>,
+    base: u64,
+}
+
+impl HoldingCell {
+    pub fn builder<'a>() -> HoldingCellBuilder<'a> {
+        HoldingCellBuilder::new()
+    }
+
+    fn test_errors(vcpu: &GunyahVcpu) -> Result<()> {
+        if let VcpuExit::Mmio {
+            addr: EXCEPTION_ADDR,
+            data,
+            ..
+        } = vcpu.exit()
+        {
+            let esr = u64::from_le_bytes(data);
+            let result = vcpu
+                .run_once()
+                .context(format!("Failed to read FAR after getting ESR={:x}", esr))?;
+            let VcpuExit::Mmio {
+                addr: EXCEPTION_ADDR,
+                data,
+                ..
+            } = result
+            else {
+                bail!("unexpected exit after ESR: {:?}", result);
+            };
+            let far = u64::from_le_bytes(data);
+            bail!("holding cell got sync abort. esr={:x} far={:x}", esr, far);
+        }
+
+        Ok(())
+    }
+
+    /// The result a cell writes to [`COMMAND_ADDR`] once its command is done.
+    fn command_result(result: VcpuExit) -> Result<u64> {
+        match result {
+            VcpuExit::Mmio {
+                addr: COMMAND_ADDR,
+                data,
+                is_write: true,
+                ..
+            } => Ok(u64::from_le_bytes(data)),
+            VcpuExit::Mmio { .. } => bail!("unexpected mmio exit reason: {:?}", result),
+            _ => bail!("unexpected exit reason: {:?}", result),
+        }
+    }
+
+    /// Starts the VM before the first command, later ones find it running already.
+    fn start_once(&self) -> Result<()> {
+        if self.vm.state() == VmState::Ready {
+            self.vm.start().context("Failed to start vcpu")?;
+        }
+        Ok(())
+    }
+
+    /// Sends command `test` with `args` to `cell_id`. Returns a closure which collects the result.
+    /// With `hold`, the cell keeps running until the closure is called.
+    pub fn run_test(
+        &self,
+        cell_id: u8,
+        test: u8,
+        args: &[u64],
+        hold: bool,
+    ) -> Result<Box<dyn Fn() -> Result<u64> + '_>> {
+        self.start_once()?;
+        let vcpu = &self.vcpus[cell_id as usize];
+        vcpu.run_once()
+            .context("Failed to run vcpu before providing command")?;
+        Self::test_errors(vcpu)?;
+        let command = command_word(test, args.len(), hold)?;
+        vcpu.vmmio_provide_read(COMMAND_ADDR, &command)
+            .context(format!("Failed to provide command: {:?}", vcpu.exit()))?;
+
+        for arg in args {
+            vcpu.run_once()
+                .context(format!("Failed to run vcpu before providing {arg}"))?;
+            Self::test_errors(vcpu)?;
+            vcpu.vmmio_provide_read(COMMAND_ADDR, &arg.to_le_bytes())?;
+        }
+
+        if hold {
+            Ok(Box::new(|| {
+                let result = vcpu
+                    .run_once()
+                    .context("Failed to run vcpu to get result")?;
+                Self::test_errors(vcpu)?;
+                Self::command_result(result)
+            }))
+        } else {
+            let result = vcpu
+                .run_once()
+                .context("Failed to run vcpu to get result")?;
+            Self::test_errors(vcpu)?;
+            let value = Self::command_result(result)?;
+            Ok(Box::new(move || Ok(value)))
+        }
+    }
+
+    pub fn run_immediately(&self, cell_id: u8, test: u8, args: &[u64]) -> Result<u64> {
+        self.run_test(cell_id, test, args, false).and_then(|f| f())
+    }
+
+    pub fn ack_ok(&self, cell_id: u8) -> Result<()> {
+        self.run_immediately(cell_id, 0, &[])?;
+        Ok(())
+    }
+
+    pub fn read_addr(&self, cell_id: u8, addr: u64) -> Result<u64> {
+        self.run_immediately(cell_id, 2, &[addr])
+    }
+
+    pub fn write_addr(&self, cell_id: u8, addr: u64, value: u64) -> Result<()> {
+        if self.run_immediately(cell_id, 3, &[addr, value])? != 0 {
+            Err(anyhow!("Unexpected nonzero response"))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn read_io(&self, cell_id: u8, addr: u64, value: u64) -> Result<u64> {
+        self.start_once()?;
+        let vcpu = &self.vcpus[cell_id as usize];
+        vcpu.run_once()
+            .context("Failed to run vcpu before providing command")?;
+        Self::test_errors(vcpu)?;
+        let command = command_word(8, 1, false)?;
+        vcpu.vmmio_provide_read(COMMAND_ADDR, &command)
+            .context(format!("Failed to provide command: {:?}", vcpu.exit()))?;
+
+        vcpu.run_once()
+            .context("Failed to run vcpu before providing addr")?;
+        Self::test_errors(vcpu)?;
+        vcpu.vmmio_provide_read(COMMAND_ADDR, &addr.to_le_bytes())?;
+
+        vcpu.run_once()
+            .context("Failed to run vcpu before providing value")?;
+        Self::test_errors(vcpu)?;
+
+        vcpu.vmmio_provide_read(addr, &value.to_le_bytes())?;
+
+        let result = vcpu
+            .run_once()
+            .context("Failed to run vcpu after providing value")?;
+        Self::test_errors(vcpu)?;
+        Self::command_result(result)
+    }
+
+    pub fn write_io(&self, cell_id: u8, addr: u64, value: u64) -> Result<()> {
+        if self.run_immediately(cell_id, 9, &[addr, value])? != 0 {
+            Err(anyhow!("Unexpected nonzero response"))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Routes SPI `line` to `cell_id` and waits until it's raised, e.g. by triggering a
+    /// [`crate::GunyahInterrupt`] of the line from another thread.
+    pub fn wait_irq(&self, cell_id: u8, line: u32) -> Result<()> {
+        let intid = u64::from(GIC_SPI_BASE + line);
+        let received = self.run_immediately(cell_id, 10, &[HOLDING_CELL_GICD_ADDR, intid])?;
+        if received != intid {
+            bail!("Received interrupt {} instead of {}", received, intid);
+        }
+        Ok(())
+    }
+
+    /// Has `cell_id` perform `op` on every data cache line of `[addr, addr + len)` and wait for
+    /// it to complete, e.g. to write dirty lines back before relinquishing without the flush flag.
+    pub fn dcache_maintain(
+        &self,
+        cell_id: u8,
+        op: CacheMaintenance,
+        addr: u64,
+        len: u64,
+    ) -> Result<()> {
+        if self.run_immediately(cell_id, op.command(), &[addr, len])? != 0 {
+            Err(anyhow!("Unexpected nonzero response"))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Arms `timer` of `cell_id` to fire in `ticks` of the system counter and waits for its
+    /// interrupt with every PPI enabled. Returns the PPI the timer raised, to be compared with
+    /// the one [`generate_holding_cell_fdt`] describes.
+    pub fn wait_timer(&self, cell_id: u8, timer: ArchTimer, ticks: u64) -> Result<u32> {
+        let gic = holding_cell_gic(self.vcpus.len() as u8);
+        let timer_arg = match timer {
+            ArchTimer::Virtual => 0,
+            ArchTimer::Physical => 1,
+        };
+        let received = self.run_immediately(
+            cell_id,
+            15,
+            &[gic.dist_base, gic.redist_base, timer_arg, ticks],
+        )?;
+        if received == TIMER_EARLY {
+            bail!("{:?} timer fired before {} ticks", timer, ticks);
+        }
+        u32::try_from(received)
+            .ok()
+            .and_then(|intid| intid.checked_sub(GIC_PPI_BASE))
+            .filter(|&ppi| ppi < GIC_PPI_BASE)
+            .ok_or(anyhow!("{:?} timer raised interrupt {}", timer, received))
+    }
+
+    /// Enables the SGIs of `cell_id`. The lower the SGI, the higher its priority, so
+    /// [`Self::ack_sgi`] returns pending SGIs in ascending order.
+    pub fn enable_sgis(&self, cell_id: u8) -> Result<()> {
+        let gic = holding_cell_gic(self.vcpus.len() as u8);
+        if self.run_immediately(cell_id, 16, &[gic.dist_base, gic.redist_base])? != 0 {
+            Err(anyhow!("Unexpected nonzero response"))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Has `cell_id` send `sgi` to `target`, which needs its SGIs enabled with
+    /// [`Self::enable_sgis`] to acknowledge it.
+    pub fn send_sgi(&self, cell_id: u8, target: u8, sgi: u8) -> Result<()> {
+        if sgi >= GIC_NR_SGIS {
+            bail!("No SGI {}", sgi);
+        }
+        let target = u64::from(self.vcpus[target as usize].id());
+        if self.run_immediately(cell_id, 17, &[target, u64::from(sgi)])? != 0 {
+            Err(anyhow!("Unexpected nonzero response"))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Acknowledges the highest priority interrupt pending on `cell_id`, which must be an SGI,
+    /// waiting for one if none is. Returns the SGI.
+    pub fn ack_sgi(&self, cell_id: u8) -> Result<u8> {
+        let received = self.run_immediately(cell_id, 18, &[])?;
+        u8::try_from(received)
+            .ok()
+            .filter(|&sgi| sgi < GIC_NR_SGIS)
+            .ok_or(anyhow!(
+                "Acknowledged interrupt {} instead of an SGI",
+                received
+            ))
+    }
+
+    /// SCTLR_EL1 of `cell_id`, e.g. to check whether its MMU and caches are enabled.
+    pub fn read_sctlr(&self, cell_id: u8) -> Result<u64> {
+        self.run_immediately(cell_id, 14, &[])
+    }
+
+    pub fn smccc_immediately(&self, cell_id: u8, args: &[u64]) -> Result<u64> {
+        let mut _args = [0u64; 5];
+        _args[..args.len()].copy_from_slice(args);
+        self.run_immediately(cell_id, 6, &_args)
+    }
+
+    pub fn power_on_cell(&self, cell_id: u8) -> Result<()> {
+        self.smccc_immediately(
+            0,
+            &[
+                0xC400_0003,
+                self.vcpus[cell_id as usize].id() as u64,
+                self.base,
+                0,
+                0,
+            ],
+        )
+        .map(|_| ())
+    }
+
+    pub fn power_off(&self, cell_id: u8) -> Result<()> {
+        if self.smccc_immediately(cell_id, &[0x8400_0008]).is_err() {
+            Ok(())
+        } else {
+            Err(anyhow!("Failed to shutdown VM"))
+        }
+    }
+
+    pub fn page_relinquish(
+        &self,
+        cell_id: u8,
+        addr: u64,
+        nr_pages: u32,
+        sanitize: bool,
+        flush: FlushType,
+    ) -> Result<()> {
+        let addrspc_flags = 0b1 | if sanitize { 0b10 } else { 0 };
+        for i in 1..(nr_pages + 1) {
+            let flags = addrspc_flags
+                | match flush {
+                    FlushType::FlushEvery => 0b100,
+                    FlushType::FlushOnLast => {
+                        if i == nr_pages {
+                            0b100
+                        } else {
+                            0
+                        }
+                    }
+                    FlushType::FlushAfter => 0,
+                    FlushType::NoFlush => 0,
+                };
+            self.smccc_immediately(
+                cell_id,
+                &[
+                    gunyah_hvc!(0x8069),
+                    0,
+                    addr + ((i - 1) * kib!(4)) as u64,
+                    kib!(4),
+                    flags,
+                ],
+            )?;
+        }
+        if flush == FlushType::FlushAfter {
+            let ret = self.smccc_immediately(cell_id, &[gunyah_hvc!(0x8069), 0, 0, 0, 0b100])?;
+            if ret != 0 {
+                return Err(anyhow!("hypercall returned error: {}", ret));
+            };
+        }
+        Ok(())
+    }
+
+    pub fn cell_state(&self, cell_id: u8) -> VcpuExit {
+        self.vcpus[cell_id as usize].exit()
+    }
+
+    pub fn host_write_slice(&self, address: u64, data: &[u8]) -> Result<()> {
+        self.vm.write_slice(address, data)
+    }
+
+    pub fn host_read_slice(&self, address: u64, data: &mut [u8]) -> Result<()> {
+        self.vm.read_slice(address, data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use claim::assert_err;
+
+    use super::command_word;
+
+    #[test]
+    fn command_encoding() {
+        assert_eq!(command_word(4, 1, false).unwrap(), [4, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(command_word(6, 5, true).unwrap(), [6, 5, 1, 0, 0, 0, 0, 0]);
+        assert_err!(command_word(6, 16, false));
+    }
+}
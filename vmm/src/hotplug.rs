@@ -0,0 +1,197 @@
+// Copyright (c) 2024, Qualcomm Innovation Center, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause-Clear
+
+//! Adding vCPUs and memory to a running VM.
+//!
+//! Gunyah lets the VMM create a vCPU after the VM has started. The guest can only use it if its
+//! device tree already lists it, so the cpus node also describes the possible vCPUs which
+//! haven't been added yet, with `status = "disabled"`. Once one is added and has a thread
+//! running it, the guest brings it online with PSCI CPU_ON, e.g. by writing 1 to
+//! /sys/devices/system/cpu/cpuN/online in Linux.
+//!
+//! Memory works the same way: Gunyah accepts mappings after the VM has started, and the window
+//! of guest physical addresses memory can be added at is described up front by a memory node
+//! with `status = "disabled"` and `hotpluggable`. The guest learns which parts of the window
+//! were added from whoever added them, e.g. the control socket's client, and brings them online
+//! itself; Linux on arm64 has no firmware interface which would announce them.
+
+use std::{
+    num::NonZeroUsize,
+    sync::{Arc, Mutex, RwLock},
+    thread::{self, JoinHandle},
+};
+
+use anyhow::{anyhow, Context, Result};
+use gunyah::{GuestMemRegion, GuestMemoryAccess, Gunyah, ShareType};
+
+use crate::{
+    AccessId, Bus, GunyahGuestMemoryRegion, GunyahVcpu, MemoryCounters, SeccompPolicy, VmDebug,
+    VmExit, VmExitRequest,
+};
+
+/// Creates the vCPUs of a VM, also once it runs, see [`crate::GunyahVirtualMachine::vcpu_hotplug`].
+#[derive(Clone)]
+pub struct VcpuHotplug {
+    vm: gunyah::Vm,
+    bus: Bus,
+    exit: VmExitRequest,
+    debug: VmDebug,
+    vcpus: Arc<RwLock<Vec<Arc<GunyahVcpu>>>>,
+    /// vCPU IDs below this can be added
+    possible: u8,
+    seccomp: Option<SeccompPolicy>,
+}
+
+impl VcpuHotplug {
+    pub(crate) fn new(
+        vm: gunyah::Vm,
+        bus: Bus,
+        exit: VmExitRequest,
+        debug: VmDebug,
+        vcpus: Arc<RwLock<Vec<Arc<GunyahVcpu>>>>,
+        possible: u8,
+        seccomp: Option<SeccompPolicy>,
+    ) -> Self {
+        Self {
+            vm,
+            bus,
+            exit,
+            debug,
+            vcpus,
+            possible,
+            seccomp,
+        }
+    }
+
+    /// Creates vCPU `id` without running it, the caller runs it.
+    pub fn create(&self, id: u8) -> Result<Arc<GunyahVcpu>> {
+        let vcpu = Arc::new(
+            GunyahVcpu::new(
+                &self.vm,
+                self.bus.clone().set_access_id(AccessId::Vcpu(id)),
+                self.exit.clone(),
+                self.debug.clone(),
+                id,
+                self.seccomp,
+            )
+            .context("Failed to create vcpu")?,
+        );
+        self.vcpus.write().unwrap().push(vcpu.clone());
+        Ok(vcpu)
+    }
+
+    /// Creates the possible vCPU `id` and runs it in a new thread until the VM exits. The vCPU
+    /// stays off until the guest turns it on with PSCI CPU_ON.
+    pub fn add(&self, id: u8) -> Result<(Arc<GunyahVcpu>, JoinHandle<Result<VmExit>>)> {
+        if id >= self.possible {
+            return Err(anyhow!(
+                "vCPU {} isn't possible, the VM only has room for {} vCPUs",
+                id,
+                self.possible
+            ));
+        }
+        if self.is_added(id) {
+            return Err(anyhow!("vCPU {} already exists", id));
+        }
+        let vcpu = self.create(id)?;
+        let runner = vcpu.clone();
+        let handle = thread::Builder::new()
+            .name(format!("vcpu{}", id))
+            .spawn(move || runner.run())
+            .context("Failed to spawn vcpu thread")?;
+        Ok((vcpu, handle))
+    }
+
+    fn is_added(&self, id: u8) -> bool {
+        self.vcpus
+            .read()
+            .unwrap()
+            .iter()
+            .any(|vcpu| vcpu.id() == u32::from(id))
+    }
+
+    /// vCPUs created so far.
+    pub fn vcpus(&self) -> Vec<Arc<GunyahVcpu>> {
+        self.vcpus.read().unwrap().clone()
+    }
+}
+
+/// Adds memory to a VM, also once it runs, see [`crate::GunyahVirtualMachine::memory_hotplug`].
+#[derive(Clone)]
+pub struct MemoryHotplug {
+    vm: Arc<Mutex<gunyah::Vm>>,
+    bus: Bus,
+    memory: Arc<RwLock<Vec<Arc<Mutex<GunyahGuestMemoryRegion>>>>>,
+    /// Base and size of the addresses memory can be added at, if any
+    window: Option<(u64, u64)>,
+    counters: Arc<MemoryCounters>,
+}
+
+impl MemoryHotplug {
+    pub(crate) fn new(
+        vm: gunyah::Vm,
+        bus: Bus,
+        memory: Arc<RwLock<Vec<Arc<Mutex<GunyahGuestMemoryRegion>>>>>,
+        window: Option<(u64, u64)>,
+        counters: Arc<MemoryCounters>,
+    ) -> Self {
+        Self {
+            vm: Arc::new(Mutex::new(vm)),
+            bus,
+            memory,
+            window,
+            counters,
+        }
+    }
+
+    /// Maps `len` bytes of new regular memory at `start`, which must be in the hotplug window.
+    pub fn add(
+        &self,
+        start: u64,
+        len: NonZeroUsize,
+        share_type: ShareType,
+        huge_pages: bool,
+    ) -> Result<Arc<Mutex<GunyahGuestMemoryRegion>>> {
+        let (base, size) = self
+            .window
+            .ok_or(anyhow!("The VM has no room for hotplugged memory"))?;
+        let end = start.checked_add(len.get() as u64).ok_or(anyhow!(
+            "{:#x}+{:#x} overflows",
+            start,
+            len
+        ))?;
+        if start < base || end > base + size {
+            return Err(anyhow!(
+                "{:#x}-{:#x} is outside the hotplug window {:#x}-{:#x}",
+                start,
+                end,
+                base,
+                base + size
+            ));
+        }
+
+        let guest_mem = Gunyah::new()?
+            .create_guest_memory(len, huge_pages)
+            .context("Failed to create guest memory")?;
+        let region = GuestMemRegion::new(guest_mem, 0, len)?;
+        let guest_region = Arc::new(Mutex::new(
+            GunyahGuestMemoryRegion::new(
+                region,
+                start,
+                &mut self.vm.lock().unwrap(),
+                share_type,
+                GuestMemoryAccess::Rwx,
+                false,
+                true,
+            )
+            .context("Failed to add guest memory region to vm")?,
+        ));
+        // The VM runs, so the guest uses the memory right away
+        guest_region.lock().unwrap().unmap_from_vmm();
+        self.bus
+            .insert(guest_region.clone(), start, len.get() as u64)?;
+        self.memory.write().unwrap().push(guest_region.clone());
+        self.counters.map(len.get() as u64);
+        Ok(guest_region)
+    }
+}
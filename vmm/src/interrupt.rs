@@ -1,10 +1,22 @@
 // Copyright (c) 2024, Qualcomm Innovation Center, Inc. All rights reserved.
 // SPDX-License-Identifier: BSD-3-Clause-Clear
 
-use anyhow::Result;
+use std::{
+    os::fd::{AsRawFd, RawFd},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, RwLock, Weak,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Result};
 use gunyah::Irqfd;
 
-use crate::GunyahVirtualMachine;
+use crate::{DeviceTask, GunyahVirtualMachine, TaskPoll};
+
+/// How often asserted level interrupts are triggered again, see [`GunyahInterrupt::set_level`]
+const RESAMPLE_INTERVAL: Duration = Duration::from_millis(1);
 
 const GIC_FDT_IRQ_TYPE_SPI: u32 = 0;
 
@@ -15,6 +27,10 @@ const IRQ_TYPE_LEVEL_HIGH: u32 = 0x00000004;
 pub struct GunyahInterrupt {
     line: u32,
     irqfd: Irqfd,
+    /// Level of a level triggered interrupt as set by its device
+    asserted: AtomicBool,
+    /// How often the interrupt was triggered, see [`Self::triggers`]
+    triggers: AtomicU64,
 }
 
 impl GunyahInterrupt {
@@ -22,6 +38,8 @@ impl GunyahInterrupt {
         Ok(Self {
             line,
             irqfd: Irqfd::new(vm.vm().clone(), line, true)?,
+            asserted: AtomicBool::new(false),
+            triggers: AtomicU64::new(0),
         })
     }
 
@@ -29,22 +47,66 @@ impl GunyahInterrupt {
         Ok(Self {
             line,
             irqfd: Irqfd::new(vm.vm().clone(), line, false)?,
+            asserted: AtomicBool::new(false),
+            triggers: AtomicU64::new(0),
         })
     }
 
     pub fn trigger(&self) -> Result<()> {
+        self.triggers.fetch_add(1, Ordering::Relaxed);
         self.irqfd.trigger()
     }
 
+    /// How often the interrupt was triggered, including again while asserted, see
+    /// [`Self::set_level`].
+    pub fn triggers(&self) -> u64 {
+        self.triggers.load(Ordering::Relaxed)
+    }
+
+    /// Asserts or deasserts a level interrupt.
+    ///
+    /// Gunyah tells the VMM neither when the guest handled the interrupt nor when it EOIs it, so
+    /// triggering a level interrupt only raises it once. While asserted the interrupt is
+    /// triggered again periodically, which raises it anew once the guest handled it while the
+    /// device's condition persists. Devices deassert it when the condition is gone, which stops
+    /// it from being raised again; if it is raised already it stays so until the guest handles it.
+    pub fn set_level(&self, asserted: bool) -> Result<()> {
+        if !self.is_level() {
+            bail!("SPI {} is edge triggered", self.line);
+        }
+        let was_asserted = self.asserted.swap(asserted, Ordering::Relaxed);
+        if asserted && !was_asserted {
+            self.trigger()?;
+        }
+        Ok(())
+    }
+
+    /// Whether a level interrupt is asserted, see [`Self::set_level`].
+    pub fn is_asserted(&self) -> bool {
+        self.asserted.load(Ordering::Relaxed)
+    }
+
+    /// Triggers the interrupt again if it is asserted.
+    fn resample(&self) -> Result<()> {
+        if self.is_asserted() {
+            self.trigger()?;
+        }
+        Ok(())
+    }
+
     pub fn line(&self) -> u32 {
         self.line
     }
 
+    pub fn is_level(&self) -> bool {
+        self.irqfd.level()
+    }
+
     pub fn fdt_config(&self) -> [u32; 3] {
         [
             GIC_FDT_IRQ_TYPE_SPI,
             self.line(),
-            if self.irqfd.level() {
+            if self.is_level() {
                 IRQ_TYPE_LEVEL_HIGH
             } else {
                 IRQ_TYPE_EDGE_RISING
@@ -70,3 +132,39 @@ impl GunyahInterrupt {
         Ok(())
     }
 }
+
+impl AsRawFd for GunyahInterrupt {
+    /// eventfd which triggers the interrupt when written, e.g. by another process.
+    fn as_raw_fd(&self) -> RawFd {
+        self.irqfd.as_raw_fd()
+    }
+}
+
+/// Resamples the asserted level interrupts of a VM, see [`GunyahInterrupt::set_level`].
+pub(crate) struct ResampleTask {
+    interrupts: Weak<RwLock<Vec<Arc<GunyahInterrupt>>>>,
+}
+
+impl ResampleTask {
+    pub(crate) fn new(interrupts: &Arc<RwLock<Vec<Arc<GunyahInterrupt>>>>) -> Self {
+        Self {
+            interrupts: Arc::downgrade(interrupts),
+        }
+    }
+}
+
+impl DeviceTask for ResampleTask {
+    fn debug_label(&self) -> String {
+        "interrupt resample".to_string()
+    }
+
+    fn poll(&mut self, now: Instant) -> Result<TaskPoll> {
+        let Some(interrupts) = self.interrupts.upgrade() else {
+            return Ok(TaskPoll::Done);
+        };
+        for interrupt in interrupts.read().unwrap().iter() {
+            interrupt.resample()?;
+        }
+        Ok(TaskPoll::Pending(Some(now + RESAMPLE_INTERVAL)))
+    }
+}
@@ -0,0 +1,286 @@
+// Copyright (c) 2024, Qualcomm Innovation Center, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause-Clear
+
+//! How device backends read and write host files, see
+//! [`crate::GunyahVirtualMachine::set_io_engine`].
+//!
+//! The default engine makes the read and write syscalls itself. With the `io-uring` feature,
+//! I/O can instead go through an io_uring shared by all threads. Each operation still waits for
+//! its own completion, but threads only hold the ring's lock to queue operations and collect
+//! completions, so they don't wait for each other's I/O. Kernels without io_uring, or with it
+//! disabled, fall back to the syscalls.
+
+use std::{fs::File, io, os::unix::fs::FileExt, sync::Arc};
+
+#[cfg(not(feature = "io-uring"))]
+use anyhow::anyhow;
+use anyhow::Result;
+#[cfg(feature = "io-uring")]
+use std::{
+    collections::HashMap,
+    os::fd::AsRawFd,
+    sync::{Condvar, Mutex},
+};
+
+/// Submission queue size of an io_uring engine. Operations are submitted as soon as they're
+/// queued, so the queue holds at most one at a time.
+#[cfg(feature = "io-uring")]
+const IO_URING_ENTRIES: u32 = 16;
+
+/// The kinds of [`IoEngine`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IoEngineKind {
+    /// read and write syscalls
+    #[default]
+    Sync,
+    /// An io_uring, with the syscalls as fallback if the kernel doesn't support it
+    IoUring,
+}
+
+enum Engine {
+    Sync,
+    #[cfg(feature = "io-uring")]
+    IoUring(Box<IoUringEngine>),
+}
+
+#[cfg(feature = "io-uring")]
+struct IoUringEngine {
+    ring: io_uring::IoUring,
+    /// Guards the queues of `ring`
+    state: Mutex<IoUringState>,
+    /// Signalled when a thread stops waiting for completions
+    reaped: Condvar,
+}
+
+#[cfg(feature = "io-uring")]
+#[derive(Default)]
+struct IoUringState {
+    /// user_data of the next operation
+    next_id: u64,
+    /// Results of completed operations which their threads haven't collected yet
+    results: HashMap<u64, i32>,
+    /// Whether a thread is waiting in the kernel for completions
+    reaping: bool,
+}
+
+/// Reads and writes files for device backends. Clones share the same engine.
+#[derive(Clone)]
+pub struct IoEngine(Arc<Engine>);
+
+impl Default for IoEngine {
+    fn default() -> Self {
+        Self(Arc::new(Engine::Sync))
+    }
+}
+
+impl std::fmt::Debug for IoEngine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "IoEngine({:?})", self.kind())
+    }
+}
+
+impl IoEngine {
+    /// Creates an engine of `kind`, which fails for io_uring if the VMM was built without the
+    /// `io-uring` feature.
+    pub fn new(kind: IoEngineKind) -> Result<Self> {
+        match kind {
+            IoEngineKind::Sync => Ok(Self::default()),
+            #[cfg(feature = "io-uring")]
+            IoEngineKind::IoUring => match io_uring::IoUring::new(IO_URING_ENTRIES) {
+                Ok(ring) => Ok(Self(Arc::new(Engine::IoUring(Box::new(IoUringEngine {
+                    ring,
+                    state: Mutex::default(),
+                    reaped: Condvar::new(),
+                }))))),
+                Err(e) => {
+                    println!("io_uring is unavailable, using read and write: {}", e);
+                    Ok(Self::default())
+                }
+            },
+            #[cfg(not(feature = "io-uring"))]
+            IoEngineKind::IoUring => Err(anyhow!("The VMM was built without the io-uring feature")),
+        }
+    }
+
+    /// The kind of engine in use, which is [`IoEngineKind::Sync`] after a fallback.
+    pub fn kind(&self) -> IoEngineKind {
+        match *self.0 {
+            Engine::Sync => IoEngineKind::Sync,
+            #[cfg(feature = "io-uring")]
+            Engine::IoUring(_) => IoEngineKind::IoUring,
+        }
+    }
+
+    /// Reads from `file` at `offset`, like [`FileExt::read_at`].
+    pub fn read_at(&self, file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        match &*self.0 {
+            Engine::Sync => file.read_at(buf, offset),
+            #[cfg(feature = "io-uring")]
+            Engine::IoUring(ring) => {
+                let fd = io_uring::types::Fd(file.as_raw_fd());
+                let len = buf.len().try_into().unwrap_or(u32::MAX);
+                let read = io_uring::opcode::Read::new(fd, buf.as_mut_ptr(), len)
+                    .offset(offset)
+                    .build();
+                // SAFETY: Safe because buf outlives the operation, which completes before
+                // submit returns.
+                unsafe { ring.submit(read) }
+            }
+        }
+    }
+
+    /// Writes to `file` at `offset`, like [`FileExt::write_at`].
+    pub fn write_at(&self, file: &File, buf: &[u8], offset: u64) -> io::Result<usize> {
+        self.write_with_offset(file, buf, offset)
+    }
+
+    /// Writes to `file` at its current position, or its end if it was opened for appending.
+    pub fn write(&self, file: &File, buf: &[u8]) -> io::Result<usize> {
+        // An offset of -1 makes io_uring use the file position like write does
+        self.write_with_offset(file, buf, u64::MAX)
+    }
+
+    fn write_with_offset(&self, file: &File, buf: &[u8], offset: u64) -> io::Result<usize> {
+        match &*self.0 {
+            Engine::Sync if offset == u64::MAX => io::Write::write(&mut &*file, buf),
+            Engine::Sync => file.write_at(buf, offset),
+            #[cfg(feature = "io-uring")]
+            Engine::IoUring(ring) => {
+                let fd = io_uring::types::Fd(file.as_raw_fd());
+                let len = buf.len().try_into().unwrap_or(u32::MAX);
+                let write = io_uring::opcode::Write::new(fd, buf.as_ptr(), len)
+                    .offset(offset)
+                    .build();
+                // SAFETY: Safe because buf outlives the operation, which completes before
+                // submit returns.
+                unsafe { ring.submit(write) }
+            }
+        }
+    }
+}
+
+#[cfg(feature = "io-uring")]
+impl IoUringEngine {
+    /// Submits `entry` and waits for its result. One of the waiting threads waits in the kernel
+    /// and hands the other threads their results.
+    ///
+    /// # Safety
+    ///
+    /// The buffers `entry` refers to must be valid for the operation.
+    unsafe fn submit(&self, entry: io_uring::squeue::Entry) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id = state.next_id.wrapping_add(1);
+        // SAFETY: Safe because the submission queue is only used under the lock, and the caller
+        // keeps the buffers valid.
+        unsafe { self.ring.submission_shared().push(&entry.user_data(id)) }
+            .map_err(|_| io::Error::other("io_uring submission queue is full"))?;
+        self.ring.submit()?;
+        loop {
+            if let Some(result) = state.results.remove(&id) {
+                return if result < 0 {
+                    Err(io::Error::from_raw_os_error(-result))
+                } else {
+                    Ok(result as usize)
+                };
+            }
+            if state.reaping {
+                state = self.reaped.wait(state).unwrap();
+                continue;
+            }
+            state.reaping = true;
+            drop(state);
+            let waited = match self.ring.submitter().submit_and_wait(1) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => Ok(0),
+                result => result,
+            };
+            state = self.state.lock().unwrap();
+            state.reaping = false;
+            // SAFETY: Safe because the completion queue is only used under the lock.
+            for completion in unsafe { self.ring.completion_shared() } {
+                state
+                    .results
+                    .insert(completion.user_data(), completion.result());
+            }
+            self.reaped.notify_all();
+            waited?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, OpenOptions};
+
+    use claim::assert_ok;
+
+    use super::{IoEngine, IoEngineKind};
+
+    fn read_write(engine: &IoEngine, name: &str) {
+        let path = std::env::temp_dir().join(format!(
+            "io-engine-{:?}-{}-{}",
+            engine.kind(),
+            name,
+            std::process::id()
+        ));
+        let file = assert_ok!(OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .read(true)
+            .write(true)
+            .open(&path));
+        assert_eq!(assert_ok!(engine.write_at(&file, b"gunyah", 4)), 6);
+        let mut buf = [0xffu8; 10];
+        assert_eq!(assert_ok!(engine.read_at(&file, &mut buf, 0)), 10);
+        assert_eq!(&buf, b"\0\0\0\0gunyah");
+        // Past the end of the file
+        assert_eq!(assert_ok!(engine.read_at(&file, &mut buf, 20)), 0);
+
+        let log = assert_ok!(OpenOptions::new().append(true).open(&path));
+        assert_eq!(assert_ok!(engine.write(&log, b"!")), 1);
+        assert_eq!(assert_ok!(fs::read(&path)), b"\0\0\0\0gunyah!");
+        assert_ok!(fs::remove_file(&path));
+    }
+
+    #[test]
+    fn sync() {
+        let engine = assert_ok!(IoEngine::new(IoEngineKind::Sync));
+        assert_eq!(engine.kind(), IoEngineKind::Sync);
+        read_write(&engine, "sync");
+    }
+
+    #[cfg(feature = "io-uring")]
+    #[test]
+    fn io_uring() {
+        // Falls back to sync where io_uring is unavailable, the results are the same
+        read_write(
+            &assert_ok!(IoEngine::new(IoEngineKind::IoUring)),
+            "io_uring",
+        );
+    }
+
+    #[cfg(feature = "io-uring")]
+    #[test]
+    fn io_uring_threads() {
+        let engine = assert_ok!(IoEngine::new(IoEngineKind::IoUring));
+        let threads: Vec<_> = (0..4)
+            .map(|i| {
+                let engine = engine.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..100 {
+                        read_write(&engine, &format!("thread{}", i));
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            assert_ok!(thread.join());
+        }
+    }
+
+    #[cfg(not(feature = "io-uring"))]
+    #[test]
+    fn io_uring_unsupported() {
+        claim::assert_err!(IoEngine::new(IoEngineKind::IoUring));
+    }
+}
@@ -0,0 +1,370 @@
+// Copyright (c) 2024, Qualcomm Innovation Center, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause-Clear
+
+use std::{
+    sync::{Arc, Mutex, Weak},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use vm_fdt::FdtWriter;
+
+use crate::{BusAccessInfo, BusDevice, DeviceTask, GunyahVirtualMachine, TaskPoll};
+
+pub const IRQ_GEN_MMIO_SIZE: u64 = 0x1000;
+/// Longest the generator task waits, so changes to the period are noticed.
+const MAX_SLEEP: Duration = Duration::from_millis(10);
+/// Interrupts which are more than this many periods late are dropped instead of raised in a
+/// burst.
+const MAX_BACKLOG: u32 = 1000;
+
+/// RW: nanoseconds between interrupts, 0 stops the generator
+const IRQ_GEN_PERIOD: u64 = 0x00;
+/// WO: raises the interrupt this many times right away
+const IRQ_GEN_FIRE: u64 = 0x08;
+/// RO: number of times the interrupt was raised
+const IRQ_GEN_RAISED: u64 = 0x10;
+/// R: number of acknowledged interrupts. W: acknowledges the last interrupt
+const IRQ_GEN_ACKED: u64 = 0x18;
+/// RO: nanoseconds between the last interrupt and its acknowledgement
+const IRQ_GEN_LATENCY: u64 = 0x20;
+
+type IrqGenTrigger = Box<dyn Fn() -> Result<()> + Send>;
+
+/// Test device which raises its interrupt periodically or on command, for interrupt latency and
+/// interrupt storm testing.
+///
+/// All registers are 64 bits wide and may be accessed 4 bytes at a time. The guest acknowledges
+/// each interrupt by writing to the ACKED register, which records how long it took.
+pub struct IrqGen {
+    base: u64,
+    trigger: IrqGenTrigger,
+    interrupts: [u32; 3],
+    period: Option<Duration>,
+    /// When the next periodic interrupt is due
+    next: Option<Instant>,
+    raised: u64,
+    acked: u64,
+    last_raised: Option<Instant>,
+    latency: Duration,
+}
+
+/// Raises the periodic interrupts of an [`IrqGen`] until the device is dropped.
+struct IrqGenTask(Weak<Mutex<IrqGen>>);
+
+impl DeviceTask for IrqGenTask {
+    fn debug_label(&self) -> String {
+        "irq-gen".to_string()
+    }
+
+    fn poll(&mut self, now: Instant) -> Result<TaskPoll> {
+        let Some(device) = self.0.upgrade() else {
+            return Ok(TaskPoll::Done);
+        };
+        let mut device = device.lock().unwrap();
+        if let Err(e) = device.tick(now) {
+            println!("Failed to raise the generated interrupt: {:?}", e);
+        }
+        let wait = device
+            .next
+            .map_or(MAX_SLEEP, |next| next.saturating_duration_since(now))
+            .min(MAX_SLEEP);
+        Ok(TaskPoll::Pending(Some(now + wait)))
+    }
+}
+
+impl IrqGen {
+    pub fn new(
+        vm: &mut GunyahVirtualMachine,
+        base: u64,
+        interrupt_line: u32,
+    ) -> Result<Arc<Mutex<Self>>> {
+        let irq = vm.add_edge_interrupt(interrupt_line)?;
+        let interrupts = irq.fdt_config();
+        let device = Arc::new(Mutex::new(Self::with_trigger(
+            base,
+            Box::new(move || irq.trigger()),
+            interrupts,
+        )));
+        vm.add_device(device.clone(), base, IRQ_GEN_MMIO_SIZE)?;
+
+        vm.executor()
+            .spawn(Box::new(IrqGenTask(Arc::downgrade(&device))));
+        Ok(device)
+    }
+
+    fn with_trigger(base: u64, trigger: IrqGenTrigger, interrupts: [u32; 3]) -> Self {
+        Self {
+            base,
+            trigger,
+            interrupts,
+            period: None,
+            next: None,
+            raised: 0,
+            acked: 0,
+            last_raised: None,
+            latency: Duration::ZERO,
+        }
+    }
+
+    /// Raises the interrupts which became due by `now`.
+    fn tick(&mut self, now: Instant) -> Result<()> {
+        let (Some(period), Some(mut next)) = (self.period, self.next) else {
+            return Ok(());
+        };
+        if now > next + period * MAX_BACKLOG {
+            next = now;
+        }
+        while next <= now {
+            self.raise(now)?;
+            next += period;
+        }
+        self.next = Some(next);
+        Ok(())
+    }
+
+    fn raise(&mut self, now: Instant) -> Result<()> {
+        self.raised += 1;
+        self.last_raised = Some(now);
+        (self.trigger)()
+    }
+
+    /// Raises the interrupt `count` times right away.
+    pub fn fire(&mut self, count: u64) -> Result<()> {
+        for _ in 0..count {
+            self.raise(Instant::now())?;
+        }
+        Ok(())
+    }
+
+    /// Raises the interrupt every `period`, or stops doing so.
+    pub fn set_period(&mut self, period: Option<Duration>) {
+        self.period = period.filter(|p| !p.is_zero());
+        self.next = self.period.map(|p| Instant::now() + p);
+    }
+
+    pub fn raised(&self) -> u64 {
+        self.raised
+    }
+
+    pub fn acked(&self) -> u64 {
+        self.acked
+    }
+
+    /// Time between the last interrupt and the guest acknowledging it.
+    pub fn latency(&self) -> Duration {
+        self.latency
+    }
+
+    fn ack(&mut self, now: Instant) {
+        self.acked += 1;
+        if let Some(raised) = self.last_raised {
+            self.latency = now.saturating_duration_since(raised);
+        }
+    }
+
+    fn register(&self, offset: u64) -> u64 {
+        match offset {
+            IRQ_GEN_PERIOD => self.period.map_or(0, |p| p.as_nanos() as u64),
+            IRQ_GEN_RAISED => self.raised,
+            IRQ_GEN_ACKED => self.acked,
+            IRQ_GEN_LATENCY => self.latency.as_nanos() as u64,
+            _ => 0,
+        }
+    }
+}
+
+/// Checks an access is 4 or 8 bytes and naturally aligned.
+fn check_access(offset: u64, len: usize) -> Result<()> {
+    if (len != 4 && len != 8) || !offset.is_multiple_of(len as u64) {
+        return Err(anyhow!(
+            "Unsupported {} byte access at offset {:#x}",
+            len,
+            offset
+        ));
+    }
+    Ok(())
+}
+
+impl BusDevice for IrqGen {
+    fn debug_label(&self) -> String {
+        format!("irq-gen@{:x}", self.base)
+    }
+
+    fn read(&mut self, access: BusAccessInfo, data: &mut [u8]) -> Result<()> {
+        check_access(access.offset, data.len())?;
+        let value = self.register(access.offset & !7).to_le_bytes();
+        let start = (access.offset & 7) as usize;
+        data.copy_from_slice(&value[start..start + data.len()]);
+        Ok(())
+    }
+
+    fn write(&mut self, access: BusAccessInfo, data: &[u8]) -> Result<()> {
+        check_access(access.offset, data.len())?;
+        let mut value = [0u8; 8];
+        value[..data.len()].copy_from_slice(data);
+        let value = u64::from_le_bytes(value);
+        match access.offset {
+            IRQ_GEN_PERIOD => self.set_period(Some(Duration::from_nanos(value))),
+            IRQ_GEN_FIRE => self.fire(value)?,
+            IRQ_GEN_ACKED => self.ack(Instant::now()),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn snapshot(&self) -> Result<Option<Vec<u8>>> {
+        Ok(Some(
+            [
+                IRQ_GEN_PERIOD,
+                IRQ_GEN_RAISED,
+                IRQ_GEN_ACKED,
+                IRQ_GEN_LATENCY,
+            ]
+            .iter()
+            .flat_map(|offset| self.register(*offset).to_le_bytes())
+            .collect(),
+        ))
+    }
+
+    fn restore(&mut self, state: &[u8]) -> Result<()> {
+        if state.len() != 32 {
+            return Err(anyhow!("Expected 32 bytes of state, got {}", state.len()));
+        }
+        let value = |i: usize| u64::from_le_bytes(state[i * 8..(i + 1) * 8].try_into().unwrap());
+        self.set_period(Some(Duration::from_nanos(value(0))));
+        self.raised = value(1);
+        self.acked = value(2);
+        self.latency = Duration::from_nanos(value(3));
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        self.set_period(None);
+        Ok(())
+    }
+
+    fn device_config(&self, fdt: &mut FdtWriter) -> Result<()> {
+        let node = fdt.begin_node(&format!("irq-gen@{:x}", self.base))?;
+        fdt.property_string("compatible", "gunyah-vmm,irq-gen")?;
+        fdt.property_array_u64("reg", &[self.base, IRQ_GEN_MMIO_SIZE])?;
+        fdt.property_array_u32("interrupts", &self.interrupts)?;
+        fdt.end_node(node)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc,
+        },
+        time::{Duration, Instant},
+    };
+
+    use claim::{assert_err, assert_ok};
+
+    use super::{
+        IrqGen, IRQ_GEN_ACKED, IRQ_GEN_FIRE, IRQ_GEN_LATENCY, IRQ_GEN_PERIOD, IRQ_GEN_RAISED,
+    };
+    use crate::{AccessId, BusAccessInfo, BusDevice};
+
+    fn access(offset: u64) -> BusAccessInfo {
+        BusAccessInfo {
+            offset,
+            address: 0x9200 + offset,
+            id: AccessId::Vcpu(0),
+        }
+    }
+
+    fn irq_gen() -> (IrqGen, Arc<AtomicU64>) {
+        let count = Arc::new(AtomicU64::new(0));
+        let triggered = count.clone();
+        let dev = IrqGen::with_trigger(
+            0x9200,
+            Box::new(move || {
+                triggered.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            }),
+            [0, 13, 1],
+        );
+        (dev, count)
+    }
+
+    fn read(dev: &mut IrqGen, offset: u64) -> u64 {
+        let mut data = [0u8; 8];
+        assert_ok!(dev.read(access(offset), &mut data));
+        u64::from_le_bytes(data)
+    }
+
+    #[test]
+    fn fire_and_ack() {
+        let (mut dev, count) = irq_gen();
+        assert_ok!(dev.write(access(IRQ_GEN_FIRE), &3u64.to_le_bytes()));
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+        assert_eq!(read(&mut dev, IRQ_GEN_RAISED), 3);
+
+        assert_ok!(dev.write(access(IRQ_GEN_ACKED), &0u32.to_le_bytes()));
+        assert_eq!(read(&mut dev, IRQ_GEN_ACKED), 1);
+        assert_eq!(
+            read(&mut dev, IRQ_GEN_LATENCY),
+            dev.latency().as_nanos() as u64
+        );
+
+        // 4 byte halves
+        let mut data = [0u8; 4];
+        assert_ok!(dev.read(access(IRQ_GEN_RAISED), &mut data));
+        assert_eq!(u32::from_le_bytes(data), 3);
+        assert_ok!(dev.read(access(IRQ_GEN_RAISED + 4), &mut data));
+        assert_eq!(u32::from_le_bytes(data), 0);
+    }
+
+    #[test]
+    fn periodic() {
+        let (mut dev, count) = irq_gen();
+        assert_ok!(dev.write(access(IRQ_GEN_PERIOD), &1_000_000u64.to_le_bytes()));
+        assert_eq!(read(&mut dev, IRQ_GEN_PERIOD), 1_000_000);
+        let start = dev.next.unwrap();
+
+        assert_ok!(dev.tick(start - Duration::from_micros(1)));
+        assert_eq!(count.load(Ordering::SeqCst), 0);
+        assert_ok!(dev.tick(start + Duration::from_micros(2500)));
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+
+        // Far behind: the backlog is dropped
+        assert_ok!(dev.tick(start + Duration::from_secs(10)));
+        assert_eq!(count.load(Ordering::SeqCst), 4);
+
+        assert_ok!(dev.write(access(IRQ_GEN_PERIOD), &0u64.to_le_bytes()));
+        assert_ok!(dev.tick(Instant::now() + Duration::from_secs(20)));
+        assert_eq!(dev.raised(), 4);
+    }
+
+    #[test]
+    fn snapshot() {
+        let (mut dev, _) = irq_gen();
+        assert_ok!(dev.fire(2));
+        dev.ack(Instant::now());
+        dev.set_period(Some(Duration::from_millis(1)));
+        let state = assert_ok!(dev.snapshot()).unwrap();
+
+        let (mut restored, count) = irq_gen();
+        assert_ok!(restored.restore(&state));
+        assert_eq!(restored.raised(), 2);
+        assert_eq!(restored.acked(), 1);
+        assert_eq!(restored.latency(), dev.latency());
+        assert_eq!(read(&mut restored, IRQ_GEN_PERIOD), 1_000_000);
+        assert_eq!(count.load(Ordering::SeqCst), 0);
+        assert_err!(restored.restore(&state[1..]));
+    }
+
+    #[test]
+    fn bad_access() {
+        let (mut dev, _) = irq_gen();
+        assert_err!(dev.write(access(IRQ_GEN_FIRE), &[1]));
+        let mut data = [0u8; 8];
+        assert_err!(dev.read(access(IRQ_GEN_RAISED + 4), &mut data));
+    }
+}
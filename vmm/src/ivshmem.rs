@@ -0,0 +1,234 @@
+// Copyright (c) 2024, Qualcomm Innovation Center, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause-Clear
+
+use std::{
+    fs::File,
+    io::{self, Read, Write},
+    num::NonZeroUsize,
+    os::{
+        fd::{AsRawFd, RawFd},
+        unix::fs::MetadataExt,
+    },
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use anyhow::{anyhow, Context, Result};
+use gunyah::{GuestMem, GuestMemRegion, GuestMemoryAccess, Ioeventfd, ShareType};
+use vm_fdt::FdtWriter;
+
+use crate::{
+    set_nonblocking, BusAccessInfo, BusDevice, DeviceTask, GunyahGuestMemoryRegion,
+    GunyahInterrupt, GunyahVirtualMachine, TaskPoll,
+};
+
+pub const IVSHMEM_MMIO_SIZE: u64 = 0x1000;
+
+/// Writing any value rings the host's doorbell
+const IVSHMEM_DOORBELL: u64 = 0x0;
+
+/// Memory shared between the guest and host processes, in the spirit of QEMU's ivshmem.
+///
+/// The memory comes from a host-provided file and is mapped into the guest as a RAM window.
+/// A page of registers next to it holds a doorbell: guest writes to it signal an eventfd without
+/// exiting to the VMM, and the host rings the guest by triggering the device's interrupt. The
+/// device tree node describes the registers and the memory as two `reg` entries, so Linux can
+/// drive it with uio_pdrv_genirq.
+///
+/// Two VMs of the same process can share memory instead, see [`Self::connect`].
+pub struct Ivshmem {
+    base: u64,
+    memory: Arc<Mutex<GunyahGuestMemoryRegion>>,
+    doorbell: Ioeventfd,
+    irq: Arc<GunyahInterrupt>,
+    /// Shared with the peer device in another VM, see [`Self::connect`]
+    label: Option<u32>,
+}
+
+impl Ivshmem {
+    /// Adds the registers at `base` and maps all of `mem` at `mem_base`. `mem` must be usable as
+    /// Gunyah guest memory.
+    pub fn new(
+        vm: &mut GunyahVirtualMachine,
+        base: u64,
+        interrupt_line: u32,
+        mem: GuestMem,
+        mem_base: u64,
+    ) -> Result<Arc<Mutex<Self>>> {
+        let len = usize::try_from(mem.as_file().metadata()?.len())?;
+        let size = NonZeroUsize::new(len).ok_or(anyhow!("Shared memory is empty"))?;
+        let memory = vm
+            .add_memory_region(
+                GuestMemRegion::new(mem, 0, size)?,
+                mem_base,
+                ShareType::Share,
+                GuestMemoryAccess::Rw,
+                false,
+                false,
+            )
+            .context("Failed to map shared memory")?;
+        let doorbell = vm.add_ioevent(base + IVSHMEM_DOORBELL, 4, None)?;
+        let irq = vm.add_edge_interrupt(interrupt_line)?;
+
+        let dev = Arc::new(Mutex::new(Self {
+            base,
+            memory,
+            doorbell,
+            irq,
+            label: None,
+        }));
+        vm.add_device(dev.clone(), base, IVSHMEM_MMIO_SIZE)?;
+        Ok(dev)
+    }
+
+    pub fn memory(&self) -> &Arc<Mutex<GunyahGuestMemoryRegion>> {
+        &self.memory
+    }
+
+    /// eventfd which is signalled whenever the guest rings the doorbell.
+    pub fn doorbell(&self) -> &File {
+        self.doorbell.as_file()
+    }
+
+    /// Interrupts the guest. Other processes can do the same by writing to the interrupt's
+    /// eventfd.
+    pub fn ring_guest(&self) -> Result<()> {
+        self.irq.trigger()
+    }
+
+    pub fn interrupt(&self) -> &GunyahInterrupt {
+        &self.irq
+    }
+
+    /// Pairs `first`, a device of `first_vm`, with `second`, a device of `second_vm`, which map
+    /// the same memory, e.g. a clone of the [`GuestMem`] passed to [`Self::new`]. Both VMs'
+    /// devices are labelled `label` in their device trees, so the guests can tell which device
+    /// they share, and each guest's doorbell interrupts the other guest from then on: the VMs'
+    /// executors relay the doorbells instead of the host waiting for them.
+    pub fn connect(
+        first_vm: &GunyahVirtualMachine,
+        first: &Arc<Mutex<Self>>,
+        second_vm: &GunyahVirtualMachine,
+        second: &Arc<Mutex<Self>>,
+        label: u32,
+    ) -> Result<()> {
+        if Arc::ptr_eq(first, second) {
+            return Err(anyhow!("Can't connect ivshmem to itself"));
+        }
+        let (first_relay, second_relay) = {
+            let mut first = first.lock().unwrap();
+            let mut second = second.lock().unwrap();
+            if !first.shares_memory_with(&second)? {
+                return Err(anyhow!(
+                    "ivshmem@{:x} and ivshmem@{:x} don't share their memory",
+                    first.base,
+                    second.base
+                ));
+            }
+            if let Some(label) = first.label.or(second.label) {
+                return Err(anyhow!("ivshmem already connected as {}", label));
+            }
+            first.label = Some(label);
+            second.label = Some(label);
+            (
+                DoorbellRelay::new(&first, second.irq.clone(), label)?,
+                DoorbellRelay::new(&second, first.irq.clone(), label)?,
+            )
+        };
+        first_vm.executor().spawn(Box::new(first_relay));
+        second_vm.executor().spawn(Box::new(second_relay));
+        Ok(())
+    }
+
+    fn shares_memory_with(&self, other: &Self) -> Result<bool> {
+        let memory = self.memory.lock().unwrap();
+        let other_memory = other.memory.lock().unwrap();
+        let (region, other_region) = (memory.as_region(), other_memory.as_region());
+        let file = region.as_guest_mem().as_file().metadata()?;
+        let other_file = other_region.as_guest_mem().as_file().metadata()?;
+        Ok(file.dev() == other_file.dev()
+            && file.ino() == other_file.ino()
+            && region.offset() == other_region.offset()
+            && region.size() == other_region.size())
+    }
+}
+
+/// Interrupts the peer VM of a connected [`Ivshmem`] whenever its own VM rings the doorbell.
+struct DoorbellRelay {
+    doorbell: File,
+    peer: Arc<GunyahInterrupt>,
+    label: u32,
+}
+
+impl DoorbellRelay {
+    fn new(device: &Ivshmem, peer: Arc<GunyahInterrupt>, label: u32) -> Result<Self> {
+        let doorbell = device.doorbell().try_clone()?;
+        set_nonblocking(doorbell.as_raw_fd()).context("Failed to make doorbell non-blocking")?;
+        Ok(Self {
+            doorbell,
+            peer,
+            label,
+        })
+    }
+}
+
+impl DeviceTask for DoorbellRelay {
+    fn debug_label(&self) -> String {
+        format!("ivshmem {} doorbell", self.label)
+    }
+
+    fn fds(&self) -> Vec<RawFd> {
+        vec![self.doorbell.as_raw_fd()]
+    }
+
+    fn poll(&mut self, _now: Instant) -> Result<TaskPoll> {
+        let mut count = [0u8; 8];
+        match self.doorbell.read_exact(&mut count) {
+            Ok(()) => self.peer.trigger()?,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => return Err(e).context("Failed to read doorbell"),
+        }
+        Ok(TaskPoll::Pending(None))
+    }
+}
+
+impl BusDevice for Ivshmem {
+    fn debug_label(&self) -> String {
+        format!("ivshmem@{:x}", self.base)
+    }
+
+    fn read(&mut self, _access: BusAccessInfo, data: &mut [u8]) -> Result<()> {
+        data.fill(0);
+        Ok(())
+    }
+
+    fn write(&mut self, access: BusAccessInfo, _data: &[u8]) -> Result<()> {
+        // 4 byte writes go straight to the eventfd, this is only reached for other sizes
+        if access.offset == IVSHMEM_DOORBELL {
+            (&mut self.doorbell.as_file()).write_all(&1u64.to_ne_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn device_config(&self, fdt: &mut FdtWriter) -> Result<()> {
+        let memory = self.memory.lock().unwrap();
+        let node = fdt.begin_node(&format!("shmem@{:x}", self.base))?;
+        fdt.property_string("compatible", "gunyah-vmm,ivshmem")?;
+        fdt.property_array_u64(
+            "reg",
+            &[
+                self.base,
+                IVSHMEM_MMIO_SIZE,
+                memory.guest_address(),
+                memory.as_region().size().try_into()?,
+            ],
+        )?;
+        fdt.property_string_list("reg-names", vec!["doorbell".into(), "shmem".into()])?;
+        fdt.property_array_u32("interrupts", &self.irq.fdt_config())?;
+        if let Some(label) = self.label {
+            fdt.property_u32("label", label)?;
+        }
+        fdt.end_node(node)?;
+        Ok(())
+    }
+}
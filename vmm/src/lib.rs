@@ -7,11 +7,93 @@ mod bus;
 pub use bus::*;
 mod memory;
 pub use memory::*;
+mod snapshot;
+pub use snapshot::*;
 mod virtual_machine;
 pub use virtual_machine::*;
 mod vcpu;
 pub use vcpu::*;
+mod vm_exit;
+pub use vm_exit::*;
+mod vm_state;
+pub use vm_state::*;
 mod interrupt;
 pub use interrupt::*;
+mod gdb;
+pub use gdb::*;
+mod api;
+pub use api::*;
+mod monitor;
+pub use monitor::*;
+mod fdt_builder;
+pub use fdt_builder::*;
+mod fdt_reader;
+pub use fdt_reader::*;
+mod fdt_overlay;
+pub use fdt_overlay::*;
+mod debug;
+pub use debug::*;
+mod debug_log;
+pub use debug_log::*;
+mod topology;
+pub use topology::*;
+mod vcpu_sched;
+pub use vcpu_sched::*;
+mod numa;
+pub use numa::*;
+mod hotplug;
+pub use hotplug::*;
+mod mmio_trace;
+pub use mmio_trace::*;
+mod executor;
+pub use executor::*;
+mod io_engine;
+pub use io_engine::*;
+mod metrics;
+pub use metrics::*;
+mod debug_exit;
+mod fast_write;
+pub use debug_exit::*;
+mod irq_gen;
+pub use irq_gen::*;
+mod msi;
+pub use msi::*;
+mod ramoops;
+pub use ramoops::*;
+mod restricted_dma;
+pub use restricted_dma::*;
+mod rm_console;
+pub use rm_console::*;
+mod message_queue;
+pub use message_queue::*;
+mod ivshmem;
+pub use ivshmem::*;
+mod retry;
+pub use retry::*;
+mod seccomp;
+pub use seccomp::*;
+mod holding_cell;
+pub use holding_cell::*;
+mod virtio;
+pub use virtio::*;
+mod virtio_balloon;
+pub use virtio_balloon::*;
+mod vhost_user;
+pub use vhost_user::*;
+mod virtio_fs;
+pub use virtio_fs::*;
+mod virtio_gpu;
+pub use virtio_gpu::*;
+mod virtio_input;
+pub use virtio_input::*;
+mod virtio_iommu;
+pub use virtio_iommu::*;
+mod virtio_pmem;
+pub use virtio_pmem::*;
+mod virtio_9p;
+pub use virtio_9p::*;
 
 mod unsafe_read;
+
+#[cfg(test)]
+mod test_util;
@@ -1,9 +1,12 @@
 // Copyright (c) 2024, Qualcomm Innovation Center, Inc. All rights reserved.
 // SPDX-License-Identifier: BSD-3-Clause-Clear
 
-use std::{num::NonZeroUsize, ops::DerefMut};
+use std::{
+    io::{Read, Write},
+    sync::{Arc, Mutex},
+};
 
-use gunyah::{GuestMemRegion, GuestMemoryAccess, ShareType, Vm};
+use gunyah::{GuestMemRegion, GuestMemoryAccess, MmapMut, ShareType, Vm};
 
 use crate::{
     AccessId::{Vcpu, VmmUserspace},
@@ -19,6 +22,13 @@ pub struct GunyahGuestMemoryRegion {
     guest_access: GuestMemoryAccess,
     unmap_on_drop: bool,
     regular_memory: bool,
+    /// The whole region mapped into the VMM for its accesses, created on the first one. Pages
+    /// are only faulted in as they are accessed.
+    mapping: Option<MmapMut>,
+    /// Whether the memory is lent to a running guest, so the VMM can't access it
+    guest_owned: bool,
+    /// Whether the guest has started using the region, see [`Self::unmap_from_vmm`]
+    guest_started: bool,
 }
 
 impl GunyahGuestMemoryRegion {
@@ -41,6 +51,9 @@ impl GunyahGuestMemoryRegion {
             guest_access,
             unmap_on_drop,
             regular_memory,
+            mapping: None,
+            guest_owned: false,
+            guest_started: false,
         })
     }
 
@@ -52,6 +65,41 @@ impl GunyahGuestMemoryRegion {
         self.guest_address
     }
 
+    pub fn share_type(&self) -> ShareType {
+        self.share_type
+    }
+
+    pub fn guest_access(&self) -> GuestMemoryAccess {
+        self.guest_access
+    }
+
+    /// Whether the region is described to the guest as regular memory.
+    pub fn is_regular_memory(&self) -> bool {
+        self.regular_memory
+    }
+
+    /// Whether the region is lent to the running guest, so the VMM can't access it.
+    pub fn is_guest_owned(&self) -> bool {
+        self.guest_owned
+    }
+
+    /// Frees the backing memory of `len` bytes at `offset` without changing the guest's mapping.
+    /// The guest reads zeroes from the range afterwards.
+    pub fn discard(&self, offset: u64, len: usize) -> Result<()> {
+        if offset + len as u64 > self.region.size() as u64 {
+            return Err(anyhow!(
+                "{:#x}+{:#x} is outside the {:#x} byte region",
+                offset,
+                len,
+                self.region.size()
+            ));
+        }
+        self.region
+            .as_guest_mem()
+            .punch_hole((self.region.offset() + offset).try_into()?, len.try_into()?)
+            .context("Failed to free guest memory")
+    }
+
     pub fn punch_hole(&mut self, offset: u64, len: usize) -> Result<Vec<GunyahGuestMemoryRegion>> {
         let mut vec = Vec::new();
 
@@ -68,6 +116,9 @@ impl GunyahGuestMemoryRegion {
                 guest_access: self.guest_access,
                 unmap_on_drop: self.unmap_on_drop,
                 regular_memory: self.regular_memory,
+                mapping: None,
+                guest_owned: self.guest_owned,
+                guest_started: self.guest_started,
             });
         }
 
@@ -86,6 +137,9 @@ impl GunyahGuestMemoryRegion {
                 guest_access: self.guest_access,
                 unmap_on_drop: self.unmap_on_drop,
                 regular_memory: self.regular_memory,
+                mapping: None,
+                guest_owned: self.guest_owned,
+                guest_started: self.guest_started,
             })
         }
 
@@ -99,9 +153,153 @@ impl GunyahGuestMemoryRegion {
         )?;
 
         self.unmap_on_drop = false;
+        self.mapping = None;
 
         Ok(vec)
     }
+
+    /// Switches the region between shared with and lent to the guest while the VM exists, by
+    /// unmapping it from the guest and mapping it again with `share_type`, as the kernel can't
+    /// convert a mapping in place. The guest mustn't access the region meanwhile. Lent memory can
+    /// only be taken back once the guest has relinquished it. How the region is described to the
+    /// guest doesn't change.
+    pub fn convert(&mut self, share_type: ShareType) -> Result<()> {
+        if share_type == self.share_type {
+            return Ok(());
+        }
+        if cfg!(feature = "ack-bindings") {
+            return Err(anyhow!(
+                "Memory can't be unmapped from the guest with the Android bindings"
+            ));
+        }
+        self.vm
+            .unmap_memory(
+                self.guest_address,
+                self.share_type,
+                self.guest_access,
+                &self.region,
+            )
+            .context(format!(
+                "Failed to unmap memory at {:#x} from the guest",
+                self.guest_address
+            ))?;
+        self.mapping = None;
+        if let Err(e) = self.vm.map_memory(
+            self.guest_address,
+            share_type,
+            self.guest_access,
+            &self.region,
+        ) {
+            // Leave the region as it was rather than missing from the guest
+            self.vm
+                .map_memory(
+                    self.guest_address,
+                    self.share_type,
+                    self.guest_access,
+                    &self.region,
+                )
+                .context(format!(
+                    "Failed to map memory at {:#x} again after failing to convert it: {}",
+                    self.guest_address, e
+                ))?;
+            return Err(anyhow!(e).context(format!(
+                "Failed to map memory at {:#x} into the guest as {:?}",
+                self.guest_address, share_type
+            )));
+        }
+        self.share_type = share_type;
+        self.guest_owned = self.guest_started && share_type == ShareType::Lend;
+        Ok(())
+    }
+
+    /// Reads `len` bytes from `file` straight into the region at `offset`, without a buffer in
+    /// between.
+    pub fn read_from(&mut self, file: &mut impl Read, offset: u64, len: usize) -> Result<()> {
+        let dst = self.mapped(offset, len)?;
+        file.read_exact(dst)
+            .context("Failed to read into guest memory")
+    }
+
+    /// Makes `len` bytes at `offset` of the region, which must be page aligned, come from host
+    /// NUMA node `node`. Pages allocated before are moved.
+    pub fn bind_host_numa_node(&mut self, offset: u64, len: usize, node: u32) -> Result<()> {
+        let mem = self.mapped(offset, len)?;
+        crate::numa::bind_to_host_node(mem, node).context(format!(
+            "Failed to bind guest memory to host NUMA node {}",
+            node
+        ))
+    }
+
+    /// Drops the VMM's mapping of the region, the next access maps it again. Called as the guest
+    /// starts using the region, which is the guest's from then on if it is lent.
+    pub(crate) fn unmap_from_vmm(&mut self) {
+        self.mapping = None;
+        self.guest_owned = self.share_type == ShareType::Lend;
+        self.guest_started = true;
+    }
+
+    /// Writes `len` bytes at `offset` of the region to `writer`.
+    pub fn write_to(&mut self, writer: &mut impl Write, offset: u64, len: usize) -> Result<()> {
+        if self.guest_owned {
+            return Err(anyhow!(
+                "Memory at {:#x} is lent to the guest, the VMM can't read it",
+                self.guest_address
+            ));
+        }
+        let src = self.mapped(offset, len)?;
+        writer
+            .write_all(src)
+            .context("Failed to write guest memory")
+    }
+
+    /// Returns `len` bytes at `offset` of the VMM's mapping of the region.
+    fn mapped(&mut self, offset: u64, len: usize) -> Result<&mut [u8]> {
+        if self.mapping.is_none() {
+            self.mapping = Some(
+                self.region
+                    .map_mut()
+                    .context("Failed to map guest memory")?,
+            );
+        }
+        let start = usize::try_from(offset)?;
+        self.mapping
+            .as_mut()
+            .unwrap()
+            .get_mut(start..start + len)
+            .ok_or(anyhow!(
+                "{:#x}+{:#x} is outside the {:#x} byte region",
+                offset,
+                len,
+                self.region.size()
+            ))
+    }
+}
+
+/// Writes `len` bytes of guest memory at `address`, which may span adjacent `regions`, to
+/// `writer`.
+pub(crate) fn dump_memory(
+    regions: &[Arc<Mutex<GunyahGuestMemoryRegion>>],
+    address: u64,
+    len: u64,
+    writer: &mut impl Write,
+) -> Result<()> {
+    let end = address + len;
+    let mut next = address;
+    while next < end {
+        let mut region = regions
+            .iter()
+            .map(|region| region.lock().unwrap())
+            .find(|region| {
+                (region.guest_address..region.guest_address + region.region.size() as u64)
+                    .contains(&next)
+            })
+            .ok_or(anyhow!("No guest memory at {:#x}", next))?;
+        let offset = next - region.guest_address;
+        let chunk = (end - next).min(region.region.size() as u64 - offset);
+        region.write_to(writer, offset, chunk.try_into()?)?;
+        next += chunk;
+    }
+    Ok(())
 }
 
 impl Drop for GunyahGuestMemoryRegion {
@@ -127,14 +325,9 @@ impl BusDevice for GunyahGuestMemoryRegion {
     fn read(&mut self, access: crate::BusAccessInfo, data: &mut [u8]) -> anyhow::Result<()> {
         match access.id {
             VmmUserspace => {
-                let src = self.region.map_region(
-                    access.offset,
-                    NonZeroUsize::new(data.len()).ok_or(anyhow!("data length was zero"))?,
-                )?;
-
-                crate::unsafe_read::cautious_memcpy(data, &src)
+                let src = self.mapped(access.offset, data.len())?;
+                crate::unsafe_read::cautious_memcpy(data, src)
                     .or(Err(anyhow!("unable to read memory")))?;
-
                 Ok(())
             }
             Vcpu(_) => todo!(),
@@ -144,11 +337,8 @@ impl BusDevice for GunyahGuestMemoryRegion {
     fn write(&mut self, access: crate::BusAccessInfo, data: &[u8]) -> anyhow::Result<()> {
         match access.id {
             VmmUserspace => {
-                let mut src = self.region.map_region_mut(
-                    access.offset,
-                    NonZeroUsize::new(data.len()).ok_or(anyhow!("data length was zero"))?,
-                )?;
-                crate::unsafe_read::cautious_memcpy(src.deref_mut(), data)
+                let dst = self.mapped(access.offset, data.len())?;
+                crate::unsafe_read::cautious_memcpy(dst, data)
                     .or(Err(anyhow!("unable to write memory")))?;
                 Ok(())
             }
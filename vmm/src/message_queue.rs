@@ -0,0 +1,176 @@
+// Copyright (c) 2024, Qualcomm Innovation Center, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause-Clear
+
+//! Gunyah message queues between the VM and the host, see
+//! [`crate::GunyahVirtualMachine::add_message_queue`].
+//!
+//! The Resource Manager creates a message queue for each `message-queue` vdevice in the VM's
+//! configuration, with the host's VM as its peer. Neither the mainline nor the Android kernel's
+//! VM interface hands message queues to userspace, so the host's end belongs to host kernel
+//! drivers, which find it by its label; the VMM only asks for the queue.
+
+use std::str::FromStr;
+
+use anyhow::{anyhow, Context, Result};
+use vm_fdt::FdtWriter;
+
+/// Largest message the hypervisor passes in one message queue call
+pub const MESSAGE_QUEUE_MAX_MESSAGE_SIZE: u32 = 240;
+
+/// Which way a message queue carries messages.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MessageQueueDirection {
+    /// The guest sends, the host receives
+    ToHost,
+    /// The host sends, the guest receives
+    ToGuest,
+}
+
+/// A message queue between the VM and the host.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MessageQueueConfig {
+    /// Identifies the queue to the drivers on both ends
+    pub label: u32,
+    pub direction: MessageQueueDirection,
+    pub message_size: u32,
+    /// Number of messages the queue holds
+    pub queue_depth: u32,
+}
+
+impl FromStr for MessageQueueConfig {
+    type Err = anyhow::Error;
+
+    /// Parses `LABEL,to-host|to-guest[,SIZE,DEPTH]`, with messages of up to 240 bytes and 8 of
+    /// them queued by default.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split(',').collect();
+        let (label, direction, size, depth) = match fields[..] {
+            [label, direction] => (label, direction, None, None),
+            [label, direction, size, depth] => (label, direction, Some(size), Some(depth)),
+            _ => {
+                return Err(anyhow!(
+                    "Invalid {:?}, expected LABEL,to-host|to-guest[,SIZE,DEPTH]",
+                    s
+                ))
+            }
+        };
+        let direction = match direction {
+            "to-host" => MessageQueueDirection::ToHost,
+            "to-guest" => MessageQueueDirection::ToGuest,
+            _ => {
+                return Err(anyhow!(
+                    "Unknown direction {:?} in {:?}, expected to-host or to-guest",
+                    direction,
+                    s
+                ))
+            }
+        };
+        let number = |field: Option<&str>, default: u32| -> Result<u32> {
+            field.map_or(Ok(default), |field| {
+                field
+                    .parse()
+                    .with_context(|| format!("Invalid number {:?} in {:?}", field, s))
+            })
+        };
+        let config = Self {
+            label: number(Some(label), 0)?,
+            direction,
+            message_size: number(size, MESSAGE_QUEUE_MAX_MESSAGE_SIZE)?,
+            queue_depth: number(depth, 8)?,
+        };
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+impl MessageQueueConfig {
+    pub(crate) fn validate(&self) -> Result<()> {
+        if self.message_size == 0 || self.message_size > MESSAGE_QUEUE_MAX_MESSAGE_SIZE {
+            return Err(anyhow!(
+                "Message size {} is not between 1 and {} bytes",
+                self.message_size,
+                MESSAGE_QUEUE_MAX_MESSAGE_SIZE
+            ));
+        }
+        if self.queue_depth == 0 {
+            return Err(anyhow!("Message queue {} holds no messages", self.label));
+        }
+        Ok(())
+    }
+
+    /// Writes the vdevice asking the Resource Manager for the queue.
+    pub(crate) fn generate_vdevice(&self, fdt: &mut FdtWriter) -> Result<(), vm_fdt::Error> {
+        let name = format!("msgq-{:x}", self.label);
+        let msgq_node = fdt.begin_node(&name)?;
+        fdt.property_string("vdevice-type", "message-queue")?;
+        fdt.property_string("generate", &format!("/hypervisor/{}", name))?;
+        fdt.property_u32("label", self.label)?;
+        fdt.property_null("peer-default")?;
+        fdt.property_null(match self.direction {
+            MessageQueueDirection::ToHost => "is-sender",
+            MessageQueueDirection::ToGuest => "is-receiver",
+        })?;
+        fdt.property_u32("message-size", self.message_size)?;
+        fdt.property_u32("queue-depth", self.queue_depth)?;
+        fdt.end_node(msgq_node)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use claim::{assert_err, assert_ok};
+    use vm_fdt::FdtWriter;
+
+    use super::{MessageQueueConfig, MessageQueueDirection};
+    use crate::parse_fdt;
+
+    #[test]
+    fn parse() {
+        let config = assert_ok!(MessageQueueConfig::from_str("3,to-host"));
+        assert_eq!(
+            config,
+            MessageQueueConfig {
+                label: 3,
+                direction: MessageQueueDirection::ToHost,
+                message_size: 240,
+                queue_depth: 8,
+            }
+        );
+        let config = assert_ok!(MessageQueueConfig::from_str("4,to-guest,64,16"));
+        assert_eq!(config.message_size, 64);
+        assert_eq!(config.queue_depth, 16);
+
+        assert_err!(MessageQueueConfig::from_str("3"));
+        assert_err!(MessageQueueConfig::from_str("3,sideways"));
+        assert_err!(MessageQueueConfig::from_str("3,to-host,241,16"));
+        assert_err!(MessageQueueConfig::from_str("3,to-host,64,0"));
+        assert_err!(MessageQueueConfig::from_str("3,to-host,64"));
+    }
+
+    #[test]
+    fn vdevice() {
+        let config = assert_ok!(MessageQueueConfig::from_str("10,to-guest"));
+        let mut fdt = assert_ok!(FdtWriter::new());
+        let root = assert_ok!(fdt.begin_node(""));
+        assert_ok!(config.generate_vdevice(&mut fdt));
+        assert_ok!(fdt.end_node(root));
+        let blob = assert_ok!(fdt.finish());
+        let fdt = assert_ok!(parse_fdt(&blob));
+
+        assert_eq!(
+            fdt.prop_str("/msgq-a", "vdevice-type"),
+            Some("message-queue")
+        );
+        assert_eq!(
+            fdt.prop_str("/msgq-a", "generate"),
+            Some("/hypervisor/msgq-a")
+        );
+        assert_eq!(fdt.prop_u32("/msgq-a", "label"), Some(10));
+        assert!(fdt.has_prop("/msgq-a", "is-receiver"));
+        assert!(!fdt.has_prop("/msgq-a", "is-sender"));
+        assert_eq!(fdt.prop_u32("/msgq-a", "queue-depth"), Some(8));
+    }
+}
@@ -0,0 +1,533 @@
+// Copyright (c) 2024, Qualcomm Innovation Center, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause-Clear
+
+//! Counters of a running VM, for the trends of long runs like soak tests rather than their
+//! outcome: vCPU exits by reason, interrupts injected, memory mapped into and punched out of the
+//! VM, and MMIO accesses to each device.
+//!
+//! [`MetricsServer`] serves them in Prometheus' text format over HTTP at `/metrics`, and the
+//! control socket's `query-metrics` command returns them as JSON, see [`crate::ApiServer`].
+
+use std::{
+    fmt::Write as _,
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    os::fd::{AsRawFd, RawFd},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use serde_json::{json, Map, Value};
+
+use crate::{
+    Bus, BusDeviceStats, DeviceExecutor, DeviceTask, ExitStats, GunyahInterrupt, GunyahVcpu,
+    GunyahVirtualMachine, TaskPoll, VcpuStats,
+};
+
+/// Largest HTTP request read, clients sending more are disconnected
+const MAX_REQUEST_SIZE: usize = 0x2000;
+/// How long a client which doesn't read its response is waited for before writing again.
+const WRITE_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Memory mapped into and punched out of a VM, in bytes.
+#[derive(Debug, Default)]
+pub(crate) struct MemoryCounters {
+    mapped: AtomicU64,
+    punched: AtomicU64,
+}
+
+impl MemoryCounters {
+    pub(crate) fn map(&self, len: u64) {
+        self.mapped.fetch_add(len, Ordering::Relaxed);
+    }
+
+    pub(crate) fn punch(&self, len: u64) {
+        self.punched.fetch_add(len, Ordering::Relaxed);
+    }
+}
+
+/// Reads the counters of a VM, see [`GunyahVirtualMachine::metrics`]. vCPUs and interrupts added
+/// later are included.
+#[derive(Clone, Default)]
+pub struct VmMetrics {
+    vcpus: Arc<RwLock<Vec<Arc<GunyahVcpu>>>>,
+    interrupts: Arc<RwLock<Vec<Arc<GunyahInterrupt>>>>,
+    bus: Bus,
+    memory: Arc<MemoryCounters>,
+}
+
+impl VmMetrics {
+    pub(crate) fn new(
+        vcpus: Arc<RwLock<Vec<Arc<GunyahVcpu>>>>,
+        interrupts: Arc<RwLock<Vec<Arc<GunyahInterrupt>>>>,
+        bus: Bus,
+        memory: Arc<MemoryCounters>,
+    ) -> Self {
+        Self {
+            vcpus,
+            interrupts,
+            bus,
+            memory,
+        }
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            vcpus: self
+                .vcpus
+                .read()
+                .unwrap()
+                .iter()
+                .map(|vcpu| (vcpu.id(), vcpu.stats()))
+                .collect(),
+            interrupts: self
+                .interrupts
+                .read()
+                .unwrap()
+                .iter()
+                .map(|interrupt| (interrupt.line(), interrupt.triggers()))
+                .collect(),
+            memory_mapped: self.memory.mapped.load(Ordering::Relaxed),
+            memory_punched: self.memory.punched.load(Ordering::Relaxed),
+            devices: self.bus.stats(),
+        }
+    }
+}
+
+/// The counters of a VM at one point in time.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    /// Exits of each vCPU, by vCPU ID
+    pub vcpus: Vec<(u32, VcpuStats)>,
+    /// How often each SPI was triggered, by line. Asserted level interrupts count every time
+    /// they are triggered again, see [`GunyahInterrupt::set_level`].
+    pub interrupts: Vec<(u32, u64)>,
+    /// Bytes of memory mapped into the VM, including memory added once it runs
+    pub memory_mapped: u64,
+    /// Bytes of memory removed from the VM again, e.g. by offlining or breakpoints
+    pub memory_punched: u64,
+    /// Accesses of vCPUs to each device on the bus
+    pub devices: Vec<BusDeviceStats>,
+}
+
+impl MetricsSnapshot {
+    /// The snapshot as returned by the control socket's `query-metrics` command.
+    pub fn to_json(&self) -> Value {
+        let vcpus: Vec<Value> = self
+            .vcpus
+            .iter()
+            .map(|(id, stats)| {
+                let mut exits = Map::new();
+                exits.insert("id".to_string(), json!(id));
+                for (reason, stats) in stats.by_reason() {
+                    exits.insert(
+                        reason.to_string(),
+                        json!({ "count": stats.count, "time_ns": stats.time.as_nanos() }),
+                    );
+                }
+                Value::Object(exits)
+            })
+            .collect();
+        let interrupts: Vec<Value> = self
+            .interrupts
+            .iter()
+            .map(|(line, triggers)| json!({ "line": line, "triggers": triggers }))
+            .collect();
+        let devices: Vec<Value> = self
+            .devices
+            .iter()
+            .map(|device| {
+                json!({
+                    "label": device.label,
+                    "base": device.base,
+                    "reads": device.reads,
+                    "read_bytes": device.read_bytes,
+                    "writes": device.writes,
+                    "written_bytes": device.written_bytes,
+                })
+            })
+            .collect();
+        json!({
+            "vcpus": vcpus,
+            "interrupts": interrupts,
+            "memory": { "mapped": self.memory_mapped, "punched": self.memory_punched },
+            "devices": devices,
+        })
+    }
+
+    /// The snapshot in Prometheus' text exposition format.
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        let mut family = |name: &str, help: &str, samples: Vec<(String, String)>| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            for (labels, value) in samples {
+                let _ = writeln!(out, "{}{} {}", name, labels, value);
+            }
+        };
+
+        let exits = |value: &dyn Fn(&ExitStats) -> String| {
+            self.vcpus
+                .iter()
+                .flat_map(|(id, stats)| {
+                    stats.by_reason().map(|(reason, stats)| {
+                        (
+                            labels(&[("vcpu", &id.to_string()), ("reason", reason)]),
+                            value(&stats),
+                        )
+                    })
+                })
+                .collect()
+        };
+        family(
+            "gunyah_vcpu_exits_total",
+            "Exits of vCPUs to the VMM.",
+            exits(&|stats| stats.count.to_string()),
+        );
+        family(
+            "gunyah_vcpu_exit_seconds_total",
+            "Time the VMM took handling exits of vCPUs.",
+            exits(&|stats| stats.time.as_secs_f64().to_string()),
+        );
+        family(
+            "gunyah_interrupts_injected_total",
+            "Interrupts the VMM triggered.",
+            self.interrupts
+                .iter()
+                .map(|(line, triggers)| {
+                    (labels(&[("line", &line.to_string())]), triggers.to_string())
+                })
+                .collect(),
+        );
+        family(
+            "gunyah_memory_mapped_bytes_total",
+            "Memory mapped into the VM.",
+            vec![(String::new(), self.memory_mapped.to_string())],
+        );
+        family(
+            "gunyah_memory_punched_bytes_total",
+            "Memory removed from the VM.",
+            vec![(String::new(), self.memory_punched.to_string())],
+        );
+
+        let devices = |value: &dyn Fn(&BusDeviceStats) -> u64| {
+            self.devices
+                .iter()
+                .map(|device| {
+                    (
+                        labels(&[
+                            ("device", &device.label),
+                            ("base", &format!("{:#x}", device.base)),
+                        ]),
+                        value(device).to_string(),
+                    )
+                })
+                .collect()
+        };
+        family(
+            "gunyah_mmio_reads_total",
+            "MMIO reads of vCPUs from each device.",
+            devices(&|device| device.reads),
+        );
+        family(
+            "gunyah_mmio_read_bytes_total",
+            "Bytes vCPUs read from each device with MMIO.",
+            devices(&|device| device.read_bytes),
+        );
+        family(
+            "gunyah_mmio_writes_total",
+            "MMIO writes of vCPUs to each device.",
+            devices(&|device| device.writes),
+        );
+        family(
+            "gunyah_mmio_written_bytes_total",
+            "Bytes vCPUs wrote to each device with MMIO.",
+            devices(&|device| device.written_bytes),
+        );
+        out
+    }
+}
+
+/// Formats Prometheus labels, escaping their values.
+fn labels(labels: &[(&str, &str)]) -> String {
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(name, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", name, value)
+        })
+        .collect();
+    format!("{{{}}}", labels.join(","))
+}
+
+/// Serves the metrics of a VM over HTTP.
+pub struct MetricsServer {
+    executor: DeviceExecutor,
+    metrics: VmMetrics,
+}
+
+impl MetricsServer {
+    pub fn new(vm: &GunyahVirtualMachine) -> Self {
+        Self {
+            executor: vm.executor(),
+            metrics: vm.metrics(),
+        }
+    }
+
+    /// Serves clients at `addr` until the VM exits and returns the address listened on, which
+    /// tells the port picked for port 0.
+    pub fn listen(self, addr: SocketAddr) -> Result<SocketAddr> {
+        let listener = TcpListener::bind(addr).context(format!("Failed to listen on {}", addr))?;
+        listener.set_nonblocking(true)?;
+        let addr = listener.local_addr()?;
+        let executor = self.executor.clone();
+        executor.spawn(Box::new(MetricsListenTask {
+            server: Arc::new(self),
+            listener,
+            addr,
+        }));
+        Ok(addr)
+    }
+}
+
+/// Accepts HTTP clients of a [`MetricsServer`].
+struct MetricsListenTask {
+    server: Arc<MetricsServer>,
+    listener: TcpListener,
+    addr: SocketAddr,
+}
+
+impl DeviceTask for MetricsListenTask {
+    fn debug_label(&self) -> String {
+        format!("metrics server {}", self.addr)
+    }
+
+    fn fds(&self) -> Vec<RawFd> {
+        vec![self.listener.as_raw_fd()]
+    }
+
+    fn poll(&mut self, _now: Instant) -> Result<TaskPoll> {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    stream.set_nonblocking(true)?;
+                    self.server.executor.spawn(Box::new(MetricsClientTask {
+                        metrics: self.server.metrics.clone(),
+                        stream,
+                        request: Vec::new(),
+                        response: None,
+                    }));
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return Ok(TaskPoll::Pending(None))
+                }
+                Err(e) => return Err(e).context("Failed to accept metrics connection"),
+            }
+        }
+    }
+}
+
+/// Answers the request of one HTTP client, then closes the connection.
+struct MetricsClientTask {
+    metrics: VmMetrics,
+    stream: TcpStream,
+    /// The request headers received so far
+    request: Vec<u8>,
+    /// Remainder of the response once the request arrived
+    response: Option<Vec<u8>>,
+}
+
+impl DeviceTask for MetricsClientTask {
+    fn debug_label(&self) -> String {
+        "metrics connection".to_string()
+    }
+
+    fn fds(&self) -> Vec<RawFd> {
+        vec![self.stream.as_raw_fd()]
+    }
+
+    fn poll(&mut self, _now: Instant) -> Result<TaskPoll> {
+        let mut buf = [0u8; 1024];
+        while self.response.is_none() {
+            match self.stream.read(&mut buf) {
+                Ok(0) => return Ok(TaskPoll::Done),
+                Ok(len) => {
+                    self.request.extend_from_slice(&buf[..len]);
+                    if self.request.windows(4).any(|end| end == b"\r\n\r\n") {
+                        let request = String::from_utf8_lossy(&self.request);
+                        self.response =
+                            Some(http_response(request.lines().next().unwrap_or(""), || {
+                                self.metrics.snapshot()
+                            }));
+                    } else if self.request.len() > MAX_REQUEST_SIZE {
+                        return Ok(TaskPoll::Done);
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return Ok(TaskPoll::Pending(None))
+                }
+                // A client that went away doesn't concern the VM
+                Err(_) => return Ok(TaskPoll::Done),
+            }
+        }
+        let response = self.response.as_mut().unwrap();
+        while !response.is_empty() {
+            match self.stream.write(response) {
+                Ok(len) => {
+                    response.drain(..len);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return Ok(TaskPoll::Pending(Some(
+                        Instant::now() + WRITE_RETRY_INTERVAL,
+                    )))
+                }
+                Err(_) => return Ok(TaskPoll::Done),
+            }
+        }
+        Ok(TaskPoll::Done)
+    }
+}
+
+/// The response to the HTTP request starting with `request_line`.
+fn http_response(request_line: &str, snapshot: impl FnOnce() -> MetricsSnapshot) -> Vec<u8> {
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", snapshot().to_prometheus()),
+        (Some("GET"), Some(_)) => ("404 Not Found", "Metrics are at /metrics\n".to_string()),
+        _ => ("405 Method Not Allowed", String::new()),
+    };
+    format!(
+        "HTTP/1.1 {}\r\n\
+         Content-Type: text/plain; version=0.0.4\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\
+         \r\n\
+         {}",
+        status,
+        body.len(),
+        body
+    )
+    .into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpStream,
+        time::Duration,
+    };
+
+    use claim::assert_ok;
+    use serde_json::json;
+
+    use super::{http_response, labels, MetricsServer, MetricsSnapshot, VmMetrics};
+    use crate::{BusDeviceStats, DeviceExecutor, ExitStats, VcpuStats, VmExit, VmExitRequest};
+
+    fn snapshot() -> MetricsSnapshot {
+        MetricsSnapshot {
+            vcpus: vec![(
+                1,
+                VcpuStats {
+                    mmio: ExitStats {
+                        count: 12,
+                        time: Duration::from_micros(1500),
+                    },
+                    ..Default::default()
+                },
+            )],
+            interrupts: vec![(5, 3)],
+            memory_mapped: 0x20_0000,
+            memory_punched: 0x1000,
+            devices: vec![BusDeviceStats {
+                label: "pl011 \"uart\"".to_string(),
+                base: 0x900_0000,
+                len: 0x1000,
+                reads: 2,
+                read_bytes: 8,
+                writes: 1,
+                written_bytes: 4,
+            }],
+        }
+    }
+
+    #[test]
+    fn prometheus() {
+        let text = snapshot().to_prometheus();
+        for line in [
+            "# TYPE gunyah_vcpu_exits_total counter",
+            "gunyah_vcpu_exits_total{vcpu=\"1\",reason=\"mmio\"} 12",
+            "gunyah_vcpu_exits_total{vcpu=\"1\",reason=\"status\"} 0",
+            "gunyah_vcpu_exit_seconds_total{vcpu=\"1\",reason=\"mmio\"} 0.0015",
+            "gunyah_interrupts_injected_total{line=\"5\"} 3",
+            "gunyah_memory_mapped_bytes_total 2097152",
+            "gunyah_memory_punched_bytes_total 4096",
+            "gunyah_mmio_read_bytes_total{device=\"pl011 \\\"uart\\\"\",base=\"0x9000000\"} 8",
+            "gunyah_mmio_writes_total{device=\"pl011 \\\"uart\\\"\",base=\"0x9000000\"} 1",
+        ] {
+            assert!(
+                text.lines().any(|l| l == line),
+                "{} missing from\n{}",
+                line,
+                text
+            );
+        }
+        assert_eq!(labels(&[("a", "x\\y\nz")]), "{a=\"x\\\\y\\nz\"}");
+    }
+
+    #[test]
+    fn json() {
+        let json = snapshot().to_json();
+        assert_eq!(json["vcpus"][0]["id"], json!(1));
+        assert_eq!(
+            json["vcpus"][0]["mmio"],
+            json!({ "count": 12, "time_ns": 1_500_000 })
+        );
+        assert_eq!(json["interrupts"], json!([{ "line": 5, "triggers": 3 }]));
+        assert_eq!(
+            json["memory"],
+            json!({ "mapped": 0x20_0000, "punched": 0x1000 })
+        );
+        assert_eq!(json["devices"][0]["written_bytes"], json!(4));
+    }
+
+    #[test]
+    fn http() {
+        let ok = String::from_utf8(http_response("GET /metrics HTTP/1.1", snapshot)).unwrap();
+        assert!(ok.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(ok.ends_with(&snapshot().to_prometheus()));
+        let not_found = String::from_utf8(http_response("GET / HTTP/1.1", snapshot)).unwrap();
+        assert!(not_found.starts_with("HTTP/1.1 404 "));
+        let post = String::from_utf8(http_response("POST /metrics HTTP/1.1", snapshot)).unwrap();
+        assert!(post.starts_with("HTTP/1.1 405 "));
+    }
+
+    #[test]
+    fn server() {
+        let executor = DeviceExecutor::default();
+        let exit = VmExitRequest::default();
+        let runner = executor.run(exit.clone());
+        let server = MetricsServer {
+            executor,
+            metrics: VmMetrics::default(),
+        };
+        let addr = assert_ok!(server.listen("127.0.0.1:0".parse().unwrap()));
+
+        let mut client = assert_ok!(TcpStream::connect(addr));
+        assert_ok!(client.write_all(b"GET /metrics HTTP/1.1\r\nHost: vmm\r\n\r\n"));
+        let mut response = String::new();
+        assert_ok!(client.read_to_string(&mut response));
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("\ngunyah_memory_mapped_bytes_total 0\n"));
+
+        exit.request(VmExit::Poweroff);
+        runner.join().unwrap();
+    }
+}
@@ -0,0 +1,241 @@
+// Copyright (c) 2024, Qualcomm Innovation Center, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause-Clear
+
+//! Tracing of the MMIO accesses vCPUs make to devices on the [`crate::Bus`].
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MmioDirection {
+    Read,
+    Write,
+}
+
+/// One MMIO access, as seen by the device after it handled it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MmioTraceRecord {
+    /// Time since tracing started
+    pub timestamp: Duration,
+    pub vcpu: u8,
+    pub direction: MmioDirection,
+    pub address: u64,
+    /// Size of the access in bytes
+    pub width: usize,
+    /// Data read or written, little endian as the guest sees it
+    pub value: u64,
+    /// The device returned an error
+    pub failed: bool,
+}
+
+impl fmt::Display for MmioTraceRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:>12.6} vcpu{} {} {:#x}/{} {:#x}{}",
+            self.timestamp.as_secs_f64(),
+            self.vcpu,
+            match self.direction {
+                MmioDirection::Read => "R",
+                MmioDirection::Write => "W",
+            },
+            self.address,
+            self.width,
+            self.value,
+            if self.failed { " failed" } else { "" }
+        )
+    }
+}
+
+#[derive(Debug)]
+enum TraceSink {
+    Ring {
+        records: VecDeque<MmioTraceRecord>,
+        capacity: usize,
+    },
+    File(BufWriter<File>),
+}
+
+#[derive(Debug)]
+struct TraceState {
+    start: Instant,
+    sink: TraceSink,
+}
+
+/// Where traced accesses go, see [`crate::Bus::set_trace`]. Clones share the same records.
+#[derive(Clone, Debug)]
+pub struct MmioTrace {
+    state: Arc<Mutex<TraceState>>,
+}
+
+impl MmioTrace {
+    fn new(sink: TraceSink) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(TraceState {
+                start: Instant::now(),
+                sink,
+            })),
+        }
+    }
+
+    /// Keeps the last `capacity` accesses in memory, see [`Self::records`].
+    pub fn ring(capacity: usize) -> Self {
+        Self::new(TraceSink::Ring {
+            records: VecDeque::with_capacity(capacity),
+            capacity,
+        })
+    }
+
+    /// Writes every access to `path` as a line of text.
+    pub fn file(path: &Path) -> Result<Self> {
+        let file = File::create(path).context(format!("Failed to create {}", path.display()))?;
+        Ok(Self::new(TraceSink::File(BufWriter::new(file))))
+    }
+
+    pub(crate) fn record(
+        &self,
+        vcpu: u8,
+        direction: MmioDirection,
+        address: u64,
+        data: &[u8],
+        failed: bool,
+    ) {
+        let mut value = [0u8; 8];
+        let len = data.len().min(value.len());
+        value[..len].copy_from_slice(&data[..len]);
+
+        let mut state = self.state.lock().unwrap();
+        let record = MmioTraceRecord {
+            timestamp: state.start.elapsed(),
+            vcpu,
+            direction,
+            address,
+            width: data.len(),
+            value: u64::from_le_bytes(value),
+            failed,
+        };
+        match &mut state.sink {
+            TraceSink::Ring { records, capacity } => {
+                if *capacity == 0 {
+                    return;
+                }
+                if records.len() == *capacity {
+                    records.pop_front();
+                }
+                records.push_back(record);
+            }
+            // Losing trace lines mustn't fail the access
+            TraceSink::File(file) => {
+                let _ = writeln!(file, "{}", record);
+            }
+        }
+    }
+
+    /// Accesses kept by a ring trace, oldest first. Empty for a file trace.
+    pub fn records(&self) -> Vec<MmioTraceRecord> {
+        match &self.state.lock().unwrap().sink {
+            TraceSink::Ring { records, .. } => records.iter().copied().collect(),
+            TraceSink::File(_) => Vec::new(),
+        }
+    }
+
+    /// Writes buffered lines of a file trace out.
+    pub fn flush(&self) -> Result<()> {
+        if let TraceSink::File(file) = &mut self.state.lock().unwrap().sink {
+            file.flush().context("Failed to write MMIO trace")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use claim::{assert_err, assert_ok};
+
+    use super::{MmioDirection, MmioTrace};
+    use crate::{AccessId, Bus, BusAccessInfo, BusDevice};
+
+    struct Register(u32);
+
+    impl BusDevice for Register {
+        fn debug_label(&self) -> String {
+            "register".to_string()
+        }
+
+        fn read(&mut self, _access: BusAccessInfo, data: &mut [u8]) -> anyhow::Result<()> {
+            data.copy_from_slice(&self.0.to_le_bytes()[..data.len()]);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn ring() {
+        let trace = MmioTrace::ring(2);
+        trace.record(0, MmioDirection::Write, 0x3f800, &[0x41], false);
+        trace.record(1, MmioDirection::Read, 0x3f805, &[0x60], false);
+        trace.record(1, MmioDirection::Read, 0x1000, &[1, 2, 3, 4], true);
+
+        let records = trace.records();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].address, 0x3f805);
+        assert_eq!(records[1].vcpu, 1);
+        assert_eq!(records[1].width, 4);
+        assert_eq!(records[1].value, 0x0403_0201);
+        assert!(records[1].failed);
+        assert!(records[1]
+            .to_string()
+            .ends_with("vcpu1 R 0x1000/4 0x4030201 failed"));
+    }
+
+    #[test]
+    fn file() {
+        let path = std::env::temp_dir().join(format!("mmio-trace-{}", std::process::id()));
+        let trace = assert_ok!(MmioTrace::file(&path));
+        trace.record(2, MmioDirection::Write, 0x9000, &[0xff; 16], false);
+        assert_ok!(trace.flush());
+        assert!(trace.records().is_empty());
+
+        let text = assert_ok!(std::fs::read_to_string(&path));
+        assert!(text.ends_with("vcpu2 W 0x9000/16 0xffffffffffffffff\n"));
+        assert_ok!(std::fs::remove_file(&path));
+    }
+
+    #[test]
+    fn bus() {
+        let bus = Bus::new();
+        assert_ok!(bus.insert(Arc::new(Mutex::new(Register(0xabcd))), 0x1000, 4));
+        let trace = MmioTrace::ring(8);
+        bus.set_trace(Some(trace.clone()));
+
+        let vcpu_bus = bus.clone().set_access_id(AccessId::Vcpu(3));
+        let mut data = [0u8; 2];
+        assert_ok!(vcpu_bus.read(0x1000, &mut data));
+        assert_err!(vcpu_bus.write(0x1000, &data));
+        // The VMM's own accesses aren't traced
+        assert_ok!(bus.read(0x1000, &mut data));
+
+        let records = trace.records();
+        assert_eq!(records.len(), 2);
+        assert_eq!(
+            (records[0].vcpu, records[0].direction, records[0].value),
+            (3, MmioDirection::Read, 0xabcd)
+        );
+        assert_eq!(records[1].direction, MmioDirection::Write);
+        assert!(records[1].failed);
+
+        bus.set_trace(None);
+        assert_ok!(vcpu_bus.read(0x1000, &mut data));
+        assert_eq!(trace.records().len(), 2);
+    }
+}
@@ -0,0 +1,263 @@
+// Copyright (c) 2024, Qualcomm Innovation Center, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause-Clear
+
+use std::{
+    fs,
+    io::{self, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use anyhow::{anyhow, Context, Result};
+use gunyah::{GuestMemoryAccess, ShareType};
+
+use crate::{
+    memory, AccessId, Bus, GunyahGuestMemoryRegion, GunyahInterrupt, GunyahVirtualMachine, VmExit,
+    VmExitRequest,
+};
+
+const PROMPT: &str = "(monitor) ";
+
+const HELP: &str = "\
+info status      show whether the VM is running
+info mem         list guest memory regions
+info irq         list interrupts used by devices
+info devices     list devices and how often vCPUs accessed them
+dump-dtb FILE    write the device tree the VM booted with to FILE
+dump-mem ADDR LEN FILE
+                 write LEN bytes of guest memory at ADDR to FILE
+pause            stop the vCPUs
+resume           let paused vCPUs run again
+quit             power off the VM
+";
+
+/// Interactive prompt for inspecting a VM, shared with the guest console's input.
+pub struct Monitor {
+    bus: Bus,
+    exit: VmExitRequest,
+    interrupts: Vec<Arc<GunyahInterrupt>>,
+    memory: Vec<Arc<Mutex<GunyahGuestMemoryRegion>>>,
+    dtb: Option<Vec<u8>>,
+    /// Whether console input goes to the monitor instead of the guest
+    active: AtomicBool,
+}
+
+impl Monitor {
+    /// Creates a monitor for `vm`, which knows about the memory, interrupts and device tree
+    /// added so far.
+    pub fn new(vm: &GunyahVirtualMachine) -> Self {
+        Self {
+            bus: vm.get_bus(AccessId::VmmUserspace),
+            exit: vm.exit_request(),
+            interrupts: vm.interrupts(),
+            memory: vm.memory_regions(),
+            dtb: vm.dtb(),
+            active: AtomicBool::new(false),
+        }
+    }
+
+    /// Whether console input goes to the monitor instead of the guest.
+    pub fn is_active(&self) -> bool {
+        self.active.load(Ordering::SeqCst)
+    }
+
+    /// Switches console input between the guest and the monitor.
+    pub fn toggle(&self) {
+        if self.active.fetch_xor(true, Ordering::SeqCst) {
+            print_flush("Back to the guest console\n");
+        } else {
+            print_flush(&format!("Monitor, type help for commands\n{}", PROMPT));
+        }
+    }
+
+    /// Runs a line typed into the monitor and prints its output followed by a new prompt.
+    pub fn run_line(&self, line: &str) {
+        let output = self
+            .execute(line)
+            .unwrap_or_else(|e| format!("Error: {:#}\n", e));
+        print_flush(&format!("{}{}", output, PROMPT));
+    }
+
+    /// Runs one monitor command and returns its output.
+    pub fn execute(&self, line: &str) -> Result<String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            [] => Ok(String::new()),
+            ["help"] => Ok(HELP.to_string()),
+            ["info", "status"] => Ok(format!(
+                "{}\n",
+                match self.exit.reason() {
+                    Some(reason) => format!("exited ({:?})", reason),
+                    None if self.exit.is_paused() => "paused".to_string(),
+                    None => "running".to_string(),
+                }
+            )),
+            ["info", "mem"] => {
+                let mut output = String::new();
+                for region in &self.memory {
+                    let region = region.lock().unwrap();
+                    let start = region.guest_address();
+                    let size = u64::try_from(region.as_region().size())?;
+                    output += &format!(
+                        "{:#012x}-{:#012x} {:>5} {:>3}{}\n",
+                        start,
+                        start + size - 1,
+                        match region.share_type() {
+                            ShareType::Share => "share",
+                            ShareType::Lend => "lend",
+                        },
+                        match region.guest_access() {
+                            GuestMemoryAccess::R => "r",
+                            GuestMemoryAccess::Rw => "rw",
+                            GuestMemoryAccess::Rx => "rx",
+                            GuestMemoryAccess::Rwx => "rwx",
+                        },
+                        if region.is_regular_memory() {
+                            " ram"
+                        } else {
+                            ""
+                        }
+                    );
+                }
+                Ok(output)
+            }
+            ["info", "irq"] => Ok(self
+                .interrupts
+                .iter()
+                .map(|interrupt| {
+                    format!(
+                        "SPI {} {}\n",
+                        interrupt.line(),
+                        match (interrupt.is_level(), interrupt.is_asserted()) {
+                            (true, true) => "level asserted",
+                            (true, false) => "level",
+                            (false, _) => "edge",
+                        }
+                    )
+                })
+                .collect()),
+            ["info", "devices"] => Ok(self
+                .bus
+                .stats()
+                .iter()
+                .map(|device| {
+                    format!(
+                        "{:#012x}-{:#012x} {:<24} {:>8} reads ({} bytes) {:>8} writes ({} bytes)\n",
+                        device.base,
+                        device.base + device.len - 1,
+                        device.label,
+                        device.reads,
+                        device.read_bytes,
+                        device.writes,
+                        device.written_bytes
+                    )
+                })
+                .collect()),
+            ["dump-dtb", path] => {
+                let dtb = self
+                    .dtb
+                    .as_ref()
+                    .ok_or(anyhow!("The VM has no device tree"))?;
+                fs::write(path, dtb).context(format!("Failed to write {}", path))?;
+                Ok(format!("Wrote {} bytes to {}\n", dtb.len(), path))
+            }
+            ["dump-mem", addr, len, path] => {
+                let (addr, len) = (parse_number(addr)?, parse_number(len)?);
+                let mut file =
+                    fs::File::create(path).context(format!("Failed to create {}", path))?;
+                memory::dump_memory(&self.memory, addr, len, &mut file)?;
+                Ok(format!("Wrote {} bytes to {}\n", len, path))
+            }
+            ["pause"] => {
+                self.exit.pause();
+                Ok(String::new())
+            }
+            ["resume"] => {
+                self.exit.resume();
+                Ok(String::new())
+            }
+            ["quit"] => {
+                self.exit.request(VmExit::Poweroff);
+                Ok(String::new())
+            }
+            _ => Err(anyhow!("Unknown command, type help for commands")),
+        }
+    }
+}
+
+/// Parses a hexadecimal number with a `0x` prefix or a decimal one.
+fn parse_number(s: &str) -> Result<u64> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(&hex.replace('_', ""), 16),
+        None => s.parse(),
+    }
+    .context(format!("Invalid number {:?}", s))
+}
+
+/// Prints `output` right away, even without a trailing newline.
+fn print_flush(output: &str) {
+    print!("{}", output);
+    let _ = io::stdout().flush();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicBool;
+
+    use claim::{assert_err, assert_ok};
+
+    use super::Monitor;
+    use crate::{Bus, VmExit, VmExitRequest};
+
+    fn new_monitor(dtb: Option<Vec<u8>>) -> Monitor {
+        Monitor {
+            bus: Bus::new(),
+            exit: VmExitRequest::default(),
+            interrupts: Vec::new(),
+            memory: Vec::new(),
+            dtb,
+            active: AtomicBool::new(false),
+        }
+    }
+
+    #[test]
+    fn toggle() {
+        let monitor = new_monitor(None);
+        assert!(!monitor.is_active());
+        monitor.toggle();
+        assert!(monitor.is_active());
+        monitor.run_line("quit");
+        assert_eq!(monitor.exit.reason(), Some(VmExit::Poweroff));
+        monitor.toggle();
+        assert!(!monitor.is_active());
+    }
+
+    #[test]
+    fn commands() {
+        let monitor = new_monitor(Some(vec![0xd0, 0x0d, 0xfe, 0xed]));
+        assert_eq!(assert_ok!(monitor.execute("info status")), "running\n");
+        assert_ok!(monitor.execute("pause"));
+        assert_eq!(assert_ok!(monitor.execute("info  status\n")), "paused\n");
+        assert_ok!(monitor.execute("resume"));
+        assert_eq!(assert_ok!(monitor.execute("")), "");
+        assert_eq!(assert_ok!(monitor.execute("info irq")), "");
+        assert_eq!(assert_ok!(monitor.execute("info devices")), "");
+        assert_err!(monitor.execute("info"));
+        assert_err!(monitor.execute("dump-dtb"));
+
+        let path = std::env::temp_dir().join(format!("monitor-dtb-{}", std::process::id()));
+        assert_ok!(monitor.execute(&format!("dump-dtb {}", path.display())));
+        assert_eq!(assert_ok!(std::fs::read(&path)), [0xd0, 0x0d, 0xfe, 0xed]);
+        assert_ok!(std::fs::remove_file(&path));
+
+        assert_err!(new_monitor(None).execute("dump-dtb /dev/null"));
+
+        // The monitor has no memory
+        let err = assert_err!(monitor.execute("dump-mem 0x8000_0000 16 /dev/null"));
+        assert!(format!("{:#}", err).contains("No guest memory at 0x80000000"));
+        assert_err!(monitor.execute("dump-mem 0x8000_0000 lots /dev/null"));
+        assert_ok!(monitor.execute("dump-mem 0x8000_0000 0 /dev/null"));
+    }
+}
@@ -0,0 +1,188 @@
+// Copyright (c) 2024, Qualcomm Innovation Center, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause-Clear
+
+//! Message based interrupts for devices which raise interrupts by writing to memory, like PCIe
+//! MSIs, see Documentation/devicetree/bindings/interrupt-controller/arm,gic-v3.yaml in Linux.
+//!
+//! The Gunyah VM interface offers no way to give the VM a GICv3 ITS or to inject LPIs, so MSIs
+//! are mapped to a range of SPIs instead: the GIC node gets `mbi-ranges` and `mbi-alias`, and an
+//! [`MsiFrame`] at the alias address emulates the GICD_SETSPI_NSR register, raising the SPI
+//! whose INTID is written to it.
+
+use anyhow::{anyhow, bail, Context, Result};
+use vm_fdt::FdtWriter;
+
+use crate::{BusAccessInfo, BusDevice, FastWriteRegion, GunyahVirtualMachine};
+
+pub const MSI_FRAME_MMIO_SIZE: u64 = 0x1000;
+/// WO: raises the SPI whose INTID is written
+const GICD_SETSPI_NSR: u64 = 0x40;
+/// WO: lowers the SPI whose INTID is written, which does nothing for the edge triggered SPIs
+const GICD_CLRSPI_NSR: u64 = 0x48;
+/// INTID of SPI 0
+const GIC_SPI_INTID_BASE: u32 = 32;
+
+type MsiTrigger = Box<dyn Fn() -> Result<()> + Send>;
+
+/// SPIs reserved for MSIs and the frame raising them, see
+/// [`GunyahVirtualMachine::add_msi_frame`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MbiConfig {
+    /// Address of the [`MsiFrame`]
+    pub alias: u64,
+    pub first_spi: u32,
+    pub count: u32,
+}
+
+impl MbiConfig {
+    /// Writes the properties which make the GIC node an MSI controller.
+    pub(crate) fn generate_gic(&self, fdt: &mut FdtWriter) -> Result<(), vm_fdt::Error> {
+        fdt.property_null("msi-controller")?;
+        fdt.property_array_u32(
+            "mbi-ranges",
+            &[GIC_SPI_INTID_BASE + self.first_spi, self.count],
+        )?;
+        fdt.property_u64("mbi-alias", self.alias)?;
+        Ok(())
+    }
+}
+
+/// Raises the SPIs of an [`MbiConfig`] when the guest or its devices write their INTIDs to it.
+pub struct MsiFrame {
+    first_intid: u32,
+    triggers: Vec<MsiTrigger>,
+}
+
+impl MsiFrame {
+    pub(crate) fn new(vm: &GunyahVirtualMachine, config: &MbiConfig) -> Result<Self> {
+        let triggers = (config.first_spi..config.first_spi + config.count)
+            .map(|line| {
+                let irq = vm.add_edge_interrupt(line)?;
+                Ok(Box::new(move || irq.trigger()) as MsiTrigger)
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self::with_triggers(config.first_spi, triggers))
+    }
+
+    fn with_triggers(first_spi: u32, triggers: Vec<MsiTrigger>) -> Self {
+        Self {
+            first_intid: GIC_SPI_INTID_BASE + first_spi,
+            triggers,
+        }
+    }
+
+    fn raise(&self, intid: u32) -> Result<()> {
+        let trigger = intid
+            .checked_sub(self.first_intid)
+            .and_then(|index| self.triggers.get(index as usize))
+            .ok_or_else(|| anyhow!("INTID {} is not an MSI", intid))?;
+        trigger()
+    }
+}
+
+impl BusDevice for MsiFrame {
+    fn debug_label(&self) -> String {
+        "MSI frame".to_string()
+    }
+
+    fn write(&mut self, offset: BusAccessInfo, data: &[u8]) -> Result<()> {
+        let intid = u32::from_le_bytes(
+            data.try_into()
+                .map_err(|_| anyhow!("Only 32-bit register writes allowed"))?,
+        );
+        match offset.offset {
+            GICD_SETSPI_NSR => self.raise(intid),
+            GICD_CLRSPI_NSR => Ok(()),
+            _ => bail!("Unhandled register write at {:#x}", offset.offset),
+        }
+    }
+
+    /// Each MSI gets an ioeventfd, so raising it doesn't stop the vCPU.
+    fn fast_write_regions(&self) -> Vec<FastWriteRegion> {
+        (0..self.triggers.len() as u32)
+            .map(|index| FastWriteRegion {
+                offset: GICD_SETSPI_NSR,
+                len: 4,
+                datamatch: Some(u64::from(self.first_intid + index)),
+            })
+            .collect()
+    }
+
+    fn fast_write(&mut self, region: FastWriteRegion) -> Result<()> {
+        let intid = region.datamatch.context("MSI without INTID")?;
+        self.raise(intid as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    };
+
+    use claim::{assert_err, assert_ok};
+    use vm_fdt::FdtWriter;
+
+    use super::{MbiConfig, MsiFrame, MsiTrigger, GICD_CLRSPI_NSR, GICD_SETSPI_NSR};
+    use crate::{parse_fdt, Bus, BusDevice};
+
+    #[test]
+    fn raises_spis() {
+        let raised: Arc<Vec<AtomicU32>> = Arc::new((0..2).map(|_| AtomicU32::new(0)).collect());
+        let triggers = (0..2)
+            .map(|index| {
+                let raised = raised.clone();
+                Box::new(move || {
+                    raised[index].fetch_add(1, Ordering::Relaxed);
+                    Ok(())
+                }) as MsiTrigger
+            })
+            .collect();
+        let frame = Arc::new(Mutex::new(MsiFrame::with_triggers(64, triggers)));
+        let bus = Bus::new();
+        assert_ok!(bus.insert(frame.clone(), 0x1000, 0x1000));
+
+        // SPI 65 is INTID 97
+        assert_ok!(bus.write(0x1000 + GICD_SETSPI_NSR, &97u32.to_le_bytes()));
+        assert_ok!(bus.write(0x1000 + GICD_CLRSPI_NSR, &97u32.to_le_bytes()));
+        assert_err!(bus.write(0x1000 + GICD_SETSPI_NSR, &98u32.to_le_bytes()));
+        assert_err!(bus.write(0x1000 + GICD_SETSPI_NSR, &95u32.to_le_bytes()));
+        assert_eq!(raised[0].load(Ordering::Relaxed), 0);
+        assert_eq!(raised[1].load(Ordering::Relaxed), 1);
+
+        let mut frame = frame.lock().unwrap();
+        let regions = frame.fast_write_regions();
+        assert_eq!(regions.len(), 2);
+        assert_eq!(regions[0].datamatch, Some(96));
+        assert_ok!(frame.fast_write(regions[0]));
+        assert_eq!(raised[0].load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn gic_properties() {
+        let config = MbiConfig {
+            alias: 0x3ffe_0000,
+            first_spi: 64,
+            count: 32,
+        };
+        let mut fdt = assert_ok!(FdtWriter::new());
+        let root = assert_ok!(fdt.begin_node(""));
+        let gic = assert_ok!(fdt.begin_node("interrupt-controller"));
+        assert_ok!(config.generate_gic(&mut fdt));
+        assert_ok!(fdt.end_node(gic));
+        assert_ok!(fdt.end_node(root));
+        let blob = assert_ok!(fdt.finish());
+        let fdt = assert_ok!(parse_fdt(&blob));
+
+        assert!(fdt.has_prop("/interrupt-controller", "msi-controller"));
+        assert_eq!(
+            fdt.prop_u32_array("/interrupt-controller", "mbi-ranges"),
+            Some(vec![96, 32])
+        );
+        assert_eq!(
+            fdt.prop_u64("/interrupt-controller", "mbi-alias"),
+            Some(0x3ffe_0000)
+        );
+    }
+}
@@ -0,0 +1,145 @@
+// Copyright (c) 2024, Qualcomm Innovation Center, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause-Clear
+
+//! NUMA nodes described to the guest, see Documentation/devicetree/bindings/numa.txt in Linux,
+//! and placement of guest memory on host NUMA nodes.
+
+use std::io;
+
+use vm_fdt::FdtWriter;
+
+/// Distance of a node to itself, other nodes are twice as far
+const LOCAL_DISTANCE: u32 = 10;
+const REMOTE_DISTANCE: u32 = 20;
+
+/// Only allocate from the given nodes
+const MPOL_BIND: libc::c_int = 2;
+/// Move pages already allocated elsewhere
+const MPOL_MF_MOVE: libc::c_uint = 1 << 1;
+
+/// A NUMA node of the guest, whose ID is its index in [`crate::GunyahVirtualMachine::set_numa_nodes`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct NumaNode {
+    /// Base and size of each memory range of the node
+    pub memory: Vec<(u64, u64)>,
+    pub vcpus: Vec<u32>,
+}
+
+/// Writes a memory node for each memory range of `nodes`.
+pub(crate) fn generate_memory(
+    fdt: &mut FdtWriter,
+    nodes: &[NumaNode],
+) -> Result<(), vm_fdt::Error> {
+    for (id, node) in nodes.iter().enumerate() {
+        for &(base, size) in &node.memory {
+            let memory_node = fdt.begin_node(&format!("memory@{:x}", base))?;
+            fdt.property_string("device_type", "memory")?;
+            fdt.property_array_u64("reg", &[base, size])?;
+            fdt.property_u32("numa-node-id", id as u32)?;
+            fdt.end_node(memory_node)?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns the node `vcpu` belongs to, if any.
+pub(crate) fn vcpu_node(nodes: &[NumaNode], vcpu: u32) -> Option<u32> {
+    nodes
+        .iter()
+        .position(|node| node.vcpus.contains(&vcpu))
+        .map(|id| id as u32)
+}
+
+/// Writes the distances between `nodes`.
+pub(crate) fn generate_distance_map(
+    fdt: &mut FdtWriter,
+    nodes: &[NumaNode],
+) -> Result<(), vm_fdt::Error> {
+    let count = nodes.len() as u32;
+    let matrix: Vec<u32> = (0..count)
+        .flat_map(|from| {
+            (0..count).flat_map(move |to| {
+                let distance = if from == to {
+                    LOCAL_DISTANCE
+                } else {
+                    REMOTE_DISTANCE
+                };
+                [from, to, distance]
+            })
+        })
+        .collect();
+    let map_node = fdt.begin_node("distance-map")?;
+    fdt.property_string("compatible", "numa-distance-map-v1")?;
+    fdt.property_array_u32("distance-matrix", &matrix)?;
+    fdt.end_node(map_node)?;
+    Ok(())
+}
+
+/// Makes the pages of `mem`, which must be page aligned, come from host NUMA node `node`.
+///
+/// The policy sticks to the memory where its file keeps a shared policy, like the memfds used
+/// with the Android kernel's interface. Pages allocated before are moved.
+pub(crate) fn bind_to_host_node(mem: &mut [u8], node: u32) -> io::Result<()> {
+    let mut mask = vec![0u64; node as usize / 64 + 1];
+    mask[node as usize / 64] |= 1 << (node % 64);
+    // SAFETY: Safe because mem is mapped memory we own and mask holds the number of bits passed.
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_mbind,
+            mem.as_mut_ptr(),
+            mem.len(),
+            MPOL_BIND,
+            mask.as_ptr(),
+            // The kernel ignores the last bit
+            mask.len() * 64 + 1,
+            MPOL_MF_MOVE,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use claim::assert_ok;
+    use vm_fdt::FdtWriter;
+
+    use super::{generate_distance_map, generate_memory, vcpu_node, NumaNode};
+    use crate::parse_fdt;
+
+    #[test]
+    fn nodes() {
+        let nodes = [
+            NumaNode {
+                memory: vec![(0x8000_0000, 0x1000_0000)],
+                vcpus: vec![0, 1],
+            },
+            NumaNode {
+                memory: vec![(0x9000_0000, 0x1000_0000)],
+                vcpus: vec![2],
+            },
+        ];
+        assert_eq!(vcpu_node(&nodes, 2), Some(1));
+        assert_eq!(vcpu_node(&nodes, 3), None);
+
+        let mut fdt = assert_ok!(FdtWriter::new());
+        let root = assert_ok!(fdt.begin_node(""));
+        assert_ok!(generate_memory(&mut fdt, &nodes));
+        assert_ok!(generate_distance_map(&mut fdt, &nodes));
+        assert_ok!(fdt.end_node(root));
+        let blob = assert_ok!(fdt.finish());
+        let fdt = assert_ok!(parse_fdt(&blob));
+
+        assert_eq!(fdt.prop_u32("/memory@90000000", "numa-node-id"), Some(1));
+        assert_eq!(
+            fdt.prop_u64_array("/memory@80000000", "reg"),
+            Some(vec![0x8000_0000, 0x1000_0000])
+        );
+        assert_eq!(
+            fdt.prop_u32_array("/distance-map", "distance-matrix"),
+            Some(vec![0, 0, 10, 0, 1, 20, 1, 0, 20, 1, 1, 10])
+        );
+    }
+}
@@ -0,0 +1,114 @@
+// Copyright (c) 2024, Qualcomm Innovation Center, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause-Clear
+
+use std::{
+    fs,
+    num::NonZeroUsize,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, Context, Result};
+use gunyah::{GuestMemoryAccess, ShareType};
+use vm_fdt::FdtWriter;
+
+use crate::{GunyahGuestMemoryRegion, GunyahVirtualMachine};
+
+/// Describes a ramoops region of `size` bytes at `base` to the guest, inside the reserved-memory
+/// node. The region is split evenly between oops/panic records, the console log, ftrace and pmsg.
+pub fn create_fdt_ramoops(fdt: &mut FdtWriter, base: u64, size: u64) -> Result<()> {
+    let ramoops = fdt.begin_node(&format!("ramoops@{:x}", base))?;
+    fdt.property_string("compatible", "ramoops")?;
+    fdt.property_array_u64("reg", &[base, size])?;
+    let part = u32::try_from(size / 4)?;
+    fdt.property_u32("record-size", part)?;
+    fdt.property_u32("console-size", part)?;
+    fdt.property_u32("ftrace-size", part)?;
+    fdt.property_u32("pmsg-size", part)?;
+    fdt.end_node(ramoops)?;
+    Ok(())
+}
+
+/// Guest memory the guest kernel's pstore keeps its crash logs in.
+///
+/// The memory is shared with the host and lies outside the guest's regular memory, so it can be
+/// read after the VM stopped, including for protected VMs.
+pub struct Ramoops {
+    region: Arc<Mutex<GunyahGuestMemoryRegion>>,
+    base: u64,
+    size: NonZeroUsize,
+}
+
+impl Ramoops {
+    pub fn new(vm: &mut GunyahVirtualMachine, base: u64, size: NonZeroUsize) -> Result<Self> {
+        let region = vm
+            .add_memory(base, size, ShareType::Share, GuestMemoryAccess::Rw, false)
+            .context("Failed to add ramoops memory")?;
+        Ok(Self { region, base, size })
+    }
+
+    /// Describes the region inside the reserved-memory node, see [`crate::create_fdt_reserved_memory`].
+    pub fn device_config(&self, fdt: &mut FdtWriter) -> Result<()> {
+        create_fdt_ramoops(fdt, self.base, self.size.get().try_into()?)
+    }
+
+    /// Current contents of the region.
+    pub fn contents(&self) -> Result<Vec<u8>> {
+        let region = self.region.lock().unwrap();
+        Ok(region.as_region().map()?.to_vec())
+    }
+
+    /// Fills the region with the contents of a previous boot, so the guest finds the records it
+    /// wrote before it was reset.
+    pub fn restore(&self, contents: &[u8]) -> Result<()> {
+        if contents.len() != self.size.get() {
+            return Err(anyhow!(
+                "Expected {} bytes of ramoops contents, got {}",
+                self.size,
+                contents.len()
+            ));
+        }
+        let region = self.region.lock().unwrap();
+        region.as_region().map_mut()?.copy_from_slice(contents);
+        Ok(())
+    }
+
+    /// Writes the contents of the region to `path`.
+    pub fn dump(&self, path: &Path) -> Result<()> {
+        fs::write(path, self.contents()?)
+            .context(format!("Failed to write ramoops to {}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use claim::assert_ok;
+    use vm_fdt::FdtWriter;
+
+    use super::create_fdt_ramoops;
+    use crate::{create_fdt_reserved_memory, parse_fdt};
+
+    #[test]
+    fn fdt_describes_ramoops() {
+        let mut fdt = assert_ok!(FdtWriter::new());
+        let root = assert_ok!(fdt.begin_node(""));
+        assert_ok!(create_fdt_reserved_memory(&mut fdt, |fdt| {
+            create_fdt_ramoops(fdt, 0x8640_0000, 0x10_0000)
+        }));
+        assert_ok!(fdt.end_node(root));
+        let blob = assert_ok!(fdt.finish());
+
+        let fdt = assert_ok!(parse_fdt(&blob));
+        assert_eq!(fdt.prop_u32("/reserved-memory", "#address-cells"), Some(2));
+        assert!(fdt.has_prop("/reserved-memory", "ranges"));
+        let node = "/reserved-memory/ramoops@86400000";
+        assert_eq!(fdt.prop_str(node, "compatible"), Some("ramoops"));
+        assert_eq!(
+            fdt.prop_u64_array(node, "reg"),
+            Some(vec![0x8640_0000, 0x10_0000])
+        );
+        for prop in ["record-size", "console-size", "ftrace-size", "pmsg-size"] {
+            assert_eq!(fdt.prop_u32(node, prop), Some(0x4_0000));
+        }
+    }
+}
@@ -0,0 +1,97 @@
+// Copyright (c) 2024, Qualcomm Innovation Center, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause-Clear
+
+//! Restricted DMA pool: guest memory shared with the host, which devices do all their DMA through.
+//!
+//! The memory of a protected VM is lent to it, so the VMM can't reach the buffers its virtio
+//! drivers hand out. Devices whose node points at a `restricted-dma-pool` reserved-memory node
+//! get their buffers bounced through that pool by the guest kernel (swiotlb) instead, see
+//! [`crate::VirtioMmio::set_restricted_dma`].
+
+use std::num::NonZeroUsize;
+
+use anyhow::{Context, Result};
+use gunyah::{GuestMemoryAccess, ShareType};
+use vm_fdt::FdtWriter;
+
+use crate::{BusRange, GunyahVirtualMachine};
+
+pub const RESTRICTED_DMA_PHANDLE: u32 = 0x101;
+
+/// Describes a restricted DMA pool of `size` bytes at `base` to the guest, inside the
+/// reserved-memory node. Devices refer to it with `memory-region = <RESTRICTED_DMA_PHANDLE>`.
+pub fn create_fdt_restricted_dma(fdt: &mut FdtWriter, base: u64, size: u64) -> Result<()> {
+    let pool = fdt.begin_node(&format!("restricted-dma@{:x}", base))?;
+    fdt.property_string("compatible", "restricted-dma-pool")?;
+    fdt.property_array_u64("reg", &[base, size])?;
+    fdt.property_u32("phandle", RESTRICTED_DMA_PHANDLE)?;
+    fdt.end_node(pool)?;
+    Ok(())
+}
+
+/// Guest memory shared with the host for bounce buffers.
+///
+/// The memory is part of the guest's memory node, because the guest kernel needs it in its linear
+/// map, and reserved so it only uses it for DMA.
+#[derive(Clone, Copy, Debug)]
+pub struct RestrictedDmaPool {
+    base: u64,
+    size: NonZeroUsize,
+}
+
+impl RestrictedDmaPool {
+    pub fn new(vm: &mut GunyahVirtualMachine, base: u64, size: NonZeroUsize) -> Result<Self> {
+        vm.add_memory(base, size, ShareType::Share, GuestMemoryAccess::Rw, false)
+            .context("Failed to add restricted DMA memory")?;
+        Ok(Self { base, size })
+    }
+
+    /// Guest physical addresses devices using the pool may access.
+    pub fn range(&self) -> BusRange {
+        BusRange {
+            base: self.base,
+            len: self.size.get() as u64,
+        }
+    }
+
+    /// Describes the pool inside the reserved-memory node, see
+    /// [`crate::create_fdt_reserved_memory`].
+    pub fn device_config(&self, fdt: &mut FdtWriter) -> Result<()> {
+        create_fdt_restricted_dma(fdt, self.base, self.size.get().try_into()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use claim::assert_ok;
+    use vm_fdt::FdtWriter;
+
+    use super::{create_fdt_restricted_dma, RESTRICTED_DMA_PHANDLE};
+    use crate::{create_fdt_ramoops, create_fdt_reserved_memory, parse_fdt};
+
+    #[test]
+    fn fdt_describes_restricted_dma() {
+        let mut fdt = assert_ok!(FdtWriter::new());
+        let root = assert_ok!(fdt.begin_node(""));
+        assert_ok!(create_fdt_reserved_memory(&mut fdt, |fdt| {
+            create_fdt_ramoops(fdt, 0x8640_0000, 0x10_0000)?;
+            create_fdt_restricted_dma(fdt, 0x8650_0000, 0x40_0000)
+        }));
+        assert_ok!(fdt.end_node(root));
+        let blob = assert_ok!(fdt.finish());
+
+        let fdt = assert_ok!(parse_fdt(&blob));
+        assert!(fdt.has_prop("/reserved-memory/ramoops@86400000", "reg"));
+        let node = "/reserved-memory/restricted-dma@86500000";
+        assert_eq!(
+            fdt.prop_str(node, "compatible"),
+            Some("restricted-dma-pool")
+        );
+        assert_eq!(
+            fdt.prop_u64_array(node, "reg"),
+            Some(vec![0x8650_0000, 0x40_0000])
+        );
+        assert_eq!(fdt.prop_u32(node, "phandle"), Some(RESTRICTED_DMA_PHANDLE));
+        assert!(!fdt.has_prop(node, "no-map"));
+    }
+}
@@ -0,0 +1,100 @@
+// Copyright (c) 2024, Qualcomm Innovation Center, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause-Clear
+
+use std::{thread, time::Duration};
+
+use gunyah::Error;
+
+/// Bounded retry with exponential backoff for operations which may fail transiently.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first one.
+    pub attempts: u32,
+    /// Delay before the first retry. Doubled after every retry.
+    pub backoff: Duration,
+    /// Errors worth retrying. `EINVAL` is never retried, even if listed here.
+    pub errnos: Vec<Error>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 3,
+            backoff: Duration::from_millis(10),
+            errnos: vec![Error::EAGAIN, Error::EBUSY, Error::ENOMEM],
+        }
+    }
+}
+
+impl RetryPolicy {
+    fn is_transient(&self, err: Error) -> bool {
+        err != Error::EINVAL && self.errnos.contains(&err)
+    }
+
+    /// Calls `op` until it succeeds, fails with a non-transient error, or runs out of attempts.
+    pub fn run<T>(&self, mut op: impl FnMut() -> Result<T, Error>) -> Result<T, Error> {
+        let mut backoff = self.backoff;
+        let mut attempt = 1;
+        loop {
+            match op() {
+                Err(err) if attempt < self.attempts && self.is_transient(err) => {
+                    thread::sleep(backoff);
+                    backoff = backoff.saturating_mul(2);
+                    attempt += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, time::Duration};
+
+    use claim::assert_ok;
+    use gunyah::Error;
+
+    use super::RetryPolicy;
+
+    fn policy(attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            attempts,
+            backoff: Duration::ZERO,
+            ..Default::default()
+        }
+    }
+
+    /// Runs an operation which fails with each of `errors` in turn, then succeeds.
+    fn run(policy: &RetryPolicy, errors: &[Error]) -> (Result<(), Error>, usize) {
+        let calls = Cell::new(0);
+        let res = policy.run(|| {
+            calls.set(calls.get() + 1);
+            errors.get(calls.get() - 1).map_or(Ok(()), |err| Err(*err))
+        });
+        (res, calls.get())
+    }
+
+    #[test]
+    fn transient_then_success() {
+        let (res, calls) = run(&policy(3), &[Error::EAGAIN, Error::EBUSY]);
+        assert_ok!(res);
+        assert_eq!(calls, 3);
+    }
+
+    #[test]
+    fn attempts_exhausted() {
+        let (res, calls) = run(&policy(2), &[Error::EAGAIN, Error::EAGAIN]);
+        assert_eq!(res, Err(Error::EAGAIN));
+        assert_eq!(calls, 2);
+    }
+
+    #[test]
+    fn einval_not_retried() {
+        let mut policy = policy(3);
+        policy.errnos.push(Error::EINVAL);
+        let (res, calls) = run(&policy, &[Error::EINVAL]);
+        assert_eq!(res, Err(Error::EINVAL));
+        assert_eq!(calls, 1);
+    }
+}
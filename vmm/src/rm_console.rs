@@ -0,0 +1,134 @@
+// Copyright (c) 2024, Qualcomm Innovation Center, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause-Clear
+
+//! Console of the Resource Manager, see [`crate::GunyahVirtualMachine::add_rm_console`].
+//!
+//! Guests write to the RM console with Resource Manager calls instead of MMIO, so it isn't a
+//! device on the bus: the VM's configuration only asks the Resource Manager for a `console`
+//! vdevice. The host kernel passes what the guest writes on through a file, e.g. the host's tty
+//! of the VM's RM console, which an [`RmConsole`] relays to the VMM's output.
+
+use std::{
+    fs::File,
+    io::{self, Read, Write},
+    os::fd::{AsRawFd, RawFd},
+    time::Instant,
+};
+
+use anyhow::{Context, Result};
+use vm_fdt::FdtWriter;
+
+use crate::{set_nonblocking, DeviceTask, TaskPoll};
+
+/// Relays the output of the Resource Manager console from its host file to `output`.
+pub struct RmConsole {
+    source: File,
+    output: Box<dyn Write + Send>,
+}
+
+impl RmConsole {
+    pub(crate) fn new(source: File, output: Box<dyn Write + Send>) -> Result<Self> {
+        set_nonblocking(source.as_raw_fd()).context("Failed to make RM console non-blocking")?;
+        Ok(Self { source, output })
+    }
+}
+
+impl DeviceTask for RmConsole {
+    fn debug_label(&self) -> String {
+        "RM console".to_string()
+    }
+
+    fn fds(&self) -> Vec<RawFd> {
+        vec![self.source.as_raw_fd()]
+    }
+
+    fn poll(&mut self, _now: Instant) -> Result<TaskPoll> {
+        let mut buf = [0u8; 256];
+        loop {
+            match self.source.read(&mut buf) {
+                Ok(0) => return Ok(TaskPoll::Done),
+                Ok(len) => {
+                    self.output.write_all(&buf[..len])?;
+                    self.output.flush()?;
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return Ok(TaskPoll::Pending(None))
+                }
+                Err(e) => return Err(e).context("Failed to read RM console"),
+            }
+        }
+    }
+}
+
+/// Writes the vdevice which gives the VM a Resource Manager console.
+pub(crate) fn generate_vdevice(fdt: &mut FdtWriter) -> Result<(), vm_fdt::Error> {
+    let console_node = fdt.begin_node("console")?;
+    fdt.property_string("vdevice-type", "console")?;
+    fdt.property_string("generate", "/hypervisor/console")?;
+    fdt.end_node(console_node)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::File,
+        io::Write,
+        os::fd::FromRawFd,
+        sync::{Arc, Mutex},
+        time::Instant,
+    };
+
+    use claim::assert_ok;
+    use vm_fdt::FdtWriter;
+
+    use super::{generate_vdevice, RmConsole};
+    use crate::{parse_fdt, DeviceTask, TaskPoll};
+
+    #[derive(Clone, Default)]
+    struct Output(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn relays_output() {
+        let mut fds = [0; 2];
+        // SAFETY: Safe because fds has room for the two file descriptors pipe returns.
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        // SAFETY: Safe because pipe returned new file descriptors we own.
+        let (source, mut sink) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+        let output = Output::default();
+        let mut console = assert_ok!(RmConsole::new(source, Box::new(output.clone())));
+
+        // Nothing written yet, the read doesn't block
+        assert_eq!(
+            assert_ok!(console.poll(Instant::now())),
+            TaskPoll::Pending(None)
+        );
+        assert_ok!(sink.write_all(b"RM console\n"));
+        assert_ok!(console.poll(Instant::now()));
+        assert_eq!(*output.0.lock().unwrap(), b"RM console\n");
+
+        drop(sink);
+        assert_eq!(assert_ok!(console.poll(Instant::now())), TaskPoll::Done);
+    }
+
+    #[test]
+    fn vdevice() {
+        let mut fdt = assert_ok!(FdtWriter::new());
+        let root = assert_ok!(fdt.begin_node(""));
+        assert_ok!(generate_vdevice(&mut fdt));
+        assert_ok!(fdt.end_node(root));
+        let blob = assert_ok!(fdt.finish());
+        let fdt = assert_ok!(parse_fdt(&blob));
+        assert_eq!(fdt.prop_str("/console", "vdevice-type"), Some("console"));
+    }
+}
@@ -0,0 +1,358 @@
+// Copyright (c) 2024, Qualcomm Innovation Center, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause-Clear
+
+//! seccomp-BPF filters confining the threads which act on behalf of the guest, see
+//! [`crate::GunyahVirtualMachine::set_seccomp`].
+//!
+//! vCPU threads handle MMIO exits, so a guest which finds a bug in a device model controls what
+//! they do next. The filters keep them to I/O on descriptors the VMM opened, memory management
+//! without making memory executable, futexes and signals, and the run ioctl. Device threads,
+//! which serve the executor's tasks like the control socket and virtio queues, may also open
+//! and change files by path, accept connections, and hotplug vCPUs and memory with the Gunyah
+//! ioctls for that. Neither may create sockets, execute programs or trace processes.
+//!
+//! Filters are installed by each thread for itself once the VM is set up. Threads which aren't
+//! derived from vCPUs or the executor, like the GDB server's, aren't confined.
+
+use std::{cell::Cell, collections::BTreeMap};
+
+use anyhow::{anyhow, Result};
+use seccompiler::{
+    BpfProgram, SeccompAction, SeccompCmpArgLen, SeccompCmpOp, SeccompCondition, SeccompFilter,
+    SeccompRule,
+};
+
+/// Syscalls of every confined thread.
+const COMMON_SYSCALLS: &[libc::c_long] = &[
+    // I/O on descriptors the VMM opened
+    libc::SYS_read,
+    libc::SYS_write,
+    libc::SYS_readv,
+    libc::SYS_writev,
+    libc::SYS_pread64,
+    libc::SYS_pwrite64,
+    libc::SYS_preadv,
+    libc::SYS_pwritev,
+    libc::SYS_lseek,
+    libc::SYS_close,
+    libc::SYS_fstat,
+    libc::SYS_newfstatat,
+    libc::SYS_statx,
+    libc::SYS_fstatfs,
+    libc::SYS_fsync,
+    libc::SYS_fdatasync,
+    libc::SYS_ftruncate,
+    libc::SYS_fallocate,
+    libc::SYS_fcntl,
+    libc::SYS_dup,
+    libc::SYS_dup3,
+    libc::SYS_io_uring_enter,
+    // Memory, see also [`MEMORY_PROTECTION_SYSCALLS`]
+    libc::SYS_munmap,
+    libc::SYS_mremap,
+    libc::SYS_madvise,
+    libc::SYS_brk,
+    // Synchronization, time and signals, e.g. vCPU kicks
+    libc::SYS_futex,
+    libc::SYS_sched_yield,
+    libc::SYS_nanosleep,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_clock_gettime,
+    libc::SYS_gettimeofday,
+    libc::SYS_ppoll,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_sigaltstack,
+    libc::SYS_tgkill,
+    libc::SYS_gettid,
+    libc::SYS_getpid,
+    libc::SYS_getrandom,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+];
+
+/// Syscalls which are allowed unless they make memory executable.
+const MEMORY_PROTECTION_SYSCALLS: &[libc::c_long] = &[libc::SYS_mmap, libc::SYS_mprotect];
+
+/// Further syscalls of device threads.
+const DEVICE_SYSCALLS: &[libc::c_long] = &[
+    // Files by path: virtio-9p's shared directory, snapshots and guest memory for hotplug
+    libc::SYS_openat,
+    libc::SYS_statfs,
+    libc::SYS_getdents64,
+    libc::SYS_mkdirat,
+    libc::SYS_unlinkat,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_renameat,
+    libc::SYS_renameat2,
+    libc::SYS_linkat,
+    libc::SYS_symlinkat,
+    libc::SYS_readlinkat,
+    libc::SYS_fchmod,
+    libc::SYS_fchmodat,
+    libc::SYS_fchown,
+    libc::SYS_fchownat,
+    libc::SYS_utimensat,
+    // Clients of the control socket and the metrics server
+    libc::SYS_accept4,
+    libc::SYS_recvfrom,
+    libc::SYS_recvmsg,
+    libc::SYS_sendto,
+    libc::SYS_sendmsg,
+    libc::SYS_shutdown,
+    libc::SYS_getsockname,
+    libc::SYS_getpeername,
+    libc::SYS_getsockopt,
+    libc::SYS_setsockopt,
+    // Threads of hotplugged vCPUs
+    libc::SYS_clone,
+    libc::SYS_clone3,
+    libc::SYS_set_robust_list,
+    libc::SYS_rseq,
+    libc::SYS_sched_getaffinity,
+    libc::SYS_prctl,
+];
+
+/// ioctls other than Gunyah's: non-blocking sockets and terminal checks
+// The type of ioctl numbers differs between C libraries
+#[allow(clippy::unnecessary_cast)]
+const OTHER_IOCTLS: &[libc::Ioctl] = &[libc::FIONBIO as libc::Ioctl, libc::TCGETS as libc::Ioctl];
+
+/// Gunyah ioctls of vCPU threads
+const VCPU_IOCTLS: &[libc::Ioctl] = &[gunyah_bindings::GUNYAH_VCPU_RUN];
+
+/// Further Gunyah ioctls of device threads: vCPU and memory hotplug, and the ioeventfds and
+/// irqfds of hotplugged devices
+const DEVICE_IOCTLS: &[libc::Ioctl] = &[
+    gunyah_bindings::GUNYAH_VCPU_MMAP_SIZE,
+    gunyah_bindings::GUNYAH_VM_ADD_FUNCTION,
+    gunyah_bindings::GUNYAH_VM_REMOVE_FUNCTION,
+    #[cfg(not(feature = "ack-bindings"))]
+    gunyah_bindings::GUNYAH_CREATE_GUEST_MEM,
+    #[cfg(not(feature = "ack-bindings"))]
+    gunyah_bindings::GUNYAH_VM_MAP_MEM,
+    #[cfg(feature = "ack-bindings")]
+    gunyah_bindings::GUNYAH_VM_SET_USER_MEM_REGION,
+    #[cfg(feature = "ack-bindings")]
+    gunyah_bindings::GH_VM_ANDROID_LEND_USER_MEM,
+];
+
+/// What happens when a confined thread makes a syscall it isn't allowed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SeccompPolicy {
+    /// Kill the VMM
+    Kill,
+    /// Log the syscall to the audit log and allow it, to find what a setup needs
+    Log,
+}
+
+impl From<SeccompPolicy> for SeccompAction {
+    fn from(policy: SeccompPolicy) -> Self {
+        match policy {
+            SeccompPolicy::Kill => SeccompAction::KillProcess,
+            SeccompPolicy::Log => SeccompAction::Log,
+        }
+    }
+}
+
+/// Threads of the VMM which can be confined.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SeccompThread {
+    Vcpu,
+    /// The executor's, see [`crate::DeviceExecutor`]
+    Device,
+}
+
+thread_local! {
+    /// Whether the current thread installed its filter already
+    static CONFINED: Cell<bool> = const { Cell::new(false) };
+}
+
+impl SeccompThread {
+    /// Confines the calling thread with `policy` for disallowed syscalls. The filter is only
+    /// installed once per thread, later calls do nothing.
+    pub fn confine(&self, policy: SeccompPolicy) -> Result<()> {
+        if CONFINED.get() {
+            return Ok(());
+        }
+        let filter = self.filter(policy.into())?;
+        seccompiler::apply_filter(&filter)
+            .map_err(|e| anyhow!("Failed to install the {:?} seccomp filter: {}", self, e))?;
+        CONFINED.set(true);
+        Ok(())
+    }
+
+    fn filter(&self, mismatch: SeccompAction) -> Result<BpfProgram> {
+        let mut rules: BTreeMap<i64, Vec<SeccompRule>> = BTreeMap::new();
+        let (syscalls, ioctls) = match self {
+            Self::Vcpu => (COMMON_SYSCALLS.to_vec(), VCPU_IOCTLS.to_vec()),
+            Self::Device => (
+                [COMMON_SYSCALLS, DEVICE_SYSCALLS].concat(),
+                [VCPU_IOCTLS, DEVICE_IOCTLS].concat(),
+            ),
+        };
+        for syscall in syscalls {
+            rules.insert(syscall, Vec::new());
+        }
+        for &syscall in MEMORY_PROTECTION_SYSCALLS {
+            rules.insert(syscall, vec![not_executable()?]);
+        }
+        rules.insert(libc::SYS_ioctl, ioctl_rules(&ioctls)?);
+
+        let arch = std::env::consts::ARCH
+            .try_into()
+            .map_err(|e| anyhow!("No seccomp filters for this architecture: {}", e))?;
+        SeccompFilter::new(rules, mismatch, SeccompAction::Allow, arch)
+            .and_then(BpfProgram::try_from)
+            .map_err(|e| anyhow!("Invalid {:?} seccomp filter: {}", self, e))
+    }
+}
+
+/// Rules allowing exactly `ioctls` and [`OTHER_IOCTLS`].
+fn ioctl_rules(ioctls: &[libc::Ioctl]) -> Result<Vec<SeccompRule>> {
+    ioctls
+        .iter()
+        .chain(OTHER_IOCTLS)
+        .map(|&ioctl| {
+            // The type of ioctl numbers differs between C libraries
+            #[allow(clippy::unnecessary_cast)]
+            let ioctl = ioctl as u64;
+            SeccompCondition::new(1, SeccompCmpArgLen::Dword, SeccompCmpOp::Eq, ioctl)
+                .and_then(|condition| SeccompRule::new(vec![condition]))
+        })
+        .collect::<Result<_, _>>()
+        .map_err(|e| anyhow!("Invalid ioctl seccomp rule: {}", e))
+}
+
+/// Rule for mmap and mprotect, allowing any protection but PROT_EXEC.
+fn not_executable() -> Result<SeccompRule> {
+    SeccompCondition::new(
+        2,
+        SeccompCmpArgLen::Dword,
+        SeccompCmpOp::MaskedEq(libc::PROT_EXEC as u64),
+        0,
+    )
+    .and_then(|condition| SeccompRule::new(vec![condition]))
+    .map_err(|e| anyhow!("Invalid memory protection seccomp rule: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{self, Write},
+        os::fd::AsRawFd,
+        thread,
+        time::Duration,
+    };
+
+    use claim::{assert_err, assert_ok};
+    use seccompiler::SeccompAction;
+
+    use super::{SeccompPolicy, SeccompThread, CONFINED};
+    use crate::{VmExit, VmExitRequest};
+
+    #[test]
+    fn filters() {
+        for thread in [SeccompThread::Vcpu, SeccompThread::Device] {
+            assert_ok!(thread.filter(SeccompPolicy::Kill.into()));
+        }
+        let vcpu = assert_ok!(SeccompThread::Vcpu.filter(SeccompAction::Trap));
+        let device = assert_ok!(SeccompThread::Device.filter(SeccompAction::Trap));
+        assert!(device.len() > vcpu.len());
+    }
+
+    #[test]
+    fn confined_thread() {
+        let path = std::env::temp_dir().join(format!("seccomp-{}", std::process::id()));
+        let mut file = assert_ok!(std::fs::File::create(&path));
+        let result = {
+            let path = path.clone();
+            thread::spawn(move || {
+                let filter = SeccompThread::Vcpu
+                    .filter(SeccompAction::Errno(libc::EPERM as u32))
+                    .unwrap();
+                seccompiler::apply_filter(&filter).unwrap();
+                CONFINED.set(true);
+                // Already confined
+                SeccompThread::Vcpu.confine(SeccompPolicy::Kill).unwrap();
+
+                let written = file.write_all(b"gunyah");
+                let socket = std::net::UdpSocket::bind("127.0.0.1:0").map(|_| ());
+                let opened = std::fs::File::open(&path).map(|_| ());
+                // SAFETY: Safe because the ioctl takes no argument and fails on a regular file.
+                let ioctl = match unsafe {
+                    libc::ioctl(file.as_raw_fd(), gunyah_bindings::GUNYAH_VM_START)
+                } {
+                    -1 => io::Error::last_os_error().raw_os_error(),
+                    _ => None,
+                };
+                let len = page_size::get();
+                // SAFETY: Safe because the mapping is new and unmapped again below.
+                let page = unsafe {
+                    libc::mmap(
+                        std::ptr::null_mut(),
+                        len,
+                        libc::PROT_READ | libc::PROT_WRITE,
+                        libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                        -1,
+                        0,
+                    )
+                };
+                assert_ne!(page, libc::MAP_FAILED);
+                // SAFETY: Safe because nothing else uses the page.
+                let exec =
+                    match unsafe { libc::mprotect(page, len, libc::PROT_READ | libc::PROT_EXEC) } {
+                        -1 => io::Error::last_os_error().raw_os_error(),
+                        _ => None,
+                    };
+                // SAFETY: Safe because nothing else uses the page.
+                let read_only = unsafe { libc::mprotect(page, len, libc::PROT_READ) };
+                // SAFETY: Safe because nothing else uses the page.
+                unsafe { libc::munmap(page, len) };
+                (written, socket, opened, ioctl, exec, read_only)
+            })
+            .join()
+            .unwrap()
+        };
+        let (written, socket, opened, ioctl, exec, read_only) = result;
+        assert_ok!(written);
+        assert_eq!(assert_err!(socket).raw_os_error(), Some(libc::EPERM));
+        assert_eq!(assert_err!(opened).raw_os_error(), Some(libc::EPERM));
+        // Not ENOTTY, the filter stops other Gunyah ioctls before they reach the file
+        assert_eq!(ioctl, Some(libc::EPERM));
+        assert_eq!(exec, Some(libc::EPERM));
+        assert_eq!(read_only, 0);
+        assert_eq!(assert_ok!(std::fs::read(&path)), b"gunyah");
+        assert_ok!(std::fs::remove_file(&path));
+    }
+
+    #[test]
+    fn confined_exit_request() {
+        let request = VmExitRequest::default();
+        let vcpu = {
+            let request = request.clone();
+            thread::spawn(move || {
+                let _guard = request.enter(None);
+                while request.reason().is_none() {
+                    // SAFETY: Safe because sleep has no preconditions.
+                    unsafe { libc::sleep(10) };
+                }
+            })
+        };
+        thread::sleep(Duration::from_millis(50));
+
+        // As a vCPU thread does on a breakpoint and on poweroff
+        let confined = request.clone();
+        assert_ok!(thread::spawn(move || {
+            let filter = SeccompThread::Vcpu
+                .filter(SeccompAction::Errno(libc::EPERM as u32))
+                .unwrap();
+            seccompiler::apply_filter(&filter).unwrap();
+            assert!(confined.request_pause());
+            confined.request(VmExit::Poweroff);
+        })
+        .join());
+        assert_ok!(vcpu.join());
+        assert_eq!(request.reason(), Some(VmExit::Poweroff));
+    }
+}
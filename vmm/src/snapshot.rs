@@ -0,0 +1,309 @@
+// Copyright (c) 2024, Qualcomm Innovation Center, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause-Clear
+
+//! On-disk format of VM snapshots, see [`crate::GunyahVirtualMachine::snapshot`].
+//!
+//! All integers are little endian. The file starts with [`SNAPSHOT_MAGIC`], followed by the VM
+//! type, the boot configuration, the device states and the memory regions, each followed by its
+//! contents.
+
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+use anyhow::{anyhow, Context, Result};
+use gunyah::{BootReg, GuestMemoryAccess, ShareType, VmType};
+
+const SNAPSHOT_MAGIC: &[u8; 8] = b"GYSNAP03";
+
+/// A guest memory region. Its contents are streamed to and from the snapshot file, see
+/// [`Snapshot::save`] and [`Snapshot::load`].
+pub struct MemorySnapshot {
+    pub guest_address: u64,
+    pub share_type: ShareType,
+    pub guest_access: GuestMemoryAccess,
+    pub regular_memory: bool,
+    pub size: u64,
+}
+
+/// State of the device at `base` on the bus, as returned by
+/// [`crate::BusDevice::snapshot`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeviceSnapshot {
+    pub base: u64,
+    pub label: String,
+    pub state: Vec<u8>,
+}
+
+/// Everything needed to boot a VM again from where a snapshot was taken.
+///
+/// vCPU registers aren't part of it since Gunyah doesn't expose them, so a restored VM starts from
+/// its boot context with its memory and devices as they were.
+#[derive(Default)]
+pub struct Snapshot {
+    pub vm_type: VmType,
+    pub force_psci: bool,
+    /// DTB address and size
+    pub dtb: Option<(u64, u64)>,
+    /// Boot context registers which were set, see [`crate::GunyahVirtualMachine::set_boot_reg`]
+    pub boot_regs: Vec<(BootReg, u64)>,
+    pub memory: Vec<MemorySnapshot>,
+    pub devices: Vec<DeviceSnapshot>,
+}
+
+impl Snapshot {
+    /// Writes the snapshot to `path`. `write_memory` writes the `size` bytes of contents of the
+    /// memory region with the given index.
+    pub fn save(
+        &self,
+        path: &Path,
+        write_memory: impl FnMut(usize, &mut BufWriter<File>) -> Result<()>,
+    ) -> Result<()> {
+        let file = File::create(path).context(format!("Failed to create {}", path.display()))?;
+        let mut writer = BufWriter::new(file);
+        self.encode(&mut writer, write_memory)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Reads a snapshot from `path`. `read_memory` reads the `size` bytes of contents of each
+    /// memory region in turn.
+    pub fn load(
+        path: &Path,
+        read_memory: impl FnMut(&MemorySnapshot, &mut BufReader<File>) -> Result<()>,
+    ) -> Result<Self> {
+        let file = File::open(path).context(format!("Failed to open {}", path.display()))?;
+        Self::decode(&mut BufReader::new(file), read_memory)
+            .context(format!("Failed to read snapshot {}", path.display()))
+    }
+
+    fn encode<W: Write>(
+        &self,
+        w: &mut W,
+        mut write_memory: impl FnMut(usize, &mut W) -> Result<()>,
+    ) -> Result<()> {
+        w.write_all(SNAPSHOT_MAGIC)?;
+        write_u8(w, self.vm_type.raw().try_into()?)?;
+        write_u8(w, self.force_psci.into())?;
+        write_option(w, self.dtb.map(|(addr, _)| addr))?;
+        write_u64(w, self.dtb.map_or(0, |(_, size)| size))?;
+        write_u64(w, self.boot_regs.len().try_into()?)?;
+        for (reg, value) in &self.boot_regs {
+            write_u64(w, reg.id().into())?;
+            write_u64(w, *value)?;
+        }
+
+        write_u64(w, self.devices.len().try_into()?)?;
+        for device in &self.devices {
+            write_u64(w, device.base)?;
+            write_bytes(w, device.label.as_bytes())?;
+            write_bytes(w, &device.state)?;
+        }
+
+        write_u64(w, self.memory.len().try_into()?)?;
+        for (index, region) in self.memory.iter().enumerate() {
+            write_u64(w, region.guest_address)?;
+            write_u8(
+                w,
+                match region.share_type {
+                    ShareType::Share => 0,
+                    ShareType::Lend => 1,
+                },
+            )?;
+            write_u8(
+                w,
+                match region.guest_access {
+                    GuestMemoryAccess::R => 0,
+                    GuestMemoryAccess::Rw => 1,
+                    GuestMemoryAccess::Rx => 2,
+                    GuestMemoryAccess::Rwx => 3,
+                },
+            )?;
+            write_u8(w, region.regular_memory.into())?;
+            write_u64(w, region.size)?;
+            write_memory(index, w)?;
+        }
+        Ok(())
+    }
+
+    fn decode<R: Read>(
+        r: &mut R,
+        mut read_memory: impl FnMut(&MemorySnapshot, &mut R) -> Result<()>,
+    ) -> Result<Self> {
+        let mut magic = [0u8; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != SNAPSHOT_MAGIC {
+            return Err(anyhow!("Not a snapshot"));
+        }
+        let raw_type = read_u8(r)?;
+        let vm_type = VmType::ALL
+            .into_iter()
+            .find(|vm_type| vm_type.raw() == i32::from(raw_type))
+            .ok_or(anyhow!("Unknown VM type {}", raw_type))?;
+        let force_psci = read_u8(r)? != 0;
+        let dtb_addr = read_option(r)?;
+        let dtb_size = read_u64(r)?;
+        let mut boot_regs = Vec::new();
+        for _ in 0..read_u64(r)? {
+            let id = read_u64(r)?;
+            let reg = u32::try_from(id)
+                .ok()
+                .and_then(BootReg::from_id)
+                .ok_or(anyhow!("Unknown boot register {:#x}", id))?;
+            boot_regs.push((reg, read_u64(r)?));
+        }
+
+        let mut devices = Vec::new();
+        for _ in 0..read_u64(r)? {
+            devices.push(DeviceSnapshot {
+                base: read_u64(r)?,
+                label: String::from_utf8(read_bytes(r)?)?,
+                state: read_bytes(r)?,
+            });
+        }
+
+        let mut memory = Vec::new();
+        for _ in 0..read_u64(r)? {
+            let guest_address = read_u64(r)?;
+            let share_type = match read_u8(r)? {
+                0 => ShareType::Share,
+                1 => ShareType::Lend,
+                t => return Err(anyhow!("Unknown share type {}", t)),
+            };
+            let guest_access = match read_u8(r)? {
+                0 => GuestMemoryAccess::R,
+                1 => GuestMemoryAccess::Rw,
+                2 => GuestMemoryAccess::Rx,
+                3 => GuestMemoryAccess::Rwx,
+                a => return Err(anyhow!("Unknown memory access {}", a)),
+            };
+            let region = MemorySnapshot {
+                guest_address,
+                share_type,
+                guest_access,
+                regular_memory: read_u8(r)? != 0,
+                size: read_u64(r)?,
+            };
+            read_memory(&region, r)?;
+            memory.push(region);
+        }
+
+        Ok(Self {
+            vm_type,
+            force_psci,
+            dtb: dtb_addr.map(|addr| (addr, dtb_size)),
+            boot_regs,
+            memory,
+            devices,
+        })
+    }
+}
+
+fn write_u8<W: Write>(w: &mut W, value: u8) -> Result<()> {
+    Ok(w.write_all(&[value])?)
+}
+
+fn write_u64<W: Write>(w: &mut W, value: u64) -> Result<()> {
+    Ok(w.write_all(&value.to_le_bytes())?)
+}
+
+fn write_option<W: Write>(w: &mut W, value: Option<u64>) -> Result<()> {
+    write_u8(w, value.is_some().into())?;
+    write_u64(w, value.unwrap_or_default())
+}
+
+fn write_bytes<W: Write>(w: &mut W, data: &[u8]) -> Result<()> {
+    write_u64(w, data.len().try_into()?)?;
+    Ok(w.write_all(data)?)
+}
+
+fn read_u8<R: Read>(r: &mut R) -> Result<u8> {
+    let mut value = [0u8];
+    r.read_exact(&mut value)?;
+    Ok(value[0])
+}
+
+fn read_u64<R: Read>(r: &mut R) -> Result<u64> {
+    let mut value = [0u8; 8];
+    r.read_exact(&mut value)?;
+    Ok(u64::from_le_bytes(value))
+}
+
+fn read_option<R: Read>(r: &mut R) -> Result<Option<u64>> {
+    let present = read_u8(r)? != 0;
+    let value = read_u64(r)?;
+    Ok(present.then_some(value))
+}
+
+fn read_bytes<R: Read>(r: &mut R) -> Result<Vec<u8>> {
+    let len = usize::try_from(read_u64(r)?)?;
+    let mut data = Vec::new();
+    r.take(len.try_into()?).read_to_end(&mut data)?;
+    if data.len() != len {
+        return Err(anyhow!("Snapshot is truncated"));
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+
+    use claim::assert_ok;
+    use gunyah::{BootReg, GuestMemoryAccess, ShareType, VmType};
+
+    use super::{DeviceSnapshot, MemorySnapshot, Snapshot};
+
+    #[test]
+    fn round_trip() {
+        let snapshot = Snapshot {
+            vm_type: VmType::Pas,
+            force_psci: true,
+            dtb: Some((0x8600_0000, 0x2000)),
+            boot_regs: vec![(BootReg::Pc, 0x8000_0000), (BootReg::X(0), 0x8600_0000)],
+            memory: vec![MemorySnapshot {
+                guest_address: 0x8000_0000,
+                share_type: ShareType::Lend,
+                guest_access: GuestMemoryAccess::Rwx,
+                regular_memory: true,
+                size: 0x1000,
+            }],
+            devices: vec![DeviceSnapshot {
+                base: 0x9200,
+                label: "irq-gen@9200".to_string(),
+                state: vec![1, 2, 3],
+            }],
+        };
+        let mut data = Vec::new();
+        assert_ok!(snapshot.encode(&mut data, |index, w| {
+            assert_eq!(index, 0);
+            Ok(w.write_all(&[0xa5; 0x1000])?)
+        }));
+
+        let read_memory = |region: &MemorySnapshot, r: &mut &[u8]| {
+            let mut contents = vec![0; region.size as usize];
+            r.read_exact(&mut contents)?;
+            assert_eq!(contents, [0xa5; 0x1000]);
+            Ok(())
+        };
+        let restored = assert_ok!(Snapshot::decode(&mut data.as_slice(), read_memory));
+        assert_eq!(restored.vm_type, VmType::Pas);
+        assert!(restored.force_psci);
+        assert_eq!(restored.dtb, Some((0x8600_0000, 0x2000)));
+        assert_eq!(restored.boot_regs, snapshot.boot_regs);
+        assert_eq!(restored.memory.len(), 1);
+        let region = &restored.memory[0];
+        assert_eq!(region.guest_address, 0x8000_0000);
+        assert_eq!(region.share_type, ShareType::Lend);
+        assert!(matches!(region.guest_access, GuestMemoryAccess::Rwx));
+        assert!(region.regular_memory);
+        assert_eq!(region.size, 0x1000);
+        assert_eq!(restored.devices, snapshot.devices);
+
+        assert!(Snapshot::decode(&mut &data[..data.len() - 1], read_memory).is_err());
+        assert!(Snapshot::decode(&mut &b"GYSNAP00"[..], read_memory).is_err());
+        assert!(Snapshot::decode(&mut &b"GYSNAP02"[..], read_memory).is_err());
+    }
+}
@@ -0,0 +1,27 @@
+// Copyright (c) 2024, Qualcomm Innovation Center, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause-Clear
+
+use anyhow::Result;
+
+use crate::{BusAccessInfo, BusDevice};
+
+/// Plain memory on the bus, for unit tests which don't have a VM to map guest memory into.
+pub struct Ram(pub Vec<u8>);
+
+impl BusDevice for Ram {
+    fn debug_label(&self) -> String {
+        "ram".to_string()
+    }
+
+    fn read(&mut self, access: BusAccessInfo, data: &mut [u8]) -> Result<()> {
+        let offset = access.offset as usize;
+        data.copy_from_slice(&self.0[offset..offset + data.len()]);
+        Ok(())
+    }
+
+    fn write(&mut self, access: BusAccessInfo, data: &[u8]) -> Result<()> {
+        let offset = access.offset as usize;
+        self.0[offset..offset + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+}
@@ -0,0 +1,139 @@
+// Copyright (c) 2024, Qualcomm Innovation Center, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause-Clear
+
+//! CPU topology and caches described to the guest in the `cpus` node, see
+//! Documentation/devicetree/bindings/cpu/cpu-topology.txt in Linux.
+
+use vm_fdt::FdtWriter;
+
+/// Phandle of the cpu node for vCPU 0, the others follow
+const CPU_PHANDLE_BASE: u32 = 0x300;
+/// Phandle of the L2 cache node of cluster 0, the others follow
+const L2_PHANDLE_BASE: u32 = 0x400;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CacheInfo {
+    pub size: u32,
+    pub line_size: u32,
+}
+
+/// How vCPUs are grouped in clusters and which caches they have. vCPUs are assigned to clusters
+/// in order of their IDs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CpuTopology {
+    pub cores_per_cluster: u32,
+    /// Private instruction and data caches of each core
+    pub l1: Option<CacheInfo>,
+    /// Unified cache shared by the cores of a cluster
+    pub l2: Option<CacheInfo>,
+}
+
+impl CpuTopology {
+    fn cluster(&self, vcpu: u32) -> u32 {
+        vcpu / self.cores_per_cluster
+    }
+
+    /// Writes the properties linking the cpu node of `vcpu` to the topology.
+    pub(crate) fn generate_cpu(&self, fdt: &mut FdtWriter, vcpu: u32) -> Result<(), vm_fdt::Error> {
+        fdt.property_u32("phandle", CPU_PHANDLE_BASE + vcpu)?;
+        if let Some(l1) = self.l1 {
+            fdt.property_u32("i-cache-size", l1.size)?;
+            fdt.property_u32("i-cache-line-size", l1.line_size)?;
+            fdt.property_u32("d-cache-size", l1.size)?;
+            fdt.property_u32("d-cache-line-size", l1.line_size)?;
+        }
+        if self.l2.is_some() {
+            fdt.property_u32("next-level-cache", L2_PHANDLE_BASE + self.cluster(vcpu))?;
+        }
+        Ok(())
+    }
+
+    /// Writes the `cpu-map` and L2 cache nodes for `vcpus` into the `cpus` node.
+    pub(crate) fn generate_map(
+        &self,
+        fdt: &mut FdtWriter,
+        vcpus: &[u32],
+    ) -> Result<(), vm_fdt::Error> {
+        let Some(last) = vcpus.iter().map(|vcpu| self.cluster(*vcpu)).max() else {
+            return Ok(());
+        };
+
+        let map_node = fdt.begin_node("cpu-map")?;
+        for cluster in 0..=last {
+            let cluster_node = fdt.begin_node(&format!("cluster{}", cluster))?;
+            for &vcpu in vcpus.iter().filter(|vcpu| self.cluster(**vcpu) == cluster) {
+                let core_node =
+                    fdt.begin_node(&format!("core{}", vcpu % self.cores_per_cluster))?;
+                fdt.property_u32("cpu", CPU_PHANDLE_BASE + vcpu)?;
+                fdt.end_node(core_node)?;
+            }
+            fdt.end_node(cluster_node)?;
+        }
+        fdt.end_node(map_node)?;
+
+        if let Some(l2) = self.l2 {
+            for cluster in 0..=last {
+                let cache_node = fdt.begin_node(&format!("l2-cache{}", cluster))?;
+                fdt.property_string("compatible", "cache")?;
+                fdt.property_u32("cache-level", 2)?;
+                fdt.property_null("cache-unified")?;
+                fdt.property_u32("cache-size", l2.size)?;
+                fdt.property_u32("cache-line-size", l2.line_size)?;
+                fdt.property_u32("phandle", L2_PHANDLE_BASE + cluster)?;
+                fdt.end_node(cache_node)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use claim::{assert_none, assert_ok, assert_some};
+    use vm_fdt::FdtWriter;
+
+    use super::{CacheInfo, CpuTopology};
+    use crate::parse_fdt;
+
+    #[test]
+    fn cpu_map() {
+        let topology = CpuTopology {
+            cores_per_cluster: 2,
+            l1: Some(CacheInfo {
+                size: 0x8000,
+                line_size: 64,
+            }),
+            l2: Some(CacheInfo {
+                size: 0x8_0000,
+                line_size: 64,
+            }),
+        };
+        let vcpus = [0, 1, 2];
+        let mut fdt = assert_ok!(FdtWriter::new());
+        let root = assert_ok!(fdt.begin_node(""));
+        let cpus = assert_ok!(fdt.begin_node("cpus"));
+        for vcpu in vcpus {
+            let cpu = assert_ok!(fdt.begin_node(&format!("cpu@{:x}", vcpu)));
+            assert_ok!(topology.generate_cpu(&mut fdt, vcpu));
+            assert_ok!(fdt.end_node(cpu));
+        }
+        assert_ok!(topology.generate_map(&mut fdt, &vcpus));
+        assert_ok!(fdt.end_node(cpus));
+        assert_ok!(fdt.end_node(root));
+        let blob = assert_ok!(fdt.finish());
+        let fdt = assert_ok!(parse_fdt(&blob));
+
+        let cpu2 = assert_some!(fdt.prop_u32("/cpus/cpu@2", "phandle"));
+        assert_eq!(
+            fdt.prop_u32("/cpus/cpu-map/cluster1/core0", "cpu"),
+            Some(cpu2)
+        );
+        assert_none!(fdt.node("/cpus/cpu-map/cluster1/core1"));
+        assert_eq!(fdt.prop_u32("/cpus/cpu@2", "d-cache-size"), Some(0x8000));
+
+        let l2 = assert_some!(fdt.prop_u32("/cpus/cpu@2", "next-level-cache"));
+        assert_eq!(fdt.prop_u32("/cpus/l2-cache1", "phandle"), Some(l2));
+        assert_eq!(fdt.prop_u32("/cpus/l2-cache1", "cache-level"), Some(2));
+        assert_ne!(fdt.prop_u32("/cpus/cpu@1", "next-level-cache"), Some(l2));
+    }
+}
@@ -1,116 +1,298 @@
 // Copyright (c) 2024, Qualcomm Innovation Center, Inc. All rights reserved.
 // SPDX-License-Identifier: BSD-3-Clause-Clear
 
-use std::sync::RwLock;
+use std::{
+    fmt,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
 
 use anyhow::{anyhow, Result};
+use gunyah::{ResumeAction, VcpuExit};
 use gunyah_bindings::{
     gunyah_vcpu_exit::{
         GUNYAH_VCPU_EXIT_MMIO, GUNYAH_VCPU_EXIT_PAGE_FAULT, GUNYAH_VCPU_EXIT_STATUS,
         GUNYAH_VCPU_EXIT_UNKNOWN,
     },
-    gunyah_vcpu_resume_action::{GUNYAH_VCPU_RESUME_FAULT, GUNYAH_VCPU_RESUME_HANDLED},
-    gunyah_vcpu_run,
+    gunyah_vm_status::{GUNYAH_VM_STATUS_CRASHED, GUNYAH_VM_STATUS_EXITED},
+};
+
+use crate::{
+    kick_signal, Bus, RunningGuard, SeccompPolicy, SeccompThread, VmDebug, VmExit, VmExitRequest,
 };
 
-use crate::{Bus, GunyahVirtualMachine};
+// Resource Manager VM exit types reported with GUNYAH_VM_STATUS_EXITED
+const GUNYAH_RM_VM_EXIT_TYPE_PSCI_SYSTEM_RESET: u16 = 2;
+const GUNYAH_RM_VM_EXIT_TYPE_PSCI_SYSTEM_RESET2: u16 = 3;
+const GUNYAH_RM_VM_EXIT_TYPE_WDT_BITE: u16 = 4;
+
+/// Exits of one kind and the time the VMM spent handling them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ExitStats {
+    pub count: u64,
+    pub time: Duration,
+}
+
+/// Exits of a vCPU to the VMM by reason, see [`GunyahVcpu::stats`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct VcpuStats {
+    pub mmio: ExitStats,
+    pub page_fault: ExitStats,
+    pub status: ExitStats,
+    /// Unknown exit reasons
+    pub other: ExitStats,
+}
+
+impl VcpuStats {
+    fn record(&mut self, exit_reason: u32, time: Duration) {
+        let stats = match exit_reason {
+            GUNYAH_VCPU_EXIT_MMIO => &mut self.mmio,
+            GUNYAH_VCPU_EXIT_PAGE_FAULT => &mut self.page_fault,
+            GUNYAH_VCPU_EXIT_STATUS => &mut self.status,
+            _ => &mut self.other,
+        };
+        stats.count += 1;
+        stats.time += time;
+    }
+
+    /// Stats of each exit reason along with its name.
+    pub fn by_reason(&self) -> [(&'static str, ExitStats); 4] {
+        [
+            ("mmio", self.mmio),
+            ("page-fault", self.page_fault),
+            ("status", self.status),
+            ("other", self.other),
+        ]
+    }
+}
+
+/// One line per exit reason the vCPU has seen.
+impl fmt::Display for VcpuStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (reason, stats) in self.by_reason() {
+            if stats.count != 0 {
+                writeln!(
+                    f,
+                    "{:<10} {:>10} exits {:>12.3?} total {:>10.3?} average",
+                    reason,
+                    stats.count,
+                    stats.time,
+                    stats.time / u32::try_from(stats.count).unwrap_or(u32::MAX)
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
 
 pub struct GunyahVcpu {
     bus: Bus,
     vcpu: RwLock<gunyah::Vcpu>,
+    exit: VmExitRequest,
+    debug: VmDebug,
+    kicker: Arc<gunyah::VcpuKicker>,
+    /// Thread in [`Self::run`], if any
+    thread: Mutex<Option<libc::pthread_t>>,
+    stats: Mutex<VcpuStats>,
+    /// Confines the thread in [`Self::run`], see [`crate::GunyahVirtualMachine::set_seccomp`]
+    seccomp: Option<SeccompPolicy>,
 }
 
 impl GunyahVcpu {
-    pub(crate) fn new(vm: &GunyahVirtualMachine, id: u8) -> Result<Self> {
+    pub(crate) fn new(
+        vm: &gunyah::Vm,
+        bus: Bus,
+        exit: VmExitRequest,
+        debug: VmDebug,
+        id: u8,
+        seccomp: Option<SeccompPolicy>,
+    ) -> Result<Self> {
+        let vcpu = gunyah::Vcpu::new(vm.clone(), id.into())?;
         Ok(Self {
-            bus: vm.get_bus(crate::AccessId::Vcpu(id)),
-            vcpu: RwLock::new(gunyah::Vcpu::new(vm.vm().clone(), id.into())?),
+            bus,
+            kicker: Arc::new(vcpu.kicker()?),
+            vcpu: RwLock::new(vcpu),
+            exit,
+            debug,
+            thread: Mutex::new(None),
+            stats: Mutex::new(VcpuStats::default()),
+            seccomp,
         })
     }
 
+    /// Makes [`Self::run`] leave the guest promptly to check for exit and pause requests, and
+    /// [`Self::run_once`] fail with EINTR. Unlike a bare signal, a kick which arrives just before
+    /// the vCPU enters the guest isn't lost.
+    pub fn kick(&self) {
+        self.kicker.set();
+        if let Some(thread) = *self.thread.lock().unwrap() {
+            // SAFETY: Safe because the thread is in Self::run, which clears `thread` under the
+            // lock before returning.
+            unsafe { libc::pthread_kill(thread, kick_signal()) };
+        }
+    }
+
     pub fn id(&self) -> u32 {
         self.vcpu.read().unwrap().id()
     }
 
-    pub fn run_once(&self) -> Result<gunyah_vcpu_run> {
+    /// Exits handled by [`Self::run`] so far. The time of an exit is how long the VMM took to
+    /// handle it before the vCPU could enter the guest again.
+    pub fn stats(&self) -> VcpuStats {
+        *self.stats.lock().unwrap()
+    }
+
+    pub fn run_once(&self) -> Result<VcpuExit> {
         let mut vcpu = self.vcpu.write().unwrap();
-        vcpu.run()?;
-        Ok(*vcpu.mmap())
+        let result = vcpu.run();
+        if matches!(result, Err(e) if e as i32 == libc::EINTR) {
+            self.kicker.clear();
+        }
+        result?;
+        Ok(vcpu.exit())
+    }
+
+    /// Runs the vCPU until the VM exits or an exit is requested through
+    /// [`crate::GunyahVirtualMachine::exit_request`]. While the VM is paused, the vCPU waits between
+    /// exits. A vCPU which hits a breakpoint pauses the VM, see
+    /// [`crate::GunyahVirtualMachine::set_breakpoint`].
+    pub fn run(&self) -> Result<VmExit> {
+        if let Some(policy) = self.seccomp {
+            SeccompThread::Vcpu.confine(policy)?;
+        }
+        let running = self.exit.enter(Some(self.kicker.clone()));
+        // SAFETY: Safe because pthread_self has no preconditions.
+        *self.thread.lock().unwrap() = Some(unsafe { libc::pthread_self() });
+        let result = self.run_until_exit(&running);
+        *self.thread.lock().unwrap() = None;
+        result
     }
 
-    pub fn run(&self) -> Result<()> {
+    fn run_until_exit(&self, running: &RunningGuard) -> Result<VmExit> {
         loop {
+            running.wait_while_paused();
+            if let Some(reason) = self.exit.reason() {
+                return Ok(reason);
+            }
             let mut vcpu = self.vcpu.write().unwrap();
-            vcpu.run()?;
-            let result = vcpu.mmap_mut();
-            match result.exit_reason {
-                GUNYAH_VCPU_EXIT_UNKNOWN => Err(anyhow!("Unexpected exit for unknown reason")),
-                GUNYAH_VCPU_EXIT_MMIO => {
-                    // SAFETY: Safe because we just checked exit_reason is GUNYAH_VCPU_EXIT_MMIO and we are the only ones that run the vcpu
-                    let reason = unsafe { &mut result.__bindgen_anon_1.mmio };
-                    let len = reason.len as usize;
-                    let handled = match reason.is_write {
-                        1 => self.bus.write(reason.phys_addr, &reason.data[0..len]),
-                        0 => self.bus.read(reason.phys_addr, &mut reason.data[0..len]),
-                        _ => unreachable!(),
+            match vcpu.run() {
+                // Kicked by VmExitRequest or Self::kick
+                Err(e) if e as i32 == libc::EINTR => {
+                    self.kicker.clear();
+                    continue;
+                }
+                result => result?,
+            }
+            let start = Instant::now();
+            let exit = vcpu.exit();
+            let handled = match exit {
+                VcpuExit::Mmio {
+                    addr,
+                    mut data,
+                    is_write,
+                    len,
+                } => {
+                    let handled = if is_write {
+                        self.bus.write(addr, &data[..len])
+                    } else {
+                        self.bus.read(addr, &mut data[..len])
                     };
-                    reason.resume_action = match handled {
-                        Ok(_) => GUNYAH_VCPU_RESUME_HANDLED,
+                    match handled {
+                        Ok(_) if is_write => vcpu.complete_mmio(None, ResumeAction::Handled),
+                        Ok(_) => vcpu.complete_mmio(Some(&data[..len]), ResumeAction::Handled),
                         Err(e) => {
-                            println!(
-                                "Failed to handle address access at  {}: {:?}",
-                                reason.phys_addr, e
-                            );
-                            GUNYAH_VCPU_RESUME_FAULT
+                            println!("Failed to handle address access at  {}: {:?}", addr, e);
+                            vcpu.complete_mmio(None, ResumeAction::Fault)
                         }
                     }
-                    .try_into()
-                    .unwrap();
+                }
+                VcpuExit::Status { status, exit_type } => {
+                    let reason = match (status, exit_type) {
+                        (GUNYAH_VM_STATUS_CRASHED, _)
+                        | (GUNYAH_VM_STATUS_EXITED, GUNYAH_RM_VM_EXIT_TYPE_WDT_BITE) => {
+                            VmExit::Crash
+                        }
+                        (
+                            GUNYAH_VM_STATUS_EXITED,
+                            GUNYAH_RM_VM_EXIT_TYPE_PSCI_SYSTEM_RESET
+                            | GUNYAH_RM_VM_EXIT_TYPE_PSCI_SYSTEM_RESET2,
+                        ) => VmExit::Reset,
+                        (GUNYAH_VM_STATUS_EXITED, _) => VmExit::Poweroff,
+                        (s, _) => return Err(anyhow!(format!("VM failed with status {}", s))),
+                    };
+                    self.exit.request(reason);
                     Ok(())
                 }
-                GUNYAH_VCPU_EXIT_STATUS => todo!(),
-                GUNYAH_VCPU_EXIT_PAGE_FAULT => {
-                    // SAFETY: Safe because we just checked exit_reason is GUNYAH_VCPU_EXIT_PAGE_FAULT and we are the only ones that run the vcpu
-                    let reason = unsafe { result.__bindgen_anon_1.page_fault };
-                    Err(anyhow!(format!(
-                        "Unexpected page fault at {:x}",
-                        reason.phys_addr
-                    )))
+                VcpuExit::PageFault { addr, .. } => {
+                    let page = addr & !(u64::try_from(page_size::get())? - 1);
+                    if self.debug.hit(vcpu.id(), page, addr)? {
+                        // The page is back, stop before the access is retried
+                        self.exit.request_pause();
+                        vcpu.complete_page_fault(ResumeAction::Retry)
+                    } else {
+                        Err(anyhow!(format!("Unexpected page fault at {:x}", addr)))
+                    }
                 }
-                e => Err(anyhow!(format!("Unknown exit reason: {}", e))),
-            }?;
+                VcpuExit::Unknown(GUNYAH_VCPU_EXIT_UNKNOWN) => {
+                    Err(anyhow!("Unexpected exit for unknown reason"))
+                }
+                VcpuExit::Unknown(e) => Err(anyhow!(format!("Unknown exit reason: {}", e))),
+            };
+            self.stats
+                .lock()
+                .unwrap()
+                .record(exit.reason(), start.elapsed());
+            handled?;
         }
     }
 
     pub fn vmmio_provide_read(&self, phys_addr: u64, data: &[u8]) -> Result<()> {
         let mut vcpu = self.vcpu.write().unwrap();
-        let result = vcpu.mmap_mut();
-        if result.exit_reason != GUNYAH_VCPU_EXIT_MMIO {
-            return Err(anyhow!("vCPU didn't exit for mmio"));
-        }
-        // SAFETY: Safe because we just checked exit_reason is GUNYAH_VCPU_EXIT_MMIO and we are the only ones that run the vcpu
-        let reason = unsafe { &mut result.__bindgen_anon_1.mmio };
-        if reason.is_write != 0 {
-            return Err(anyhow!("vCPU didn't exit for mmio read"));
-        }
-        if reason.phys_addr != phys_addr {
-            return Err(anyhow!(format!(
+        match vcpu.exit() {
+            VcpuExit::Mmio { addr, .. } if addr != phys_addr => Err(anyhow!(format!(
                 "vCPU didn't exit for mmio read at {}",
                 phys_addr
-            )));
-        }
-        if reason.len as usize != data.len() {
-            return Err(anyhow!("vCPU length didn't match"));
+            ))),
+            _ => vcpu.complete_mmio(Some(data), ResumeAction::Handled),
         }
+    }
 
-        reason.data.copy_from_slice(data);
-        reason.resume_action = GUNYAH_VCPU_RESUME_HANDLED as u8;
-
-        Ok(())
+    /// Why the vCPU last returned from running.
+    pub fn exit(&self) -> VcpuExit {
+        self.vcpu.read().unwrap().exit()
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use gunyah_bindings::gunyah_vcpu_exit::{GUNYAH_VCPU_EXIT_MMIO, GUNYAH_VCPU_EXIT_STATUS};
+
+    use super::{ExitStats, VcpuStats};
+
+    #[test]
+    fn stats() {
+        let mut stats = VcpuStats::default();
+        assert_eq!(stats.to_string(), "");
+        stats.record(GUNYAH_VCPU_EXIT_MMIO, Duration::from_micros(3));
+        stats.record(GUNYAH_VCPU_EXIT_MMIO, Duration::from_micros(5));
+        stats.record(GUNYAH_VCPU_EXIT_STATUS, Duration::from_micros(1));
+        stats.record(42, Duration::ZERO);
+        assert_eq!(
+            stats.mmio,
+            ExitStats {
+                count: 2,
+                time: Duration::from_micros(8),
+            }
+        );
+        assert_eq!(stats.status.count, 1);
+        assert_eq!(stats.other.count, 1);
+        assert_eq!(stats.page_fault, ExitStats::default());
 
-    pub fn status(&self) -> gunyah_vcpu_run {
-        let vcpu = self.vcpu.read().unwrap();
-        *vcpu.mmap()
+        let report = stats.to_string();
+        assert_eq!(report.lines().count(), 3);
+        assert!(report.starts_with("mmio                2 exits"));
+        assert!(report.contains("4.000µs average"));
     }
 }
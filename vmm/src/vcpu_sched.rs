@@ -0,0 +1,113 @@
+// Copyright (c) 2024, Qualcomm Innovation Center, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause-Clear
+
+//! How the hypervisor schedules the VM's vCPUs, described to the Resource Manager in the `vcpus`
+//! node of the VM's configuration, see [`crate::GunyahVirtualMachine::set_vcpu_scheduling`].
+
+use anyhow::{anyhow, Result};
+use vm_fdt::FdtWriter;
+
+/// Where vCPUs run.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum VcpuAffinity {
+    /// vCPUs run on the host CPU whose thread runs them, see [`crate::GunyahVcpu::run`]
+    #[default]
+    Proxy,
+    /// Each vCPU only runs on the physical CPU at its ID in the map
+    Static(Vec<u32>),
+    /// Each vCPU starts on the physical CPU at its ID in the map and the hypervisor may migrate
+    /// it later
+    Sticky(Vec<u32>),
+}
+
+/// Scheduling of the VM's vCPUs by the hypervisor.
+///
+/// The Resource Manager takes a physical CPU for each vCPU, but only one priority and timeslice
+/// for all of them.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VcpuScheduling {
+    pub affinity: VcpuAffinity,
+    /// Relative to the hypervisor's default priority, higher runs first
+    pub priority: Option<i32>,
+    /// Time a vCPU runs before others of the same priority get their turn, in microseconds
+    pub timeslice_us: Option<u32>,
+}
+
+impl VcpuScheduling {
+    /// Writes the `vcpus` node of the VM's configuration for vCPUs with IDs up to `vcpus`.
+    pub(crate) fn generate(&self, fdt: &mut FdtWriter, vcpus: u32) -> Result<()> {
+        let vcpus_node = fdt.begin_node("vcpus")?;
+        let (affinity, map) = match &self.affinity {
+            VcpuAffinity::Proxy => ("proxy", None),
+            VcpuAffinity::Static(map) => ("static", Some(map)),
+            VcpuAffinity::Sticky(map) => ("sticky", Some(map)),
+        };
+        fdt.property_string("affinity", affinity)?;
+        if let Some(map) = map {
+            if map.len() != vcpus as usize {
+                return Err(anyhow!(
+                    "{} vCPU affinity maps {} vCPUs, the VM has {}",
+                    affinity,
+                    map.len(),
+                    vcpus
+                ));
+            }
+            fdt.property_array_u32("affinity-map", map)?;
+        }
+        if let Some(priority) = self.priority {
+            fdt.property_u32("sched-priority", priority as u32)?;
+        }
+        if let Some(timeslice) = self.timeslice_us {
+            fdt.property_u32("sched-timeslice", timeslice)?;
+        }
+        fdt.end_node(vcpus_node)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use claim::{assert_err, assert_ok};
+    use vm_fdt::FdtWriter;
+
+    use super::{VcpuAffinity, VcpuScheduling};
+    use crate::parse_fdt;
+
+    fn generate(sched: &VcpuScheduling, vcpus: u32) -> anyhow::Result<Vec<u8>> {
+        let mut fdt = FdtWriter::new()?;
+        let root = fdt.begin_node("")?;
+        sched.generate(&mut fdt, vcpus)?;
+        fdt.end_node(root)?;
+        Ok(fdt.finish()?)
+    }
+
+    #[test]
+    fn proxy() {
+        let blob = assert_ok!(generate(&VcpuScheduling::default(), 2));
+        let fdt = assert_ok!(parse_fdt(&blob));
+        assert_eq!(fdt.prop_str("/vcpus", "affinity"), Some("proxy"));
+        assert!(!fdt.has_prop("/vcpus", "affinity-map"));
+        assert!(!fdt.has_prop("/vcpus", "sched-priority"));
+    }
+
+    #[test]
+    fn static_affinity() {
+        let sched = VcpuScheduling {
+            affinity: VcpuAffinity::Static(vec![2, 3]),
+            priority: Some(-1),
+            timeslice_us: Some(5000),
+        };
+        let blob = assert_ok!(generate(&sched, 2));
+        let fdt = assert_ok!(parse_fdt(&blob));
+        assert_eq!(fdt.prop_str("/vcpus", "affinity"), Some("static"));
+        assert_eq!(
+            fdt.prop_u32_array("/vcpus", "affinity-map"),
+            Some(vec![2, 3])
+        );
+        assert_eq!(fdt.prop_u32("/vcpus", "sched-priority"), Some(u32::MAX));
+        assert_eq!(fdt.prop_u32("/vcpus", "sched-timeslice"), Some(5000));
+
+        // Every vCPU needs a physical CPU
+        assert_err!(generate(&sched, 3));
+    }
+}
@@ -0,0 +1,518 @@
+// Copyright (c) 2024, Qualcomm Innovation Center, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause-Clear
+
+//! Frontend side of the vhost-user protocol, used to hand virtqueues to a device backend running
+//! in another process.
+//!
+//! Guest physical addresses double as the "frontend virtual addresses" in the memory table, so
+//! virtqueue addresses are passed to the backend unchanged. Once a device is activated, the
+//! guest kicks the backend through an ioeventfd on the queue notify register and the backend
+//! interrupts the guest through the device's irqfd, so the VMM isn't involved in the data path.
+
+use std::{
+    io::{Read, Write},
+    os::{
+        fd::{AsRawFd, RawFd},
+        unix::net::UnixStream,
+    },
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{bail, Context, Result};
+use gunyah::{Ioeventfd, ShareType};
+use vmm_sys_util::sock_ctrl_msg::ScmSocket;
+
+use crate::{
+    GuestMemory, GunyahGuestMemoryRegion, GunyahVirtualMachine, VirtioDevice, VirtioInterrupt,
+    VirtioMmio, Virtqueue, VIRTIO_MMIO_QUEUE_NOTIFY,
+};
+
+/// Backend supports the `GET_PROTOCOL_FEATURES` family of requests.
+pub const VHOST_USER_F_PROTOCOL_FEATURES: u64 = 1 << 30;
+
+/// Backend supports `GET_CONFIG`.
+const VHOST_USER_PROTOCOL_F_CONFIG: u64 = 1 << 9;
+
+const VHOST_USER_VERSION: u32 = 0x1;
+const VHOST_USER_REPLY: u32 = 0x4;
+
+const VHOST_USER_GET_FEATURES: u32 = 1;
+const VHOST_USER_SET_FEATURES: u32 = 2;
+const VHOST_USER_SET_OWNER: u32 = 3;
+const VHOST_USER_SET_MEM_TABLE: u32 = 5;
+const VHOST_USER_SET_VRING_NUM: u32 = 8;
+const VHOST_USER_SET_VRING_ADDR: u32 = 9;
+const VHOST_USER_SET_VRING_BASE: u32 = 10;
+const VHOST_USER_GET_VRING_BASE: u32 = 11;
+const VHOST_USER_SET_VRING_KICK: u32 = 12;
+const VHOST_USER_SET_VRING_CALL: u32 = 13;
+const VHOST_USER_GET_PROTOCOL_FEATURES: u32 = 15;
+const VHOST_USER_SET_PROTOCOL_FEATURES: u32 = 16;
+const VHOST_USER_SET_VRING_ENABLE: u32 = 18;
+const VHOST_USER_GET_CONFIG: u32 = 24;
+
+/// Maximum number of memory regions in `SET_MEM_TABLE`.
+const VHOST_USER_MAX_REGIONS: usize = 8;
+
+pub struct VhostUserFrontend {
+    socket: UnixStream,
+    features: u64,
+    /// Protocol features negotiated with the backend, if it supports any
+    protocol_features: Option<u64>,
+}
+
+impl VhostUserFrontend {
+    /// Connects to the backend listening on `path` and takes ownership of it.
+    pub fn connect(path: &Path) -> Result<Self> {
+        let socket = UnixStream::connect(path)
+            .context(format!("Failed to connect to {}", path.display()))?;
+        Self::from_stream(socket)
+    }
+
+    /// Takes ownership of the backend connected to `socket`.
+    pub fn from_stream(socket: UnixStream) -> Result<Self> {
+        let mut frontend = Self {
+            socket,
+            features: 0,
+            protocol_features: None,
+        };
+
+        frontend.send(VHOST_USER_SET_OWNER, &[], &[])?;
+        frontend.features = frontend.get_u64(VHOST_USER_GET_FEATURES)?;
+        if frontend.features & VHOST_USER_F_PROTOCOL_FEATURES != 0 {
+            let protocol_features =
+                frontend.get_u64(VHOST_USER_GET_PROTOCOL_FEATURES)? & VHOST_USER_PROTOCOL_F_CONFIG;
+            frontend.send(
+                VHOST_USER_SET_PROTOCOL_FEATURES,
+                &protocol_features.to_le_bytes(),
+                &[],
+            )?;
+            frontend.protocol_features = Some(protocol_features);
+        }
+        Ok(frontend)
+    }
+
+    /// virtio features offered by the backend.
+    pub fn features(&self) -> u64 {
+        self.features & !VHOST_USER_F_PROTOCOL_FEATURES
+    }
+
+    fn send(&mut self, request: u32, payload: &[u8], fds: &[RawFd]) -> Result<()> {
+        let mut header = [0u8; 12];
+        header[..4].copy_from_slice(&request.to_le_bytes());
+        header[4..8].copy_from_slice(&VHOST_USER_VERSION.to_le_bytes());
+        header[8..].copy_from_slice(&u32::try_from(payload.len())?.to_le_bytes());
+        let len = header.len() + payload.len();
+        let sent = self
+            .socket
+            .send_with_fds(&[&header[..], payload], fds)
+            .context(format!("Failed to send vhost-user request {}", request))?;
+        if sent != len {
+            bail!("Short write of vhost-user request {}", request);
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self, request: u32, payload: &mut [u8]) -> Result<()> {
+        let mut header = [0u8; 12];
+        self.socket.read_exact(&mut header).context(format!(
+            "Failed to read reply to vhost-user request {}",
+            request
+        ))?;
+        let reply_request = u32::from_le_bytes(header[..4].try_into()?);
+        let flags = u32::from_le_bytes(header[4..8].try_into()?);
+        let size = u32::from_le_bytes(header[8..].try_into()?);
+        if reply_request != request || flags & VHOST_USER_REPLY == 0 {
+            bail!(
+                "Unexpected reply to vhost-user request {}: request={} flags={:#x}",
+                request,
+                reply_request,
+                flags
+            );
+        }
+        if size as usize != payload.len() {
+            bail!(
+                "Unexpected reply size {} to vhost-user request {}",
+                size,
+                request
+            );
+        }
+        self.socket.read_exact(payload)?;
+        Ok(())
+    }
+
+    fn get_u64(&mut self, request: u32) -> Result<u64> {
+        self.send(request, &[], &[])?;
+        let mut payload = [0u8; 8];
+        self.receive(request, &mut payload)?;
+        Ok(u64::from_le_bytes(payload))
+    }
+
+    fn vring_state(index: usize, num: u32) -> Result<[u8; 8]> {
+        let mut state = [0u8; 8];
+        state[..4].copy_from_slice(&u32::try_from(index)?.to_le_bytes());
+        state[4..].copy_from_slice(&num.to_le_bytes());
+        Ok(state)
+    }
+
+    /// Acknowledges the virtio features negotiated with the driver.
+    pub fn set_features(&mut self, features: u64) -> Result<()> {
+        let features = if self.protocol_features.is_some() {
+            features | VHOST_USER_F_PROTOCOL_FEATURES
+        } else {
+            features
+        };
+        self.send(VHOST_USER_SET_FEATURES, &features.to_le_bytes(), &[])
+    }
+
+    /// Reads `data.len()` bytes at `offset` of the device's configuration space from the backend.
+    pub fn get_config(&mut self, offset: u32, data: &mut [u8]) -> Result<()> {
+        if self.protocol_features.unwrap_or_default() & VHOST_USER_PROTOCOL_F_CONFIG == 0 {
+            bail!("Backend doesn't provide a configuration space");
+        }
+        let mut payload = vec![0u8; 12 + data.len()];
+        payload[..4].copy_from_slice(&offset.to_le_bytes());
+        payload[4..8].copy_from_slice(&u32::try_from(data.len())?.to_le_bytes());
+        self.send(VHOST_USER_GET_CONFIG, &payload, &[])?;
+        self.receive(VHOST_USER_GET_CONFIG, &mut payload)?;
+        data.copy_from_slice(&payload[12..]);
+        Ok(())
+    }
+
+    /// Shares `regions` with the backend. Lent memory is inaccessible to the host once the VM
+    /// runs, so only shared regions can be handed to the backend.
+    pub fn set_mem_table(&mut self, regions: &[Arc<Mutex<GunyahGuestMemoryRegion>>]) -> Result<()> {
+        if regions.len() > VHOST_USER_MAX_REGIONS {
+            bail!(
+                "vhost-user supports at most {} memory regions",
+                VHOST_USER_MAX_REGIONS
+            );
+        }
+        let mut payload = Vec::new();
+        payload.extend_from_slice(&u32::try_from(regions.len())?.to_le_bytes());
+        payload.extend_from_slice(&0u32.to_le_bytes());
+        let mut fds = Vec::new();
+        for region in regions {
+            let region = region.lock().unwrap();
+            if region.share_type() != ShareType::Share {
+                bail!(
+                    "Guest memory at {:#x} is lent to the guest and can't be exported to a vhost-user backend",
+                    region.guest_address()
+                );
+            }
+            let mem = region.as_region();
+            payload.extend_from_slice(&region.guest_address().to_le_bytes());
+            payload.extend_from_slice(&(mem.size() as u64).to_le_bytes());
+            payload.extend_from_slice(&region.guest_address().to_le_bytes());
+            payload.extend_from_slice(&mem.offset().to_le_bytes());
+            fds.push(mem.as_guest_mem().as_raw_fd());
+        }
+        self.send(VHOST_USER_SET_MEM_TABLE, &payload, &fds)
+    }
+
+    /// Hands `queue` over to the backend. The backend is kicked through `kick` and signals used
+    /// buffers through `call`.
+    pub fn start_vring(
+        &mut self,
+        index: usize,
+        queue: &Virtqueue,
+        kick: RawFd,
+        call: RawFd,
+    ) -> Result<()> {
+        self.send(
+            VHOST_USER_SET_VRING_NUM,
+            &Self::vring_state(index, queue.size().into())?,
+            &[],
+        )?;
+        self.send(
+            VHOST_USER_SET_VRING_BASE,
+            &Self::vring_state(index, 0)?,
+            &[],
+        )?;
+
+        let mut addr = Vec::new();
+        addr.extend_from_slice(&u32::try_from(index)?.to_le_bytes());
+        addr.extend_from_slice(&0u32.to_le_bytes());
+        addr.extend_from_slice(&queue.desc_table().to_le_bytes());
+        addr.extend_from_slice(&queue.used_ring().to_le_bytes());
+        addr.extend_from_slice(&queue.avail_ring().to_le_bytes());
+        addr.extend_from_slice(&0u64.to_le_bytes());
+        self.send(VHOST_USER_SET_VRING_ADDR, &addr, &[])?;
+
+        let index_u64 = u64::try_from(index)?.to_le_bytes();
+        self.send(VHOST_USER_SET_VRING_CALL, &index_u64, &[call])?;
+        self.send(VHOST_USER_SET_VRING_KICK, &index_u64, &[kick])?;
+        if self.protocol_features.is_some() {
+            self.send(
+                VHOST_USER_SET_VRING_ENABLE,
+                &Self::vring_state(index, 1)?,
+                &[],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Stops the backend from processing queue `index`.
+    pub fn stop_vring(&mut self, index: usize) -> Result<()> {
+        self.send(
+            VHOST_USER_GET_VRING_BASE,
+            &Self::vring_state(index, 0)?,
+            &[],
+        )?;
+        let mut state = [0u8; 8];
+        self.receive(VHOST_USER_GET_VRING_BASE, &mut state)
+    }
+}
+
+/// Where a [`VhostUserDevice`]'s configuration space comes from.
+pub enum VhostUserConfig {
+    /// Fixed contents provided by the VMM, e.g. the mount tag of a virtio-fs device
+    Local(Vec<u8>),
+    /// Forwarded to the backend with `GET_CONFIG`
+    Backend,
+}
+
+/// virtio device whose queues are processed by a vhost-user backend.
+pub struct VhostUserDevice {
+    frontend: Mutex<VhostUserFrontend>,
+    device_type: u32,
+    queue_sizes: Vec<u16>,
+    config: VhostUserConfig,
+    regions: Vec<Arc<Mutex<GunyahGuestMemoryRegion>>>,
+    vm: gunyah::Vm,
+    notify_address: u64,
+    /// Started queues and the ioeventfds kicking them
+    kicks: Vec<(usize, Ioeventfd)>,
+}
+
+impl VhostUserDevice {
+    /// Adds a virtio device of type `device_type` at `base` whose queues are handed to the
+    /// backend listening on `socket`. The backend gets access to `regions`, which must be shared
+    /// with the guest.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        vm: &mut GunyahVirtualMachine,
+        base: u64,
+        interrupt_line: u32,
+        socket: &Path,
+        device_type: u32,
+        queue_sizes: Vec<u16>,
+        config: VhostUserConfig,
+        regions: Vec<Arc<Mutex<GunyahGuestMemoryRegion>>>,
+    ) -> Result<Arc<Mutex<VirtioMmio<Self>>>> {
+        let device = Self {
+            frontend: Mutex::new(VhostUserFrontend::connect(socket)?),
+            device_type,
+            queue_sizes,
+            config,
+            regions,
+            vm: vm.vm().clone(),
+            notify_address: base + VIRTIO_MMIO_QUEUE_NOTIFY,
+            kicks: Vec::new(),
+        };
+        VirtioMmio::new(vm, base, interrupt_line, device)
+    }
+}
+
+impl VirtioDevice for VhostUserDevice {
+    fn debug_label(&self) -> String {
+        format!("vhost-user type {}", self.device_type)
+    }
+
+    fn device_type(&self) -> u32 {
+        self.device_type
+    }
+
+    fn queue_max_sizes(&self) -> Vec<u16> {
+        self.queue_sizes.clone()
+    }
+
+    fn features(&self) -> u64 {
+        self.frontend.lock().unwrap().features()
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        data.fill(0);
+        match &self.config {
+            VhostUserConfig::Local(config) => {
+                if let Some(src) = config.get(offset as usize..) {
+                    let len = src.len().min(data.len());
+                    data[..len].copy_from_slice(&src[..len]);
+                }
+            }
+            VhostUserConfig::Backend => {
+                if let Err(e) = self
+                    .frontend
+                    .lock()
+                    .unwrap()
+                    .get_config(offset as u32, data)
+                {
+                    println!("Failed to read vhost-user config at {:#x}: {:?}", offset, e);
+                }
+            }
+        }
+    }
+
+    fn fast_notify(&self) -> bool {
+        // Guest kicks go to the backend through the ioeventfds registered in activate
+        false
+    }
+
+    fn activate(
+        &mut self,
+        queues: &[Virtqueue],
+        features: u64,
+        interrupt: &VirtioInterrupt,
+    ) -> Result<()> {
+        let frontend = self.frontend.get_mut().unwrap();
+        frontend.set_features(features)?;
+        frontend.set_mem_table(&self.regions)?;
+        for (index, queue) in queues.iter().enumerate() {
+            if !queue.is_ready() {
+                continue;
+            }
+            let kick = Ioeventfd::new(
+                self.vm.clone(),
+                self.notify_address,
+                4,
+                Some(index.try_into()?),
+            )?;
+            frontend.start_vring(
+                index,
+                queue,
+                kick.as_raw_fd(),
+                interrupt.used_queue_eventfd(),
+            )?;
+            self.kicks.push((index, kick));
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        let frontend = self.frontend.get_mut().unwrap();
+        for (index, _) in &self.kicks {
+            if let Err(e) = frontend.stop_vring(*index) {
+                println!("Failed to stop vhost-user queue {}: {:?}", index, e);
+            }
+        }
+        self.kicks.clear();
+    }
+
+    fn process_queue(
+        &mut self,
+        index: usize,
+        _queue: &mut Virtqueue,
+        _mem: &GuestMemory,
+    ) -> Result<bool> {
+        // Guest kicks go straight to the backend through the ioeventfd, this is only reached when
+        // the VMM notifies the queue itself
+        let (_, kick) = self
+            .kicks
+            .iter()
+            .find(|(started, _)| *started == index)
+            .context(format!("Queue {} was not started", index))?;
+        (&mut kick.as_file()).write_all(&1u64.to_ne_bytes())?;
+        // The backend returns buffers and raises the interrupt itself
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        os::unix::net::UnixStream,
+        thread,
+    };
+
+    use super::{
+        VhostUserFrontend, VHOST_USER_F_PROTOCOL_FEATURES, VHOST_USER_GET_CONFIG,
+        VHOST_USER_GET_FEATURES, VHOST_USER_GET_PROTOCOL_FEATURES, VHOST_USER_PROTOCOL_F_CONFIG,
+        VHOST_USER_SET_OWNER, VHOST_USER_SET_PROTOCOL_FEATURES,
+    };
+
+    fn read_request(socket: &mut UnixStream) -> (u32, Vec<u8>) {
+        let mut header = [0u8; 12];
+        socket.read_exact(&mut header).unwrap();
+        let mut payload = vec![0u8; u32::from_le_bytes(header[8..].try_into().unwrap()) as usize];
+        socket.read_exact(&mut payload).unwrap();
+        (u32::from_le_bytes(header[..4].try_into().unwrap()), payload)
+    }
+
+    fn reply(socket: &mut UnixStream, request: u32, payload: &[u8]) {
+        let mut reply = Vec::new();
+        reply.extend_from_slice(&request.to_le_bytes());
+        reply.extend_from_slice(&0x5u32.to_le_bytes());
+        reply.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        reply.extend_from_slice(payload);
+        socket.write_all(&reply).unwrap();
+    }
+
+    #[test]
+    fn handshake() {
+        let (frontend, mut backend) = UnixStream::pair().unwrap();
+        let backend = thread::spawn(move || {
+            assert_eq!(read_request(&mut backend), (VHOST_USER_SET_OWNER, vec![]));
+            assert_eq!(
+                read_request(&mut backend),
+                (VHOST_USER_GET_FEATURES, vec![])
+            );
+            reply(
+                &mut backend,
+                VHOST_USER_GET_FEATURES,
+                &(1u64 << 32 | 1).to_le_bytes(),
+            );
+        });
+
+        let mut frontend = VhostUserFrontend::from_stream(frontend).unwrap();
+        backend.join().unwrap();
+        assert_eq!(frontend.features(), 1 << 32 | 1);
+        // Without protocol features there's no way to ask for the configuration space
+        assert!(frontend.get_config(0, &mut [0u8; 4]).is_err());
+    }
+
+    #[test]
+    fn backend_config() {
+        let (frontend, mut backend) = UnixStream::pair().unwrap();
+        let backend = thread::spawn(move || {
+            read_request(&mut backend);
+            read_request(&mut backend);
+            reply(
+                &mut backend,
+                VHOST_USER_GET_FEATURES,
+                &VHOST_USER_F_PROTOCOL_FEATURES.to_le_bytes(),
+            );
+            assert_eq!(
+                read_request(&mut backend),
+                (VHOST_USER_GET_PROTOCOL_FEATURES, vec![])
+            );
+            reply(
+                &mut backend,
+                VHOST_USER_GET_PROTOCOL_FEATURES,
+                &(VHOST_USER_PROTOCOL_F_CONFIG | 1).to_le_bytes(),
+            );
+            // Only the features the frontend knows about are acknowledged
+            assert_eq!(
+                read_request(&mut backend),
+                (
+                    VHOST_USER_SET_PROTOCOL_FEATURES,
+                    VHOST_USER_PROTOCOL_F_CONFIG.to_le_bytes().to_vec()
+                )
+            );
+
+            let (request, mut payload) = read_request(&mut backend);
+            assert_eq!(request, VHOST_USER_GET_CONFIG);
+            assert_eq!(&payload[..8], &[4, 0, 0, 0, 2, 0, 0, 0]);
+            payload[12..].copy_from_slice(&[0xab, 0xcd]);
+            reply(&mut backend, VHOST_USER_GET_CONFIG, &payload);
+        });
+
+        let mut frontend = VhostUserFrontend::from_stream(frontend).unwrap();
+        assert_eq!(frontend.features(), 0);
+        let mut data = [0u8; 2];
+        frontend.get_config(4, &mut data).unwrap();
+        backend.join().unwrap();
+        assert_eq!(data, [0xab, 0xcd]);
+    }
+}
@@ -0,0 +1,941 @@
+// Copyright (c) 2024, Qualcomm Innovation Center, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause-Clear
+
+//! virtio-mmio (version 2) transport with split virtqueues.
+//!
+//! Devices only see guest memory through the VMM's [`Bus`], so the guest memory backing the
+//! virtqueues and buffers must be accessible to the host, e.g. shared instead of lent. Devices
+//! behind a [`crate::VirtioIommu`] additionally only see what the driver mapped for them.
+
+use std::{
+    os::fd::{AsRawFd, RawFd},
+    sync::{
+        atomic::{fence, AtomicBool, AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use crate::{
+    AccessId, BusAccessInfo, BusDevice, BusRange, FastWriteRegion, FdtWriter, GunyahInterrupt,
+    GunyahVirtualMachine, IommuEndpoint, RestrictedDmaPool, RESTRICTED_DMA_PHANDLE,
+};
+
+pub const VIRTIO_MMIO_SIZE: u64 = 0x200;
+/// Offset of the register the driver writes a queue's index to after making buffers available.
+pub const VIRTIO_MMIO_QUEUE_NOTIFY: u64 = 0x050;
+
+pub const VIRTIO_ID_CONSOLE: u32 = 3;
+pub const VIRTIO_ID_BALLOON: u32 = 5;
+pub const VIRTIO_ID_9P: u32 = 9;
+pub const VIRTIO_ID_GPU: u32 = 16;
+pub const VIRTIO_ID_INPUT: u32 = 18;
+pub const VIRTIO_ID_IOMMU: u32 = 23;
+pub const VIRTIO_ID_PMEM: u32 = 27;
+pub const VIRTIO_ID_FS: u32 = 26;
+
+/// Device complies with the virtio 1.x specification. Always offered by [`VirtioMmio`].
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+/// Device accesses memory through an iommu or a restricted DMA pool, so the driver must use the
+/// DMA API. Offered by [`VirtioMmio`] for devices behind either.
+pub const VIRTIO_F_ACCESS_PLATFORM: u64 = 1 << 33;
+
+const VIRTIO_MMIO_MAGIC: u32 = 0x7472_6976; // "virt"
+const VIRTIO_MMIO_VERSION: u32 = 2;
+
+const VIRTIO_MMIO_MAGIC_VALUE: u64 = 0x000;
+const VIRTIO_MMIO_VERSION_REG: u64 = 0x004;
+const VIRTIO_MMIO_DEVICE_ID: u64 = 0x008;
+const VIRTIO_MMIO_VENDOR_ID: u64 = 0x00c;
+const VIRTIO_MMIO_DEVICE_FEATURES: u64 = 0x010;
+const VIRTIO_MMIO_DEVICE_FEATURES_SEL: u64 = 0x014;
+const VIRTIO_MMIO_DRIVER_FEATURES: u64 = 0x020;
+const VIRTIO_MMIO_DRIVER_FEATURES_SEL: u64 = 0x024;
+const VIRTIO_MMIO_QUEUE_SEL: u64 = 0x030;
+const VIRTIO_MMIO_QUEUE_NUM_MAX: u64 = 0x034;
+const VIRTIO_MMIO_QUEUE_NUM: u64 = 0x038;
+const VIRTIO_MMIO_QUEUE_READY: u64 = 0x044;
+const VIRTIO_MMIO_INTERRUPT_STATUS: u64 = 0x060;
+const VIRTIO_MMIO_INTERRUPT_ACK: u64 = 0x064;
+const VIRTIO_MMIO_STATUS: u64 = 0x070;
+const VIRTIO_MMIO_QUEUE_DESC_LOW: u64 = 0x080;
+const VIRTIO_MMIO_QUEUE_DESC_HIGH: u64 = 0x084;
+const VIRTIO_MMIO_QUEUE_DRIVER_LOW: u64 = 0x090;
+const VIRTIO_MMIO_QUEUE_DRIVER_HIGH: u64 = 0x094;
+const VIRTIO_MMIO_QUEUE_DEVICE_LOW: u64 = 0x0a0;
+const VIRTIO_MMIO_QUEUE_DEVICE_HIGH: u64 = 0x0a4;
+const VIRTIO_MMIO_CONFIG_GENERATION: u64 = 0x0fc;
+const VIRTIO_MMIO_CONFIG: u64 = 0x100;
+
+const VIRTIO_STATUS_DRIVER_OK: u32 = 4;
+
+const VIRTIO_MMIO_INT_VRING: u32 = 1;
+const VIRTIO_MMIO_INT_CONFIG: u32 = 2;
+
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+const VIRTQ_DESC_F_INDIRECT: u16 = 4;
+
+/// Guest memory as seen by virtio devices.
+///
+/// Accesses which hit the device's own MMIO window are rejected, because the device is already
+/// locked while it processes its queues. Addresses of devices behind an iommu are translated
+/// first. Devices using a restricted DMA pool can't access memory outside of it.
+#[derive(Clone, Debug)]
+pub struct GuestMemory {
+    bus: crate::Bus,
+    exclude: BusRange,
+    iommu: Option<IommuEndpoint>,
+    restricted_dma: Option<BusRange>,
+}
+
+impl GuestMemory {
+    pub fn new(bus: crate::Bus, exclude: BusRange) -> Self {
+        Self {
+            bus,
+            exclude,
+            iommu: None,
+            restricted_dma: None,
+        }
+    }
+
+    /// Guest physical ranges backing `len` bytes at device address `addr`.
+    fn translate(&self, addr: u64, len: usize, write: bool) -> Result<Vec<(u64, usize)>> {
+        let Some(iommu) = &self.iommu else {
+            return Ok(vec![(addr, len)]);
+        };
+        Ok(iommu
+            .translate(addr, len as u64, write)?
+            .into_iter()
+            .map(|(addr, len)| (addr, len as usize))
+            .collect())
+    }
+
+    fn check(&self, addr: u64, len: usize) -> Result<()> {
+        if self.exclude.overlaps(addr, len as u64) {
+            bail!("Guest buffer {:#x}+{:#x} overlaps the device", addr, len);
+        }
+        if let Some(pool) = &self.restricted_dma {
+            let end = addr.checked_add(len as u64);
+            if addr < pool.base || end.is_none_or(|end| end > pool.base + pool.len) {
+                bail!(
+                    "Guest buffer {:#x}+{:#x} lies outside the restricted DMA pool",
+                    addr,
+                    len
+                );
+            }
+        }
+        Ok(())
+    }
+
+    pub fn read(&self, addr: u64, data: &mut [u8]) -> Result<()> {
+        let mut done = 0;
+        for (addr, len) in self.translate(addr, data.len(), false)? {
+            self.check(addr, len)?;
+            self.bus.read(addr, &mut data[done..done + len])?;
+            done += len;
+        }
+        Ok(())
+    }
+
+    pub fn write(&self, addr: u64, data: &[u8]) -> Result<()> {
+        let mut done = 0;
+        for (addr, len) in self.translate(addr, data.len(), true)? {
+            self.check(addr, len)?;
+            self.bus.write(addr, &data[done..done + len])?;
+            done += len;
+        }
+        Ok(())
+    }
+
+    pub fn read_u16(&self, addr: u64) -> Result<u16> {
+        let mut data = [0u8; 2];
+        self.read(addr, &mut data)?;
+        Ok(u16::from_le_bytes(data))
+    }
+
+    pub fn read_u32(&self, addr: u64) -> Result<u32> {
+        let mut data = [0u8; 4];
+        self.read(addr, &mut data)?;
+        Ok(u32::from_le_bytes(data))
+    }
+
+    pub fn read_u64(&self, addr: u64) -> Result<u64> {
+        let mut data = [0u8; 8];
+        self.read(addr, &mut data)?;
+        Ok(u64::from_le_bytes(data))
+    }
+
+    pub fn write_u16(&self, addr: u64, value: u16) -> Result<()> {
+        self.write(addr, &value.to_le_bytes())
+    }
+
+    pub fn write_u32(&self, addr: u64, value: u32) -> Result<()> {
+        self.write(addr, &value.to_le_bytes())
+    }
+}
+
+/// A buffer described by a virtqueue descriptor.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Descriptor {
+    pub addr: u64,
+    pub len: u32,
+    flags: u16,
+}
+
+impl Descriptor {
+    /// Whether the device writes to this buffer (as opposed to reading from it).
+    pub fn is_write_only(&self) -> bool {
+        self.flags & VIRTQ_DESC_F_WRITE != 0
+    }
+}
+
+/// A request made available by the driver: the head index and its chained buffers.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DescriptorChain {
+    pub head: u16,
+    pub descriptors: Vec<Descriptor>,
+}
+
+impl DescriptorChain {
+    /// Copies the device-readable buffers into a single vector. The guest picks their sizes, so
+    /// nothing is allocated if they add up to more than `max_len` bytes or any of them wraps
+    /// around the end of the address space.
+    pub fn read_all(&self, mem: &GuestMemory, max_len: usize) -> Result<Vec<u8>> {
+        let readable = || self.descriptors.iter().filter(|d| !d.is_write_only());
+        let mut len = 0usize;
+        for desc in readable() {
+            if desc.addr.checked_add(u64::from(desc.len)).is_none() {
+                bail!(
+                    "Guest buffer {:#x}+{:#x} overflows the address space",
+                    desc.addr,
+                    desc.len
+                );
+            }
+            len = len.saturating_add(desc.len as usize);
+        }
+        if len > max_len {
+            bail!(
+                "Guest request of {:#x} bytes is larger than {:#x}",
+                len,
+                max_len
+            );
+        }
+        let mut data = vec![0; len];
+        let mut done = 0;
+        for desc in readable() {
+            let end = done + desc.len as usize;
+            mem.read(desc.addr, &mut data[done..end])?;
+            done = end;
+        }
+        Ok(data)
+    }
+
+    /// Fills the device-writable buffers from `data`. Returns how many bytes were written.
+    pub fn write_all(&self, mem: &GuestMemory, data: &[u8]) -> Result<usize> {
+        let mut written = 0;
+        for desc in self.descriptors.iter().filter(|d| d.is_write_only()) {
+            if written == data.len() {
+                break;
+            }
+            let len = (desc.len as usize).min(data.len() - written);
+            mem.write(desc.addr, &data[written..written + len])?;
+            written += len;
+        }
+        Ok(written)
+    }
+}
+
+/// Device side of a split virtqueue.
+#[derive(Debug, Default)]
+pub struct Virtqueue {
+    max_size: u16,
+    size: u16,
+    ready: bool,
+    desc_table: u64,
+    avail_ring: u64,
+    used_ring: u64,
+    next_avail: u16,
+    next_used: u16,
+}
+
+impl Virtqueue {
+    pub fn new(max_size: u16) -> Self {
+        Self {
+            max_size,
+            size: max_size,
+            ..Default::default()
+        }
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.ready
+    }
+
+    /// Number of descriptors the driver set up.
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    pub fn desc_table(&self) -> u64 {
+        self.desc_table
+    }
+
+    pub fn avail_ring(&self) -> u64 {
+        self.avail_ring
+    }
+
+    pub fn used_ring(&self) -> u64 {
+        self.used_ring
+    }
+
+    fn reset(&mut self) {
+        *self = Self::new(self.max_size);
+    }
+
+    /// A ready queue with its rings at the given addresses, for unit tests of devices.
+    #[cfg(test)]
+    pub(crate) fn with_rings(size: u16, desc_table: u64, avail_ring: u64, used_ring: u64) -> Self {
+        Self {
+            desc_table,
+            avail_ring,
+            used_ring,
+            ready: true,
+            ..Self::new(size)
+        }
+    }
+
+    fn read_descriptor(&self, mem: &GuestMemory, index: u16) -> Result<(Descriptor, u16)> {
+        if index >= self.size {
+            bail!("Descriptor index {} out of range", index);
+        }
+        let mut raw = [0u8; 16];
+        mem.read(ring_addr(self.desc_table, u64::from(index) * 16)?, &mut raw)?;
+        let flags = u16::from_le_bytes(raw[12..14].try_into().unwrap());
+        if flags & VIRTQ_DESC_F_INDIRECT != 0 {
+            bail!("Indirect descriptors are not supported");
+        }
+        let desc = Descriptor {
+            addr: u64::from_le_bytes(raw[..8].try_into().unwrap()),
+            len: u32::from_le_bytes(raw[8..12].try_into().unwrap()),
+            flags,
+        };
+        Ok((desc, u16::from_le_bytes(raw[14..].try_into().unwrap())))
+    }
+
+    /// Takes the next request made available by the driver, if any.
+    pub fn pop(&mut self, mem: &GuestMemory) -> Result<Option<DescriptorChain>> {
+        if !self.ready {
+            return Ok(None);
+        }
+        let avail_idx = mem.read_u16(ring_addr(self.avail_ring, 2)?)?;
+        if avail_idx == self.next_avail {
+            return Ok(None);
+        }
+        // Don't read the ring entry before the index that covers it
+        fence(Ordering::Acquire);
+
+        let slot = u64::from(self.next_avail % self.size);
+        let head = mem.read_u16(ring_addr(self.avail_ring, 4 + slot * 2)?)?;
+        self.next_avail = self.next_avail.wrapping_add(1);
+
+        let mut descriptors = Vec::new();
+        let mut index = head;
+        loop {
+            if descriptors.len() == self.size as usize {
+                bail!("Descriptor chain starting at {} loops", head);
+            }
+            let (desc, next) = self.read_descriptor(mem, index)?;
+            descriptors.push(desc);
+            if desc.flags & VIRTQ_DESC_F_NEXT == 0 {
+                break;
+            }
+            index = next;
+        }
+        Ok(Some(DescriptorChain { head, descriptors }))
+    }
+
+    /// Returns the request starting at `head` to the driver with `len` bytes written to it.
+    pub fn add_used(&mut self, mem: &GuestMemory, head: u16, len: u32) -> Result<()> {
+        let slot = u64::from(self.next_used % self.size);
+        let mut entry = [0u8; 8];
+        entry[..4].copy_from_slice(&u32::from(head).to_le_bytes());
+        entry[4..].copy_from_slice(&len.to_le_bytes());
+        mem.write(ring_addr(self.used_ring, 4 + slot * 8)?, &entry)?;
+        self.next_used = self.next_used.wrapping_add(1);
+        // The driver must see the entry before the index that covers it
+        fence(Ordering::Release);
+        mem.write_u16(ring_addr(self.used_ring, 2)?, self.next_used)
+    }
+}
+
+/// Address `offset` bytes into a ring at the guest programmed address `base`.
+fn ring_addr(base: u64, offset: u64) -> Result<u64> {
+    base.checked_add(offset)
+        .ok_or_else(|| anyhow!("Ring at {:#x} overflows the address space", base))
+}
+
+/// Interrupt of a virtio-mmio device, which can be raised without holding the transport's lock.
+#[derive(Clone, Debug)]
+pub struct VirtioInterrupt {
+    status: Arc<AtomicU32>,
+    /// Used buffers are signalled through the eventfd, bypassing `status`
+    external: Arc<AtomicBool>,
+    irq: Arc<GunyahInterrupt>,
+}
+
+impl VirtioInterrupt {
+    fn new(irq: Arc<GunyahInterrupt>) -> Self {
+        Self {
+            status: Arc::new(AtomicU32::new(0)),
+            external: Arc::new(AtomicBool::new(false)),
+            irq,
+        }
+    }
+
+    fn raise(&self, reason: u32) -> Result<()> {
+        self.status.fetch_or(reason, Ordering::SeqCst);
+        self.irq.trigger()
+    }
+
+    /// Tells the driver that buffers were returned on one of the queues.
+    pub fn signal_used_queue(&self) -> Result<()> {
+        self.raise(VIRTIO_MMIO_INT_VRING)
+    }
+
+    /// Returns an eventfd which interrupts the guest when written, so that a backend outside the
+    /// VMM can signal used buffers. Such interrupts can't update the interrupt status, so the
+    /// driver is told to check the queues on every interrupt until the device is reset.
+    pub fn used_queue_eventfd(&self) -> RawFd {
+        self.external.store(true, Ordering::SeqCst);
+        self.irq.as_raw_fd()
+    }
+
+    fn status(&self) -> u32 {
+        let status = self.status.load(Ordering::SeqCst);
+        if self.external.load(Ordering::SeqCst) {
+            status | VIRTIO_MMIO_INT_VRING
+        } else {
+            status
+        }
+    }
+
+    fn ack(&self, value: u32) {
+        self.status.fetch_and(!value, Ordering::SeqCst);
+    }
+
+    fn clear(&self) {
+        self.status.store(0, Ordering::SeqCst);
+        self.external.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Device specific part of a virtio device. The transport handles feature negotiation and queue
+/// setup.
+pub trait VirtioDevice: Send {
+    fn debug_label(&self) -> String;
+
+    /// virtio device ID, e.g. [`VIRTIO_ID_CONSOLE`].
+    fn device_type(&self) -> u32;
+
+    /// Maximum size of each of the device's queues.
+    fn queue_max_sizes(&self) -> Vec<u16>;
+
+    /// Device specific feature bits.
+    fn features(&self) -> u64 {
+        0
+    }
+
+    fn read_config(&self, _offset: u64, data: &mut [u8]) {
+        data.fill(0);
+    }
+
+    fn write_config(&mut self, _offset: u64, _data: &[u8]) -> Result<()> {
+        Err(anyhow!("Unhandled config write"))
+    }
+
+    /// Called when the driver resets the device.
+    fn reset(&mut self) {}
+
+    /// Whether the driver's queue notifications reach [`VirtioDevice::process_queue`] through
+    /// ioeventfds, see [`BusDevice::fast_write_regions`]. Devices which register their own
+    /// ioeventfds for the queue notify register return false.
+    fn fast_notify(&self) -> bool {
+        true
+    }
+
+    /// Adds device specific properties to the device's FDT node.
+    fn device_config(&self, _fdt: &mut FdtWriter) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called once the driver has set up the queues and negotiated `features`.
+    fn activate(
+        &mut self,
+        _queues: &[Virtqueue],
+        _features: u64,
+        _interrupt: &VirtioInterrupt,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    /// Processes requests on queue `index`. Returns true if buffers were returned to the driver.
+    fn process_queue(
+        &mut self,
+        index: usize,
+        queue: &mut Virtqueue,
+        mem: &GuestMemory,
+    ) -> Result<bool>;
+}
+
+/// virtio-mmio transport for a [`VirtioDevice`].
+pub struct VirtioMmio<D: VirtioDevice> {
+    base: u64,
+    device: D,
+    interrupt: VirtioInterrupt,
+    mem: GuestMemory,
+    queues: Vec<Virtqueue>,
+    queue_sel: u32,
+    device_features_sel: u32,
+    driver_features_sel: u32,
+    driver_features: u64,
+    status: u32,
+    config_generation: u32,
+}
+
+impl<D: VirtioDevice + 'static> VirtioMmio<D> {
+    /// Adds `device` to `vm` at `base`, signalling used buffers with SPI `interrupt_line`.
+    pub fn new(
+        vm: &mut GunyahVirtualMachine,
+        base: u64,
+        interrupt_line: u32,
+        device: D,
+    ) -> Result<Arc<Mutex<Self>>> {
+        let queues = device
+            .queue_max_sizes()
+            .into_iter()
+            .map(Virtqueue::new)
+            .collect();
+        let transport = Arc::new(Mutex::new(Self {
+            base,
+            device,
+            interrupt: VirtioInterrupt::new(vm.add_edge_interrupt(interrupt_line)?),
+            mem: GuestMemory::new(
+                vm.get_bus(AccessId::VmmUserspace),
+                BusRange {
+                    base,
+                    len: VIRTIO_MMIO_SIZE,
+                },
+            ),
+            queues,
+            queue_sel: 0,
+            device_features_sel: 0,
+            driver_features_sel: 0,
+            driver_features: 0,
+            status: 0,
+            config_generation: 0,
+        }));
+        vm.add_device(transport.clone(), base, VIRTIO_MMIO_SIZE)?;
+        Ok(transport)
+    }
+}
+
+impl<D: VirtioDevice> VirtioMmio<D> {
+    pub fn device(&self) -> &D {
+        &self.device
+    }
+
+    pub fn device_mut(&mut self) -> &mut D {
+        &mut self.device
+    }
+
+    /// Features negotiated with the driver.
+    pub fn acked_features(&self) -> u64 {
+        self.driver_features & self.device_features()
+    }
+
+    /// Puts the device behind an iommu, so it can only access memory the driver mapped for
+    /// `endpoint`. Must be called before the guest boots.
+    pub fn set_iommu(&mut self, endpoint: IommuEndpoint) {
+        self.mem.iommu = Some(endpoint);
+    }
+
+    /// Has the guest bounce the device's buffers through `pool`, so it only accesses memory
+    /// shared with the host. Must be called before the guest boots.
+    pub fn set_restricted_dma(&mut self, pool: &RestrictedDmaPool) {
+        self.mem.restricted_dma = Some(pool.range());
+    }
+
+    fn device_features(&self) -> u64 {
+        let features = self.device.features() | VIRTIO_F_VERSION_1;
+        if self.mem.iommu.is_some() || self.mem.restricted_dma.is_some() {
+            features | VIRTIO_F_ACCESS_PLATFORM
+        } else {
+            features
+        }
+    }
+
+    fn selected_queue(&mut self) -> Option<&mut Virtqueue> {
+        self.queues.get_mut(self.queue_sel as usize)
+    }
+
+    fn reset(&mut self) {
+        self.queues.iter_mut().for_each(Virtqueue::reset);
+        self.queue_sel = 0;
+        self.device_features_sel = 0;
+        self.driver_features_sel = 0;
+        self.driver_features = 0;
+        self.interrupt.clear();
+        self.status = 0;
+        self.device.reset();
+    }
+
+    /// Processes queue `index`, e.g. after the device has new data for the driver, and interrupts
+    /// the guest if buffers were used.
+    pub fn notify(&mut self, index: usize) -> Result<()> {
+        if self.status & VIRTIO_STATUS_DRIVER_OK == 0 {
+            return Ok(());
+        }
+        let queue = self
+            .queues
+            .get_mut(index)
+            .context(format!("Invalid queue {}", index))?;
+        if !queue.is_ready() {
+            return Ok(());
+        }
+        if self.device.process_queue(index, queue, &self.mem)? {
+            self.interrupt.signal_used_queue()?;
+        }
+        Ok(())
+    }
+
+    /// Tells the driver that the device's configuration space changed.
+    pub fn config_changed(&mut self) -> Result<()> {
+        self.config_generation = self.config_generation.wrapping_add(1);
+        if self.status & VIRTIO_STATUS_DRIVER_OK == 0 {
+            return Ok(());
+        }
+        self.interrupt.raise(VIRTIO_MMIO_INT_CONFIG)
+    }
+
+    fn read_register(&self, offset: u64) -> Result<u32> {
+        let queue = self.queues.get(self.queue_sel as usize);
+        Ok(match offset {
+            VIRTIO_MMIO_MAGIC_VALUE => VIRTIO_MMIO_MAGIC,
+            VIRTIO_MMIO_VERSION_REG => VIRTIO_MMIO_VERSION,
+            VIRTIO_MMIO_DEVICE_ID => self.device.device_type(),
+            VIRTIO_MMIO_VENDOR_ID => 0,
+            VIRTIO_MMIO_DEVICE_FEATURES => match self.device_features_sel {
+                0 => self.device_features() as u32,
+                1 => (self.device_features() >> 32) as u32,
+                _ => 0,
+            },
+            VIRTIO_MMIO_QUEUE_NUM_MAX => queue.map_or(0, |q| q.max_size.into()),
+            VIRTIO_MMIO_QUEUE_READY => queue.map_or(0, |q| q.ready.into()),
+            VIRTIO_MMIO_INTERRUPT_STATUS => self.interrupt.status(),
+            VIRTIO_MMIO_STATUS => self.status,
+            VIRTIO_MMIO_CONFIG_GENERATION => self.config_generation,
+            _ => bail!("Unhandled register read at {:#x}", offset),
+        })
+    }
+
+    fn write_register(&mut self, offset: u64, value: u32) -> Result<()> {
+        let set_low = |reg: &mut u64| *reg = (*reg & !0xffff_ffff) | u64::from(value);
+        let set_high = |reg: &mut u64| *reg = (*reg & 0xffff_ffff) | (u64::from(value) << 32);
+
+        match offset {
+            VIRTIO_MMIO_DEVICE_FEATURES_SEL => self.device_features_sel = value,
+            VIRTIO_MMIO_DRIVER_FEATURES => match self.driver_features_sel {
+                0 => set_low(&mut self.driver_features),
+                1 => set_high(&mut self.driver_features),
+                _ => (),
+            },
+            VIRTIO_MMIO_DRIVER_FEATURES_SEL => self.driver_features_sel = value,
+            VIRTIO_MMIO_QUEUE_SEL => self.queue_sel = value,
+            VIRTIO_MMIO_QUEUE_NOTIFY => self.notify(value as usize)?,
+            VIRTIO_MMIO_INTERRUPT_ACK => self.interrupt.ack(value),
+            VIRTIO_MMIO_STATUS => {
+                if value == 0 {
+                    self.reset();
+                } else {
+                    let activate = value & !self.status & VIRTIO_STATUS_DRIVER_OK != 0;
+                    self.status = value;
+                    if activate {
+                        let features = self.acked_features();
+                        self.device
+                            .activate(&self.queues, features, &self.interrupt)
+                            .context("Failed to activate device")?;
+                    }
+                }
+            }
+            _ => {
+                let queue = self.selected_queue().context(format!(
+                    "Queue register write at {:#x} without queue",
+                    offset
+                ))?;
+                match offset {
+                    VIRTIO_MMIO_QUEUE_NUM => {
+                        if value == 0 || value > queue.max_size.into() || !value.is_power_of_two() {
+                            bail!("Invalid queue size {}", value);
+                        }
+                        queue.size = value as u16;
+                    }
+                    VIRTIO_MMIO_QUEUE_READY => queue.ready = value == 1,
+                    VIRTIO_MMIO_QUEUE_DESC_LOW => set_low(&mut queue.desc_table),
+                    VIRTIO_MMIO_QUEUE_DESC_HIGH => set_high(&mut queue.desc_table),
+                    VIRTIO_MMIO_QUEUE_DRIVER_LOW => set_low(&mut queue.avail_ring),
+                    VIRTIO_MMIO_QUEUE_DRIVER_HIGH => set_high(&mut queue.avail_ring),
+                    VIRTIO_MMIO_QUEUE_DEVICE_LOW => set_low(&mut queue.used_ring),
+                    VIRTIO_MMIO_QUEUE_DEVICE_HIGH => set_high(&mut queue.used_ring),
+                    _ => bail!("Unhandled register write at {:#x}", offset),
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<D: VirtioDevice> BusDevice for VirtioMmio<D> {
+    fn debug_label(&self) -> String {
+        format!("virtio-mmio {}", self.device.debug_label())
+    }
+
+    fn read(&mut self, offset: BusAccessInfo, data: &mut [u8]) -> Result<()> {
+        if offset.offset >= VIRTIO_MMIO_CONFIG {
+            self.device
+                .read_config(offset.offset - VIRTIO_MMIO_CONFIG, data);
+            return Ok(());
+        }
+        if data.len() != 4 {
+            bail!("Only 32-bit register reads allowed");
+        }
+        data.copy_from_slice(&self.read_register(offset.offset)?.to_le_bytes());
+        Ok(())
+    }
+
+    fn write(&mut self, offset: BusAccessInfo, data: &[u8]) -> Result<()> {
+        if offset.offset >= VIRTIO_MMIO_CONFIG {
+            return self
+                .device
+                .write_config(offset.offset - VIRTIO_MMIO_CONFIG, data);
+        }
+        let value = u32::from_le_bytes(
+            data.try_into()
+                .map_err(|_| anyhow!("Only 32-bit register writes allowed"))?,
+        );
+        self.write_register(offset.offset, value)
+    }
+
+    fn fast_write_regions(&self) -> Vec<FastWriteRegion> {
+        if !self.device.fast_notify() {
+            return Vec::new();
+        }
+        (0..self.queues.len() as u64)
+            .map(|index| FastWriteRegion {
+                offset: VIRTIO_MMIO_QUEUE_NOTIFY,
+                len: 4,
+                datamatch: Some(index),
+            })
+            .collect()
+    }
+
+    fn fast_write(&mut self, region: FastWriteRegion) -> Result<()> {
+        let index = region
+            .datamatch
+            .context("Queue notify without queue index")?;
+        self.notify(index as usize)
+    }
+
+    fn device_config(&self, fdt: &mut FdtWriter) -> Result<()> {
+        let node = fdt.begin_node(&format!("virtio_mmio@{:x}", self.base))?;
+        fdt.property_string("compatible", "virtio,mmio")?;
+        fdt.property_array_u64("reg", &[self.base, VIRTIO_MMIO_SIZE])?;
+        fdt.property_array_u32("interrupts", &self.interrupt.irq.fdt_config())?;
+        fdt.property_null("dma-coherent")?;
+        if let Some(iommu) = &self.mem.iommu {
+            iommu.device_config(fdt)?;
+        }
+        if self.mem.restricted_dma.is_some() {
+            fdt.property_u32("memory-region", RESTRICTED_DMA_PHANDLE)?;
+        }
+        self.device.device_config(fdt)?;
+        fdt.end_node(node)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use claim::{assert_err, assert_none, assert_ok};
+
+    use crate::{test_util::Ram, Bus, BusRange, VirtioIommu};
+
+    use super::{Descriptor, DescriptorChain, GuestMemory, Virtqueue};
+
+    const RAM_BASE: u64 = 0x1000;
+    const DESC: u64 = RAM_BASE;
+    const AVAIL: u64 = RAM_BASE + 0x100;
+    const USED: u64 = RAM_BASE + 0x200;
+
+    fn setup() -> (GuestMemory, Virtqueue) {
+        let bus = Bus::new();
+        bus.insert(Arc::new(Mutex::new(Ram(vec![0; 0x1000]))), RAM_BASE, 0x1000)
+            .unwrap();
+        let mem = GuestMemory::new(
+            bus,
+            BusRange {
+                base: 0x10_0000,
+                len: 0x200,
+            },
+        );
+        (mem, Virtqueue::with_rings(8, DESC, AVAIL, USED))
+    }
+
+    fn write_desc(mem: &GuestMemory, index: u64, addr: u64, len: u32, flags: u16, next: u16) {
+        let desc = DESC + index * 16;
+        mem.write(desc, &addr.to_le_bytes()).unwrap();
+        mem.write_u32(desc + 8, len).unwrap();
+        mem.write_u16(desc + 12, flags).unwrap();
+        mem.write_u16(desc + 14, next).unwrap();
+    }
+
+    fn make_available(mem: &GuestMemory, slot: u16, head: u16) {
+        mem.write_u16(AVAIL + 4 + u64::from(slot) * 2, head)
+            .unwrap();
+        mem.write_u16(AVAIL + 2, slot + 1).unwrap();
+    }
+
+    #[test]
+    fn pop_chain() {
+        let (mem, mut queue) = setup();
+        assert_none!(queue.pop(&mem).unwrap());
+
+        write_desc(&mem, 3, 0x1800, 4, super::VIRTQ_DESC_F_NEXT, 5);
+        write_desc(&mem, 5, 0x1900, 8, super::VIRTQ_DESC_F_WRITE, 0);
+        make_available(&mem, 0, 3);
+
+        let chain = queue.pop(&mem).unwrap().unwrap();
+        assert_eq!(chain.head, 3);
+        assert_eq!(chain.descriptors.len(), 2);
+        assert!(!chain.descriptors[0].is_write_only());
+        assert!(chain.descriptors[1].is_write_only());
+        assert_none!(queue.pop(&mem).unwrap());
+    }
+
+    #[test]
+    fn add_used() {
+        let (mem, mut queue) = setup();
+        assert_ok!(queue.add_used(&mem, 3, 12));
+        assert_eq!(mem.read_u16(USED + 2).unwrap(), 1);
+        assert_eq!(mem.read_u32(USED + 4).unwrap(), 3);
+        assert_eq!(mem.read_u32(USED + 8).unwrap(), 12);
+    }
+
+    #[test]
+    fn looping_chain() {
+        let (mem, mut queue) = setup();
+        write_desc(&mem, 0, 0x1800, 4, super::VIRTQ_DESC_F_NEXT, 1);
+        write_desc(&mem, 1, 0x1800, 4, super::VIRTQ_DESC_F_NEXT, 0);
+        make_available(&mem, 0, 0);
+        assert_err!(queue.pop(&mem));
+    }
+
+    #[test]
+    fn ring_overflow() {
+        let (mem, mut queue) = setup();
+        queue.used_ring = u64::MAX - 4;
+        assert_err!(queue.add_used(&mem, 0, 0));
+        queue.avail_ring = u64::MAX - 1;
+        assert_err!(queue.pop(&mem));
+
+        let (mem, mut queue) = setup();
+        make_available(&mem, 0, 7);
+        queue.desc_table = u64::MAX - 0x20;
+        assert_err!(queue.pop(&mem));
+    }
+
+    #[test]
+    fn chain_data() {
+        let (mem, _) = setup();
+        mem.write(0x1800, b"hello").unwrap();
+        let chain = DescriptorChain {
+            head: 0,
+            descriptors: vec![
+                Descriptor {
+                    addr: 0x1800,
+                    len: 5,
+                    flags: 0,
+                },
+                Descriptor {
+                    addr: 0x1900,
+                    len: 3,
+                    flags: super::VIRTQ_DESC_F_WRITE,
+                },
+            ],
+        };
+        assert_eq!(chain.read_all(&mem, 5).unwrap(), b"hello");
+        // Too large requests aren't read
+        assert_err!(chain.read_all(&mem, 4));
+        assert_eq!(chain.write_all(&mem, b"world").unwrap(), 3);
+        let mut data = [0u8; 3];
+        mem.read(0x1900, &mut data).unwrap();
+        assert_eq!(&data, b"wor");
+    }
+
+    #[test]
+    fn huge_chain() {
+        let (mem, _) = setup();
+        let desc = |addr| Descriptor {
+            addr,
+            len: u32::MAX,
+            flags: 0,
+        };
+        // Rejected before allocating the 8 GiB the descriptors add up to
+        let chain = DescriptorChain {
+            head: 0,
+            descriptors: vec![desc(RAM_BASE), desc(RAM_BASE)],
+        };
+        assert_err!(chain.read_all(&mem, 0x1000));
+        let chain = DescriptorChain {
+            head: 0,
+            descriptors: vec![desc(u64::MAX - 0xff)],
+        };
+        assert_err!(chain.read_all(&mem, usize::MAX));
+    }
+
+    #[test]
+    fn rejects_own_window() {
+        let (mem, _) = setup();
+        let mut data = [0u8; 4];
+        assert_err!(mem.read(0x10_0100, &mut data));
+    }
+
+    #[test]
+    fn iommu_blocks_unattached_endpoint() {
+        let (mut mem, _) = setup();
+        let mut data = [0u8; 4];
+        assert_ok!(mem.read(RAM_BASE, &mut data));
+
+        mem.iommu = Some(VirtioIommu::default().add_endpoint());
+        assert_err!(mem.read(RAM_BASE, &mut data));
+        assert_err!(mem.write(RAM_BASE, &data));
+    }
+
+    #[test]
+    fn restricted_dma_blocks_outside_pool() {
+        let (mut mem, _) = setup();
+        mem.restricted_dma = Some(BusRange {
+            base: RAM_BASE + 0x800,
+            len: 0x800,
+        });
+        let mut data = [0u8; 4];
+        assert_ok!(mem.read(RAM_BASE + 0x800, &mut data));
+        assert_ok!(mem.write(RAM_BASE + 0xffc, &data));
+        assert_err!(mem.read(RAM_BASE, &mut data));
+        assert_err!(mem.write(RAM_BASE + 0xffe, &data));
+        assert_err!(mem.read(u64::MAX - 1, &mut data));
+    }
+}
@@ -0,0 +1,956 @@
+// Copyright (c) 2024, Qualcomm Innovation Center, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause-Clear
+
+use std::{
+    collections::HashMap,
+    ffi::{CString, OsStr},
+    fs::{self, DirBuilder, File, Metadata, OpenOptions},
+    io,
+    mem::MaybeUninit,
+    os::{
+        fd::AsRawFd,
+        unix::{
+            ffi::OsStrExt,
+            fs::{DirBuilderExt, FileTypeExt, MetadataExt, OpenOptionsExt, PermissionsExt},
+        },
+    },
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    GuestMemory, GunyahVirtualMachine, IoEngine, VirtioDevice, VirtioMmio, Virtqueue, VIRTIO_ID_9P,
+};
+
+const QUEUE_SIZE: u16 = 128;
+
+/// Device has a tag the guest mounts it by.
+const VIRTIO_9P_MOUNT_TAG: u64 = 1 << 0;
+
+const VERSION: &[u8] = b"9P2000.L";
+const MAX_MSIZE: u32 = 128 * 1024;
+/// Smallest msize the Linux client negotiates, large enough for any reply's header
+const MIN_MSIZE: u32 = 4096;
+/// size[4] type[1] tag[2] count[4] preceding the data of Rread and Rreaddir
+const IO_HEADER_SIZE: u32 = 11;
+
+const P9_RLERROR: u8 = 7;
+const P9_TSTATFS: u8 = 8;
+const P9_TLOPEN: u8 = 12;
+const P9_TLCREATE: u8 = 14;
+const P9_TREADLINK: u8 = 22;
+const P9_TGETATTR: u8 = 24;
+const P9_TSETATTR: u8 = 26;
+const P9_TREADDIR: u8 = 40;
+const P9_TFSYNC: u8 = 50;
+const P9_TMKDIR: u8 = 72;
+const P9_TRENAMEAT: u8 = 74;
+const P9_TUNLINKAT: u8 = 76;
+const P9_TVERSION: u8 = 100;
+const P9_TATTACH: u8 = 104;
+const P9_TFLUSH: u8 = 108;
+const P9_TWALK: u8 = 110;
+const P9_TREAD: u8 = 116;
+const P9_TWRITE: u8 = 118;
+const P9_TCLUNK: u8 = 120;
+
+const P9_QTDIR: u8 = 0x80;
+const P9_QTSYMLINK: u8 = 0x02;
+const P9_QTFILE: u8 = 0x00;
+
+/// mode, nlink, uid, gid, rdev, atime, mtime, ctime, ino, size and blocks
+const P9_GETATTR_BASIC: u64 = 0x7ff;
+
+const P9_SETATTR_MODE: u32 = 1 << 0;
+const P9_SETATTR_SIZE: u32 = 1 << 3;
+
+/// Open flags as sent by the guest, which use the asm-generic values regardless of architecture.
+const P9_DOTL_ACCMODE: u32 = 0o3;
+const P9_DOTL_WRONLY: u32 = 0o1;
+const P9_DOTL_RDWR: u32 = 0o2;
+const P9_DOTL_TRUNC: u32 = 0o1000;
+const P9_DOTL_APPEND: u32 = 0o2000;
+
+const P9_DOTL_AT_REMOVEDIR: u32 = 0x200;
+
+const V9FS_MAGIC: u32 = 0x01021997;
+
+fn errno(errno: i32) -> io::Error {
+    io::Error::from_raw_os_error(errno)
+}
+
+/// Cursor over the body of a T-message.
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.data.len() < len {
+            return Err(errno(libc::EINVAL));
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> io::Result<&'a [u8]> {
+        let len = self.u16()?;
+        self.bytes(len.into())
+    }
+
+    /// A single path component which can be created or removed.
+    fn name(&mut self) -> io::Result<&'a OsStr> {
+        let name = self.string()?;
+        if name.is_empty() || name == b"." || name == b".." || name.contains(&b'/') {
+            return Err(errno(libc::EINVAL));
+        }
+        Ok(OsStr::from_bytes(name))
+    }
+}
+
+/// Builds an R-message; the size is filled in by [`Writer::finish`].
+struct Writer {
+    data: Vec<u8>,
+}
+
+impl Writer {
+    fn new(message_type: u8, tag: u16) -> Self {
+        let mut data = vec![0u8; 4];
+        data.push(message_type);
+        data.extend(tag.to_le_bytes());
+        Self { data }
+    }
+
+    fn u16(&mut self, value: u16) -> &mut Self {
+        self.data.extend(value.to_le_bytes());
+        self
+    }
+
+    fn u32(&mut self, value: u32) -> &mut Self {
+        self.data.extend(value.to_le_bytes());
+        self
+    }
+
+    fn u64(&mut self, value: u64) -> &mut Self {
+        self.data.extend(value.to_le_bytes());
+        self
+    }
+
+    fn string(&mut self, value: &[u8]) -> &mut Self {
+        self.u16(value.len() as u16);
+        self.data.extend(value);
+        self
+    }
+
+    fn qid(&mut self, qid: &[u8; 13]) -> &mut Self {
+        self.data.extend(qid);
+        self
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        let size = self.data.len() as u32;
+        self.data[..4].copy_from_slice(&size.to_le_bytes());
+        self.data
+    }
+}
+
+fn qid(metadata: &Metadata) -> [u8; 13] {
+    let file_type = metadata.file_type();
+    let mut qid = [0u8; 13];
+    qid[0] = if file_type.is_dir() {
+        P9_QTDIR
+    } else if file_type.is_symlink() {
+        P9_QTSYMLINK
+    } else {
+        P9_QTFILE
+    };
+    qid[1..5].copy_from_slice(&(metadata.mtime() as u32).to_le_bytes());
+    qid[5..].copy_from_slice(&metadata.ino().to_le_bytes());
+    qid
+}
+
+fn dirent_type(metadata: &Metadata) -> u8 {
+    let file_type = metadata.file_type();
+    if file_type.is_dir() {
+        libc::DT_DIR
+    } else if file_type.is_symlink() {
+        libc::DT_LNK
+    } else if file_type.is_file() {
+        libc::DT_REG
+    } else if file_type.is_fifo() {
+        libc::DT_FIFO
+    } else if file_type.is_socket() {
+        libc::DT_SOCK
+    } else if file_type.is_char_device() {
+        libc::DT_CHR
+    } else if file_type.is_block_device() {
+        libc::DT_BLK
+    } else {
+        libc::DT_UNKNOWN
+    }
+}
+
+/// Path through which an open file can be accessed, even if it was opened with `O_PATH`.
+fn proc_path(file: &File) -> PathBuf {
+    PathBuf::from(format!("/proc/self/fd/{}", file.as_raw_fd()))
+}
+
+/// Host path of a file in the shared directory. It goes through the file's parent directory,
+/// which is kept open, so the path can't be redirected by changing the directories above it.
+struct HostPath {
+    _parent: File,
+    path: PathBuf,
+}
+
+impl AsRef<Path> for HostPath {
+    fn as_ref(&self) -> &Path {
+        &self.path
+    }
+}
+
+struct Fid {
+    /// Path relative to the shared directory. Never contains "..".
+    path: PathBuf,
+    file: Option<File>,
+}
+
+/// 9P2000.L server confined to one host directory.
+///
+/// Paths are tracked per fid relative to the shared directory and ".." is resolved without
+/// consulting the host, so the guest can't walk out of the directory. Symlinks are reported to
+/// the guest but never followed on the host: every directory on a path is opened with
+/// `O_NOFOLLOW`, and files are only accessed relative to their opened parent.
+struct P9Server {
+    root: PathBuf,
+    msize: u32,
+    fids: HashMap<u32, Fid>,
+    /// Reads and writes opened files
+    io_engine: IoEngine,
+}
+
+impl P9Server {
+    fn new(root: PathBuf) -> Self {
+        Self {
+            root,
+            msize: MAX_MSIZE,
+            fids: HashMap::new(),
+            io_engine: IoEngine::default(),
+        }
+    }
+
+    /// Handles one T-message and returns its R-message, which is at most `max_len` bytes.
+    fn handle(&mut self, request: &[u8], max_len: usize) -> Vec<u8> {
+        let mut reader = Reader { data: request };
+        let (message_type, tag) = match (|| -> io::Result<_> {
+            let size = reader.u32()? as usize;
+            reader.data = request.get(..size).ok_or(errno(libc::EINVAL))?;
+            reader.bytes(4)?;
+            Ok((reader.bytes(1)?[0], reader.u16()?))
+        })() {
+            Ok(header) => header,
+            Err(_) => {
+                let mut response = Writer::new(P9_RLERROR, !0);
+                response.u32(libc::EINVAL as u32);
+                return response.finish();
+            }
+        };
+        let max_len = max_len.min(self.msize as usize) as u32;
+
+        match self.dispatch(message_type, tag, &mut reader, max_len) {
+            Ok(response) => response.finish(),
+            Err(e) => {
+                let mut response = Writer::new(P9_RLERROR, tag);
+                response.u32(e.raw_os_error().unwrap_or(libc::EIO) as u32);
+                response.finish()
+            }
+        }
+    }
+
+    fn fid(&self, fid: u32) -> io::Result<&Fid> {
+        self.fids.get(&fid).ok_or(errno(libc::EBADF))
+    }
+
+    /// Opens the directory `path` with `O_PATH` without following symlinks in any of its
+    /// components.
+    fn open_dir(&self, path: &Path) -> io::Result<File> {
+        let open = |path: &Path, flags| {
+            OpenOptions::new()
+                .read(true)
+                .custom_flags(libc::O_PATH | libc::O_DIRECTORY | flags)
+                .open(path)
+        };
+        let mut dir = open(&self.root, 0)?;
+        for name in path {
+            dir = open(&proc_path(&dir).join(name), libc::O_NOFOLLOW)?;
+        }
+        Ok(dir)
+    }
+
+    fn host_path(&self, path: &Path) -> io::Result<HostPath> {
+        let parent = self.open_dir(path.parent().unwrap_or(path))?;
+        let path = match path.file_name() {
+            Some(name) => proc_path(&parent).join(name),
+            None => self.root.clone(),
+        };
+        Ok(HostPath {
+            _parent: parent,
+            path,
+        })
+    }
+
+    fn lstat(&self, path: &Path) -> io::Result<Metadata> {
+        fs::symlink_metadata(self.host_path(path)?)
+    }
+
+    fn dispatch(
+        &mut self,
+        message_type: u8,
+        tag: u16,
+        r: &mut Reader,
+        max_len: u32,
+    ) -> io::Result<Writer> {
+        let mut w = Writer::new(message_type + 1, tag);
+        match message_type {
+            P9_TVERSION => {
+                let msize = r.u32()?;
+                let version = r.string()?;
+                if msize < MIN_MSIZE {
+                    return Err(errno(libc::EINVAL));
+                }
+                self.fids.clear();
+                self.msize = msize.min(MAX_MSIZE);
+                w.u32(self.msize);
+                w.string(if version == VERSION {
+                    VERSION
+                } else {
+                    b"unknown"
+                });
+            }
+            P9_TATTACH => {
+                let fid = r.u32()?;
+                let _afid = r.u32()?;
+                let _uname = r.string()?;
+                let _aname = r.string()?;
+                let root = PathBuf::new();
+                w.qid(&qid(&self.lstat(&root)?));
+                self.fids.insert(
+                    fid,
+                    Fid {
+                        path: root,
+                        file: None,
+                    },
+                );
+            }
+            P9_TFLUSH => {
+                // Requests are completed in order, so there is never anything to flush
+                r.u16()?;
+            }
+            P9_TWALK => {
+                let fid = r.u32()?;
+                let newfid = r.u32()?;
+                let nwname = r.u16()?;
+                let mut path = self.fid(fid)?.path.clone();
+                let mut metadata = self.lstat(&path)?;
+                let mut qids = Vec::new();
+                for _ in 0..nwname {
+                    let name = r.string()?;
+                    if name.is_empty() || name.contains(&b'/') {
+                        return Err(errno(libc::EINVAL));
+                    }
+                    let step = (|| {
+                        if !metadata.is_dir() {
+                            return Err(errno(libc::ENOTDIR));
+                        }
+                        let mut next = path.clone();
+                        match name {
+                            b"." => {}
+                            b".." => {
+                                next.pop();
+                            }
+                            _ => next.push(OsStr::from_bytes(name)),
+                        }
+                        let next_metadata = self.lstat(&next)?;
+                        Ok((next, next_metadata))
+                    })();
+                    match step {
+                        Ok((next, next_metadata)) => {
+                            qids.push(qid(&next_metadata));
+                            path = next;
+                            metadata = next_metadata;
+                        }
+                        Err(e) if qids.is_empty() => return Err(e),
+                        Err(_) => break,
+                    }
+                }
+                if qids.len() == usize::from(nwname) {
+                    self.fids.insert(newfid, Fid { path, file: None });
+                }
+                w.u16(qids.len() as u16);
+                for qid in &qids {
+                    w.qid(qid);
+                }
+            }
+            P9_TGETATTR => {
+                let fid = r.u32()?;
+                let _request_mask = r.u64()?;
+                let metadata = self.lstat(&self.fid(fid)?.path)?;
+                w.u64(P9_GETATTR_BASIC)
+                    .qid(&qid(&metadata))
+                    .u32(metadata.mode())
+                    .u32(metadata.uid())
+                    .u32(metadata.gid())
+                    .u64(metadata.nlink())
+                    .u64(metadata.rdev())
+                    .u64(metadata.size())
+                    .u64(metadata.blksize())
+                    .u64(metadata.blocks())
+                    .u64(metadata.atime() as u64)
+                    .u64(metadata.atime_nsec() as u64)
+                    .u64(metadata.mtime() as u64)
+                    .u64(metadata.mtime_nsec() as u64)
+                    .u64(metadata.ctime() as u64)
+                    .u64(metadata.ctime_nsec() as u64)
+                    // btime, gen and data_version aren't reported
+                    .u64(0)
+                    .u64(0)
+                    .u64(0)
+                    .u64(0)
+                    .u64(0);
+            }
+            P9_TSETATTR => {
+                let fid = r.u32()?;
+                let valid = r.u32()?;
+                let mode = r.u32()?;
+                let _uid = r.u32()?;
+                let _gid = r.u32()?;
+                let size = r.u64()?;
+                // Changing the owner or timestamps is quietly ignored
+                if valid & (P9_SETATTR_MODE | P9_SETATTR_SIZE) != 0 {
+                    // Changed through the opened file, so it's the one which was checked
+                    let file = OpenOptions::new()
+                        .read(true)
+                        .custom_flags(libc::O_PATH | libc::O_NOFOLLOW)
+                        .open(self.host_path(&self.fid(fid)?.path)?)?;
+                    if file.metadata()?.is_symlink() {
+                        return Err(errno(libc::EPERM));
+                    }
+                    if valid & P9_SETATTR_MODE != 0 {
+                        fs::set_permissions(
+                            proc_path(&file),
+                            fs::Permissions::from_mode(mode & 0o7777),
+                        )?;
+                    }
+                    if valid & P9_SETATTR_SIZE != 0 {
+                        OpenOptions::new()
+                            .write(true)
+                            .open(proc_path(&file))?
+                            .set_len(size)?;
+                    }
+                }
+            }
+            P9_TREADLINK => {
+                let fid = r.u32()?;
+                let target = fs::read_link(self.host_path(&self.fid(fid)?.path)?)?;
+                w.string(target.as_os_str().as_bytes());
+            }
+            P9_TSTATFS => {
+                let fid = r.u32()?;
+                let host_path = self.host_path(&self.fid(fid)?.path)?;
+                let path = CString::new(host_path.as_ref().as_os_str().as_bytes())
+                    .map_err(|_| errno(libc::EINVAL))?;
+                let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+                // SAFETY: Safe because path is a valid C string and the kernel only writes to stat
+                if unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                // SAFETY: Safe because statvfs succeeded and filled in stat
+                let stat = unsafe { stat.assume_init() };
+                w.u32(V9FS_MAGIC)
+                    .u32(stat.f_bsize as u32)
+                    .u64(stat.f_blocks)
+                    .u64(stat.f_bfree)
+                    .u64(stat.f_bavail)
+                    .u64(stat.f_files)
+                    .u64(stat.f_ffree)
+                    .u64(stat.f_fsid)
+                    .u32(stat.f_namemax as u32);
+            }
+            P9_TLOPEN => {
+                let fid = r.u32()?;
+                let flags = r.u32()?;
+                let path = self.fid(fid)?.path.clone();
+                let file = self.open(&path, flags, None)?;
+                w.qid(&qid(&file.metadata()?))
+                    .u32(self.msize - IO_HEADER_SIZE);
+                self.fids.get_mut(&fid).unwrap().file = Some(file);
+            }
+            P9_TLCREATE => {
+                let fid = r.u32()?;
+                let name = r.name()?;
+                let flags = r.u32()?;
+                let mode = r.u32()?;
+                let _gid = r.u32()?;
+                let path = self.fid(fid)?.path.join(name);
+                let file = self.open(&path, flags, Some(mode))?;
+                w.qid(&qid(&file.metadata()?))
+                    .u32(self.msize - IO_HEADER_SIZE);
+                // The fid now refers to the new file
+                self.fids.insert(
+                    fid,
+                    Fid {
+                        path,
+                        file: Some(file),
+                    },
+                );
+            }
+            P9_TMKDIR => {
+                let fid = r.u32()?;
+                let name = r.name()?;
+                let mode = r.u32()?;
+                let _gid = r.u32()?;
+                let path = self.fid(fid)?.path.join(name);
+                DirBuilder::new()
+                    .mode(mode & 0o7777)
+                    .create(self.host_path(&path)?)?;
+                w.qid(&qid(&self.lstat(&path)?));
+            }
+            P9_TUNLINKAT => {
+                let fid = r.u32()?;
+                let name = r.name()?;
+                let flags = r.u32()?;
+                let path = self.host_path(&self.fid(fid)?.path.join(name))?;
+                if flags & P9_DOTL_AT_REMOVEDIR != 0 {
+                    fs::remove_dir(path)?;
+                } else {
+                    fs::remove_file(path)?;
+                }
+            }
+            P9_TRENAMEAT => {
+                let old_fid = r.u32()?;
+                let old_name = r.name()?;
+                let new_fid = r.u32()?;
+                let new_name = r.name()?;
+                fs::rename(
+                    self.host_path(&self.fid(old_fid)?.path.join(old_name))?,
+                    self.host_path(&self.fid(new_fid)?.path.join(new_name))?,
+                )?;
+            }
+            P9_TREADDIR => {
+                let fid = r.u32()?;
+                let offset = r.u64()?;
+                let count = r.u32()?.min(max_len.saturating_sub(IO_HEADER_SIZE));
+                let data = self.readdir(&self.fid(fid)?.path, offset, count as usize)?;
+                w.u32(data.len() as u32);
+                w.data.extend(data);
+            }
+            P9_TREAD => {
+                let fid = r.u32()?;
+                let offset = r.u64()?;
+                let count = r.u32()?.min(max_len.saturating_sub(IO_HEADER_SIZE));
+                let file = self.fid(fid)?.file.as_ref().ok_or(errno(libc::EBADF))?;
+                let mut data = vec![0u8; count as usize];
+                let len = self.io_engine.read_at(file, &mut data, offset)?;
+                w.u32(len as u32);
+                w.data.extend(&data[..len]);
+            }
+            P9_TWRITE => {
+                let fid = r.u32()?;
+                let offset = r.u64()?;
+                let count = r.u32()?;
+                let data = r.bytes(count as usize)?;
+                let file = self.fid(fid)?.file.as_ref().ok_or(errno(libc::EBADF))?;
+                w.u32(self.io_engine.write_at(file, data, offset)? as u32);
+            }
+            P9_TFSYNC => {
+                let fid = r.u32()?;
+                let datasync = r.u32()?;
+                let file = self.fid(fid)?.file.as_ref().ok_or(errno(libc::EBADF))?;
+                if datasync != 0 {
+                    file.sync_data()?;
+                } else {
+                    file.sync_all()?;
+                }
+            }
+            P9_TCLUNK => {
+                let fid = r.u32()?;
+                self.fids.remove(&fid).ok_or(errno(libc::EBADF))?;
+            }
+            _ => return Err(errno(libc::EOPNOTSUPP)),
+        }
+        Ok(w)
+    }
+
+    /// Opens `path` with the guest's open `flags`, creating it with `mode` if given.
+    fn open(&self, path: &Path, flags: u32, mode: Option<u32>) -> io::Result<File> {
+        let mut options = OpenOptions::new();
+        match flags & P9_DOTL_ACCMODE {
+            P9_DOTL_WRONLY => options.write(true),
+            P9_DOTL_RDWR => options.read(true).write(true),
+            _ => options.read(true),
+        };
+        options
+            .truncate(flags & P9_DOTL_TRUNC != 0)
+            .append(flags & P9_DOTL_APPEND != 0)
+            .custom_flags(libc::O_NOFOLLOW);
+        if let Some(mode) = mode {
+            options.create_new(true).mode(mode & 0o7777);
+        }
+        options.open(self.host_path(path)?)
+    }
+
+    /// Directory entries of `path` starting at `offset` which fit into `count` bytes. An entry's
+    /// offset is the position of the next entry, with "." and ".." first.
+    fn readdir(&self, path: &Path, offset: u64, count: usize) -> io::Result<Vec<u8>> {
+        let mut entries = vec![
+            (b".".to_vec(), self.lstat(path)?),
+            (b"..".to_vec(), self.lstat(path.parent().unwrap_or(path))?),
+        ];
+        let dir = self.open_dir(path)?;
+        let mut children = fs::read_dir(proc_path(&dir))?
+            .filter_map(|entry| {
+                let entry = entry.ok()?;
+                let metadata = entry.metadata().ok()?;
+                Some((entry.file_name().as_bytes().to_vec(), metadata))
+            })
+            .collect::<Vec<_>>();
+        children.sort_by(|(a, _), (b, _)| a.cmp(b));
+        entries.extend(children);
+
+        let mut data = Vec::new();
+        for (index, (name, metadata)) in entries.iter().enumerate().skip(offset as usize) {
+            if data.len() + 13 + 8 + 1 + 2 + name.len() > count {
+                break;
+            }
+            data.extend(qid(metadata));
+            data.extend((index as u64 + 1).to_le_bytes());
+            data.push(dirent_type(metadata));
+            data.extend((name.len() as u16).to_le_bytes());
+            data.extend(name);
+        }
+        Ok(data)
+    }
+}
+
+/// virtio-9p sharing a host directory with the guest over 9P2000.L. The guest mounts it with
+/// `mount -t 9p -o trans=virtio,version=9p2000.L TAG DIR`.
+pub struct Virtio9p {
+    server: P9Server,
+    tag: String,
+}
+
+impl Virtio9p {
+    pub fn new(
+        vm: &mut GunyahVirtualMachine,
+        base: u64,
+        interrupt_line: u32,
+        root: &Path,
+        tag: &str,
+    ) -> Result<Arc<Mutex<VirtioMmio<Self>>>> {
+        let mut p9 = Self::with_root(root, tag)?;
+        p9.server.io_engine = vm.io_engine();
+        VirtioMmio::new(vm, base, interrupt_line, p9)
+    }
+
+    fn with_root(root: &Path, tag: &str) -> Result<Self> {
+        if !root.is_dir() {
+            return Err(anyhow!("{} is not a directory", root.display()));
+        }
+        if tag.is_empty() || tag.len() > u16::MAX.into() {
+            return Err(anyhow!("Invalid virtio-9p tag {:?}", tag));
+        }
+        Ok(Self {
+            server: P9Server::new(root.to_path_buf()),
+            tag: tag.to_string(),
+        })
+    }
+}
+
+impl VirtioDevice for Virtio9p {
+    fn debug_label(&self) -> String {
+        format!("9p-{}", self.tag)
+    }
+
+    fn device_type(&self) -> u32 {
+        VIRTIO_ID_9P
+    }
+
+    fn queue_max_sizes(&self) -> Vec<u16> {
+        vec![QUEUE_SIZE]
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_9P_MOUNT_TAG
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        // tag_len followed by the tag, which isn't NUL terminated
+        let mut config = (self.tag.len() as u16).to_le_bytes().to_vec();
+        config.extend(self.tag.as_bytes());
+        data.fill(0);
+        if let Some(src) = config.get(offset as usize..) {
+            let len = src.len().min(data.len());
+            data[..len].copy_from_slice(&src[..len]);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.server.fids.clear();
+    }
+
+    fn process_queue(
+        &mut self,
+        _index: usize,
+        queue: &mut Virtqueue,
+        mem: &GuestMemory,
+    ) -> Result<bool> {
+        let mut used = false;
+        while let Some(chain) = queue.pop(mem)? {
+            let request = chain.read_all(mem, self.server.msize as usize)?;
+            let max_len = chain
+                .descriptors
+                .iter()
+                .filter(|d| d.is_write_only())
+                .map(|d| d.len as usize)
+                .sum();
+            let response = self.server.handle(&request, max_len);
+            let written = chain.write_all(mem, &response)?;
+            queue.add_used(mem, chain.head, written.try_into()?)?;
+            used = true;
+        }
+        Ok(used)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env, fs,
+        os::unix::fs::symlink,
+        path::{Path, PathBuf},
+    };
+
+    use crate::VirtioDevice;
+
+    use super::{
+        Virtio9p, Writer, MAX_MSIZE, P9_DOTL_AT_REMOVEDIR, P9_QTDIR, P9_RLERROR, P9_TATTACH,
+        P9_TLCREATE, P9_TLOPEN, P9_TMKDIR, P9_TREAD, P9_TREADDIR, P9_TUNLINKAT, P9_TVERSION,
+        P9_TWALK, P9_TWRITE, VERSION,
+    };
+
+    fn shared_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("virtio-9p-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("sub/hello.txt"), b"hello from the host").unwrap();
+        dir
+    }
+
+    fn attached(dir: &Path) -> Virtio9p {
+        let mut p9 = Virtio9p::with_root(dir, "share").unwrap();
+        let mut version = Writer::new(P9_TVERSION, !0);
+        version.u32(8192).string(VERSION);
+        let response = p9.server.handle(&version.finish(), usize::MAX);
+        assert_eq!(response[4], P9_TVERSION + 1);
+        assert_eq!(p9.server.msize, 8192);
+
+        let mut attach = Writer::new(P9_TATTACH, 1);
+        attach.u32(0).u32(!0).string(b"root").string(b"").u32(0);
+        let response = p9.server.handle(&attach.finish(), usize::MAX);
+        assert_eq!(response[4], P9_TATTACH + 1);
+        assert_eq!(response[7], P9_QTDIR);
+        p9
+    }
+
+    fn walk(p9: &mut Virtio9p, fid: u32, newfid: u32, names: &[&[u8]]) -> Vec<u8> {
+        let mut walk = Writer::new(P9_TWALK, 2);
+        walk.u32(fid).u32(newfid).u16(names.len() as u16);
+        for name in names {
+            walk.string(name);
+        }
+        p9.server.handle(&walk.finish(), usize::MAX)
+    }
+
+    #[test]
+    fn small_msize() {
+        let dir = shared_dir("msize");
+        let mut p9 = attached(&dir);
+        let mut version = Writer::new(P9_TVERSION, !0);
+        version.u32(8).string(VERSION);
+        let response = p9.server.handle(&version.finish(), usize::MAX);
+        assert_eq!(response[4], P9_RLERROR);
+        // The session negotiated before stays
+        assert_eq!(p9.server.msize, 8192);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn read_file() {
+        let dir = shared_dir("read");
+        let mut p9 = attached(&dir);
+        let response = walk(&mut p9, 0, 1, &[b"sub", b"hello.txt"]);
+        assert_eq!(response[4], P9_TWALK + 1);
+        assert_eq!(u16::from_le_bytes([response[7], response[8]]), 2);
+
+        let mut open = Writer::new(P9_TLOPEN, 3);
+        open.u32(1).u32(0);
+        assert_eq!(
+            p9.server.handle(&open.finish(), usize::MAX)[4],
+            P9_TLOPEN + 1
+        );
+
+        let mut read = Writer::new(P9_TREAD, 4);
+        read.u32(1).u64(11).u32(100);
+        let response = p9.server.handle(&read.finish(), usize::MAX);
+        assert_eq!(response[4], P9_TREAD + 1);
+        assert_eq!(&response[11..], b"the host");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn create_and_write() {
+        let dir = shared_dir("write");
+        let mut p9 = attached(&dir);
+        walk(&mut p9, 0, 1, &[]);
+        let mut create = Writer::new(P9_TLCREATE, 3);
+        create.u32(1).string(b"out.log").u32(0o2).u32(0o644).u32(0);
+        assert_eq!(
+            p9.server.handle(&create.finish(), usize::MAX)[4],
+            P9_TLCREATE + 1
+        );
+
+        let mut write = Writer::new(P9_TWRITE, 4);
+        write.u32(1).u64(0).u32(5);
+        write.data.extend(b"guest");
+        let response = p9.server.handle(&write.finish(), usize::MAX);
+        assert_eq!(response[4], P9_TWRITE + 1);
+        assert_eq!(fs::read(dir.join("out.log")).unwrap(), b"guest");
+
+        // Names are single components
+        let mut create = Writer::new(P9_TLCREATE, 5);
+        create
+            .u32(1)
+            .string(b"../escape")
+            .u32(0o2)
+            .u32(0o644)
+            .u32(0);
+        assert_eq!(
+            p9.server.handle(&create.finish(), usize::MAX)[4],
+            P9_RLERROR
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn walk_stays_inside() {
+        let dir = shared_dir("walk");
+        symlink("/", dir.join("sub/link")).unwrap();
+        let mut p9 = attached(&dir);
+
+        // ".." of the shared directory is the shared directory itself
+        walk(&mut p9, 0, 1, &[b"..", b"..", b"sub"]);
+        assert_eq!(p9.server.fids[&1].path, PathBuf::from("sub"));
+
+        // Symlinks aren't followed
+        let response = walk(&mut p9, 0, 2, &[b"sub", b"link", b"etc"]);
+        assert_eq!(u16::from_le_bytes([response[7], response[8]]), 2);
+        assert!(!p9.server.fids.contains_key(&2));
+
+        // The first element failing is an error
+        assert_eq!(walk(&mut p9, 0, 3, &[b"missing"])[4], P9_RLERROR);
+        assert_eq!(walk(&mut p9, 0, 3, &[b"sub/hello.txt"])[4], P9_RLERROR);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn symlinked_fid() {
+        let dir = shared_dir("symlinked");
+        let outside = shared_dir("outside");
+        symlink(&outside, dir.join("sub/link")).unwrap();
+        let mut p9 = attached(&dir);
+        let response = walk(&mut p9, 0, 1, &[b"sub", b"link"]);
+        assert_eq!(u16::from_le_bytes([response[7], response[8]]), 2);
+
+        // The fid refers to the symlink, which isn't followed to create or remove files
+        let mut create = Writer::new(P9_TLCREATE, 3);
+        create.u32(1).string(b"x").u32(0o2).u32(0o644).u32(0);
+        assert_eq!(
+            p9.server.handle(&create.finish(), usize::MAX)[4],
+            P9_RLERROR
+        );
+        assert!(!outside.join("x").exists());
+
+        let mut mkdir = Writer::new(P9_TMKDIR, 4);
+        mkdir.u32(1).string(b"x").u32(0o755).u32(0);
+        assert_eq!(p9.server.handle(&mkdir.finish(), usize::MAX)[4], P9_RLERROR);
+        assert!(!outside.join("x").exists());
+
+        let mut unlink = Writer::new(P9_TUNLINKAT, 5);
+        unlink.u32(1).string(b"sub").u32(P9_DOTL_AT_REMOVEDIR);
+        fs::remove_file(outside.join("sub/hello.txt")).unwrap();
+        assert_eq!(
+            p9.server.handle(&unlink.finish(), usize::MAX)[4],
+            P9_RLERROR
+        );
+        assert!(outside.join("sub").exists());
+        fs::remove_dir_all(dir).unwrap();
+        fs::remove_dir_all(outside).unwrap();
+    }
+
+    #[test]
+    fn readdir() {
+        let dir = shared_dir("readdir");
+        fs::write(dir.join("sub/a"), b"").unwrap();
+        let mut p9 = attached(&dir);
+        walk(&mut p9, 0, 1, &[b"sub"]);
+
+        let names = |data: &[u8]| {
+            let mut names = Vec::new();
+            let mut data = &data[11..];
+            while !data.is_empty() {
+                let len = u16::from_le_bytes([data[22], data[23]]) as usize;
+                names.push(String::from_utf8(data[24..24 + len].to_vec()).unwrap());
+                data = &data[24 + len..];
+            }
+            names
+        };
+
+        let mut readdir = Writer::new(P9_TREADDIR, 3);
+        readdir.u32(1).u64(0).u32(MAX_MSIZE);
+        let response = p9.server.handle(&readdir.finish(), usize::MAX);
+        assert_eq!(names(&response), [".", "..", "a", "hello.txt"]);
+
+        // Resume after the third entry, with room for one entry only
+        let mut readdir = Writer::new(P9_TREADDIR, 4);
+        readdir.u32(1).u64(3).u32(40);
+        let response = p9.server.handle(&readdir.finish(), usize::MAX);
+        assert_eq!(names(&response), ["hello.txt"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn config_space() {
+        let dir = shared_dir("config");
+        let p9 = Virtio9p::with_root(&dir, "artifacts").unwrap();
+        let mut config = [0u8; 11];
+        p9.read_config(0, &mut config);
+        assert_eq!(config, *b"\x09\x00artifacts");
+        assert!(Virtio9p::with_root(&dir.join("sub/hello.txt"), "x").is_err());
+        assert!(Virtio9p::with_root(&dir, "").is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
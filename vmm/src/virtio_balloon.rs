@@ -0,0 +1,258 @@
+// Copyright (c) 2024, Qualcomm Innovation Center, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause-Clear
+
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    GuestMemory, GunyahGuestMemoryRegion, GunyahVirtualMachine, VirtioDevice, VirtioMmio,
+    Virtqueue, VIRTIO_ID_BALLOON,
+};
+
+const INFLATE_QUEUE: usize = 0;
+const DEFLATE_QUEUE: usize = 1;
+/// Follows the deflate queue as long as neither the stats nor the free page hint queue exist
+const REPORTING_QUEUE: usize = 2;
+const QUEUE_SIZE: u16 = 64;
+
+const VIRTIO_BALLOON_F_FREE_PAGE_REPORTING: u64 = 1 << 5;
+/// Linux passes at most 256 page frame numbers per buffer
+const MAX_PFNS_SIZE: usize = 256 * 4;
+
+/// The balloon always talks in 4kb pages, regardless of the guest's page size.
+pub const BALLOON_PAGE_SIZE: u64 = 4096;
+
+/// virtio balloon which frees the backing memory of pages the guest gives up.
+///
+/// Inflated pages stay mapped into the guest, so the guest can use them again after deflating
+/// the balloon and gets zeroed pages. The balloon also offers free page reporting, with which
+/// the guest hands over free memory on its own, e.g. Linux with CONFIG_PAGE_REPORTING; the
+/// reported memory is freed the same way and the guest may use it again right away.
+pub struct VirtioBalloon {
+    regions: Vec<Arc<Mutex<GunyahGuestMemoryRegion>>>,
+    /// Number of pages the host wants the guest to give up
+    num_pages: u32,
+    /// Number of pages the guest has given up
+    actual: u32,
+    /// Bytes freed since the device was created
+    reclaimed: u64,
+    /// Bytes of `reclaimed` the guest reported as free
+    reported: u64,
+}
+
+impl VirtioBalloon {
+    /// Adds a balloon which can free pages of `regions`.
+    pub fn new(
+        vm: &mut GunyahVirtualMachine,
+        base: u64,
+        interrupt_line: u32,
+        regions: Vec<Arc<Mutex<GunyahGuestMemoryRegion>>>,
+    ) -> Result<Arc<Mutex<VirtioMmio<Self>>>> {
+        VirtioMmio::new(vm, base, interrupt_line, Self::with_regions(regions))
+    }
+
+    fn with_regions(regions: Vec<Arc<Mutex<GunyahGuestMemoryRegion>>>) -> Self {
+        Self {
+            regions,
+            num_pages: 0,
+            actual: 0,
+            reclaimed: 0,
+            reported: 0,
+        }
+    }
+
+    /// Asks the guest to inflate or deflate the balloon to `num_pages` pages.
+    pub fn set_target(balloon: &mut VirtioMmio<Self>, num_pages: u32) -> Result<()> {
+        balloon.device_mut().num_pages = num_pages;
+        balloon.config_changed()
+    }
+
+    /// Number of pages the guest reports to be in the balloon.
+    pub fn actual(&self) -> u32 {
+        self.actual
+    }
+
+    /// Bytes freed since the balloon was created, both of inflated and of reported pages.
+    pub fn reclaimed(&self) -> u64 {
+        self.reclaimed
+    }
+
+    /// Bytes freed because the guest reported them as free.
+    pub fn reported(&self) -> u64 {
+        self.reported
+    }
+
+    fn config(&self) -> [u8; 8] {
+        let mut config = [0u8; 8];
+        config[..4].copy_from_slice(&self.num_pages.to_le_bytes());
+        config[4..].copy_from_slice(&self.actual.to_le_bytes());
+        config
+    }
+
+    fn discard_page(&mut self, pfn: u32) -> Result<()> {
+        self.discard(u64::from(pfn) * BALLOON_PAGE_SIZE, BALLOON_PAGE_SIZE)
+    }
+
+    fn discard(&mut self, address: u64, len: u64) -> Result<()> {
+        let end = address.checked_add(len).ok_or(anyhow!(
+            "Balloon memory {:#x}+{:#x} overflows the address space",
+            address,
+            len
+        ))?;
+        let region = self
+            .regions
+            .iter()
+            .map(|region| region.lock().unwrap())
+            .find(|region| {
+                address >= region.guest_address()
+                    && end <= region.guest_address() + region.as_region().size() as u64
+            })
+            .ok_or(anyhow!(
+                "Balloon memory {:#x}+{:#x} is not reclaimable memory",
+                address,
+                len
+            ))?;
+        region.discard(address - region.guest_address(), len.try_into()?)?;
+        self.reclaimed += len;
+        Ok(())
+    }
+}
+
+impl VirtioDevice for VirtioBalloon {
+    fn debug_label(&self) -> String {
+        "balloon".to_string()
+    }
+
+    fn device_type(&self) -> u32 {
+        VIRTIO_ID_BALLOON
+    }
+
+    fn queue_max_sizes(&self) -> Vec<u16> {
+        vec![QUEUE_SIZE, QUEUE_SIZE, QUEUE_SIZE]
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_BALLOON_F_FREE_PAGE_REPORTING
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        let config = self.config();
+        data.fill(0);
+        if let Some(src) = config.get(offset as usize..) {
+            let len = src.len().min(data.len());
+            data[..len].copy_from_slice(&src[..len]);
+        }
+    }
+
+    fn write_config(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        match (offset, data.len()) {
+            (4, 4) => {
+                self.actual = u32::from_le_bytes(data.try_into()?);
+                Ok(())
+            }
+            _ => Err(anyhow!(
+                "Unhandled balloon config write at {:#x}+{}",
+                offset,
+                data.len()
+            )),
+        }
+    }
+
+    fn process_queue(
+        &mut self,
+        index: usize,
+        queue: &mut Virtqueue,
+        mem: &GuestMemory,
+    ) -> Result<bool> {
+        let mut used = false;
+        while let Some(chain) = queue.pop(mem)? {
+            match index {
+                INFLATE_QUEUE => {
+                    let pfns = chain.read_all(mem, MAX_PFNS_SIZE)?;
+                    for pfn in pfns.chunks_exact(4) {
+                        self.discard_page(u32::from_le_bytes(pfn.try_into()?))?;
+                    }
+                }
+                // Deflated pages are still mapped, the guest faults them back in on access
+                DEFLATE_QUEUE => (),
+                // Each buffer is a range of free memory, which the device "writes"
+                REPORTING_QUEUE => {
+                    for desc in chain.descriptors.iter().filter(|d| d.is_write_only()) {
+                        self.discard(desc.addr, u64::from(desc.len))?;
+                        self.reported += u64::from(desc.len);
+                    }
+                }
+                _ => unreachable!("virtio balloon has only 3 queues"),
+            }
+            queue.add_used(mem, chain.head, 0)?;
+            used = true;
+        }
+        Ok(used)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use claim::{assert_err, assert_ok};
+
+    use crate::{test_util::Ram, Bus, BusRange, GuestMemory, VirtioDevice, Virtqueue};
+
+    use super::{VirtioBalloon, REPORTING_QUEUE, VIRTIO_BALLOON_F_FREE_PAGE_REPORTING};
+
+    #[test]
+    fn config_space() {
+        let mut balloon = VirtioBalloon::with_regions(Vec::new());
+        balloon.num_pages = 0x100;
+
+        let mut data = [0u8; 4];
+        balloon.read_config(0, &mut data);
+        assert_eq!(u32::from_le_bytes(data), 0x100);
+
+        assert_ok!(balloon.write_config(4, &0x80u32.to_le_bytes()));
+        assert_eq!(balloon.actual(), 0x80);
+        balloon.read_config(4, &mut data);
+        assert_eq!(u32::from_le_bytes(data), 0x80);
+
+        // The host owns num_pages
+        assert_err!(balloon.write_config(0, &0u32.to_le_bytes()));
+
+        assert_eq!(balloon.features(), VIRTIO_BALLOON_F_FREE_PAGE_REPORTING);
+        assert_eq!(balloon.queue_max_sizes().len(), 3);
+    }
+
+    #[test]
+    fn unknown_page() {
+        let mut balloon = VirtioBalloon::with_regions(Vec::new());
+        assert_err!(balloon.discard_page(0x80000));
+        assert_err!(balloon.discard(0x8000_0000, 0x20_0000));
+        assert_err!(balloon.discard(u64::MAX - 0xfff, 0x2000));
+        assert_eq!(balloon.reclaimed(), 0);
+    }
+
+    #[test]
+    fn reporting_queue() {
+        let bus = Bus::new();
+        assert_ok!(bus.insert(Arc::new(Mutex::new(Ram(vec![0; 0x1000]))), 0x1000, 0x1000));
+        let mem = GuestMemory::new(
+            bus,
+            BusRange {
+                base: 0x10_0000,
+                len: 0x200,
+            },
+        );
+        let mut queue = Virtqueue::with_rings(8, 0x1000, 0x1100, 0x1200);
+        // One device-writable buffer which wraps around the end of the address space
+        assert_ok!(mem.write(0x1000, &(u64::MAX - 0xfff).to_le_bytes()));
+        assert_ok!(mem.write_u32(0x1008, 0x2000));
+        assert_ok!(mem.write_u16(0x100c, 2));
+        assert_ok!(mem.write_u16(0x1102, 1));
+
+        let mut balloon = VirtioBalloon::with_regions(Vec::new());
+        assert_err!(balloon.process_queue(REPORTING_QUEUE, &mut queue, &mem));
+        assert_eq!(balloon.reported(), 0);
+        assert_eq!(balloon.reclaimed(), 0);
+    }
+}
@@ -0,0 +1,72 @@
+// Copyright (c) 2024, Qualcomm Innovation Center, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause-Clear
+
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{bail, Result};
+
+use crate::{
+    GunyahGuestMemoryRegion, GunyahVirtualMachine, VhostUserConfig, VhostUserDevice, VirtioMmio,
+    VIRTIO_ID_FS,
+};
+
+/// Length of the mount tag in the device's configuration space.
+const TAG_LEN: usize = 36;
+const QUEUE_SIZE: u16 = 1024;
+/// One high priority queue and one request queue.
+const NUM_QUEUES: usize = 2;
+
+/// Adds a virtio-fs device exporting the directory served by a vhost-user-fs backend such as
+/// virtiofsd listening on `socket`, with mount tag `tag`. The backend gets access to `regions`.
+pub fn add_vhost_user_fs(
+    vm: &mut GunyahVirtualMachine,
+    base: u64,
+    interrupt_line: u32,
+    socket: &Path,
+    tag: &str,
+    regions: Vec<Arc<Mutex<GunyahGuestMemoryRegion>>>,
+) -> Result<Arc<Mutex<VirtioMmio<VhostUserDevice>>>> {
+    VhostUserDevice::new(
+        vm,
+        base,
+        interrupt_line,
+        socket,
+        VIRTIO_ID_FS,
+        vec![QUEUE_SIZE; NUM_QUEUES],
+        VhostUserConfig::Local(virtio_fs_config(tag)?),
+        regions,
+    )
+}
+
+/// Configuration space holding the mount tag and number of request queues.
+fn virtio_fs_config(tag: &str) -> Result<Vec<u8>> {
+    if tag.is_empty() || tag.len() > TAG_LEN {
+        bail!("virtio-fs tag must be 1 to {} bytes long", TAG_LEN);
+    }
+    let mut config = vec![0u8; TAG_LEN];
+    config[..tag.len()].copy_from_slice(tag.as_bytes());
+    config.extend_from_slice(&(NUM_QUEUES as u32 - 1).to_le_bytes());
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::virtio_fs_config;
+
+    #[test]
+    fn config_space() {
+        let config = virtio_fs_config("share").unwrap();
+        assert_eq!(config.len(), 40);
+        assert_eq!(&config[..8], b"share\0\0\0");
+        assert_eq!(u32::from_le_bytes(config[36..].try_into().unwrap()), 1);
+    }
+
+    #[test]
+    fn tag_too_long() {
+        assert!(virtio_fs_config(&"a".repeat(37)).is_err());
+        assert!(virtio_fs_config("").is_err());
+    }
+}
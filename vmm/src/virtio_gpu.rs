@@ -0,0 +1,626 @@
+// Copyright (c) 2024, Qualcomm Innovation Center, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause-Clear
+
+//! 2D-only virtio-gpu with a single scanout, enough for a guest to bring up a framebuffer.
+//!
+//! The guest renders into resources backed by its own memory and tells the device which parts
+//! changed. The device copies those parts out of guest memory through the bus and writes the
+//! scanout to a PPM image on every flush, so tests can look at what the guest displays.
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::{
+    GuestMemory, GunyahVirtualMachine, VirtioDevice, VirtioMmio, Virtqueue, VIRTIO_ID_GPU,
+};
+
+const CONTROL_QUEUE: usize = 0;
+const CURSOR_QUEUE: usize = 1;
+const QUEUE_SIZE: u16 = 64;
+
+const VIRTIO_GPU_CMD_GET_DISPLAY_INFO: u32 = 0x100;
+const VIRTIO_GPU_CMD_RESOURCE_CREATE_2D: u32 = 0x101;
+const VIRTIO_GPU_CMD_RESOURCE_UNREF: u32 = 0x102;
+const VIRTIO_GPU_CMD_SET_SCANOUT: u32 = 0x103;
+const VIRTIO_GPU_CMD_RESOURCE_FLUSH: u32 = 0x104;
+const VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D: u32 = 0x105;
+const VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING: u32 = 0x106;
+const VIRTIO_GPU_CMD_RESOURCE_DETACH_BACKING: u32 = 0x107;
+
+const VIRTIO_GPU_RESP_OK_NODATA: u32 = 0x1100;
+const VIRTIO_GPU_RESP_OK_DISPLAY_INFO: u32 = 0x1101;
+const VIRTIO_GPU_RESP_ERR_UNSPEC: u32 = 0x1200;
+const VIRTIO_GPU_RESP_ERR_OUT_OF_MEMORY: u32 = 0x1201;
+const VIRTIO_GPU_RESP_ERR_INVALID_SCANOUT_ID: u32 = 0x1202;
+const VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID: u32 = 0x1203;
+const VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER: u32 = 0x1205;
+
+const VIRTIO_GPU_FLAG_FENCE: u32 = 1;
+
+const VIRTIO_GPU_MAX_SCANOUTS: usize = 16;
+const CTRL_HDR_SIZE: usize = 24;
+const BYTES_PER_PIXEL: u32 = 4;
+/// Host memory all resources together may use, in display sized resources. Enough for double
+/// buffering and a cursor.
+const MAX_RESOURCE_DISPLAYS: u64 = 4;
+/// Largest command, enough to attach the backing of a resource in 64k 4 KiB pages
+const MAX_REQUEST_SIZE: usize = 0x10_0000;
+
+/// Position of the red, green and blue bytes within a pixel of each supported format.
+fn rgb_offsets(format: u32) -> Option<[usize; 3]> {
+    match format {
+        // B8G8R8A8, B8G8R8X8
+        1 | 2 => Some([2, 1, 0]),
+        // A8R8G8B8, X8R8G8B8
+        3 | 4 => Some([1, 2, 3]),
+        // R8G8B8A8, R8G8B8X8
+        67 | 134 => Some([0, 1, 2]),
+        // X8B8G8R8, A8B8G8R8
+        68 | 121 => Some([3, 2, 1]),
+        _ => None,
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+struct Rect {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+impl Rect {
+    fn parse(data: &[u8]) -> Result<Self> {
+        Ok(Self {
+            x: le_u32(data, 0)?,
+            y: le_u32(data, 4)?,
+            width: le_u32(data, 8)?,
+            height: le_u32(data, 12)?,
+        })
+    }
+
+    fn fits(&self, width: u32, height: u32) -> bool {
+        self.x.checked_add(self.width).is_some_and(|x| x <= width)
+            && self.y.checked_add(self.height).is_some_and(|y| y <= height)
+    }
+}
+
+fn le_u32(data: &[u8], offset: usize) -> Result<u32> {
+    Ok(u32::from_le_bytes(
+        data.get(offset..offset + 4)
+            .context("Truncated virtio-gpu request")?
+            .try_into()?,
+    ))
+}
+
+fn le_u64(data: &[u8], offset: usize) -> Result<u64> {
+    Ok(u64::from_le_bytes(
+        data.get(offset..offset + 8)
+            .context("Truncated virtio-gpu request")?
+            .try_into()?,
+    ))
+}
+
+/// Host copy of a guest resource and the guest memory backing it.
+struct Resource {
+    format: u32,
+    width: u32,
+    height: u32,
+    /// Guest address and length of each backing page entry
+    backing: Vec<(u64, u32)>,
+    data: Vec<u8>,
+}
+
+impl Resource {
+    fn stride(&self) -> usize {
+        (self.width * BYTES_PER_PIXEL) as usize
+    }
+
+    /// Reads `buf.len()` bytes at `offset` of the backing memory, which is the concatenation of
+    /// the backing entries.
+    fn read_backing(&self, mem: &GuestMemory, mut offset: u64, buf: &mut [u8]) -> Result<()> {
+        let mut done = 0;
+        for &(addr, len) in &self.backing {
+            let len = u64::from(len);
+            if offset >= len {
+                offset -= len;
+                continue;
+            }
+            let chunk = ((len - offset) as usize).min(buf.len() - done);
+            mem.read(addr + offset, &mut buf[done..done + chunk])?;
+            done += chunk;
+            offset = 0;
+            if done == buf.len() {
+                return Ok(());
+            }
+        }
+        Err(anyhow!("Transfer beyond the resource's backing memory"))
+    }
+}
+
+/// virtio-gpu device exposing a single display of `width` by `height` pixels.
+pub struct VirtioGpu {
+    width: u32,
+    height: u32,
+    output: PathBuf,
+    resources: HashMap<u32, Resource>,
+    /// Bytes of host memory used by `resources`
+    resource_memory: u64,
+    /// Resource shown on the display and the visible part of it
+    scanout: Option<(u32, Rect)>,
+    frames: u64,
+}
+
+impl VirtioGpu {
+    /// Adds a GPU whose display is written to `output` as a PPM image whenever the guest flushes
+    /// it.
+    pub fn new(
+        vm: &mut GunyahVirtualMachine,
+        base: u64,
+        interrupt_line: u32,
+        width: u32,
+        height: u32,
+        output: PathBuf,
+    ) -> Result<Arc<Mutex<VirtioMmio<Self>>>> {
+        VirtioMmio::new(
+            vm,
+            base,
+            interrupt_line,
+            Self::with_display(width, height, output),
+        )
+    }
+
+    fn with_display(width: u32, height: u32, output: PathBuf) -> Self {
+        Self {
+            width,
+            height,
+            output,
+            resources: HashMap::new(),
+            resource_memory: 0,
+            scanout: None,
+            frames: 0,
+        }
+    }
+
+    /// Number of frames written to the output so far.
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    /// Current contents of the display as packed RGB, or None if the guest hasn't set up a
+    /// scanout.
+    pub fn snapshot(&self) -> Option<(u32, u32, Vec<u8>)> {
+        let (resource_id, rect) = self.scanout?;
+        let resource = self.resources.get(&resource_id)?;
+        let offsets = rgb_offsets(resource.format)?;
+        let mut rgb = Vec::with_capacity((rect.width * rect.height * 3) as usize);
+        for y in rect.y..rect.y + rect.height {
+            let row = y as usize * resource.stride();
+            for x in rect.x..rect.x + rect.width {
+                let pixel = row + (x * BYTES_PER_PIXEL) as usize;
+                rgb.extend(offsets.map(|offset| resource.data[pixel + offset]));
+            }
+        }
+        Some((rect.width, rect.height, rgb))
+    }
+
+    fn write_output(&mut self) -> Result<()> {
+        let Some((width, height, rgb)) = self.snapshot() else {
+            return Ok(());
+        };
+        let mut image = format!("P6\n{} {}\n255\n", width, height).into_bytes();
+        image.extend(rgb);
+        // Write the whole frame at once so readers never see a partial image
+        let tmp = self.output.with_extension("tmp");
+        fs::write(&tmp, image).context(format!("Failed to write {}", tmp.display()))?;
+        fs::rename(&tmp, &self.output)
+            .context(format!("Failed to write {}", self.output.display()))?;
+        self.frames += 1;
+        Ok(())
+    }
+
+    fn display_info(&self) -> Vec<u8> {
+        let mut info = vec![0u8; VIRTIO_GPU_MAX_SCANOUTS * 24];
+        info[8..12].copy_from_slice(&self.width.to_le_bytes());
+        info[12..16].copy_from_slice(&self.height.to_le_bytes());
+        // enabled
+        info[16..20].copy_from_slice(&1u32.to_le_bytes());
+        info
+    }
+
+    /// Bytes of a resource covering the whole display, which is the most a single resource may
+    /// use.
+    fn display_size(&self) -> u64 {
+        u64::from(self.width) * u64::from(self.height) * u64::from(BYTES_PER_PIXEL)
+    }
+
+    fn resource(&mut self, id: u32) -> Result<&mut Resource, u32> {
+        self.resources
+            .get_mut(&id)
+            .ok_or(VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID)
+    }
+
+    /// Executes a control command. Returns the response type and any data following the header.
+    fn control(&mut self, request: &[u8], mem: &GuestMemory) -> Result<(u32, Vec<u8>)> {
+        let command = le_u32(request, 0)?;
+        let body = request.get(CTRL_HDR_SIZE..).unwrap_or_default();
+        let result = match command {
+            VIRTIO_GPU_CMD_GET_DISPLAY_INFO => {
+                return Ok((VIRTIO_GPU_RESP_OK_DISPLAY_INFO, self.display_info()))
+            }
+            VIRTIO_GPU_CMD_RESOURCE_CREATE_2D => {
+                let id = le_u32(body, 0)?;
+                let format = le_u32(body, 4)?;
+                let width = le_u32(body, 8)?;
+                let height = le_u32(body, 12)?;
+                // The guest picks the size, so don't let it make the VMM allocate without bounds
+                let size = u64::from(width) * u64::from(height) * u64::from(BYTES_PER_PIXEL);
+                if id == 0 || self.resources.contains_key(&id) {
+                    Err(VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID)
+                } else if rgb_offsets(format).is_none() {
+                    Err(VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER)
+                } else if size > self.display_size()
+                    || self.resource_memory + size > MAX_RESOURCE_DISPLAYS * self.display_size()
+                {
+                    Err(VIRTIO_GPU_RESP_ERR_OUT_OF_MEMORY)
+                } else {
+                    self.resource_memory += size;
+                    self.resources.insert(
+                        id,
+                        Resource {
+                            format,
+                            width,
+                            height,
+                            backing: Vec::new(),
+                            data: vec![0; size as usize],
+                        },
+                    );
+                    Ok(())
+                }
+            }
+            VIRTIO_GPU_CMD_RESOURCE_UNREF => {
+                let id = le_u32(body, 0)?;
+                if self.scanout.is_some_and(|(scanout, _)| scanout == id) {
+                    self.scanout = None;
+                }
+                self.resources
+                    .remove(&id)
+                    .map(|resource| self.resource_memory -= resource.data.len() as u64)
+                    .ok_or(VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID)
+            }
+            VIRTIO_GPU_CMD_SET_SCANOUT => {
+                let rect = Rect::parse(body)?;
+                let scanout_id = le_u32(body, 16)?;
+                let resource_id = le_u32(body, 20)?;
+                if scanout_id != 0 {
+                    Err(VIRTIO_GPU_RESP_ERR_INVALID_SCANOUT_ID)
+                } else if resource_id == 0 {
+                    // Disables the display
+                    self.scanout = None;
+                    Ok(())
+                } else {
+                    match self.resources.get(&resource_id) {
+                        None => Err(VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID),
+                        Some(resource) if !rect.fits(resource.width, resource.height) => {
+                            Err(VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER)
+                        }
+                        Some(_) => {
+                            self.scanout = Some((resource_id, rect));
+                            Ok(())
+                        }
+                    }
+                }
+            }
+            VIRTIO_GPU_CMD_RESOURCE_FLUSH => {
+                let resource_id = le_u32(body, 16)?;
+                if !self.resources.contains_key(&resource_id) {
+                    Err(VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID)
+                } else {
+                    if self
+                        .scanout
+                        .is_some_and(|(scanout, _)| scanout == resource_id)
+                    {
+                        self.write_output()?;
+                    }
+                    Ok(())
+                }
+            }
+            VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D => {
+                let rect = Rect::parse(body)?;
+                let offset = le_u64(body, 16)?;
+                match self.resources.get_mut(&le_u32(body, 24)?) {
+                    None => Err(VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID),
+                    Some(resource) if !rect.fits(resource.width, resource.height) => {
+                        Err(VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER)
+                    }
+                    Some(resource) => {
+                        let stride = resource.stride();
+                        let len = (rect.width * BYTES_PER_PIXEL) as usize;
+                        let mut row = vec![0u8; len];
+                        for line in 0..rect.height {
+                            resource.read_backing(
+                                mem,
+                                offset + u64::from(line) * stride as u64,
+                                &mut row,
+                            )?;
+                            let dst = (rect.y + line) as usize * stride
+                                + (rect.x * BYTES_PER_PIXEL) as usize;
+                            resource.data[dst..dst + len].copy_from_slice(&row);
+                        }
+                        Ok(())
+                    }
+                }
+            }
+            VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING => {
+                let id = le_u32(body, 0)?;
+                let entries = le_u32(body, 4)? as usize;
+                let backing = (0..entries)
+                    .map(|entry| {
+                        let entry = 8 + entry * 16;
+                        Ok((le_u64(body, entry)?, le_u32(body, entry + 8)?))
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                self.resource(id).map(|resource| resource.backing = backing)
+            }
+            VIRTIO_GPU_CMD_RESOURCE_DETACH_BACKING => {
+                let id = le_u32(body, 0)?;
+                self.resource(id).map(|resource| resource.backing.clear())
+            }
+            _ => {
+                println!("Unhandled virtio-gpu command {:#x}", command);
+                Err(VIRTIO_GPU_RESP_ERR_UNSPEC)
+            }
+        };
+        Ok((
+            result.err().unwrap_or(VIRTIO_GPU_RESP_OK_NODATA),
+            Vec::new(),
+        ))
+    }
+}
+
+impl VirtioDevice for VirtioGpu {
+    fn debug_label(&self) -> String {
+        "gpu".to_string()
+    }
+
+    fn device_type(&self) -> u32 {
+        VIRTIO_ID_GPU
+    }
+
+    fn queue_max_sizes(&self) -> Vec<u16> {
+        vec![QUEUE_SIZE, QUEUE_SIZE]
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        // events_read, events_clear, num_scanouts, num_capsets
+        let mut config = [0u8; 16];
+        config[8..12].copy_from_slice(&1u32.to_le_bytes());
+        data.fill(0);
+        if let Some(src) = config.get(offset as usize..) {
+            let len = src.len().min(data.len());
+            data[..len].copy_from_slice(&src[..len]);
+        }
+    }
+
+    fn write_config(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        // No events are ever raised, so there's nothing to clear
+        match (offset, data.len()) {
+            (4, 4) => Ok(()),
+            _ => Err(anyhow!(
+                "Unhandled virtio-gpu config write at {:#x}+{}",
+                offset,
+                data.len()
+            )),
+        }
+    }
+
+    fn process_queue(
+        &mut self,
+        index: usize,
+        queue: &mut Virtqueue,
+        mem: &GuestMemory,
+    ) -> Result<bool> {
+        let mut used = false;
+        while let Some(chain) = queue.pop(mem)? {
+            let request = chain.read_all(mem, MAX_REQUEST_SIZE)?;
+            let mut response = [0u8; CTRL_HDR_SIZE].to_vec();
+            let (response_type, data) = match index {
+                CONTROL_QUEUE => self.control(&request, mem)?,
+                // There's no cursor to draw, but the guest still expects its commands back
+                CURSOR_QUEUE => (VIRTIO_GPU_RESP_OK_NODATA, Vec::new()),
+                _ => unreachable!("virtio-gpu has only 2 queues"),
+            };
+            response[..4].copy_from_slice(&response_type.to_le_bytes());
+            // Commands complete synchronously, so fences are signalled right away
+            if le_u32(&request, 4)? & VIRTIO_GPU_FLAG_FENCE != 0 {
+                response[4..CTRL_HDR_SIZE].copy_from_slice(&request[4..CTRL_HDR_SIZE]);
+            }
+            response.extend(data);
+            let written = chain.write_all(mem, &response)?;
+            queue.add_used(mem, chain.head, written.try_into()?)?;
+            used = true;
+        }
+        Ok(used)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env, fs,
+        sync::{Arc, Mutex},
+    };
+
+    use crate::{test_util::Ram, Bus, BusRange, GuestMemory};
+
+    use super::*;
+
+    const RAM_BASE: u64 = 0x10000;
+    const BACKING: u64 = 0x11000;
+
+    fn mem() -> GuestMemory {
+        let bus = Bus::new();
+        bus.insert(Arc::new(Mutex::new(Ram(vec![0; 0x4000]))), RAM_BASE, 0x4000)
+            .unwrap();
+        GuestMemory::new(
+            bus,
+            BusRange {
+                base: 0x10_0000,
+                len: 0x200,
+            },
+        )
+    }
+
+    fn command(command: u32, body: &[u32]) -> Vec<u8> {
+        let mut request = command.to_le_bytes().to_vec();
+        request.resize(CTRL_HDR_SIZE, 0);
+        request.extend(body.iter().flat_map(|word| word.to_le_bytes()));
+        request
+    }
+
+    #[test]
+    fn display_info() {
+        let mut gpu = VirtioGpu::with_display(640, 480, PathBuf::new());
+        let (response, info) = gpu
+            .control(&command(VIRTIO_GPU_CMD_GET_DISPLAY_INFO, &[]), &mem())
+            .unwrap();
+        assert_eq!(response, VIRTIO_GPU_RESP_OK_DISPLAY_INFO);
+        assert_eq!(info.len(), 16 * 24);
+        assert_eq!(le_u32(&info, 8).unwrap(), 640);
+        assert_eq!(le_u32(&info, 12).unwrap(), 480);
+        assert_eq!(le_u32(&info, 16).unwrap(), 1);
+    }
+
+    #[test]
+    fn scanout_to_ppm() {
+        let output = env::temp_dir().join(format!("virtio-gpu-{}.ppm", std::process::id()));
+        let mut gpu = VirtioGpu::with_display(2, 2, output.clone());
+        let mem = mem();
+        // A 2x2 B8G8R8X8 image: red, green / blue, white
+        mem.write(
+            BACKING,
+            &[
+                0, 0, 0xff, 0, 0, 0xff, 0, 0, 0xff, 0, 0, 0, 0xff, 0xff, 0xff, 0,
+            ],
+        )
+        .unwrap();
+
+        let run = |gpu: &mut VirtioGpu, request: Vec<u8>| gpu.control(&request, &mem).unwrap().0;
+        assert_eq!(
+            run(
+                &mut gpu,
+                command(VIRTIO_GPU_CMD_RESOURCE_CREATE_2D, &[1, 2, 2, 2])
+            ),
+            VIRTIO_GPU_RESP_OK_NODATA
+        );
+        assert_eq!(
+            run(
+                &mut gpu,
+                command(
+                    VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING,
+                    &[1, 1, BACKING as u32, 0, 16, 0]
+                )
+            ),
+            VIRTIO_GPU_RESP_OK_NODATA
+        );
+        assert_eq!(
+            run(
+                &mut gpu,
+                command(
+                    VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D,
+                    &[0, 0, 2, 2, 0, 0, 1, 0]
+                )
+            ),
+            VIRTIO_GPU_RESP_OK_NODATA
+        );
+        assert_eq!(
+            run(
+                &mut gpu,
+                command(VIRTIO_GPU_CMD_SET_SCANOUT, &[0, 0, 2, 2, 0, 1])
+            ),
+            VIRTIO_GPU_RESP_OK_NODATA
+        );
+        assert_eq!(
+            run(
+                &mut gpu,
+                command(VIRTIO_GPU_CMD_RESOURCE_FLUSH, &[0, 0, 2, 2, 1, 0])
+            ),
+            VIRTIO_GPU_RESP_OK_NODATA
+        );
+        assert_eq!(gpu.frames(), 1);
+
+        let mut expected = b"P6\n2 2\n255\n".to_vec();
+        expected.extend([0xff, 0, 0, 0, 0xff, 0, 0, 0, 0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(fs::read(&output).unwrap(), expected);
+        fs::remove_file(output).unwrap();
+    }
+
+    #[test]
+    fn invalid_resource() {
+        let mut gpu = VirtioGpu::with_display(2, 2, PathBuf::new());
+        let mem = mem();
+        for request in [
+            command(VIRTIO_GPU_CMD_RESOURCE_UNREF, &[7, 0]),
+            command(VIRTIO_GPU_CMD_SET_SCANOUT, &[0, 0, 2, 2, 0, 7]),
+            command(
+                VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D,
+                &[0, 0, 2, 2, 0, 0, 7, 0],
+            ),
+        ] {
+            assert_eq!(
+                gpu.control(&request, &mem).unwrap().0,
+                VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID
+            );
+        }
+        // Unsupported pixel format
+        assert_eq!(
+            gpu.control(
+                &command(VIRTIO_GPU_CMD_RESOURCE_CREATE_2D, &[1, 0x1234, 2, 2]),
+                &mem
+            )
+            .unwrap()
+            .0,
+            VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER
+        );
+    }
+
+    #[test]
+    fn resource_memory_limit() {
+        let mut gpu = VirtioGpu::with_display(4, 4, PathBuf::new());
+        let mem = mem();
+        let mut run = |request: Vec<u8>| gpu.control(&request, &mem).unwrap().0;
+        // Bigger than the display
+        assert_eq!(
+            run(command(
+                VIRTIO_GPU_CMD_RESOURCE_CREATE_2D,
+                &[1, 2, 0x10000, 0x10000]
+            )),
+            VIRTIO_GPU_RESP_ERR_OUT_OF_MEMORY
+        );
+        for id in 1..=4 {
+            assert_eq!(
+                run(command(VIRTIO_GPU_CMD_RESOURCE_CREATE_2D, &[id, 2, 4, 4])),
+                VIRTIO_GPU_RESP_OK_NODATA
+            );
+        }
+        assert_eq!(
+            run(command(VIRTIO_GPU_CMD_RESOURCE_CREATE_2D, &[5, 2, 1, 1])),
+            VIRTIO_GPU_RESP_ERR_OUT_OF_MEMORY
+        );
+        // Freeing a resource makes room again
+        assert_eq!(
+            run(command(VIRTIO_GPU_CMD_RESOURCE_UNREF, &[2, 0])),
+            VIRTIO_GPU_RESP_OK_NODATA
+        );
+        assert_eq!(
+            run(command(VIRTIO_GPU_CMD_RESOURCE_CREATE_2D, &[5, 2, 4, 4])),
+            VIRTIO_GPU_RESP_OK_NODATA
+        );
+    }
+}
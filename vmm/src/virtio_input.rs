@@ -0,0 +1,249 @@
+// Copyright (c) 2024, Qualcomm Innovation Center, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause-Clear
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    GuestMemory, GunyahVirtualMachine, VirtioDevice, VirtioMmio, Virtqueue, VIRTIO_ID_INPUT,
+};
+
+const EVENT_QUEUE: usize = 0;
+const STATUS_QUEUE: usize = 1;
+const QUEUE_SIZE: u16 = 64;
+
+const VIRTIO_INPUT_CFG_ID_NAME: u8 = 0x01;
+const VIRTIO_INPUT_CFG_EV_BITS: u8 = 0x11;
+
+pub const EV_SYN: u16 = 0x00;
+pub const EV_KEY: u16 = 0x01;
+pub const EV_REL: u16 = 0x02;
+const SYN_REPORT: u16 = 0;
+
+pub const REL_X: u16 = 0x00;
+pub const REL_Y: u16 = 0x01;
+pub const REL_WHEEL: u16 = 0x08;
+
+pub const BTN_LEFT: u16 = 0x110;
+pub const BTN_RIGHT: u16 = 0x111;
+pub const BTN_MIDDLE: u16 = 0x112;
+
+/// Highest keyboard key code reported, KEY_MICMUTE.
+const KEY_MAX_KEYBOARD: u16 = 248;
+
+const NAME: &[u8] = b"Gunyah virtio input";
+
+/// Sets the bits for `codes` in an evdev style bitmap.
+fn bitmap(codes: impl IntoIterator<Item = u16>) -> Vec<u8> {
+    let mut bitmap = Vec::new();
+    for code in codes {
+        let byte = usize::from(code / 8);
+        if bitmap.len() <= byte {
+            bitmap.resize(byte + 1, 0);
+        }
+        bitmap[byte] |= 1 << (code % 8);
+    }
+    bitmap
+}
+
+/// Combined keyboard and relative pointer. Tests inject events with [`VirtioInput::send_key`]
+/// and [`VirtioInput::send_rel`]; events are held until the guest provides buffers for them.
+#[derive(Default)]
+pub struct VirtioInput {
+    events: VecDeque<[u8; 8]>,
+    select: u8,
+    subsel: u8,
+}
+
+impl VirtioInput {
+    pub fn new(
+        vm: &mut GunyahVirtualMachine,
+        base: u64,
+        interrupt_line: u32,
+    ) -> Result<Arc<Mutex<VirtioMmio<Self>>>> {
+        VirtioMmio::new(vm, base, interrupt_line, Self::default())
+    }
+
+    /// Presses (`pressed`) or releases key or button `code`, e.g. KEY_A (30) or [`BTN_LEFT`].
+    pub fn send_key(input: &mut VirtioMmio<Self>, code: u16, pressed: bool) -> Result<()> {
+        input.device_mut().queue_event(EV_KEY, code, pressed.into());
+        Self::sync(input)
+    }
+
+    /// Moves relative axis `code`, e.g. [`REL_X`], by `value`.
+    pub fn send_rel(input: &mut VirtioMmio<Self>, code: u16, value: i32) -> Result<()> {
+        input.device_mut().queue_event(EV_REL, code, value as u32);
+        Self::sync(input)
+    }
+
+    /// Number of events the guest hasn't picked up yet.
+    pub fn pending_events(&self) -> usize {
+        self.events.len()
+    }
+
+    fn sync(input: &mut VirtioMmio<Self>) -> Result<()> {
+        input.device_mut().queue_event(EV_SYN, SYN_REPORT, 0);
+        input.notify(EVENT_QUEUE)
+    }
+
+    fn queue_event(&mut self, event_type: u16, code: u16, value: u32) {
+        let mut event = [0u8; 8];
+        event[..2].copy_from_slice(&event_type.to_le_bytes());
+        event[2..4].copy_from_slice(&code.to_le_bytes());
+        event[4..].copy_from_slice(&value.to_le_bytes());
+        self.events.push_back(event);
+    }
+
+    /// Payload of the configuration space for the current select/subsel.
+    fn config_data(&self) -> Vec<u8> {
+        match (self.select, u16::from(self.subsel)) {
+            (VIRTIO_INPUT_CFG_ID_NAME, _) => NAME.to_vec(),
+            (VIRTIO_INPUT_CFG_EV_BITS, EV_KEY) => {
+                bitmap((1..=KEY_MAX_KEYBOARD).chain([BTN_LEFT, BTN_RIGHT, BTN_MIDDLE]))
+            }
+            (VIRTIO_INPUT_CFG_EV_BITS, EV_REL) => bitmap([REL_X, REL_Y, REL_WHEEL]),
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl VirtioDevice for VirtioInput {
+    fn debug_label(&self) -> String {
+        "input".to_string()
+    }
+
+    fn device_type(&self) -> u32 {
+        VIRTIO_ID_INPUT
+    }
+
+    fn queue_max_sizes(&self) -> Vec<u16> {
+        vec![QUEUE_SIZE, QUEUE_SIZE]
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        // select, subsel, size, reserved[5], then up to 128 bytes of payload
+        let payload = self.config_data();
+        let mut config = vec![self.select, self.subsel, payload.len() as u8, 0, 0, 0, 0, 0];
+        config.extend(payload);
+        data.fill(0);
+        if let Some(src) = config.get(offset as usize..) {
+            let len = src.len().min(data.len());
+            data[..len].copy_from_slice(&src[..len]);
+        }
+    }
+
+    fn write_config(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        match (offset, data) {
+            (0, [select]) => self.select = *select,
+            (1, [subsel]) => self.subsel = *subsel,
+            _ => {
+                return Err(anyhow!(
+                    "Unhandled virtio-input config write at {:#x}+{}",
+                    offset,
+                    data.len()
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.select = 0;
+        self.subsel = 0;
+    }
+
+    fn process_queue(
+        &mut self,
+        index: usize,
+        queue: &mut Virtqueue,
+        mem: &GuestMemory,
+    ) -> Result<bool> {
+        let mut used = false;
+        match index {
+            EVENT_QUEUE => {
+                while let Some(event) = self.events.front() {
+                    let Some(chain) = queue.pop(mem)? else {
+                        break;
+                    };
+                    let written = chain.write_all(mem, event)?;
+                    queue.add_used(mem, chain.head, written.try_into()?)?;
+                    self.events.pop_front();
+                    used = true;
+                }
+            }
+            // LED updates, there are no LEDs to light up
+            STATUS_QUEUE => {
+                while let Some(chain) = queue.pop(mem)? {
+                    queue.add_used(mem, chain.head, 0)?;
+                    used = true;
+                }
+            }
+            _ => unreachable!("virtio-input has only 2 queues"),
+        }
+        Ok(used)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use claim::{assert_err, assert_ok};
+
+    use crate::VirtioDevice;
+
+    use super::{
+        VirtioInput, BTN_LEFT, EV_KEY, EV_REL, REL_WHEEL, VIRTIO_INPUT_CFG_EV_BITS,
+        VIRTIO_INPUT_CFG_ID_NAME,
+    };
+
+    fn select(input: &mut VirtioInput, select: u8, subsel: u16) -> Vec<u8> {
+        assert_ok!(input.write_config(0, &[select]));
+        assert_ok!(input.write_config(1, &[subsel as u8]));
+        let mut size = [0u8];
+        input.read_config(2, &mut size);
+        let mut data = vec![0u8; size[0].into()];
+        input.read_config(8, &mut data);
+        data
+    }
+
+    #[test]
+    fn config_space() {
+        let mut input = VirtioInput::default();
+        assert_eq!(
+            select(&mut input, VIRTIO_INPUT_CFG_ID_NAME, 0),
+            b"Gunyah virtio input"
+        );
+
+        let keys = select(&mut input, VIRTIO_INPUT_CFG_EV_BITS, EV_KEY);
+        // KEY_A and BTN_LEFT
+        assert_ne!(keys[30 / 8] & 1 << (30 % 8), 0);
+        assert_ne!(keys[usize::from(BTN_LEFT / 8)] & 1 << (BTN_LEFT % 8), 0);
+        assert_eq!(keys[0] & 1, 0);
+
+        let rel = select(&mut input, VIRTIO_INPUT_CFG_EV_BITS, EV_REL);
+        assert_eq!(rel, [0x03, 1 << (REL_WHEEL % 8)]);
+
+        // Nothing is reported for absolute axes
+        assert!(select(&mut input, VIRTIO_INPUT_CFG_EV_BITS, 3).is_empty());
+    }
+
+    #[test]
+    fn queued_events() {
+        let mut input = VirtioInput::default();
+        input.queue_event(EV_REL, 0, -5i32 as u32);
+        assert_eq!(input.pending_events(), 1);
+        assert_eq!(
+            input.events.front(),
+            Some(&[2, 0, 0, 0, 0xfb, 0xff, 0xff, 0xff])
+        );
+    }
+
+    #[test]
+    fn config_read_only() {
+        let mut input = VirtioInput::default();
+        assert_err!(input.write_config(2, &[1]));
+    }
+}
@@ -0,0 +1,512 @@
+// Copyright (c) 2024, Qualcomm Innovation Center, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause-Clear
+
+//! virtio-iommu which restricts the guest memory other virtio devices can access.
+//!
+//! Devices put behind the iommu with [`VirtioMmio::set_iommu`] only reach guest memory the
+//! driver mapped into their domain, similar to how a protected VM constrains device DMA.
+//! Endpoints which aren't attached to a domain can't access any memory.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::{
+    FdtWriter, GuestMemory, GunyahVirtualMachine, VirtioDevice, VirtioMmio, Virtqueue,
+    VIRTIO_ID_IOMMU,
+};
+
+const REQUEST_QUEUE: usize = 0;
+const EVENT_QUEUE: usize = 1;
+const QUEUE_SIZE: u16 = 64;
+/// Larger than any request the device handles
+const MAX_REQUEST_SIZE: usize = 0x100;
+
+/// phandle of the iommu node in the device tree. Only one virtio-iommu is supported per VM.
+pub const VIRTIO_IOMMU_PHANDLE: u32 = 0x100;
+
+const VIRTIO_IOMMU_F_INPUT_RANGE: u64 = 1 << 0;
+const VIRTIO_IOMMU_F_DOMAIN_RANGE: u64 = 1 << 1;
+const VIRTIO_IOMMU_F_MAP_UNMAP: u64 = 1 << 2;
+
+const VIRTIO_IOMMU_T_ATTACH: u8 = 1;
+const VIRTIO_IOMMU_T_DETACH: u8 = 2;
+const VIRTIO_IOMMU_T_MAP: u8 = 3;
+const VIRTIO_IOMMU_T_UNMAP: u8 = 4;
+
+const VIRTIO_IOMMU_S_OK: u8 = 0;
+const VIRTIO_IOMMU_S_UNSUPP: u8 = 2;
+const VIRTIO_IOMMU_S_INVAL: u8 = 4;
+const VIRTIO_IOMMU_S_RANGE: u8 = 5;
+const VIRTIO_IOMMU_S_NOENT: u8 = 6;
+
+const VIRTIO_IOMMU_MAP_F_READ: u32 = 1 << 0;
+const VIRTIO_IOMMU_MAP_F_WRITE: u32 = 1 << 1;
+
+const PAGE_SIZE_MASK: u64 = !0xfff;
+
+#[derive(Copy, Clone, Debug)]
+struct Mapping {
+    /// Last address of the mapping, inclusive
+    virt_end: u64,
+    phys_start: u64,
+    flags: u32,
+}
+
+#[derive(Debug, Default)]
+struct IommuState {
+    /// Endpoints known to the iommu and the domain they're attached to
+    endpoints: HashMap<u32, Option<u32>>,
+    /// Mappings of each domain, by start address
+    domains: HashMap<u32, BTreeMap<u64, Mapping>>,
+}
+
+impl IommuState {
+    fn attach(&mut self, domain: u32, endpoint: u32) -> u8 {
+        let Some(attached) = self.endpoints.get_mut(&endpoint) else {
+            return VIRTIO_IOMMU_S_NOENT;
+        };
+        let previous = attached.replace(domain);
+        self.domains.entry(domain).or_default();
+        if let Some(previous) = previous.filter(|&previous| previous != domain) {
+            self.release_domain(previous);
+        }
+        VIRTIO_IOMMU_S_OK
+    }
+
+    fn detach(&mut self, domain: u32, endpoint: u32) -> u8 {
+        match self.endpoints.get_mut(&endpoint) {
+            None => VIRTIO_IOMMU_S_NOENT,
+            Some(attached) if *attached != Some(domain) => VIRTIO_IOMMU_S_INVAL,
+            Some(attached) => {
+                *attached = None;
+                self.release_domain(domain);
+                VIRTIO_IOMMU_S_OK
+            }
+        }
+    }
+
+    /// Destroys `domain` and its mappings once no endpoint is attached to it.
+    fn release_domain(&mut self, domain: u32) {
+        if !self.endpoints.values().any(|&d| d == Some(domain)) {
+            self.domains.remove(&domain);
+        }
+    }
+
+    fn map(&mut self, domain: u32, virt_start: u64, mapping: Mapping) -> u8 {
+        let Some(mappings) = self.domains.get_mut(&domain) else {
+            return VIRTIO_IOMMU_S_NOENT;
+        };
+        if mapping.virt_end < virt_start
+            || mapping.flags & !(VIRTIO_IOMMU_MAP_F_READ | VIRTIO_IOMMU_MAP_F_WRITE) != 0
+        {
+            return VIRTIO_IOMMU_S_INVAL;
+        }
+        // The guest physical range must not wrap around, so translations can't overflow
+        if mapping
+            .phys_start
+            .checked_add(mapping.virt_end - virt_start)
+            .is_none()
+        {
+            return VIRTIO_IOMMU_S_RANGE;
+        }
+        let overlaps = mappings
+            .range(..=mapping.virt_end)
+            .next_back()
+            .is_some_and(|(_, existing)| existing.virt_end >= virt_start);
+        if overlaps {
+            return VIRTIO_IOMMU_S_INVAL;
+        }
+        mappings.insert(virt_start, mapping);
+        VIRTIO_IOMMU_S_OK
+    }
+
+    fn unmap(&mut self, domain: u32, virt_start: u64, virt_end: u64) -> u8 {
+        let Some(mappings) = self.domains.get_mut(&domain) else {
+            return VIRTIO_IOMMU_S_NOENT;
+        };
+        let covered: Vec<_> = mappings
+            .range(..=virt_end)
+            .filter(|(_, mapping)| mapping.virt_end >= virt_start)
+            .map(|(&start, mapping)| (start, mapping.virt_end))
+            .collect();
+        // Mappings can't be split
+        if covered
+            .iter()
+            .any(|&(start, end)| start < virt_start || end > virt_end)
+        {
+            return VIRTIO_IOMMU_S_RANGE;
+        }
+        for (start, _) in covered {
+            mappings.remove(&start);
+        }
+        VIRTIO_IOMMU_S_OK
+    }
+
+    fn translate(
+        &self,
+        endpoint: u32,
+        iova: u64,
+        len: u64,
+        write: bool,
+    ) -> Result<Vec<(u64, u64)>> {
+        let domain = self
+            .endpoints
+            .get(&endpoint)
+            .copied()
+            .flatten()
+            .ok_or(anyhow!("Endpoint {} isn't attached to a domain", endpoint))?;
+        let mappings = &self.domains[&domain];
+        let required = if write {
+            VIRTIO_IOMMU_MAP_F_WRITE
+        } else {
+            VIRTIO_IOMMU_MAP_F_READ
+        };
+
+        let mut ranges = Vec::new();
+        let mut iova = iova;
+        let mut left = len;
+        while left > 0 {
+            let (start, mapping) = mappings
+                .range(..=iova)
+                .next_back()
+                .filter(|(_, mapping)| mapping.virt_end >= iova)
+                .ok_or(anyhow!(
+                    "Endpoint {} accessed unmapped address {:#x}",
+                    endpoint,
+                    iova
+                ))?;
+            if mapping.flags & required == 0 {
+                bail!(
+                    "Endpoint {} isn't allowed to {} {:#x}",
+                    endpoint,
+                    if write { "write" } else { "read" },
+                    iova
+                );
+            }
+            let chunk = (mapping.virt_end - iova).saturating_add(1).min(left);
+            ranges.push((mapping.phys_start + (iova - start), chunk));
+            iova = iova.wrapping_add(chunk);
+            left -= chunk;
+        }
+        Ok(ranges)
+    }
+}
+
+/// A device behind a [`VirtioIommu`], used to translate the device's DMA addresses.
+#[derive(Clone, Debug)]
+pub struct IommuEndpoint {
+    state: Arc<Mutex<IommuState>>,
+    id: u32,
+}
+
+impl IommuEndpoint {
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Translates `len` bytes of device address `iova` to guest physical ranges. Fails if any
+    /// part isn't mapped with the required permission.
+    pub fn translate(&self, iova: u64, len: u64, write: bool) -> Result<Vec<(u64, u64)>> {
+        self.state
+            .lock()
+            .unwrap()
+            .translate(self.id, iova, len, write)
+    }
+
+    /// Describes the endpoint in the node of the device behind the iommu.
+    pub fn device_config(&self, fdt: &mut FdtWriter) -> Result<()> {
+        fdt.property_array_u32("iommus", &[VIRTIO_IOMMU_PHANDLE, self.id])?;
+        Ok(())
+    }
+}
+
+pub struct VirtioIommu {
+    state: Arc<Mutex<IommuState>>,
+    next_endpoint: u32,
+}
+
+impl VirtioIommu {
+    pub fn new(
+        vm: &mut GunyahVirtualMachine,
+        base: u64,
+        interrupt_line: u32,
+    ) -> Result<Arc<Mutex<VirtioMmio<Self>>>> {
+        VirtioMmio::new(vm, base, interrupt_line, Self::default())
+    }
+
+    /// Registers a new endpoint. Pass it to [`VirtioMmio::set_iommu`] to put a device behind the
+    /// iommu.
+    pub fn add_endpoint(&mut self) -> IommuEndpoint {
+        let id = self.next_endpoint;
+        self.next_endpoint += 1;
+        self.state.lock().unwrap().endpoints.insert(id, None);
+        IommuEndpoint {
+            state: self.state.clone(),
+            id,
+        }
+    }
+
+    fn config(&self) -> [u8; 40] {
+        let mut config = [0u8; 40];
+        config[..8].copy_from_slice(&PAGE_SIZE_MASK.to_le_bytes());
+        // input_range.start is 0
+        config[16..24].copy_from_slice(&u64::MAX.to_le_bytes());
+        // domain_range.start is 0
+        config[28..32].copy_from_slice(&u32::MAX.to_le_bytes());
+        config
+    }
+
+    /// Executes the request in `request`. Returns the status to write to its tail.
+    fn request(&mut self, request: &[u8]) -> Result<u8> {
+        let le_u32 = |offset: usize| -> Result<u32> {
+            Ok(u32::from_le_bytes(
+                request
+                    .get(offset..offset + 4)
+                    .ok_or(anyhow!("Truncated virtio-iommu request"))?
+                    .try_into()?,
+            ))
+        };
+        let le_u64 = |offset: usize| -> Result<u64> {
+            Ok(u64::from_le_bytes(
+                request
+                    .get(offset..offset + 8)
+                    .ok_or(anyhow!("Truncated virtio-iommu request"))?
+                    .try_into()?,
+            ))
+        };
+
+        let mut state = self.state.lock().unwrap();
+        let kind = *request
+            .first()
+            .ok_or(anyhow!("Empty virtio-iommu request"))?;
+        Ok(match kind {
+            VIRTIO_IOMMU_T_ATTACH => state.attach(le_u32(4)?, le_u32(8)?),
+            VIRTIO_IOMMU_T_DETACH => state.detach(le_u32(4)?, le_u32(8)?),
+            VIRTIO_IOMMU_T_MAP => state.map(
+                le_u32(4)?,
+                le_u64(8)?,
+                Mapping {
+                    virt_end: le_u64(16)?,
+                    phys_start: le_u64(24)?,
+                    flags: le_u32(32)?,
+                },
+            ),
+            VIRTIO_IOMMU_T_UNMAP => state.unmap(le_u32(4)?, le_u64(8)?, le_u64(16)?),
+            _ => VIRTIO_IOMMU_S_UNSUPP,
+        })
+    }
+}
+
+impl Default for VirtioIommu {
+    fn default() -> Self {
+        Self {
+            state: Default::default(),
+            // Endpoint IDs start at 1 to make missing "iommus" cells easier to spot
+            next_endpoint: 1,
+        }
+    }
+}
+
+impl VirtioDevice for VirtioIommu {
+    fn debug_label(&self) -> String {
+        "iommu".to_string()
+    }
+
+    fn device_type(&self) -> u32 {
+        VIRTIO_ID_IOMMU
+    }
+
+    fn queue_max_sizes(&self) -> Vec<u16> {
+        vec![QUEUE_SIZE, QUEUE_SIZE]
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_IOMMU_F_INPUT_RANGE | VIRTIO_IOMMU_F_DOMAIN_RANGE | VIRTIO_IOMMU_F_MAP_UNMAP
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        let config = self.config();
+        data.fill(0);
+        if let Some(src) = config.get(offset as usize..) {
+            let len = src.len().min(data.len());
+            data[..len].copy_from_slice(&src[..len]);
+        }
+    }
+
+    fn reset(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        state.domains.clear();
+        state
+            .endpoints
+            .values_mut()
+            .for_each(|domain| *domain = None);
+    }
+
+    fn device_config(&self, fdt: &mut FdtWriter) -> Result<()> {
+        fdt.property_u32("#iommu-cells", 1)?;
+        fdt.property_u32("phandle", VIRTIO_IOMMU_PHANDLE)?;
+        Ok(())
+    }
+
+    fn process_queue(
+        &mut self,
+        index: usize,
+        queue: &mut Virtqueue,
+        mem: &GuestMemory,
+    ) -> Result<bool> {
+        // Buffers for fault reports are kept, no faults are reported
+        if index == EVENT_QUEUE {
+            return Ok(false);
+        }
+        assert_eq!(index, REQUEST_QUEUE, "virtio-iommu has only 2 queues");
+
+        let mut used = false;
+        while let Some(chain) = queue.pop(mem)? {
+            let request = chain.read_all(mem, MAX_REQUEST_SIZE)?;
+            let status = self.request(&request)?;
+            // The tail is the status followed by 3 reserved bytes
+            let written = chain.write_all(mem, &[status, 0, 0, 0])?;
+            queue.add_used(mem, chain.head, written.try_into()?)?;
+            used = true;
+        }
+        Ok(used)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use claim::{assert_err, assert_ok};
+
+    use super::*;
+
+    fn attach(domain: u32, endpoint: u32) -> Vec<u8> {
+        let mut request = vec![VIRTIO_IOMMU_T_ATTACH, 0, 0, 0];
+        request.extend(domain.to_le_bytes());
+        request.extend(endpoint.to_le_bytes());
+        request.extend([0; 12]);
+        request
+    }
+
+    fn map(domain: u32, start: u64, end: u64, phys: u64, flags: u32) -> Vec<u8> {
+        let mut request = vec![VIRTIO_IOMMU_T_MAP, 0, 0, 0];
+        request.extend(domain.to_le_bytes());
+        request.extend(start.to_le_bytes());
+        request.extend(end.to_le_bytes());
+        request.extend(phys.to_le_bytes());
+        request.extend(flags.to_le_bytes());
+        request
+    }
+
+    fn unmap(domain: u32, start: u64, end: u64) -> Vec<u8> {
+        let mut request = vec![VIRTIO_IOMMU_T_UNMAP, 0, 0, 0];
+        request.extend(domain.to_le_bytes());
+        request.extend(start.to_le_bytes());
+        request.extend(end.to_le_bytes());
+        request.extend([0; 4]);
+        request
+    }
+
+    const RW: u32 = VIRTIO_IOMMU_MAP_F_READ | VIRTIO_IOMMU_MAP_F_WRITE;
+
+    #[test]
+    fn unattached_endpoint() {
+        let mut iommu = VirtioIommu::default();
+        let endpoint = iommu.add_endpoint();
+        assert_err!(endpoint.translate(0x1000, 4, false));
+        // Unknown endpoints can't be attached
+        assert_eq!(iommu.request(&attach(1, 42)).unwrap(), VIRTIO_IOMMU_S_NOENT);
+    }
+
+    #[test]
+    fn map_and_translate() {
+        let mut iommu = VirtioIommu::default();
+        let endpoint = iommu.add_endpoint();
+        assert_eq!(
+            iommu.request(&attach(1, endpoint.id())).unwrap(),
+            VIRTIO_IOMMU_S_OK
+        );
+        assert_eq!(
+            iommu
+                .request(&map(1, 0x1000, 0x1fff, 0x8000_0000, RW))
+                .unwrap(),
+            VIRTIO_IOMMU_S_OK
+        );
+        assert_eq!(
+            iommu
+                .request(&map(
+                    1,
+                    0x2000,
+                    0x2fff,
+                    0x9000_0000,
+                    VIRTIO_IOMMU_MAP_F_READ
+                ))
+                .unwrap(),
+            VIRTIO_IOMMU_S_OK
+        );
+
+        assert_eq!(
+            assert_ok!(endpoint.translate(0x1ff0, 0x20, false)),
+            vec![(0x8000_0ff0, 0x10), (0x9000_0000, 0x10)]
+        );
+        // Read-only and unmapped memory
+        assert_err!(endpoint.translate(0x2000, 4, true));
+        assert_err!(endpoint.translate(0x3000, 4, false));
+        // Overlapping mappings are rejected
+        assert_eq!(
+            iommu.request(&map(1, 0x1800, 0x27ff, 0, RW)).unwrap(),
+            VIRTIO_IOMMU_S_INVAL
+        );
+        // So are physical ranges past the end of the address space
+        assert_eq!(
+            iommu
+                .request(&map(1, 0x4000, 0x4fff, u64::MAX, RW))
+                .unwrap(),
+            VIRTIO_IOMMU_S_RANGE
+        );
+        assert_err!(endpoint.translate(0x4000, 4, false));
+    }
+
+    #[test]
+    fn unmap_whole_mappings() {
+        let mut iommu = VirtioIommu::default();
+        let endpoint = iommu.add_endpoint();
+        iommu.request(&attach(1, endpoint.id())).unwrap();
+        iommu
+            .request(&map(1, 0x1000, 0x2fff, 0x8000_0000, RW))
+            .unwrap();
+
+        assert_eq!(
+            iommu.request(&unmap(1, 0x1000, 0x1fff)).unwrap(),
+            VIRTIO_IOMMU_S_RANGE
+        );
+        assert_ok!(endpoint.translate(0x1000, 4, true));
+        assert_eq!(
+            iommu.request(&unmap(1, 0, 0xffff)).unwrap(),
+            VIRTIO_IOMMU_S_OK
+        );
+        assert_err!(endpoint.translate(0x1000, 4, true));
+    }
+
+    #[test]
+    fn detach_destroys_domain() {
+        let mut iommu = VirtioIommu::default();
+        let endpoint = iommu.add_endpoint();
+        iommu.request(&attach(1, endpoint.id())).unwrap();
+        iommu
+            .request(&map(1, 0x1000, 0x1fff, 0x8000_0000, RW))
+            .unwrap();
+
+        let mut detach = attach(1, endpoint.id());
+        detach[0] = VIRTIO_IOMMU_T_DETACH;
+        assert_eq!(iommu.request(&detach).unwrap(), VIRTIO_IOMMU_S_OK);
+        assert_err!(endpoint.translate(0x1000, 4, false));
+
+        // Reattaching starts from an empty domain
+        iommu.request(&attach(1, endpoint.id())).unwrap();
+        assert_err!(endpoint.translate(0x1000, 4, false));
+    }
+}
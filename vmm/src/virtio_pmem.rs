@@ -0,0 +1,164 @@
+// Copyright (c) 2024, Qualcomm Innovation Center, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause-Clear
+
+use std::{
+    fs::{File, OpenOptions},
+    num::NonZeroUsize,
+    os::unix::fs::FileExt,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, Context, Result};
+use gunyah::{GuestMemoryAccess, ShareType};
+
+use crate::{
+    GuestMemory, GunyahGuestMemoryRegion, GunyahVirtualMachine, VirtioDevice, VirtioMmio,
+    Virtqueue, VIRTIO_ID_PMEM,
+};
+
+const QUEUE_SIZE: u16 = 32;
+
+const VIRTIO_PMEM_REQ_TYPE_FLUSH: u32 = 0;
+/// A request is only its type
+const REQUEST_SIZE: usize = 4;
+
+/// virtio-pmem exposing the contents of a host file as persistent memory.
+///
+/// The file is copied into shared guest memory which the guest maps directly, so reads don't
+/// exit to the VMM. Guest writes reach the file when the guest flushes the device. Read-only
+/// devices are mapped without write access and never touch the file.
+pub struct VirtioPmem {
+    region: Arc<Mutex<GunyahGuestMemoryRegion>>,
+    file: File,
+    read_only: bool,
+}
+
+impl VirtioPmem {
+    /// Adds a virtio-pmem device at `base` whose memory is placed at `guest_address`. The memory
+    /// is the size of `path` rounded up to `page_size`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        vm: &mut GunyahVirtualMachine,
+        base: u64,
+        interrupt_line: u32,
+        path: &Path,
+        guest_address: u64,
+        page_size: usize,
+        read_only: bool,
+    ) -> Result<Arc<Mutex<VirtioMmio<Self>>>> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(!read_only)
+            .open(path)
+            .context(format!("Failed to open {}", path.display()))?;
+        let len = usize::try_from(file.metadata()?.len())?;
+        let size = NonZeroUsize::new(len.next_multiple_of(page_size))
+            .ok_or(anyhow!("{} is empty", path.display()))?;
+
+        let region = vm.add_memory(
+            guest_address,
+            size,
+            ShareType::Share,
+            if read_only {
+                GuestMemoryAccess::R
+            } else {
+                GuestMemoryAccess::Rw
+            },
+            false,
+        )?;
+        {
+            let region = region.lock().unwrap();
+            let mut mem = region.as_region().map_mut()?;
+            file.read_exact_at(&mut mem[..len], 0)
+                .context(format!("Failed to read {}", path.display()))?;
+        }
+
+        VirtioMmio::new(
+            vm,
+            base,
+            interrupt_line,
+            Self {
+                region,
+                file,
+                read_only,
+            },
+        )
+    }
+
+    /// Writes the guest's changes back to the file.
+    pub fn flush(&self) -> Result<()> {
+        if self.read_only {
+            return Ok(());
+        }
+        let region = self.region.lock().unwrap();
+        let mem = region.as_region().map()?;
+        let len = usize::try_from(self.file.metadata()?.len())?;
+        self.file.write_all_at(&mem[..len], 0)?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    fn config(&self) -> [u8; 16] {
+        let region = self.region.lock().unwrap();
+        let mut config = [0u8; 16];
+        config[..8].copy_from_slice(&region.guest_address().to_le_bytes());
+        config[8..].copy_from_slice(&(region.as_region().size() as u64).to_le_bytes());
+        config
+    }
+}
+
+impl VirtioDevice for VirtioPmem {
+    fn debug_label(&self) -> String {
+        "pmem".to_string()
+    }
+
+    fn device_type(&self) -> u32 {
+        VIRTIO_ID_PMEM
+    }
+
+    fn queue_max_sizes(&self) -> Vec<u16> {
+        vec![QUEUE_SIZE]
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        let config = self.config();
+        data.fill(0);
+        if let Some(src) = config.get(offset as usize..) {
+            let len = src.len().min(data.len());
+            data[..len].copy_from_slice(&src[..len]);
+        }
+    }
+
+    fn process_queue(
+        &mut self,
+        _index: usize,
+        queue: &mut Virtqueue,
+        mem: &GuestMemory,
+    ) -> Result<bool> {
+        let mut used = false;
+        while let Some(chain) = queue.pop(mem)? {
+            let request = chain.read_all(mem, REQUEST_SIZE)?;
+            let request_type = u32::from_le_bytes(
+                request
+                    .get(..4)
+                    .ok_or(anyhow!("Truncated virtio-pmem request"))?
+                    .try_into()?,
+            );
+            let ret: u32 = match request_type {
+                VIRTIO_PMEM_REQ_TYPE_FLUSH => match self.flush() {
+                    Ok(()) => 0,
+                    Err(e) => {
+                        println!("Failed to flush virtio-pmem: {:?}", e);
+                        1
+                    }
+                },
+                _ => 1,
+            };
+            let written = chain.write_all(mem, &ret.to_le_bytes())?;
+            queue.add_used(mem, chain.head, written.try_into()?)?;
+            used = true;
+        }
+        Ok(used)
+    }
+}
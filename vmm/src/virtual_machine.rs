@@ -2,64 +2,401 @@
 // SPDX-License-Identifier: BSD-3-Clause-Clear
 
 use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::File,
+    io::{self, Read, Stdout, Write},
     num::NonZeroUsize,
+    path::Path,
     sync::{Arc, Mutex, RwLock},
 };
 
-use anyhow::{Context, Result};
-use gunyah::{GuestMemRegion, GuestMemoryAccess, Gunyah, Ioeventfd, ShareType};
+use anyhow::{anyhow, Context, Result};
+use gunyah::{
+    BootReg, GuestMemRegion, GuestMemoryAccess, Gunyah, HugePageSize, Ioeventfd, ShareType, VmType,
+};
 
 use vm_fdt::FdtWriter;
 
 use crate::{
-    AccessId, Bus, BusDevice, BusDeviceSync, GunyahGuestMemoryRegion, GunyahInterrupt, GunyahVcpu,
+    fast_write::FastWriteTask, interrupt::ResampleTask, memory, numa, rm_console,
+    vm_state::check_state, AccessId, Bus, BusDevice, BusDeviceSync, CpuTopology, DebugExit,
+    DebugStop, DeviceExecutor, FdtBuilder, GicConfig, GunyahGuestMemoryRegion, GunyahInterrupt,
+    GunyahVcpu, IoEngine, MbiConfig, MemoryCounters, MemoryHotplug, MemorySnapshot,
+    MessageQueueConfig, MmioTrace, MsiFrame, NumaNode, PrefixedLog, RetryPolicy, RmConsole,
+    SeccompPolicy, Snapshot, VcpuHotplug, VcpuScheduling, VmDebug, VmExitRequest, VmMetrics,
+    VmState, VmStateError, DEBUG_EXIT_MMIO_SIZE, DEBUG_LOG_MMIO_SIZE, MSI_FRAME_MMIO_SIZE,
 };
 
+/// Boot configuration handed to Gunyah, remembered for snapshots.
+#[derive(Default)]
+struct BootConfig {
+    dtb: Option<(u64, u64)>,
+    /// The DTB as passed to [`GunyahVirtualMachine::set_dtb_config`]
+    dtb_blob: Option<Vec<u8>>,
+    regs: BTreeMap<BootReg, u64>,
+    firmware: Option<(u64, u64)>,
+}
+
 pub struct GunyahVirtualMachine {
     vm: gunyah::Vm,
-    vcpus: RwLock<Vec<Arc<GunyahVcpu>>>,
+    vm_type: VmType,
+    vcpus: Arc<RwLock<Vec<Arc<GunyahVcpu>>>>,
+    /// vCPU IDs below this are described to the guest, see [`Self::set_possible_vcpus`]
+    possible_vcpus: u8,
     bus: Bus,
-    interrupts: RwLock<Vec<Arc<GunyahInterrupt>>>,
+    interrupts: Arc<RwLock<Vec<Arc<GunyahInterrupt>>>>,
+    force_psci: bool,
+    cpu_topology: Option<CpuTopology>,
+    numa_nodes: Vec<NumaNode>,
+    vcpu_scheduling: VcpuScheduling,
+    rm_console: bool,
+    message_queues: Vec<MessageQueueConfig>,
+    mbi: Option<MbiConfig>,
+    start_retry: Option<RetryPolicy>,
+    seccomp: Option<SeccompPolicy>,
+    exit: VmExitRequest,
+    /// Whether [`Self::start`] succeeded, see [`Self::state`]
+    started: Mutex<bool>,
+    boot: Mutex<BootConfig>,
+    memory: Arc<RwLock<Vec<Arc<Mutex<GunyahGuestMemoryRegion>>>>>,
+    /// Base and size of the addresses memory can be added at once the VM runs
+    hotplug_memory: Option<(u64, u64)>,
+    /// Memory mapped into and punched out of the VM, see [`Self::metrics`]
+    memory_counters: Arc<MemoryCounters>,
+    debug: VmDebug,
+    executor: DeviceExecutor,
+    io_engine: IoEngine,
+    /// Ioeventfds of the fast write regions of the devices added at each base address
+    fast_writes: Mutex<BTreeMap<u64, Vec<Arc<Ioeventfd>>>>,
+    /// Device states from [`Self::restore`] waiting for [`Self::restore_devices`]
+    restored_devices: Mutex<Vec<crate::DeviceSnapshot>>,
 }
 
 impl From<gunyah::Vm> for GunyahVirtualMachine {
     fn from(vm: gunyah::Vm) -> Self {
+        let interrupts = Arc::new(RwLock::new(Vec::new()));
+        let executor = DeviceExecutor::default();
+        executor.spawn(Box::new(ResampleTask::new(&interrupts)));
         Self {
             vm,
-            vcpus: RwLock::new(Vec::new()),
+            vm_type: VmType::default(),
+            vcpus: Arc::new(RwLock::new(Vec::new())),
+            possible_vcpus: 0,
             bus: Bus::new(),
-            interrupts: RwLock::new(Vec::new()),
+            interrupts,
+            force_psci: true,
+            cpu_topology: None,
+            numa_nodes: Vec::new(),
+            vcpu_scheduling: VcpuScheduling::default(),
+            rm_console: false,
+            message_queues: Vec::new(),
+            mbi: None,
+            start_retry: None,
+            seccomp: None,
+            exit: VmExitRequest::default(),
+            started: Mutex::new(false),
+            boot: Mutex::new(BootConfig::default()),
+            memory: Arc::new(RwLock::new(Vec::new())),
+            hotplug_memory: None,
+            memory_counters: Arc::new(MemoryCounters::default()),
+            debug: VmDebug::default(),
+            executor,
+            io_engine: IoEngine::default(),
+            fast_writes: Mutex::new(BTreeMap::new()),
+            restored_devices: Mutex::new(Vec::new()),
         }
     }
 }
 
 impl GunyahVirtualMachine {
     pub fn new() -> Result<Self> {
-        Ok(gunyah::Gunyah::new()
-            .context("Failed to open gunyah")?
-            .create_vm()
-            .context("Failed to create vm")?
-            .into())
+        Self::with_type(VmType::Unauthenticated)
+    }
+
+    /// Creates a VM of type `vm_type`, which picks how the Resource Manager authenticates it.
+    pub fn with_type(vm_type: VmType) -> Result<Self> {
+        let gunyah = gunyah::Gunyah::new().context("Failed to open gunyah")?;
+        match gunyah.create_vm_with_type(vm_type) {
+            Ok(vm) => {
+                let mut vm = Self::from(vm);
+                vm.vm_type = vm_type;
+                Ok(vm)
+            }
+            Err(e) => {
+                let supported = match gunyah.supported_vm_types() {
+                    Ok(vm_types) => format!("{:?}", vm_types),
+                    Err(e) => format!("unknown: {}", e),
+                };
+                Err(anyhow!(e).context(format!(
+                    "Failed to create {:?} vm, the kernel supports {}",
+                    vm_type, supported
+                )))
+            }
+        }
+    }
+
+    /// Controls whether cpu nodes get an empty `cpu-idle-states` property (default: enabled).
+    ///
+    /// The Resource Manager only sets up PSCI for a VM when its cpu nodes have a
+    /// `cpu-idle-states` property. Resource Manager versions which always set up PSCI don't need
+    /// this, and some guests are confused by the empty property.
+    pub fn set_force_psci(&mut self, force_psci: bool) {
+        self.force_psci = force_psci;
+    }
+
+    /// Describes the vCPUs' clusters and caches in the `cpus` node generated by
+    /// [`Self::fdt_builder`] (default: none, each vCPU is a core of its own).
+    pub fn set_cpu_topology(&mut self, topology: Option<CpuTopology>) {
+        self.cpu_topology = topology;
+    }
+
+    /// Describes NUMA nodes to the guest (default: none). Memory the nodes don't cover is
+    /// described without a node.
+    pub fn set_numa_nodes(&mut self, nodes: Vec<NumaNode>) {
+        self.numa_nodes = nodes;
+    }
+
+    /// Picks how the hypervisor schedules the vCPUs (default: proxy scheduled by the host's vCPU
+    /// threads, with the hypervisor's default priority and timeslice).
+    pub fn set_vcpu_scheduling(&mut self, scheduling: VcpuScheduling) {
+        self.vcpu_scheduling = scheduling;
+    }
+
+    /// Records the MMIO accesses of all vCPUs in `trace`, or stops tracing if None.
+    pub fn set_mmio_trace(&self, trace: Option<MmioTrace>) {
+        self.bus.set_trace(trace);
+    }
+
+    /// Confines vCPU threads and the executor's thread with seccomp filters once they run, with
+    /// `policy` for syscalls they aren't allowed (default: not confined). Applies to vCPUs
+    /// created from now on.
+    pub fn set_seccomp(&mut self, policy: Option<SeccompPolicy>) {
+        self.seccomp = policy;
+    }
+
+    /// Retries [`Self::start`] on transient failures according to `policy` (default: no retry).
+    pub fn set_start_retry(&mut self, policy: Option<RetryPolicy>) {
+        self.start_retry = policy;
+    }
+
+    /// Lets devices stop the VM's vCPUs, see [`GunyahVcpu::run`].
+    pub fn exit_request(&self) -> VmExitRequest {
+        self.exit.clone()
+    }
+
+    /// Where the VM is in its lifecycle.
+    pub fn state(&self) -> VmState {
+        self.state_with(*self.started.lock().unwrap())
+    }
+
+    fn state_with(&self, started: bool) -> VmState {
+        if !started {
+            if self.boot.lock().unwrap().dtb.is_some() {
+                VmState::Ready
+            } else {
+                VmState::Configuring
+            }
+        } else if self.exit.reason().is_some() {
+            VmState::Stopped
+        } else if self.exit.is_paused() {
+            VmState::Paused
+        } else {
+            VmState::Running
+        }
+    }
+
+    /// Fails unless the VM hasn't started yet.
+    fn check_configurable(&self, operation: &'static str) -> Result<(), VmStateError> {
+        check_state(
+            operation,
+            self.state(),
+            &[VmState::Configuring, VmState::Ready],
+        )
+    }
+
+    /// Stops all vCPUs at their next exit and waits until they have stopped. Devices keep
+    /// running, so e.g. timers still fire and interrupts stay pending until [`Self::resume`].
+    pub fn pause(&self) -> Result<(), VmStateError> {
+        check_state("pause", self.state(), &[VmState::Running, VmState::Paused])?;
+        self.exit.pause();
+        Ok(())
+    }
+
+    pub fn resume(&self) -> Result<(), VmStateError> {
+        check_state("resume", self.state(), &[VmState::Running, VmState::Paused])?;
+        self.exit.resume();
+        Ok(())
     }
 
     pub fn get_bus(&self, access: AccessId) -> Bus {
         self.bus.clone().set_access_id(access)
     }
 
+    /// Creates vCPU `id` before the VM starts, afterwards vCPUs are added with
+    /// [`Self::vcpu_hotplug`].
     pub fn create_vcpu(&self, id: u8) -> Result<Arc<GunyahVcpu>> {
-        let vcpu = Arc::new(GunyahVcpu::new(self, id).context("Failed to create vcpu")?);
-        self.vcpus.write().unwrap().push(vcpu.clone());
-        Ok(vcpu)
+        self.check_configurable("create a vCPU")?;
+        self.vcpu_hotplug().create(id)
+    }
+
+    /// Lets vCPUs up to `count` be added once the VM runs (default: 0, only vCPUs created before
+    /// [`Self::fdt_builder`] exist). They are described to the guest as disabled
+    /// until added with [`VcpuHotplug::add`].
+    pub fn set_possible_vcpus(&mut self, count: u8) {
+        self.possible_vcpus = count;
+    }
+
+    /// Adds vCPUs to the VM, also once it runs.
+    pub fn vcpu_hotplug(&self) -> VcpuHotplug {
+        VcpuHotplug::new(
+            self.vm.clone(),
+            self.bus.clone(),
+            self.exit.clone(),
+            self.debug.clone(),
+            self.vcpus.clone(),
+            self.possible_vcpus,
+            self.seccomp,
+        )
+    }
+
+    /// Reserves `size` bytes of guest physical addresses at `base` for memory added once the VM
+    /// runs with [`MemoryHotplug::add`] (default: none). They are described to the guest as
+    /// disabled, hotpluggable memory.
+    pub fn set_hotplug_memory(&mut self, base: u64, size: u64) {
+        self.hotplug_memory = Some((base, size));
+    }
+
+    /// Adds memory to the VM, also once it runs.
+    pub fn memory_hotplug(&self) -> MemoryHotplug {
+        MemoryHotplug::new(
+            self.vm.clone(),
+            self.bus.clone(),
+            self.memory.clone(),
+            self.hotplug_memory,
+            self.memory_counters.clone(),
+        )
+    }
+
+    /// Counters of the VM for monitoring it while it runs, see [`MetricsServer`].
+    ///
+    /// [`MetricsServer`]: crate::MetricsServer
+    pub fn metrics(&self) -> VmMetrics {
+        VmMetrics::new(
+            self.vcpus.clone(),
+            self.interrupts.clone(),
+            self.bus.clone(),
+            self.memory_counters.clone(),
+        )
+    }
+
+    /// vCPUs created so far.
+    pub fn vcpus(&self) -> Vec<Arc<GunyahVcpu>> {
+        self.vcpus.read().unwrap().clone()
     }
 
+    /// Removes the device at `base` with length `len` from the running VM and stops it, see
+    /// [`BusDevice::stop`]. The vCPUs are paused meanwhile, so none is in the middle of
+    /// accessing the device. The guest still finds the device in its device tree and has to
+    /// stop using it first; its accesses fail afterwards.
+    pub fn remove_device(&self, base: u64, len: u64) -> Result<()> {
+        let was_paused = self.exit.is_paused();
+        self.exit.pause();
+        let result = self.bus.remove_and_stop(base, len);
+        self.fast_writes.lock().unwrap().remove(&base);
+        if !was_paused {
+            self.exit.resume();
+        }
+        result.context(format!("Failed to remove device at {:#x}", base))
+    }
+
+    /// Gives the VM a Resource Manager console whose output the host kernel passes on through
+    /// `source`, and relays it to `output` on [`Self::executor`].
+    pub fn add_rm_console(&mut self, source: File, output: Box<dyn Write + Send>) -> Result<()> {
+        self.executor
+            .spawn(Box::new(RmConsole::new(source, output)?));
+        self.rm_console = true;
+        Ok(())
+    }
+
+    /// Asks the Resource Manager for a message queue between the VM and the host, whose host end
+    /// is left to host kernel drivers, see [`crate::MessageQueueConfig`].
+    pub fn add_message_queue(&mut self, config: MessageQueueConfig) -> Result<()> {
+        config.validate()?;
+        if self
+            .message_queues
+            .iter()
+            .any(|queue| queue.label == config.label)
+        {
+            return Err(anyhow!("Message queue {} added twice", config.label));
+        }
+        self.message_queues.push(config);
+        Ok(())
+    }
+
+    /// Writes `data` at `address`, split into one write per memory region or device it spans,
+    /// e.g. for an image straddling two memory regions.
     pub fn write_slice(&self, address: u64, data: &[u8]) -> Result<()> {
-        self.bus.write(address, data)
+        let mut data = data;
+        for (addr, len) in self.bus.split(address, data.len())? {
+            let (piece, rest) = data.split_at(len);
+            self.bus.write(addr, piece)?;
+            data = rest;
+        }
+        Ok(())
     }
 
+    /// Reads `data` from `address`, split like with [`Self::write_slice`].
     pub fn read_slice(&self, address: u64, data: &mut [u8]) -> Result<()> {
-        self.bus.read(address, data)
+        let mut data = data;
+        for (addr, len) in self.bus.split(address, data.len())? {
+            let (piece, rest) = data.split_at_mut(len);
+            self.bus.read(addr, piece)?;
+            data = rest;
+        }
+        Ok(())
+    }
+
+    /// Writes `len` bytes of guest memory at `addr` to `writer`, e.g. to look into a crashed
+    /// guest. The range may span adjacent memory regions, but not memory lent to the running
+    /// guest.
+    pub fn dump_memory(&self, addr: u64, len: u64, writer: &mut impl Write) -> Result<()> {
+        memory::dump_memory(&self.memory.read().unwrap(), addr, len, writer)
+            .context(format!("Failed to dump {:#x} bytes at {:#x}", len, addr))
+    }
+
+    /// Loads the file at `path` into guest memory at `guest_addr` and returns its size. The file
+    /// is read straight into the VMM's mapping of the memory, which must hold it in one region,
+    /// instead of being buffered and copied through the bus like with [`Self::write_slice`].
+    pub fn load_file(&self, path: &Path, guest_addr: u64) -> Result<u64> {
+        let mut file = File::open(path).context(format!("Failed to open {}", path.display()))?;
+        let len = file.metadata()?.len();
+        let region = self
+            .memory
+            .read()
+            .unwrap()
+            .iter()
+            .find(|region| {
+                let region = region.lock().unwrap();
+                guest_addr >= region.guest_address()
+                    && guest_addr + len <= region.guest_address() + region.as_region().size() as u64
+            })
+            .cloned()
+            .ok_or(anyhow!(
+                "{} ({:#x} bytes at {:#x}) doesn't fit in one memory region",
+                path.display(),
+                len,
+                guest_addr
+            ))?;
+        let mut region = region.lock().unwrap();
+        let offset = guest_addr - region.guest_address();
+        region
+            .read_from(&mut file, offset, len.try_into()?)
+            .context(format!("Failed to load {}", path.display()))?;
+        Ok(len)
     }
 
+    /// Adds memory before the VM starts, afterwards memory is added with
+    /// [`Self::memory_hotplug`].
     pub fn add_memory_region(
         &mut self,
         region: GuestMemRegion,
@@ -69,6 +406,7 @@ impl GunyahVirtualMachine {
         unmap_on_drop: bool,
         regular_memory: bool,
     ) -> Result<Arc<Mutex<GunyahGuestMemoryRegion>>> {
+        self.check_configurable("add memory")?;
         let guest_region = Arc::new(Mutex::new(
             GunyahGuestMemoryRegion::new(
                 region.clone(),
@@ -86,6 +424,8 @@ impl GunyahVirtualMachine {
             guest_address,
             region.size().try_into()?,
         )?;
+        self.memory.write().unwrap().push(guest_region.clone());
+        self.memory_counters.map(region.size() as u64);
         Ok(guest_region)
     }
 
@@ -115,6 +455,38 @@ impl GunyahVirtualMachine {
         )
     }
 
+    /// Like [`Self::add_memory`], but backed by hugetlbfs pages of `page_size` instead of
+    /// transparent huge pages, so every page of the region is a huge page. `len` must be a
+    /// multiple of `page_size` and the host must have reserved enough pages.
+    pub fn add_hugetlb_memory(
+        &mut self,
+        start: u64,
+        len: NonZeroUsize,
+        share_type: ShareType,
+        guest_access: GuestMemoryAccess,
+        page_size: HugePageSize,
+    ) -> Result<Arc<Mutex<GunyahGuestMemoryRegion>>> {
+        let guest_mem = Gunyah::new()?
+            .create_hugetlb_guest_memory(len, page_size)
+            .context(format!(
+                "Failed to create guest memory from {:?} hugetlbfs pages",
+                page_size
+            ))?;
+        let region = GuestMemRegion::new(guest_mem, 0, len)?;
+        let regular_memory = match share_type {
+            ShareType::Share => false,
+            ShareType::Lend => true,
+        };
+        self.add_memory_region(
+            region,
+            start,
+            share_type,
+            guest_access,
+            false,
+            regular_memory,
+        )
+    }
+
     pub fn add_regular_memory(
         &mut self,
         start: u64,
@@ -134,38 +506,293 @@ impl GunyahVirtualMachine {
         offset: u64,
         len: usize,
     ) -> Result<()> {
+        let mut memory = self.memory.write().unwrap();
+        memory.retain(|r| !Arc::ptr_eq(r, &region));
         let mut region = region.lock().unwrap();
 
         let new_regions = region.punch_hole(offset, len)?;
 
+        let replacements = new_regions
+            .into_iter()
+            .map(|new_region| {
+                let guest_address = new_region.guest_address();
+                let size = new_region.as_region().size() as u64;
+                let new_region = Arc::new(Mutex::new(new_region));
+                memory.push(new_region.clone());
+                (new_region as Arc<Mutex<dyn BusDevice>>, guest_address, size)
+            })
+            .collect();
         self.bus
-            .remove(region.guest_address(), region.as_region().size() as u64)
-            .expect("Failed to remove original region from VMM's bus");
-
-        for new_region in new_regions {
-            let guest_address = new_region.guest_address();
-            let size = new_region.as_region().size() as u64;
-            self.bus
-                .insert(Arc::new(Mutex::new(new_region)), guest_address, size)
-                .expect("Failed to insert replacement region into VMM's bus");
+            .replace(
+                region.guest_address(),
+                region.as_region().size() as u64,
+                replacements,
+            )
+            .expect("Failed to replace original region in VMM's bus");
+        self.memory_counters.punch(len as u64);
+        Ok(())
+    }
+
+    /// Removes `len` bytes at `offset` of `region` from a running VM and frees the backing memory.
+    ///
+    /// The guest must have relinquished the pages beforehand. Guest accesses to the range exit to
+    /// the VMM afterwards, and the remainder of `region` stays accessible throughout.
+    pub fn offline_memory(
+        &self,
+        region: Arc<Mutex<GunyahGuestMemoryRegion>>,
+        offset: u64,
+        len: usize,
+    ) -> Result<()> {
+        let (guest_mem, mem_offset) = {
+            let region = region.lock().unwrap();
+            (
+                region.as_region().as_guest_mem().clone(),
+                region.as_region().offset() + offset,
+            )
+        };
+
+        self.punch_hole(region, offset, len)
+            .context("Failed to remove memory from the VM")?;
+        guest_mem
+            .punch_hole(mem_offset.try_into()?, len.try_into()?)
+            .context("Failed to free guest memory")
+    }
+
+    /// Pauses the VM before the guest first accesses the page holding the guest physical address
+    /// `addr`, and tells where through [`Self::debug_stop`]. The page is removed from the VM until
+    /// then, so breakpoints have page granularity and fire once. Setting a second breakpoint in
+    /// the same page does nothing.
+    pub fn set_breakpoint(&self, addr: u64) -> Result<()> {
+        let page_size = u64::try_from(page_size::get())?;
+        let page = addr & !(page_size - 1);
+        if self.debug.contains(page) {
+            return Ok(());
+        }
+        let region = self
+            .memory
+            .read()
+            .unwrap()
+            .iter()
+            .find(|region| {
+                let region = region.lock().unwrap();
+                let start = region.guest_address();
+                (start..start + region.as_region().size() as u64).contains(&page)
+            })
+            .cloned()
+            .ok_or(anyhow!("No guest memory at {:#x}", addr))?;
+        let (offset, page_region, share_type, guest_access, regular_memory) = {
+            let region = region.lock().unwrap();
+            let offset = page - region.guest_address();
+            (
+                offset,
+                GuestMemRegion::new(
+                    region.as_region().as_guest_mem().clone(),
+                    region.as_region().offset() + offset,
+                    usize::try_from(page_size)?.try_into()?,
+                )?,
+                region.share_type(),
+                region.guest_access(),
+                region.is_regular_memory(),
+            )
+        };
+        self.punch_hole(region, offset, page_size.try_into()?)
+            .context(format!(
+                "Failed to remove the page at {:#x} from the VM",
+                page
+            ))?;
+
+        let mut vm = self.vm.clone();
+        let bus = self.bus.clone();
+        let memory = self.memory.clone();
+        let memory_counters = self.memory_counters.clone();
+        let remap = move || {
+            let region = Arc::new(Mutex::new(
+                GunyahGuestMemoryRegion::new(
+                    page_region,
+                    page,
+                    &mut vm,
+                    share_type,
+                    guest_access,
+                    false,
+                    regular_memory,
+                )
+                .context(format!("Failed to map the page at {:#x} back", page))?,
+            ));
+            bus.insert(region.clone(), page, page_size)?;
+            memory.write().unwrap().push(region);
+            memory_counters.map(page_size);
+            Ok(())
+        };
+        self.debug.insert(page, addr, Box::new(remap));
+        Ok(())
+    }
+
+    /// Removes a breakpoint set with [`Self::set_breakpoint`] which hasn't fired yet.
+    pub fn remove_breakpoint(&self, addr: u64) -> Result<()> {
+        let page = addr & !(u64::try_from(page_size::get())? - 1);
+        if !self.debug.remove(page)? {
+            return Err(anyhow!("No breakpoint at {:#x}", addr));
         }
         Ok(())
     }
 
+    /// Returns where the VM last stopped for a breakpoint, once.
+    pub fn debug_stop(&self) -> Option<DebugStop> {
+        self.debug.take_stop()
+    }
+
+    /// Copies `dtb` to `start` and makes the `len` bytes there the VM's DTB, which makes the VM
+    /// [`VmState::Ready`].
     pub fn set_dtb_config(&self, start: u64, len: u64, dtb: &[u8]) -> Result<()> {
+        self.check_configurable("set the DTB")?;
         self.write_slice(start, dtb)
             .context("Failed to copy DTB to VM")?;
         self.vm
             .set_dtb_config(start, len)
-            .context("Failed to set DTB configuration for VM")
+            .context("Failed to set DTB configuration for VM")?;
+        let mut boot = self.boot.lock().unwrap();
+        boot.dtb = Some((start, len));
+        boot.dtb_blob = Some(dtb.to_vec());
+        Ok(())
+    }
+
+    /// The DTB set with [`Self::set_dtb_config`].
+    pub fn dtb(&self) -> Option<Vec<u8>> {
+        self.boot.lock().unwrap().dtb_blob.clone()
+    }
+
+    /// Makes the `size` bytes at `addr`, which must have been loaded into guest memory, the
+    /// firmware of a protected VM, which the Resource Manager authenticates and boots first.
+    pub fn set_firmware(&self, addr: u64, size: u64) -> Result<()> {
+        self.check_configurable("set the firmware")?;
+        self.vm
+            .set_firmware_config(addr, size)
+            .context("Failed to set firmware configuration for VM")?;
+        self.boot.lock().unwrap().firmware = Some((addr, size));
+        Ok(())
+    }
+
+    /// The firmware set with [`Self::set_firmware`], as address and size.
+    pub fn firmware(&self) -> Option<(u64, u64)> {
+        self.boot.lock().unwrap().firmware
+    }
+
+    /// Starts the VM with `reg` set to `value`, e.g. to pass arguments to a baremetal payload in
+    /// x0 to x3.
+    pub fn set_boot_reg(&self, reg: BootReg, value: u64) -> Result<(), VmStateError> {
+        self.check_configurable("set a boot register")?;
+        self.vm.set_boot_context(reg, value)?;
+        self.boot.lock().unwrap().regs.insert(reg, value);
+        Ok(())
+    }
+
+    pub fn set_boot_pc(&self, value: u64) -> Result<(), VmStateError> {
+        self.set_boot_reg(BootReg::Pc, value)
     }
 
-    pub fn set_boot_pc(&self, value: u64) -> Result<(), gunyah::Error> {
-        self.vm.set_boot_pc(value)
+    pub fn set_boot_sp(&self, value: u64) -> Result<(), VmStateError> {
+        self.set_boot_reg(BootReg::SpEl1, value)
     }
 
-    pub fn set_boot_sp(&self, value: u64) -> Result<(), gunyah::Error> {
-        self.vm.set_boot_sp(value)
+    /// Writes the VM's memory, boot configuration and device state to `path`.
+    ///
+    /// Gunyah doesn't expose vCPU registers, so this is a cold-boot snapshot: a VM restored from
+    /// it starts again from its boot context. Lent memory can only be read before the VM starts,
+    /// so VMs with lent memory can't be snapshotted once they run, and shared memory should only
+    /// be snapshotted while the vCPUs are stopped.
+    pub fn snapshot(&self, path: &Path) -> Result<()> {
+        let regions = self.memory.read().unwrap().clone();
+        if let Some(region) = regions.iter().find(|r| r.lock().unwrap().is_guest_owned()) {
+            return Err(anyhow!(
+                "Memory at {:#x} is lent to the running guest and can't be snapshotted",
+                region.lock().unwrap().guest_address()
+            ));
+        }
+        let boot = self.boot.lock().unwrap();
+        let mut snapshot = Snapshot {
+            vm_type: self.vm_type,
+            force_psci: self.force_psci,
+            dtb: boot.dtb,
+            boot_regs: boot
+                .regs
+                .iter()
+                .map(|(&reg, &value)| (reg, value))
+                .collect(),
+            memory: Vec::new(),
+            devices: self.bus.snapshot_devices()?,
+        };
+        drop(boot);
+        for region in &regions {
+            let region = region.lock().unwrap();
+            snapshot.memory.push(MemorySnapshot {
+                guest_address: region.guest_address(),
+                share_type: region.share_type(),
+                guest_access: region.guest_access(),
+                regular_memory: region.is_regular_memory(),
+                size: region.as_region().size().try_into()?,
+            });
+        }
+        snapshot.save(path, |index, writer| {
+            let mut region = regions[index].lock().unwrap();
+            let size = region.as_region().size();
+            region.write_to(writer, 0, size).context(format!(
+                "Failed to read memory at {:#x}",
+                region.guest_address()
+            ))
+        })
+    }
+
+    /// Creates a VM from a snapshot taken with [`Self::snapshot`], with the same type, memory and
+    /// boot configuration. Memory regions come back as anonymous guest memory, even if they were
+    /// backed by a file. Devices have to be added again at the same addresses before calling
+    /// [`Self::restore_devices`].
+    pub fn restore(path: &Path) -> Result<Self> {
+        let mut regions = Vec::new();
+        let snapshot = Snapshot::load(path, |region, reader| {
+            let len = NonZeroUsize::new(region.size.try_into()?)
+                .ok_or(anyhow!("Empty memory region in snapshot"))?;
+            let guest_mem = Gunyah::new()?
+                .create_guest_memory(len, false)
+                .context("Failed to create guest memory")?;
+            let mem = GuestMemRegion::new(guest_mem, 0, len)?;
+            reader
+                .read_exact(&mut mem.map_mut()?)
+                .context("Snapshot is truncated")?;
+            regions.push(mem);
+            Ok(())
+        })?;
+        let mut vm = Self::with_type(snapshot.vm_type)?;
+        vm.set_force_psci(snapshot.force_psci);
+        for (region, mem) in snapshot.memory.iter().zip(regions) {
+            vm.add_memory_region(
+                mem,
+                region.guest_address,
+                region.share_type,
+                region.guest_access,
+                false,
+                region.regular_memory,
+            )?;
+        }
+        if let Some((start, len)) = snapshot.dtb {
+            vm.vm
+                .set_dtb_config(start, len)
+                .context("Failed to set DTB configuration for VM")?;
+            vm.boot.lock().unwrap().dtb = Some((start, len));
+        }
+        for (reg, value) in snapshot.boot_regs {
+            vm.set_boot_reg(reg, value)?;
+        }
+        *vm.restored_devices.lock().unwrap() = snapshot.devices;
+        Ok(vm)
+    }
+
+    /// Restores the state of the devices in the snapshot the VM was restored from. Every device
+    /// which had state must have been added again.
+    pub fn restore_devices(&self) -> Result<()> {
+        for device in self.restored_devices.lock().unwrap().drain(..) {
+            self.bus.restore_device(&device)?;
+        }
+        Ok(())
     }
 
     pub fn add_level_interrupt(&self, line: u32) -> Result<Arc<GunyahInterrupt>> {
@@ -186,20 +813,124 @@ impl GunyahVirtualMachine {
         Ok(interrupt)
     }
 
+    /// Memory regions added so far.
+    pub fn memory_regions(&self) -> Vec<Arc<Mutex<GunyahGuestMemoryRegion>>> {
+        self.memory.read().unwrap().clone()
+    }
+
+    /// Interrupts registered by devices so far.
+    pub fn interrupts(&self) -> Vec<Arc<GunyahInterrupt>> {
+        self.interrupts.read().unwrap().clone()
+    }
+
     pub fn add_ioevent(&self, addr: u64, len: u32, datamatch: Option<u64>) -> Result<Ioeventfd> {
         Ioeventfd::new(self.vm.clone(), addr, len, datamatch)
     }
 
+    /// Runs the background work of devices, like delivering fast writes, once the VM started.
+    pub fn executor(&self) -> DeviceExecutor {
+        self.executor.clone()
+    }
+
+    /// Makes devices added from now on read and write host files with `engine`.
+    pub fn set_io_engine(&mut self, engine: IoEngine) {
+        self.io_engine = engine;
+    }
+
+    /// How devices read and write host files, see [`Self::set_io_engine`].
+    pub fn io_engine(&self) -> IoEngine {
+        self.io_engine.clone()
+    }
+
+    /// Adds `device` at `base`. Guest writes to its [`BusDevice::fast_write_regions`] don't
+    /// exit to the VMM, they reach the device through ioeventfds on [`Self::executor`].
     pub fn add_device(
         &mut self,
         device: Arc<Mutex<dyn BusDevice>>,
         base: u64,
         len: u64,
     ) -> Result<()> {
-        self.bus.insert(device, base, len)?;
+        self.bus.insert(device.clone(), base, len)?;
+        let eventfds = self.add_fast_writes(&device, base).inspect_err(|_| {
+            // Drops the ioeventfds created so far, which ends their tasks
+            let _ = self.bus.remove(base, len);
+        })?;
+        if !eventfds.is_empty() {
+            self.fast_writes.lock().unwrap().insert(base, eventfds);
+        }
         Ok(())
     }
 
+    fn add_fast_writes(
+        &self,
+        device: &Arc<Mutex<dyn BusDevice>>,
+        base: u64,
+    ) -> Result<Vec<Arc<Ioeventfd>>> {
+        let regions = device.lock().unwrap().fast_write_regions();
+        let mut eventfds = Vec::new();
+        for region in regions {
+            let eventfd = Arc::new(
+                self.add_ioevent(base + region.offset, region.len, region.datamatch)
+                    .context(format!(
+                        "Failed to add fast write region at {:#x}",
+                        base + region.offset
+                    ))?,
+            );
+            self.executor.spawn(Box::new(FastWriteTask::new(
+                device.clone(),
+                region,
+                &eventfd,
+            )?));
+            eventfds.push(eventfd);
+        }
+        Ok(eventfds)
+    }
+
+    /// Adds a [`PrefixedLog`] at `base` which prints each line written by the guest to stdout,
+    /// prefixed with `prefix`.
+    pub fn add_debug_log(
+        &mut self,
+        base: u64,
+        prefix: &str,
+    ) -> Result<Arc<Mutex<PrefixedLog<Stdout>>>> {
+        let log = Arc::new(Mutex::new(PrefixedLog::new(base, prefix, io::stdout())));
+        self.add_device(log.clone(), base, DEBUG_LOG_MMIO_SIZE)?;
+        Ok(log)
+    }
+
+    /// Adds a [`DebugExit`] at `base`, which stops the VM with [`crate::VmExit::Exit`] and the
+    /// value the guest writes to it.
+    pub fn add_debug_exit(&mut self, base: u64) -> Result<Arc<Mutex<DebugExit>>> {
+        let dev = Arc::new(Mutex::new(DebugExit::new(base, self.exit_request())));
+        self.add_device(dev.clone(), base, DEBUG_EXIT_MMIO_SIZE)?;
+        Ok(dev)
+    }
+
+    /// Reserves `count` SPIs from `first_spi` on for MSIs, which are raised by writing their
+    /// INTIDs to the [`MsiFrame`] added at `base`. The GIC node generated by
+    /// [`Self::fdt_builder`] describes them.
+    pub fn add_msi_frame(
+        &mut self,
+        base: u64,
+        first_spi: u32,
+        count: u32,
+    ) -> Result<Arc<Mutex<MsiFrame>>> {
+        if self.mbi.is_some() {
+            return Err(anyhow!("The VM already has an MSI frame"));
+        }
+        let config = MbiConfig {
+            alias: base,
+            first_spi,
+            count,
+        };
+        let frame = Arc::new(Mutex::new(
+            MsiFrame::new(self, &config).context("Failed to add MSI interrupts")?,
+        ));
+        self.add_device(frame.clone(), base, MSI_FRAME_MMIO_SIZE)?;
+        self.mbi = Some(config);
+        Ok(frame)
+    }
+
     pub fn add_device_sync(
         &mut self,
         device: Arc<dyn BusDeviceSync>,
@@ -210,8 +941,24 @@ impl GunyahVirtualMachine {
         Ok(())
     }
 
-    pub fn start(&self) -> Result<(), gunyah::Error> {
-        self.vm.start()
+    /// Starts the VM and [`Self::executor`]. Only a [`VmState::Ready`] VM can start, once.
+    pub fn start(&self) -> Result<(), VmStateError> {
+        let mut started = self.started.lock().unwrap();
+        check_state("start", self.state_with(*started), &[VmState::Ready])?;
+        // Gunyah won't lend pages the VMM still has mapped
+        for region in self.memory.read().unwrap().iter() {
+            region.lock().unwrap().unmap_from_vmm();
+        }
+        match &self.start_retry {
+            Some(policy) => policy.run(|| self.vm.start()),
+            None => self.vm.start(),
+        }?;
+        *started = true;
+        match self.seccomp {
+            Some(policy) => self.executor.run_confined(self.exit.clone(), policy),
+            None => self.executor.run(self.exit.clone()),
+        };
+        Ok(())
     }
 
     pub fn create_fdt_vm_config(
@@ -240,9 +987,7 @@ impl GunyahVirtualMachine {
         fdt.property_u32("config", intc_phandle)?;
         fdt.end_node(interrupts_node)?;
 
-        let vcpus_node = fdt.begin_node("vcpus")?;
-        fdt.property_string("affinity", "proxy")?;
-        fdt.end_node(vcpus_node)?;
+        self.vcpu_scheduling.generate(fdt, self.described_vcpus())?;
 
         let vdev_node = fdt.begin_node("vdevices")?;
         fdt.property_string("generate", "/hypervisor")?;
@@ -250,91 +995,123 @@ impl GunyahVirtualMachine {
         for interrupt in self.interrupts.read().unwrap().iter() {
             interrupt.generate_vdevice(fdt)?;
         }
+        if self.rm_console {
+            rm_console::generate_vdevice(fdt)?;
+        }
+        for queue in &self.message_queues {
+            queue.generate_vdevice(fdt)?;
+        }
         fdt.end_node(vdev_node)?;
         fdt.end_node(vm_config)?;
         Ok(())
     }
 
-    pub fn create_fdt_basic_config(
-        &self,
-        fdt: &mut FdtWriter,
-        gic_config: &[u64; 4],
-        timer_interrupts: &[u32; 4],
-    ) -> Result<()> {
-        const PHANDLE_GIC: u32 = 1;
-
-        fdt.property_u32("#address-cells", 2)?;
-        fdt.property_u32("#size-cells", 2)?;
-        fdt.property_u32("interrupt-parent", PHANDLE_GIC)?;
+    /// Starts the device tree of the VM, with its GIC at `gic`.
+    pub fn fdt_builder(&self, gic: GicConfig) -> FdtBuilder<'_> {
+        FdtBuilder::new(self, gic)
+    }
 
-        let memory_node = fdt.begin_node("memory")?;
-        fdt.property_string("device_type", "memory")?;
+    /// Writes the memory node and returns the base address of the VM's memory.
+    pub(crate) fn generate_fdt_memory(&self, fdt: &mut FdtWriter) -> Result<u64> {
         let mem_reg = self.bus.list_memory_regions();
-        fdt.property_array_u64("reg", &mem_reg)?;
-        fdt.end_node(memory_node)?;
+        let in_node = |base: u64, size: u64| {
+            self.numa_nodes.iter().any(|node| {
+                node.memory
+                    .iter()
+                    .any(|&(start, len)| base < start + len && start < base + size)
+            })
+        };
+        let plain_reg: Vec<u64> = mem_reg
+            .chunks_exact(2)
+            .filter(|range| !in_node(range[0], range[1]))
+            .flatten()
+            .copied()
+            .collect();
+        if !plain_reg.is_empty() {
+            let memory_node = fdt.begin_node("memory")?;
+            fdt.property_string("device_type", "memory")?;
+            fdt.property_array_u64("reg", &plain_reg)?;
+            fdt.end_node(memory_node)?;
+        }
+        if !self.numa_nodes.is_empty() {
+            numa::generate_memory(fdt, &self.numa_nodes)?;
+            numa::generate_distance_map(fdt, &self.numa_nodes)?;
+        }
+        if let Some((base, size)) = self.hotplug_memory {
+            // Added later, see MemoryHotplug
+            let hotplug_node = fdt.begin_node(&format!("memory@{:x}", base))?;
+            fdt.property_string("device_type", "memory")?;
+            fdt.property_array_u64("reg", &[base, size])?;
+            fdt.property_null("hotpluggable")?;
+            fdt.property_string("status", "disabled")?;
+            fdt.end_node(hotplug_node)?;
+        }
+        mem_reg.first().copied().context("vm has no memory")
+    }
+
+    /// Number of vCPU IDs described to the guest: those of created and possible vCPUs.
+    fn described_vcpus(&self) -> u32 {
+        let created = self
+            .vcpus
+            .read()
+            .expect("Unable to read lock vcpus")
+            .iter()
+            .map(|vcpu| vcpu.id() + 1)
+            .max()
+            .unwrap_or(0);
+        created.max(u32::from(self.possible_vcpus))
+    }
 
+    pub(crate) fn generate_fdt_cpus(&self, fdt: &mut FdtWriter) -> Result<()> {
         let cpus_node = fdt.begin_node("cpus")?;
         fdt.property_u32("#address-cells", 1)?;
         fdt.property_u32("#size-cells", 0)?;
-        for vcpu in self.vcpus.read().expect("Unable to read lock vcpus").iter() {
-            let cpu_node = fdt.begin_node(&format!("cpu@{:x}", vcpu.id()))?;
+        let created: BTreeSet<u32> = self
+            .vcpus
+            .read()
+            .expect("Unable to read lock vcpus")
+            .iter()
+            .map(|vcpu| vcpu.id())
+            .collect();
+        let mut ids = created.clone();
+        ids.extend(0..u32::from(self.possible_vcpus));
+        for &id in &ids {
+            let cpu_node = fdt.begin_node(&format!("cpu@{:x}", id))?;
             fdt.property_string("device_type", "cpu")?;
             fdt.property_string("compatible", "arm,arm-v8")?;
             fdt.property_string("enable-method", "psci")?;
-            fdt.property_u32("reg", vcpu.id())?;
-            // HACK: Force RM to set up PSCI
-            fdt.property_null("cpu-idle-states")?;
+            fdt.property_u32("reg", id)?;
+            if !created.contains(&id) {
+                // Possible but not added yet, see VcpuHotplug
+                fdt.property_string("status", "disabled")?;
+            }
+            if self.force_psci {
+                // HACK: Force RM to set up PSCI
+                fdt.property_null("cpu-idle-states")?;
+            }
+            if let Some(topology) = &self.cpu_topology {
+                topology.generate_cpu(fdt, id)?;
+            }
+            if let Some(node) = numa::vcpu_node(&self.numa_nodes, id) {
+                fdt.property_u32("numa-node-id", node)?;
+            }
             fdt.end_node(cpu_node)?;
         }
+        if let Some(topology) = &self.cpu_topology {
+            let ids: Vec<u32> = ids.into_iter().collect();
+            topology.generate_map(fdt, &ids)?;
+        }
         fdt.end_node(cpus_node)?;
+        Ok(())
+    }
 
-        let psci_node = fdt.begin_node("psci")?;
-        fdt.property_string("compatible", "arm,psci-0.2")?;
-        fdt.property_string("method", "hvc")?;
-        fdt.end_node(psci_node)?;
-
-        let intc_node = fdt.begin_node(&format!("interrupt-controller@{:x}", gic_config[0]))?;
-        fdt.property_string("compatible", "arm,gic-v3")?;
-        fdt.property_u32("#interrupt-cells", 3)?;
-        fdt.property_u32("#address-cells", 2)?;
-        fdt.property_u32("#size-cells", 2)?;
-        fdt.property_null("interrupt-controller")?;
-        fdt.property_array_u64("reg", gic_config)?;
-        fdt.property_u32("phandle", PHANDLE_GIC)?;
-        fdt.end_node(intc_node)?;
-
-        let timer_node = fdt.begin_node("timer")?;
-        fdt.property_string("compatible", "arm,armv8-timer")?;
-        fdt.property_null("always-on")?;
-        let interrupts: [u32; 12] = [
-            1,
-            timer_interrupts[0],
-            0x108,
-            1,
-            timer_interrupts[1],
-            0x108,
-            1,
-            timer_interrupts[2],
-            0x108,
-            1,
-            timer_interrupts[3],
-            0x108,
-        ];
-        fdt.property_array_u32("interrupts", &interrupts)?;
-        fdt.property_u32("clock-frequency", 19200000)?;
-        fdt.end_node(timer_node)?;
-
-        self.bus.generate_device_config(fdt)?;
-
-        self.create_fdt_vm_config(
-            fdt,
-            "linux",
-            *mem_reg.first().expect("vm has no memory"),
-            None,
-            PHANDLE_GIC,
-        )?;
+    /// Writes the nodes of the devices on the bus.
+    pub(crate) fn generate_fdt_devices(&self, fdt: &mut FdtWriter) -> Result<()> {
+        self.bus.generate_device_config(fdt)
+    }
 
-        Ok(())
+    pub(crate) fn mbi_config(&self) -> Option<&MbiConfig> {
+        self.mbi.as_ref()
     }
 
     pub(crate) fn vm(&self) -> &gunyah::Vm {
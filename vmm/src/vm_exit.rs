@@ -0,0 +1,385 @@
+// Copyright (c) 2024, Qualcomm Innovation Center, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause-Clear
+
+use std::{
+    sync::{
+        atomic::{AtomicI32, Ordering},
+        Arc, Condvar, Mutex, Once,
+    },
+    time::{Duration, Instant},
+};
+
+use vmm_sys_util::{
+    errno,
+    signal::{register_signal_handler, SIGRTMIN},
+};
+
+use crate::{DeviceExecutor, DeviceTask, TaskPoll};
+
+/// How often [`SignalTask`]s check for signals.
+const SIGNAL_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Why a VM stopped running.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VmExit {
+    /// The guest powered off.
+    Poweroff,
+    /// The guest or a device asked for the VM to be restarted.
+    Reset,
+    /// The guest crashed.
+    Crash,
+    /// A device asked the VMM to exit with this code.
+    Exit(i32),
+}
+
+/// Signal which interrupts a vCPU thread's run ioctl.
+pub(crate) fn kick_signal() -> i32 {
+    SIGRTMIN()
+}
+
+extern "C" fn handle_kick(_: libc::c_int, _: *mut libc::siginfo_t, _: *mut libc::c_void) {}
+
+/// Last of SIGUSR1/SIGUSR2 received and not handled yet, 0 if none
+static PAUSE_SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+extern "C" fn handle_pause_signal(
+    signal: libc::c_int,
+    _: *mut libc::siginfo_t,
+    _: *mut libc::c_void,
+) {
+    PAUSE_SIGNAL.store(signal, Ordering::SeqCst);
+}
+
+/// Last of SIGINT/SIGTERM received and not handled yet, 0 if none
+static EXIT_SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+extern "C" fn handle_exit_signal(
+    signal: libc::c_int,
+    _: *mut libc::siginfo_t,
+    _: *mut libc::c_void,
+) {
+    EXIT_SIGNAL.store(signal, Ordering::SeqCst);
+}
+
+/// A thread running a vCPU.
+#[derive(Debug)]
+struct Running {
+    thread: libc::pthread_t,
+    /// Makes the vCPU's next run fail with EINTR, so that a kick isn't lost if it arrives just
+    /// before the thread enters the guest
+    kicker: Option<Arc<gunyah::VcpuKicker>>,
+}
+
+#[derive(Debug, Default)]
+struct ExitState {
+    reason: Option<VmExit>,
+    /// Threads currently running a vCPU
+    running: Vec<Running>,
+    paused: bool,
+    /// Threads of `running` which are waiting for the VM to be resumed
+    parked: Vec<libc::pthread_t>,
+}
+
+/// Stops all vCPUs of a VM. Shared by the VM, its vCPUs and any device which can end the VM,
+/// e.g. a watchdog.
+///
+/// vCPUs can also be paused and resumed without ending the VM.
+#[derive(Clone, Debug, Default)]
+pub struct VmExitRequest {
+    state: Arc<Mutex<ExitState>>,
+    /// Signalled when `paused`, `parked` or `reason` change
+    changed: Arc<Condvar>,
+}
+
+impl VmExitRequest {
+    /// Asks all vCPUs to stop and return `reason`. Only the first request counts.
+    pub fn request(&self, reason: VmExit) {
+        let mut state = self.state.lock().unwrap();
+        if state.reason.is_some() {
+            return;
+        }
+        state.reason = Some(reason);
+        drop(state);
+        self.changed.notify_all();
+        self.kick(|_, _| true);
+    }
+
+    pub fn reason(&self) -> Option<VmExit> {
+        self.state.lock().unwrap().reason
+    }
+
+    /// Stops all vCPUs at their next exit and waits until they have stopped. vCPUs which aren't
+    /// running yet stop before entering the guest for the first time.
+    pub fn pause(&self) {
+        if !self.request_pause() {
+            return;
+        }
+        let state = self.state.lock().unwrap();
+        let _state = self
+            .changed
+            .wait_while(state, |state| {
+                state.paused && state.reason.is_none() && state.parked.len() != state.running.len()
+            })
+            .unwrap();
+    }
+
+    /// Stops all vCPUs at their next exit without waiting, so that a vCPU can pause its own VM.
+    /// Returns false if the VM was already paused.
+    pub(crate) fn request_pause(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.paused {
+            return false;
+        }
+        state.paused = true;
+        drop(state);
+        self.kick(|state, running| {
+            state.paused && state.reason.is_none() && !state.parked.contains(&running.thread)
+        });
+        true
+    }
+
+    /// Lets paused vCPUs run again.
+    pub fn resume(&self) {
+        self.state.lock().unwrap().paused = false;
+        self.changed.notify_all();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.state.lock().unwrap().paused
+    }
+
+    /// Pauses the VM on SIGUSR1 and resumes it on SIGUSR2 until `executor` stops.
+    pub fn pause_on_signals(&self, executor: &DeviceExecutor) -> errno::Result<()> {
+        for signal in [libc::SIGUSR1, libc::SIGUSR2] {
+            register_signal_handler(signal, handle_pause_signal)?;
+        }
+        let request = self.clone();
+        executor.spawn(Box::new(SignalTask {
+            label: "SIGUSR1/SIGUSR2",
+            signal: &PAUSE_SIGNAL,
+            handle: Box::new(move |signal| match signal {
+                libc::SIGUSR1 => request.pause(),
+                _ => request.resume(),
+            }),
+        }));
+        Ok(())
+    }
+
+    /// Requests [`VmExit::Exit`] with the usual shell exit code of 128 plus the signal number on
+    /// SIGINT and SIGTERM, so the VMM shuts down cleanly instead of being killed.
+    pub fn exit_on_signals(&self, executor: &DeviceExecutor) -> errno::Result<()> {
+        for signal in [libc::SIGINT, libc::SIGTERM] {
+            register_signal_handler(signal, handle_exit_signal)?;
+        }
+        let request = self.clone();
+        executor.spawn(Box::new(SignalTask {
+            label: "SIGINT/SIGTERM",
+            signal: &EXIT_SIGNAL,
+            handle: Box::new(move |signal| request.request(VmExit::Exit(128 + signal))),
+        }));
+        Ok(())
+    }
+
+    /// Kicks the running threads for which `pending` returns true, once. The kick doesn't spawn a
+    /// thread to repeat it, so that confined vCPU threads can request exits and pauses too.
+    fn kick<F>(&self, pending: F)
+    where
+        F: Fn(&ExitState, &Running) -> bool,
+    {
+        let state = self.state.lock().unwrap();
+        for running in state
+            .running
+            .iter()
+            .filter(|running| pending(&state, running))
+        {
+            if let Some(kicker) = &running.kicker {
+                kicker.set();
+            }
+            // SAFETY: Safe because the thread is still running a vCPU, it removes itself from
+            // `running` under the lock before exiting.
+            unsafe { libc::pthread_kill(running.thread, kick_signal()) };
+        }
+    }
+
+    /// Registers the calling thread as running a vCPU until the returned guard is dropped. Without
+    /// the vCPU's `kicker`, a kick which arrives just before the thread blocks is lost.
+    pub(crate) fn enter(&self, kicker: Option<Arc<gunyah::VcpuKicker>>) -> RunningGuard {
+        static REGISTER: Once = Once::new();
+        REGISTER.call_once(|| {
+            register_signal_handler(kick_signal(), handle_kick)
+                .expect("Failed to register the vCPU kick handler")
+        });
+        // SAFETY: Safe because pthread_self has no preconditions.
+        let thread = unsafe { libc::pthread_self() };
+        self.state
+            .lock()
+            .unwrap()
+            .running
+            .push(Running { thread, kicker });
+        RunningGuard {
+            request: self.clone(),
+            thread,
+        }
+    }
+}
+
+/// Handles the signals a signal handler recorded in `signal`.
+struct SignalTask {
+    label: &'static str,
+    signal: &'static AtomicI32,
+    handle: Box<dyn Fn(i32) + Send>,
+}
+
+impl DeviceTask for SignalTask {
+    fn debug_label(&self) -> String {
+        self.label.to_string()
+    }
+
+    fn poll(&mut self, now: Instant) -> anyhow::Result<TaskPoll> {
+        match self.signal.swap(0, Ordering::SeqCst) {
+            0 => {}
+            signal => (self.handle)(signal),
+        }
+        Ok(TaskPoll::Pending(Some(now + SIGNAL_POLL_INTERVAL)))
+    }
+}
+
+pub(crate) struct RunningGuard {
+    request: VmExitRequest,
+    thread: libc::pthread_t,
+}
+
+impl RunningGuard {
+    /// Blocks while the VM is paused, unless an exit is requested.
+    pub(crate) fn wait_while_paused(&self) {
+        let mut state = self.request.state.lock().unwrap();
+        if !state.paused || state.reason.is_some() {
+            return;
+        }
+        state.parked.push(self.thread);
+        self.request.changed.notify_all();
+        let mut state = self
+            .request
+            .changed
+            .wait_while(state, |state| state.paused && state.reason.is_none())
+            .unwrap();
+        state.parked.retain(|thread| *thread != self.thread);
+    }
+}
+
+impl Drop for RunningGuard {
+    fn drop(&mut self) {
+        self.request
+            .state
+            .lock()
+            .unwrap()
+            .running
+            .retain(|running| running.thread != self.thread);
+        self.request.changed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Arc,
+        },
+        thread,
+        time::Duration,
+    };
+
+    use super::{VmExit, VmExitRequest};
+    use crate::DeviceExecutor;
+
+    #[test]
+    fn first_request_wins() {
+        let request = VmExitRequest::default();
+        assert_eq!(request.reason(), None);
+        request.request(VmExit::Exit(3));
+        request.clone().request(VmExit::Reset);
+        assert_eq!(request.reason(), Some(VmExit::Exit(3)));
+    }
+
+    #[test]
+    fn kicks_blocked_threads() {
+        let request = VmExitRequest::default();
+        let interrupted = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let request = request.clone();
+            let interrupted = interrupted.clone();
+            thread::spawn(move || {
+                let _guard = request.enter(None);
+                // Stands in for the run ioctl, which returns EINTR when kicked
+                while request.reason().is_none() {
+                    // SAFETY: Safe because sleep has no preconditions.
+                    if unsafe { libc::sleep(10) } != 0 {
+                        interrupted.store(true, Ordering::SeqCst);
+                    }
+                }
+            })
+        };
+        thread::sleep(Duration::from_millis(50));
+        request.request(VmExit::Poweroff);
+        thread.join().unwrap();
+        assert!(interrupted.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn pause_and_resume() {
+        let request = VmExitRequest::default();
+        let runs = Arc::new(AtomicUsize::new(0));
+        let thread = {
+            let request = request.clone();
+            let runs = runs.clone();
+            thread::spawn(move || {
+                let guard = request.enter(None);
+                loop {
+                    guard.wait_while_paused();
+                    if request.reason().is_some() {
+                        break;
+                    }
+                    runs.fetch_add(1, Ordering::SeqCst);
+                    // SAFETY: Safe because sleep has no preconditions.
+                    unsafe { libc::sleep(10) };
+                }
+            })
+        };
+        thread::sleep(Duration::from_millis(50));
+
+        request.pause();
+        assert!(request.is_paused());
+        let paused_at = runs.load(Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(runs.load(Ordering::SeqCst), paused_at);
+
+        request.resume();
+        assert!(!request.is_paused());
+        thread::sleep(Duration::from_millis(50));
+        assert!(runs.load(Ordering::SeqCst) > paused_at);
+
+        // Exits aren't held up by a pause
+        request.pause();
+        request.request(VmExit::Poweroff);
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn exit_on_sigterm() {
+        let request = VmExitRequest::default();
+        let executor = DeviceExecutor::default();
+        request.exit_on_signals(&executor).unwrap();
+        let runner = executor.run(request.clone());
+        // SAFETY: Safe because a handler for SIGTERM is installed.
+        unsafe { libc::raise(libc::SIGTERM) };
+        for _ in 0..100 {
+            if request.reason().is_some() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(request.reason(), Some(VmExit::Exit(128 + libc::SIGTERM)));
+        runner.join().unwrap();
+    }
+}
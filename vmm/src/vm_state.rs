@@ -0,0 +1,89 @@
+// Copyright (c) 2024, Qualcomm Innovation Center, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause-Clear
+
+//! Lifecycle of a [`crate::GunyahVirtualMachine`], see [`VmState`].
+//!
+//! Gunyah only takes the boot configuration, memory and vCPUs of a VM before it starts, and a VM
+//! can't be started twice. The VM checks its state before handing calls to the kernel, so misuse
+//! fails with a [`VmStateError`] naming the call instead of an EINVAL from deep in the Resource
+//! Manager.
+
+use std::fmt::{self, Display};
+
+use thiserror::Error as ThisError;
+
+/// Where a VM is in its lifecycle:
+/// Configuring → Ready → Running ⇄ Paused, and Running or Paused → Stopped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VmState {
+    /// Memory, vCPUs, devices and boot configuration are being added, the DTB isn't set yet
+    Configuring,
+    /// The DTB is set, the VM can start. It can still be configured further.
+    Ready,
+    Running,
+    /// The vCPUs are stopped until resumed, devices keep running
+    Paused,
+    /// The VM exited, see [`crate::VmExitRequest::reason`]
+    Stopped,
+}
+
+impl VmState {
+    /// Whether the VM was started, successfully.
+    pub fn is_started(&self) -> bool {
+        matches!(self, Self::Running | Self::Paused | Self::Stopped)
+    }
+}
+
+impl Display for VmState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Configuring => "configuring",
+            Self::Ready => "ready",
+            Self::Running => "running",
+            Self::Paused => "paused",
+            Self::Stopped => "stopped",
+        })
+    }
+}
+
+#[derive(ThisError, Debug)]
+pub enum VmStateError {
+    /// `operation` isn't possible in `state`.
+    #[error("Can't {operation} while the VM is {state}")]
+    InvalidState {
+        operation: &'static str,
+        state: VmState,
+    },
+    #[error(transparent)]
+    Gunyah(#[from] gunyah::Error),
+}
+
+/// Fails with [`VmStateError::InvalidState`] unless `state` is one of `allowed`.
+pub(crate) fn check_state(
+    operation: &'static str,
+    state: VmState,
+    allowed: &[VmState],
+) -> Result<(), VmStateError> {
+    if allowed.contains(&state) {
+        Ok(())
+    } else {
+        Err(VmStateError::InvalidState { operation, state })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use claim::{assert_err, assert_ok};
+
+    use super::{check_state, VmState};
+
+    #[test]
+    fn check() {
+        let configurable = [VmState::Configuring, VmState::Ready];
+        assert_ok!(check_state("add memory", VmState::Ready, &configurable));
+        let error = assert_err!(check_state("add memory", VmState::Paused, &configurable));
+        assert_eq!(error.to_string(), "Can't add memory while the VM is paused");
+        assert!(VmState::Stopped.is_started());
+        assert!(!VmState::Ready.is_started());
+    }
+}
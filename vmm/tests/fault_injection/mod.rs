@@ -12,8 +12,7 @@ use libc::c_long;
 use nonzero_ext::nonzero;
 use rstest::rstest;
 use serial_test::serial;
-use vm_fdt::FdtWriter;
-use vmm::GunyahVirtualMachine;
+use vmm::{GicConfig, GunyahVirtualMachine, RetryPolicy};
 
 pub(crate) fn clear_fault_injection() -> Result<()> {
     let mut f = File::options()
@@ -26,6 +25,11 @@ pub(crate) fn clear_fault_injection() -> Result<()> {
 }
 
 pub(crate) fn fault_inject_function(function: &str, error: c_long) -> Result<()> {
+    fault_inject_function_times(function, error, -1)
+}
+
+/// Like [`fault_inject_function`], but only fails the first `times` calls (-1 for all).
+pub(crate) fn fault_inject_function_times(function: &str, error: c_long, times: i32) -> Result<()> {
     fs::write("/sys/kernel/debug/fail_function/inject", function)
         .context("Couldn't set injection")?;
     fs::write(
@@ -35,7 +39,8 @@ pub(crate) fn fault_inject_function(function: &str, error: c_long) -> Result<()>
     .context("Couldn't set retval")?;
     fs::write("/sys/kernel/debug/fail_function/probability", "100")
         .context("Couldn't set probability")?;
-    fs::write("/sys/kernel/debug/fail_function/times", "-1").context("Couldn't set probability")?;
+    fs::write("/sys/kernel/debug/fail_function/times", times.to_string())
+        .context("Couldn't set probability")?;
     fs::write("/sys/kernel/debug/fail_function/task-filter", "Y")
         .context("Couldn't set probability")?;
 
@@ -52,16 +57,15 @@ fn can_create_injection() {
         .expect("Couldn't set up a fault injection");
 }
 
+const GIC: GicConfig = GicConfig {
+    dist_base: 0x3FFF0000,
+    dist_size: 0x10000,
+    redist_base: 0x3FF00000,
+    redist_size: 0x20000,
+};
+
 fn generate_fdt(vm: &GunyahVirtualMachine) -> Result<Vec<u8>> {
-    let mut fdt = FdtWriter::new()?;
-    let root_node = fdt.begin_node("")?;
-    vm.create_fdt_basic_config(
-        &mut fdt,
-        &[0x3FFF0000, 0x10000, 0x3FF00000, 0x20000],
-        &[13, 14, 11, 10],
-    )?;
-    fdt.end_node(root_node)?;
-    Ok(fdt.finish()?)
+    vm.fdt_builder(GIC).build()
 }
 
 fn setup_basic_vm() -> Result<GunyahVirtualMachine> {
@@ -86,14 +90,9 @@ fn setup_basic_vm() -> Result<GunyahVirtualMachine> {
     )
     .expect("Failed to set dtb config");
 
-    let mut fdt = FdtWriter::new().context("Failed to create fdt writer")?;
-    fdt.begin_node("")?;
-    vm.create_fdt_basic_config(
-        &mut fdt,
-        &[0x3FFF0000, 0x10000, 0x3FF00000, 0x20000],
-        &[13, 14, 11, 10],
-    )
-    .context("Failed to create fdt config")?;
+    vm.fdt_builder(GIC)
+        .build()
+        .context("Failed to create fdt config")?;
 
     Ok(vm)
 }
@@ -132,3 +131,26 @@ fn vm_start_fails(
     let vm = setup_basic_vm().expect("Failed to create VM");
     assert_err!(vm.start());
 }
+
+#[rstest]
+#[ignore = "assumed fail_function not available"]
+#[serial]
+fn vm_start_retries(
+    #[values(libc::EAGAIN, libc::EINVAL)] errno: i32,
+    #[values(false, true)] retry: bool,
+) {
+    clear_fault_injection().expect("Couldn't clear fault injections");
+    fault_inject_function_times("gunyah_rm_vm_start", -(errno as c_long), 1)
+        .expect("Couldn't set up a fault injection");
+
+    let mut vm = setup_basic_vm().expect("Failed to create VM");
+    if retry {
+        vm.set_start_retry(Some(RetryPolicy::default()));
+    }
+
+    if retry && errno != libc::EINVAL {
+        assert_ok!(vm.start());
+    } else {
+        assert_err!(vm.start());
+    }
+}
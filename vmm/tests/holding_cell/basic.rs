@@ -2,8 +2,9 @@
 // SPDX-License-Identifier: BSD-3-Clause-Clear
 
 use claim::{assert_err, assert_lt, assert_ok, assert_ok_eq};
-use gunyah_bindings::gunyah_vcpu_exit::GUNYAH_VCPU_EXIT_MMIO;
+use gunyah::VcpuExit;
 use rstest::rstest;
+use vmm::{ArchTimer, TimerConfig};
 
 use crate::holding_cell::{HoldingCell, HOLDING_CELL_BIN};
 
@@ -27,9 +28,14 @@ fn holding_cell_reads() {
     vm.vm.start().expect("Failed to start VM");
     let vcpu = &vm.vcpus[0];
     let result = vcpu.run_once().expect("vcpu run failed");
-    assert_eq!(result.exit_reason, GUNYAH_VCPU_EXIT_MMIO);
-    assert_eq!(unsafe { result.__bindgen_anon_1.mmio }.phys_addr, 0x6000);
-    assert_eq!(unsafe { result.__bindgen_anon_1.mmio }.is_write, 0);
+    assert!(matches!(
+        result,
+        VcpuExit::Mmio {
+            addr: 0x6000,
+            is_write: false,
+            ..
+        }
+    ));
 }
 
 /// Test that we can run test_ok
@@ -83,6 +89,43 @@ fn magic() {
     assert_ok_eq!(hc.run_immediately(0, 5, &[magic]), 1);
 }
 
+#[test]
+fn builder_run_immediately() {
+    let hc = assert_ok!(vmm::HoldingCellBuilder::new()
+        .payload(HOLDING_CELL_BIN)
+        .num_cells(2)
+        .build());
+    assert_eq!(hc.vcpus.len(), 2);
+    let magic = 0x12345678u64;
+    assert_ok_eq!(hc.run_immediately(0, 4, &[magic]), magic);
+}
+
+#[test]
+fn wait_irq() {
+    let hc = HoldingCell::new();
+    let irq = assert_ok!(hc.vm.add_edge_interrupt(5));
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            // Give the cell time to start waiting
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            assert_ok!(irq.trigger());
+        });
+        assert_ok!(hc.wait_irq(0, 5));
+    });
+}
+
+/// Test that the timers raise the PPIs the DT describes, and not before their deadline
+#[rstest]
+fn timer_interrupt(#[values(ArchTimer::Virtual, ArchTimer::Physical)] timer: ArchTimer) {
+    let config = TimerConfig::default();
+    let hc = HoldingCell::new();
+    // 10ms
+    let ticks = u64::from(config.clock_frequency) / 100;
+    assert_ok_eq!(hc.wait_timer(0, timer, ticks), timer.ppi(&config));
+    // Once more, the first interrupt was deasserted
+    assert_ok_eq!(hc.wait_timer(0, timer, ticks), timer.ppi(&config));
+}
+
 #[test]
 fn huge_pages_base() {
     let hc = HoldingCell::new_with_options(HoldingCellOptions {
@@ -93,6 +136,34 @@ fn huge_pages_base() {
     assert_ok!(hc.ack_ok(0));
 }
 
+#[rstest]
+fn mmu_and_caches(#[values(true, false)] mmu: bool) {
+    const SCTLR_M_C: u64 = 0b101;
+
+    const ADDRESS: u64 = 0x0008_0000u64;
+    const MAGIC: u64 = 0xdeadf00d;
+
+    let mut hc = HoldingCell::new_with_options(HoldingCellOptions {
+        mmu,
+        ..Default::default()
+    });
+    assert_ok!(hc.vm.add_memory(
+        ADDRESS,
+        std::num::NonZeroUsize::new(0x1000).unwrap(),
+        gunyah::ShareType::Share,
+        gunyah::GuestMemoryAccess::Rw,
+        false,
+    ));
+    let sctlr = assert_ok!(hc.read_sctlr(0));
+    assert_eq!(sctlr & SCTLR_M_C == SCTLR_M_C, mmu);
+
+    assert_ok!(hc.write_addr(0, ADDRESS, MAGIC));
+    let mut data = [0u8; 8];
+    assert_ok!(hc.host_read_slice(ADDRESS, &mut data));
+    assert_eq!(u64::from_le_bytes(data), MAGIC);
+    assert_ok_eq!(hc.read_addr(0, ADDRESS), MAGIC);
+}
+
 #[rstest]
 fn big_dtb(#[values(true, false)] huge_pages: bool) {
     use nonzero_ext::NonZero;
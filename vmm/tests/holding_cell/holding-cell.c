@@ -7,6 +7,9 @@
 
 #define report_err()		do { *(unsigned long*)HOLDING_CELL_ERR_ADDR = __LINE__; } while(0)
 
+/* Boot flags, patched into the image by the VMM */
+#define BOOT_FLAG_MMU		(1UL << 0)
+
 #define COMMAND_HOLD(cmd)	(!!((cmd) & 0x1000))
 #define COMMAND_NARGS(cmd)	(((cmd) >> 8) & 0xf)
 #define COMMAND_ID(cmd)		((cmd) & 0xff)
@@ -17,10 +20,23 @@ struct command {
 		long (*cb0)(void);
 		long (*cb1)(unsigned long);
 		long (*cb2)(unsigned long, unsigned long);
+		long (*cb4)(unsigned long, unsigned long, unsigned long, unsigned long);
 		long (*cb5)(unsigned long, unsigned long, unsigned long, unsigned long, unsigned long);
 	};
 };
 
+/* Image header: the entry point branches over the boot flags, kept at offset 8 */
+asm (
+	".section .start.header, \"ax\"\n"
+	"b start\n"
+	".balign 8\n"
+	".global boot_flags\n"
+	"boot_flags: .quad 1\n"	// BOOT_FLAG_MMU
+	".previous\n"
+);
+
+extern const volatile unsigned long boot_flags;
+
 long test_ok(void) {
 	return 0;
 }
@@ -83,10 +99,209 @@ long write_io(unsigned long addr, unsigned long value) {
 	return 0;
 }
 
+#define GICD_CTLR		0x0000
+#define GICD_IGROUPR		0x0080
+#define GICD_ISENABLER		0x0100
+#define GICD_IPRIORITYR		0x0400
+#define GICD_IROUTER		0x6000
+#define GICD_CTLR_ENABLE_G1A	(1 << 1)
+#define GICD_CTLR_ARE_NS	(1 << 4)
+#define GIC_SPURIOUS_INTID	1023
+
+#define GICR_FRAME_SIZE		0x20000
+#define GICR_WAKER		0x0014
+#define GICR_SGI_BASE		0x10000
+#define GICR_IGROUPR0		(GICR_SGI_BASE + 0x0080)
+#define GICR_ISENABLER0		(GICR_SGI_BASE + 0x0100)
+#define GICR_IPRIORITYR		(GICR_SGI_BASE + 0x0400)
+#define GICR_WAKER_PROCESSOR_SLEEP	(1 << 1)
+#define GICR_WAKER_CHILDREN_ASLEEP	(1 << 2)
+#define GIC_PPI_MASK		0xffff0000
+#define GIC_SGI_MASK		0x0000ffff
+#define GIC_NR_SGIS		16
+
+#define TIMER_VIRTUAL		0
+#define TIMER_PHYSICAL		1
+#define CNT_CTL_ENABLE		(1 << 0)
+#define TIMER_EARLY		(~0UL)
+
+/* Enables the system register interface of the CPU, for every priority of group 1 */
+static void gic_cpu_enable(void) {
+	asm volatile (
+		"msr S3_0_C12_C12_5, %0\n"	// ICC_SRE_EL1: system register interface
+		"isb\n"
+		"msr S3_0_C4_C6_0, %1\n"	// ICC_PMR_EL1: every priority
+		"msr S3_0_C12_C12_7, %2\n"	// ICC_IGRPEN1_EL1: group 1 enabled
+		"isb\n"
+		: : "r" (7UL), "r" (0xffUL), "r" (1UL)
+	);
+}
+
+/* Enables the CPU interface and waits for an interrupt with IRQs masked, so no vector is needed:
+ * the pending interrupt only wakes up WFI. Returns its intid once acknowledged. */
+static unsigned long gic_wait(void) {
+	unsigned long iar;
+
+	gic_cpu_enable();
+	do {
+		asm volatile (
+			"wfi\n"
+			"mrs %0, S3_0_C12_C12_0\n"	// ICC_IAR1_EL1
+			: "=r" (iar)
+		);
+		iar &= 0xffffff;
+	} while (iar == GIC_SPURIOUS_INTID);
+	asm volatile ("msr S3_0_C12_C12_1, %0" : : "r" (iar));	// ICC_EOIR1_EL1
+
+	return iar;
+}
+
+/* Routes SPI intid of the distributor at gicd to this CPU and waits for it */
+long wait_irq(unsigned long gicd, unsigned long intid) {
+	volatile unsigned int *dist = (volatile unsigned int *)gicd;
+	unsigned long mpidr;
+
+	asm volatile ("mrs %0, MPIDR_EL1" : "=r" (mpidr));
+	dist[GICD_CTLR / 4] = GICD_CTLR_ARE_NS | GICD_CTLR_ENABLE_G1A;
+	dist[GICD_IGROUPR / 4 + intid / 32] |= 1u << (intid % 32);
+	*(volatile unsigned char *)(gicd + GICD_IPRIORITYR + intid) = 0x80;
+	*(volatile unsigned long *)(gicd + GICD_IROUTER + intid * 8) = mpidr & 0xff00ffffffUL;
+	dist[GICD_ISENABLER / 4 + intid / 32] = 1u << (intid % 32);
+
+	return gic_wait();
+}
+
+/* Enables the distributor and wakes the redistributor of this CPU up, gicr being the first CPU's.
+ * Returns the base of this CPU's redistributor. */
+static unsigned long gicr_wake(unsigned long gicd, unsigned long gicr) {
+	volatile unsigned int *dist = (volatile unsigned int *)gicd;
+	unsigned long mpidr;
+
+	asm volatile ("mrs %0, MPIDR_EL1" : "=r" (mpidr));
+	gicr += (mpidr & 0xff) * GICR_FRAME_SIZE;
+	dist[GICD_CTLR / 4] = GICD_CTLR_ARE_NS | GICD_CTLR_ENABLE_G1A;
+	*(volatile unsigned int *)(gicr + GICR_WAKER) &= ~GICR_WAKER_PROCESSOR_SLEEP;
+	while (*(volatile unsigned int *)(gicr + GICR_WAKER) & GICR_WAKER_CHILDREN_ASLEEP)
+		;
+
+	return gicr;
+}
+
+/* Arms the virtual or physical timer of this CPU to fire in ticks and waits for it with every PPI
+ * of its redistributor, gicr being the first CPU's, enabled. Returns the intid the timer raised,
+ * or TIMER_EARLY if it came before the deadline. */
+long wait_timer(unsigned long gicd, unsigned long gicr, unsigned long timer, unsigned long ticks) {
+	unsigned long start, now, iar;
+
+	gicr = gicr_wake(gicd, gicr);
+	*(volatile unsigned int *)(gicr + GICR_IGROUPR0) |= GIC_PPI_MASK;
+	for (unsigned long intid = 16; intid < 32; intid++)
+		*(volatile unsigned char *)(gicr + GICR_IPRIORITYR + intid) = 0x80;
+	*(volatile unsigned int *)(gicr + GICR_ISENABLER0) = GIC_PPI_MASK;
+
+	if (timer == TIMER_PHYSICAL) {
+		asm volatile (
+			"isb\n"
+			"mrs %0, CNTPCT_EL0\n"
+			"msr CNTP_TVAL_EL0, %1\n"
+			"msr CNTP_CTL_EL0, %2\n"
+			"isb\n"
+			: "=&r" (start) : "r" (ticks), "r" ((unsigned long)CNT_CTL_ENABLE)
+		);
+	} else {
+		asm volatile (
+			"isb\n"
+			"mrs %0, CNTVCT_EL0\n"
+			"msr CNTV_TVAL_EL0, %1\n"
+			"msr CNTV_CTL_EL0, %2\n"
+			"isb\n"
+			: "=&r" (start) : "r" (ticks), "r" ((unsigned long)CNT_CTL_ENABLE)
+		);
+	}
+
+	iar = gic_wait();
+
+	/* The timer interrupt is level triggered, disable it before it fires again */
+	if (timer == TIMER_PHYSICAL)
+		asm volatile ("msr CNTP_CTL_EL0, xzr\n" "isb\n" "mrs %0, CNTPCT_EL0" : "=r" (now));
+	else
+		asm volatile ("msr CNTV_CTL_EL0, xzr\n" "isb\n" "mrs %0, CNTVCT_EL0" : "=r" (now));
+
+	return now - start < ticks ? TIMER_EARLY : iar;
+}
+
+/* Enables the SGIs of this CPU, gicr being the first CPU's redistributor. The higher the SGI, the
+ * lower its priority, so pending SGIs are acknowledged in order. */
+long enable_sgis(unsigned long gicd, unsigned long gicr) {
+	gicr = gicr_wake(gicd, gicr);
+	*(volatile unsigned int *)(gicr + GICR_IGROUPR0) |= GIC_SGI_MASK;
+	for (unsigned long intid = 0; intid < GIC_NR_SGIS; intid++)
+		*(volatile unsigned char *)(gicr + GICR_IPRIORITYR + intid) = 0x40 + intid * 8;
+	*(volatile unsigned int *)(gicr + GICR_ISENABLER0) = GIC_SGI_MASK;
+
+	return 0;
+}
+
+/* Sends SGI intid to the CPU with MPIDR target */
+long send_sgi(unsigned long target, unsigned long intid) {
+	unsigned long aff0 = target & 0xff;
+	unsigned long sgi1r = (1UL << (aff0 % 16)) |	// TargetList
+		(((target >> 8) & 0xff) << 16) |	// Aff1
+		((intid & 0xf) << 24) |
+		(((target >> 16) & 0xff) << 32) |	// Aff2
+		((aff0 / 16) << 44) |			// RS
+		(((target >> 32) & 0xff) << 48);	// Aff3
+
+	gic_cpu_enable();
+	asm volatile (
+		"msr S3_0_C12_C11_5, %0\n"	// ICC_SGI1R_EL1
+		"isb\n"
+		: : "r" (sgi1r)
+	);
+
+	return 0;
+}
+
+/* Acknowledges the highest priority pending interrupt, waiting for one if there is none */
+long ack_irq(void) {
+	return gic_wait();
+}
+
+long read_sctlr(void) {
+	unsigned long sctlr;
+
+	asm volatile ("mrs %0, SCTLR_EL1" : "=r" (sctlr));
+	return sctlr;
+}
+
+/* Smallest data cache line of the caches the CPU maintains, from CTR_EL0.DminLine */
+static unsigned long dcache_line_size(void) {
+	unsigned long ctr;
+
+	asm volatile ("mrs %0, CTR_EL0" : "=r" (ctr));
+	return 4UL << ((ctr >> 16) & 0xf);
+}
+
+/* Cache maintenance by VA to the point of coherency for every line of [start, start + length),
+ * completed before returning. With stage 2 translation, the hypervisor may upgrade an invalidate
+ * to clean and invalidate, so dirty lines are never lost. */
+#define DCACHE_RANGE_OP(name, op) \
+long name(unsigned long start, unsigned long length) { \
+	unsigned long line = dcache_line_size(); \
+	for (unsigned long addr = start & ~(line - 1); addr < start + length; addr += line) \
+		asm volatile ("dc " op ", %0" : : "r" (addr) : "memory"); \
+	asm volatile ("dsb sy" : : : "memory"); \
+	return 0; \
+}
+
+DCACHE_RANGE_OP(dcache_clean_range, "cvac")
+DCACHE_RANGE_OP(dcache_invalidate_range, "ivac")
+DCACHE_RANGE_OP(dcache_clean_invalidate_range, "civac")
+
 #define TEST(sym, _nargs, id) \
 	[id] = { .nargs = _nargs, .cb ## _nargs = sym }
 
-#define NR_COMMANDS		11
+#define NR_COMMANDS		19
 const struct command COMMANDS[NR_COMMANDS]= {
 	/*   function         nargs    id */
 	TEST(test_ok,		0,	0),
@@ -99,6 +314,15 @@ const struct command COMMANDS[NR_COMMANDS]= {
 	TEST(access_page_range,	2,	7),
 	TEST(read_io,		1,	8),
 	TEST(write_io,		2,	9),
+	TEST(wait_irq,		2,	10),
+	TEST(dcache_clean_range,	2,	11),
+	TEST(dcache_invalidate_range,	2,	12),
+	TEST(dcache_clean_invalidate_range,	2,	13),
+	TEST(read_sctlr,		0,	14),
+	TEST(wait_timer,		4,	15),
+	TEST(enable_sgis,		2,	16),
+	TEST(send_sgi,		2,	17),
+	TEST(ack_irq,		0,	18),
 };
 
 int main() {
@@ -142,6 +366,9 @@ int main() {
 		case 2:
 			*holding_cell = cmd->cb2(args[0], args[1]);
 			break;
+		case 4:
+			*holding_cell = cmd->cb4(args[0], args[1], args[2], args[3]);
+			break;
 		case 5:
 			*holding_cell = cmd->cb5(args[0], args[1], args[2], args[3], args[4]);
 		}
@@ -161,10 +388,13 @@ void __attribute__ ((noinline)) __start()
 		: [mpidr] "=r" (mpidr)
 	);
 
-	if ((mpidr & 0xff) == 0)
-		construct_page_table();
+	/* Without the MMU, every data access is Device-nGnRnE and bypasses the caches */
+	if (boot_flags & BOOT_FLAG_MMU) {
+		if ((mpidr & 0xff) == 0)
+			construct_page_table();
 
-	enable_mmu();
+		enable_mmu();
+	}
 
 	main();
 }
@@ -4,7 +4,7 @@
 use std::{io::Read, os::fd::AsRawFd, time::Duration};
 
 use claim::assert_none;
-use gunyah_bindings::gunyah_vcpu_exit::GUNYAH_VCPU_EXIT_MMIO;
+use gunyah::VcpuExit;
 use mio::{unix::SourceFd, Events, Interest, Poll, Token};
 use rstest::rstest;
 
@@ -62,11 +62,11 @@ fn datamatch(#[values(0, 0xf00d)] bad_magic: u64) {
 
     // Ignore error, it's going to be confused we had MMIO exit
     let _ = hc.write_io(0, address, bad_magic);
-    let state = hc.cell_state(0);
-    assert_eq!(state.exit_reason, GUNYAH_VCPU_EXIT_MMIO);
-    let mmio_exit = unsafe { state.__bindgen_anon_1.mmio };
-    assert_eq!(mmio_exit.phys_addr, address);
-    assert_eq!(mmio_exit.data, bad_magic.to_le_bytes());
+    let VcpuExit::Mmio { addr, data, .. } = hc.cell_state(0) else {
+        panic!("Expected an MMIO exit: {:?}", hc.cell_state(0));
+    };
+    assert_eq!(addr, address);
+    assert_eq!(data, bad_magic.to_le_bytes());
     poll.poll(&mut events, Some(Duration::ZERO))
         .expect("Failed to poll");
     assert_none!(events.iter().next());
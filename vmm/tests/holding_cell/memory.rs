@@ -10,9 +10,11 @@ use std::{
 
 use claim::{assert_err, assert_ok, assert_ok_eq};
 use gunyah::GuestMemoryAccess;
+#[cfg(feature = "ack-bindings")]
+use gunyah::HugePageSize;
 use rstest::rstest;
 
-use crate::holding_cell::{FlushType, HoldingCell};
+use crate::holding_cell::{CacheMaintenance, FlushType, HoldingCell};
 
 macro_rules! kib {
     ($x:expr) => {
@@ -304,7 +306,10 @@ fn lend_unlock_no_sanitize() {
     const ADDRESS: u64 = 0xa000_0000u64;
     const MAGIC: u64 = 0xf00ddeadu64;
 
-    let mut hc = HoldingCell::new();
+    let mut hc = HoldingCell::new_with_options(crate::holding_cell::HoldingCellOptions {
+        mmu: false,
+        ..Default::default()
+    });
     let mut data = [0u8; 8];
     let mem = hc
         .vm
@@ -333,6 +338,114 @@ fn lend_unlock_no_sanitize() {
     assert_ok_eq!(hc.read_addr(0, ADDRESS), 0);
 }
 
+/// Test that converting a region between shared and lent at runtime changes whether the host can
+/// access it, while the guest keeps its contents
+#[test]
+// This test is only applicable with guest_memfd where it can enforce that
+// userspace can't mmap/fault in the lent memory.
+#[cfg(not(feature = "ack-bindings"))]
+fn convert_share_lend() {
+    const ADDRESS: u64 = 0xa000_0000u64;
+    const MAGIC: u64 = 0xf00ddeadu64;
+
+    let mut hc = HoldingCell::new_with_options(crate::holding_cell::HoldingCellOptions {
+        mmu: false,
+        ..Default::default()
+    });
+    let mut data = [0u8; 8];
+    let mem = hc
+        .vm
+        .add_memory(
+            ADDRESS,
+            NonZeroUsize::new(kib!(4)).unwrap(),
+            gunyah::ShareType::Share,
+            GuestMemoryAccess::Rw,
+            false,
+        )
+        .expect("Failed to add memory");
+
+    assert_ok!(hc.write_addr(0, ADDRESS, MAGIC));
+    assert_ok!(hc.host_read_slice(ADDRESS, &mut data));
+    assert_eq!(u64::from_le_bytes(data), MAGIC);
+
+    assert_ok!(mem.lock().unwrap().convert(gunyah::ShareType::Lend));
+    assert_ok_eq!(hc.read_addr(0, ADDRESS), MAGIC);
+    assert_err!(hc.host_read_slice(ADDRESS, &mut data));
+
+    // The guest has to give the page back before the host can take it
+    assert_ok!(hc.write_addr(0, ADDRESS, !MAGIC));
+    assert_ok!(hc.page_relinquish(0, ADDRESS, 1, false, FlushType::NoFlush));
+    assert_ok!(mem.lock().unwrap().convert(gunyah::ShareType::Share));
+    assert_ok!(hc.host_read_slice(ADDRESS, &mut data));
+    assert_eq!(u64::from_le_bytes(data), !MAGIC);
+    assert_ok!(hc.host_write_slice(ADDRESS, &MAGIC.to_le_bytes()));
+    assert_ok_eq!(hc.read_addr(0, ADDRESS), MAGIC);
+}
+
+/// Test that dirty cache lines the guest writes back itself reach the host after relinquishing
+/// without the flush flag
+#[rstest]
+#[case(CacheMaintenance::Clean)]
+#[case(CacheMaintenance::CleanInvalidate)]
+// This test is only applicable with guest_memfd where it can enforce that
+// userspace can't mmap/fault in the lent memory.
+#[cfg(not(feature = "ack-bindings"))]
+fn relinquish_cleaned_lines(#[case] op: CacheMaintenance) {
+    const ADDRESS: u64 = 0xa000_0000u64;
+    const MAGIC: u64 = 0xf00ddeadu64;
+
+    let mut hc = HoldingCell::new();
+    hc.vm
+        .add_memory(
+            ADDRESS,
+            NonZeroUsize::new(kib!(4)).unwrap(),
+            gunyah::ShareType::Lend,
+            GuestMemoryAccess::Rw,
+            false,
+        )
+        .expect("Failed to add memory");
+
+    // Dirty a line in the middle and one at the end of the page, then write back a range which
+    // isn't line aligned
+    assert_ok!(hc.write_addr(0, ADDRESS + 0x808, MAGIC));
+    assert_ok!(hc.write_addr(0, ADDRESS + 0xff8, !MAGIC));
+    assert_ok!(hc.dcache_maintain(0, op, ADDRESS + 0x804, 0x7fc));
+    assert_ok!(hc.page_relinquish(0, ADDRESS, 1, false, FlushType::NoFlush));
+
+    let mut data = [0u8; 8];
+    assert_ok!(hc.host_read_slice(ADDRESS + 0x808, &mut data));
+    assert_eq!(u64::from_le_bytes(data), MAGIC);
+    assert_ok!(hc.host_read_slice(ADDRESS + 0xff8, &mut data));
+    assert_eq!(u64::from_le_bytes(data), !MAGIC);
+}
+
+/// Test that the guest reads what the host wrote to shared memory after invalidating lines it
+/// read before
+#[test]
+fn share_invalidate_host_write() {
+    const ADDRESS: u64 = 0x0008_0000u64;
+    const MAGIC: u64 = 0xdeadf00d;
+
+    let mut hc = HoldingCell::new();
+    hc.vm
+        .add_memory(
+            ADDRESS,
+            NonZeroUsize::new(kib!(4)).unwrap(),
+            gunyah::ShareType::Share,
+            GuestMemoryAccess::Rw,
+            false,
+        )
+        .expect("Failed to add memory");
+
+    // Bring the line into the cache
+    assert_ok_eq!(hc.read_addr(0, ADDRESS), 0);
+    assert_ok!(hc.host_write_slice(ADDRESS, &MAGIC.to_le_bytes()));
+    assert_ok!(hc.dcache_maintain(0, CacheMaintenance::Invalidate, ADDRESS, kib!(4)));
+    assert_ok_eq!(hc.read_addr(0, ADDRESS), MAGIC);
+    assert_ok!(hc.dcache_maintain(0, CacheMaintenance::CleanInvalidate, ADDRESS, kib!(4)));
+    assert_ok_eq!(hc.read_addr(0, ADDRESS), MAGIC);
+}
+
 #[test]
 #[ignore = "No support yet for guest immediately reclaiming page. HA was unset"]
 fn unlocked_page_access() {
@@ -534,6 +647,29 @@ fn large_footprint(#[case] size: usize, #[case] huge_pages: bool) {
     println!("{:?}", Instant::now().duration_since(start));
 }
 
+/// Like large_footprint, but with every page a hugetlbfs page instead of best-effort transparent
+/// huge pages. The host needs enough huge pages of each size reserved. Guest memfds can't be
+/// backed by hugetlbfs.
+#[cfg(feature = "ack-bindings")]
+#[rstest]
+#[case(mib!(4), HugePageSize::Size2M)] // case 1
+// #[case(mib!(1024), HugePageSize::Size1G)] // case 2
+#[trace]
+fn large_footprint_hugetlb(#[case] size: usize, #[case] page_size: HugePageSize) {
+    let mut hc = HoldingCell::new();
+    let address = 0xa000_0000u64;
+    assert_ok!(hc.vm.add_hugetlb_memory(
+        address,
+        NonZeroUsize::new(size).unwrap(),
+        gunyah::ShareType::Lend,
+        GuestMemoryAccess::Rw,
+        page_size,
+    ));
+    let start = Instant::now();
+    assert_ok!(hc.run_immediately(0, 7, &[address, size as u64]));
+    println!("{:?}", Instant::now().duration_since(start));
+}
+
 // To test that unaligned access is ok, apply the patch below. This ioctl
 // doesn't make sense in production, so it won't be merged anywhere. The
 // pr_err will print some mostly garbage value. We don't care what it prints:
@@ -710,3 +846,37 @@ fn partial_unmap_memory2() {
     let mut data = [0u8; 8];
     assert_err!(hc.host_read_slice(ADDRESS + kib!(4), &mut data));
 }
+
+#[test]
+#[cfg(not(feature = "ack-bindings"))]
+fn offline_relinquished_memory() {
+    const ADDRESS: u64 = 0xa000_0000u64;
+
+    let mut hc = HoldingCell::new();
+    let mem = hc
+        .vm
+        .add_memory(
+            ADDRESS,
+            NonZeroUsize::new(4 * kib!(4)).unwrap(),
+            gunyah::ShareType::Lend,
+            GuestMemoryAccess::Rw,
+            false,
+        )
+        .expect("Failed to add memory");
+
+    assert_ok!(hc.write_addr(0, ADDRESS, 1));
+    assert_ok!(hc.write_addr(0, ADDRESS + kib!(4), 2));
+    assert_ok!(hc.write_addr(0, ADDRESS + 3 * kib!(4), 3));
+
+    assert_ok!(hc.page_relinquish(0, ADDRESS + kib!(4), 2, true, FlushType::FlushOnLast));
+    assert_ok!(hc.vm.offline_memory(mem, kib!(4), 2 * kib!(4)));
+
+    // Offlined pages aren't memory anymore, so guest accesses exit to the VMM
+    assert_ok_eq!(hc.read_io(0, ADDRESS + kib!(4), 0xf00d), 0xf00du64);
+    let mut data = [0u8; 8];
+    assert_err!(hc.host_read_slice(ADDRESS + kib!(4), &mut data));
+
+    // The rest of the region is untouched
+    assert_ok_eq!(hc.read_addr(0, ADDRESS), 1u64);
+    assert_ok_eq!(hc.read_addr(0, ADDRESS + 3 * kib!(4)), 3u64);
+}
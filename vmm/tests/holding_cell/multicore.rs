@@ -11,6 +11,8 @@ use claim::{assert_ok, assert_ok_eq};
 use gunyah::GuestMemoryAccess;
 use rstest::rstest;
 
+use vmm::{ArchTimer, TimerConfig};
+
 use super::{HoldingCell, HoldingCellOptions};
 
 #[derive(Debug, Clone, Copy)]
@@ -77,6 +79,71 @@ fn basic_multicore(#[values(2, 4, 7, 8, 16)] num_cells: u8) {
     }
 }
 
+/// Tests that SGIs reach the vCPU they're sent to, and that SGIs pending at once are acknowledged
+/// in the order of their priorities rather than the order they were sent in.
+#[rstest]
+fn sgi_between_cells(#[values(2, 4)] num_cells: u8) {
+    const SGIS: [u8; 3] = [5, 1, 3];
+
+    if usize::from(num_cells) > core_affinity::get_core_ids().unwrap().len() {
+        return;
+    }
+    let hc = HoldingCell::new_with_options(HoldingCellOptions {
+        num_cells,
+        ..Default::default()
+    });
+    assert_ok!(hc.vm.start());
+    assert_ok!(hc.enable_sgis(0));
+    for cell in 1..num_cells {
+        assert_ok!(hc.power_on_cell(cell), "Failed to power on vcpu {}", cell);
+        assert_ok!(hc.enable_sgis(cell));
+    }
+
+    for cell in 1..num_cells {
+        for sgi in SGIS {
+            assert_ok!(hc.send_sgi(0, cell, sgi));
+        }
+        let mut sorted = SGIS;
+        sorted.sort();
+        for sgi in sorted {
+            assert_ok_eq!(hc.ack_sgi(cell), sgi);
+        }
+
+        // And back
+        assert_ok!(hc.send_sgi(cell, 0, cell));
+        assert_ok_eq!(hc.ack_sgi(0), cell);
+    }
+}
+
+/// Tests that every vCPU gets the interrupts of its own timers, through its own redistributor.
+#[test]
+fn timer_per_cell() {
+    const NUM_CELLS: u8 = 2;
+
+    if usize::from(NUM_CELLS) > core_affinity::get_core_ids().unwrap().len() {
+        return;
+    }
+    let hc = HoldingCell::new_with_options(HoldingCellOptions {
+        num_cells: NUM_CELLS,
+        ..Default::default()
+    });
+    let config = TimerConfig::default();
+    assert_ok!(hc.vm.start());
+    for cell in 1..NUM_CELLS {
+        assert_ok!(hc.power_on_cell(cell), "Failed to power on vcpu {}", cell);
+    }
+    for cell in 0..NUM_CELLS {
+        assert_ok_eq!(
+            hc.wait_timer(
+                cell,
+                ArchTimer::Virtual,
+                u64::from(config.clock_frequency) / 1000
+            ),
+            ArchTimer::Virtual.ppi(&config)
+        );
+    }
+}
+
 #[test]
 #[ignore = "share temporarily not working"]
 fn share_reclaim_race_10sec() {
@@ -198,7 +265,8 @@ fn unclaimed_vcpu_exit() {
     let hc = HoldingCell::new();
     assert_ok!(hc.ack_ok(0));
     hc.vm
-        .create_vcpu(1)
+        .vcpu_hotplug()
+        .create(1)
         .expect("Failed to create vcpu after vm was running");
     hc.power_off(0).unwrap();
 }
@@ -3,9 +3,9 @@
 
 use anyhow::Result;
 use claim::{assert_err, assert_ok};
-use gunyah::{GuestMemoryAccess, ShareType};
-use vm_fdt::FdtWriter;
-use vmm::GunyahVirtualMachine;
+use gunyah::{BootReg, GuestMemoryAccess, ShareType};
+use rstest::rstest;
+use vmm::{parse_fdt, GicConfig, GunyahVirtualMachine, VmState, VmStateError};
 
 macro_rules! kib {
     ($x:expr) => {
@@ -13,16 +13,15 @@ macro_rules! kib {
     };
 }
 
+const GIC: GicConfig = GicConfig {
+    dist_base: 0x3FFF0000,
+    dist_size: 0x10000,
+    redist_base: 0x3FF00000,
+    redist_size: 0x20000,
+};
+
 fn generate_fdt(vm: &GunyahVirtualMachine) -> Result<Vec<u8>> {
-    let mut fdt = FdtWriter::new()?;
-    let root_node = fdt.begin_node("")?;
-    vm.create_fdt_basic_config(
-        &mut fdt,
-        &[0x3FFF0000, 0x10000, 0x3FF00000, 0x20000],
-        &[13, 14, 11, 10],
-    )?;
-    fdt.end_node(root_node)?;
-    Ok(fdt.finish()?)
+    vm.fdt_builder(GIC).build()
 }
 
 /// Ensures that VM DTB can't be mapped when launching the VM. This essentially
@@ -78,3 +77,553 @@ fn shared_vm() {
 
     assert_ok!(vm.start());
 }
+
+#[test]
+fn lifecycle() {
+    let mut vm = GunyahVirtualMachine::new().expect("Failed to create Gunyah Virtual machine");
+    assert_eq!(vm.state(), VmState::Configuring);
+    assert!(matches!(
+        vm.start(),
+        Err(VmStateError::InvalidState {
+            state: VmState::Configuring,
+            ..
+        })
+    ));
+
+    vm.add_regular_memory(
+        0x8000_0000,
+        kib!(16).try_into().unwrap(),
+        ShareType::Share,
+        GuestMemoryAccess::Rwx,
+        false,
+    )
+    .expect("Failed to create guest memory");
+    vm.create_vcpu(0).expect("Failed to create vcpu");
+    let dtb = generate_fdt(&vm).expect("Failed to generate DT");
+    vm.set_dtb_config(0x8000_0000, kib!(4), &dtb)
+        .expect("Failed to set DTB configuration");
+    assert_eq!(vm.state(), VmState::Ready);
+    assert_err!(vm.pause());
+
+    assert_ok!(vm.start());
+    assert_eq!(vm.state(), VmState::Running);
+    assert_err!(vm.start());
+    assert!(vm.create_vcpu(1).is_err());
+    assert_err!(vm.set_boot_pc(0x8000_0000));
+    assert_err!(vm.set_boot_reg(BootReg::X(0), 0x8000_2000));
+    assert!(vm
+        .add_regular_memory(
+            0x9000_0000,
+            kib!(4).try_into().unwrap(),
+            ShareType::Share,
+            GuestMemoryAccess::Rw,
+            false,
+        )
+        .is_err());
+
+    assert_ok!(vm.pause());
+    assert_eq!(vm.state(), VmState::Paused);
+    assert_ok!(vm.resume());
+    assert_eq!(vm.state(), VmState::Running);
+}
+
+#[test]
+fn fdt_describes_memory_and_gic() {
+    let mut vm = GunyahVirtualMachine::new().expect("Failed to create Gunyah Virtual machine");
+    vm.add_regular_memory(
+        0x8000_0000,
+        kib!(16).try_into().unwrap(),
+        ShareType::Share,
+        GuestMemoryAccess::Rwx,
+        false,
+    )
+    .expect("Failed to create guest memory");
+    vm.create_vcpu(0).expect("Failed to create vcpu");
+
+    let dtb = generate_fdt(&vm).expect("Failed to generate DT");
+    let fdt = parse_fdt(&dtb).expect("Failed to parse DT");
+
+    assert_eq!(
+        fdt.prop_u64_array("/memory", "reg"),
+        Some(vec![0x8000_0000, kib!(16)])
+    );
+    assert_eq!(
+        fdt.prop_u64_array("/interrupt-controller@3fff0000", "reg"),
+        Some(vec![0x3FFF0000, 0x10000, 0x3FF00000, 0x20000])
+    );
+    assert_eq!(
+        fdt.prop_u32("/", "interrupt-parent"),
+        fdt.prop_u32("/interrupt-controller@3fff0000", "phandle")
+    );
+    assert_eq!(fdt.prop_str("/cpus/cpu@0", "enable-method"), Some("psci"));
+}
+
+#[rstest]
+fn cpu_idle_states(#[values(true, false)] force_psci: bool) {
+    let mut vm = GunyahVirtualMachine::new().expect("Failed to create Gunyah Virtual machine");
+    vm.set_force_psci(force_psci);
+    vm.add_regular_memory(
+        0x8000_0000,
+        kib!(16).try_into().unwrap(),
+        ShareType::Share,
+        GuestMemoryAccess::Rwx,
+        false,
+    )
+    .expect("Failed to create guest memory");
+    vm.create_vcpu(0).expect("Failed to create vcpu");
+
+    let dtb = generate_fdt(&vm).expect("Failed to generate DT");
+    let fdt = parse_fdt(&dtb).expect("Failed to parse DT");
+
+    assert_eq!(fdt.has_prop("/cpus/cpu@0", "cpu-idle-states"), force_psci);
+}
+
+/// Discarding memory frees the backing pages but keeps the range mapped, so it reads back as zeroes
+#[test]
+#[cfg(not(feature = "ack-bindings"))]
+fn discard_shared_memory() {
+    let mut vm = GunyahVirtualMachine::new().expect("Failed to create Gunyah Virtual machine");
+    let mem = vm
+        .add_memory(
+            0x8000_0000,
+            kib!(16).try_into().unwrap(),
+            ShareType::Share,
+            GuestMemoryAccess::Rw,
+            false,
+        )
+        .expect("Failed to add memory");
+
+    assert_ok!(vm.write_slice(0x8000_0000, &[0xaa; kib!(8)]));
+    assert_ok!(mem.lock().unwrap().discard(kib!(4), kib!(4)));
+    assert_err!(mem.lock().unwrap().discard(kib!(12), kib!(8)));
+
+    let mut data = [0u8; kib!(8)];
+    assert_ok!(vm.read_slice(0x8000_0000, &mut data));
+    assert_eq!(data[..kib!(4)], [0xaa; kib!(4)]);
+    assert_eq!(data[kib!(4)..], [0; kib!(4)]);
+}
+
+/// virtio-pmem memory starts out with the file's contents and writes reach the file on flush
+#[test]
+#[cfg(not(feature = "ack-bindings"))]
+fn pmem_flush_writes_back() {
+    let path = std::env::temp_dir().join(format!("pmem-{}.img", std::process::id()));
+    std::fs::write(&path, [0x55; kib!(6)]).unwrap();
+
+    let mut vm = GunyahVirtualMachine::new().expect("Failed to create Gunyah Virtual machine");
+    let pmem = assert_ok!(vmm::VirtioPmem::new(
+        &mut vm,
+        0x3e000,
+        9,
+        &path,
+        0x1_0000_0000,
+        kib!(4),
+        false
+    ));
+
+    // The file is padded to whole pages
+    let mut data = [0u8; kib!(8)];
+    assert_ok!(vm.read_slice(0x1_0000_0000, &mut data));
+    assert_eq!(data[..kib!(6)], [0x55; kib!(6)]);
+    assert_eq!(data[kib!(6)..], [0; kib!(2)]);
+
+    assert_ok!(vm.write_slice(0x1_0000_0000, &[0xaa; kib!(1)]));
+    assert_ok!(pmem.lock().unwrap().device().flush());
+    let file = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(file.len(), kib!(6));
+    assert_eq!(file[..kib!(1)], [0xaa; kib!(1)]);
+    assert_eq!(file[kib!(1)..], [0x55; kib!(5)]);
+}
+
+/// ivshmem memory is shared with the host and guest doorbell writes signal the eventfd
+#[test]
+#[cfg(not(feature = "ack-bindings"))]
+fn shmem_shared_with_host() {
+    use std::io::Read;
+
+    let mem = assert_ok!(gunyah::Gunyah::new()
+        .unwrap()
+        .create_guest_memory(kib!(16).try_into().unwrap(), false));
+    let host = assert_ok!(gunyah::GuestMemRegion::new(
+        mem.clone(),
+        0,
+        kib!(16).try_into().unwrap()
+    ));
+    assert_ok!(host.map_mut()).fill(0x5a);
+
+    let mut vm = GunyahVirtualMachine::new().expect("Failed to create Gunyah Virtual machine");
+    let shmem = assert_ok!(vmm::Ivshmem::new(&mut vm, 0x3d000, 12, mem, 0x1_0000_0000));
+
+    let mut data = [0u8; kib!(16)];
+    assert_ok!(vm.read_slice(0x1_0000_0000, &mut data));
+    assert_eq!(data, [0x5a; kib!(16)]);
+    assert_ok!(vm.write_slice(0x1_0000_0000, &[0xa5; kib!(1)]));
+    assert_eq!(assert_ok!(host.map())[..kib!(1)], [0xa5; kib!(1)]);
+
+    // Accesses through the bus don't hit the ioeventfd
+    assert_ok!(vm.write_slice(0x3d000, &[1, 0]));
+    let mut count = [0u8; 8];
+    assert_ok!(shmem.lock().unwrap().doorbell().read_exact(&mut count));
+    assert_eq!(u64::from_ne_bytes(count), 1);
+
+    let fdt = assert_ok!(generate_fdt(&vm));
+    let fdt = assert_ok!(parse_fdt(&fdt));
+    assert_eq!(
+        fdt.prop_u64_array("/shmem@3d000", "reg"),
+        Some(vec![0x3d000, 0x1000, 0x1_0000_0000, kib!(16)])
+    );
+    assert_eq!(
+        fdt.prop_str("/shmem@3d000", "compatible"),
+        Some("gunyah-vmm,ivshmem")
+    );
+}
+
+/// Connected ivshmem devices of two VMs share their memory and are labelled alike
+#[test]
+#[cfg(not(feature = "ack-bindings"))]
+fn shmem_shared_between_vms() {
+    let mem = assert_ok!(gunyah::Gunyah::new()
+        .unwrap()
+        .create_guest_memory(kib!(16).try_into().unwrap(), false));
+    let mut first_vm =
+        GunyahVirtualMachine::new().expect("Failed to create Gunyah Virtual machine");
+    let mut second_vm =
+        GunyahVirtualMachine::new().expect("Failed to create Gunyah Virtual machine");
+    let first = assert_ok!(vmm::Ivshmem::new(
+        &mut first_vm,
+        0x3d000,
+        12,
+        mem.clone(),
+        0x1_0000_0000
+    ));
+    let second = assert_ok!(vmm::Ivshmem::new(
+        &mut second_vm,
+        0x3e000,
+        13,
+        mem,
+        0x2_0000_0000
+    ));
+    assert_err!(vmm::Ivshmem::connect(
+        &first_vm, &first, &first_vm, &first, 7
+    ));
+    assert_ok!(vmm::Ivshmem::connect(
+        &first_vm, &first, &second_vm, &second, 7
+    ));
+    assert_err!(vmm::Ivshmem::connect(
+        &first_vm, &first, &second_vm, &second, 8
+    ));
+
+    assert_ok!(first_vm.write_slice(0x1_0000_0000, &[0xa5; kib!(1)]));
+    let mut data = [0u8; kib!(1)];
+    assert_ok!(second_vm.read_slice(0x2_0000_0000, &mut data));
+    assert_eq!(data, [0xa5; kib!(1)]);
+
+    let fdt = assert_ok!(generate_fdt(&first_vm));
+    let fdt = assert_ok!(parse_fdt(&fdt));
+    assert_eq!(fdt.prop_u32("/shmem@3d000", "label"), Some(7));
+    let fdt = assert_ok!(generate_fdt(&second_vm));
+    let fdt = assert_ok!(parse_fdt(&fdt));
+    assert_eq!(fdt.prop_u32("/shmem@3e000", "label"), Some(7));
+}
+
+/// A restored VM gets the snapshotted memory, boot configuration and device state back
+#[test]
+#[cfg(not(feature = "ack-bindings"))]
+fn snapshot_and_restore() {
+    let path = std::env::temp_dir().join(format!("snapshot-{}.bin", std::process::id()));
+
+    let mut vm = GunyahVirtualMachine::new().expect("Failed to create Gunyah Virtual machine");
+    assert_ok!(vm.add_memory(
+        0x8000_0000,
+        kib!(16).try_into().unwrap(),
+        ShareType::Lend,
+        GuestMemoryAccess::Rwx,
+        false,
+    ));
+    assert_ok!(vm.write_slice(0x8000_0000, &[0x5a; kib!(4)]));
+    let dtb = assert_ok!(generate_fdt(&vm));
+    assert_ok!(vm.set_dtb_config(0x8000_2000, kib!(4), &dtb));
+    assert_ok!(vm.set_boot_pc(0x8000_0000));
+    assert_ok!(vm.set_boot_reg(BootReg::X(0), 0x8000_2000));
+    let irq_gen = assert_ok!(vmm::IrqGen::new(&mut vm, 0x9200, 13));
+    assert_ok!(irq_gen.lock().unwrap().fire(3));
+    assert_ok!(vm.snapshot(&path));
+    drop(vm);
+
+    let mut vm = assert_ok!(GunyahVirtualMachine::restore(&path));
+    std::fs::remove_file(&path).unwrap();
+    let mut data = [0u8; kib!(4)];
+    assert_ok!(vm.read_slice(0x8000_0000, &mut data));
+    assert_eq!(data, [0x5a; kib!(4)]);
+
+    // Devices have to be added back before their state can be restored
+    let irq_gen = assert_ok!(vmm::IrqGen::new(&mut vm, 0x9200, 13));
+    assert_ok!(vm.restore_devices());
+    assert_eq!(irq_gen.lock().unwrap().raised(), 3);
+}
+
+/// A breakpoint takes its page out of the VM until it is removed again
+#[test]
+#[cfg(not(feature = "ack-bindings"))]
+fn breakpoint_removes_page() {
+    let mut vm = GunyahVirtualMachine::new().expect("Failed to create Gunyah Virtual machine");
+    vm.add_memory(
+        0x8000_0000,
+        kib!(16).try_into().unwrap(),
+        ShareType::Share,
+        GuestMemoryAccess::Rwx,
+        false,
+    )
+    .expect("Failed to add memory");
+    assert_ok!(vm.write_slice(0x8000_1000, &[0xaa; kib!(4)]));
+
+    assert_err!(vm.set_breakpoint(0x9000_0000));
+    assert_ok!(vm.set_breakpoint(0x8000_1234));
+    assert_ok!(vm.set_breakpoint(0x8000_1238));
+    assert_eq!(vm.memory_regions().len(), 2);
+    assert_err!(vm.read_slice(0x8000_1000, &mut [0u8; 4]));
+
+    assert_ok!(vm.remove_breakpoint(0x8000_1000));
+    assert_err!(vm.remove_breakpoint(0x8000_1000));
+    let mut data = [0u8; kib!(4)];
+    assert_ok!(vm.read_slice(0x8000_1000, &mut data));
+    assert_eq!(data, [0xaa; kib!(4)]);
+    assert_eq!(vm.debug_stop(), None);
+}
+
+/// A kick before the vCPU enters the guest isn't lost
+#[test]
+fn kick_before_run() {
+    let mut vm = GunyahVirtualMachine::new().expect("Failed to create Gunyah Virtual machine");
+    vm.add_regular_memory(
+        0x8000_0000,
+        kib!(16).try_into().unwrap(),
+        ShareType::Share,
+        GuestMemoryAccess::Rwx,
+        false,
+    )
+    .expect("Failed to create guest memory");
+
+    let vcpu = vm.create_vcpu(0).expect("Failed to create vcpu");
+
+    let dtb = generate_fdt(&vm).expect("Failed to generate DT");
+    vm.set_dtb_config(0x8000_0000, kib!(4), &dtb)
+        .expect("Failed to set DTB configuration");
+    assert_ok!(vm.start());
+
+    vcpu.kick();
+    assert_err!(vcpu.run_once());
+}
+
+/// Possible vCPUs are described as disabled until they are added to the running VM
+#[test]
+fn hot_add_vcpu() {
+    let mut vm = GunyahVirtualMachine::new().expect("Failed to create Gunyah Virtual machine");
+    vm.set_possible_vcpus(2);
+    vm.add_regular_memory(
+        0x8000_0000,
+        kib!(16).try_into().unwrap(),
+        ShareType::Share,
+        GuestMemoryAccess::Rwx,
+        false,
+    )
+    .expect("Failed to create guest memory");
+
+    vm.create_vcpu(0).expect("Failed to create vcpu");
+
+    let dtb = generate_fdt(&vm).expect("Failed to generate DT");
+    let fdt = parse_fdt(&dtb).expect("Failed to parse DT");
+    assert!(!fdt.has_prop("/cpus/cpu@0", "status"));
+    assert_eq!(fdt.prop_str("/cpus/cpu@1", "status"), Some("disabled"));
+    assert!(fdt.node("/cpus/cpu@2").is_none());
+
+    vm.set_dtb_config(0x8000_0000, kib!(4), &dtb)
+        .expect("Failed to set DTB configuration");
+    assert_ok!(vm.start());
+
+    let hotplug = vm.vcpu_hotplug();
+    assert!(hotplug.add(0).is_err());
+    assert!(hotplug.add(2).is_err());
+    let (vcpu, runner) = assert_ok!(hotplug.add(1));
+    assert_eq!(vcpu.id(), 1);
+    assert_eq!(vm.vcpus().len(), 2);
+
+    vm.exit_request().request(vmm::VmExit::Poweroff);
+    vcpu.kick();
+    assert_eq!(assert_ok!(runner.join().unwrap()), vmm::VmExit::Poweroff);
+}
+
+/// Memory can be added to the hotplug window of a running VM
+#[test]
+fn hot_add_memory() {
+    let mut vm = GunyahVirtualMachine::new().expect("Failed to create Gunyah Virtual machine");
+    vm.set_hotplug_memory(0x9000_0000, kib!(64));
+    vm.add_regular_memory(
+        0x8000_0000,
+        kib!(16).try_into().unwrap(),
+        ShareType::Share,
+        GuestMemoryAccess::Rwx,
+        false,
+    )
+    .expect("Failed to create guest memory");
+
+    vm.create_vcpu(0).expect("Failed to create vcpu");
+
+    let dtb = generate_fdt(&vm).expect("Failed to generate DT");
+    let fdt = parse_fdt(&dtb).expect("Failed to parse DT");
+    assert_eq!(fdt.prop_str("/memory@90000000", "status"), Some("disabled"));
+    assert!(fdt.has_prop("/memory@90000000", "hotpluggable"));
+
+    vm.set_dtb_config(0x8000_0000, kib!(4), &dtb)
+        .expect("Failed to set DTB configuration");
+    assert_ok!(vm.start());
+
+    let hotplug = vm.memory_hotplug();
+    let len = kib!(16).try_into().unwrap();
+    assert!(hotplug
+        .add(0xa000_0000, len, ShareType::Share, false)
+        .is_err());
+    assert!(hotplug
+        .add(0x9000_c000 + kib!(4), len, ShareType::Share, false)
+        .is_err());
+    hotplug
+        .add(0x9000_4000, len, ShareType::Share, false)
+        .expect("Failed to add memory");
+    assert!(hotplug
+        .add(0x9000_4000, len, ShareType::Share, false)
+        .is_err());
+    assert_eq!(vm.memory_regions().len(), 2);
+
+    assert_ok!(vm.write_slice(0x9000_4000, &[0xaa]));
+    let mut data = [0u8];
+    assert_ok!(vm.read_slice(0x9000_4000, &mut data));
+    assert_eq!(data, [0xaa]);
+}
+
+/// Files are loaded straight into guest memory, which must hold them in one region
+#[test]
+fn load_file() {
+    let mut vm = GunyahVirtualMachine::new().expect("Failed to create Gunyah Virtual machine");
+    vm.add_memory(
+        0x8000_0000,
+        kib!(16).try_into().unwrap(),
+        ShareType::Share,
+        GuestMemoryAccess::Rwx,
+        false,
+    )
+    .expect("Failed to create guest memory");
+
+    let path = std::env::temp_dir().join(format!("load-file-{}", std::process::id()));
+    let data: Vec<u8> = (0..kib!(5)).map(|i| i as u8).collect();
+    assert_ok!(std::fs::write(&path, &data));
+
+    assert_eq!(assert_ok!(vm.load_file(&path, 0x8000_1000)), kib!(5));
+    let mut loaded = vec![0u8; kib!(5)];
+    assert_ok!(vm.read_slice(0x8000_1000, &mut loaded));
+    assert_eq!(loaded, data);
+
+    // Runs past the end of memory
+    assert_err!(vm.load_file(&path, 0x8000_3000));
+    assert_ok!(std::fs::remove_file(&path));
+}
+
+/// The firmware's address is part of the VM's configuration
+#[test]
+fn firmware_config() {
+    let mut vm = GunyahVirtualMachine::new().expect("Failed to create Gunyah Virtual machine");
+    vm.add_memory(
+        0x8000_0000,
+        kib!(16).try_into().unwrap(),
+        ShareType::Share,
+        GuestMemoryAccess::Rwx,
+        false,
+    )
+    .expect("Failed to create guest memory");
+    vm.create_vcpu(0).expect("Failed to create vcpu");
+
+    let dtb = generate_fdt(&vm).expect("Failed to generate DT");
+    let fdt = parse_fdt(&dtb).expect("Failed to parse DT");
+    assert!(!fdt.has_prop("/gunyah-vm-config/memory", "firmware-address"));
+
+    assert_ok!(vm.set_firmware(0x8000_2000, kib!(4)));
+    assert_eq!(vm.firmware(), Some((0x8000_2000, kib!(4))));
+    let dtb = generate_fdt(&vm).expect("Failed to generate DT");
+    let fdt = parse_fdt(&dtb).expect("Failed to parse DT");
+    assert_eq!(
+        fdt.prop_u64("/gunyah-vm-config/memory", "firmware-address"),
+        Some(0x8000_2000)
+    );
+}
+
+/// Reads and writes are split at region boundaries
+#[test]
+fn slices_span_regions() {
+    let mut vm = GunyahVirtualMachine::new().expect("Failed to create Gunyah Virtual machine");
+    for base in [0x8000_0000, 0x8000_4000] {
+        vm.add_memory(
+            base,
+            kib!(16).try_into().unwrap(),
+            ShareType::Share,
+            GuestMemoryAccess::Rwx,
+            false,
+        )
+        .expect("Failed to create guest memory");
+    }
+    let data: Vec<u8> = (0..kib!(4)).map(|i| i as u8).collect();
+    assert_ok!(vm.write_slice(0x8000_3800, &data));
+    let mut read = vec![0u8; kib!(4)];
+    assert_ok!(vm.read_slice(0x8000_3800, &mut read));
+    assert_eq!(read, data);
+
+    // Runs past the end of memory
+    assert_err!(vm.write_slice(0x8000_7800, &data));
+}
+
+/// Memory dumps may span adjacent regions
+#[test]
+fn dump_memory() {
+    let mut vm = GunyahVirtualMachine::new().expect("Failed to create Gunyah Virtual machine");
+    for base in [0x8000_0000, 0x8000_4000] {
+        vm.add_memory(
+            base,
+            kib!(16).try_into().unwrap(),
+            ShareType::Share,
+            GuestMemoryAccess::Rwx,
+            false,
+        )
+        .expect("Failed to create guest memory");
+    }
+    assert_ok!(vm.write_slice(0x8000_3fff, &[0xaa]));
+    assert_ok!(vm.write_slice(0x8000_4000, &[0xbb]));
+
+    let mut dump = Vec::new();
+    assert_ok!(vm.dump_memory(0x8000_3ff0, 0x20, &mut dump));
+    assert_eq!(dump.len(), 0x20);
+    assert_eq!(dump[0xf..0x11], [0xaa, 0xbb]);
+
+    // Runs past the end of memory
+    assert_err!(vm.dump_memory(0x8000_7000, 0x2000, &mut Vec::new()));
+}
+
+/// Only level interrupts can be asserted and deasserted
+#[test]
+fn level_interrupt() {
+    let vm = GunyahVirtualMachine::new().expect("Failed to create Gunyah Virtual machine");
+    let level = vm
+        .add_level_interrupt(4)
+        .expect("Failed to add level interrupt");
+    let edge = vm
+        .add_edge_interrupt(5)
+        .expect("Failed to add edge interrupt");
+
+    assert!(!level.is_asserted());
+    assert_ok!(level.set_level(true));
+    assert!(level.is_asserted());
+    assert_ok!(level.set_level(true));
+    assert_ok!(level.set_level(false));
+    assert!(!level.is_asserted());
+
+    assert_err!(edge.set_level(true));
+    assert!(!edge.is_asserted());
+}